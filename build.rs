@@ -33,4 +33,9 @@ fn main()
 	{
 		compile_shader(fragpath);
 	}
+
+	for comppath in glob("shaders/*.comp").unwrap().filter_map(Result::ok)
+	{
+		compile_shader(comppath);
+	}
 }
@@ -0,0 +1,41 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+precision highp float;
+
+layout(push_constant) uniform DecalBlock {
+	mat4 decal_m;
+	vec4 color;
+	float seed;
+} Decal;
+
+layout(location = 0) in vec3 decalspace_pos;
+layout(location = 1) in float decalspace_normal_z;
+
+layout(location = 0) out vec4 out_color;
+
+float hash(vec2 p)
+{
+	return fract(sin(dot(p, vec2(127.1, 311.7))) * 43758.5453);
+}
+
+void main()
+{
+	if (any(greaterThan(abs(decalspace_pos), vec3(1.0))))
+		discard;
+
+	// Scratches run along x, in thin rows of random strength and length
+	float rows = 24.0;
+	float row = floor((decalspace_pos.y + 1.0) * 0.5 * rows);
+	float strength = hash(vec2(row, Decal.seed));
+	float start = 2.0 * hash(vec2(row + 0.5, Decal.seed)) - 1.0;
+	float extent = hash(vec2(row + 0.25, Decal.seed + 1.0));
+	float along = abs(decalspace_pos.x - start) < extent ? 1.0 : 0.0;
+	float scratch = step(0.5, strength) * along * (0.5 + 0.5 * strength);
+
+	// Fades out towards the edge of the decal, and on surfaces facing away from it. The decal presses along +z, so the
+	// surfaces it hits face -z
+	float falloff = 1.0 - smoothstep(0.5, 1.0, length(decalspace_pos.xy));
+	float facing = clamp(-decalspace_normal_z, 0.0, 1.0);
+
+	out_color = vec4(Decal.color.rgb, scratch * falloff * facing * Decal.color.a);
+}
@@ -0,0 +1,27 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 4) in vec2 tex_uv;
+
+// Must match DamageDecal in damage.rs
+layout(push_constant) uniform DecalBlock {
+	// Mesh space to the decal box, which spans [-1, 1] along each axis
+	mat4 decal_m;
+	vec4 color;
+	float seed;
+} Decal;
+
+layout(location = 0) out vec3 decalspace_pos;
+layout(location = 1) out float decalspace_normal_z;
+
+void main()
+{
+	vec4 decalspace_pos4 = Decal.decal_m * vec4(position, 1.0);
+	decalspace_pos = vec3(decalspace_pos4) / decalspace_pos4.w;
+	decalspace_normal_z = normalize(mat3(Decal.decal_m) * normal).z;
+
+	// Unwrap the mesh onto the damage texture
+	gl_Position = vec4(2.0 * tex_uv - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,48 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// One instance per glyph
+layout(location = 0) in vec2 position;
+layout(location = 1) in uint cell;
+layout(location = 2) in vec4 color;
+
+// Must match GlyphParams in overlay.rs
+layout(push_constant) uniform GlyphBlock {
+	vec2 screen_size;
+	vec2 cell_size;
+	vec2 cell_uv_size;
+	float padding;
+	float outline_width;
+	uint atlas_columns;
+} Glyph;
+
+// Same outputs as overlay.vert, so overlay.frag can be reused
+layout(location = 0) out vec2 texCoord;
+layout(location = 1) out vec4 fillColor;
+layout(location = 2) out vec4 outlineColor;
+layout(location = 3) out vec4 shadowColor;
+layout(location = 4) out vec4 styleParams;
+layout(location = 5) out vec2 screenCoord;
+
+const vec2 corners[6] = vec2[](
+	vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0),
+	vec2(0.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)
+);
+
+void main()
+{
+	vec2 corner = corners[gl_VertexIndex];
+	vec2 cell_origin = vec2(cell % Glyph.atlas_columns, cell / Glyph.atlas_columns);
+	texCoord = (cell_origin + corner) * Glyph.cell_uv_size;
+	fillColor = color;
+	// Black outline for readability, no shadow
+	outlineColor = vec4(0.0, 0.0, 0.0, color.a);
+	shadowColor = vec4(0.0);
+	// Never depth tested
+	styleParams = vec4(Glyph.outline_width, 0.0, 0.0, -1.0);
+
+	vec2 pixel_position = position - Glyph.padding + corner * Glyph.cell_size;
+	screenCoord = pixel_position / Glyph.screen_size;
+	// Pixels to NDC, Vulkan has y pointing down like the screen
+	gl_Position = vec4(pixel_position / Glyph.screen_size * 2.0 - 1.0, 0.0, 1.0);
+}
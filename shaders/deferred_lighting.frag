@@ -0,0 +1,111 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_GOOGLE_include_directive : require
+precision highp float;
+
+// Lights the surfaces in the G-buffer written by phong.frag, see MainPass::finish_geometry(). Drawn over the whole
+// render image, and added to the bounce light, reflections and emission already in it.
+
+// Must match MAX_LIGHTS in mainpass.rs and MAX_REFLECTION_PROBES in reflection_probes.rs, which define them when
+// compiled at runtime
+#ifndef MAX_LIGHTS
+#define MAX_LIGHTS 4u
+#endif
+#ifndef MAX_REFLECTION_PROBES
+#define MAX_REFLECTION_PROBES 4u
+#endif
+
+// Directional lights have the direction towards the light in place of the position, and a radius of 0
+struct PointLight {
+	vec4 position_radius;
+	vec4 color;
+	// Where the light's faces are in the shadow atlas, see shadow_atlas.glsl
+	vec4 shadow_tile;
+};
+
+struct ReflectionProbe {
+	vec4 position;
+	vec4 extents;
+};
+
+layout(set = 1, binding = 0) uniform FrameBlock {
+	mat4 v;
+	float wetness;
+	uint light_count;
+	uint probe_count;
+	uint count_fragments;
+	float time;
+	PointLight lights[MAX_LIGHTS];
+	ReflectionProbe probes[MAX_REFLECTION_PROBES];
+	vec4 irradiance_origin;
+	vec4 irradiance_inverse_spacing;
+	uvec4 irradiance_counts;
+} Frame;
+
+layout(set = 1, binding = 1) uniform sampler2D shadow_atlas;
+
+// Surface color and specular strength, world space normal with 1 in alpha where there is a surface, and world space
+// position
+layout(input_attachment_index = 0, set = 1, binding = 8) uniform subpassInput gbuffer_albedo;
+layout(input_attachment_index = 1, set = 1, binding = 9) uniform subpassInput gbuffer_normal;
+layout(input_attachment_index = 2, set = 1, binding = 10) uniform subpassInput gbuffer_position;
+
+layout(location = 0) out vec4 fragColor;
+
+#include "shadow_atlas.glsl"
+
+void main()
+{
+	vec4 normal = subpassLoad(gbuffer_normal);
+	if (normal.a == 0.0)
+		discard;
+	vec4 albedo = subpassLoad(gbuffer_albedo);
+	vec3 worldspace_pos = subpassLoad(gbuffer_position).xyz;
+	vec3 texcolor = albedo.rgb;
+	float specular_strength = albedo.a;
+	float shininess = mix(50.0, 120.0, Frame.wetness);
+
+	// The eye is where the view matrix moves the origin from
+	mat3 view_rotation = mat3(Frame.v);
+	vec3 worldspace_eye = -(transpose(view_rotation) * Frame.v[3].xyz);
+	vec3 N = normalize(normal.xyz);
+	vec3 V = normalize(worldspace_eye - worldspace_pos);
+
+	vec3 color = vec3(0.0);
+	// Same as the forward lighting in phong.frag, in world instead of tangent space
+	for (uint i = 0; i < min(Frame.light_count, MAX_LIGHTS); i++)
+	{
+		vec3 light_position = Frame.lights[i].position_radius.xyz;
+		float light_radius = Frame.lights[i].position_radius.w;
+		vec3 light_color = Frame.lights[i].color.rgb;
+		vec3 worldspace_lightdir = light_position;
+		float attenuation = 1.0;
+
+		// Directional lights are not attenuated, nor shadowed
+		if (light_radius > 0.0)
+		{
+			worldspace_lightdir = light_position - worldspace_pos;
+
+			if (length(worldspace_lightdir) > light_radius)
+				continue;
+			vec3 L_div_r = worldspace_lightdir / light_radius;
+			attenuation = max(1.0 - dot(L_div_r, L_div_r), 0.0);
+			attenuation *= shadow(shadow_atlas, Frame.lights[i].shadow_tile, -worldspace_lightdir, light_radius);
+			if (attenuation == 0.0)
+				continue;
+		}
+
+		vec3 L = normalize(worldspace_lightdir);
+		float lambertian = max(dot(L, N), 0.0);
+		float specular = 0.0;
+		if (lambertian > 0.0)
+		{
+			vec3 R = normalize(reflect(-L, N));
+			specular = specular_strength * pow(max(dot(R, V), 0.0), shininess);
+		}
+
+		color += texcolor * lambertian * light_color * attenuation;
+		color += specular * light_color * attenuation;
+	}
+	fragColor = vec4(color, 0.0);
+}
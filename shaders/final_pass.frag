@@ -3,8 +3,289 @@
 
 layout(location = 0) in vec2 texCoord;
 layout(binding = 0) uniform sampler2D tex;
+layout(binding = 1) uniform sampler2D depth_tex;
+// Previous and current color grading tables
+layout(binding = 2) uniform sampler3D luts[2];
+// Blended over the final image by its alpha, like the heatmaps
+layout(binding = 3) uniform sampler2D debug_overlay;
+// Light scattered towards the eye and transmittance up to the far edge of each froxel, see volumetric_fog.comp
+layout(binding = 5) uniform sampler3D fog_froxels;
 layout(location = 0) out vec4 outColor;
 
+layout(push_constant) uniform PostBlock {
+	float time;
+	float droplets;
+	vec2 sun_position;
+	float sun_shafts_intensity;
+	float sun_shafts_decay;
+	float depth_near;
+	float depth_far;
+	float focus_distance;
+	float dof_aperture;
+	uint dof_debug;
+	float chromatic_aberration;
+	float film_grain;
+	float vignette;
+	float lut_blend;
+	float debug_overlay;
+	float lens_flare_intensity;
+	float fog_distance;
+} Post;
+
+// Most flare elements, must match MAX_FLARE_ELEMENTS in lens_flare.rs
+const int MAX_FLARE_ELEMENTS = 8;
+
+struct FlareElement {
+	// Position along the axis from the sun through the screen center, radius, and 1 for rings
+	vec4 placement;
+	vec4 color;
+};
+
+layout(binding = 4) uniform FlareBlock {
+	uint count;
+	FlareElement elements[MAX_FLARE_ELEMENTS];
+} Flare;
+
+const vec3 SUN_COLOR = vec3(1.0, 0.9, 0.7);
+const int SUN_SHAFT_SAMPLES = 48;
+// Radius of the area around the sun tested for occlusion, as a fraction of the screen height
+const float SUN_OCCLUSION_RADIUS = 0.01;
+// Distance from the eye to the first fog slice, must match FOG_NEAR in volumetric_fog.comp
+const float FOG_NEAR = 0.5;
+const int DOF_SAMPLES = 48;
+// Largest blur radius, as a fraction of the screen height
+const float DOF_MAX_RADIUS = 0.02;
+const float GOLDEN_ANGLE = 2.39996323;
+
+// Hash without sine, see https://www.shadertoy.com/view/4djSRW
+vec3 hash32(vec2 p)
+{
+	vec3 p3 = fract(vec3(p.xyx) * vec3(0.1031, 0.1030, 0.0973));
+	p3 += dot(p3, p3.yxz + 33.33);
+	return fract((p3.xxy + p3.yzz) * p3.zyx);
+}
+
+// Returns the texture coordinate offset caused by rain droplets on the screen.
+vec2 droplet_offset(vec2 uv)
+{
+	const vec2 cells = vec2(16.0, 9.0);
+	vec2 cell = floor(uv * cells);
+	vec2 cell_uv = fract(uv * cells);
+	vec3 rnd = hash32(cell);
+
+	// Only some cells have a droplet, more the harder it rains
+	if (rnd.z > Post.droplets)
+		return vec2(0.0);
+
+	// Each droplet appears, slides slightly down and shrinks away
+	float life = fract(Post.time * (0.2 + 0.3 * rnd.x) + rnd.y);
+	vec2 center = vec2(0.2 + 0.6 * rnd.x, 0.2 + 0.3 * rnd.y + 0.3 * life);
+	float radius = 0.35 * (1.0 - life);
+	vec2 delta = (cell_uv - center) / radius;
+	float dist2 = dot(delta, delta);
+	if (dist2 > 1.0)
+		return vec2(0.0);
+
+	// Lens-like refraction, strongest at the droplet edge
+	return -delta * sqrt(dist2) * radius * 0.5 / cells;
+}
+
+// Returns the light scattered towards the eye from the sun, by blurring the unoccluded sky radially from the sun.
+vec3 sun_shafts(vec2 uv)
+{
+	ivec2 depth_size = textureSize(depth_tex, 0);
+	vec2 delta = (Post.sun_position - uv) / float(SUN_SHAFT_SAMPLES);
+	float weight = 1.0;
+	float light = 0.0;
+	for (int i = 0; i < SUN_SHAFT_SAMPLES; i++)
+	{
+		uv += delta;
+		if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))))
+			break;
+		// Nothing was drawn where the depth is still cleared to the far plane
+		ivec2 texel = min(ivec2(uv * vec2(depth_size)), depth_size - 1);
+		if (texelFetch(depth_tex, texel, 0).r >= 1.0)
+			light += weight;
+		weight *= Post.sun_shafts_decay;
+	}
+	return SUN_COLOR * Post.sun_shafts_intensity * light / float(SUN_SHAFT_SAMPLES);
+}
+
+// Returns the fraction of a small area around the sun where nothing was drawn, from the depth buffer. Parts of the area
+// outside the screen are not tested.
+float sun_visibility()
+{
+	ivec2 depth_size = textureSize(depth_tex, 0);
+	vec2 spacing = vec2(float(depth_size.y) / float(depth_size.x), 1.0) * SUN_OCCLUSION_RADIUS * 0.5;
+	float visible = 0.0;
+	float tested = 0.0;
+	for (int y = -2; y <= 2; y++)
+	{
+		for (int x = -2; x <= 2; x++)
+		{
+			vec2 uv = Post.sun_position + vec2(x, y) * spacing;
+			if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))))
+				continue;
+			ivec2 texel = min(ivec2(uv * vec2(depth_size)), depth_size - 1);
+			if (texelFetch(depth_tex, texel, 0).r >= 1.0)
+				visible += 1.0;
+			tested += 1.0;
+		}
+	}
+	return tested > 0.0 ? visible / tested : 0.0;
+}
+
+// Returns the light of the flare elements at the texture coordinate, faded by how much of the sun is occluded.
+vec3 lens_flare(vec2 uv)
+{
+	float visibility = sun_visibility();
+	if (visibility <= 0.0)
+		return vec3(0.0);
+	ivec2 size = textureSize(tex, 0);
+	vec2 aspect = vec2(float(size.x) / float(size.y), 1.0);
+	vec2 axis = vec2(0.5) - Post.sun_position;
+	vec3 light = vec3(0.0);
+	for (int i = 0; i < min(int(Flare.count), MAX_FLARE_ELEMENTS); i++)
+	{
+		vec4 placement = Flare.elements[i].placement;
+		vec2 center = Post.sun_position + axis * placement.x;
+		float dist = length((uv - center) * aspect) / placement.y;
+		float shape;
+		if (placement.z > 0.5)
+			shape = 1.0 - smoothstep(0.0, 0.08, abs(dist - 0.92));
+		else
+			shape = 1.0 - smoothstep(0.7, 1.0, dist);
+		light += Flare.elements[i].color.rgb * shape;
+	}
+	return light * Post.lens_flare_intensity * visibility;
+}
+
+// Samples the rendered image, with the red and blue channels split apart towards the screen edges.
+vec3 scene_color(vec2 uv)
+{
+	if (Post.chromatic_aberration <= 0.0)
+		return texture(tex, uv).rgb;
+	// Scaled so the split is chromatic_aberration at the corners
+	vec2 split = (uv - 0.5) * 1.41421356 * Post.chromatic_aberration;
+	split.x *= float(textureSize(tex, 0).y) / float(textureSize(tex, 0).x);
+	return vec3(texture(tex, uv + split).r, texture(tex, uv).g, texture(tex, uv - split).b);
+}
+
+// Returns the distance from the eye of the surface drawn at the texel.
+float linear_depth(ivec2 texel)
+{
+	float ndc_z = 2.0 * texelFetch(depth_tex, texel, 0).r - 1.0;
+	float near = Post.depth_near;
+	float far = Post.depth_far;
+	return 2.0 * near * far / (far + near - ndc_z * (far - near));
+}
+
+// Returns the signed radius of the circle of confusion, as a fraction of the screen height.
+// Negative in front of the focus plane, positive behind it.
+float circle_of_confusion(float depth)
+{
+	float coc = Post.dof_aperture * (depth - Post.focus_distance) / depth;
+	return clamp(coc, -DOF_MAX_RADIUS, DOF_MAX_RADIUS);
+}
+
+// Blurs the image by gathering samples on a disc, each sample contributing if its own circle of confusion reaches
+// this pixel. Samples behind the pixel cannot blur further than the pixel itself, so sharp foreground objects are not
+// smeared by the blurry background.
+vec3 depth_of_field(vec2 uv)
+{
+	ivec2 depth_size = textureSize(depth_tex, 0);
+	float aspect_ratio = float(depth_size.x) / float(depth_size.y);
+	float center_depth = linear_depth(min(ivec2(uv * vec2(depth_size)), depth_size - 1));
+	float center_coc = abs(circle_of_confusion(center_depth));
+
+	vec3 color = scene_color(uv);
+	float total_weight = 1.0;
+	for (int i = 0; i < DOF_SAMPLES; i++)
+	{
+		float radius = DOF_MAX_RADIUS * sqrt((float(i) + 0.5) / float(DOF_SAMPLES));
+		float angle = float(i) * GOLDEN_ANGLE;
+		vec2 sample_uv = clamp(uv + vec2(cos(angle) / aspect_ratio, sin(angle)) * radius, vec2(0.0), vec2(1.0));
+		float sample_depth = linear_depth(min(ivec2(sample_uv * vec2(depth_size)), depth_size - 1));
+		float sample_coc = abs(circle_of_confusion(sample_depth));
+		if (sample_depth > center_depth)
+			sample_coc = min(sample_coc, center_coc);
+		float weight = smoothstep(radius - 0.002, radius, sample_coc);
+		color += scene_color(sample_uv) * weight;
+		total_weight += weight;
+	}
+	return color / total_weight;
+}
+
+// Attenuates the color by the fog in front of the surface drawn at the texture coordinate, and adds the light the fog
+// scatters towards the eye.
+vec3 apply_fog(vec2 uv, vec3 color)
+{
+	ivec2 depth_size = textureSize(depth_tex, 0);
+	float depth = linear_depth(min(ivec2(uv * vec2(depth_size)), depth_size - 1));
+	// Fractional slice of the depth, inverting the exponential slicing
+	float slices = float(textureSize(fog_froxels, 0).z);
+	float slice = log(max(depth, FOG_NEAR) / FOG_NEAR) / log(Post.fog_distance / FOG_NEAR) * slices;
+	// Each froxel holds the fog up to its far edge, and there is none in front of the first slice
+	vec4 fog = textureLod(fog_froxels, vec3(uv, clamp((slice - 0.5) / slices, 0.5 / slices, 1.0 - 0.5 / slices)), 0.0);
+	fog = mix(vec4(0.0, 0.0, 0.0, 1.0), fog, clamp(slice, 0.0, 1.0));
+	return color * fog.a + fog.rgb;
+}
+
+// Looks up the color in the table, sampling texel centers so the corners of the table map to 0 and 1.
+vec3 apply_lut(int lut, vec3 color)
+{
+	float size = float(textureSize(luts[lut], 0).x);
+	return texture(luts[lut], color * ((size - 1.0) / size) + 0.5 / size).rgb;
+}
+
+vec3 color_grade(vec3 color)
+{
+	color = clamp(color, 0.0, 1.0);
+	vec3 graded = apply_lut(1, color);
+	if (Post.lut_blend < 1.0)
+		graded = mix(apply_lut(0, color), graded, Post.lut_blend);
+	return graded;
+}
+
 void main() {
-    outColor = texture(tex, texCoord);
+	vec2 uv = texCoord;
+	if (Post.droplets > 0.0)
+		uv += droplet_offset(texCoord);
+	outColor = texture(tex, uv);
+	if (Post.dof_aperture > 0.0)
+		outColor.rgb = depth_of_field(uv);
+	else
+		outColor.rgb = scene_color(uv);
+	if (Post.fog_distance > 0.0)
+		outColor.rgb = apply_fog(uv, outColor.rgb);
+	if (Post.sun_shafts_intensity > 0.0)
+		outColor.rgb += sun_shafts(uv);
+	// Unaffected by droplets, as it is in the lens rather than the scene
+	if (Post.lens_flare_intensity > 0.0)
+		outColor.rgb += lens_flare(texCoord);
+	if (Post.vignette > 0.0)
+	{
+		// 1 at the corners
+		float corner = length(texCoord - 0.5) * 1.41421356;
+		outColor.rgb *= 1.0 - Post.vignette * corner * corner;
+	}
+	outColor.rgb = color_grade(outColor.rgb);
+	if (Post.film_grain > 0.0)
+	{
+		// New grain every frame, at 24 frames per second like film
+		float frame = floor(Post.time * 24.0);
+		float grain = hash32(gl_FragCoord.xy + vec2(frame * 7.31, frame * 3.17)).x - 0.5;
+		outColor.rgb += grain * Post.film_grain;
+	}
+	if (Post.dof_debug != 0u)
+	{
+		ivec2 depth_size = textureSize(depth_tex, 0);
+		float coc = circle_of_confusion(linear_depth(min(ivec2(uv * vec2(depth_size)), depth_size - 1)));
+		outColor.rgb = vec3(max(-coc, 0.0), 0.0, max(coc, 0.0)) / DOF_MAX_RADIUS;
+	}
+	if (Post.debug_overlay > 0.0)
+	{
+		vec4 overlay = texture(debug_overlay, texCoord);
+		outColor.rgb = mix(outColor.rgb, overlay.rgb, overlay.a * Post.debug_overlay);
+	}
 }
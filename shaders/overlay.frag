@@ -0,0 +1,45 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(set = 0, binding = 0) uniform sampler2D sdfAtlas;
+layout(set = 0, binding = 1) uniform sampler2D sceneDepth;
+
+layout(location = 0) in vec2 texCoord;
+layout(location = 1) in vec4 fillColor;
+layout(location = 2) in vec4 outlineColor;
+layout(location = 3) in vec4 shadowColor;
+// x: outline width in distance field units, yz: shadow offset in texture coordinates,
+// w: depth of world labels, negative to draw on top of everything
+layout(location = 4) in vec4 styleParams;
+// Position on the screen [0, 1], to look up the scene depth
+layout(location = 5) in vec2 screenCoord;
+
+layout(location = 0) out vec4 fragColor;
+
+void main()
+{
+	// World labels behind the scene are hidden
+	if (styleParams.w >= 0.0 && styleParams.w > texture(sceneDepth, screenCoord).r)
+	{
+		discard;
+	}
+
+	// The distance field is 0.5 on edges, increasing inwards
+	float dist = texture(sdfAtlas, texCoord).r;
+	// Anti-alias over one screen pixel, regardless of the scale
+	float aa = max(fwidth(dist), 0.0001);
+
+	float fill = smoothstep(0.5 - aa, 0.5 + aa, dist);
+	float outline_edge = 0.5 - styleParams.x;
+	float coverage = smoothstep(outline_edge - aa, outline_edge + aa, dist);
+	vec4 foreground = mix(outlineColor, fillColor, fill);
+	foreground.a *= coverage;
+
+	float shadow_dist = texture(sdfAtlas, texCoord - styleParams.yz).r;
+	float shadow = smoothstep(outline_edge - aa, outline_edge + aa, shadow_dist) * shadowColor.a;
+
+	// Foreground over shadow
+	float alpha = foreground.a + shadow * (1.0 - foreground.a);
+	vec3 color = foreground.rgb * foreground.a + shadowColor.rgb * shadow * (1.0 - foreground.a);
+	fragColor = vec4(color / max(alpha, 0.0001), alpha);
+}
@@ -0,0 +1,35 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec4 color;
+layout(location = 3) in vec4 outline_color;
+layout(location = 4) in vec4 shadow_color;
+layout(location = 5) in vec4 params;
+
+layout(push_constant) uniform ScreenBlock {
+	vec2 size;
+	// Area of the screen the scene is presented in, to look up its depth
+	vec2 image_offset;
+	vec2 image_size;
+} Screen;
+
+layout(location = 0) out vec2 texCoord;
+layout(location = 1) out vec4 fillColor;
+layout(location = 2) out vec4 outlineColor;
+layout(location = 3) out vec4 shadowColor;
+layout(location = 4) out vec4 styleParams;
+layout(location = 5) out vec2 screenCoord;
+
+void main()
+{
+	texCoord = uv;
+	fillColor = color;
+	outlineColor = outline_color;
+	shadowColor = shadow_color;
+	styleParams = params;
+	screenCoord = (position - Screen.image_offset) / Screen.image_size;
+	// Pixels to NDC, Vulkan has y pointing down like the screen
+	gl_Position = vec4(position / Screen.size * 2.0 - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,14 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(set = 0, binding = 0) uniform sampler2D image;
+
+layout(location = 0) in vec2 texCoord;
+layout(location = 1) in vec4 fillColor;
+
+layout(location = 0) out vec4 fragColor;
+
+void main()
+{
+	fragColor = texture(image, texCoord) * fillColor;
+}
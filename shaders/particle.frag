@@ -0,0 +1,32 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec4 interpolated_color;
+
+// The start of the FrameBlock in phong.frag
+layout(set = 1, binding = 0) uniform FrameBlock {
+	mat4 v;
+	float wetness;
+	uint light_count;
+	uint probe_count;
+	uint count_fragments;
+} Frame;
+
+// Fragments drawn to each pixel, all in the low and blended ones in the high 16 bits, see heatmap.comp
+layout(set = 1, binding = 6, r32ui) uniform uimage2D fragment_counts;
+
+// Counting needs the fragments hidden by the depth test to be skipped before shading
+layout(early_fragment_tests) in;
+
+layout(location = 0) out vec4 fragColor;
+
+void main()
+{
+	fragColor = interpolated_color;
+
+	// Particles are always blended
+	if (Frame.count_fragments != 0u)
+	{
+		imageAtomicAdd(fragment_counts, ivec2(gl_FragCoord.xy), 0x10001u);
+	}
+}
@@ -0,0 +1,18 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+layout(push_constant) uniform MatrixBlock {
+	mat4 m;
+	mat4 mvp;
+} Matrices;
+
+layout(location = 0) out vec4 interpolated_color;
+
+void main()
+{
+	interpolated_color = color;
+	gl_Position = Matrices.mvp * vec4(position, 1.0);
+}
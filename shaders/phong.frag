@@ -1,62 +1,345 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
+#extension GL_GOOGLE_include_directive : require
 precision highp float;
 
 layout(location = 0) in vec3 tangentspace_eyedir;
-layout(location = 1) in vec3 worldspace_lightdir;
-layout(location = 2) in vec3 tangentspace_lightdir;
-layout(location = 3) in vec2 tex_uv;
+layout(location = 1) in vec3 worldspace_pos;
+layout(location = 2) in mat3 TBN;
+layout(location = 5) in vec2 tex_uv;
 
 layout(set = 0, binding = 0) uniform sampler2D color_tex;
 layout(set = 0, binding = 1) uniform sampler2D normal_tex;
+layout(set = 0, binding = 2) uniform MaterialBlock {
+	vec4 tint;
+	vec4 emissive;
+	float specular;
+	vec2 uv_scroll;
+	// Columns, rows and frames per second
+	vec4 flipbook;
+} Material;
+// Baked damage, see damage.rs. Transparent for materials without damage
+layout(set = 0, binding = 3) uniform sampler2D damage_tex;
 
-layout(location = 0) out vec3 fragColor;
+// Indirect draws read their material from the materials buffer instead of the material block
+layout(constant_id = 0) const bool INDIRECT = false;
+layout(location = 6) flat in uint material_index;
 
+// Material features, see MaterialFeatures in material.rs
+layout(constant_id = 1) const bool NORMAL_MAPPING = true;
+layout(constant_id = 2) const bool EMISSIVE = true;
+// Whether the material is blended, for the fragment counts
+layout(constant_id = 3) const bool BLENDED = false;
+// Drawn in the geometry subpass of the deferred main pass: the lights are left to deferred_lighting.frag, which reads
+// the surface from the G-buffer outputs
+layout(constant_id = 4) const bool DEFERRED = false;
+
+// Counting needs the fragments hidden by the depth test to be skipped before shading
+layout(early_fragment_tests) in;
+
+struct MaterialParams {
+	vec4 tint;
+	vec4 emissive;
+	float specular;
+	vec2 uv_scroll;
+	vec4 flipbook;
+};
+
+layout(std430, set = 2, binding = 1) readonly buffer MaterialsBlock {
+	MaterialParams materials[];
+} Materials;
+
+// Must match MAX_LIGHTS in mainpass.rs and MAX_REFLECTION_PROBES in reflection_probes.rs, which define them when
+// compiled at runtime
+#ifndef MAX_LIGHTS
+#define MAX_LIGHTS 4u
+#endif
+#ifndef MAX_REFLECTION_PROBES
+#define MAX_REFLECTION_PROBES 4u
+#endif
+
+// Directional lights have the direction towards the light in place of the position, and a radius of 0
 struct PointLight {
-	float radius;
-	vec3 color;
+	vec4 position_radius;
+	vec4 color;
+	// Where the light's faces are in the shadow atlas, see shadow_atlas.glsl
+	vec4 shadow_tile;
+};
+
+struct ReflectionProbe {
+	vec4 position;
+	// Half the size of the box volume along each axis
+	vec4 extents;
 };
 
-//hardcoded for now
-PointLight light = PointLight(1000.0, vec3(1.0, 1.0, 1.0));
+layout(set = 1, binding = 0) uniform FrameBlock {
+	mat4 v;
+	float wetness;
+	uint light_count;
+	uint probe_count;
+	uint count_fragments;
+	float time;
+	PointLight lights[MAX_LIGHTS];
+	ReflectionProbe probes[MAX_REFLECTION_PROBES];
+	// Irradiance grid: position of the first probe, probes per meter along each axis, and probes along each axis, 0
+	// without a grid
+	vec4 irradiance_origin;
+	vec4 irradiance_inverse_spacing;
+	uvec4 irradiance_counts;
+} Frame;
 
-void main()
+layout(set = 1, binding = 1) uniform sampler2D shadow_atlas;
+// Separate bindings, as sampler arrays cannot be indexed dynamically everywhere
+layout(set = 1, binding = 2) uniform samplerCube reflection_probe0;
+layout(set = 1, binding = 3) uniform samplerCube reflection_probe1;
+layout(set = 1, binding = 4) uniform samplerCube reflection_probe2;
+layout(set = 1, binding = 5) uniform samplerCube reflection_probe3;
+// Fragments drawn to each pixel, all in the low and blended ones in the high 16 bits, see heatmap.comp
+layout(set = 1, binding = 6, r32ui) uniform uimage2D fragment_counts;
+
+// Second order spherical harmonics of the irradiance over pi, see irradiance_grid.rs
+struct IrradianceProbe {
+	vec4 sh[9];
+};
+
+layout(std430, set = 1, binding = 7) readonly buffer IrradianceProbesBlock {
+	IrradianceProbe probes[];
+} IrradianceProbes;
+
+layout(location = 0) out vec4 fragColor;
+// G-buffer, see GBuffer in mainpass.rs. Only written when DEFERRED
+layout(location = 1) out vec4 gbuffer_albedo;
+layout(location = 2) out vec4 gbuffer_normal;
+layout(location = 3) out vec4 gbuffer_position;
+
+#include "shadow_atlas.glsl"
+
+vec3 sample_probe(uint probe, vec3 direction)
 {
-	vec3 color = vec3(0.0);
-	vec3 texcolor = texture(color_tex, tex_uv).rgb;
-	// for each light
-	for (uint i = 0; i < 1u; i++)
+	// The probes are baked mirrored in y, see reflection_probes.rs
+	direction.y = -direction.y;
+	if (probe == 0u)
+		return textureLod(reflection_probe0, direction, 0.0).rgb;
+	if (probe == 1u)
+		return textureLod(reflection_probe1, direction, 0.0).rgb;
+	if (probe == 2u)
+		return textureLod(reflection_probe2, direction, 0.0).rgb;
+	return textureLod(reflection_probe3, direction, 0.0).rgb;
+}
+
+// Returns the light reflected by the scene onto a white diffuse surface with the given world space normal, interpolated
+// between the eight probes of the irradiance grid around the fragment
+vec3 grid_irradiance(vec3 N)
+{
+	uvec3 counts = Frame.irradiance_counts.xyz;
+	if (counts.x == 0u)
+		return vec3(0.0);
+	float basis[9] = float[9](
+		0.282095,
+		0.488603 * N.y,
+		0.488603 * N.z,
+		0.488603 * N.x,
+		1.092548 * N.x * N.y,
+		1.092548 * N.y * N.z,
+		0.315392 * (3.0 * N.z * N.z - 1.0),
+		1.092548 * N.x * N.z,
+		0.546274 * (N.x * N.x - N.y * N.y));
+	vec3 grid_pos = (worldspace_pos - Frame.irradiance_origin.xyz) * Frame.irradiance_inverse_spacing.xyz;
+	grid_pos = clamp(grid_pos, vec3(0.0), vec3(counts - 1u));
+	uvec3 lower = min(uvec3(grid_pos), counts - 1u);
+	uvec3 upper = min(lower + 1u, counts - 1u);
+	vec3 t = grid_pos - vec3(lower);
+	vec3 irradiance = vec3(0.0);
+	for (uint corner = 0u; corner < 8u; corner++)
+	{
+		bvec3 is_upper = bvec3((corner & 1u) != 0u, (corner & 2u) != 0u, (corner & 4u) != 0u);
+		uvec3 cell = mix(lower, upper, is_upper);
+		vec3 weights = mix(1.0 - t, t, is_upper);
+		uint probe = cell.x + counts.x * (cell.y + counts.y * cell.z);
+		vec3 probe_irradiance = vec3(0.0);
+		for (uint i = 0u; i < 9u; i++)
+		{
+			probe_irradiance += IrradianceProbes.probes[probe].sh[i].rgb * basis[i];
+		}
+		irradiance += weights.x * weights.y * weights.z * probe_irradiance;
+	}
+	return max(irradiance, vec3(0.0));
+}
+
+// Returns the color reflected in the given world space direction, blending the two nearest probes whose volumes
+// contain the fragment. Each probe fades out towards the edges of its volume.
+vec3 probe_reflection(vec3 R)
+{
+	uint nearest[2] = uint[2](MAX_REFLECTION_PROBES, MAX_REFLECTION_PROBES);
+	float nearest_distance[2] = float[2](1e30, 1e30);
+	for (uint i = 0; i < min(Frame.probe_count, MAX_REFLECTION_PROBES); i++)
 	{
-		// Check distance and calculate attenuation
-		if (length(worldspace_lightdir) > light.radius)
+		vec3 local = (worldspace_pos - Frame.probes[i].position.xyz) / Frame.probes[i].extents.xyz;
+		if (any(greaterThan(abs(local), vec3(1.0))))
 			continue;
-		vec3 L_div_r = worldspace_lightdir / light.radius;
-		float attenuation = max(1.0 - dot(L_div_r, L_div_r), 0.0);
+		float probe_distance = distance(worldspace_pos, Frame.probes[i].position.xyz);
+		if (probe_distance < nearest_distance[0])
+		{
+			nearest[1] = nearest[0];
+			nearest_distance[1] = nearest_distance[0];
+			nearest[0] = i;
+			nearest_distance[0] = probe_distance;
+		}
+		else if (probe_distance < nearest_distance[1])
+		{
+			nearest[1] = i;
+			nearest_distance[1] = probe_distance;
+		}
+	}
 
-		// Set up phong variables
-		vec3 L = normalize(tangentspace_lightdir);
-		// Look up the normal
-		vec3 normal = texture(normal_tex, tex_uv).rgb;
+	vec3 reflection = vec3(0.0);
+	float total_weight = 0.0;
+	for (uint j = 0; j < 2u; j++)
+	{
+		if (nearest[j] == MAX_REFLECTION_PROBES)
+			continue;
+		vec3 center = Frame.probes[nearest[j]].position.xyz;
+		vec3 extents = Frame.probes[nearest[j]].extents.xyz;
+		vec3 local = abs(worldspace_pos - center) / extents;
+		float weight = clamp(4.0 * (1.0 - max(local.x, max(local.y, local.z))), 0.0, 1.0);
+
+		// Project the reflection onto the volume, so nearby walls reflect at the right place
+		vec3 to_max = (center + extents - worldspace_pos) / R;
+		vec3 to_min = (center - extents - worldspace_pos) / R;
+		vec3 exit = max(to_max, to_min);
+		float exit_distance = min(exit.x, min(exit.y, exit.z));
+		vec3 direction = worldspace_pos + R * exit_distance - center;
+
+		reflection += weight * sample_probe(nearest[j], direction);
+		total_weight += weight;
+	}
+	// Only normalized where the probes overlap, so a lone probe still fades out
+	return reflection / max(total_weight, 1.0);
+}
+
+// Returns the texture coordinates scrolled, and moved to the current frame of the flipbook, if any. The scale of the
+// coordinates is returned for their gradients, which jump where the frame repeats.
+vec2 animate_uv(MaterialParams params, out vec2 scale)
+{
+	vec2 uv = tex_uv + params.uv_scroll * Frame.time;
+	vec2 grid = params.flipbook.xy;
+	float frame_count = grid.x * grid.y;
+	scale = vec2(1.0);
+	if (params.flipbook.z <= 0.0 || frame_count <= 1.0)
+		return uv;
+	float frame = mod(floor(Frame.time * params.flipbook.z), frame_count);
+	vec2 cell = vec2(mod(frame, grid.x), floor(frame / grid.x));
+	scale = 1.0 / grid;
+	return (cell + fract(uv)) * scale;
+}
+
+void main()
+{
+	MaterialParams params = MaterialParams(
+		Material.tint, Material.emissive, Material.specular, Material.uv_scroll, Material.flipbook);
+	if (INDIRECT)
+	{
+		params = Materials.materials[material_index];
+	}
+	vec2 uv_scale;
+	vec2 uv = animate_uv(params, uv_scale);
+	vec2 uv_dx = dFdx(tex_uv) * uv_scale;
+	vec2 uv_dy = dFdy(tex_uv) * uv_scale;
+
+	vec3 color = vec3(0.0);
+	vec4 texsample = textureGrad(color_tex, uv, uv_dx, uv_dy);
+	vec3 texcolor = texsample.rgb * params.tint.rgb;
+	// Damage stays where it was baked, regardless of scrolling and flipbooks
+	vec4 damage = texture(damage_tex, tex_uv);
+	texcolor = mix(texcolor, damage.rgb, damage.a);
+	// Wet surfaces are darker and shinier
+	texcolor *= mix(1.0, 0.6, Frame.wetness);
+	float shininess = mix(50.0, 120.0, Frame.wetness);
+	float specular_strength = mix(1.0, 2.5, Frame.wetness) * params.specular * (1.0 - 0.7 * damage.a);
+	// Look up the normal, or use the surface normal
+	vec3 N = vec3(0.0, 0.0, 1.0);
+	if (NORMAL_MAPPING)
+	{
+		vec3 normal = textureGrad(normal_tex, uv, uv_dx, uv_dy).rgb;
 		// Flip y-value from top left to bottom left
 		normal.g = 1.0 - normal.g;
 		// Move normal it from [0,1] to [-1, 1]
-		vec3 N = normalize(2.0 * normal - 1.0);
+		N = normalize(2.0 * normal - 1.0);
+	}
+	vec3 V = normalize(tangentspace_eyedir);
+	// for each light
+	for (uint i = 0; i < (DEFERRED ? 0u : min(Frame.light_count, MAX_LIGHTS)); i++)
+	{
+		vec3 light_position = Frame.lights[i].position_radius.xyz;
+		float light_radius = Frame.lights[i].position_radius.w;
+		vec3 light_color = Frame.lights[i].color.rgb;
+		vec3 worldspace_lightdir = light_position;
+		float attenuation = 1.0;
+
+		// Directional lights are not attenuated, nor shadowed
+		if (light_radius > 0.0)
+		{
+			worldspace_lightdir = light_position - worldspace_pos;
+
+			// Check distance and calculate attenuation
+			if (length(worldspace_lightdir) > light_radius)
+				continue;
+			vec3 L_div_r = worldspace_lightdir / light_radius;
+			attenuation = max(1.0 - dot(L_div_r, L_div_r), 0.0);
+			attenuation *= shadow(shadow_atlas, Frame.lights[i].shadow_tile, -worldspace_lightdir, light_radius);
+			if (attenuation == 0.0)
+				continue;
+		}
+
+		// Set up phong variables
+		vec3 L = normalize(TBN * vec3(Frame.v * vec4(worldspace_lightdir, 0.0)));
 
 		float lambertian = max(dot(L, N), 0.0);
 		float specular = 0.0;
 
 		if (lambertian > 0.0)
 		{
-			vec3 V = normalize(tangentspace_eyedir);
 			vec3 R = normalize(reflect(-L, N));
-			specular = pow(max(dot(R, V), 0.0), 50.0);
+			specular = specular_strength * pow(max(dot(R, V), 0.0), shininess);
 		}
 
 		// Diffuse
-		color += texcolor * lambertian * light.color * attenuation;
+		color += texcolor * lambertian * light_color * attenuation;
 
 		// Specular
-		color += specular * light.color * attenuation;
+		color += specular * light_color * attenuation;
+	}
+
+	// Tangent to view space is the transpose of TBN, and view to world space the transpose of the view rotation
+	mat3 tangent_to_world = transpose(mat3(Frame.v)) * transpose(TBN);
+
+	vec3 worldspace_N = normalize(tangent_to_world * N);
+
+	// Bounce light
+	color += texcolor * grid_irradiance(worldspace_N);
+
+	// Reflections, stronger on wet surfaces
+	vec3 tangentspace_R = reflect(-V, N);
+	vec3 worldspace_R = normalize(tangent_to_world * tangentspace_R);
+	float reflectivity = mix(0.1, 0.4, Frame.wetness) * params.specular;
+	color += reflectivity * probe_reflection(worldspace_R);
+
+	if (EMISSIVE)
+	{
+		color += params.emissive.rgb;
+	}
+	// The opacity is in the alpha of the tint
+	fragColor = vec4(color, texsample.a * params.tint.a);
+	if (DEFERRED)
+	{
+		// The alpha of the normal marks the pixels with a surface to light
+		gbuffer_albedo = vec4(texcolor, specular_strength);
+		gbuffer_normal = vec4(worldspace_N, 1.0);
+		gbuffer_position = vec4(worldspace_pos, 0.0);
+	}
+
+	if (Frame.count_fragments != 0u)
+	{
+		imageAtomicAdd(fragment_counts, ivec2(gl_FragCoord.xy), BLENDED ? 0x10001u : 1u);
 	}
-	fragColor = color;
 }
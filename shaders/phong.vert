@@ -12,42 +12,103 @@ layout(push_constant) uniform MatrixBlock {
 	mat4 mvp;
 } Matrices;
 
-layout(set = 1, binding = 0) uniform ViewMatrixBlock {
-       mat4 v;
-} ViewMatrix;
+layout(set = 1, binding = 0) uniform FrameBlock {
+	mat4 v;
+	float wetness;
+} Frame;
 
-layout(location = 0) out vec3 tangentspace_eyedir;
-layout(location = 1) out vec3 worldspace_lightdir;
-layout(location = 2) out vec3 tangentspace_lightdir;
-layout(location = 3) out vec2 interpolated_tex_uv;
+// Indirect draws read their matrices and material from the draws buffer, at the index given as first instance
+layout(constant_id = 0) const bool INDIRECT = false;
+
+struct DrawData {
+	mat4 m;
+	mat4 mvp;
+	uint material;
+};
+
+layout(std430, set = 2, binding = 0) readonly buffer DrawsBlock {
+	DrawData draws[];
+} Draws;
+
+// Morph targets of the mesh, one target after the other, and the blend weights of the object. Meshes drawn without
+// morphing have no targets.
+const uint MAX_MORPH_TARGETS = 8;
 
-vec3 worldspace_lightpos = vec3(0.0, 5.0, 20.0);
+struct MorphDelta {
+	vec4 position;
+	vec4 normal;
+};
+
+layout(std430, set = 3, binding = 0) readonly buffer MorphDeltasBlock {
+	MorphDelta deltas[];
+} MorphDeltas;
+
+layout(set = 3, binding = 1) uniform MorphWeightsBlock {
+	uint target_count;
+	uint vertex_count;
+	vec4 weights[MAX_MORPH_TARGETS / 4];
+} Morph;
+
+layout(location = 0) out vec3 tangentspace_eyedir;
+layout(location = 1) out vec3 worldspace_pos;
+// View space to tangent space, for the light directions
+layout(location = 2) out mat3 TBN;
+layout(location = 5) out vec2 interpolated_tex_uv;
+layout(location = 6) flat out uint material_index;
+// The depth prepass draws with this shader too, and the shaded surfaces must land on the same depth
+invariant gl_Position;
 
 void main()
 {
-	mat4 mv_matrix = ViewMatrix.v * Matrices.m;
+	mat4 m_matrix = Matrices.m;
+	mat4 mvp_matrix = Matrices.mvp;
+	material_index = 0;
+	if (INDIRECT)
+	{
+		DrawData draw = Draws.draws[gl_InstanceIndex];
+		m_matrix = draw.m;
+		mvp_matrix = draw.mvp;
+		material_index = draw.material;
+	}
+
+	// Blend in the morph targets with a weight
+	vec3 morphed_position = position;
+	vec3 morphed_normal = normal;
+	for (uint i = 0; i < Morph.target_count; i++)
+	{
+		float weight = Morph.weights[i / 4][i % 4];
+		if (weight != 0.0)
+		{
+			MorphDelta delta = MorphDeltas.deltas[i * Morph.vertex_count + gl_VertexIndex];
+			morphed_position += weight * delta.position.xyz;
+			morphed_normal += weight * delta.normal.xyz;
+		}
+	}
+	if (Morph.target_count > 0)
+	{
+		morphed_normal = normalize(morphed_normal);
+	}
+
+	mat4 mv_matrix = Frame.v * m_matrix;
 	// normal, tangent and bitanget are vectors, set w to 0.0
-	vec3 viewspace_normal = vec3(mv_matrix * vec4(normal, 0.0));
+	vec3 viewspace_normal = vec3(mv_matrix * vec4(morphed_normal, 0.0));
 	vec3 viewspace_tangent = vec3(mv_matrix * vec4(tangent, 0.0));
 	vec3 viewspace_bitangent = vec3(mv_matrix * vec4(bitangent, 0.0));
 
 	// calculate the tangent space matrix
-	mat3 TBN = transpose(mat3(viewspace_tangent, viewspace_bitangent, viewspace_normal));
+	TBN = transpose(mat3(viewspace_tangent, viewspace_bitangent, viewspace_normal));
 
 	// position is a point, set w to 1.0 and divide it out afterwards
-	vec4 worldspace_pos4 = Matrices.m * vec4(position, 1.0);
-	vec3 worldspace_pos = vec3(worldspace_pos4) / worldspace_pos4.w;
+	vec4 worldspace_pos4 = m_matrix * vec4(morphed_position, 1.0);
+	worldspace_pos = vec3(worldspace_pos4) / worldspace_pos4.w;
 
-	// calculate eyedir and lightdir in tangent space
-	vec4 viewspace_pos4 = mv_matrix * vec4(position, 1.0);
+	// calculate eyedir in tangent space, the light directions are calculated per light
+	vec4 viewspace_pos4 = mv_matrix * vec4(morphed_position, 1.0);
 	vec3 viewspace_pos = vec3(viewspace_pos4) / viewspace_pos4.w;
 	tangentspace_eyedir = TBN * (-viewspace_pos);
-	worldspace_lightdir = (worldspace_lightpos - worldspace_pos);
-	vec3 viewspace_lightdir = vec3(ViewMatrix.v * vec4(worldspace_lightdir, 0.0));
-	tangentspace_lightdir = TBN * viewspace_lightdir;
 
 	// interpolate texture coordinates
 	interpolated_tex_uv = tex_uv;
 
-	gl_Position = Matrices.mvp * vec4(position, 1.0);
+	gl_Position = mvp_matrix * vec4(morphed_position, 1.0);
 }
@@ -0,0 +1,11 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+precision highp float;
+
+layout(location = 0) in vec3 lightspace_pos;
+
+void main()
+{
+	// Store the distance to the light over its radius, so all faces of the cube map share the same depth
+	gl_FragDepth = length(lightspace_pos);
+}
@@ -0,0 +1,20 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 position;
+
+layout(push_constant) uniform MatrixBlock {
+	mat4 mvp;
+	// Model to light space, scaled so the light radius is 1
+	mat4 light_m;
+} Matrices;
+
+layout(location = 0) out vec3 lightspace_pos;
+
+void main()
+{
+	vec4 lightspace_pos4 = Matrices.light_m * vec4(position, 1.0);
+	lightspace_pos = vec3(lightspace_pos4) / lightspace_pos4.w;
+
+	gl_Position = Matrices.mvp * vec4(position, 1.0);
+}
@@ -0,0 +1,48 @@
+// Point light shadows in the shadow atlas, shared by phong.frag, deferred_lighting.frag and volumetric_fog.comp. Each
+// shadowed light has a tile of 3x2 cube map faces in the atlas, in the order of the cube map layers, see shadowpass.rs.
+// The atlas stores the distance to the closest occluder over the light radius, see shadow.frag.
+
+// Returns the cube map face the direction points at in z, and the coordinates on the face in xy, like sampling a cube
+// map does
+vec3 cube_face_coords(vec3 direction)
+{
+	vec3 a = abs(direction);
+	float face;
+	float major;
+	vec2 coords;
+	if (a.x >= a.y && a.x >= a.z)
+	{
+		face = direction.x > 0.0 ? 0.0 : 1.0;
+		major = a.x;
+		coords = vec2(direction.x > 0.0 ? -direction.z : direction.z, -direction.y);
+	}
+	else if (a.y >= a.z)
+	{
+		face = direction.y > 0.0 ? 2.0 : 3.0;
+		major = a.y;
+		coords = vec2(direction.x, direction.y > 0.0 ? direction.z : -direction.z);
+	}
+	else
+	{
+		face = direction.z > 0.0 ? 4.0 : 5.0;
+		major = a.z;
+		coords = vec2(direction.z > 0.0 ? direction.x : -direction.x, -direction.y);
+	}
+	return vec3(0.5 * (coords / major + 1.0), face);
+}
+
+// Returns 0 if the position is shadowed from the light, 1 otherwise. The tile holds the atlas texel of its top left
+// corner in xy and the face resolution in z, which is 0 if the light has no shadows.
+float shadow(sampler2D shadow_atlas, vec4 tile, vec3 light_to_position, float radius)
+{
+	if (tile.z <= 0.0)
+		return 1.0;
+	// Grows with distance and the texel size, as the shadow map texels do
+	float bias = 0.02 + 10.0 * length(light_to_position) / tile.z;
+	float depth = (length(light_to_position) - bias) / radius;
+	vec3 face_coords = cube_face_coords(light_to_position);
+	vec2 face_offset = vec2(mod(face_coords.z, 3.0), floor(face_coords.z / 3.0)) * tile.z;
+	vec2 texel = tile.xy + face_offset + min(face_coords.xy * tile.z, tile.z - 1.0);
+	float occluder = texelFetch(shadow_atlas, ivec2(texel), 0).r;
+	return depth > occluder ? 0.0 : 1.0;
+}
@@ -0,0 +1,414 @@
+use crate::core::{Console, Rng, Volumes};
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus};
+use sdl2::AudioSubsystem;
+use std::f32::consts::PI;
+
+const SAMPLE_RATE: i32 = 44_100;
+const BUFFER_SAMPLES: u16 = 1_024;
+// Fraction of the way to the target gain and frequency moved per sample, to avoid clicks when they change
+const SMOOTHING: f32 = 0.002;
+// Sounds closer than this are not attenuated, in meters
+const REFERENCE_DISTANCE: f32 = 5.0;
+const SQUEAL_WOBBLE_HZ: f32 = 7.0;
+const IMPACT_DURATION: f32 = 0.6;
+const IMPACT_THUMP_HZ: f32 = 60.0;
+const IMPACT_NOISE_CUTOFF: f32 = 2_500.0;
+const MAX_IMPACTS: usize = 16;
+
+/// Procedural sound sources for looping voices.
+#[derive(Clone, Copy)]
+pub enum Waveform
+{
+	/// Engine harmonics, the frequency is the firing rate of the cylinders.
+	Engine,
+	/// Low-pass filtered noise, the frequency is the cutoff.
+	Rumble,
+	/// Wobbling tone, like tires sliding on asphalt.
+	Squeal,
+}
+
+/// Groups of sounds mixed with their own volume, under the master volume.
+// Nothing plays music or menu sounds yet, but their volumes can already be set
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Bus
+{
+	Music,
+	/// Sounds of the world, like the car.
+	Sfx,
+	/// Sounds of the menus and overlays.
+	Ui,
+}
+
+/// Handle to a looping voice, see AudioSystem::play_loop().
+#[derive(Clone, Copy)]
+pub struct VoiceId(usize);
+
+/// Coefficient of a one-pole low-pass filter with the given cutoff.
+fn low_pass_coefficient(cutoff: f32, sample_rate: f32) -> f32
+{
+	return 1.0 - (-2.0 * PI * cutoff / sample_rate).exp();
+}
+
+#[derive(Clone)]
+struct Voice
+{
+	waveform: Waveform,
+	bus: Bus,
+	frequency: f32,
+	target_frequency: f32,
+	gains: [f32; 2],
+	target_gains: [f32; 2],
+	phase: f32,
+	wobble_phase: f32,
+	// Low-pass filter state
+	filtered: f32,
+}
+
+impl Voice
+{
+	fn next_sample(&mut self, sample_rate: f32, rng: &mut Rng) -> f32
+	{
+		self.frequency += (self.target_frequency - self.frequency) * SMOOTHING;
+		match self.waveform
+		{
+			Waveform::Engine =>
+			{
+				self.phase = (self.phase + self.frequency / sample_rate).fract();
+				let angle = 2.0 * PI * self.phase;
+				// A bit of noise makes it sound less like an organ
+				return 0.5 * angle.sin() +
+					0.3 * (2.0 * angle).sin() +
+					0.2 * (3.0 * angle).sin() +
+					rng.range(-0.05, 0.05);
+			}
+			Waveform::Rumble =>
+			{
+				self.filtered +=
+					(rng.range(-1.0, 1.0) - self.filtered) * low_pass_coefficient(self.frequency, sample_rate);
+				return self.filtered;
+			}
+			Waveform::Squeal =>
+			{
+				self.wobble_phase = (self.wobble_phase + SQUEAL_WOBBLE_HZ / sample_rate).fract();
+				let wobble = 1.0 + 0.03 * (2.0 * PI * self.wobble_phase).sin();
+				self.phase = (self.phase + self.frequency * wobble / sample_rate).fract();
+				return (2.0 * PI * self.phase).sin();
+			}
+		}
+	}
+}
+
+/// A one-shot burst of noise with a low thump, fading out over IMPACT_DURATION. Played on the effects bus.
+#[derive(Clone)]
+struct Impact
+{
+	gains: [f32; 2],
+	time: f32,
+	filtered: f32,
+}
+
+impl Impact
+{
+	fn next_sample(&mut self, sample_rate: f32, rng: &mut Rng) -> f32
+	{
+		self.filtered +=
+			(rng.range(-1.0, 1.0) - self.filtered) * low_pass_coefficient(IMPACT_NOISE_CUTOFF, sample_rate);
+		let noise = self.filtered * (-self.time * 20.0).exp();
+		let thump = (2.0 * PI * IMPACT_THUMP_HZ * self.time).sin() * (-self.time * 8.0).exp();
+		self.time += 1.0 / sample_rate;
+		return noise + thump;
+	}
+}
+
+/// Mixes all voices, run by SDL on the audio thread.
+#[derive(Clone)]
+struct Mixer
+{
+	sample_rate: f32,
+	voices: Vec<Voice>,
+	impacts: Vec<Impact>,
+	rng: Rng,
+	// Volumes of the buses in the order of Bus, including the master volume, smoothed like the voice gains
+	bus_gains: [f32; 3],
+	target_bus_gains: [f32; 3],
+}
+
+impl AudioCallback for Mixer
+{
+	type Channel = f32;
+
+	/// Fills the interleaved stereo buffer.
+	fn callback(&mut self, out: &mut [f32])
+	{
+		let sample_rate = self.sample_rate;
+		let rng = &mut self.rng;
+		for frame in out.chunks_mut(2)
+		{
+			for (gain, target) in self.bus_gains.iter_mut().zip(self.target_bus_gains.iter())
+			{
+				*gain += (target - *gain) * SMOOTHING;
+			}
+			let mut mix = [0.0; 2];
+			for voice in self.voices.iter_mut()
+			{
+				let sample = voice.next_sample(sample_rate, rng) * self.bus_gains[voice.bus as usize];
+				for channel in 0..2
+				{
+					voice.gains[channel] += (voice.target_gains[channel] - voice.gains[channel]) * SMOOTHING;
+					mix[channel] += sample * voice.gains[channel];
+				}
+			}
+			for impact in self.impacts.iter_mut()
+			{
+				let sample = impact.next_sample(sample_rate, rng) * self.bus_gains[Bus::Sfx as usize];
+				for channel in 0..2
+				{
+					mix[channel] += sample * impact.gains[channel];
+				}
+			}
+			for (output, value) in frame.iter_mut().zip(mix.iter())
+			{
+				*output = value.max(-1.0).min(1.0);
+			}
+		}
+		self.impacts.retain(|impact| impact.time < IMPACT_DURATION);
+	}
+}
+
+/// Plays procedurally generated sounds, positioned relative to a listener and mixed by Bus.
+///
+/// Looping voices are started once and then updated every frame, one-shot sounds play until they fade out. The default
+/// audio device is opened again when devices are plugged in or out, carrying the voices over, see
+/// handle_device_change(). Without an audio device, voices are kept silently until one appears.
+pub struct AudioSystem
+{
+	audio: Option<AudioSubsystem>,
+	device: Option<AudioDevice<Mixer>>,
+	// The mixer while there is no device, so the voices carry over to the next one
+	idle_mixer: Option<Mixer>,
+	// Playback devices when the device was last opened, to notice when they change
+	device_count: Option<u32>,
+	volumes: Volumes,
+	listener_position: Point3<f32>,
+	listener_right: Vector3<f32>,
+}
+
+impl AudioSystem
+{
+	/// Opens the default audio device, and registers the volume cvars, set to the given volumes.
+	pub fn new(sdl_context: &sdl2::Sdl, rng: Rng, console: &mut Console, volumes: &Volumes) -> AudioSystem
+	{
+		console.register_float("volume_master", 1.0, "Volume of all sounds [0, 1]");
+		console.register_float("volume_music", 1.0, "Volume of the music [0, 1]");
+		console.register_float("volume_sfx", 1.0, "Volume of the sound effects [0, 1]");
+		console.register_float("volume_ui", 1.0, "Volume of the menu sounds [0, 1]");
+		// The volumes are kept in the Config, which wins over saved cvar values
+		console.set_value("volume_master", volumes.master);
+		console.set_value("volume_music", volumes.music);
+		console.set_value("volume_sfx", volumes.sfx);
+		console.set_value("volume_ui", volumes.ui);
+
+		let audio = match sdl_context.audio()
+		{
+			Ok(audio) => Some(audio),
+			Err(e) =>
+			{
+				warning!("Unable to initialize audio, sound is disabled: {}", e);
+				None
+			}
+		};
+		let bus_gains = AudioSystem::get_bus_gains(volumes);
+		let mut audio_system = AudioSystem {
+			audio: audio,
+			device: None,
+			idle_mixer: Some(Mixer {
+				sample_rate: SAMPLE_RATE as f32,
+				voices: Vec::new(),
+				impacts: Vec::new(),
+				rng: rng,
+				bus_gains: bus_gains,
+				target_bus_gains: bus_gains,
+			}),
+			device_count: None,
+			volumes: *volumes,
+			listener_position: Point3::new(0.0, 0.0, 0.0),
+			listener_right: Vector3::unit_x(),
+		};
+		audio_system.open_device();
+		return audio_system;
+	}
+
+	/// Opens the default playback device, moving the voices over to it. They stay idle if no device can be opened.
+	fn open_device(&mut self)
+	{
+		let (audio, mixer) = match (&self.audio, &self.idle_mixer)
+		{
+			(Some(audio), Some(mixer)) => (audio, mixer.clone()),
+			_ => return,
+		};
+		let desired_spec = AudioSpecDesired {
+			freq: Some(SAMPLE_RATE),
+			channels: Some(2),
+			samples: Some(BUFFER_SAMPLES),
+		};
+		// Impacts from before the device are over by now
+		let device = audio.open_playback(None, &desired_spec, |spec| Mixer {
+			sample_rate: spec.freq as f32,
+			impacts: Vec::new(),
+			..mixer
+		});
+		match device
+		{
+			Ok(device) =>
+			{
+				device.resume();
+				self.device = Some(device);
+				self.idle_mixer = None;
+			}
+			Err(e) => warning!("Unable to open audio device, sound is disabled until one is plugged in: {}", e),
+		}
+		self.device_count = audio.num_audio_playback_devices();
+	}
+
+	fn close_device(&mut self)
+	{
+		if let Some(device) = self.device.take()
+		{
+			self.idle_mixer = Some(device.close_and_get_callback());
+		}
+	}
+
+	/// Follows audio devices being plugged in or out, called for the audio device events of SDL. The default device is
+	/// opened again if the playback devices changed, as the default might have changed with them, or if the device in
+	/// use is gone.
+	pub fn handle_device_change(&mut self)
+	{
+		let device_count = match &self.audio
+		{
+			Some(audio) => audio.num_audio_playback_devices(),
+			None => return,
+		};
+		let lost = self.device.as_ref().map_or(false, |device| device.status() == AudioStatus::Stopped);
+		if device_count == self.device_count && !lost
+		{
+			return;
+		}
+		self.close_device();
+		self.open_device();
+	}
+
+	/// Applies changes to the volume cvars. Returns the new volumes if they changed, so they can be saved.
+	pub fn update_volumes(&mut self, console: &Console) -> Option<Volumes>
+	{
+		let get_volume = |name: &str| console.get_float(name).max(0.0).min(1.0);
+		let volumes = Volumes {
+			master: get_volume("volume_master"),
+			music: get_volume("volume_music"),
+			sfx: get_volume("volume_sfx"),
+			ui: get_volume("volume_ui"),
+		};
+		if volumes == self.volumes
+		{
+			return None;
+		}
+		self.volumes = volumes;
+		let bus_gains = AudioSystem::get_bus_gains(&volumes);
+		self.with_mixer(|mixer| mixer.target_bus_gains = bus_gains);
+		return Some(volumes);
+	}
+
+	/// Returns the gains of the buses in the order of Bus, including the master volume.
+	fn get_bus_gains(volumes: &Volumes) -> [f32; 3]
+	{
+		return [volumes.master * volumes.music, volumes.master * volumes.sfx, volumes.master * volumes.ui];
+	}
+
+	/// Calls the function with the mixer, on the device or idle.
+	fn with_mixer<R>(&mut self, function: impl FnOnce(&mut Mixer) -> R) -> R
+	{
+		match (self.device.as_mut(), self.idle_mixer.as_mut())
+		{
+			(Some(device), _) => return function(&mut device.lock()),
+			(None, Some(mixer)) => return function(mixer),
+			// There is always one or the other
+			(None, None) => unreachable!(),
+		}
+	}
+
+	/// Sets where sounds are heard from, usually the camera.
+	pub fn set_listener(&mut self, position: Point3<f32>, right: Vector3<f32>)
+	{
+		self.listener_position = position;
+		self.listener_right = right;
+	}
+
+	/// Returns the left and right gains of a sound at the given position, attenuated by distance and panned.
+	fn get_gains(&self, volume: f32, position: Point3<f32>) -> [f32; 2]
+	{
+		let offset = position - self.listener_position;
+		let distance = offset.magnitude();
+		let attenuation = REFERENCE_DISTANCE / distance.max(REFERENCE_DISTANCE);
+		let pan = if distance > 0.0
+		{
+			offset.dot(self.listener_right) / distance
+		}
+		else
+		{
+			0.0
+		};
+		// Equal power panning
+		let angle = 0.25 * PI * (pan + 1.0);
+		return [volume * attenuation * angle.cos(), volume * attenuation * angle.sin()];
+	}
+
+	/// Starts a looping voice on the given bus. It is silent until set with set_voice().
+	pub fn play_loop(&mut self, waveform: Waveform, bus: Bus) -> VoiceId
+	{
+		return self.with_mixer(|mixer| {
+			mixer.voices.push(Voice {
+				waveform: waveform,
+				bus: bus,
+				frequency: 0.0,
+				target_frequency: 0.0,
+				gains: [0.0; 2],
+				target_gains: [0.0; 2],
+				phase: 0.0,
+				wobble_phase: 0.0,
+				filtered: 0.0,
+			});
+			return VoiceId(mixer.voices.len() - 1);
+		});
+	}
+
+	/// Sets the frequency of a looping voice, see Waveform, and its volume and position in the world.
+	pub fn set_voice(&mut self, voice: VoiceId, frequency: f32, volume: f32, position: Point3<f32>)
+	{
+		let gains = self.get_gains(volume, position);
+		self.with_mixer(|mixer| {
+			let voice = &mut mixer.voices[voice.0];
+			voice.target_frequency = frequency;
+			voice.target_gains = gains;
+		});
+	}
+
+	/// Plays an impact sound at the given position. Volume is in the range [0, 1].
+	pub fn play_impact(&mut self, volume: f32, position: Point3<f32>)
+	{
+		let gains = self.get_gains(volume, position);
+		if let Some(device) = self.device.as_mut()
+		{
+			let mut mixer = device.lock();
+			if mixer.impacts.len() < MAX_IMPACTS
+			{
+				mixer.impacts.push(Impact {
+					gains: gains,
+					time: 0.0,
+					filtered: 0.0,
+				});
+			}
+		}
+	}
+}
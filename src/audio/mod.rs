@@ -0,0 +1,5 @@
+mod mixer;
+mod vehicle;
+
+pub use self::mixer::{AudioSystem, Bus, VoiceId, Waveform};
+pub use self::vehicle::VehicleAudio;
@@ -0,0 +1,62 @@
+use crate::audio::{AudioSystem, Bus, VoiceId, Waveform};
+use cgmath::Point3;
+
+const ENGINE_CYLINDERS: f32 = 4.0;
+const ENGINE_VOLUME: f32 = 0.3;
+const REDLINE_RPM: f32 = 7_000.0;
+const ROLLING_VOLUME: f32 = 0.25;
+// Speed at which the tire rolling noise is at its loudest, in m/s
+const ROLLING_FULL_SPEED: f32 = 40.0;
+const SQUEAL_VOLUME: f32 = 0.15;
+const SQUEAL_FREQUENCY: f32 = 900.0;
+// Sideways slip, in m/s, where the tires start squealing and where they are at their loudest
+const SQUEAL_MIN_SLIP: f32 = 2.0;
+const SQUEAL_FULL_SLIP: f32 = 10.0;
+// Collision impulse, in Ns, giving the loudest impact sound
+const LOUDEST_IMPACT: f32 = 2_000.0;
+
+/// Engine, tire and impact sounds of a vehicle.
+pub struct VehicleAudio
+{
+	engine: VoiceId,
+	rolling: VoiceId,
+	squeal: VoiceId,
+}
+
+impl VehicleAudio
+{
+	pub fn new(audio: &mut AudioSystem) -> VehicleAudio
+	{
+		VehicleAudio {
+			engine: audio.play_loop(Waveform::Engine, Bus::Sfx),
+			rolling: audio.play_loop(Waveform::Rumble, Bus::Sfx),
+			squeal: audio.play_loop(Waveform::Squeal, Bus::Sfx),
+		}
+	}
+
+	/// Updates the looping sounds from the vehicle state. Speed and sideways slip of the tires are in m/s.
+	pub fn update(&self, audio: &mut AudioSystem, position: Point3<f32>, rpm: f32, speed: f32, slip: f32)
+	{
+		// Four-stroke engines fire each cylinder every other revolution
+		let firing_rate = rpm / 60.0 * 0.5 * ENGINE_CYLINDERS;
+		let engine_volume = ENGINE_VOLUME * (0.5 + 0.5 * (rpm / REDLINE_RPM).min(1.0));
+		audio.set_voice(self.engine, firing_rate, engine_volume, position);
+
+		// Rolling noise gets louder and brighter with speed
+		let rolling = (speed / ROLLING_FULL_SPEED).min(1.0);
+		audio.set_voice(self.rolling, 200.0 + 1_500.0 * rolling, ROLLING_VOLUME * rolling, position);
+
+		let squeal = ((slip - SQUEAL_MIN_SLIP) / (SQUEAL_FULL_SLIP - SQUEAL_MIN_SLIP)).max(0.0).min(1.0);
+		audio.set_voice(self.squeal, SQUEAL_FREQUENCY * (0.9 + 0.2 * squeal), SQUEAL_VOLUME * squeal, position);
+	}
+
+	/// Plays an impact sound, scaled by the collision impulse in Ns.
+	pub fn play_impact(&self, audio: &mut AudioSystem, position: Point3<f32>, impulse: f32)
+	{
+		let volume = (impulse / LOUDEST_IMPACT).min(1.0);
+		if volume > 0.01
+		{
+			audio.play_impact(volume, position);
+		}
+	}
+}
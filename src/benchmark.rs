@@ -0,0 +1,288 @@
+//! Benchmark mode, run with --benchmark to measure the engine on a standardized scene and compare drivers and changes.
+
+use crate::core::{unversioned_to_v1, Config, FileFormat, VertexFormat};
+use crate::renderer::{MainPass, PresentPass, RenderState};
+use ash::vk;
+use cgmath::Point3;
+use serde_derive::Serialize;
+use std::f32::consts::PI;
+use std::ffi::CStr;
+use std::time::Duration;
+
+/// The gallery scene benchmarked, shadowed point lights around a reflection probe at night.
+pub const SCENE: &str = "lighting";
+/// Console lines applied after loading the scene, so the camera follows the path alone.
+pub const CONSOLE_LINES: &[&str] = &["cam_mode free"];
+pub const DEFAULT_DURATION: f32 = 30.0;
+pub const DEFAULT_REPORT_PATH: &str = "benchmark.json";
+// Frames before the measurement starts, once the textures and probes are ready, for pipelines and caches to warm up
+const WARMUP_FRAMES: usize = 60;
+// The camera circles the center of the scene once during the benchmark, bobbing up and down twice
+const ORBIT_RADIUS: f32 = 14.0;
+const ORBIT_HEIGHT: f32 = 4.0;
+const ORBIT_BOB: f32 = 2.0;
+const ORBIT_TARGET: Point3<f32> = Point3 {
+	x: 0.0,
+	y: 1.5,
+	z: 0.0,
+};
+
+const REPORT_FORMAT: FileFormat = FileFormat {
+	name: "benchmark report",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+
+/// Summary of a series of times, in milliseconds.
+#[derive(Serialize)]
+struct TimeStats
+{
+	average: f32,
+	p50: f32,
+	p90: f32,
+	p99: f32,
+	max: f32,
+}
+
+impl TimeStats
+{
+	fn new(times: &[f32]) -> TimeStats
+	{
+		let mut sorted = times.to_vec();
+		sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		let percentile = |fraction: f32| match sorted.len()
+		{
+			0 => 0.0,
+			count => sorted[((count - 1) as f32 * fraction).round() as usize],
+		};
+		TimeStats {
+			average: sorted.iter().sum::<f32>() / sorted.len().max(1) as f32,
+			p50: percentile(0.5),
+			p90: percentile(0.9),
+			p99: percentile(0.99),
+			max: percentile(1.0),
+		}
+	}
+}
+
+/// How the benchmark was rendered, to tell whether two reports are comparable.
+#[derive(Serialize)]
+pub struct BenchmarkSettings
+{
+	pub gpu: String,
+	pub driver_version: u32,
+	pub vulkan_version: String,
+	pub render_width: u32,
+	pub render_height: u32,
+	pub window_width: u32,
+	pub window_height: u32,
+	pub msaa_samples: u32,
+	pub packed_vertices: bool,
+	/// False if the GPU has no present mode without vsync, so the frame rate is capped by the display.
+	pub vsync_off: bool,
+	pub random_seed: u64,
+}
+
+impl BenchmarkSettings
+{
+	pub fn new(rs: &RenderState, cfg: &Config, mp: &MainPass, pp: &PresentPass, random_seed: u64) -> BenchmarkSettings
+	{
+		let properties = rs.get_device_properties();
+		let gpu = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+		let render_extent = mp.get_render_extent();
+		let window_size = rs.window.size();
+		BenchmarkSettings {
+			gpu: gpu.to_string_lossy().to_string(),
+			driver_version: properties.driver_version,
+			vulkan_version: format!(
+				"{}.{}.{}",
+				vk::version_major(properties.api_version),
+				vk::version_minor(properties.api_version),
+				vk::version_patch(properties.api_version)
+			),
+			render_width: render_extent.width,
+			render_height: render_extent.height,
+			window_width: window_size.0,
+			window_height: window_size.1,
+			msaa_samples: rs.get_msaa_samples().as_raw(),
+			packed_vertices: cfg.packed_vertices && rs.get_packed_vertex_format() == VertexFormat::Packed,
+			vsync_off: !pp.has_vsync(rs),
+			random_seed: random_seed,
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct PassTimes
+{
+	name: String,
+	time: TimeStats,
+}
+
+#[derive(Serialize)]
+struct BenchmarkReport<'a>
+{
+	app_version: String,
+	scene: &'static str,
+	duration: f32,
+	frames: usize,
+	average_fps: f32,
+	frame_time: TimeStats,
+	/// Simulation, audio and asset updates of the frames.
+	cpu_update: TimeStats,
+	/// Recording and submitting the frames, including waiting for the GPU to present them.
+	cpu_render: TimeStats,
+	/// GPU time of each pass, added up over the times it ran in a frame. Empty if the GPU cannot time passes.
+	gpu_passes: Vec<PassTimes>,
+	settings: &'a BenchmarkSettings,
+}
+
+/// Flies the camera along a fixed path around the benchmark scene for a set time, and collects the frame times, the
+/// CPU times of updating and rendering and the GPU times of the passes.
+///
+/// Measuring starts once the scene is ready, after a few warmup frames, see record_frame().
+pub struct Benchmark
+{
+	duration: f32,
+	report_path: String,
+	// None until measuring has started, then the time measured so far
+	elapsed: Option<f32>,
+	warmup_frames: usize,
+	frame_times: Vec<f32>,
+	update_times: Vec<f32>,
+	render_times: Vec<f32>,
+	// In the order the passes first ran, with a time per measured frame
+	pass_times: Vec<(String, Vec<f32>)>,
+}
+
+impl Benchmark
+{
+	pub fn new(duration: f32, report_path: &str) -> Benchmark
+	{
+		Benchmark {
+			duration: duration.max(1.0),
+			report_path: report_path.to_string(),
+			elapsed: None,
+			warmup_frames: 0,
+			frame_times: Vec::new(),
+			update_times: Vec::new(),
+			render_times: Vec::new(),
+			pass_times: Vec::new(),
+		}
+	}
+
+	/// Returns the position and the target of the camera for the current point in the benchmark. The path starts over
+	/// when measuring starts.
+	pub fn get_camera(&self) -> (Point3<f32>, Point3<f32>)
+	{
+		let progress = self.elapsed.unwrap_or(0.0) / self.duration;
+		let angle = 2.0 * PI * progress;
+		let position = Point3::new(
+			ORBIT_TARGET.x + ORBIT_RADIUS * angle.cos(),
+			ORBIT_HEIGHT + ORBIT_BOB * (2.0 * angle).sin(),
+			ORBIT_TARGET.z + ORBIT_RADIUS * angle.sin(),
+		);
+		return (position, ORBIT_TARGET);
+	}
+
+	/// Records a rendered frame. Frames only count once the scene is ready, textures loaded and probes baked, and the
+	/// warmup frames have passed.
+	pub fn record_frame(
+		&mut self, ready: bool, frame_time: Duration, update_time: Duration, render_time: Duration,
+		gpu_timings: Vec<(String, f32)>,
+	)
+	{
+		let elapsed = match self.elapsed
+		{
+			Some(elapsed) => elapsed,
+			None =>
+			{
+				if ready
+				{
+					self.warmup_frames += 1;
+				}
+				if self.warmup_frames >= WARMUP_FRAMES
+				{
+					println!("Benchmark started, running for {} seconds", self.duration);
+					self.elapsed = Some(0.0);
+				}
+				return;
+			}
+		};
+		let milliseconds = |time: Duration| time.as_secs_f32() * 1000.0;
+		self.elapsed = Some(elapsed + frame_time.as_secs_f32());
+		self.frame_times.push(milliseconds(frame_time));
+		self.update_times.push(milliseconds(update_time));
+		self.render_times.push(milliseconds(render_time));
+
+		let frame = self.frame_times.len() - 1;
+		for (name, time) in gpu_timings
+		{
+			let idx = match self.pass_times.iter().position(|(timed, _)| *timed == name)
+			{
+				Some(idx) => idx,
+				None =>
+				{
+					self.pass_times.push((name, Vec::new()));
+					self.pass_times.len() - 1
+				}
+			};
+			// Passes that did not run in some frames, like probe bakes, count as taking no time there
+			let times = &mut self.pass_times[idx].1;
+			times.resize(frame, 0.0);
+			times.push(time);
+		}
+	}
+
+	/// Returns true once the benchmark has run for its duration.
+	pub fn is_finished(&self) -> bool
+	{
+		return self.elapsed.map_or(false, |elapsed| elapsed >= self.duration);
+	}
+
+	/// Writes the report, and prints a summary of it.
+	pub fn write_report(&self, app_version: String, settings: &BenchmarkSettings) -> Result<(), String>
+	{
+		let frames = self.frame_times.len();
+		let gpu_passes = self
+			.pass_times
+			.iter()
+			.map(|(name, times)| {
+				let mut times = times.clone();
+				times.resize(frames, 0.0);
+				PassTimes {
+					name: name.clone(),
+					time: TimeStats::new(&times),
+				}
+			})
+			.collect();
+		let report = BenchmarkReport {
+			app_version: app_version,
+			scene: SCENE,
+			duration: self.elapsed.unwrap_or(0.0),
+			frames: frames,
+			average_fps: frames as f32 / self.elapsed.unwrap_or(0.0).max(0.001),
+			frame_time: TimeStats::new(&self.frame_times),
+			cpu_update: TimeStats::new(&self.update_times),
+			cpu_render: TimeStats::new(&self.render_times),
+			gpu_passes: gpu_passes,
+			settings: settings,
+		};
+		REPORT_FORMAT.write(&self.report_path, &report)?;
+
+		println!(
+			"\nBenchmark: {} frames in {:.1} s, {:.1} FPS, frame time p50 {:.2} ms, p99 {:.2} ms. Wrote {}",
+			report.frames,
+			report.duration,
+			report.average_fps,
+			report.frame_time.p50,
+			report.frame_time.p99,
+			self.report_path
+		);
+		for pass in report.gpu_passes.iter()
+		{
+			println!("  {:<32} {:>7.3} ms", pass.name, pass.time.average);
+		}
+		return Ok(());
+	}
+}
@@ -0,0 +1,117 @@
+use image::RgbaImage;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Decoding is mostly waiting on memory and the disk, so a few threads are enough
+const MAX_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// An image being decoded by the AssetLoader.
+pub struct ImageRequest
+{
+	path: String,
+	receiver: Receiver<Result<RgbaImage, String>>,
+}
+
+impl ImageRequest
+{
+	/// Returns the decoded image, or why it could not be loaded, once it is done.
+	pub fn poll(&self) -> Option<Result<RgbaImage, String>>
+	{
+		return match self.receiver.try_recv()
+		{
+			Ok(result) => Some(result),
+			Err(TryRecvError::Empty) => None,
+			Err(TryRecvError::Disconnected) => Some(Err(format!("{}: the loader stopped", self.path))),
+		};
+	}
+
+	/// Waits until the image is decoded.
+	pub fn wait(&self) -> Result<RgbaImage, String>
+	{
+		return self.receiver.recv().unwrap_or_else(|_| Err(format!("{}: the loader stopped", self.path)));
+	}
+}
+
+/// Loads assets on a pool of worker threads, so reading and decoding them does not stall the main thread. Results are
+/// sent back over a channel per request, and picked up by polling the request.
+pub struct AssetLoader
+{
+	// None once dropped, which stops the workers
+	jobs: Option<Sender<Job>>,
+	workers: Vec<JoinHandle<()>>,
+}
+
+impl AssetLoader
+{
+	/// Starts a worker per core, leaving one for the main thread, up to MAX_THREADS.
+	pub fn new() -> AssetLoader
+	{
+		let cores = thread::available_parallelism().map(|count| count.get()).unwrap_or(1);
+		let thread_count = cores.saturating_sub(1).max(1).min(MAX_THREADS);
+		let (sender, receiver) = channel::<Job>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		let workers = (0..thread_count)
+			.map(|idx| {
+				let receiver = Arc::clone(&receiver);
+				thread::Builder::new()
+					.name(format!("asset loader {}", idx))
+					.spawn(move || {
+						loop
+						{
+							// The lock is only held while waiting for the next job
+							let job = match receiver.lock().unwrap().recv()
+							{
+								Ok(job) => job,
+								Err(_) => break,
+							};
+							job();
+						}
+					})
+					.expect("Could not start asset loader thread")
+			})
+			.collect();
+
+		AssetLoader {
+			jobs: Some(sender),
+			workers: workers,
+		}
+	}
+
+	/// Reads the image at the given path and decodes it to RGBA on one of the workers.
+	pub fn load_image(&self, path: &str) -> ImageRequest
+	{
+		let (sender, receiver) = channel();
+		let job_path = path.to_string();
+		let job = Box::new(move || {
+			let result =
+				image::open(&job_path).map(|image| image.to_rgba8()).map_err(|e| format!("{}: {}", job_path, e));
+			// Nobody is waiting for requests that have been dropped
+			let _ = sender.send(result);
+		});
+		if let Some(jobs) = &self.jobs
+		{
+			// Only fails without workers, which the request reports when polled
+			let _ = jobs.send(job);
+		}
+		return ImageRequest {
+			path: path.to_string(),
+			receiver: receiver,
+		};
+	}
+}
+
+impl Drop for AssetLoader
+{
+	/// Waits for the workers to finish the queued jobs.
+	fn drop(&mut self)
+	{
+		self.jobs = None;
+		for worker in self.workers.drain(..)
+		{
+			let _ = worker.join();
+		}
+	}
+}
@@ -0,0 +1,61 @@
+use crate::core::versioning::write_atomic;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Writes files on a background thread, so saving never stalls a frame.
+///
+/// Files are replaced atomically, see write_atomic(). Queued files are all written before the autosaver is dropped,
+/// which also happens when unwinding from a panic.
+pub struct Autosaver
+{
+	sender: Option<Sender<(String, String)>>,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl Autosaver
+{
+	pub fn new() -> Autosaver
+	{
+		let (sender, receiver) = channel::<(String, String)>();
+		let thread = thread::spawn(move || {
+			for (path, contents) in receiver
+			{
+				if let Err(e) = write_atomic(&path, contents.as_bytes())
+				{
+					warning!("Could not save {}: {}", path, e);
+				}
+			}
+		});
+
+		Autosaver {
+			sender: Some(sender),
+			thread: Some(thread),
+		}
+	}
+
+	/// Queues the file at the given path to be replaced with the given contents.
+	pub fn save(&self, path: &str, contents: String)
+	{
+		let queued = self.sender.as_ref().map_or(false, |sender| sender.send((path.to_string(), contents)).is_ok());
+		if !queued
+		{
+			warning!("Could not queue {} for saving", path);
+		}
+	}
+}
+
+impl Drop for Autosaver
+{
+	fn drop(&mut self)
+	{
+		// Closing the channel lets the thread finish the queued files and return
+		self.sender = None;
+		if let Some(thread) = self.thread.take()
+		{
+			if thread.join().is_err()
+			{
+				warning!("The autosave thread panicked, some files may not have been saved");
+			}
+		}
+	}
+}
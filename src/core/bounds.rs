@@ -0,0 +1,98 @@
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+
+/// Axis aligned bounding box.
+#[derive(Clone, Copy)]
+pub struct BoundingBox
+{
+	pub min: Point3<f32>,
+	pub max: Point3<f32>,
+}
+
+impl BoundingBox
+{
+	/// Returns the smallest box containing all the points, or None if there are no points.
+	pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<BoundingBox>
+	{
+		let mut points = points.into_iter();
+		let first = points.next()?;
+		let mut bounds = BoundingBox {
+			min: first,
+			max: first,
+		};
+		for point in points
+		{
+			bounds.min = Point3::new(bounds.min.x.min(point.x), bounds.min.y.min(point.y), bounds.min.z.min(point.z));
+			bounds.max = Point3::new(bounds.max.x.max(point.x), bounds.max.y.max(point.y), bounds.max.z.max(point.z));
+		}
+		return Some(bounds);
+	}
+
+	/// Returns the smallest box containing both boxes.
+	pub fn union(&self, other: &BoundingBox) -> BoundingBox
+	{
+		return BoundingBox::from_points(vec![self.min, self.max, other.min, other.max]).unwrap();
+	}
+
+	pub fn get_center(&self) -> Point3<f32>
+	{
+		return self.min.midpoint(self.max);
+	}
+
+	pub fn get_extents(&self) -> Vector3<f32>
+	{
+		return self.max - self.min;
+	}
+
+	/// Returns the radius of the bounding sphere around the box.
+	pub fn get_radius(&self) -> f32
+	{
+		return self.get_extents().magnitude() * 0.5;
+	}
+
+	/// Returns true if the boxes overlap or touch.
+	pub fn intersects(&self, other: &BoundingBox) -> bool
+	{
+		return (0..3).all(|axis| self.min[axis] <= other.max[axis] && other.min[axis] <= self.max[axis]);
+	}
+
+	/// Returns the distance along the ray to where it enters the box, 0 if it starts inside, or None if it misses.
+	/// The direction must be normalized for the distance to be in world units.
+	pub fn intersect_ray(&self, origin: Point3<f32>, direction: Vector3<f32>) -> Option<f32>
+	{
+		let mut enter = 0.0f32;
+		let mut exit = std::f32::INFINITY;
+		for axis in 0..3
+		{
+			if direction[axis] == 0.0
+			{
+				if origin[axis] < self.min[axis] || origin[axis] > self.max[axis]
+				{
+					return None;
+				}
+				continue;
+			}
+			let t0 = (self.min[axis] - origin[axis]) / direction[axis];
+			let t1 = (self.max[axis] - origin[axis]) / direction[axis];
+			enter = enter.max(t0.min(t1));
+			exit = exit.min(t0.max(t1));
+		}
+		if enter > exit
+		{
+			return None;
+		}
+		return Some(enter);
+	}
+
+	/// Returns the distance along the ray to where a sphere moving along it first touches the box, 0 if it starts
+	/// touching, or None if it misses. The box is grown by the radius, so the corners and edges are treated as square.
+	pub fn cast_sphere(&self, origin: Point3<f32>, direction: Vector3<f32>, radius: f32) -> Option<f32>
+	{
+		let margin = Vector3::new(radius, radius, radius);
+		let grown = BoundingBox {
+			min: self.min - margin,
+			max: self.max + margin,
+		};
+		return grown.intersect_ray(origin, direction);
+	}
+}
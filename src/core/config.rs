@@ -1,5 +1,5 @@
+use crate::core::versioning::{unversioned_to_v1, FileFormat};
 use serde_derive::{Deserialize, Serialize};
-use serde_json;
 use std::fs::File;
 use std::io::{Error, ErrorKind};
 
@@ -7,6 +7,36 @@ const APP_NAME: &str = "ProjectPeril";
 const APP_VERSION_MAJOR: &str = env!("CARGO_PKG_VERSION_MAJOR");
 const APP_VERSION_MINOR: &str = env!("CARGO_PKG_VERSION_MINOR");
 const APP_VERSION_PATCH: &str = env!("CARGO_PKG_VERSION_PATCH");
+const DEFAULT_WINDOW_ICON: &str = "assets/original/textures/project_peril_logo.png";
+const CONFIG_FORMAT: FileFormat = FileFormat {
+	name: "config",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+
+/// Volumes of the audio buses, from 0 to 1. See audio::Bus.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Volumes
+{
+	pub master: f32,
+	pub music: f32,
+	pub sfx: f32,
+	pub ui: f32,
+}
+
+impl Default for Volumes
+{
+	fn default() -> Volumes
+	{
+		Volumes {
+			master: 1.0,
+			music: 1.0,
+			sfx: 1.0,
+			ui: 1.0,
+		}
+	}
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Config
@@ -21,6 +51,46 @@ pub struct Config
 	pub render_height: u32,
 	pub window_width: u32,
 	pub window_height: u32,
+	/// Seed for all randomness in the simulation. Can be overridden with --seed on the command line.
+	#[serde(default)]
+	pub random_seed: u64,
+	/// Loads meshes with half float and packed vertices where the precision allows, to save memory bandwidth.
+	#[serde(default)]
+	pub packed_vertices: bool,
+	/// Image shown as the window icon, empty for none.
+	#[serde(default = "Config::default_window_icon")]
+	pub window_icon: String,
+	/// Uses the cursors of the system cursor theme instead of the game's own.
+	#[serde(default)]
+	pub system_cursors: bool,
+	/// Samples per pixel of the rendered image, for anti-aliased edges. 1 disables multisampling, and counts the GPU
+	/// does not support fall back to the highest one below.
+	#[serde(default = "Config::default_msaa_samples")]
+	pub msaa_samples: u32,
+	/// Lights each pixel of the main pass with all the lights as it is drawn, instead of once from a G-buffer after
+	/// the opaque geometry. For comparing the two, multisampling always uses the forward path.
+	#[serde(default)]
+	pub forward_shading: bool,
+	/// Draws the depth of the opaque surfaces before shading them, so only the nearest surface of each pixel is
+	/// shaded. Saves shading where surfaces overlap a lot, at the cost of drawing them twice.
+	#[serde(default)]
+	pub depth_prepass: bool,
+	/// Texture data uploaded per frame once decoded, in megabytes. Textures are loaded in the background and drawn
+	/// with a placeholder until then, see RenderState::submit_texture_uploads(). At least one texture is uploaded per
+	/// frame.
+	#[serde(default = "Config::default_texture_upload_budget")]
+	pub texture_upload_budget: f32,
+	/// Waits for the display before presenting each frame, which caps the frame rate at its refresh rate. --benchmark
+	/// turns it off.
+	#[serde(default = "Config::default_vsync")]
+	pub vsync: bool,
+	/// Presents for streaming and capture tools: a borderless window and a B8G8R8A8_UNORM swapchain, with a copy of
+	/// each presented frame kept for reading from other threads, see renderer::CaptureReader.
+	#[serde(default)]
+	pub capture: bool,
+	/// Set in the options menu, and with the volume cvars.
+	#[serde(default)]
+	pub volumes: Volumes,
 }
 
 impl Config
@@ -41,6 +111,26 @@ impl Config
 		ret
 	}
 
+	fn default_window_icon() -> String
+	{
+		return DEFAULT_WINDOW_ICON.to_string();
+	}
+
+	fn default_msaa_samples() -> u32
+	{
+		return 1;
+	}
+
+	fn default_texture_upload_budget() -> f32
+	{
+		return 16.0;
+	}
+
+	fn default_vsync() -> bool
+	{
+		return true;
+	}
+
 	/// Prints the current app version as a string.
 	pub fn version_to_string(&self) -> String
 	{
@@ -52,14 +142,15 @@ impl Config
 	}
 
 	/// Saves the Config to the supplied filename.
-	fn save(&self, filename: &str) -> Result<(), Error>
+	pub fn save(&self, filename: &str) -> Result<(), Error>
 	{
-		let file = File::create(filename)?;
-		match serde_json::to_writer_pretty(file, self)
-		{
-			Ok(_) => Ok(()),
-			Err(e) => Err(e.into()),
-		}
+		return CONFIG_FORMAT.write(filename, self).map_err(|e| Error::new(ErrorKind::Other, e));
+	}
+
+	/// Serializes the Config as it is saved, for saving it elsewhere, e.g. through the Autosaver.
+	pub fn to_json(&self) -> Result<String, Error>
+	{
+		return CONFIG_FORMAT.to_string(self).map_err(|e| Error::new(ErrorKind::Other, e));
 	}
 
 	/// Either reads the config given by the filename and generates a Config struct,
@@ -77,7 +168,8 @@ impl Config
 		{
 			Ok(file) =>
 			{
-				let mut cfg: Config = serde_json::from_reader(file)?;
+				let mut cfg: Config =
+					CONFIG_FORMAT.from_reader(file).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
 
 				let mut needs_save = false;
 				if cfg.app_name != correct_name
@@ -101,7 +193,7 @@ impl Config
 			{
 				ErrorKind::NotFound =>
 				{
-					println!("WARNING: Options file ({}) not found, creating new with default values.", filename);
+					warning!("Options file ({}) not found, creating new with default values.", filename);
 					let cfg = Config {
 						app_name: correct_name,
 						app_version: correct_version,
@@ -113,6 +205,17 @@ impl Config
 						render_height: 320,
 						window_width: 480,
 						window_height: 320,
+						random_seed: 0,
+						packed_vertices: false,
+						window_icon: Config::default_window_icon(),
+						system_cursors: false,
+						msaa_samples: Config::default_msaa_samples(),
+						forward_shading: false,
+						depth_prepass: false,
+						texture_upload_budget: Config::default_texture_upload_budget(),
+						vsync: Config::default_vsync(),
+						capture: false,
+						volumes: Volumes::default(),
 					};
 					cfg.save(filename)?;
 					Ok(cfg)
@@ -0,0 +1,307 @@
+use crate::core::versioning::{unversioned_to_v1, FileFormat};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+const CVARS_FORMAT: FileFormat = FileFormat {
+	name: "cvars",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CVarValue
+{
+	Bool(bool),
+	Float(f32),
+}
+
+impl CVarValue
+{
+	/// Parses the string according to the type of this value.
+	fn parse_same_type(&self, input: &str) -> Option<CVarValue>
+	{
+		match self
+		{
+			CVarValue::Bool(_) => match input
+			{
+				"1" | "true" | "on" => Some(CVarValue::Bool(true)),
+				"0" | "false" | "off" => Some(CVarValue::Bool(false)),
+				_ => None,
+			},
+			CVarValue::Float(_) => input.parse::<f32>().ok().map(CVarValue::Float),
+		}
+	}
+
+	fn to_string(&self) -> String
+	{
+		match self
+		{
+			CVarValue::Bool(val) => format!("{}", val),
+			CVarValue::Float(val) => format!("{}", val),
+		}
+	}
+}
+
+/// The cvars changed from their defaults, as saved to disk.
+#[derive(Serialize, Deserialize)]
+struct SavedCVars
+{
+	values: BTreeMap<String, CVarValue>,
+}
+
+struct CVar
+{
+	value: CVarValue,
+	default: CVarValue,
+	description: &'static str,
+}
+
+/// Runtime tweakable variables (cvars) and commands.
+///
+/// Lines are read from stdin on a background thread and handled once per frame by poll(). A line of the form
+/// "<cvar> <value>" sets a cvar, "<cvar>" prints it. Anything else is returned to the caller as a command.
+pub struct Console
+{
+	cvars: BTreeMap<&'static str, CVar>,
+	// Loaded values of cvars that have not been registered yet
+	saved_values: BTreeMap<String, CVarValue>,
+	// Whether a cvar was changed since the values were last saved
+	dirty: bool,
+	input: Receiver<String>,
+	input_open: bool,
+}
+
+impl Console
+{
+	pub fn new() -> Console
+	{
+		let (sender, receiver) = channel();
+		// The thread is never joined, it dies with the process.
+		thread::spawn(move || {
+			let stdin = std::io::stdin();
+			for line in stdin.lock().lines()
+			{
+				match line
+				{
+					Ok(line) =>
+					{
+						if sender.send(line).is_err()
+						{
+							return;
+						}
+					}
+					Err(_) => return,
+				}
+			}
+		});
+
+		Console {
+			cvars: BTreeMap::new(),
+			saved_values: BTreeMap::new(),
+			dirty: false,
+			input: receiver,
+			input_open: true,
+		}
+	}
+
+	fn register(&mut self, name: &'static str, value: CVarValue, description: &'static str)
+	{
+		debug_assert!(!name.contains(char::is_whitespace));
+		match self.cvars.get_mut(name)
+		{
+			// Re-registering (e.g. a reloaded scene) keeps the type, but resets the value.
+			Some(cvar) =>
+			{
+				debug_assert!(std::mem::discriminant(&cvar.value) == std::mem::discriminant(&value));
+				cvar.value = value;
+				cvar.default = value;
+			}
+			None =>
+			{
+				// Saved values replace the default when first registered, if they are of the right type
+				let saved_value = self
+					.saved_values
+					.remove(name)
+					.filter(|saved_value| std::mem::discriminant(saved_value) == std::mem::discriminant(&value));
+				self.cvars.insert(
+					name,
+					CVar {
+						value: saved_value.unwrap_or(value),
+						default: value,
+						description: description,
+					},
+				);
+			}
+		}
+	}
+
+	/// Loads cvar values saved by save_values(), applied as the cvars are registered. A missing file is not an error.
+	pub fn load_values(&mut self, path: &str)
+	{
+		if !Path::new(path).exists()
+		{
+			return;
+		}
+		match CVARS_FORMAT.read::<SavedCVars>(path)
+		{
+			Ok(saved) => self.saved_values = saved.values,
+			Err(e) => warning!("Could not read saved cvars: {}", e),
+		}
+	}
+
+	/// Returns the serialized values of the cvars changed from their defaults, if any cvar changed since the last call.
+	/// Saved values of cvars not registered this run are kept.
+	pub fn save_values(&mut self) -> Option<String>
+	{
+		if !self.dirty
+		{
+			return None;
+		}
+		self.dirty = false;
+		let mut values = self.saved_values.clone();
+		for (name, cvar) in self.cvars.iter().filter(|(_, cvar)| cvar.value != cvar.default)
+		{
+			values.insert(name.to_string(), cvar.value);
+		}
+		match CVARS_FORMAT.to_string(&SavedCVars {
+			values: values,
+		})
+		{
+			Ok(contents) => Some(contents),
+			Err(e) =>
+			{
+				warning!("Could not serialize cvars: {}", e);
+				None
+			}
+		}
+	}
+
+	pub fn register_bool(&mut self, name: &'static str, value: bool, description: &'static str)
+	{
+		self.register(name, CVarValue::Bool(value), description);
+	}
+
+	pub fn register_float(&mut self, name: &'static str, value: f32, description: &'static str)
+	{
+		self.register(name, CVarValue::Float(value), description);
+	}
+
+	pub fn get_bool(&self, name: &str) -> bool
+	{
+		match self.cvars.get(name).map(|cvar| cvar.value)
+		{
+			Some(CVarValue::Bool(val)) => val,
+			_ => panic!("No bool cvar named {}", name),
+		}
+	}
+
+	pub fn get_float(&self, name: &str) -> f32
+	{
+		match self.cvars.get(name).map(|cvar| cvar.value)
+		{
+			Some(CVarValue::Float(val)) => val,
+			_ => panic!("No float cvar named {}", name),
+		}
+	}
+
+	/// Sets a cvar from the game, e.g. by a timeline: a float to the value, a bool to whether it is at least 0.5.
+	/// Unlike values set from the console, this does not count as a change to save. Returns false if there is no such
+	/// cvar.
+	pub fn set_value(&mut self, name: &str, value: f32) -> bool
+	{
+		match self.cvars.get_mut(name)
+		{
+			Some(cvar) =>
+			{
+				cvar.value = match cvar.value
+				{
+					CVarValue::Bool(_) => CVarValue::Bool(value >= 0.5),
+					CVarValue::Float(_) => CVarValue::Float(value),
+				};
+				return true;
+			}
+			None => return false,
+		}
+	}
+
+	/// Handles a single console line.
+	///
+	/// Returns the line split into words if it was not a cvar access, so that the caller can treat it as a command.
+	pub fn execute(&mut self, line: &str) -> Option<Vec<String>>
+	{
+		let words: Vec<&str> = line.split_whitespace().collect();
+		if words.is_empty()
+		{
+			return None;
+		}
+
+		if words[0] == "cvars"
+		{
+			for (name, cvar) in self.cvars.iter()
+			{
+				println!(
+					"{} = {} (default {}): {}",
+					name,
+					cvar.value.to_string(),
+					cvar.default.to_string(),
+					cvar.description
+				);
+			}
+			return None;
+		}
+
+		match self.cvars.get_mut(words[0])
+		{
+			Some(cvar) =>
+			{
+				if words.len() == 1
+				{
+					println!("{} = {}", words[0], cvar.value.to_string());
+				}
+				else
+				{
+					match cvar.value.parse_same_type(words[1])
+					{
+						Some(value) =>
+						{
+							cvar.value = value;
+							self.dirty = true;
+						}
+						None => println!("Invalid value \"{}\" for {}", words[1], words[0]),
+					}
+				}
+				None
+			}
+			None => Some(words.iter().map(|word| word.to_string()).collect()),
+		}
+	}
+
+	/// Handles all lines received since the last call.
+	///
+	/// Returns the commands not handled by the console itself.
+	pub fn poll(&mut self) -> Vec<Vec<String>>
+	{
+		let mut commands = Vec::new();
+		while self.input_open
+		{
+			match self.input.try_recv()
+			{
+				Ok(line) =>
+				{
+					if let Some(command) = self.execute(&line)
+					{
+						commands.push(command);
+					}
+				}
+				Err(TryRecvError::Empty) => break,
+				Err(TryRecvError::Disconnected) => self.input_open = false,
+			}
+		}
+		commands
+	}
+}
@@ -0,0 +1,64 @@
+use crate::core::Transformable;
+use cgmath::Vector3;
+use serde_derive::{Deserialize, Serialize};
+
+/// Places or turns an object relative to a target, after the simulation has moved them. An object can have several,
+/// applied in order, e.g. following a target while looking at it.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Constraint
+{
+	/// Turns the object to face the target.
+	LookAt,
+	/// Moves the object to the target, offset along the target's right, up and front vectors, in meters.
+	CopyPosition
+	{
+		offset: [f32; 3],
+	},
+	/// Like CopyPosition, but trails behind the target. The lag is the time to cover most of the distance to the
+	/// offset position, in seconds.
+	Follow
+	{
+		offset: [f32; 3],
+		lag: f32,
+	},
+}
+
+impl Constraint
+{
+	pub fn apply<O: Transformable, T: Transformable>(&self, object: &mut O, target: &T, timestep: f32)
+	{
+		let offset_position = |offset: &[f32; 3]| {
+			let up = Vector3::unit_y();
+			target.get_position() +
+				target.get_right_vector() * offset[0] +
+				up * offset[1] +
+				target.get_front_vector() * offset[2]
+		};
+		match self
+		{
+			Constraint::LookAt => object.look_at(target.get_position()),
+			Constraint::CopyPosition {
+				offset,
+			} => object.set_position(offset_position(offset)),
+			Constraint::Follow {
+				offset,
+				lag,
+			} =>
+			{
+				let goal = offset_position(offset);
+				// Covers 95% of the distance in the lag, whatever the timestep
+				let fraction = if *lag > 0.0
+				{
+					1.0 - (-3.0 * timestep / lag).exp()
+				}
+				else
+				{
+					1.0
+				};
+				let position = object.get_position();
+				object.set_position(position + (goal - position) * fraction);
+			}
+		}
+	}
+}
@@ -0,0 +1,134 @@
+use sdl2::mouse::{Cursor, SystemCursor};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::surface::Surface;
+
+/// One row per line, top to bottom. 'X' is the outline, '.' the fill and anything else transparent.
+const ARROW_IMAGE: [&str; 17] = [
+	"X          ",
+	"XX         ",
+	"X.X        ",
+	"X..X       ",
+	"X...X      ",
+	"X....X     ",
+	"X.....X    ",
+	"X......X   ",
+	"X.......X  ",
+	"X........X ",
+	"X.....XXXXX",
+	"X..X..X    ",
+	"X.X X..X   ",
+	"XX  X..X   ",
+	"X    X..X  ",
+	"     X..X  ",
+	"      XX   ",
+];
+const CROSSHAIR_IMAGE: [&str; 15] = [
+	"      XXX      ",
+	"      X.X      ",
+	"      X.X      ",
+	"      X.X      ",
+	"      X.X      ",
+	"      XXX      ",
+	"XXXXXX   XXXXXX",
+	"X....X   X....X",
+	"XXXXXX   XXXXXX",
+	"      XXX      ",
+	"      X.X      ",
+	"      X.X      ",
+	"      X.X      ",
+	"      X.X      ",
+	"      XXX      ",
+];
+const OUTLINE_COLOR: [u8; 4] = [0, 0, 0, 255];
+const FILL_COLOR: [u8; 4] = [255, 255, 255, 255];
+
+/// Cursors for the input contexts of the game.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CursorKind
+{
+	/// Pointing at the UI, or outside the rendered image.
+	Arrow,
+	/// Pointing at the scene, where clicking picks objects.
+	Crosshair,
+}
+
+/// Creates a cursor from one of the images above, falling back to the system cursor if that fails.
+fn create_cursor(image: &[&str], hotspot: (i32, i32), fallback: SystemCursor) -> Result<Cursor, String>
+{
+	let width = image[0].len();
+	let mut pixels = Vec::with_capacity(width * image.len() * 4);
+	for row in image
+	{
+		for pixel in row.chars()
+		{
+			match pixel
+			{
+				'X' => pixels.extend_from_slice(&OUTLINE_COLOR),
+				'.' => pixels.extend_from_slice(&FILL_COLOR),
+				_ => pixels.extend_from_slice(&[0; 4]),
+			}
+		}
+	}
+	let surface =
+		Surface::from_data(&mut pixels, width as u32, image.len() as u32, 4 * width as u32, PixelFormatEnum::RGBA32);
+	match surface.and_then(|surface| Cursor::from_surface(surface, hotspot.0, hotspot.1))
+	{
+		Ok(cursor) => return Ok(cursor),
+		Err(e) =>
+		{
+			warning!("Could not create cursor, using the system cursor: {}", e);
+			return Cursor::from_system(fallback);
+		}
+	}
+}
+
+/// The mouse cursors, switched as the cursor moves between input contexts.
+///
+/// The cursor is hidden while captured, where a software crosshair is drawn on the overlay instead.
+pub struct Cursors
+{
+	arrow: Cursor,
+	crosshair: Cursor,
+	current: Option<CursorKind>,
+}
+
+impl Cursors
+{
+	/// Creates the cursors, either the game's own or the ones of the system cursor theme. The mouse subsystem must be
+	/// initialized.
+	pub fn new(system_cursors: bool) -> Result<Cursors, String>
+	{
+		let (arrow, crosshair) = if system_cursors
+		{
+			(Cursor::from_system(SystemCursor::Arrow)?, Cursor::from_system(SystemCursor::Crosshair)?)
+		}
+		else
+		{
+			(
+				create_cursor(&ARROW_IMAGE, (0, 0), SystemCursor::Arrow)?,
+				create_cursor(&CROSSHAIR_IMAGE, (7, 7), SystemCursor::Crosshair)?,
+			)
+		};
+
+		Ok(Cursors {
+			arrow: arrow,
+			crosshair: crosshair,
+			current: None,
+		})
+	}
+
+	/// Switches to the given cursor, if it is not already shown.
+	pub fn set(&mut self, kind: CursorKind)
+	{
+		if self.current == Some(kind)
+		{
+			return;
+		}
+		match kind
+		{
+			CursorKind::Arrow => self.arrow.set(),
+			CursorKind::Crosshair => self.crosshair.set(),
+		}
+		self.current = Some(kind);
+	}
+}
@@ -1,4 +1,5 @@
-use crate::core::{Material, Mesh};
+use crate::core::{Frustum, Material, Mesh, MorphWeights};
+use crate::renderer::{MainPass, ShadowPass};
 use ash::version::DeviceV1_0;
 use ash::{vk, Device};
 use cgmath::Matrix4;
@@ -9,22 +10,122 @@ pub trait Drawable
 	fn get_mesh(&self) -> &Mesh;
 	fn get_material(&self) -> &Material;
 
+	/// Returns the blend weights of the morph targets of the mesh, for objects that deform it.
+	fn get_morph_weights(&self) -> Option<&MorphWeights>
+	{
+		return None;
+	}
+
+	/// Returns false if the mesh is entirely outside the frustum, so drawing it can be skipped.
+	fn is_visible(&self, model_matrix: &Matrix4<f32>, frustum: &Frustum) -> bool
+	{
+		let (center, radius) = self.get_mesh().get_bounding_sphere(model_matrix);
+		return frustum.intersects_sphere(center, radius);
+	}
+
 	fn draw(
-		&self, device: &Device, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout,
-		model_matrix: &Matrix4<f32>, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+		&self, device: &Device, cmd_buf: vk::CommandBuffer, mp: &MainPass, model_matrix: &Matrix4<f32>,
+		view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
 	)
 	{
+		// Blended surfaces are left out of the depth prepass
+		if !mp.draws_permutation(self.get_material().get_permutation_index())
+		{
+			return;
+		}
+		let pipeline_layout = mp.pipeline_layout;
 		let mv_matrix = view_matrix * model_matrix;
 		let mvp_matrix = projection_matrix * mv_matrix;
 		let matrices = [model_matrix.clone(), mvp_matrix];
 
-		self.get_mesh().bind_buffers(cmd_buf);
-		self.get_material().bind_descriptor_sets(cmd_buf, pipeline_layout);
+		let mesh = self.get_mesh();
+		let lod = mesh.select_lod(&mv_matrix);
+		// Indirect draws share the default morph set
+		let morph_weights = self.get_morph_weights();
+		if morph_weights.is_none() &&
+			mp.queue_indirect_draw(mesh, lod, self.get_material(), &[(*model_matrix, mvp_matrix)])
+		{
+			return;
+		}
+		let material = self.get_material();
+		mp.bind_mesh_pipeline(
+			cmd_buf,
+			material.get_permutation_index(),
+			mesh.get_vertex_format(),
+			mesh.get_vertex_bytes(),
+		);
+		mesh.bind_buffers(cmd_buf, lod);
+		material.bind_descriptor_sets(cmd_buf, pipeline_layout);
+		if let Some(morph_weights) = morph_weights
+		{
+			morph_weights.bind(cmd_buf, pipeline_layout);
+		}
 
 		unsafe {
 			let matrices_bytes = slice::from_raw_parts(matrices.as_ptr() as *const u8, mem::size_of_val(&matrices));
 			device.cmd_push_constants(cmd_buf, pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, matrices_bytes);
-			device.cmd_draw_indexed(cmd_buf, self.get_mesh().get_num_indices(), 1, 0, 0, 1);
+			device.cmd_draw_indexed(cmd_buf, mesh.get_num_indices(lod), 1, 0, 0, 1);
+		}
+		if morph_weights.is_some()
+		{
+			mp.bind_default_morph_set(cmd_buf);
+		}
+	}
+
+	/// Draws the mesh at each of the model matrices. Instances with the same level of detail are issued as one
+	/// instanced indirect draw where possible, the others are drawn one by one.
+	fn draw_instances(
+		&self, device: &Device, cmd_buf: vk::CommandBuffer, mp: &MainPass, model_matrices: &[Matrix4<f32>],
+		view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+	)
+	{
+		let mesh = self.get_mesh();
+		// Level of detail, and model and MVP matrices of each instance
+		let mut instances: Vec<(usize, (Matrix4<f32>, Matrix4<f32>))> = model_matrices
+			.iter()
+			.map(|model_matrix| {
+				let mv_matrix = view_matrix * model_matrix;
+				(mesh.select_lod(&mv_matrix), (*model_matrix, projection_matrix * mv_matrix))
+			})
+			.collect();
+		instances.sort_by_key(|&(lod, _)| lod);
+
+		let mut start = 0;
+		while start < instances.len()
+		{
+			let lod = instances[start].0;
+			let count = instances[start..].iter().take_while(|&&(instance_lod, _)| instance_lod == lod).count();
+			let lod_instances: Vec<(Matrix4<f32>, Matrix4<f32>)> =
+				instances[start..start + count].iter().map(|&(_, matrices)| matrices).collect();
+			if self.get_morph_weights().is_some() ||
+				!mp.queue_indirect_draw(mesh, lod, self.get_material(), &lod_instances)
+			{
+				for (model_matrix, _) in &lod_instances
+				{
+					self.draw(device, cmd_buf, mp, model_matrix, view_matrix, projection_matrix);
+				}
+			}
+			start += count;
+		}
+	}
+
+	/// Draws the mesh into a shadow map face, see ShadowPass::render(). Always uses the full detail mesh, as LODs are
+	/// selected for the camera, in its rest shape.
+	fn draw_shadow(
+		&self, device: &Device, cmd_buf: vk::CommandBuffer, sp: &ShadowPass, model_matrix: &Matrix4<f32>,
+		view_projection_matrix: &Matrix4<f32>, light_matrix: &Matrix4<f32>,
+	)
+	{
+		let matrices = [view_projection_matrix * model_matrix, light_matrix * model_matrix];
+
+		let mesh = self.get_mesh();
+		sp.bind_mesh_pipeline(cmd_buf, mesh.get_vertex_format());
+		mesh.bind_buffers(cmd_buf, 0);
+
+		unsafe {
+			let matrices_bytes = slice::from_raw_parts(matrices.as_ptr() as *const u8, mem::size_of_val(&matrices));
+			device.cmd_push_constants(cmd_buf, sp.pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, matrices_bytes);
+			device.cmd_draw_indexed(cmd_buf, mesh.get_num_indices(0), 1, 0, 0, 1);
 		}
 	}
 }
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+// Checking modification times is cheap, but there's no need to do it every frame
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Detects changes to files on disk by polling their modification times.
+pub struct FileWatcher
+{
+	files: BTreeMap<String, Option<SystemTime>>,
+	last_poll: Instant,
+}
+
+impl FileWatcher
+{
+	pub fn new() -> FileWatcher
+	{
+		FileWatcher {
+			files: BTreeMap::new(),
+			last_poll: Instant::now(),
+		}
+	}
+
+	fn get_modified_time(path: &str) -> Option<SystemTime>
+	{
+		return fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+	}
+
+	/// Starts watching the file. Watching the same file again has no effect.
+	pub fn watch(&mut self, path: &str)
+	{
+		if !self.files.contains_key(path)
+		{
+			self.files.insert(path.to_string(), FileWatcher::get_modified_time(path));
+		}
+	}
+
+	pub fn unwatch(&mut self, path: &str)
+	{
+		self.files.remove(path);
+	}
+
+	/// Returns the watched files that have been modified since the last call.
+	pub fn poll(&mut self) -> Vec<String>
+	{
+		if self.last_poll.elapsed() < POLL_INTERVAL
+		{
+			return Vec::new();
+		}
+		self.last_poll = Instant::now();
+
+		let mut changed = Vec::new();
+		for (path, modified) in self.files.iter_mut()
+		{
+			let current = FileWatcher::get_modified_time(path);
+			// Files being deleted (e.g. while an editor saves) are not reported, only when they reappear
+			if current.is_some() && current != *modified
+			{
+				changed.push(path.clone());
+			}
+			*modified = current;
+		}
+		return changed;
+	}
+}
@@ -0,0 +1,42 @@
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector4};
+
+/// The volume seen through a view-projection matrix, as six planes with their normals pointing inwards. Used to skip
+/// drawing objects that cannot be seen.
+pub struct Frustum
+{
+	// Normal in xyz and distance in w, with normalized normals
+	planes: [Vector4<f32>; 6],
+}
+
+impl Frustum
+{
+	/// Extracts the planes from a matrix mapping to Vulkan clip space, with depths from 0 to 1.
+	pub fn from_matrix(view_projection: &Matrix4<f32>) -> Frustum
+	{
+		let rows = [view_projection.row(0), view_projection.row(1), view_projection.row(2), view_projection.row(3)];
+		let mut planes = [
+			// Left, right, top, bottom, near and far
+			rows[3] + rows[0],
+			rows[3] - rows[0],
+			rows[3] + rows[1],
+			rows[3] - rows[1],
+			rows[2],
+			rows[3] - rows[2],
+		];
+		for plane in planes.iter_mut()
+		{
+			*plane /= plane.truncate().magnitude().max(std::f32::EPSILON);
+		}
+		return Frustum {
+			planes: planes,
+		};
+	}
+
+	/// Returns false if the sphere is entirely outside the frustum. Spheres near its corners can be outside while
+	/// returning true.
+	pub fn intersects_sphere(&self, center: Point3<f32>, radius: f32) -> bool
+	{
+		return self.planes.iter().all(|plane| plane.truncate().dot(center.to_vec()) + plane.w >= -radius);
+	}
+}
@@ -0,0 +1,677 @@
+use crate::core::material::{BlendMode, MaterialDesc};
+use crate::core::{MorphTarget, Vertex};
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Matrix4, Point3, Quaternion, Vector3, Vector4};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Binary glTF chunk types
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_JSON_CHUNK: u32 = 0x4E4F_534A;
+const GLB_BIN_CHUNK: u32 = 0x004E_4942;
+const MODE_TRIANGLES: u32 = 4;
+
+/// Returns true for the paths of glTF 2.0 files, text (.gltf) or binary (.glb).
+pub fn is_gltf(path: &str) -> bool
+{
+	return path.ends_with(".gltf") || path.ends_with(".glb");
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Document
+{
+	scene: Option<usize>,
+	scenes: Vec<SceneNodes>,
+	nodes: Vec<Node>,
+	meshes: Vec<MeshPrimitives>,
+	accessors: Vec<Accessor>,
+	buffer_views: Vec<BufferView>,
+	buffers: Vec<Buffer>,
+	materials: Vec<MaterialDef>,
+	textures: Vec<TextureDef>,
+	images: Vec<ImageDef>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SceneNodes
+{
+	nodes: Vec<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Node
+{
+	children: Vec<usize>,
+	mesh: Option<usize>,
+	matrix: Option<[f32; 16]>,
+	translation: Option<[f32; 3]>,
+	rotation: Option<[f32; 4]>,
+	scale: Option<[f32; 3]>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MeshPrimitives
+{
+	primitives: Vec<Primitive>,
+	extras: MeshExtras,
+}
+
+/// Application specific data of a mesh. Exporters like Blender put the names of the morph targets here.
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct MeshExtras
+{
+	target_names: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Primitive
+{
+	attributes: HashMap<String, usize>,
+	indices: Option<usize>,
+	material: Option<usize>,
+	mode: Option<u32>,
+	// Morph targets, with offsets of the attributes
+	targets: Vec<HashMap<String, usize>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Accessor
+{
+	buffer_view: Option<usize>,
+	byte_offset: usize,
+	component_type: u32,
+	normalized: bool,
+	count: usize,
+	#[serde(rename = "type")]
+	kind: String,
+	sparse: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct BufferView
+{
+	buffer: usize,
+	byte_offset: usize,
+	byte_length: usize,
+	byte_stride: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Buffer
+{
+	uri: Option<String>,
+	byte_length: usize,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct MaterialDef
+{
+	pbr_metallic_roughness: Option<PbrMetallicRoughness>,
+	normal_texture: Option<TextureRef>,
+	emissive_factor: Option<[f32; 3]>,
+	alpha_mode: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct PbrMetallicRoughness
+{
+	base_color_factor: Option<[f32; 4]>,
+	base_color_texture: Option<TextureRef>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TextureRef
+{
+	index: usize,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TextureDef
+{
+	source: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct ImageDef
+{
+	uri: Option<String>,
+}
+
+/// Decodes standard base64, as used by data URIs.
+fn decode_base64(text: &str) -> Result<Vec<u8>, String>
+{
+	let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+	let mut bits: u32 = 0;
+	let mut num_bits = 0;
+	for c in text.bytes().filter(|&c| c != b'=' && !c.is_ascii_whitespace())
+	{
+		let value = match c
+		{
+			b'A'..=b'Z' => c - b'A',
+			b'a'..=b'z' => c - b'a' + 26,
+			b'0'..=b'9' => c - b'0' + 52,
+			b'+' => 62,
+			b'/' => 63,
+			_ => return Err(format!("invalid base64 character '{}'", c as char)),
+		};
+		bits = (bits << 6) | value as u32;
+		num_bits += 6;
+		if num_bits >= 8
+		{
+			num_bits -= 8;
+			bytes.push((bits >> num_bits) as u8);
+		}
+	}
+	return Ok(bytes);
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String>
+{
+	return match bytes.get(offset..offset + 4)
+	{
+		Some(word) => Ok(u32::from_le_bytes([word[0], word[1], word[2], word[3]])),
+		None => Err("unexpected end of file".to_string()),
+	};
+}
+
+/// A glTF 2.0 file with its buffers loaded.
+struct GltfFile
+{
+	document: Document,
+	buffers: Vec<Vec<u8>>,
+	// Directory of the file, which URIs are relative to
+	directory: String,
+}
+
+impl GltfFile
+{
+	/// Reads the JSON document, and the binary chunk of .glb files.
+	fn read_document(path: &str) -> Result<(Document, Option<Vec<u8>>), String>
+	{
+		let contents = fs::read(path).map_err(|e| e.to_string())?;
+		if !path.ends_with(".glb")
+		{
+			let document = serde_json::from_slice(&contents).map_err(|e| e.to_string())?;
+			return Ok((document, None));
+		}
+
+		if read_u32(&contents, 0)? != GLB_MAGIC
+		{
+			return Err("not a binary glTF file".to_string());
+		}
+		if read_u32(&contents, 4)? != 2
+		{
+			return Err("only glTF 2.0 is supported".to_string());
+		}
+		let mut json = None;
+		let mut bin = None;
+		let mut offset = 12;
+		while offset + 8 <= contents.len()
+		{
+			let length = read_u32(&contents, offset)? as usize;
+			let chunk_type = read_u32(&contents, offset + 4)?;
+			let chunk = contents.get(offset + 8..offset + 8 + length).ok_or("truncated chunk")?;
+			match chunk_type
+			{
+				GLB_JSON_CHUNK => json = Some(chunk),
+				GLB_BIN_CHUNK => bin = Some(chunk.to_vec()),
+				// Unknown chunks are to be ignored
+				_ => (),
+			}
+			offset += 8 + length;
+		}
+		let json = json.ok_or("no JSON chunk")?;
+		let document = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+		return Ok((document, bin));
+	}
+
+	/// Reads a glTF file and the buffers it references.
+	fn read(path: &str) -> Result<GltfFile, String>
+	{
+		let (document, mut bin) = GltfFile::read_document(path)?;
+		let directory = Path::new(path).parent().map_or(String::new(), |dir| dir.to_string_lossy().to_string());
+		let mut buffers = Vec::with_capacity(document.buffers.len());
+		for (idx, buffer) in document.buffers.iter().enumerate()
+		{
+			let data = match &buffer.uri
+			{
+				Some(uri) if uri.starts_with("data:") =>
+				{
+					let base64 = uri.splitn(2, ";base64,").nth(1).ok_or("data URI without base64 data")?;
+					decode_base64(base64)?
+				}
+				Some(uri) =>
+				{
+					let buffer_path = Path::new(&directory).join(uri);
+					fs::read(&buffer_path).map_err(|e| format!("{}: {}", buffer_path.display(), e))?
+				}
+				// The first buffer of a .glb file may be its binary chunk
+				None if idx == 0 => bin.take().ok_or("buffer without data")?,
+				None => return Err(format!("buffer {} has no data", idx)),
+			};
+			if data.len() < buffer.byte_length
+			{
+				return Err(format!("buffer {} is shorter than its length", idx));
+			}
+			buffers.push(data);
+		}
+		return Ok(GltfFile {
+			document: document,
+			buffers: buffers,
+			directory: directory,
+		});
+	}
+
+	/// Reads the elements of an accessor, as floats with the given number of components. Normalized integers are
+	/// scaled to [0, 1] or [-1, 1].
+	fn read_accessor(&self, idx: usize, components: usize) -> Result<Vec<f32>, String>
+	{
+		let accessor = self.document.accessors.get(idx).ok_or(format!("accessor {} not found", idx))?;
+		let accessor_components = match accessor.kind.as_str()
+		{
+			"SCALAR" => 1,
+			"VEC2" => 2,
+			"VEC3" => 3,
+			"VEC4" => 4,
+			kind => return Err(format!("accessor {} has unsupported type {}", idx, kind)),
+		};
+		if accessor_components < components
+		{
+			return Err(format!("accessor {} has too few components", idx));
+		}
+		if accessor.sparse.is_some()
+		{
+			return Err(format!("accessor {} is sparse, which is not supported", idx));
+		}
+		let component_size = match accessor.component_type
+		{
+			5120 | 5121 => 1,
+			5122 | 5123 => 2,
+			5125 | 5126 => 4,
+			component_type => return Err(format!("accessor {} has unknown component type {}", idx, component_type)),
+		};
+		let mut values = Vec::with_capacity(accessor.count * components);
+		let view = match accessor.buffer_view
+		{
+			Some(view) => self.document.buffer_views.get(view).ok_or(format!("buffer view {} not found", view))?,
+			// Without a buffer view, the elements are all zeros
+			None =>
+			{
+				values.resize(accessor.count * components, 0.0);
+				return Ok(values);
+			}
+		};
+		let buffer = self.buffers.get(view.buffer).ok_or(format!("buffer {} not found", view.buffer))?;
+		let element_size = accessor_components * component_size;
+		let stride = view.byte_stride.unwrap_or(element_size);
+		let start = view.byte_offset + accessor.byte_offset;
+		if accessor.count > 0 &&
+			(start + stride * (accessor.count - 1) + element_size > view.byte_offset + view.byte_length ||
+				view.byte_offset + view.byte_length > buffer.len())
+		{
+			return Err(format!("accessor {} is out of bounds", idx));
+		}
+		for element in 0..accessor.count
+		{
+			for component in 0..components
+			{
+				let offset = start + element * stride + component * component_size;
+				let bytes = &buffer[offset..offset + component_size];
+				let value = match (accessor.component_type, accessor.normalized)
+				{
+					(5120, false) => bytes[0] as i8 as f32,
+					(5120, true) => (bytes[0] as i8 as f32 / 127.0).max(-1.0),
+					(5121, false) => bytes[0] as f32,
+					(5121, true) => bytes[0] as f32 / 255.0,
+					(5122, normalized) =>
+					{
+						let value = i16::from_le_bytes([bytes[0], bytes[1]]) as f32;
+						if normalized
+						{
+							(value / 32_767.0).max(-1.0)
+						}
+						else
+						{
+							value
+						}
+					}
+					(5123, normalized) =>
+					{
+						let value = u16::from_le_bytes([bytes[0], bytes[1]]) as f32;
+						if normalized
+						{
+							value / 65_535.0
+						}
+						else
+						{
+							value
+						}
+					}
+					(5125, _) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32,
+					_ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+				};
+				values.push(value);
+			}
+		}
+		return Ok(values);
+	}
+
+	/// Returns the transformation of a node relative to its parent.
+	fn get_node_matrix(node: &Node) -> Matrix4<f32>
+	{
+		if let Some(m) = node.matrix
+		{
+			// Column major, like cgmath
+			return Matrix4::new(
+				m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], m[8], m[9], m[10], m[11], m[12], m[13], m[14], m[15],
+			);
+		}
+		let translation = node.translation.unwrap_or([0.0; 3]);
+		let rotation = node.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+		let scale = node.scale.unwrap_or([1.0; 3]);
+		return Matrix4::from_translation(Vector3::from(translation)) *
+			Matrix4::from(Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2])) *
+			Matrix4::from_nonuniform_scale(scale[0], scale[1], scale[2]);
+	}
+
+	/// Returns the meshes of the default scene, or of all root nodes if there is none, with their model matrices.
+	fn get_mesh_instances(&self) -> Result<Vec<(usize, Matrix4<f32>)>, String>
+	{
+		let document = &self.document;
+		let roots: Vec<usize> = match document.scene.or(
+			if document.scenes.is_empty()
+			{
+				None
+			}
+			else
+			{
+				Some(0)
+			},
+		)
+		{
+			Some(scene) => document.scenes.get(scene).ok_or(format!("scene {} not found", scene))?.nodes.clone(),
+			None =>
+			{
+				let children: Vec<usize> = document.nodes.iter().flat_map(|node| node.children.clone()).collect();
+				(0..document.nodes.len()).filter(|idx| !children.contains(idx)).collect()
+			}
+		};
+		let mut instances = Vec::new();
+		let mut stack: Vec<(usize, Matrix4<f32>)> = roots.iter().map(|&idx| (idx, Matrix4::identity())).collect();
+		while let Some((idx, parent_matrix)) = stack.pop()
+		{
+			// Node hierarchies are trees, which this also guards against
+			if stack.len() > document.nodes.len()
+			{
+				return Err("node hierarchy is not a tree".to_string());
+			}
+			let node = document.nodes.get(idx).ok_or(format!("node {} not found", idx))?;
+			let matrix = parent_matrix * GltfFile::get_node_matrix(node);
+			if let Some(mesh) = node.mesh
+			{
+				instances.push((mesh, matrix));
+			}
+			stack.extend(node.children.iter().map(|&child| (child, matrix)));
+		}
+		return Ok(instances);
+	}
+
+	/// Returns the path of a texture, if it is a file next to the glTF file. Embedded images are not supported.
+	fn get_texture_path(&self, texture: &TextureRef) -> Option<String>
+	{
+		let image = self
+			.document
+			.textures
+			.get(texture.index)
+			.and_then(|texture| texture.source)
+			.and_then(|source| self.document.images.get(source))?;
+		let uri = match &image.uri
+		{
+			Some(uri) if !uri.starts_with("data:") => uri,
+			_ =>
+			{
+				warning!("Embedded glTF images are not supported, use image files next to the model");
+				return None;
+			}
+		};
+		let path = Path::new(&self.directory).join(uri);
+		if !path.is_file()
+		{
+			warning!("glTF texture {} not found", path.display());
+			return None;
+		}
+		return Some(path.to_string_lossy().to_string());
+	}
+}
+
+/// A model read from a glTF 2.0 file: the triangles of all meshes in its scene, placed by their nodes, and the
+/// material of the first one.
+///
+/// Morph targets are matched up by index across the meshes, and named by the first mesh that has them. Vertices of
+/// primitives with fewer targets do not move with the others.
+pub struct GltfModel
+{
+	pub vertices: Vec<Vertex>,
+	pub indices: Vec<u16>,
+	pub morph_targets: Vec<MorphTarget>,
+}
+
+impl GltfModel
+{
+	/// Reads the model, generating normals and tangents where the file has none. Primitives other than triangles are
+	/// skipped.
+	pub fn read(path: &str) -> Result<GltfModel, String>
+	{
+		let file = GltfFile::read(path).map_err(|e| format!("{}: {}", path, e))?;
+		return GltfModel::from_file(&file).map_err(|e| format!("{}: {}", path, e));
+	}
+
+	fn from_file(file: &GltfFile) -> Result<GltfModel, String>
+	{
+		let mut vertices: Vec<Vertex> = Vec::new();
+		let mut indices: Vec<u16> = Vec::new();
+		let mut morph_targets: Vec<MorphTarget> = Vec::new();
+		for (mesh_idx, model_matrix) in file.get_mesh_instances()?
+		{
+			let mesh = file.document.meshes.get(mesh_idx).ok_or(format!("mesh {} not found", mesh_idx))?;
+			let normal_matrix =
+				Matrix3::from_cols(model_matrix.x.truncate(), model_matrix.y.truncate(), model_matrix.z.truncate())
+					.invert()
+					.unwrap_or(Matrix3::identity())
+					.transpose();
+			// Mirroring transformations turn the triangles inside out
+			let mirrored = model_matrix.determinant() < 0.0;
+			for primitive in mesh.primitives.iter()
+			{
+				if primitive.mode.unwrap_or(MODE_TRIANGLES) != MODE_TRIANGLES
+				{
+					warning!("Skipping glTF primitive that is not made of triangles");
+					continue;
+				}
+				let attribute = |name: &str, components: usize| -> Result<Option<Vec<f32>>, String> {
+					return match primitive.attributes.get(name)
+					{
+						Some(&accessor) => file.read_accessor(accessor, components).map(Some),
+						None => Ok(None),
+					};
+				};
+				let positions = attribute("POSITION", 3)?.ok_or("primitive without positions")?;
+				let normals = attribute("NORMAL", 3)?;
+				let tangents = attribute("TANGENT", 4)?;
+				let tex_uvs = attribute("TEXCOORD_0", 2)?;
+				let count = positions.len() / 3;
+				if vertices.len() + count > std::u16::MAX as usize + 1
+				{
+					return Err("too many vertices".to_string());
+				}
+
+				let mut primitive_vertices = Vec::with_capacity(count);
+				for i in 0..count
+				{
+					let position = Point3::new(positions[3 * i], positions[3 * i + 1], positions[3 * i + 2]);
+					let normal = normals.as_ref().map_or(Vector3::zero(), |n| {
+						(normal_matrix * Vector3::new(n[3 * i], n[3 * i + 1], n[3 * i + 2])).normalize()
+					});
+					let (tangent, bitangent) = match &tangents
+					{
+						Some(t) =>
+						{
+							let tangent = Vector4::new(t[4 * i], t[4 * i + 1], t[4 * i + 2], t[4 * i + 3]);
+							let world_tangent = (model_matrix * tangent.truncate().extend(0.0)).truncate().normalize();
+							// The handedness is in w
+							(world_tangent, normal.cross(world_tangent) * tangent.w)
+						}
+						None => (Vector3::zero(), Vector3::zero()),
+					};
+					let tex_uv = tex_uvs.as_ref().map_or([0.0; 2], |t| [t[2 * i], t[2 * i + 1]]);
+					primitive_vertices.push(Vertex::new(
+						Point3::from_homogeneous(model_matrix * position.to_homogeneous()),
+						normal,
+						tangent,
+						bitangent,
+						tex_uv,
+					));
+				}
+
+				let mut primitive_indices: Vec<u16> = match primitive.indices
+				{
+					Some(accessor) => file.read_accessor(accessor, 1)?.iter().map(|&idx| idx as u16).collect(),
+					None => (0..count as u16).collect(),
+				};
+				if primitive_indices.iter().any(|&idx| idx as usize >= count)
+				{
+					return Err("index out of bounds".to_string());
+				}
+				if mirrored
+				{
+					for triangle in primitive_indices.chunks_mut(3)
+					{
+						triangle.swap(1, 2);
+					}
+				}
+				primitive_indices.truncate(primitive_indices.len() / 3 * 3);
+				if tangents.is_none() || normals.is_none()
+				{
+					Vertex::generate_tangents(&mut primitive_vertices, &primitive_indices, normals.is_none());
+				}
+
+				for (target_idx, target) in primitive.targets.iter().enumerate()
+				{
+					if target_idx == morph_targets.len()
+					{
+						// The vertices before this primitive do not move with new targets
+						morph_targets.push(MorphTarget {
+							name: mesh
+								.extras
+								.target_names
+								.get(target_idx)
+								.cloned()
+								.unwrap_or(format!("target {}", target_idx)),
+							position_deltas: vec![Vector3::zero(); vertices.len()],
+							normal_deltas: vec![Vector3::zero(); vertices.len()],
+						});
+					}
+					let offsets = |name: &str| -> Result<Vec<Vector3<f32>>, String> {
+						let values = match target.get(name)
+						{
+							Some(&accessor) => file.read_accessor(accessor, 3)?,
+							None => vec![0.0; 3 * count],
+						};
+						if values.len() != 3 * count
+						{
+							return Err(format!("morph target {} does not match the vertices", target_idx));
+						}
+						return Ok(values.chunks(3).map(|v| Vector3::new(v[0], v[1], v[2])).collect());
+					};
+					let morph_target = &mut morph_targets[target_idx];
+					morph_target
+						.position_deltas
+						.extend(offsets("POSITION")?.iter().map(|&delta| model_matrix.transform_vector(delta)));
+					morph_target.normal_deltas.extend(offsets("NORMAL")?.iter().map(|&delta| normal_matrix * delta));
+				}
+
+				let base = vertices.len() as u16;
+				vertices.extend(primitive_vertices);
+				indices.extend(primitive_indices.iter().map(|&idx| base + idx));
+				// Targets this primitive has no offsets for
+				for morph_target in morph_targets.iter_mut()
+				{
+					morph_target.position_deltas.resize(vertices.len(), Vector3::zero());
+					morph_target.normal_deltas.resize(vertices.len(), Vector3::zero());
+				}
+			}
+		}
+		if indices.is_empty()
+		{
+			return Err("no triangles".to_string());
+		}
+		return Ok(GltfModel {
+			vertices: vertices,
+			indices: indices,
+			morph_targets: morph_targets,
+		});
+	}
+
+	/// Reads the material of the first primitive of a glTF file. The base color is used as the texture and tint, the
+	/// normal texture as normal map, and blended materials are alpha blended. Textures must be image files next to
+	/// the model.
+	pub fn read_material_desc(path: &str) -> Result<MaterialDesc, String>
+	{
+		let file = GltfFile::read(path).map_err(|e| format!("{}: {}", path, e))?;
+		let material = file
+			.document
+			.meshes
+			.iter()
+			.flat_map(|mesh| mesh.primitives.iter())
+			.find_map(|primitive| primitive.material)
+			.and_then(|idx| file.document.materials.get(idx));
+		let mut desc = MaterialDesc::untextured();
+		let material = match material
+		{
+			Some(material) => material,
+			None => return Ok(desc),
+		};
+		if let Some(pbr) = &material.pbr_metallic_roughness
+		{
+			if let Some(color) = pbr.base_color_factor
+			{
+				desc.tint = [color[0], color[1], color[2]];
+				desc.opacity = color[3];
+			}
+			if let Some(path) = pbr.base_color_texture.as_ref().and_then(|texture| file.get_texture_path(texture))
+			{
+				desc.texture = path;
+			}
+		}
+		if let Some(path) = material.normal_texture.as_ref().and_then(|texture| file.get_texture_path(texture))
+		{
+			desc.normal_map = path;
+			desc.features.normal_mapping = true;
+		}
+		if let Some(emissive) = material.emissive_factor
+		{
+			desc.emissive = emissive;
+		}
+		if material.alpha_mode.as_ref().map(|mode| mode.as_str()) == Some("BLEND")
+		{
+			desc.blend = BlendMode::Alpha;
+		}
+		return Ok(desc);
+	}
+}
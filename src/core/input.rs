@@ -1,10 +1,34 @@
+use crate::core::{unversioned_to_v1, FileFormat};
 use bit_vec::BitVec;
+use sdl2::controller::{Axis, Button};
 use sdl2::keyboard::Scancode;
 use sdl2::mouse::MouseButton;
+use serde_derive::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::path::Path;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const BINDINGS_FORMAT: FileFormat = FileFormat {
+	name: "bindings",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+// Before the repeat of Trigger::Repeat starts, and how often it fires after that
+const REPEAT_DELAY: f32 = 0.4;
+const REPEAT_RATE: f32 = 12.0;
+// Bindings of controller buttons and axes start with this, like "Pad a" or "Pad leftx-"
+const PAD_PREFIX: &str = "Pad ";
+// Half an axis is pressed when pushed past this, as a fraction of its range
+const AXIS_PRESS_THRESHOLD: f32 = 0.5;
+// Analog input below this is ignored, as sticks rarely rest exactly at the center
+const ANALOG_DEAD_ZONE: f32 = 0.15;
+const CONTROLLER_AXES: [Axis; 6] =
+	[Axis::LeftX, Axis::LeftY, Axis::RightX, Axis::RightY, Axis::TriggerLeft, Axis::TriggerRight];
+const ANALOG_CHANNELS: [AnalogChannel; 2] = [AnalogChannel::STEERING, AnalogChannel::THROTTLE];
 
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Action
 {
 	FORWARD,
@@ -20,32 +44,405 @@ pub enum Action
 	CAM_RIGHT,
 	CURSOR_CAPTURE_TOGGLE,
 	TERMINATE,
+	EDITOR_TOGGLE,
+	EDITOR_NEXT_OBJECT,
+	EDITOR_PREV_FIELD,
+	EDITOR_NEXT_FIELD,
+	EDITOR_DECREASE,
+	EDITOR_INCREASE,
+	EDITOR_SAVE,
+	FRAME_SELECTION,
+	CAMERA_TOGGLE,
+	INTERACT,
+	LOG_VIEW_TOGGLE,
+	TOGGLE_DEBUG_HUD,
+	MENU_UP,
+	MENU_DOWN,
+	MENU_LEFT,
+	MENU_RIGHT,
+	MENU_SELECT,
+	PAINT_TOGGLE,
+	// Was only for strokes, before the editors shared their history
+	#[serde(alias = "PAINT_UNDO")]
+	EDIT_UNDO,
+	EDIT_REDO,
+	PAINT_SAVE,
+	PAINT_BRUSH_SMALLER,
+	PAINT_BRUSH_LARGER,
+	GALLERY_TOGGLE,
+	GALLERY_PREV,
+	GALLERY_NEXT,
+	GALLERY_SWAP,
+	GALLERY_SLIDER_LEFT,
+	GALLERY_SLIDER_RIGHT,
+	#[serde(skip)]
 	LENGTH_OF_ENUM,
 }
 
+/// Analog input from a game controller, in [-1, 1]. Delivered along with the actions it stands in for, see
+/// get_actions().
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnalogChannel
+{
+	/// The left stick, positive to the right.
+	STEERING,
+	/// The right trigger, minus the left one.
+	THROTTLE,
+}
+
+impl AnalogChannel
+{
+	/// Returns the actions the channel is delivered with, as their keys give the same input fully pressed.
+	fn get_actions(self) -> [Action; 2]
+	{
+		return match self
+		{
+			AnalogChannel::STEERING => [Action::LEFT, Action::RIGHT],
+			AnalogChannel::THROTTLE => [Action::FORWARD, Action::BACK],
+		};
+	}
+}
+
 pub enum ActionType
 {
 	IMMEDIATE,
 	TICK,
 }
 
-#[derive(PartialEq)]
+/// Which controls are active. Consumers registered for a context only get actions while it is active, so the same
+/// keys can drive a character on foot and a vehicle.
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputContext
+{
+	OnFoot,
+	Vehicle,
+	/// The menu screens, where neither the character nor the car is controlled.
+	Menu,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum KeyEventState
 {
 	PRESSED,
 	RELEASED,
 }
 
+/// When a key binding triggers its action. Times are in seconds.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Trigger
+{
+	/// Every tick while the key is held.
+	Held,
+	/// Once when the key is pressed.
+	Press,
+	/// Once when the key is released.
+	Release,
+	/// Once when the key is released, if it was held for less than the threshold.
+	Tap
+	{
+		threshold: f32,
+	},
+	/// Once when the key has been held for the threshold.
+	Hold
+	{
+		threshold: f32,
+	},
+	/// When the key is pressed, then at the rate per second while it is held, after the delay.
+	Repeat
+	{
+		delay: f32,
+		rate: f32,
+	},
+}
+
+/// Binds a key, by its SDL scancode name, to an action. Modifiers can be put in front, like "Ctrl+Z" or
+/// "Ctrl+Shift+Z", so the binding only triggers with exactly those modifiers held. Bindings without them trigger
+/// whatever is held.
+///
+/// Game controller buttons and axes are bound by their SDL names after "Pad ", like "Pad a" or "Pad dpup". An axis
+/// is split in halves, "Pad leftx-" and "Pad leftx+", each pressed while the axis is pushed past the middle of that
+/// half. Triggers only have the positive half, "Pad righttrigger".
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Binding
+{
+	pub key: String,
+	pub action: Action,
+	pub trigger: Trigger,
+}
+
+impl Binding
+{
+	fn new(key: &str, action: Action, trigger: Trigger) -> Binding
+	{
+		return Binding {
+			key: key.to_string(),
+			action: action,
+			trigger: trigger,
+		};
+	}
+}
+
+/// The bindings used if there is no bindings file.
+fn default_bindings() -> Vec<Binding>
+{
+	let repeat = Trigger::Repeat {
+		delay: REPEAT_DELAY,
+		rate: REPEAT_RATE,
+	};
+	return vec![
+		Binding::new("W", Action::FORWARD, Trigger::Held),
+		Binding::new("A", Action::LEFT, Trigger::Held),
+		Binding::new("S", Action::BACK, Trigger::Held),
+		Binding::new("D", Action::RIGHT, Trigger::Held),
+		Binding::new("Space", Action::UP, Trigger::Held),
+		Binding::new("Left Ctrl", Action::DOWN, Trigger::Held),
+		Binding::new("Left Shift", Action::SPRINT, Trigger::Held),
+		Binding::new("Up", Action::CAM_UP, Trigger::Held),
+		Binding::new("Left", Action::CAM_LEFT, Trigger::Held),
+		Binding::new("Down", Action::CAM_DOWN, Trigger::Held),
+		Binding::new("Right", Action::CAM_RIGHT, Trigger::Held),
+		Binding::new("Escape", Action::TERMINATE, Trigger::Press),
+		Binding::new("F", Action::CURSOR_CAPTURE_TOGGLE, Trigger::Press),
+		Binding::new("F2", Action::EDITOR_TOGGLE, Trigger::Press),
+		Binding::new("Tab", Action::EDITOR_NEXT_OBJECT, Trigger::Press),
+		Binding::new("PageUp", Action::EDITOR_PREV_FIELD, repeat),
+		Binding::new("PageDown", Action::EDITOR_NEXT_FIELD, repeat),
+		Binding::new("-", Action::EDITOR_DECREASE, repeat),
+		Binding::new("Keypad -", Action::EDITOR_DECREASE, repeat),
+		Binding::new("=", Action::EDITOR_INCREASE, repeat),
+		Binding::new("Keypad +", Action::EDITOR_INCREASE, repeat),
+		Binding::new("F5", Action::EDITOR_SAVE, Trigger::Press),
+		Binding::new("E", Action::INTERACT, Trigger::Press),
+		Binding::new("Home", Action::FRAME_SELECTION, Trigger::Press),
+		Binding::new("C", Action::CAMERA_TOGGLE, Trigger::Press),
+		Binding::new("F3", Action::LOG_VIEW_TOGGLE, Trigger::Press),
+		Binding::new("F4", Action::TOGGLE_DEBUG_HUD, Trigger::Press),
+		Binding::new("Up", Action::MENU_UP, repeat),
+		Binding::new("Down", Action::MENU_DOWN, repeat),
+		Binding::new("Left", Action::MENU_LEFT, repeat),
+		Binding::new("Right", Action::MENU_RIGHT, repeat),
+		Binding::new("Return", Action::MENU_SELECT, Trigger::Press),
+		Binding::new("F6", Action::PAINT_TOGGLE, Trigger::Press),
+		Binding::new("Ctrl+Z", Action::EDIT_UNDO, repeat),
+		Binding::new("Ctrl+Y", Action::EDIT_REDO, repeat),
+		Binding::new("Ctrl+Shift+Z", Action::EDIT_REDO, repeat),
+		Binding::new("F7", Action::PAINT_SAVE, Trigger::Press),
+		Binding::new("[", Action::PAINT_BRUSH_SMALLER, repeat),
+		Binding::new("]", Action::PAINT_BRUSH_LARGER, repeat),
+		Binding::new("F8", Action::GALLERY_TOGGLE, Trigger::Press),
+		Binding::new(",", Action::GALLERY_PREV, repeat),
+		Binding::new(".", Action::GALLERY_NEXT, repeat),
+		Binding::new("/", Action::GALLERY_SWAP, Trigger::Press),
+		Binding::new(";", Action::GALLERY_SLIDER_LEFT, repeat),
+		Binding::new("'", Action::GALLERY_SLIDER_RIGHT, repeat),
+		Binding::new("Pad lefty-", Action::FORWARD, Trigger::Held),
+		Binding::new("Pad righttrigger", Action::FORWARD, Trigger::Held),
+		Binding::new("Pad lefty+", Action::BACK, Trigger::Held),
+		Binding::new("Pad lefttrigger", Action::BACK, Trigger::Held),
+		Binding::new("Pad leftx-", Action::LEFT, Trigger::Held),
+		Binding::new("Pad leftx+", Action::RIGHT, Trigger::Held),
+		Binding::new("Pad a", Action::UP, Trigger::Held),
+		Binding::new("Pad leftstick", Action::SPRINT, Trigger::Held),
+		Binding::new("Pad righty-", Action::CAM_UP, Trigger::Held),
+		Binding::new("Pad righty+", Action::CAM_DOWN, Trigger::Held),
+		Binding::new("Pad rightx-", Action::CAM_LEFT, Trigger::Held),
+		Binding::new("Pad rightx+", Action::CAM_RIGHT, Trigger::Held),
+		Binding::new("Pad back", Action::TERMINATE, Trigger::Press),
+		Binding::new("Pad x", Action::INTERACT, Trigger::Press),
+		Binding::new("Pad y", Action::CAMERA_TOGGLE, Trigger::Press),
+		Binding::new("Pad dpup", Action::MENU_UP, repeat),
+		Binding::new("Pad dpdown", Action::MENU_DOWN, repeat),
+		Binding::new("Pad dpleft", Action::MENU_LEFT, repeat),
+		Binding::new("Pad dpright", Action::MENU_RIGHT, repeat),
+		Binding::new("Pad start", Action::MENU_SELECT, Trigger::Press),
+	];
+}
+
+/// The actions delivered to a consumer, with what happened to their keys since it was last called.
+#[derive(Clone)]
+pub struct ActionEvents
+{
+	triggered: BitVec,
+	pressed: BitVec,
+	released: BitVec,
+	// Indexed by AnalogChannel, only given to tick consumers
+	analog: [f32; ANALOG_CHANNELS.len()],
+}
+
+impl ActionEvents
+{
+	fn new() -> ActionEvents
+	{
+		ActionEvents {
+			triggered: BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false),
+			pressed: BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false),
+			released: BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false),
+			analog: [0.0; ANALOG_CHANNELS.len()],
+		}
+	}
+
+	/// Returns true if the action triggered, as its bindings' Trigger says. Held actions trigger every time.
+	pub fn is_triggered(&self, action: Action) -> bool
+	{
+		return self.triggered.get(action as usize).unwrap();
+	}
+
+	/// Returns true if a key of the action went down.
+	pub fn was_pressed(&self, action: Action) -> bool
+	{
+		return self.pressed.get(action as usize).unwrap();
+	}
+
+	/// Returns the analog input of the channel, 0 if no controller gives any. Used in place of the keys of the
+	/// channel's actions when not 0.
+	pub fn get_analog(&self, channel: AnalogChannel) -> f32
+	{
+		return self.analog[channel as usize];
+	}
+
+	fn any(&self) -> bool
+	{
+		return self.triggered.any() ||
+			self.pressed.any() ||
+			self.released.any() ||
+			self.analog.iter().any(|&value| value != 0.0);
+	}
+
+	fn merge(&mut self, other: &ActionEvents)
+	{
+		self.triggered.or(&other.triggered);
+		self.pressed.or(&other.pressed);
+		self.released.or(&other.released);
+	}
+
+	/// Returns the events of the given actions only.
+	fn masked(&self, actions: &BitVec) -> ActionEvents
+	{
+		let mut masked = self.clone();
+		masked.triggered.and(actions);
+		masked.pressed.and(actions);
+		masked.released.and(actions);
+		for &channel in ANALOG_CHANNELS.iter()
+		{
+			if !channel.get_actions().iter().any(|&action| actions.get(action as usize).unwrap())
+			{
+				masked.analog[channel as usize] = 0.0;
+			}
+		}
+		return masked;
+	}
+}
+
+/// Modifier keys held along with the key of a binding.
+#[derive(Clone, Copy, PartialEq)]
+struct Modifiers
+{
+	ctrl: bool,
+	shift: bool,
+}
+
+impl Modifiers
+{
+	/// Splits the modifiers in front of a key name off it. Returns None for the modifiers if there are none.
+	fn parse(key: &str) -> (Option<Modifiers>, &str)
+	{
+		let mut modifiers = Modifiers {
+			ctrl: false,
+			shift: false,
+		};
+		let mut key = key;
+		loop
+		{
+			if let Some(rest) = key.strip_prefix("Ctrl+")
+			{
+				modifiers.ctrl = true;
+				key = rest;
+			}
+			else if let Some(rest) = key.strip_prefix("Shift+")
+			{
+				modifiers.shift = true;
+				key = rest;
+			}
+			else
+			{
+				break;
+			}
+		}
+		if !modifiers.ctrl && !modifiers.shift
+		{
+			return (None, key);
+		}
+		return (Some(modifiers), key);
+	}
+
+	fn from_held(held_keys: &[Scancode]) -> Modifiers
+	{
+		return Modifiers {
+			ctrl: held_keys.iter().any(|&key| key == Scancode::LCtrl || key == Scancode::RCtrl),
+			shift: held_keys.iter().any(|&key| key == Scancode::LShift || key == Scancode::RShift),
+		};
+	}
+}
+
+/// What a binding is pressed by.
+#[derive(Clone, Copy, PartialEq)]
+enum BoundInput
+{
+	Key(Scancode),
+	Button(Button),
+	// Half of an axis, the positive one if true
+	Axis(Axis, bool),
+}
+
+impl BoundInput
+{
+	/// Parses a key name, or the name of a controller input after PAD_PREFIX, see Binding.
+	fn parse(name: &str) -> Option<BoundInput>
+	{
+		let pad_name = match name.strip_prefix(PAD_PREFIX)
+		{
+			Some(pad_name) => pad_name,
+			None => return Scancode::from_name(name).map(BoundInput::Key),
+		};
+		if let Some(button) = Button::from_string(pad_name)
+		{
+			return Some(BoundInput::Button(button));
+		}
+		let (axis_name, positive) = match pad_name.strip_suffix('-')
+		{
+			Some(axis_name) => (axis_name, false),
+			None => (pad_name.strip_suffix('+').unwrap_or(pad_name), true),
+		};
+		return Axis::from_string(axis_name).map(|axis| BoundInput::Axis(axis, positive));
+	}
+}
+
+struct KeyBinding
+{
+	input: BoundInput,
+	// None for bindings that ignore the modifiers
+	modifiers: Option<Modifiers>,
+	action: Action,
+	trigger: Trigger,
+	// While the key is held
+	pressed_at: Option<Instant>,
+	// When Hold and Repeat triggers fire next
+	next_fire: Option<Instant>,
+}
+
 struct Consumer
 {
 	actions: BitVec,
+	// None for consumers active in every context
+	context: Option<InputContext>,
 	ptr: Rc<RefCell<dyn InputConsumer>>,
 }
 
 pub trait InputConsumer
 {
 	fn get_handled_actions(&self) -> BitVec;
-	fn consume(&mut self, actions: BitVec);
+	fn consume(&mut self, actions: &ActionEvents);
 }
 
 pub trait MouseConsumer
@@ -56,88 +453,331 @@ pub trait MouseConsumer
 
 struct InputState
 {
-	actions: BitVec,
+	// Actions with a held Trigger::Held binding
+	held: BitVec,
+	// Held modifier keys
+	modifier_keys: Vec<Scancode>,
+	// Since the last tick
+	pending: ActionEvents,
 	mouse_delta: (i32, i32),
+	// Of the controllers, indexed by Axis, in [-1, 1]
+	axes: [f32; CONTROLLER_AXES.len()],
+}
+
+impl InputState
+{
+	fn get_axis(&self, axis: Axis) -> f32
+	{
+		let value = self.axes[axis as usize];
+		if value.abs() < ANALOG_DEAD_ZONE
+		{
+			return 0.0;
+		}
+		// Rescaled so the input starts from 0 at the edge of the dead zone
+		return value.signum() * (value.abs() - ANALOG_DEAD_ZONE) / (1.0 - ANALOG_DEAD_ZONE);
+	}
+
+	/// Returns the analog channels, indexed by AnalogChannel.
+	fn get_analog(&self) -> [f32; ANALOG_CHANNELS.len()]
+	{
+		let mut analog = [0.0; ANALOG_CHANNELS.len()];
+		analog[AnalogChannel::STEERING as usize] = self.get_axis(Axis::LeftX);
+		analog[AnalogChannel::THROTTLE as usize] = self.get_axis(Axis::TriggerRight) - self.get_axis(Axis::TriggerLeft);
+		return analog;
+	}
 }
 
 pub struct InputHandler
 {
+	bindings: Vec<KeyBinding>,
 	state: InputState,
 	tick_action_consumers: Vec<Consumer>,
 	immediate_action_consumers: Vec<Consumer>,
 	mouse_consumer: Option<Rc<RefCell<dyn MouseConsumer>>>,
+	context: InputContext,
 }
 
 impl InputHandler
 {
 	pub fn new() -> InputHandler
 	{
-		InputHandler {
+		let mut input_handler = InputHandler {
+			bindings: Vec::new(),
 			state: InputState {
-				actions: BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false),
+				held: BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false),
+				modifier_keys: Vec::new(),
+				pending: ActionEvents::new(),
 				mouse_delta: (0, 0),
+				axes: [0.0; CONTROLLER_AXES.len()],
 			},
 			// Can at most have LENGTH_OF_ENUM different consumers
 			tick_action_consumers: Vec::with_capacity(Action::LENGTH_OF_ENUM as usize),
 			immediate_action_consumers: Vec::with_capacity(Action::LENGTH_OF_ENUM as usize),
 			mouse_consumer: None,
+			context: InputContext::Vehicle,
+		};
+		input_handler.set_bindings(&default_bindings());
+		return input_handler;
+	}
+
+	/// Replaces the key bindings with the ones in the given file. Writes the default bindings to it if there is no
+	/// such file, so they can be edited. Keys are SDL scancode names, like "W" or "Left Shift", or controller inputs
+	/// like "Pad a", see Binding.
+	pub fn load_bindings(&mut self, path: &str)
+	{
+		let bindings: Vec<Binding> = if Path::new(path).exists()
+		{
+			match BINDINGS_FORMAT.read::<Vec<Binding>>(path)
+			{
+				Ok(mut bindings) =>
+				{
+					// Actions added since the file was written get their default keys, and actions only bound to keys
+					// get their default controller inputs
+					let is_pad = |binding: &Binding| binding.key.starts_with(PAD_PREFIX);
+					let defaults = default_bindings();
+					let missing: Vec<Binding> = defaults
+						.into_iter()
+						.filter(|default| {
+							!bindings
+								.iter()
+								.any(|binding| binding.action == default.action && is_pad(binding) == is_pad(default))
+						})
+						.collect();
+					bindings.extend(missing);
+					bindings
+				}
+				Err(e) =>
+				{
+					warning!("Could not read key bindings: {}", e);
+					return;
+				}
+			}
 		}
+		else
+		{
+			let bindings = default_bindings();
+			if let Err(e) = BINDINGS_FORMAT.write(path, &bindings)
+			{
+				warning!("Could not write default key bindings: {}", e);
+			}
+			bindings
+		};
+		self.set_bindings(&bindings);
+	}
+
+	fn set_bindings(&mut self, bindings: &[Binding])
+	{
+		self.bindings = bindings
+			.iter()
+			.filter_map(|binding| {
+				let (modifiers, key) = Modifiers::parse(&binding.key);
+				match BoundInput::parse(key)
+				{
+					Some(input) => Some(KeyBinding {
+						input: input,
+						modifiers: modifiers,
+						action: binding.action,
+						trigger: binding.trigger,
+						pressed_at: None,
+						next_fire: None,
+					}),
+					None =>
+					{
+						warning!("Unknown key '{}' bound to {:?}", binding.key, binding.action);
+						None
+					}
+				}
+			})
+			.collect();
+		self.state.held.clear();
 	}
 
 	pub fn update_key(&mut self, scancode: Scancode, event_state: KeyEventState)
 	{
-		match scancode
-		{
-			Scancode::W => self.state.actions.set(Action::FORWARD as usize, event_state == KeyEventState::PRESSED),
-			Scancode::A => self.state.actions.set(Action::LEFT as usize, event_state == KeyEventState::PRESSED),
-			Scancode::S => self.state.actions.set(Action::BACK as usize, event_state == KeyEventState::PRESSED),
-			Scancode::D => self.state.actions.set(Action::RIGHT as usize, event_state == KeyEventState::PRESSED),
-			Scancode::Space => self.state.actions.set(Action::UP as usize, event_state == KeyEventState::PRESSED),
-			Scancode::LCtrl => self.state.actions.set(Action::DOWN as usize, event_state == KeyEventState::PRESSED),
-			Scancode::LShift => self.state.actions.set(Action::SPRINT as usize, event_state == KeyEventState::PRESSED),
-			Scancode::Up => self.state.actions.set(Action::CAM_UP as usize, event_state == KeyEventState::PRESSED),
-			Scancode::Left => self.state.actions.set(Action::CAM_LEFT as usize, event_state == KeyEventState::PRESSED),
-			Scancode::Down => self.state.actions.set(Action::CAM_DOWN as usize, event_state == KeyEventState::PRESSED),
-			Scancode::Right =>
+		let is_modifier = [Scancode::LCtrl, Scancode::RCtrl, Scancode::LShift, Scancode::RShift].contains(&scancode);
+		if is_modifier && event_state == KeyEventState::PRESSED
+		{
+			if !self.state.modifier_keys.contains(&scancode)
+			{
+				self.state.modifier_keys.push(scancode);
+			}
+		}
+		else if is_modifier
+		{
+			self.state.modifier_keys.retain(|&key| key != scancode);
+		}
+		if !self.update_input(BoundInput::Key(scancode), event_state)
+		{
+			let statestr = if event_state == KeyEventState::PRESSED
+			{
+				"pressed"
+			}
+			else
+			{
+				"released"
+			};
+			println!("Unmapped key {} {}", scancode.name(), statestr);
+		}
+	}
+
+	pub fn update_controller_button(&mut self, button: Button, event_state: KeyEventState)
+	{
+		if !self.update_input(BoundInput::Button(button), event_state)
+		{
+			let statestr = if event_state == KeyEventState::PRESSED
 			{
-				self.state.actions.set(Action::CAM_RIGHT as usize, event_state == KeyEventState::PRESSED)
+				"pressed"
 			}
-			Scancode::Escape =>
+			else
+			{
+				"released"
+			};
+			println!("Unmapped controller button {} {}", button.string(), statestr);
+		}
+	}
+
+	/// Updates the analog channels from an axis of a controller, and presses or releases the bindings of its halves as
+	/// it crosses AXIS_PRESS_THRESHOLD.
+	pub fn update_controller_axis(&mut self, axis: Axis, value: i16)
+	{
+		let value = (value as f32 / i16::MAX as f32).max(-1.0);
+		let previous = std::mem::replace(&mut self.state.axes[axis as usize], value);
+		for &positive in [false, true].iter()
+		{
+			let sign = if positive
 			{
-				self.state.actions.set(Action::TERMINATE as usize, event_state == KeyEventState::PRESSED)
+				1.0
 			}
-			Scancode::F =>
+			else
 			{
-				self.state.actions.set(Action::CURSOR_CAPTURE_TOGGLE as usize, event_state == KeyEventState::PRESSED)
+				-1.0
+			};
+			let was_pressed = previous * sign > AXIS_PRESS_THRESHOLD;
+			let is_pressed = value * sign > AXIS_PRESS_THRESHOLD;
+			if was_pressed && !is_pressed
+			{
+				self.update_input(BoundInput::Axis(axis, positive), KeyEventState::RELEASED);
 			}
-			_ =>
+			else if !was_pressed && is_pressed
 			{
-				let statestr = if event_state == KeyEventState::PRESSED
+				self.update_input(BoundInput::Axis(axis, positive), KeyEventState::PRESSED);
+			}
+		}
+	}
+
+	/// Releases everything held on the controllers, e.g. when one is disconnected.
+	pub fn release_controllers(&mut self)
+	{
+		for &axis in CONTROLLER_AXES.iter()
+		{
+			self.update_controller_axis(axis, 0);
+		}
+		let held_buttons: Vec<BoundInput> = self
+			.bindings
+			.iter()
+			.filter(|binding| binding.pressed_at.is_some())
+			.map(|binding| binding.input)
+			.filter(|input| matches!(input, BoundInput::Button(_)))
+			.collect();
+		for input in held_buttons
+		{
+			self.update_input(input, KeyEventState::RELEASED);
+		}
+	}
+
+	/// Presses or releases the bindings of the input. Returns false if nothing is bound to it.
+	fn update_input(&mut self, input: BoundInput, event_state: KeyEventState) -> bool
+	{
+		let now = Instant::now();
+		let mut events = ActionEvents::new();
+		let mut mapped = false;
+		let modifiers = Modifiers::from_held(&self.state.modifier_keys);
+		for binding in self.bindings.iter_mut().filter(|binding| binding.input == input)
+		{
+			mapped = true;
+			let action = binding.action as usize;
+			if event_state == KeyEventState::PRESSED
+			{
+				// Key repeats are ignored, Trigger::Repeat has its own rate
+				if binding.pressed_at.is_some() || binding.modifiers.map_or(false, |needed| needed != modifiers)
+				{
+					continue;
+				}
+				binding.pressed_at = Some(now);
+				events.pressed.set(action, true);
+				match binding.trigger
 				{
-					"pressed"
+					Trigger::Press => events.triggered.set(action, true),
+					Trigger::Hold {
+						threshold,
+					} => binding.next_fire = Some(now + Duration::from_secs_f32(threshold.max(0.0))),
+					Trigger::Repeat {
+						delay,
+						..
+					} =>
+					{
+						events.triggered.set(action, true);
+						binding.next_fire = Some(now + Duration::from_secs_f32(delay.max(0.0)));
+					}
+					_ => (),
 				}
-				else
+			}
+			else
+			{
+				let pressed_at = match binding.pressed_at.take()
 				{
-					"released"
+					Some(pressed_at) => pressed_at,
+					None => continue,
 				};
-				println!("Unmapped key {} {}", scancode.name(), statestr);
+				binding.next_fire = None;
+				events.released.set(action, true);
+				match binding.trigger
+				{
+					Trigger::Release => events.triggered.set(action, true),
+					Trigger::Tap {
+						threshold,
+					} if now.duration_since(pressed_at).as_secs_f32() < threshold => events.triggered.set(action, true),
+					_ => (),
+				}
+			}
+		}
+		if !mapped
+		{
+			return false;
+		}
+
+		self.dispatch(events);
+		return true;
+	}
+
+	/// Updates the held actions, and passes new events on to the immediate consumers that handle them. Tick consumers
+	/// get them with the next tick.
+	fn dispatch(&mut self, events: ActionEvents)
+	{
+		self.state.held.clear();
+		for binding in self.bindings.iter().filter(|binding| binding.pressed_at.is_some())
+		{
+			if binding.trigger == Trigger::Held
+			{
+				self.state.held.set(binding.action as usize, true);
 			}
 		}
+		self.state.pending.merge(&events);
 
 		// Early out if there's nothing to do
-		if self.state.actions.none()
+		if !events.any()
 		{
 			return;
 		}
 
-		// Handle immediate consumers
-		for consumer in self.immediate_action_consumers.iter()
+		// Held actions only reach immediate consumers along with a change to their actions
+		let mut with_held = events.clone();
+		with_held.triggered.or(&self.state.held);
+		for consumer in self.immediate_action_consumers.iter().filter(|consumer| self.is_listening(consumer))
 		{
-			let mut intersection = self.state.actions.clone();
-			intersection.and(&consumer.actions);
-			if intersection.any()
+			if events.masked(&consumer.actions).any()
 			{
-				consumer.ptr.borrow_mut().consume(intersection);
+				consumer.ptr.borrow_mut().consume(&with_held.masked(&consumer.actions));
 			}
 		}
 	}
@@ -172,6 +812,21 @@ impl InputHandler
 	}
 
 	pub fn register_actions<T: InputConsumer + 'static>(&mut self, consumer: Rc<RefCell<T>>, action_type: ActionType)
+	{
+		self.add_consumer(consumer, action_type, None);
+	}
+
+	/// Registers a consumer that only gets actions while the given context is active, see set_context().
+	pub fn register_context_actions<T: InputConsumer + 'static>(
+		&mut self, consumer: Rc<RefCell<T>>, action_type: ActionType, context: InputContext,
+	)
+	{
+		self.add_consumer(consumer, action_type, Some(context));
+	}
+
+	fn add_consumer<T: InputConsumer + 'static>(
+		&mut self, consumer: Rc<RefCell<T>>, action_type: ActionType, context: Option<InputContext>,
+	)
 	{
 		let actions_consumed = consumer.borrow().get_handled_actions();
 
@@ -180,50 +835,94 @@ impl InputHandler
 			debug_assert_eq!(actions_consumed.len(), Action::LENGTH_OF_ENUM as usize);
 			debug_assert!(actions_consumed.any());
 
-			// Cannot register same action twice
-			for consumer in self.immediate_action_consumers.iter()
-			{
-				let mut intersection = actions_consumed.clone();
-				intersection.and(&consumer.actions);
-				debug_assert!(intersection.none());
-			}
-
-			for consumer in self.tick_action_consumers.iter()
+			// Cannot register same action twice, unless only one of the consumers can be active at a time
+			for consumer in self.immediate_action_consumers.iter().chain(self.tick_action_consumers.iter())
 			{
+				if consumer.context.is_some() && context.is_some() && consumer.context != context
+				{
+					continue;
+				}
 				let mut intersection = actions_consumed.clone();
 				intersection.and(&consumer.actions);
 				debug_assert!(intersection.none());
 			}
 		}
 
+		let consumer = Consumer {
+			actions: actions_consumed,
+			context: context,
+			ptr: consumer,
+		};
 		match action_type
 		{
-			ActionType::IMMEDIATE => self.immediate_action_consumers.push(Consumer {
-				actions: actions_consumed,
-				ptr: consumer,
-			}),
-			ActionType::TICK => self.tick_action_consumers.push(Consumer {
-				actions: actions_consumed,
-				ptr: consumer,
-			}),
+			ActionType::IMMEDIATE => self.immediate_action_consumers.push(consumer),
+			ActionType::TICK => self.tick_action_consumers.push(consumer),
 		}
 	}
 
-	pub fn actions_tick(&self)
+	/// Switches the active controls. Tick consumers of the previous context get no more actions, so they should not
+	/// keep acting on the last ones.
+	pub fn set_context(&mut self, context: InputContext)
 	{
+		self.context = context;
+	}
+
+	fn is_listening(&self, consumer: &Consumer) -> bool
+	{
+		return consumer.context.map_or(true, |context| context == self.context);
+	}
+
+	/// Returns true while any action is held or waiting to fire, as held actions keep changing the scene every tick.
+	pub fn is_active(&self) -> bool
+	{
+		return self.state.held.any() ||
+			self.bindings.iter().any(|binding| binding.next_fire.is_some()) ||
+			self.state.get_analog().iter().any(|&value| value != 0.0);
+	}
+
+	/// Fires the held and repeating actions that are due, then passes everything that happened since the last tick on
+	/// to the tick consumers.
+	pub fn actions_tick(&mut self)
+	{
+		let now = Instant::now();
+		let mut timed = ActionEvents::new();
+		for binding in self.bindings.iter_mut()
+		{
+			match binding.next_fire
+			{
+				Some(next_fire) if next_fire <= now => timed.triggered.set(binding.action as usize, true),
+				_ => continue,
+			}
+			binding.next_fire = match binding.trigger
+			{
+				// Once per tick at most, a slow tick does not catch up
+				Trigger::Repeat {
+					rate,
+					..
+				} if rate > 0.0 => Some(now + Duration::from_secs_f32(1.0 / rate)),
+				_ => None,
+			};
+		}
+		if timed.triggered.any()
+		{
+			self.dispatch(timed);
+		}
+
+		let mut events = std::mem::replace(&mut self.state.pending, ActionEvents::new());
+		events.triggered.or(&self.state.held);
+		events.analog = self.state.get_analog();
 		// Early out if there's nothing to do
-		if self.state.actions.none()
+		if !events.any()
 		{
 			return;
 		}
 
-		for consumer in self.tick_action_consumers.iter()
+		for consumer in self.tick_action_consumers.iter().filter(|consumer| self.is_listening(consumer))
 		{
-			let mut intersection = self.state.actions.clone();
-			intersection.and(&consumer.actions);
-			if intersection.any()
+			let consumed = events.masked(&consumer.actions);
+			if consumed.any()
 			{
-				consumer.ptr.borrow_mut().consume(intersection);
+				consumer.ptr.borrow_mut().consume(&consumed);
 			}
 		}
 	}
@@ -0,0 +1,63 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Older entries are dropped
+const MAX_LOG_ENTRIES: usize = 500;
+
+static ENTRIES: Mutex<Vec<LogEntry>> = Mutex::new(Vec::new());
+
+/// Logs a warning, formatted like println!(). See Logger.
+macro_rules! warning {
+	($($arg:tt)*) => {
+		crate::core::Logger::log(crate::core::LogLevel::Warning, format!($($arg)*))
+	};
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogLevel
+{
+	Warning,
+	Error,
+}
+
+#[derive(Clone)]
+pub struct LogEntry
+{
+	pub level: LogLevel,
+	pub message: String,
+	pub time: Instant,
+}
+
+/// Collects the warnings and errors of the game, so they can be shown in game as well as printed. Can be used from any
+/// thread.
+pub struct Logger;
+
+impl Logger
+{
+	/// Prints the message and keeps it, see get_entries().
+	pub fn log(level: LogLevel, message: String)
+	{
+		match level
+		{
+			LogLevel::Warning => println!("WARNING: {}", message),
+			LogLevel::Error => println!("ERROR: {}", message),
+		}
+		// A thread that panicked while holding the lock left the entries usable
+		let mut entries = ENTRIES.lock().unwrap_or_else(|e| e.into_inner());
+		if entries.len() == MAX_LOG_ENTRIES
+		{
+			entries.remove(0);
+		}
+		entries.push(LogEntry {
+			level: level,
+			message: message,
+			time: Instant::now(),
+		});
+	}
+
+	/// Returns the kept entries, oldest first.
+	pub fn get_entries() -> Vec<LogEntry>
+	{
+		return ENTRIES.lock().unwrap_or_else(|e| e.into_inner()).clone();
+	}
+}
@@ -1,14 +1,189 @@
-use crate::renderer::{MainPass, RenderState, Texture};
+use crate::core::gltf::{is_gltf, GltfModel};
+use crate::core::{unversioned_to_v1, FileFormat};
+use crate::renderer::{BufferHandle, DamageTexture, MainPass, PipelinePermutation, RenderState, Texture};
+use ash::util::Align;
 use ash::version::DeviceV1_0;
 use ash::{vk, Device};
+use image::RgbaImage;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::mem::{align_of, size_of};
+use std::path::Path;
 use std::ptr;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+
+const MATERIAL_FORMAT: FileFormat = FileFormat {
+	name: "material",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+// Textures of imported materials without their own
+const DEFAULT_TEXTURE: &str = "assets/original/textures/white.png";
+const DEFAULT_NORMAL_MAP: &str = "assets/original/textures/flat_normal.png";
+// Drawn until the textures have been loaded in the background
+const PLACEHOLDER_TEXTURE: [u8; 4] = [255, 255, 255, 255];
+const PLACEHOLDER_NORMAL_MAP: [u8; 4] = [128, 128, 255, 255];
+
+/// How the color of a material is combined with what is behind it.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode
+{
+	Opaque,
+	/// Blended by the alpha of the texture and the opacity.
+	Alpha,
+	/// Added, weighted by the alpha of the texture and the opacity.
+	Additive,
+}
+
+/// Shader features that can be turned off per material.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialFeatures
+{
+	pub normal_mapping: bool,
+	pub emissive: bool,
+}
+
+impl Default for MaterialFeatures
+{
+	fn default() -> MaterialFeatures
+	{
+		MaterialFeatures {
+			normal_mapping: true,
+			emissive: true,
+		}
+	}
+}
+
+/// A texture made of a grid of animation frames, played row by row from the top left.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Flipbook
+{
+	pub columns: u32,
+	pub rows: u32,
+	/// Frames per second, 0 shows the whole texture.
+	pub fps: f32,
+}
+
+impl Default for Flipbook
+{
+	fn default() -> Flipbook
+	{
+		Flipbook {
+			columns: 1,
+			rows: 1,
+			fps: 0.0,
+		}
+	}
+}
+
+/// Description of a material, as stored in material asset files.
+///
+/// The shaders, features and blend mode select the pipeline permutation the material is drawn with.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialDesc
+{
+	/// Precompiled shaders, see PipelinePermutation.
+	pub vertex_shader: String,
+	pub fragment_shader: String,
+	pub features: MaterialFeatures,
+	pub blend: BlendMode,
+	pub texture: String,
+	pub normal_map: String,
+	/// Multiplied with the texture color.
+	pub tint: [f32; 3],
+	/// Multiplied with the texture alpha, for blended materials.
+	pub opacity: f32,
+	/// Multiplier for the specular highlights.
+	pub specular: f32,
+	/// Light emitted by the surface, added after lighting.
+	pub emissive: [f32; 3],
+	/// Texture coordinates scrolled per second of game time, along u and v.
+	pub uv_scroll: [f32; 2],
+	/// Animates the textures, after scrolling them.
+	pub flipbook: Flipbook,
+}
+
+impl Default for MaterialDesc
+{
+	fn default() -> MaterialDesc
+	{
+		let permutation = PipelinePermutation::default();
+		MaterialDesc {
+			vertex_shader: permutation.vertex_shader,
+			fragment_shader: permutation.fragment_shader,
+			features: MaterialFeatures::default(),
+			blend: permutation.blend,
+			texture: String::new(),
+			normal_map: String::new(),
+			tint: [1.0, 1.0, 1.0],
+			opacity: 1.0,
+			specular: 1.0,
+			emissive: [0.0, 0.0, 0.0],
+			uv_scroll: [0.0, 0.0],
+			flipbook: Flipbook::default(),
+		}
+	}
+}
+
+impl MaterialDesc
+{
+	/// Plain white, for imported materials. Textures and normal mapping are added where the importer finds them.
+	pub fn untextured() -> MaterialDesc
+	{
+		let mut desc = MaterialDesc {
+			texture: DEFAULT_TEXTURE.to_string(),
+			normal_map: DEFAULT_NORMAL_MAP.to_string(),
+			..Default::default()
+		};
+		desc.features.normal_mapping = false;
+		return desc;
+	}
+
+	pub fn get_permutation(&self) -> PipelinePermutation
+	{
+		PipelinePermutation {
+			vertex_shader: self.vertex_shader.clone(),
+			fragment_shader: self.fragment_shader.clone(),
+			normal_mapping: self.features.normal_mapping,
+			emissive: self.features.emissive,
+			blend: self.blend,
+		}
+	}
+}
+
+/// Must match the MaterialBlock (std140) and MaterialParams (std430) in phong.frag.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MaterialUniforms
+{
+	tint: [f32; 4],
+	emissive: [f32; 4],
+	specular: f32,
+	_padding: f32,
+	uv_scroll: [f32; 2],
+	// Columns, rows and frames per second, see Flipbook
+	flipbook: [f32; 4],
+}
 
 pub struct Material
 {
+	descriptor_pool: vk::DescriptorPool,
 	descriptor_sets: Vec<vk::DescriptorSet>,
-	texture: Texture,
-	normal_map: Texture,
+	// Textures and parameters can be changed at runtime, e.g. by the material editor
+	texture: RefCell<Texture>,
+	normal_map: RefCell<Texture>,
+	desc: RefCell<MaterialDesc>,
+	// Baked damage of the object the material belongs to, see Material::create_damaged()
+	damage: Option<DamageTexture>,
+	// See RenderState::get_permutation_index()
+	permutation_index: Cell<usize>,
+	params_ub: BufferHandle,
+	// The asset file the material was loaded from, if any
+	asset_path: Option<String>,
 
 	// Keep a pointer to the device for cleanup
 	device: Rc<Device>,
@@ -16,7 +191,76 @@ pub struct Material
 
 impl Material
 {
-	pub fn new(rs: &RenderState, mp: &MainPass, texture_path: &str, normalmap_path: &str) -> Rc<Material>
+	/// Loads a material from the given asset file.
+	pub fn load(rs: &RenderState, mp: &MainPass, path: &str) -> Result<Rc<Material>, String>
+	{
+		let desc = Material::read_desc(path)?;
+		return Ok(Material::from_desc(rs, mp, desc, Some(path.to_string()), None));
+	}
+
+	/// Creates a material that is not backed by an asset file, like the materials of imported models.
+	pub fn new(rs: &RenderState, mp: &MainPass, desc: MaterialDesc) -> Rc<Material>
+	{
+		return Material::from_desc(rs, mp, desc, None, None);
+	}
+
+	/// Creates a copy of the material for a single object, with its own damage texture. The copy starts from the
+	/// current parameters and textures, but does not follow later changes to this material or its asset file.
+	pub fn create_damaged(&self, rs: &RenderState, mp: &MainPass, damage: DamageTexture) -> Rc<Material>
+	{
+		return Material::from_desc(rs, mp, self.get_desc(), None, Some(damage));
+	}
+
+	/// Reads a material asset file, or the material of a glTF model, without checking that what it references exists.
+	pub fn read_asset_desc(path: &str) -> Result<MaterialDesc, String>
+	{
+		if is_gltf(path)
+		{
+			return GltfModel::read_material_desc(path);
+		}
+		return MATERIAL_FORMAT.read(path);
+	}
+
+	fn read_desc(path: &str) -> Result<MaterialDesc, String>
+	{
+		let desc = Material::read_asset_desc(path)?;
+		// Pipelines are created later, where missing shaders cannot be recovered from
+		for shader in [&desc.vertex_shader, &desc.fragment_shader].iter()
+		{
+			if !Path::new(shader).is_file()
+			{
+				return Err(format!("{}: shader {} not found", path, shader));
+			}
+		}
+		return Ok(desc);
+	}
+
+	/// Reads the asset file again, updating parameters and textures that changed.
+	pub fn reload(&self, rs: &RenderState) -> Result<(), String>
+	{
+		let path = match &self.asset_path
+		{
+			Some(path) => path,
+			None => return Err("material was not loaded from a file".to_string()),
+		};
+		let desc = Material::read_desc(path)?;
+		let current = self.get_desc();
+		if desc.texture != current.texture
+		{
+			self.set_texture(rs, &desc.texture, false);
+		}
+		if desc.normal_map != current.normal_map
+		{
+			self.set_texture(rs, &desc.normal_map, true);
+		}
+		self.set_params(&desc);
+		self.set_permutation(rs, &desc);
+		return Ok(());
+	}
+
+	fn from_desc(
+		rs: &RenderState, mp: &MainPass, desc: MaterialDesc, asset_path: Option<String>, damage: Option<DamageTexture>,
+	) -> Rc<Material>
 	{
 		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
@@ -30,58 +274,264 @@ impl Material
 			descriptor_sets = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
 		}
 
-		let texture = rs.load_image(texture_path, true);
+		let params_ub = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::UNIFORM_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&[Material::uniforms_from_desc(&desc)],
+			false,
+		);
+		let params_descriptor = vk::DescriptorBufferInfo {
+			buffer: params_ub.get(),
+			offset: 0,
+			range: size_of::<MaterialUniforms>() as u64,
+		};
+		let write_desc_sets = [vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			p_next: ptr::null(),
+			dst_set: descriptor_sets[0],
+			dst_binding: 2,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+			p_image_info: ptr::null(),
+			p_buffer_info: &params_descriptor,
+			p_texel_buffer_view: ptr::null(),
+		}];
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+
+		// The textures are loaded in the background, and find the material once they are done
+		let material = Rc::new_cyclic(|weak| Material {
+			descriptor_pool: mp.descriptor_pool,
+			descriptor_sets: descriptor_sets,
+			texture: RefCell::new(Material::load_texture(rs, weak, &desc.texture, false)),
+			normal_map: RefCell::new(Material::load_texture(rs, weak, &desc.normal_map, true)),
+			permutation_index: Cell::new(rs.get_permutation_index(&desc.get_permutation())),
+			desc: RefCell::new(desc),
+			damage: damage,
+			params_ub: params_ub,
+			asset_path: asset_path,
+			device: Rc::clone(&rs.device),
+		});
+		material.write_texture_descriptor(0, &material.texture.borrow());
+		material.write_texture_descriptor(1, &material.normal_map.borrow());
+		match &material.damage
+		{
+			Some(damage) => material.write_texture_descriptor(3, &damage.texture),
+			None => material.write_texture_descriptor(3, mp.get_empty_damage_texture()),
+		}
+		// Since materials are generally shared, return a refcount.
+		return material;
+	}
+
+	/// Starts loading a texture of the material in the background, see RenderState::load_image_async(). Returns a
+	/// white placeholder, or a flat one for normal maps.
+	fn load_texture(rs: &RenderState, material: &Weak<Material>, path: &str, normal_map: bool) -> Texture
+	{
+		let material = material.clone();
+		let loaded_path = path.to_string();
+		let placeholder = if normal_map
+		{
+			PLACEHOLDER_NORMAL_MAP
+		}
+		else
+		{
+			PLACEHOLDER_TEXTURE
+		};
+		return rs.load_image_async(path, !normal_map, placeholder, move |texture| {
+			if let Some(material) = material.upgrade()
+			{
+				material.set_loaded_texture(&loaded_path, texture, normal_map);
+			}
+		});
+	}
+
+	/// Replaces a placeholder with the texture loaded from the path, unless the material switched to another texture
+	/// since. Textures are uploaded between frames, so the descriptor set is not in use.
+	fn set_loaded_texture(&self, path: &str, texture: Texture, normal_map: bool)
+	{
+		let (current, binding, slot) = if normal_map
+		{
+			(self.desc.borrow().normal_map.clone(), 1, &self.normal_map)
+		}
+		else
+		{
+			(self.desc.borrow().texture.clone(), 0, &self.texture)
+		};
+		if current != path
+		{
+			return;
+		}
+		self.write_texture_descriptor(binding, &texture);
+		slot.replace(texture);
+	}
+
+	fn uniforms_from_desc(desc: &MaterialDesc) -> MaterialUniforms
+	{
+		MaterialUniforms {
+			tint: [desc.tint[0], desc.tint[1], desc.tint[2], desc.opacity],
+			emissive: [desc.emissive[0], desc.emissive[1], desc.emissive[2], 1.0],
+			specular: desc.specular,
+			_padding: 0.0,
+			uv_scroll: desc.uv_scroll,
+			flipbook: [
+				desc.flipbook.columns.max(1) as f32,
+				desc.flipbook.rows.max(1) as f32,
+				desc.flipbook.fps.max(0.0),
+				0.0,
+			],
+		}
+	}
+
+	fn write_texture_descriptor(&self, binding: u32, texture: &Texture)
+	{
 		let texture_descriptor = vk::DescriptorImageInfo {
 			image_layout: texture.current_layout,
 			image_view: texture.view,
 			sampler: texture.sampler,
 		};
+		let write_desc_sets = [vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			p_next: ptr::null(),
+			dst_set: self.descriptor_sets[0],
+			dst_binding: binding,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			p_image_info: &texture_descriptor,
+			p_buffer_info: ptr::null(),
+			p_texel_buffer_view: ptr::null(),
+		}];
+		unsafe {
+			self.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+	}
+
+	pub fn get_desc(&self) -> MaterialDesc
+	{
+		return self.desc.borrow().clone();
+	}
+
+	pub fn get_uniforms(&self) -> MaterialUniforms
+	{
+		return Material::uniforms_from_desc(&self.desc.borrow());
+	}
+
+	/// Returns the pipeline permutation to draw the material with, see MainPass::bind_mesh_pipeline().
+	pub fn get_permutation_index(&self) -> usize
+	{
+		return self.permutation_index.get();
+	}
+
+	pub fn get_descriptor_set(&self) -> vk::DescriptorSet
+	{
+		return self.descriptor_sets[0];
+	}
+
+	/// Returns the views of the color texture, the normal map and the damage texture, if any.
+	pub fn get_texture_views(&self) -> [vk::ImageView; 3]
+	{
+		let damage_view = match &self.damage
+		{
+			Some(damage) => damage.texture.view,
+			None => vk::ImageView::null(),
+		};
+		return [self.texture.borrow().view, self.normal_map.borrow().view, damage_view];
+	}
 
-		let normal_map = rs.load_image(normalmap_path, false);
-		let normal_descriptor = vk::DescriptorImageInfo {
-			image_layout: normal_map.current_layout,
-			image_view: normal_map.view,
-			sampler: normal_map.sampler,
+	pub fn get_damage(&self) -> Option<&DamageTexture>
+	{
+		return self.damage.as_ref();
+	}
+
+	pub fn get_asset_path(&self) -> Option<&str>
+	{
+		return self.asset_path.as_ref().map(|path| path.as_str());
+	}
+
+	/// Updates tint, opacity, specular and emissive from the given description. Texture paths and the permutation are
+	/// ignored.
+	pub fn set_params(&self, params: &MaterialDesc)
+	{
+		{
+			let mut desc = self.desc.borrow_mut();
+			desc.tint = params.tint;
+			desc.opacity = params.opacity;
+			desc.specular = params.specular;
+			desc.emissive = params.emissive;
+		}
+
+		let uniforms = self.get_uniforms();
+		let buf_size = size_of::<MaterialUniforms>() as u64;
+		unsafe {
+			let mem_ptr = self.params_ub.get_mapped();
+			let mut mem_align = Align::new(mem_ptr, align_of::<MaterialUniforms>() as u64, buf_size);
+			mem_align.copy_from_slice(&[uniforms]);
+		}
+	}
+
+	/// Updates the shaders, features and blend mode from the given description.
+	fn set_permutation(&self, rs: &RenderState, params: &MaterialDesc)
+	{
+		let mut desc = self.desc.borrow_mut();
+		desc.vertex_shader = params.vertex_shader.clone();
+		desc.fragment_shader = params.fragment_shader.clone();
+		desc.features = params.features;
+		desc.blend = params.blend;
+		self.permutation_index.set(rs.get_permutation_index(&desc.get_permutation()));
+	}
+
+	/// Replaces the color texture, or the normal map, with the image at the given path.
+	pub fn set_texture(&self, rs: &RenderState, path: &str, normal_map: bool)
+	{
+		let new_texture = rs.load_image(path, !normal_map);
+		// The descriptor set might still be in use
+		unsafe {
+			self.device.device_wait_idle().unwrap();
+		}
+
+		let (binding, texture) = if normal_map
+		{
+			self.desc.borrow_mut().normal_map = path.to_string();
+			(1, &self.normal_map)
+		}
+		else
+		{
+			self.desc.borrow_mut().texture = path.to_string();
+			(0, &self.texture)
 		};
+		self.write_texture_descriptor(binding, &new_texture);
+		texture.replace(new_texture);
+	}
 
-		let write_desc_sets = [
-			vk::WriteDescriptorSet {
-				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
-				p_next: ptr::null(),
-				dst_set: descriptor_sets[0],
-				dst_binding: 0,
-				dst_array_element: 0,
-				descriptor_count: 1,
-				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-				p_image_info: &texture_descriptor,
-				p_buffer_info: ptr::null(),
-				p_texel_buffer_view: ptr::null(),
-			},
-			vk::WriteDescriptorSet {
-				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
-				p_next: ptr::null(),
-				dst_set: descriptor_sets[0],
-				dst_binding: 1,
-				dst_array_element: 0,
-				descriptor_count: 1,
-				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-				p_image_info: &normal_descriptor,
-				p_buffer_info: ptr::null(),
-				p_texel_buffer_view: ptr::null(),
-			},
-		];
+	/// Replaces the color texture with the given image, keeping the path of the texture. Used for painting into it,
+	/// see TexturePainter.
+	pub fn set_texture_image(&self, rs: &RenderState, image: &RgbaImage)
+	{
+		let (width, height) = image.dimensions();
+		let new_texture = rs.create_image_from_data(width, height, vk::Format::R8G8B8A8_SRGB, image.as_raw());
+		// The descriptor set might still be in use
 		unsafe {
-			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+			self.device.device_wait_idle().unwrap();
 		}
+		self.write_texture_descriptor(0, &new_texture);
+		self.texture.replace(new_texture);
+	}
 
-		let material = Material {
-			descriptor_sets: descriptor_sets,
-			texture: texture,
-			normal_map: normal_map,
-			device: Rc::clone(&rs.device),
+	/// Writes the material back to the asset file it was loaded from.
+	pub fn save(&self) -> Result<(), String>
+	{
+		let path = match &self.asset_path
+		{
+			Some(path) => path,
+			None => return Err("material was not loaded from a file".to_string()),
 		};
-		// Since materials are generally shared, return a refcount.
-		return Rc::new(material);
+		if is_gltf(path)
+		{
+			return Err(format!("{}: materials of glTF models are edited in the model", path));
+		}
+		return MATERIAL_FORMAT.write(path, &*self.desc.borrow());
 	}
 
 	pub fn bind_descriptor_sets(&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout)
@@ -105,7 +555,9 @@ impl Drop for Material
 	{
 		// We cannot have the last reference to device at this point
 		debug_assert!(1 < Rc::strong_count(&self.device));
-		self.texture.destroy(&self.device);
-		self.normal_map.destroy(&self.device);
+		// Textures and buffers are released when dropped
+		unsafe {
+			self.device.free_descriptor_sets(self.descriptor_pool, &self.descriptor_sets);
+		}
 	}
 }
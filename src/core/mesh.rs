@@ -1,6 +1,13 @@
-use crate::renderer::RenderState;
+use crate::core::gltf::{is_gltf, GltfModel};
+use crate::core::morph::{MorphDelta, MorphTarget, MAX_MORPH_TARGETS};
+use crate::core::obj::ObjModel;
+use crate::core::{simplify, BoundingBox};
+use crate::renderer::{BufferHandle, RenderState};
 use ash::version::DeviceV1_0;
 use ash::{vk, Device};
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector3};
+use std::mem::size_of;
 use std::rc::Rc;
 
 // We never read the fields explicitly, hence they're counted as dead code.
@@ -15,61 +22,564 @@ pub struct Vertex
 	tex_uv: [f32; 2],
 }
 
-pub struct Mesh
+impl Vertex
 {
-	vertices: vk::Buffer,
-	vertex_mem: vk::DeviceMemory,
-	indices: vk::Buffer,
-	index_mem: vk::DeviceMemory,
+	pub fn new(
+		pos: Point3<f32>, normal: Vector3<f32>, tangent: Vector3<f32>, bitangent: Vector3<f32>, tex_uv: [f32; 2],
+	) -> Vertex
+	{
+		return Vertex {
+			pos: pos.into(),
+			normal: normal.into(),
+			tangent: tangent.into(),
+			bitangent: bitangent.into(),
+			tex_uv: tex_uv,
+		};
+	}
+
+	/// Generates tangents and bitangents from the texture coordinates of the triangles, and normals from the faces if
+	/// generate_normals is set.
+	pub fn generate_tangents(vertices: &mut [Vertex], indices: &[u16], generate_normals: bool)
+	{
+		// Accumulate face normals (where missing) and tangents per vertex
+		let mut tangents = vec![Vector3::zero(); vertices.len()];
+		let mut bitangents = vec![Vector3::zero(); vertices.len()];
+		for tri in indices.chunks(3)
+		{
+			let [i0, i1, i2] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+			let p0 = Vector3::from(vertices[i0].pos);
+			let edge1 = Vector3::from(vertices[i1].pos) - p0;
+			let edge2 = Vector3::from(vertices[i2].pos) - p0;
+			let uv0 = vertices[i0].tex_uv;
+			let duv1 = [vertices[i1].tex_uv[0] - uv0[0], vertices[i1].tex_uv[1] - uv0[1]];
+			let duv2 = [vertices[i2].tex_uv[0] - uv0[0], vertices[i2].tex_uv[1] - uv0[1]];
+			let det = duv1[0] * duv2[1] - duv2[0] * duv1[1];
+			let (tangent, bitangent) = if det.abs() > std::f32::EPSILON
+			{
+				((edge1 * duv2[1] - edge2 * duv1[1]) / det, (edge2 * duv1[0] - edge1 * duv2[0]) / det)
+			}
+			else
+			{
+				(edge1, edge2)
+			};
+			let face_normal = edge1.cross(edge2);
+			for &idx in &[i0, i1, i2]
+			{
+				tangents[idx] += tangent;
+				bitangents[idx] += bitangent;
+				if generate_normals
+				{
+					vertices[idx].normal = (Vector3::from(vertices[idx].normal) + face_normal).into();
+				}
+			}
+		}
+		for ((vertex, tangent), uv_bitangent) in vertices.iter_mut().zip(tangents).zip(bitangents)
+		{
+			let normal = Vector3::from(vertex.normal).normalize();
+			// Make the tangent orthogonal to the normal
+			let tangent = (tangent - normal * normal.dot(tangent)).normalize();
+			// Texture v goes downwards, while the bitangent should point up. Mirrored texture coordinates flip it.
+			let mut bitangent = normal.cross(tangent);
+			if bitangent.dot(uv_bitangent) > 0.0
+			{
+				bitangent = -bitangent;
+			}
+			vertex.normal = normal.into();
+			vertex.tangent = tangent.into();
+			vertex.bitangent = bitangent.into();
+		}
+	}
+}
+
+/// Vertex with half float positions and texture coordinates, and normals and tangents packed in 10 bits per component.
+///
+/// Less than half the size of Vertex, see VertexFormat::Packed.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+struct PackedVertex
+{
+	pos: [u16; 4],
+	normal: u32,
+	tangent: u32,
+	bitangent: u32,
+	tex_uv: [u16; 2],
+}
+
+/// Largest position coordinate stored in a packed vertex. Half floats have 11 bits of precision, so the error is
+/// below 8 mm.
+const PACKED_POSITION_LIMIT: f32 = 16.0;
+/// Largest texture coordinate stored in a packed vertex, where the error is half a texel of a 1024 texel texture.
+const PACKED_TEX_UV_LIMIT: f32 = 2.0;
+
+/// Converts to a half float, rounding to nearest. Values too small for normal half floats become subnormal, or zero,
+/// and values too large become infinity.
+fn to_half(value: f32) -> u16
+{
+	let bits = value.to_bits();
+	let sign = (bits >> 16) & 0x8000;
+	let exponent = ((bits >> 23) & 0xFF) as i32 - 127 + 15;
+	let mantissa = bits & 0x7F_FFFF;
+	if value.is_nan()
+	{
+		return (sign | 0x7E00) as u16;
+	}
+	if exponent < -10
+	{
+		return sign as u16;
+	}
+	if exponent <= 0
+	{
+		// Subnormal, with the implicit leading bit. Rounding up to the smallest normal half is still correct.
+		let shift = (14 - exponent) as u32;
+		let mantissa = mantissa | 0x80_0000;
+		return (sign | ((mantissa >> shift) + ((mantissa >> (shift - 1)) & 1))) as u16;
+	}
+	if exponent >= 31
+	{
+		return (sign | 0x7C00) as u16;
+	}
+	// Rounding up can carry into the exponent, which is still correct
+	let half = sign | (exponent as u32) << 10 | mantissa >> 13;
+	return (half + ((mantissa >> 12) & 1)) as u16;
+}
+
+/// Packs a unit vector in the A2B10G10R10_SNORM_PACK32 format.
+fn to_snorm_10_10_10(vector: [f32; 3]) -> u32
+{
+	let component = |value: f32| ((value.max(-1.0).min(1.0) * 511.0).round() as i32 as u32) & 0x3FF;
+	return component(vector[0]) | component(vector[1]) << 10 | component(vector[2]) << 20;
+}
+
+impl PackedVertex
+{
+	fn new(vertex: &Vertex) -> PackedVertex
+	{
+		PackedVertex {
+			pos: [to_half(vertex.pos[0]), to_half(vertex.pos[1]), to_half(vertex.pos[2]), to_half(1.0)],
+			normal: to_snorm_10_10_10(vertex.normal),
+			tangent: to_snorm_10_10_10(vertex.tangent),
+			bitangent: to_snorm_10_10_10(vertex.bitangent),
+			tex_uv: [to_half(vertex.tex_uv[0]), to_half(vertex.tex_uv[1])],
+		}
+	}
+
+	/// Returns true if the vertices can be packed without visible loss of precision.
+	fn can_pack(vertices: &[Vertex]) -> bool
+	{
+		return vertices.iter().all(|vertex| {
+			vertex.pos.iter().all(|coordinate| coordinate.abs() <= PACKED_POSITION_LIMIT) &&
+				vertex.tex_uv.iter().all(|coordinate| coordinate.abs() <= PACKED_TEX_UV_LIMIT)
+		});
+	}
+}
+
+/// Layouts of mesh vertex buffers. Both are read by the same shaders, with a pipeline per format.
+#[derive(Clone, Copy, PartialEq)]
+pub enum VertexFormat
+{
+	/// 32 bit floats for everything.
+	Full,
+	/// Half floats and packed normals, using less than half the memory and bandwidth. Only used for meshes small
+	/// enough for half float positions and texture coordinates.
+	Packed,
+}
+
+impl VertexFormat
+{
+	pub fn get_stride(&self) -> u32
+	{
+		match *self
+		{
+			VertexFormat::Full => size_of::<Vertex>() as u32,
+			VertexFormat::Packed => size_of::<PackedVertex>() as u32,
+		}
+	}
+
+	/// Returns the attributes of vertex buffer binding 0, in the order of the shader input locations: position,
+	/// normal, tangent, bitangent and texture coordinate.
+	pub fn get_attribute_descriptions(&self) -> Vec<vk::VertexInputAttributeDescription>
+	{
+		let formats_and_sizes = match *self
+		{
+			VertexFormat::Full => [
+				(vk::Format::R32G32B32_SFLOAT, 12),
+				(vk::Format::R32G32B32_SFLOAT, 12),
+				(vk::Format::R32G32B32_SFLOAT, 12),
+				(vk::Format::R32G32B32_SFLOAT, 12),
+				(vk::Format::R32G32_SFLOAT, 8),
+			],
+			VertexFormat::Packed => [
+				(vk::Format::R16G16B16A16_SFLOAT, 8),
+				(vk::Format::A2B10G10R10_SNORM_PACK32, 4),
+				(vk::Format::A2B10G10R10_SNORM_PACK32, 4),
+				(vk::Format::A2B10G10R10_SNORM_PACK32, 4),
+				(vk::Format::R16G16_SFLOAT, 4),
+			],
+		};
+		let mut offset = 0;
+		let mut attributes = Vec::new();
+		for (location, &(format, size)) in formats_and_sizes.iter().enumerate()
+		{
+			attributes.push(vk::VertexInputAttributeDescription {
+				binding: 0,
+				location: location as u32,
+				format: format,
+				offset: offset,
+			});
+			offset += size;
+		}
+		debug_assert!(offset == self.get_stride());
+		return attributes;
+	}
+}
+
+/// Fraction of the full detail triangles to keep in each generated level of detail.
+const LOD_TARGET_RATIOS: [f32; 2] = [0.5, 0.25];
+/// Levels that don't remove at least this fraction of the previous level's triangles are not worth keeping.
+const LOD_MIN_REDUCTION: f32 = 0.2;
+/// Approximate screen size (bounding radius over distance) below which each generated level is used.
+const LOD_SCREEN_SIZES: [f32; 2] = [0.15, 0.06];
+
+/// Vertex used for particles, which are drawn as lines without lighting.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct ParticleVertex
+{
+	pub pos: [f32; 3],
+	pub color: [f32; 4],
+}
+
+/// Index buffer for one level of detail. All levels share the vertex buffer.
+struct MeshLod
+{
+	indices: BufferHandle,
 	num_indices: u32,
+}
+
+pub struct Mesh
+{
+	vertices: BufferHandle,
+	vertex_format: VertexFormat,
+	vertex_bytes: u64,
+	// Full detail first
+	lods: Vec<MeshLod>,
+
+	// CPU side copy of the geometry, for navigation, collision, batching etc.
+	vertex_data: Vec<Vertex>,
+	index_data: Vec<u16>,
+	// Bounding sphere in model space, for selecting the level of detail
+	bounding_center: Point3<f32>,
+	bounding_radius: f32,
+	// Position and normal offsets of each vertex, one target after the other, see MorphWeights
+	morph_target_names: Vec<String>,
+	morph_deltas: Option<BufferHandle>,
 
-	// Keep a pointer to the device for cleanup
+	// Keep a pointer to the device for binding the buffers
 	device: Rc<Device>,
 }
 
 impl Mesh
 {
 	fn new(rs: &RenderState, vertices: &[Vertex], indices: &[u16]) -> Rc<Mesh>
+	{
+		return Mesh::new_with_lods(rs, vertices, indices, &[], VertexFormat::Full, &[]);
+	}
+
+	/// Creates a mesh with the given full detail indices, followed by reduced levels of detail, and morph targets.
+	fn new_with_lods(
+		rs: &RenderState, vertices: &[Vertex], indices: &[u16], lod_indices: &[Vec<u16>], vertex_format: VertexFormat,
+		morph_targets: &[MorphTarget],
+	) -> Rc<Mesh>
 	{
 		// Create buffer for vertices
-		let (vert_buffer, vert_mem) = rs.create_buffer_and_upload(
-			vk::BufferUsageFlags::VERTEX_BUFFER,
-			vk::MemoryPropertyFlags::DEVICE_LOCAL,
-			&vertices,
-			true,
-		);
-
-		// Create buffer for indices
-		let (idx_buffer, idx_mem) = rs.create_buffer_and_upload(
-			vk::BufferUsageFlags::INDEX_BUFFER,
-			vk::MemoryPropertyFlags::DEVICE_LOCAL,
-			&indices,
-			true,
-		);
+		let vert_buffer = match vertex_format
+		{
+			VertexFormat::Full => rs.create_buffer_and_upload(
+				vk::BufferUsageFlags::VERTEX_BUFFER,
+				vk::MemoryPropertyFlags::DEVICE_LOCAL,
+				&vertices,
+				true,
+			),
+			VertexFormat::Packed =>
+			{
+				let packed: Vec<PackedVertex> = vertices.iter().map(PackedVertex::new).collect();
+				rs.create_buffer_and_upload(
+					vk::BufferUsageFlags::VERTEX_BUFFER,
+					vk::MemoryPropertyFlags::DEVICE_LOCAL,
+					&packed,
+					true,
+				)
+			}
+		};
+
+		// Create buffers for indices, one per level of detail
+		let lods = std::iter::once(indices)
+			.chain(lod_indices.iter().map(|indices| indices.as_slice()))
+			.map(|indices| {
+				let idx_buffer = rs.create_buffer_and_upload(
+					vk::BufferUsageFlags::INDEX_BUFFER,
+					vk::MemoryPropertyFlags::DEVICE_LOCAL,
+					indices,
+					true,
+				);
+				MeshLod {
+					indices: idx_buffer,
+					num_indices: indices.len() as u32,
+				}
+			})
+			.collect();
+
+		let morph_deltas = if morph_targets.is_empty()
+		{
+			None
+		}
+		else
+		{
+			let deltas: Vec<MorphDelta> = morph_targets
+				.iter()
+				.flat_map(|target| target.position_deltas.iter().zip(target.normal_deltas.iter()))
+				.map(|(&position, &normal)| MorphDelta::new(position, normal))
+				.collect();
+			Some(rs.create_buffer_and_upload(
+				vk::BufferUsageFlags::STORAGE_BUFFER,
+				vk::MemoryPropertyFlags::DEVICE_LOCAL,
+				&deltas,
+				true,
+			))
+		};
+		// The bounding sphere must hold the mesh with all targets fully blended in
+		let morph_extent: f32 = morph_targets
+			.iter()
+			.map(|target| target.position_deltas.iter().map(|delta| delta.magnitude()).fold(0.0, f32::max))
+			.sum();
 
+		let bounds = BoundingBox::from_points(vertices.iter().map(|vertex| Point3::from(vertex.pos))).unwrap();
 		let mesh = Mesh {
 			vertices: vert_buffer,
-			vertex_mem: vert_mem,
-			indices: idx_buffer,
-			index_mem: idx_mem,
-			num_indices: indices.len() as u32,
+			vertex_format: vertex_format,
+			vertex_bytes: vertices.len() as u64 * vertex_format.get_stride() as u64,
+			lods: lods,
+			vertex_data: vertices.to_vec(),
+			index_data: indices.to_vec(),
+			bounding_center: bounds.get_center(),
+			bounding_radius: bounds.get_radius() + morph_extent,
+			morph_target_names: morph_targets.iter().map(|target| target.name.clone()).collect(),
+			morph_deltas: morph_deltas,
 			device: Rc::clone(&rs.device),
 		};
 		// Since materials are generally shared, return a refcount.
 		return Rc::new(mesh);
 	}
 
-	pub fn bind_buffers(&self, cmd_buf: vk::CommandBuffer)
+	/// Returns the level of detail to draw the mesh with, given its model-view matrix.
+	pub fn select_lod(&self, mv_matrix: &Matrix4<f32>) -> usize
+	{
+		let distance = mv_matrix.transform_point(self.bounding_center).to_vec().magnitude();
+		// Assumes uniform scaling, like Transform
+		let radius = self.bounding_radius * mv_matrix.x.truncate().magnitude();
+		let screen_size = radius / distance.max(std::f32::EPSILON);
+		let lod = LOD_SCREEN_SIZES.iter().take_while(|&&lod_size| screen_size < lod_size).count();
+		return lod.min(self.lods.len() - 1);
+	}
+
+	/// Returns the world space center and radius of the bounding sphere of the mesh, transformed by the given model
+	/// matrix.
+	pub fn get_bounding_sphere(&self, model_matrix: &Matrix4<f32>) -> (Point3<f32>, f32)
+	{
+		// The largest scale along any axis keeps the sphere around non-uniformly scaled meshes
+		let scale = model_matrix
+			.x
+			.truncate()
+			.magnitude()
+			.max(model_matrix.y.truncate().magnitude())
+			.max(model_matrix.z.truncate().magnitude());
+		return (model_matrix.transform_point(self.bounding_center), self.bounding_radius * scale);
+	}
+
+	pub fn bind_buffers(&self, cmd_buf: vk::CommandBuffer, lod: usize)
 	{
 		unsafe {
-			self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.vertices], &[0]);
-			self.device.cmd_bind_index_buffer(cmd_buf, self.indices, 0, vk::IndexType::UINT16);
+			self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.vertices.get()], &[0]);
+			self.device.cmd_bind_index_buffer(cmd_buf, self.lods[lod].indices.get(), 0, vk::IndexType::UINT16);
 		}
 	}
 
-	pub fn get_num_indices(&self) -> u32
+	/// Returns the vertex buffer, and the index buffer of the given level of detail.
+	pub fn get_buffers(&self, lod: usize) -> (vk::Buffer, vk::Buffer)
+	{
+		return (self.vertices.get(), self.lods[lod].indices.get());
+	}
+
+	pub fn get_num_indices(&self, lod: usize) -> u32
+	{
+		return self.lods[lod].num_indices;
+	}
+
+	pub fn get_vertex_format(&self) -> VertexFormat
+	{
+		return self.vertex_format;
+	}
+
+	/// Returns the size of the vertex buffer.
+	pub fn get_vertex_bytes(&self) -> u64
+	{
+		return self.vertex_bytes;
+	}
+
+	pub fn get_vertex_count(&self) -> usize
+	{
+		return self.vertex_data.len();
+	}
+
+	pub fn get_morph_target_names(&self) -> &[String]
 	{
-		return self.num_indices;
+		return &self.morph_target_names;
+	}
+
+	/// Returns the buffer of morph target offsets, if the mesh has any targets.
+	pub fn get_morph_deltas(&self) -> Option<vk::Buffer>
+	{
+		return self.morph_deltas.as_ref().map(|deltas| deltas.get());
+	}
+
+	/// Returns a copy of the mesh with the given morph targets, which must have an offset for each vertex.
+	pub fn with_morph_targets(&self, rs: &RenderState, morph_targets: &[MorphTarget]) -> Result<Rc<Mesh>, String>
+	{
+		if morph_targets.len() > MAX_MORPH_TARGETS
+		{
+			return Err(format!("meshes can have at most {} morph targets", MAX_MORPH_TARGETS));
+		}
+		let vertex_count = self.vertex_data.len();
+		if let Some(target) = morph_targets
+			.iter()
+			.find(|target| target.position_deltas.len() != vertex_count || target.normal_deltas.len() != vertex_count)
+		{
+			return Err(format!("morph target {} does not have an offset for each vertex", target.name));
+		}
+		// Simplifying gives the same levels of detail again
+		let lod_indices = if self.lods.len() > 1
+		{
+			Mesh::generate_lods(&self.vertex_data, &self.index_data)
+		}
+		else
+		{
+			Vec::new()
+		};
+		return Ok(Mesh::new_with_lods(
+			rs,
+			&self.vertex_data,
+			&self.index_data,
+			&lod_indices,
+			self.vertex_format,
+			morph_targets,
+		));
+	}
+
+	/// Generates a morph target by moving the vertices of the mesh with the given function, from model space position
+	/// to model space position. Normals are recomputed from the moved faces.
+	pub fn generate_morph_target(&self, name: &str, deform: impl Fn(Point3<f32>) -> Point3<f32>) -> MorphTarget
+	{
+		let face_normals = |vertices: &mut Vec<Vertex>| -> Vec<Vector3<f32>> {
+			for vertex in vertices.iter_mut()
+			{
+				vertex.normal = [0.0; 3];
+			}
+			Vertex::generate_tangents(vertices, &self.index_data, true);
+			return vertices.iter().map(|vertex| Vector3::from(vertex.normal)).collect();
+		};
+		let mut rest = self.vertex_data.clone();
+		let rest_normals = face_normals(&mut rest);
+		let mut deformed = self.vertex_data.clone();
+		for vertex in deformed.iter_mut()
+		{
+			vertex.pos = deform(Point3::from(vertex.pos)).into();
+		}
+		let deformed_normals = face_normals(&mut deformed);
+		return MorphTarget {
+			name: name.to_string(),
+			position_deltas: deformed
+				.iter()
+				.zip(self.vertex_data.iter())
+				.map(|(deformed, vertex)| Vector3::from(deformed.pos) - Vector3::from(vertex.pos))
+				.collect(),
+			normal_deltas: deformed_normals
+				.iter()
+				.zip(rest_normals.iter())
+				.map(|(deformed, rest)| deformed - rest)
+				.collect(),
+		};
+	}
+
+	/// Returns the world space bounding box of the mesh, transformed by the given model matrix.
+	pub fn get_bounds(&self, model_matrix: &Matrix4<f32>) -> BoundingBox
+	{
+		let transformed = self.vertex_data.iter().map(|vertex| model_matrix.transform_point(Point3::from(vertex.pos)));
+		// Meshes always have vertices
+		return BoundingBox::from_points(transformed).unwrap();
+	}
+
+	/// Returns the triangles of the mesh, transformed by the given model matrix.
+	pub fn get_triangles(&self, model_matrix: &Matrix4<f32>) -> Vec<[Point3<f32>; 3]>
+	{
+		let transformed: Vec<Point3<f32>> =
+			self.vertex_data.iter().map(|vertex| model_matrix.transform_point(Point3::from(vertex.pos))).collect();
+		return self
+			.index_data
+			.chunks(3)
+			.map(|tri| [transformed[tri[0] as usize], transformed[tri[1] as usize], transformed[tri[2] as usize]])
+			.collect();
+	}
+
+	/// Returns the distance along the ray to the closest triangle it hits, from either side, and the texture
+	/// coordinates there. The mesh is transformed by the given model matrix, and the direction must be normalized for
+	/// the distance to be in world units.
+	pub fn intersect_ray(
+		&self, model_matrix: &Matrix4<f32>, origin: Point3<f32>, direction: Vector3<f32>,
+	) -> Option<(f32, [f32; 2])>
+	{
+		let (center, radius) = self.get_bounding_sphere(model_matrix);
+		let to_center = center - origin;
+		let along = to_center.dot(direction);
+		if to_center.magnitude2() - along * along > radius * radius
+		{
+			return None;
+		}
+		let transformed: Vec<Point3<f32>> =
+			self.vertex_data.iter().map(|vertex| model_matrix.transform_point(Point3::from(vertex.pos))).collect();
+		let mut closest: Option<(f32, [f32; 2])> = None;
+		for tri in self.index_data.chunks(3)
+		{
+			let (a, b, c) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
+			// Moller-Trumbore, solving for the distance and the barycentric coordinates of b and c
+			let edge1 = transformed[b] - transformed[a];
+			let edge2 = transformed[c] - transformed[a];
+			let p = direction.cross(edge2);
+			let det = edge1.dot(p);
+			if det.abs() < std::f32::EPSILON
+			{
+				continue;
+			}
+			let to_origin = origin - transformed[a];
+			let v = to_origin.dot(p) / det;
+			let q = to_origin.cross(edge1);
+			let w = direction.dot(q) / det;
+			let distance = edge2.dot(q) / det;
+			if v < 0.0 || w < 0.0 || v + w > 1.0 || distance < 0.0
+			{
+				continue;
+			}
+			if closest.map_or(false, |(closest_distance, _)| closest_distance <= distance)
+			{
+				continue;
+			}
+			let uv = |idx: usize| self.vertex_data[idx].tex_uv;
+			let u = 1.0 - v - w;
+			closest = Some((
+				distance,
+				[u * uv(a)[0] + v * uv(b)[0] + w * uv(c)[0], u * uv(a)[1] + v * uv(b)[1] + w * uv(c)[1]],
+			));
+		}
+		return closest;
 	}
 
 	pub fn new_quad(rs: &RenderState, width: f32, height: f32) -> Rc<Mesh>
@@ -302,20 +812,312 @@ impl Mesh
 
 		return Mesh::new(rs, &vertices, &indices);
 	}
+
+	/// Merges meshes placed with the given model matrices into world space meshes, so static objects sharing a
+	/// material can be drawn with one draw call. The meshes are split where their vertices no longer fit 16 bit
+	/// indices, and only the full detail levels are kept.
+	pub fn merge(rs: &RenderState, parts: &[(&Mesh, Matrix4<f32>)], vertex_format: VertexFormat) -> Vec<Rc<Mesh>>
+	{
+		let mut merged = Vec::new();
+		let mut vertices: Vec<Vertex> = Vec::new();
+		let mut indices: Vec<u16> = Vec::new();
+		let mut finish = |vertices: &mut Vec<Vertex>, indices: &mut Vec<u16>| {
+			// World space meshes are usually too large for packed vertices
+			let vertex_format = if PackedVertex::can_pack(vertices)
+			{
+				vertex_format
+			}
+			else
+			{
+				VertexFormat::Full
+			};
+			merged.push(Mesh::new_with_lods(rs, vertices, indices, &[], vertex_format, &[]));
+			vertices.clear();
+			indices.clear();
+		};
+		for (mesh, model_matrix) in parts
+		{
+			if !vertices.is_empty() && vertices.len() + mesh.vertex_data.len() > std::u16::MAX as usize + 1
+			{
+				finish(&mut vertices, &mut indices);
+			}
+			// Assumes uniform scaling, like Transform
+			let rotate = |vector: [f32; 3]| model_matrix.transform_vector(Vector3::from(vector)).normalize().into();
+			let base = vertices.len();
+			vertices.extend(mesh.vertex_data.iter().map(|vertex| Vertex {
+				pos: model_matrix.transform_point(Point3::from(vertex.pos)).into(),
+				normal: rotate(vertex.normal),
+				tangent: rotate(vertex.tangent),
+				bitangent: rotate(vertex.bitangent),
+				tex_uv: vertex.tex_uv,
+			}));
+			indices.extend(mesh.index_data.iter().map(|&idx| (base + idx as usize) as u16));
+		}
+		if !indices.is_empty()
+		{
+			finish(&mut vertices, &mut indices);
+		}
+		return merged;
+	}
+
+	/// Creates a mesh from generated geometry, with reduced levels of detail.
+	///
+	/// Meshes too large for the packed vertex format are created with full precision instead.
+	pub fn from_geometry(
+		rs: &RenderState, vertices: &[Vertex], indices: &[u16], vertex_format: VertexFormat,
+	) -> Rc<Mesh>
+	{
+		let vertex_format = if PackedVertex::can_pack(vertices)
+		{
+			vertex_format
+		}
+		else
+		{
+			VertexFormat::Full
+		};
+		let lod_indices = Mesh::generate_lods(vertices, indices);
+		return Mesh::new_with_lods(rs, vertices, indices, &lod_indices, vertex_format, &[]);
+	}
+
+	/// Loads a Wavefront OBJ file. Faces are triangulated, and tangents are generated from the texture coordinates.
+	///
+	/// Meshes too large for the packed vertex format are loaded with full precision instead.
+	pub fn load_obj(rs: &RenderState, path: &str, vertex_format: VertexFormat) -> Result<Rc<Mesh>, String>
+	{
+		// Materials are not used, so all groups go into one mesh
+		let model = ObjModel::read(path)?;
+		let mut vertices: Vec<Vertex> = Vec::new();
+		let mut indices: Vec<u16> = Vec::new();
+		for group in model.groups
+		{
+			if vertices.len() + group.vertices.len() > std::u16::MAX as usize + 1
+			{
+				return Err(format!("{}: too many vertices", path));
+			}
+			let base = vertices.len() as u16;
+			vertices.extend(group.vertices);
+			indices.extend(group.indices.iter().map(|&idx| base + idx));
+		}
+		return Ok(Mesh::from_loaded(rs, path, &vertices, &indices, vertex_format, &[]));
+	}
+
+	/// Creates a loaded mesh with reduced levels of detail and its morph targets, falling back to full precision if it
+	/// is too large for packed vertices.
+	fn from_loaded(
+		rs: &RenderState, path: &str, vertices: &[Vertex], indices: &[u16], vertex_format: VertexFormat,
+		morph_targets: &[MorphTarget],
+	) -> Rc<Mesh>
+	{
+		let vertex_format = if vertex_format == VertexFormat::Packed && !PackedVertex::can_pack(vertices)
+		{
+			warning!("{} is too large for packed vertices, using full precision.", path);
+			VertexFormat::Full
+		}
+		else
+		{
+			vertex_format
+		};
+		let morph_targets = if morph_targets.len() > MAX_MORPH_TARGETS
+		{
+			warning!(
+				"{} has {} morph targets, only the first {} are used.",
+				path,
+				morph_targets.len(),
+				MAX_MORPH_TARGETS
+			);
+			&morph_targets[..MAX_MORPH_TARGETS]
+		}
+		else
+		{
+			morph_targets
+		};
+		let lod_indices = Mesh::generate_lods(vertices, indices);
+		return Mesh::new_with_lods(rs, vertices, indices, &lod_indices, vertex_format, morph_targets);
+	}
+
+	/// Loads a glTF 2.0 model with its morph targets, see GltfModel.
+	///
+	/// Meshes too large for the packed vertex format are loaded with full precision instead.
+	pub fn load_gltf(rs: &RenderState, path: &str, vertex_format: VertexFormat) -> Result<Rc<Mesh>, String>
+	{
+		let model = GltfModel::read(path)?;
+		return Ok(Mesh::from_loaded(rs, path, &model.vertices, &model.indices, vertex_format, &model.morph_targets));
+	}
+
+	/// Loads a Wavefront OBJ or glTF 2.0 file, by its extension.
+	pub fn load(rs: &RenderState, path: &str, vertex_format: VertexFormat) -> Result<Rc<Mesh>, String>
+	{
+		if is_gltf(path)
+		{
+			return Mesh::load_gltf(rs, path, vertex_format);
+		}
+		return Mesh::load_obj(rs, path, vertex_format);
+	}
+
+	/// Generates reduced levels of detail by simplifying the full detail mesh.
+	fn generate_lods(vertices: &[Vertex], indices: &[u16]) -> Vec<Vec<u16>>
+	{
+		let positions: Vec<Point3<f32>> = vertices.iter().map(|vertex| Point3::from(vertex.pos)).collect();
+		let mut lods: Vec<Vec<u16>> = Vec::new();
+		for &ratio in LOD_TARGET_RATIOS.iter()
+		{
+			let target_index_count = 3 * ((indices.len() / 3) as f32 * ratio) as usize;
+			// Simplifying the previous level is faster, and gives the same result
+			let previous = lods.last().map_or(indices, |lod| lod.as_slice());
+			let lod = simplify::simplify(&positions, previous, target_index_count);
+			if lod.len() as f32 > previous.len() as f32 * (1.0 - LOD_MIN_REDUCTION)
+			{
+				break;
+			}
+			lods.push(lod);
+		}
+		return lods;
+	}
 }
 
-impl Drop for Mesh
+#[cfg(test)]
+mod tests
 {
-	fn drop(&mut self)
+	use super::*;
+
+	fn from_half(half: u16) -> f32
+	{
+		let sign = if half & 0x8000 != 0
+		{
+			-1.0
+		}
+		else
+		{
+			1.0
+		};
+		let exponent = ((half >> 10) & 0x1F) as i32;
+		let mantissa = (half & 0x3FF) as f32;
+		return match exponent
+		{
+			0 => sign * mantissa * 2.0f32.powi(-24),
+			31 if mantissa == 0.0 => sign * f32::INFINITY,
+			31 => f32::NAN,
+			_ => sign * (1.0 + mantissa / 1024.0) * 2.0f32.powi(exponent - 15),
+		};
+	}
+
+	// Sign extends a 10 bit SNORM component, which reads -512 as -1.0 too
+	fn from_snorm_10(packed: u32, shift: u32) -> f32
+	{
+		let value = ((packed >> shift) << 22) as i32 >> 22;
+		return (value as f32 / 511.0).max(-1.0);
+	}
+
+	#[test]
+	fn half_keeps_signed_zero_and_ones()
 	{
-		// We cannot have the last reference to device at this point
-		debug_assert!(1 < Rc::strong_count(&self.device));
+		assert_eq!(to_half(0.0), 0x0000);
+		assert_eq!(to_half(-0.0), 0x8000);
+		assert_eq!(to_half(1.0), 0x3C00);
+		assert_eq!(to_half(-1.0), 0xBC00);
+		for &value in [0.0, -0.0, 1.0, -1.0, 0.5, -2.0, 65504.0].iter()
+		{
+			let round_trip = from_half(to_half(value));
+			assert_eq!(round_trip, value);
+			assert_eq!(round_trip.is_sign_negative(), value.is_sign_negative());
+		}
+	}
 
-		unsafe {
-			self.device.destroy_buffer(self.indices, None);
-			self.device.free_memory(self.index_mem, None);
-			self.device.destroy_buffer(self.vertices, None);
-			self.device.free_memory(self.vertex_mem, None);
+	#[test]
+	fn half_round_trips_within_precision()
+	{
+		// 11 bits of precision, so half an ulp is 2^-11 relative
+		for step in -1_000..=1_000
+		{
+			let value = step as f32 * 0.0173;
+			let round_trip = from_half(to_half(value));
+			assert!((round_trip - value).abs() <= value.abs() * 2.0f32.powi(-11), "{} became {}", value, round_trip);
 		}
 	}
+
+	#[test]
+	fn half_subnormals()
+	{
+		let smallest = 2.0f32.powi(-24);
+		assert_eq!(to_half(smallest), 0x0001);
+		assert_eq!(to_half(-smallest), 0x8001);
+		assert_eq!(to_half(2.0f32.powi(-15)), 0x0200);
+		assert_eq!(to_half(1023.0 * smallest), 0x03FF);
+		// Rounding up reaches the smallest normal half
+		assert_eq!(to_half(1023.75 * smallest), 0x0400);
+		assert_eq!(to_half(2.0f32.powi(-14)), 0x0400);
+		for &multiple in [1.0, 3.0, 100.0, 512.0, 1023.0].iter()
+		{
+			assert_eq!(from_half(to_half(multiple * smallest)), multiple * smallest);
+			assert_eq!(from_half(to_half(-multiple * smallest)), -multiple * smallest);
+		}
+		// Rounded to nearest, and past half the smallest subnormal to zero
+		assert_eq!(to_half(2.6 * smallest), 0x0003);
+		assert_eq!(to_half(0.75 * smallest), 0x0001);
+		assert_eq!(to_half(0.25 * smallest), 0x0000);
+		assert_eq!(to_half(-0.25 * smallest), 0x8000);
+		assert_eq!(to_half(f32::MIN_POSITIVE), 0x0000);
+	}
+
+	#[test]
+	fn half_overflows_to_infinity()
+	{
+		assert_eq!(to_half(65504.0), 0x7BFF);
+		// Halfway to the next power of two rounds up
+		assert_eq!(to_half(65520.0), 0x7C00);
+		assert_eq!(to_half(1e5), 0x7C00);
+		assert_eq!(to_half(-1e5), 0xFC00);
+		assert_eq!(to_half(f32::INFINITY), 0x7C00);
+		assert_eq!(to_half(f32::NEG_INFINITY), 0xFC00);
+		assert_eq!(from_half(to_half(f32::MAX)), f32::INFINITY);
+	}
+
+	#[test]
+	fn half_keeps_nan()
+	{
+		assert!(from_half(to_half(f32::NAN)).is_nan());
+		assert!(from_half(to_half(-f32::NAN)).is_nan());
+		// Even NaNs with only low mantissa bits set
+		assert!(from_half(to_half(f32::from_bits(0x7F80_0001))).is_nan());
+	}
+
+	#[test]
+	fn snorm_round_trips_ends_and_zero()
+	{
+		assert_eq!(to_snorm_10_10_10([0.0, 0.0, 0.0]), 0);
+		assert_eq!(to_snorm_10_10_10([1.0, 0.0, 0.0]), 0x1FF);
+		assert_eq!(to_snorm_10_10_10([0.0, 0.0, -1.0]), 0x201 << 20);
+		let packed = to_snorm_10_10_10([1.0, -1.0, -0.0]);
+		assert_eq!(from_snorm_10(packed, 0), 1.0);
+		assert_eq!(from_snorm_10(packed, 10), -1.0);
+		assert_eq!(from_snorm_10(packed, 20), 0.0);
+	}
+
+	#[test]
+	fn snorm_sign_extends_negative_components()
+	{
+		let vector = [-0.25, -0.5, -0.999];
+		let packed = to_snorm_10_10_10(vector);
+		for (idx, &value) in vector.iter().enumerate()
+		{
+			let round_trip = from_snorm_10(packed, 10 * idx as u32);
+			assert!((round_trip - value).abs() <= 0.5 / 511.0, "{} became {}", value, round_trip);
+		}
+	}
+
+	#[test]
+	fn snorm_leaves_alpha_zero()
+	{
+		// Negative components must not spill into the 2 bit alpha field
+		for &vector in [[-1.0, -1.0, -1.0], [1.0, 1.0, 1.0], [-0.1, 0.2, -0.3]].iter()
+		{
+			assert_eq!(to_snorm_10_10_10(vector) >> 30, 0);
+		}
+	}
+
+	#[test]
+	fn snorm_clamps_out_of_range()
+	{
+		assert_eq!(to_snorm_10_10_10([2.0, -2.0, 0.0]), to_snorm_10_10_10([1.0, -1.0, 0.0]));
+	}
 }
@@ -1,13 +1,56 @@
+// First, so its macros can be used by everything after it
+#[macro_use]
+mod logger;
+
+mod assets;
+mod autosave;
+mod bounds;
 mod config;
+mod console;
+mod constraints;
+mod cursors;
 mod draw;
+mod file_watcher;
+mod frustum;
+mod gltf;
 mod input;
 mod material;
 mod mesh;
+mod morph;
+mod obj;
+mod random;
+mod redraw;
+mod sim_math;
+mod simplify;
+mod time;
 mod transform;
+mod undo;
+mod versioning;
 
-pub use self::config::Config;
+pub use self::assets::{AssetLoader, ImageRequest};
+pub use self::autosave::Autosaver;
+pub use self::bounds::BoundingBox;
+pub use self::config::{Config, Volumes};
+pub use self::console::Console;
+pub use self::constraints::Constraint;
+pub use self::cursors::{CursorKind, Cursors};
 pub use self::draw::Drawable;
-pub use self::input::{Action, ActionType, InputConsumer, InputHandler, KeyEventState, MouseConsumer};
-pub use self::material::Material;
-pub use self::mesh::{Mesh, Vertex};
+pub use self::file_watcher::FileWatcher;
+pub use self::frustum::Frustum;
+pub use self::gltf::is_gltf;
+pub use self::input::{
+	Action, ActionEvents, ActionType, AnalogChannel, InputConsumer, InputContext, InputHandler, KeyEventState,
+	MouseConsumer,
+};
+pub use self::logger::{LogEntry, LogLevel, Logger};
+pub use self::material::{BlendMode, Material, MaterialDesc, MaterialUniforms};
+pub use self::mesh::{Mesh, ParticleVertex, Vertex, VertexFormat};
+pub use self::morph::{MorphTarget, MorphUniforms, MorphWeights};
+pub use self::obj::load_obj_with_materials;
+pub use self::random::{RandomService, RandomStream, Rng};
+pub use self::redraw::RedrawScheduler;
+pub use self::sim_math::sim_sin_cos;
+pub use self::time::{Time, TimeDomain, Timer};
 pub use self::transform::{Transform, Transformable};
+pub use self::undo::{UndoGroup, UndoStack};
+pub use self::versioning::{unversioned_to_v1, write_atomic, FileFormat};
@@ -0,0 +1,161 @@
+use crate::core::Mesh;
+use crate::renderer::{BufferHandle, MainPass, RenderState};
+use ash::util::Align;
+use ash::version::DeviceV1_0;
+use ash::{vk, Device};
+use cgmath::Vector3;
+use std::mem::{align_of, size_of};
+use std::rc::Rc;
+
+/// Most morph targets a mesh can have. Must match phong.vert.
+pub const MAX_MORPH_TARGETS: usize = 8;
+
+/// A shape the vertices of a mesh can be blended towards, as offsets from their rest positions and normals. Has one
+/// offset per vertex of the mesh.
+#[derive(Clone)]
+pub struct MorphTarget
+{
+	pub name: String,
+	pub position_deltas: Vec<Vector3<f32>>,
+	pub normal_deltas: Vec<Vector3<f32>>,
+}
+
+/// Offsets of one vertex in one morph target, as stored on the GPU.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct MorphDelta
+{
+	position: [f32; 4],
+	normal: [f32; 4],
+}
+
+impl MorphDelta
+{
+	pub fn new(position: Vector3<f32>, normal: Vector3<f32>) -> MorphDelta
+	{
+		return MorphDelta {
+			position: position.extend(0.0).into(),
+			normal: normal.extend(0.0).into(),
+		};
+	}
+}
+
+/// Blend weights of an object, bound along with the morph targets of its mesh. Meshes drawn without morphing bind a
+/// set without targets, see MainPass::bind_default_morph_set().
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct MorphUniforms
+{
+	target_count: u32,
+	vertex_count: u32,
+	_padding: [u32; 2],
+	weights: [f32; MAX_MORPH_TARGETS],
+}
+
+impl MorphUniforms
+{
+	pub fn new(target_count: usize, vertex_count: usize, weights: &[f32]) -> MorphUniforms
+	{
+		let mut uniforms = MorphUniforms {
+			target_count: target_count as u32,
+			vertex_count: vertex_count as u32,
+			_padding: [0; 2],
+			weights: [0.0; MAX_MORPH_TARGETS],
+		};
+		for (weight, &value) in uniforms.weights.iter_mut().zip(weights)
+		{
+			*weight = value;
+		}
+		return uniforms;
+	}
+}
+
+/// The blend weights of the morph targets of a mesh, for one object drawn with it. All weights start at zero, which
+/// draws the mesh in its rest shape.
+pub struct MorphWeights
+{
+	// Also keeps the morph targets alive while the descriptor set refers to them
+	mesh: Rc<Mesh>,
+	weights: Vec<f32>,
+	weights_ub: BufferHandle,
+	descriptor_pool: vk::DescriptorPool,
+	descriptor_set: vk::DescriptorSet,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl MorphWeights
+{
+	/// Fails if the mesh has no morph targets.
+	pub fn new(rs: &RenderState, mp: &MainPass, mesh: &Rc<Mesh>) -> Result<MorphWeights, String>
+	{
+		let deltas = mesh.get_morph_deltas().ok_or("mesh has no morph targets")?;
+		let target_count = mesh.get_morph_target_names().len();
+		let weights = vec![0.0; target_count];
+		let weights_ub = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::UNIFORM_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&[MorphUniforms::new(target_count, mesh.get_vertex_count(), &weights)],
+			false,
+		);
+		let descriptor_set =
+			MainPass::create_morph_set(rs, mp.descriptor_pool, mp.descriptor_set_layouts[3], deltas, weights_ub.get())?;
+		return Ok(MorphWeights {
+			mesh: Rc::clone(mesh),
+			weights: weights,
+			weights_ub: weights_ub,
+			descriptor_pool: mp.descriptor_pool,
+			descriptor_set: descriptor_set,
+			device: Rc::clone(&rs.device),
+		});
+	}
+
+	/// Sets the weights of the targets in order. Targets without a weight keep theirs, extra weights are ignored.
+	pub fn set_weights(&mut self, weights: &[f32])
+	{
+		for (weight, &value) in self.weights.iter_mut().zip(weights)
+		{
+			*weight = value;
+		}
+		self.upload();
+	}
+
+	fn upload(&self)
+	{
+		let uniforms = MorphUniforms::new(self.weights.len(), self.mesh.get_vertex_count(), &self.weights);
+		let size = size_of::<MorphUniforms>() as u64;
+		unsafe {
+			let mem_ptr = self.weights_ub.get_mapped();
+			let mut mem_align = Align::new(mem_ptr, align_of::<MorphUniforms>() as u64, size);
+			mem_align.copy_from_slice(&[uniforms]);
+		}
+	}
+
+	/// Binds the morph targets and weights for the next draws. The default set must be bound again afterwards.
+	pub fn bind(&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout)
+	{
+		unsafe {
+			self.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				pipeline_layout,
+				3,
+				&[self.descriptor_set],
+				&[],
+			);
+		}
+	}
+}
+
+impl Drop for MorphWeights
+{
+	fn drop(&mut self)
+	{
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+		unsafe {
+			self.device.free_descriptor_sets(self.descriptor_pool, &[self.descriptor_set]);
+		}
+	}
+}
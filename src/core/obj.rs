@@ -0,0 +1,325 @@
+use crate::core::material::{BlendMode, MaterialDesc};
+use crate::core::{Material, Mesh, Vertex, VertexFormat};
+use crate::renderer::{MainPass, RenderState};
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+/// The triangles of an OBJ file that use the same material.
+pub struct ObjGroup
+{
+	/// Name of the material in the material libraries, None for faces before the first usemtl.
+	pub material: Option<String>,
+	pub vertices: Vec<Vertex>,
+	pub indices: Vec<u16>,
+	// Vertices are unique combinations of position, texture coordinate and normal indices
+	vertex_lookup: HashMap<(usize, Option<usize>, Option<usize>), u16>,
+}
+
+/// A Wavefront OBJ file, with its faces triangulated and grouped by material. Tangents are generated from the texture
+/// coordinates, and normals from the faces if the file has none.
+pub struct ObjModel
+{
+	pub groups: Vec<ObjGroup>,
+	/// The material libraries (mtllib) of the file, relative to the working directory.
+	pub material_libs: Vec<String>,
+}
+
+impl ObjModel
+{
+	pub fn read(path: &str) -> Result<ObjModel, String>
+	{
+		let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+		let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+
+		let mut positions: Vec<Vector3<f32>> = Vec::new();
+		let mut normals: Vec<Vector3<f32>> = Vec::new();
+		let mut tex_uvs: Vec<[f32; 2]> = Vec::new();
+		let mut groups: Vec<ObjGroup> = Vec::new();
+		let mut material_libs = Vec::new();
+		let mut current_group = None;
+
+		for (line_idx, line) in contents.lines().enumerate()
+		{
+			let error = |message: &str| format!("{}:{}: {}", path, line_idx + 1, message);
+			let mut words = line.split_whitespace();
+			let keyword = match words.next()
+			{
+				Some(keyword) => keyword,
+				None => continue,
+			};
+			let mut parse_floats = |count: usize| -> Result<Vec<f32>, String> {
+				let values: Vec<f32> = words.by_ref().take(count).filter_map(|word| word.parse().ok()).collect();
+				if values.len() < count
+				{
+					return Err(error("expected more numbers"));
+				}
+				return Ok(values);
+			};
+
+			match keyword
+			{
+				"v" =>
+				{
+					let v = parse_floats(3)?;
+					positions.push(Vector3::new(v[0], v[1], v[2]));
+				}
+				"vn" =>
+				{
+					let n = parse_floats(3)?;
+					normals.push(Vector3::new(n[0], n[1], n[2]).normalize());
+				}
+				"vt" =>
+				{
+					// OBJ has the texture origin in the lower left corner, images are stored from the top
+					let t = parse_floats(2)?;
+					tex_uvs.push([t[0], 1.0 - t[1]]);
+				}
+				"usemtl" =>
+				{
+					let name = words.collect::<Vec<&str>>().join(" ");
+					current_group = Some(ObjModel::find_group(&mut groups, Some(name)));
+				}
+				"mtllib" =>
+				{
+					for lib in words
+					{
+						material_libs.push(directory.join(lib).to_string_lossy().to_string());
+					}
+				}
+				"f" =>
+				{
+					let group_idx = match current_group
+					{
+						Some(group_idx) => group_idx,
+						None => ObjModel::find_group(&mut groups, None),
+					};
+					current_group = Some(group_idx);
+					let group = &mut groups[group_idx];
+					let mut face = Vec::new();
+					for word in words
+					{
+						// Indices are 1-based, negative values are relative to the end
+						let mut parts = word.split('/').map(|part| part.parse::<i64>().ok());
+						let resolve = |idx: Option<i64>, len: usize| match idx
+						{
+							Some(idx) if idx > 0 && idx as usize <= len => Some(idx as usize - 1),
+							Some(idx) if idx < 0 && (-idx) as usize <= len => Some((len as i64 + idx) as usize),
+							_ => None,
+						};
+						let position = resolve(parts.next().unwrap_or(None), positions.len())
+							.ok_or_else(|| error("invalid position index"))?;
+						let tex_uv = resolve(parts.next().unwrap_or(None), tex_uvs.len());
+						let normal = resolve(parts.next().unwrap_or(None), normals.len());
+
+						let key = (position, tex_uv, normal);
+						let idx = match group.vertex_lookup.get(&key)
+						{
+							Some(&idx) => idx,
+							None =>
+							{
+								if group.vertices.len() > std::u16::MAX as usize
+								{
+									return Err(error("too many vertices"));
+								}
+								let idx = group.vertices.len() as u16;
+								group.vertices.push(Vertex::new(
+									Point3::from_vec(positions[position]),
+									normal.map_or(Vector3::zero(), |n| normals[n]),
+									Vector3::zero(),
+									Vector3::zero(),
+									tex_uv.map_or([0.0; 2], |t| tex_uvs[t]),
+								));
+								group.vertex_lookup.insert(key, idx);
+								idx
+							}
+						};
+						face.push(idx);
+					}
+					if face.len() < 3
+					{
+						return Err(error("face with less than 3 vertices"));
+					}
+					// Triangle fan
+					for i in 1..face.len() - 1
+					{
+						group.indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+					}
+				}
+				// Objects, groups and smoothing are not used
+				_ => (),
+			}
+		}
+
+		groups.retain(|group| !group.indices.is_empty());
+		if groups.is_empty()
+		{
+			return Err(format!("{}: no faces", path));
+		}
+		for group in groups.iter_mut()
+		{
+			Vertex::generate_tangents(&mut group.vertices, &group.indices, normals.is_empty());
+		}
+		return Ok(ObjModel {
+			groups: groups,
+			material_libs: material_libs,
+		});
+	}
+
+	/// Returns the index of the group with the given material, adding it if there is none.
+	fn find_group(groups: &mut Vec<ObjGroup>, material: Option<String>) -> usize
+	{
+		if let Some(idx) = groups.iter().position(|group| group.material == material)
+		{
+			return idx;
+		}
+		groups.push(ObjGroup {
+			material: material,
+			vertices: Vec::new(),
+			indices: Vec::new(),
+			vertex_lookup: HashMap::new(),
+		});
+		return groups.len() - 1;
+	}
+}
+
+/// Returns the path of a texture referenced by a material library, if it exists. Texture options in front of the file
+/// name, like -bm for bump maps, are skipped.
+fn get_texture_path(directory: &Path, words: &[&str]) -> Option<String>
+{
+	let file = words.last()?;
+	let path = directory.join(file);
+	if !path.is_file()
+	{
+		warning!("MTL texture {} not found", path.display());
+		return None;
+	}
+	return Some(path.to_string_lossy().to_string());
+}
+
+/// Reads the materials of a Wavefront MTL file, by name. The diffuse color and map become the tint and texture, the
+/// specular color the specular multiplier, the emissive color the emissive, and the bump or normal map the normal map.
+/// Dissolved materials are alpha blended.
+pub fn read_mtl(path: &str) -> Result<Vec<(String, MaterialDesc)>, String>
+{
+	let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+	let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+	let mut materials: Vec<(String, MaterialDesc)> = Vec::new();
+	for (line_idx, line) in contents.lines().enumerate()
+	{
+		let words: Vec<&str> = line.split_whitespace().collect();
+		let (keyword, args) = match words.split_first()
+		{
+			Some((keyword, args)) => (*keyword, args),
+			None => continue,
+		};
+		if keyword == "newmtl"
+		{
+			materials.push((args.join(" "), MaterialDesc::untextured()));
+			continue;
+		}
+		let desc = match materials.last_mut()
+		{
+			Some((_, desc)) => desc,
+			None => continue,
+		};
+		let color = || -> Result<[f32; 3], String> {
+			let values: Vec<f32> = args.iter().take(3).filter_map(|word| word.parse().ok()).collect();
+			return match values.len()
+			{
+				3 => Ok([values[0], values[1], values[2]]),
+				// A single value is gray
+				1 => Ok([values[0]; 3]),
+				_ => Err(format!("{}:{}: expected a color", path, line_idx + 1)),
+			};
+		};
+		match keyword
+		{
+			"Kd" => desc.tint = color()?,
+			"Ks" =>
+			{
+				let specular = color()?;
+				desc.specular = (specular[0] + specular[1] + specular[2]) / 3.0;
+			}
+			"Ke" => desc.emissive = color()?,
+			"d" | "Tr" =>
+			{
+				let value: f32 = args.first().and_then(|word| word.parse().ok()).unwrap_or(1.0);
+				// Tr is the transparency, d the opacity
+				desc.opacity = if keyword == "Tr"
+				{
+					1.0 - value
+				}
+				else
+				{
+					value
+				};
+				desc.blend = if desc.opacity < 1.0
+				{
+					BlendMode::Alpha
+				}
+				else
+				{
+					BlendMode::Opaque
+				};
+			}
+			"map_Kd" =>
+			{
+				if let Some(texture) = get_texture_path(directory, args)
+				{
+					desc.texture = texture;
+				}
+			}
+			"map_Bump" | "map_bump" | "bump" | "norm" =>
+			{
+				if let Some(normal_map) = get_texture_path(directory, args)
+				{
+					desc.normal_map = normal_map;
+					desc.features.normal_mapping = true;
+				}
+			}
+			// Shininess, ambient and illumination models are not used
+			_ => (),
+		}
+	}
+	return Ok(materials);
+}
+
+/// Loads a Wavefront OBJ file with its MTL material libraries, as one mesh per material. Faces with no material or an
+/// unknown one get a plain white material.
+///
+/// Meshes too large for the packed vertex format are loaded with full precision instead. Also returns the paths of the
+/// material libraries, so they can be watched along with the model.
+pub fn load_obj_with_materials(
+	rs: &RenderState, mp: &MainPass, path: &str, vertex_format: VertexFormat,
+) -> Result<(Vec<(Rc<Mesh>, Rc<Material>)>, Vec<String>), String>
+{
+	let model = ObjModel::read(path)?;
+	let mut descs: HashMap<String, MaterialDesc> = HashMap::new();
+	for lib in model.material_libs.iter()
+	{
+		descs.extend(read_mtl(lib)?);
+	}
+	let mut pairs = Vec::with_capacity(model.groups.len());
+	for group in model.groups.iter()
+	{
+		let desc = match group.material.as_ref().and_then(|name| descs.get(name))
+		{
+			Some(desc) => desc.clone(),
+			None =>
+			{
+				if let Some(name) = &group.material
+				{
+					warning!("{}: material {} not found", path, name);
+				}
+				MaterialDesc::untextured()
+			}
+		};
+		let mesh = Mesh::from_geometry(rs, &group.vertices, &group.indices, vertex_format);
+		pairs.push((mesh, Material::new(rs, mp, desc)));
+	}
+	return Ok((pairs, model.material_libs));
+}
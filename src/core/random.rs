@@ -0,0 +1,111 @@
+use cgmath::Vector3;
+
+/// Systems with their own random streams. Each stream is independent, so e.g. changing the number of particles does
+/// not change how the AI behaves.
+#[derive(Clone, Copy)]
+pub enum RandomStream
+{
+	Particles,
+	AI,
+	Gameplay,
+	Audio,
+}
+
+/// SplitMix64, used to derive well distributed seeds from similar inputs.
+fn splitmix64(mut x: u64) -> u64
+{
+	x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+	x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	return x ^ (x >> 31);
+}
+
+/// The engine's random number service. All randomness in the simulation should come from generators created here,
+/// so that runs with the same seed are reproducible.
+pub struct RandomService
+{
+	seed: u64,
+}
+
+impl RandomService
+{
+	pub fn new(seed: u64) -> RandomService
+	{
+		RandomService {
+			seed: seed,
+		}
+	}
+
+	/// Creates a generator for the given stream. Index separates multiple users of the same stream, like agents.
+	pub fn create_rng(&self, stream: RandomStream, index: u32) -> Rng
+	{
+		let stream_seed = splitmix64(self.seed ^ splitmix64(stream as u64));
+		return Rng::new(splitmix64(stream_seed ^ index as u64));
+	}
+}
+
+/// Xorshift64* random number generator, with some convenience distributions.
+#[derive(Clone)]
+pub struct Rng
+{
+	state: u64,
+}
+
+impl Rng
+{
+	fn new(seed: u64) -> Rng
+	{
+		Rng {
+			// Xorshift state must not be zero
+			state: if seed == 0
+			{
+				0x9E37_79B9_7F4A_7C15
+			}
+			else
+			{
+				seed
+			},
+		}
+	}
+
+	pub fn next_u32(&mut self) -> u32
+	{
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		return (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32;
+	}
+
+	/// Returns a value in [0, 1).
+	pub fn next_f32(&mut self) -> f32
+	{
+		return (self.next_u32() >> 8) as f32 / (1 << 24) as f32;
+	}
+
+	/// Returns a value in [min, max).
+	pub fn range(&mut self, min: f32, max: f32) -> f32
+	{
+		return min + (max - min) * self.next_f32();
+	}
+
+	/// Returns a uniformly distributed direction.
+	pub fn unit_vector(&mut self) -> Vector3<f32>
+	{
+		let z = self.range(-1.0, 1.0);
+		let angle = self.range(0.0, 2.0 * std::f32::consts::PI);
+		let radius = (1.0 - z * z).sqrt();
+		return Vector3::new(radius * angle.cos(), radius * angle.sin(), z);
+	}
+
+	/// Randomly scales the brightness and each channel of an RGBA color by up to +-amount. Alpha is kept.
+	pub fn jitter_color(&mut self, color: [f32; 4], amount: f32) -> [f32; 4]
+	{
+		let brightness = 1.0 + self.range(-amount, amount);
+		let mut jittered = color;
+		for channel in jittered[..3].iter_mut()
+		{
+			*channel = (*channel * brightness * (1.0 + self.range(-amount, amount) * 0.5)).max(0.0).min(1.0);
+		}
+		return jittered;
+	}
+}
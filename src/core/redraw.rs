@@ -0,0 +1,39 @@
+use crate::core::Console;
+
+/// Decides which frames are rendered. By default every frame is, but with redraw_on_demand set only the frames where
+/// something changed are, e.g. when using the engine as a scene editor. The simulation keeps ticking either way.
+pub struct RedrawScheduler
+{
+	// Whether something changed since the last rendered frame
+	invalidated: bool,
+}
+
+impl RedrawScheduler
+{
+	pub fn new(console: &mut Console) -> RedrawScheduler
+	{
+		console.register_bool(
+			"redraw_on_demand",
+			false,
+			"Only render when input, commands, reloaded assets or animation changed something",
+		);
+
+		RedrawScheduler {
+			invalidated: true,
+		}
+	}
+
+	/// Requests the next frame to be rendered.
+	pub fn invalidate(&mut self)
+	{
+		self.invalidated = true;
+	}
+
+	/// Returns whether to render the current frame, given whether anything is animating, and clears the request.
+	pub fn should_render(&mut self, console: &Console, animating: bool) -> bool
+	{
+		let render = self.invalidated || animating || !console.get_bool("redraw_on_demand");
+		self.invalidated = false;
+		return render;
+	}
+}
@@ -0,0 +1,97 @@
+//! Math for the simulation that has to give the same results on every machine, for lockstep networking and replays.
+//!
+//! Basic IEEE 754 arithmetic and square roots are correctly rounded everywhere, but transcendental functions come from
+//! the platform's math library, and differ in the last bits between platforms. With the deterministic feature, they
+//! are instead computed here from basic arithmetic only, in a fixed order of operations.
+
+#[cfg(feature = "deterministic")]
+use std::f32::consts::FRAC_PI_2;
+
+/// Returns the sine and cosine of an angle in radians.
+#[cfg(not(feature = "deterministic"))]
+pub fn sim_sin_cos(angle: f32) -> (f32, f32)
+{
+	return angle.sin_cos();
+}
+
+/// Returns the sine and cosine of an angle in radians. Accurate to a few ulps for angles within a few turns.
+#[cfg(feature = "deterministic")]
+pub fn sim_sin_cos(angle: f32) -> (f32, f32)
+{
+	// Cody-Waite reduction to [-pi/4, pi/4], with pi/2 split in two so the subtraction stays exact
+	const PI_2_HIGH: f32 = 1.570_312_5;
+	const PI_2_LOW: f32 = 4.838_267_9e-4;
+	let quadrant = (angle / FRAC_PI_2).round();
+	let x = (angle - quadrant * PI_2_HIGH) - quadrant * PI_2_LOW;
+
+	// Taylor polynomials, good to float precision on the reduced range
+	let x2 = x * x;
+	let sin = x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0)));
+	let cos = 1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)));
+
+	match (quadrant as i64).rem_euclid(4)
+	{
+		0 => (sin, cos),
+		1 => (cos, -sin),
+		2 => (-sin, -cos),
+		_ => (-cos, sin),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::f32::consts::{FRAC_PI_2, PI};
+
+	// Largest difference from the platform's sin_cos, a few ulps of 1.0 within a few turns. The deterministic range
+	// reduction loses about an ulp of the angle, so large angles get more.
+	const MAX_ERROR: f32 = 5e-7;
+	const MAX_LARGE_ANGLE_ERROR: f32 = 1e-6;
+
+	fn assert_close(angle: f32, max_error: f32)
+	{
+		let (sin, cos) = sim_sin_cos(angle);
+		let (expected_sin, expected_cos) = angle.sin_cos();
+		assert!((sin - expected_sin).abs() <= max_error, "sin({}) = {}, expected {}", angle, sin, expected_sin);
+		assert!((cos - expected_cos).abs() <= max_error, "cos({}) = {}, expected {}", angle, cos, expected_cos);
+	}
+
+	#[test]
+	fn exact_at_zero()
+	{
+		assert_eq!(sim_sin_cos(0.0), (0.0, 1.0));
+	}
+
+	#[test]
+	fn matches_std_over_several_turns()
+	{
+		// Negative angles too, about six turns each way
+		for step in -40_000..=40_000
+		{
+			assert_close(step as f32 * 0.001, MAX_ERROR);
+		}
+	}
+
+	#[test]
+	fn matches_std_around_quadrant_boundaries()
+	{
+		for quadrant in -32..=32
+		{
+			let boundary = quadrant as f32 * FRAC_PI_2;
+			for &offset in [-1e-3, -1e-6, 0.0, 1e-6, 1e-3, PI / 4.0 - 1e-6, -PI / 4.0 + 1e-6].iter()
+			{
+				assert_close(boundary + offset, MAX_ERROR);
+			}
+		}
+	}
+
+	#[test]
+	fn matches_std_for_large_angles()
+	{
+		for step in -1_000..=1_000
+		{
+			assert_close(step as f32 * 10.007, MAX_LARGE_ANGLE_ERROR);
+		}
+	}
+}
@@ -0,0 +1,260 @@
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Symmetric 4x4 matrix summing the squared distances to a set of planes, see Garland and Heckbert, "Surface
+/// Simplification Using Quadric Error Metrics".
+#[derive(Clone, Copy, Default)]
+struct Quadric
+{
+	a2: f32,
+	ab: f32,
+	ac: f32,
+	ad: f32,
+	b2: f32,
+	bc: f32,
+	bd: f32,
+	c2: f32,
+	cd: f32,
+	d2: f32,
+}
+
+impl Quadric
+{
+	/// Creates the quadric of the plane with the given unit normal and distance, ax + by + cz + d = 0.
+	fn from_plane(normal: Vector3<f32>, d: f32, weight: f32) -> Quadric
+	{
+		let (a, b, c) = (normal.x, normal.y, normal.z);
+		Quadric {
+			a2: weight * a * a,
+			ab: weight * a * b,
+			ac: weight * a * c,
+			ad: weight * a * d,
+			b2: weight * b * b,
+			bc: weight * b * c,
+			bd: weight * b * d,
+			c2: weight * c * c,
+			cd: weight * c * d,
+			d2: weight * d * d,
+		}
+	}
+
+	fn add(&mut self, other: &Quadric)
+	{
+		self.a2 += other.a2;
+		self.ab += other.ab;
+		self.ac += other.ac;
+		self.ad += other.ad;
+		self.b2 += other.b2;
+		self.bc += other.bc;
+		self.bd += other.bd;
+		self.c2 += other.c2;
+		self.cd += other.cd;
+		self.d2 += other.d2;
+	}
+
+	/// Returns the weighted sum of squared distances from the point to the planes.
+	fn error(&self, p: Point3<f32>) -> f32
+	{
+		let (x, y, z) = (p.x, p.y, p.z);
+		return x * x * self.a2 +
+			2.0 * x * y * self.ab +
+			2.0 * x * z * self.ac +
+			2.0 * x * self.ad +
+			y * y * self.b2 +
+			2.0 * y * z * self.bc +
+			2.0 * y * self.bd +
+			z * z * self.c2 +
+			2.0 * z * self.cd +
+			self.d2;
+	}
+}
+
+fn triangle_normal(positions: &[Point3<f32>], tri: &[u16]) -> Vector3<f32>
+{
+	let p0 = positions[tri[0] as usize];
+	return (positions[tri[1] as usize] - p0).cross(positions[tri[2] as usize] - p0);
+}
+
+/// Returns true if moving the vertex would flip any of its triangles that are not removed by the collapse.
+fn collapse_flips(positions: &[Point3<f32>], indices: &[u16], triangles: &[usize], from: u16, to: u16) -> bool
+{
+	for &tri_idx in triangles
+	{
+		let tri = &indices[3 * tri_idx..3 * tri_idx + 3];
+		if tri.contains(&to)
+		{
+			continue;
+		}
+		let moved: Vec<u16> = tri
+			.iter()
+			.map(|&v| {
+				if v == from
+				{
+					to
+				}
+				else
+				{
+					v
+				}
+			})
+			.collect();
+		if triangle_normal(positions, tri).dot(triangle_normal(positions, &moved)) <= 0.0
+		{
+			return true;
+		}
+	}
+	return false;
+}
+
+/// Simplifies a triangle list by collapsing edges with the lowest quadric error, until at most the target number of
+/// indices remain or nothing more can be collapsed.
+///
+/// Vertices are collapsed onto other existing vertices, so the result indexes the same vertex buffer. Vertices on
+/// borders and seams (sharing the position with another vertex) are never moved, keeping silhouettes and texture
+/// coordinates intact.
+pub fn simplify(positions: &[Point3<f32>], indices: &[u16], target_index_count: usize) -> Vec<u16>
+{
+	let vertex_count = positions.len();
+	let mut indices = indices.to_vec();
+
+	let mut locked = vec![false; vertex_count];
+	let mut position_lookup: HashMap<[u32; 3], usize> = HashMap::new();
+	for (idx, p) in positions.iter().enumerate()
+	{
+		let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+		match position_lookup.get(&key)
+		{
+			Some(&other) =>
+			{
+				locked[idx] = true;
+				locked[other] = true;
+			}
+			None =>
+			{
+				position_lookup.insert(key, idx);
+			}
+		}
+	}
+	// Edges not shared by exactly two triangles are on a border
+	let mut edge_uses: HashMap<(u16, u16), u32> = HashMap::new();
+	for tri in indices.chunks(3)
+	{
+		for k in 0..3
+		{
+			let (a, b) = (tri[k], tri[(k + 1) % 3]);
+			*edge_uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+		}
+	}
+	for (&(a, b), &uses) in edge_uses.iter()
+	{
+		if uses != 2
+		{
+			locked[a as usize] = true;
+			locked[b as usize] = true;
+		}
+	}
+
+	// Each vertex starts with the planes of its triangles, weighted by area
+	let mut quadrics = vec![Quadric::default(); vertex_count];
+	for tri in indices.chunks(3)
+	{
+		let normal = triangle_normal(positions, tri);
+		let double_area = normal.magnitude();
+		if double_area <= std::f32::EPSILON
+		{
+			continue;
+		}
+		let normal = normal / double_area;
+		let d = -normal.dot(positions[tri[0] as usize].to_vec());
+		let quadric = Quadric::from_plane(normal, d, 0.5 * double_area);
+		for &v in tri
+		{
+			quadrics[v as usize].add(&quadric);
+		}
+	}
+
+	// Collapse in passes. Within a pass, each vertex is only affected by one collapse, so costs and adjacency can be
+	// computed once per pass.
+	while indices.len() > target_index_count
+	{
+		let mut collapses = Vec::new();
+		for tri in indices.chunks(3)
+		{
+			for k in 0..3
+			{
+				let (a, b) = (tri[k], tri[(k + 1) % 3]);
+				for &(from, to) in [(a, b), (b, a)].iter()
+				{
+					if !locked[from as usize]
+					{
+						let mut quadric = quadrics[from as usize];
+						quadric.add(&quadrics[to as usize]);
+						collapses.push((quadric.error(positions[to as usize]), from, to));
+					}
+				}
+			}
+		}
+		collapses.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+		let mut vertex_triangles = vec![Vec::new(); vertex_count];
+		for (tri_idx, tri) in indices.chunks(3).enumerate()
+		{
+			for &v in tri
+			{
+				vertex_triangles[v as usize].push(tri_idx);
+			}
+		}
+
+		let mut touched = vec![false; vertex_count];
+		let mut triangles_to_remove = (indices.len() - target_index_count + 2) / 3;
+		let mut collapsed_any = false;
+		for (_, from, to) in collapses
+		{
+			if triangles_to_remove == 0
+			{
+				break;
+			}
+			if touched[from as usize] ||
+				touched[to as usize] ||
+				collapse_flips(positions, &indices, &vertex_triangles[from as usize], from, to)
+			{
+				continue;
+			}
+
+			for &tri_idx in &vertex_triangles[from as usize]
+			{
+				let tri = &mut indices[3 * tri_idx..3 * tri_idx + 3];
+				if tri.contains(&to)
+				{
+					triangles_to_remove = triangles_to_remove.saturating_sub(1);
+				}
+				for v in tri.iter_mut()
+				{
+					if *v == from
+					{
+						*v = to;
+					}
+					touched[*v as usize] = true;
+				}
+			}
+			touched[from as usize] = true;
+			let quadric = quadrics[from as usize];
+			quadrics[to as usize].add(&quadric);
+			collapsed_any = true;
+		}
+
+		// Drop the triangles that collapsed to lines
+		indices = indices
+			.chunks(3)
+			.filter(|tri| tri[0] != tri[1] && tri[1] != tri[2] && tri[2] != tri[0])
+			.flat_map(|tri| tri.iter().cloned())
+			.collect();
+		if !collapsed_any
+		{
+			break;
+		}
+	}
+	return indices;
+}
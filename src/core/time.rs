@@ -0,0 +1,196 @@
+use crate::core::Console;
+
+// Ramp duration of slow motion, in unscaled seconds
+const SLOW_MOTION_RAMP: f32 = 0.2;
+
+/// A temporary change of the time scale, ramping in and out. Durations are in unscaled seconds.
+struct TimeScaleEffect
+{
+	scale: f32,
+	ramp_in: f32,
+	hold: f32,
+	ramp_out: f32,
+	elapsed: f32,
+}
+
+impl TimeScaleEffect
+{
+	fn get_scale(&self) -> f32
+	{
+		let lerp = |from: f32, to: f32, t: f32| from + (to - from) * t.max(0.0).min(1.0);
+		if self.elapsed < self.ramp_in
+		{
+			return lerp(1.0, self.scale, self.elapsed / self.ramp_in);
+		}
+		let ramp_out_start = self.ramp_in + self.hold;
+		if self.elapsed < ramp_out_start
+		{
+			return self.scale;
+		}
+		if self.ramp_out > 0.0
+		{
+			return lerp(self.scale, 1.0, (self.elapsed - ramp_out_start) / self.ramp_out);
+		}
+		return 1.0;
+	}
+
+	fn is_done(&self) -> bool
+	{
+		return self.elapsed >= self.ramp_in + self.hold + self.ramp_out;
+	}
+}
+
+/// Which clock a timer follows.
+#[derive(Clone, Copy)]
+pub enum TimeDomain
+{
+	/// Game time, affected by slow motion and hit-stop.
+	Scaled,
+	/// Real time, for UI and other things that should not slow down.
+	Unscaled,
+}
+
+/// Keeps track of game time, which can be slowed down or stopped by time scale effects.
+pub struct Time
+{
+	time: f32,
+	unscaled_time: f32,
+	timestep: f32,
+	unscaled_timestep: f32,
+	time_scale: f32,
+	effects: Vec<TimeScaleEffect>,
+}
+
+impl Time
+{
+	pub fn new(console: &mut Console) -> Time
+	{
+		console.register_float("timescale", 1.0, "Base speed of game time");
+
+		Time {
+			time: 0.0,
+			unscaled_time: 0.0,
+			timestep: 0.0,
+			unscaled_timestep: 0.0,
+			time_scale: 1.0,
+			effects: Vec::new(),
+		}
+	}
+
+	/// Advances time by one engine tick, given in unscaled seconds. Game time stands still while the scene is frozen,
+	/// e.g. in the menu or paused, but unscaled time keeps running for the UI.
+	pub fn update(&mut self, console: &Console, unscaled_timestep: f32, updates_scene: bool)
+	{
+		self.unscaled_timestep = unscaled_timestep;
+		self.unscaled_time += self.unscaled_timestep;
+		if !updates_scene
+		{
+			self.timestep = 0.0;
+			return;
+		}
+
+		for effect in &mut self.effects
+		{
+			effect.elapsed += unscaled_timestep;
+		}
+		self.effects.retain(|effect| !effect.is_done());
+
+		// The strongest effect wins
+		let effect_scale = self.effects.iter().map(|effect| effect.get_scale()).fold(1.0, f32::min);
+		self.time_scale = console.get_float("timescale").max(0.0) * effect_scale;
+
+		self.timestep = unscaled_timestep * self.time_scale;
+		self.time += self.timestep;
+	}
+
+	/// Changes the time scale to the given value for a while, with linear ramps in and out.
+	pub fn add_time_scale_effect(&mut self, scale: f32, ramp_in: f32, hold: f32, ramp_out: f32)
+	{
+		self.effects.push(TimeScaleEffect {
+			scale: scale.max(0.0),
+			ramp_in: ramp_in.max(0.0),
+			hold: hold.max(0.0),
+			ramp_out: ramp_out.max(0.0),
+			elapsed: 0.0,
+		});
+	}
+
+	/// Slows time down to the given scale, for the given duration in unscaled seconds.
+	pub fn slow_motion(&mut self, scale: f32, duration: f32)
+	{
+		self.add_time_scale_effect(scale, SLOW_MOTION_RAMP, duration, SLOW_MOTION_RAMP);
+	}
+
+	/// Stops time completely for a brief moment, given in unscaled seconds.
+	pub fn hit_stop(&mut self, duration: f32)
+	{
+		self.add_time_scale_effect(0.0, 0.0, duration, 0.0);
+	}
+
+	/// Handles time commands from the console, returns false if the command is unknown.
+	pub fn execute_command(&mut self, command: &[String]) -> bool
+	{
+		let args: Option<Vec<f32>> = command[1..].iter().map(|arg| arg.parse().ok()).collect();
+		match (command[0].as_str(), args.as_ref().map(|args| args.as_slice()))
+		{
+			("slowmo", Some(&[scale, duration])) => self.slow_motion(scale, duration),
+			("hitstop", Some(&[duration])) => self.hit_stop(duration),
+			("slowmo", _) => println!("Usage: slowmo <scale> <duration>"),
+			("hitstop", _) => println!("Usage: hitstop <duration>"),
+			_ => return false,
+		}
+		return true;
+	}
+
+	pub fn get_time(&self, domain: TimeDomain) -> f32
+	{
+		match domain
+		{
+			TimeDomain::Scaled => self.time,
+			TimeDomain::Unscaled => self.unscaled_time,
+		}
+	}
+
+	/// Returns the length of the last engine tick, in seconds.
+	pub fn get_timestep(&self, domain: TimeDomain) -> f32
+	{
+		match domain
+		{
+			TimeDomain::Scaled => self.timestep,
+			TimeDomain::Unscaled => self.unscaled_timestep,
+		}
+	}
+
+	pub fn get_time_scale(&self) -> f32
+	{
+		return self.time_scale;
+	}
+}
+
+/// Counts down from a duration, following either scaled or unscaled time.
+pub struct Timer
+{
+	domain: TimeDomain,
+	end: f32,
+}
+
+impl Timer
+{
+	pub fn new(time: &Time, duration: f32, domain: TimeDomain) -> Timer
+	{
+		Timer {
+			domain: domain,
+			end: time.get_time(domain) + duration,
+		}
+	}
+
+	pub fn get_remaining(&self, time: &Time) -> f32
+	{
+		return (self.end - time.get_time(self.domain)).max(0.0);
+	}
+
+	pub fn is_done(&self, time: &Time) -> bool
+	{
+		return self.get_remaining(time) == 0.0;
+	}
+}
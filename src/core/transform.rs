@@ -16,6 +16,11 @@ pub trait Transformable
 		return self.get_transform().get_right_vector();
 	}
 
+	fn get_position(&self) -> Point3<f32>
+	{
+		return self.get_transform().get_position();
+	}
+
 	fn set_position(&mut self, position: Point3<f32>)
 	{
 		self.get_mutable_transform().set_position(position);
@@ -41,6 +46,11 @@ pub trait Transformable
 		self.get_mutable_transform().yaw(angle);
 	}
 
+	fn look_at(&mut self, target: Point3<f32>)
+	{
+		self.get_mutable_transform().look_at(target);
+	}
+
 	fn pitch(&mut self, angle: f32)
 	{
 		self.get_mutable_transform().pitch(angle);
@@ -67,6 +77,7 @@ pub trait Transformable
 	}
 }
 
+#[derive(Clone)]
 pub struct Transform
 {
 	position: Point3<f32>,
@@ -97,6 +108,26 @@ impl Transform
 		return self.position;
 	}
 
+	/// Returns the transform the given amount of the way from this one to the other, turning along the shortest arc.
+	pub fn blend(&self, other: &Transform, amount: f32) -> Transform
+	{
+		// q and -q are the same rotation, the one closer to ours is the shorter way there
+		let rotation = if self.rotation.dot(other.rotation) < 0.0
+		{
+			-other.rotation
+		}
+		else
+		{
+			other.rotation
+		};
+		return Transform {
+			position: self.position + (other.position - self.position) * amount,
+			initial_front: other.initial_front,
+			rotation: self.rotation.slerp(rotation, amount),
+			scale: self.scale + (other.scale - self.scale) * amount,
+		};
+	}
+
 	fn set_position(&mut self, position: Point3<f32>)
 	{
 		self.position = position;
@@ -165,6 +196,22 @@ impl Transform
 		self.globally_rotate(yaw);
 	}
 
+	/// Turns the front towards the target, without rolling. Expects the initial front to be horizontal.
+	fn look_at(&mut self, target: Point3<f32>)
+	{
+		let direction = target - self.position;
+		let flat = Vector3::new(direction.x, 0.0, direction.z);
+		if flat.magnitude2() < 1e-8
+		{
+			// Straight above or below, where the heading is undefined
+			return;
+		}
+		let yaw = Quaternion::from_arc(self.initial_front, flat.normalize(), Some(get_world_up()));
+		// Around a horizontal axis, as the flat direction is horizontal
+		let pitch = Quaternion::from_arc(flat.normalize(), direction.normalize(), None);
+		self.set_rotation(pitch * yaw);
+	}
+
 	fn pitch(&mut self, angle: f32)
 	{
 		let world_up = get_world_up();
@@ -209,7 +256,7 @@ impl Transform
 		return transform_matrix;
 	}
 
-	fn generate_view_matrix(&self) -> Matrix4<f32>
+	pub fn generate_view_matrix(&self) -> Matrix4<f32>
 	{
 		let world_up = get_world_up();
 		let front = self.get_front_vector();
@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+
+/// Identifies commands that are undone and redone together, see UndoStack::push_grouped().
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct UndoGroup(u64);
+
+struct Group<T>
+{
+	id: UndoGroup,
+	commands: Vec<T>,
+}
+
+/// The history of commands that can be undone and redone. Commands hold the state they replaced, so applying one swaps
+/// that state with the current one, and the same call both undoes and redoes it.
+///
+/// Only the given number of groups are kept, the oldest are dropped first.
+pub struct UndoStack<T>
+{
+	done: VecDeque<Group<T>>,
+	undone: Vec<Group<T>>,
+	max_groups: usize,
+	next_group: u64,
+}
+
+impl<T> UndoStack<T>
+{
+	pub fn new(max_groups: usize) -> UndoStack<T>
+	{
+		UndoStack {
+			done: VecDeque::new(),
+			undone: Vec::new(),
+			max_groups: max_groups.max(1),
+			next_group: 0,
+		}
+	}
+
+	/// Returns a group for push_grouped(), which has no commands yet.
+	pub fn new_group(&mut self) -> UndoGroup
+	{
+		let group = UndoGroup(self.next_group);
+		self.next_group += 1;
+		return group;
+	}
+
+	/// Records a command that has been applied, to be undone on its own. Commands undone so far can no longer be
+	/// redone.
+	pub fn push(&mut self, command: T)
+	{
+		let group = self.new_group();
+		self.push_grouped(command, group);
+	}
+
+	/// Records a command that has been applied. It is undone along with the commands pushed right before it in the same
+	/// group, like the steps of a held key.
+	pub fn push_grouped(&mut self, command: T, group: UndoGroup)
+	{
+		self.undone.clear();
+		match self.done.back_mut()
+		{
+			Some(last) if last.id == group => last.commands.push(command),
+			_ =>
+			{
+				self.done.push_back(Group {
+					id: group,
+					commands: vec![command],
+				});
+				if self.done.len() > self.max_groups
+				{
+					self.done.pop_front();
+				}
+			}
+		}
+	}
+
+	/// Undoes the last group, by applying its commands in reverse order. Returns false if there is nothing to undo.
+	pub fn undo(&mut self, mut apply: impl FnMut(&mut T)) -> bool
+	{
+		let mut group = match self.done.pop_back()
+		{
+			Some(group) => group,
+			None => return false,
+		};
+		for command in group.commands.iter_mut().rev()
+		{
+			apply(command);
+		}
+		self.undone.push(group);
+		return true;
+	}
+
+	/// Redoes the last undone group, by applying its commands in order. Returns false if there is nothing to redo.
+	pub fn redo(&mut self, mut apply: impl FnMut(&mut T)) -> bool
+	{
+		let mut group = match self.undone.pop()
+		{
+			Some(group) => group,
+			None => return false,
+		};
+		for command in group.commands.iter_mut()
+		{
+			apply(command);
+		}
+		self.done.push_back(group);
+		return true;
+	}
+
+	/// Drops the commands that no longer apply to anything, and the groups left without commands.
+	pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool)
+	{
+		for group in self.done.iter_mut().chain(self.undone.iter_mut())
+		{
+			group.commands.retain(|command| keep(command));
+		}
+		self.done.retain(|group| !group.commands.is_empty());
+		self.undone.retain(|group| !group.commands.is_empty());
+	}
+
+	/// Returns how many groups can be undone and redone.
+	pub fn get_counts(&self) -> (usize, usize)
+	{
+		return (self.done.len(), self.undone.len());
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::mem::swap;
+
+	// Commands are the previous values of a single number, set before each push
+	fn set(stack: &mut UndoStack<i32>, value: &mut i32, new_value: i32)
+	{
+		stack.push(*value);
+		*value = new_value;
+	}
+
+	#[test]
+	fn undo_and_redo_swap_values()
+	{
+		let mut stack = UndoStack::new(8);
+		let mut value = 0;
+		set(&mut stack, &mut value, 1);
+		set(&mut stack, &mut value, 2);
+		assert_eq!(stack.get_counts(), (2, 0));
+
+		assert!(stack.undo(|old| swap(old, &mut value)));
+		assert_eq!(value, 1);
+		assert!(stack.undo(|old| swap(old, &mut value)));
+		assert_eq!(value, 0);
+		assert!(!stack.undo(|old| swap(old, &mut value)));
+		assert_eq!(stack.get_counts(), (0, 2));
+
+		assert!(stack.redo(|old| swap(old, &mut value)));
+		assert_eq!(value, 1);
+		assert!(stack.redo(|old| swap(old, &mut value)));
+		assert_eq!(value, 2);
+		assert!(!stack.redo(|old| swap(old, &mut value)));
+		assert_eq!(stack.get_counts(), (2, 0));
+	}
+
+	#[test]
+	fn push_after_undo_drops_redo_branch()
+	{
+		let mut stack = UndoStack::new(8);
+		let mut value = 0;
+		set(&mut stack, &mut value, 1);
+		set(&mut stack, &mut value, 2);
+		set(&mut stack, &mut value, 3);
+		stack.undo(|old| swap(old, &mut value));
+		stack.undo(|old| swap(old, &mut value));
+		assert_eq!((value, stack.get_counts()), (1, (1, 2)));
+
+		set(&mut stack, &mut value, 10);
+		assert_eq!(stack.get_counts(), (2, 0));
+		assert!(!stack.redo(|old| swap(old, &mut value)));
+		assert_eq!(value, 10);
+		stack.undo(|old| swap(old, &mut value));
+		assert_eq!(value, 1);
+		stack.undo(|old| swap(old, &mut value));
+		assert_eq!(value, 0);
+	}
+
+	#[test]
+	fn grouped_commands_undo_together()
+	{
+		let mut stack = UndoStack::new(8);
+		let mut value = 0;
+		set(&mut stack, &mut value, 1);
+		// Like the steps of a held key
+		let group = stack.new_group();
+		for step in 2..5
+		{
+			stack.push_grouped(value, group);
+			value = step;
+		}
+		assert_eq!(stack.get_counts(), (2, 0));
+
+		stack.undo(|old| swap(old, &mut value));
+		assert_eq!(value, 1);
+		stack.redo(|old| swap(old, &mut value));
+		assert_eq!(value, 4);
+
+		// A group interrupted by another command starts over as a new one
+		set(&mut stack, &mut value, 5);
+		stack.push_grouped(value, group);
+		value = 6;
+		assert_eq!(stack.get_counts(), (4, 0));
+		stack.undo(|old| swap(old, &mut value));
+		assert_eq!(value, 5);
+	}
+
+	#[test]
+	fn oldest_groups_are_dropped()
+	{
+		let mut stack = UndoStack::new(2);
+		let mut value = 0;
+		for new_value in 1..=4
+		{
+			set(&mut stack, &mut value, new_value);
+		}
+		assert_eq!(stack.get_counts(), (2, 0));
+		while stack.undo(|old| swap(old, &mut value))
+		{}
+		assert_eq!(value, 2);
+	}
+
+	#[test]
+	fn retain_drops_emptied_groups()
+	{
+		let mut stack = UndoStack::new(8);
+		let mut value = 0;
+		for new_value in 1..=4
+		{
+			set(&mut stack, &mut value, new_value);
+		}
+		stack.undo(|old| swap(old, &mut value));
+		assert_eq!(stack.get_counts(), (3, 1));
+
+		// Undoing swapped 4 into the undone command, so this empties it and one done group
+		stack.retain(|&old| old != 1 && old != 4);
+		assert_eq!(stack.get_counts(), (2, 0));
+		let mut undone = Vec::new();
+		while stack.undo(|&mut old| undone.push(old))
+		{}
+		assert_eq!(undone, [2, 0]);
+	}
+}
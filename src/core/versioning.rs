@@ -0,0 +1,93 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{self, Value};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+
+/// Key of the format version in serialized files.
+const VERSION_KEY: &str = "format_version";
+
+/// Upgrades a parsed file from one version of its format to the next.
+pub type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// Files written before formats were versioned are version 0, with the same layout as version 1.
+pub fn unversioned_to_v1(_value: &mut Value) -> Result<(), String>
+{
+	return Ok(());
+}
+
+/// Replaces the file at the given path with the given contents. The contents are written to a temporary file next to
+/// it first, which is then renamed over it, so a crash while writing leaves either the old or the new file.
+pub fn write_atomic(path: &str, contents: &[u8]) -> io::Result<()>
+{
+	let temp_path = format!("{}.tmp", path);
+	let mut file = File::create(&temp_path)?;
+	file.write_all(contents)?;
+	file.sync_all()?;
+	return fs::rename(&temp_path, path);
+}
+
+/// A serialized (JSON) file format, with its current version and the migrations upgrading older files.
+///
+/// Files are written with a version header. When read, older files are upgraded step by step before they are
+/// deserialized, and files from newer versions of the game are rejected with a clear error.
+pub struct FileFormat
+{
+	pub name: &'static str,
+	pub version: u32,
+	/// Migration i upgrades version i to i + 1, so there is one per version.
+	pub migrations: &'static [Migration],
+}
+
+impl FileFormat
+{
+	/// Reads a file, upgrading it to the current version.
+	pub fn read<T: DeserializeOwned>(&self, path: &str) -> Result<T, String>
+	{
+		let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+		return self.from_reader(file).map_err(|e| format!("{}: {}", path, e));
+	}
+
+	/// Parses a file from a reader, upgrading it to the current version.
+	pub fn from_reader<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T, String>
+	{
+		let mut value: Value = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+		debug_assert!(self.migrations.len() == self.version as usize);
+		let object = value.as_object_mut().ok_or(format!("{} is not a JSON object", self.name))?;
+		let version = match object.remove(VERSION_KEY)
+		{
+			None => 0,
+			Some(version) => version.as_u64().ok_or(format!("invalid {}", VERSION_KEY))?,
+		};
+		if version > self.version as u64
+		{
+			return Err(format!(
+				"{} version {} is newer than the latest supported version {}",
+				self.name, version, self.version
+			));
+		}
+		for (from, migration) in self.migrations.iter().enumerate().skip(version as usize)
+		{
+			migration(&mut value).map_err(|e| format!("upgrading {} from version {}: {}", self.name, from, e))?;
+		}
+		return serde_json::from_value(value).map_err(|e| format!("{} version {}: {}", self.name, self.version, e));
+	}
+
+	/// Serializes data in the current version.
+	pub fn to_string<T: Serialize>(&self, data: &T) -> Result<String, String>
+	{
+		let mut value = serde_json::to_value(data).map_err(|e| e.to_string())?;
+		if let Some(object) = value.as_object_mut()
+		{
+			object.insert(VERSION_KEY.to_string(), Value::from(self.version));
+		}
+		return serde_json::to_string_pretty(&value).map_err(|e| e.to_string());
+	}
+
+	/// Writes a file in the current version, replacing it atomically.
+	pub fn write<T: Serialize>(&self, path: &str, data: &T) -> Result<(), String>
+	{
+		let contents = self.to_string(data).map_err(|e| format!("{}: {}", path, e))?;
+		return write_atomic(path, contents.as_bytes()).map_err(|e| format!("{}: {}", path, e));
+	}
+}
@@ -0,0 +1,428 @@
+use crate::core::{Drawable, Material, Mesh, ParticleVertex, Rng, Transform, Transformable};
+use crate::game::NavMesh;
+use cgmath::prelude::*;
+use cgmath::{Deg, Point3, Rad, Vector3};
+use serde_derive::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+// Distance at which a path point or target counts as reached
+const ARRIVE_RADIUS: f32 = 0.5;
+// Distance from the final target at which agents start slowing down
+const SLOW_RADIUS: f32 = 3.0;
+// Wander target circle, ahead of the agent
+const WANDER_DISTANCE: f32 = 2.0;
+const WANDER_RADIUS: f32 = 1.0;
+// Max change of the wander angle, in radians per second
+const WANDER_JITTER: f32 = 4.0;
+// Agents only flee from targets closer than this
+const FLEE_DISTANCE: f32 = 10.0;
+
+const TARGET_COLOR: [f32; 4] = [1.0, 0.2, 0.2, 1.0];
+const VELOCITY_COLOR: [f32; 4] = [0.2, 1.0, 1.0, 1.0];
+const PATH_COLOR: [f32; 4] = [1.0, 0.6, 0.1, 1.0];
+
+/// Steering behavior of an agent, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentBehavior
+{
+	/// Walk around randomly.
+	Wander,
+	/// Walk to the target along the navmesh, and stop there.
+	Seek
+	{
+		target: [f32; 3],
+	},
+	/// Run away from the target when it is close.
+	Flee
+	{
+		target: [f32; 3],
+	},
+	/// Walk between the waypoints along the navmesh, in a loop.
+	Patrol
+	{
+		waypoints: Vec<[f32; 3]>,
+	},
+	/// Race through the checkpoints, see RaceManager. Stands still when there is no race.
+	Race,
+}
+
+impl Default for AgentBehavior
+{
+	fn default() -> AgentBehavior
+	{
+		AgentBehavior::Wander
+	}
+}
+
+/// An agent to spawn, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AgentSettings
+{
+	pub position: [f32; 3],
+	#[serde(default)]
+	pub behavior: AgentBehavior,
+	#[serde(default = "AgentSettings::default_max_speed")]
+	pub max_speed: f32,
+}
+
+impl AgentSettings
+{
+	fn default_max_speed() -> f32
+	{
+		3.0
+	}
+}
+
+/// Steering state of an agent. Positions are on the ground, as given by the navmesh.
+struct Steering
+{
+	behavior: AgentBehavior,
+	position: Point3<f32>,
+	velocity: Vector3<f32>,
+	max_speed: f32,
+	max_force: f32,
+	wander_angle: f32,
+	rng: Rng,
+	path: Vec<Point3<f32>>,
+	path_idx: usize,
+	waypoint_idx: usize,
+	// Next checkpoint while racing
+	race_target: Option<Point3<f32>>,
+	// Where the agent is currently steering towards, for debugging
+	target: Option<Point3<f32>>,
+}
+
+impl Steering
+{
+	fn truncate(vector: Vector3<f32>, max_length: f32) -> Vector3<f32>
+	{
+		if vector.magnitude2() > max_length * max_length
+		{
+			return vector.normalize_to(max_length);
+		}
+		return vector;
+	}
+
+	fn seek(&self, target: Point3<f32>) -> Vector3<f32>
+	{
+		let offset = target - self.position;
+		if offset.magnitude2() == 0.0
+		{
+			return -self.velocity;
+		}
+		return offset.normalize_to(self.max_speed) - self.velocity;
+	}
+
+	fn arrive(&self, target: Point3<f32>) -> Vector3<f32>
+	{
+		let offset = target - self.position;
+		let distance = offset.magnitude();
+		if distance < 0.01
+		{
+			return -self.velocity;
+		}
+		let speed = self.max_speed * (distance / SLOW_RADIUS).min(1.0);
+		return offset * (speed / distance) - self.velocity;
+	}
+
+	fn flee(&self, target: Point3<f32>) -> Vector3<f32>
+	{
+		let offset = self.position - target;
+		if offset.magnitude2() == 0.0 || offset.magnitude2() > FLEE_DISTANCE * FLEE_DISTANCE
+		{
+			// Out of danger, come to a stop
+			return -self.velocity;
+		}
+		return offset.normalize_to(self.max_speed) - self.velocity;
+	}
+
+	fn wander(&mut self, timestep: f32) -> Vector3<f32>
+	{
+		self.wander_angle += self.rng.range(-1.0, 1.0) * WANDER_JITTER * timestep;
+		let heading = if self.velocity.magnitude2() > 0.0
+		{
+			self.velocity.normalize()
+		}
+		else
+		{
+			Vector3::unit_z()
+		};
+		let circle_center = self.position + heading * WANDER_DISTANCE;
+		let target =
+			circle_center + Vector3::new(self.wander_angle.cos(), 0.0, self.wander_angle.sin()) * WANDER_RADIUS;
+		self.target = Some(target);
+		return self.seek(target);
+	}
+
+	/// Follows the current path, arriving at its last point.
+	fn follow_path(&mut self) -> Vector3<f32>
+	{
+		while self.path_idx + 1 < self.path.len() && self.position.distance(self.path[self.path_idx]) < ARRIVE_RADIUS
+		{
+			self.path_idx += 1;
+		}
+		match self.path.get(self.path_idx)
+		{
+			Some(&target) =>
+			{
+				self.target = Some(target);
+				if self.path_idx + 1 == self.path.len()
+				{
+					return self.arrive(target);
+				}
+				return self.seek(target);
+			}
+			None =>
+			{
+				self.target = None;
+				return -self.velocity;
+			}
+		}
+	}
+
+	fn set_path_to(&mut self, navmesh: &NavMesh, target: Point3<f32>)
+	{
+		self.path = navmesh.find_path(self.position, target).unwrap_or(Vec::new());
+		self.path_idx = 0;
+	}
+
+	fn is_path_done(&self) -> bool
+	{
+		return self.path.last().map_or(true, |&end| self.position.distance(end) < ARRIVE_RADIUS);
+	}
+
+	fn update(&mut self, navmesh: &NavMesh, timestep: f32)
+	{
+		let force = match self.behavior.clone()
+		{
+			AgentBehavior::Wander => self.wander(timestep),
+			AgentBehavior::Seek {
+				target,
+			} =>
+			{
+				if self.path.is_empty()
+				{
+					self.set_path_to(navmesh, Point3::from(target));
+				}
+				self.follow_path()
+			}
+			AgentBehavior::Flee {
+				target,
+			} =>
+			{
+				self.target = Some(Point3::from(target));
+				self.flee(Point3::from(target))
+			}
+			AgentBehavior::Patrol {
+				waypoints,
+			} =>
+			{
+				if !waypoints.is_empty() && self.is_path_done()
+				{
+					self.set_path_to(navmesh, Point3::from(waypoints[self.waypoint_idx]));
+					self.waypoint_idx = (self.waypoint_idx + 1) % waypoints.len();
+				}
+				self.follow_path()
+			}
+			AgentBehavior::Race => match self.race_target
+			{
+				Some(target) =>
+				{
+					if self.path.is_empty()
+					{
+						self.set_path_to(navmesh, target);
+					}
+					self.follow_path()
+				}
+				None =>
+				{
+					self.target = None;
+					-self.velocity
+				}
+			},
+		};
+
+		let force = Steering::truncate(force, self.max_force);
+		self.velocity = Steering::truncate(self.velocity + force * timestep, self.max_speed);
+		self.velocity.y = 0.0;
+
+		// Stay on the navmesh
+		let next_position = self.position + self.velocity * timestep;
+		match navmesh.get_height(next_position.x, next_position.z)
+		{
+			Some(height) => self.position = Point3::new(next_position.x, height, next_position.z),
+			None =>
+			{
+				self.velocity = Vector3::zero();
+				// Turn around, so wandering agents don't keep walking into the edge
+				self.wander_angle += PI;
+			}
+		}
+	}
+}
+
+/// A character walking the navmesh using steering behaviors.
+pub struct Agent
+{
+	transform: Transform,
+	steering: Steering,
+	// Max speed from the scene file, before rubber-banding
+	base_max_speed: f32,
+	// Distance from the ground to the transform position, as the mesh is centered
+	ground_offset: f32,
+	mesh: Rc<Mesh>,
+	material: Rc<Material>,
+}
+
+impl Agent
+{
+	pub fn new(settings: &AgentSettings, rng: Rng, height: f32, mesh: Rc<Mesh>, material: Rc<Material>) -> Agent
+	{
+		let position = Point3::from(settings.position);
+		let mut agent = Agent {
+			transform: Transform::new(),
+			steering: Steering {
+				behavior: settings.behavior.clone(),
+				position: position,
+				velocity: Vector3::zero(),
+				max_speed: settings.max_speed,
+				max_force: 2.0 * settings.max_speed,
+				wander_angle: 0.0,
+				rng: rng,
+				path: Vec::new(),
+				path_idx: 0,
+				waypoint_idx: 0,
+				race_target: None,
+				target: None,
+			},
+			base_max_speed: settings.max_speed,
+			ground_offset: 0.5 * height,
+			mesh: mesh,
+			material: material,
+		};
+		agent.set_position(position + Vector3::new(0.0, agent.ground_offset, 0.0));
+		return agent;
+	}
+
+	/// Steers the agent one engine tick, and turns it to face the direction it walks in.
+	pub fn update(&mut self, navmesh: &NavMesh, timestep: f32)
+	{
+		self.steering.update(navmesh, timestep);
+
+		let position = self.steering.position + Vector3::new(0.0, self.ground_offset, 0.0);
+		self.set_position(position);
+
+		let velocity = self.steering.velocity;
+		if velocity.magnitude2() > 0.01
+		{
+			let front = self.get_front_vector();
+			let turn = Rad::atan2(front.cross(velocity).y, front.dot(velocity));
+			self.yaw(Deg::from(turn).0);
+		}
+	}
+
+	pub fn is_racer(&self) -> bool
+	{
+		return match self.steering.behavior
+		{
+			AgentBehavior::Race => true,
+			_ => false,
+		};
+	}
+
+	/// Sets the checkpoint a racing agent heads for, finding a new path when it changes.
+	pub fn set_race_target(&mut self, target: Option<Point3<f32>>)
+	{
+		if target != self.steering.race_target
+		{
+			self.steering.race_target = target;
+			self.steering.path.clear();
+			self.steering.path_idx = 0;
+		}
+	}
+
+	/// Scales the max speed and steering force of the agent, relative to its settings.
+	pub fn set_speed_scale(&mut self, scale: f32)
+	{
+		self.steering.max_speed = self.base_max_speed * scale;
+		self.steering.max_force = 2.0 * self.steering.max_speed;
+	}
+
+	pub fn get_velocity(&self) -> Vector3<f32>
+	{
+		return self.steering.velocity;
+	}
+
+	/// Returns the material of the agent, which is shared with the other agents.
+	pub fn get_shared_material(&self) -> Rc<Material>
+	{
+		return self.material.clone();
+	}
+
+	/// Returns the end of the path the agent is walking, if any.
+	pub fn get_destination(&self) -> Option<Point3<f32>>
+	{
+		return self.steering.path.last().cloned();
+	}
+
+	/// Appends lines showing the agent's target, velocity and path.
+	pub fn append_debug_lines(&self, vertices: &mut Vec<ParticleVertex>)
+	{
+		let position = self.get_position();
+		let mut push_line = |a: Point3<f32>, b: Point3<f32>, color: [f32; 4]| {
+			vertices.push(ParticleVertex {
+				pos: a.into(),
+				color: color,
+			});
+			vertices.push(ParticleVertex {
+				pos: b.into(),
+				color: color,
+			});
+		};
+
+		if let Some(target) = self.steering.target
+		{
+			push_line(position, target, TARGET_COLOR);
+		}
+		push_line(position, position + self.steering.velocity, VELOCITY_COLOR);
+		let path = &self.steering.path;
+		for idx in self.steering.path_idx..path.len()
+		{
+			let from = if idx == self.steering.path_idx
+			{
+				self.steering.position
+			}
+			else
+			{
+				path[idx - 1]
+			};
+			push_line(from, path[idx], PATH_COLOR);
+		}
+	}
+}
+
+impl Transformable for Agent
+{
+	fn get_transform(&self) -> &Transform
+	{
+		return &self.transform;
+	}
+	fn get_mutable_transform(&mut self) -> &mut Transform
+	{
+		return &mut self.transform;
+	}
+}
+
+impl Drawable for Agent
+{
+	fn get_mesh(&self) -> &Mesh
+	{
+		return &self.mesh;
+	}
+	fn get_material(&self) -> &Material
+	{
+		return &self.material;
+	}
+}
@@ -1,6 +1,52 @@
-use crate::core::{Action, InputConsumer, MouseConsumer, Transform, Transformable};
+use crate::core::{Action, ActionEvents, BoundingBox, InputConsumer, MouseConsumer, Transform, Transformable};
 use bit_vec::BitVec;
-use cgmath::{Point3, Vector3};
+use cgmath::{Matrix4, Point3, Rad, Vector3};
+
+// Extra distance when framing, so small objects are not clipped by the near plane
+const FRAMING_MARGIN: f32 = 1.0;
+// Rates, per second, at which the boom shortens when obstructed and lengthens again once clear. Pulling in is fast to
+// clip as little as possible, easing out is slow so the camera does not pop back.
+const BOOM_PULL_IN_RATE: f32 = 20.0;
+const BOOM_EASE_OUT_RATE: f32 = 2.0;
+// Orbit distances in meters, and the change per mouse wheel step
+const ORBIT_MIN_DISTANCE: f32 = 0.5;
+const ORBIT_MAX_DISTANCE: f32 = 500.0;
+const ORBIT_ZOOM_STEP: f32 = 1.15;
+// Distance panned per pixel dragged, relative to the orbit distance
+const ORBIT_PAN_SPEED: f32 = 0.002;
+// Degrees rotated per pixel dragged
+const ORBIT_ROTATE_SPEED: f32 = 0.3;
+// Seconds the view takes to move to where a new camera mode puts it
+const TRANSITION_TIME: f32 = 0.6;
+
+/// How the camera is moved, switched with the cam_mode command, or between free and follow with CAMERA_TOGGLE.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CameraMode
+{
+	/// Stays in place, looking around with the mouse.
+	Free,
+	/// Chases the car, see CameraBoom.
+	Follow,
+	/// Orbits the selected object, see Orbit.
+	Orbit,
+	/// Sits on the hood of the car, looking ahead.
+	Hood,
+}
+
+impl CameraMode
+{
+	pub fn from_name(name: &str) -> Option<CameraMode>
+	{
+		match name
+		{
+			"free" => Some(CameraMode::Free),
+			"follow" => Some(CameraMode::Follow),
+			"orbit" => Some(CameraMode::Orbit),
+			"hood" => Some(CameraMode::Hood),
+			_ => None,
+		}
+	}
+}
 
 pub struct Camera
 {
@@ -22,6 +68,241 @@ impl Camera
 		cam.set_initial_front_vector(front_vector);
 		return cam;
 	}
+
+	/// Moves the camera back along its view direction until the bounding box fits in the field of view, centered.
+	pub fn frame(&mut self, bounds: &BoundingBox, vertical_fov: Rad<f32>, aspect_ratio: f32)
+	{
+		// Fit the bounding sphere, so the result does not depend on the view direction
+		let half_vertical = vertical_fov.0 * 0.5;
+		let half_horizontal = (half_vertical.tan() * aspect_ratio).atan();
+		let half_fov = half_vertical.min(half_horizontal);
+		let distance = bounds.get_radius() / half_fov.sin() + FRAMING_MARGIN;
+
+		let position = bounds.get_center() - self.get_front_vector() * distance;
+		self.set_position(position);
+	}
+}
+
+/// The distance of a chase camera from its target, shortened when geometry is in the way.
+///
+/// The caller casts a sphere from the target towards the desired camera position, and passes the distance to the first
+/// hit. The boom then eases towards the shorter of the two.
+pub struct CameraBoom
+{
+	// Current length, None until the first update
+	length: Option<f32>,
+}
+
+impl CameraBoom
+{
+	pub fn new() -> CameraBoom
+	{
+		CameraBoom {
+			length: None,
+		}
+	}
+
+	/// Snaps to the desired length at the next update, e.g. when the camera starts following a new target.
+	pub fn reset(&mut self)
+	{
+		self.length = None;
+	}
+
+	/// Updates and returns the length of the boom, given the desired length and the distance to the first obstruction
+	/// between the target and the camera, if any.
+	pub fn update(&mut self, desired_length: f32, obstruction: Option<f32>, timestep: f32) -> f32
+	{
+		let target_length = obstruction.map_or(desired_length, |distance| distance.min(desired_length));
+		let length = match self.length
+		{
+			None => target_length,
+			Some(length) =>
+			{
+				let rate = if target_length < length
+				{
+					BOOM_PULL_IN_RATE
+				}
+				else
+				{
+					BOOM_EASE_OUT_RATE
+				};
+				length + (target_length - length) * (1.0 - (-rate * timestep).exp())
+			}
+		};
+		self.length = Some(length);
+		return length;
+	}
+}
+
+/// A pivot the camera orbits at a distance, for inspecting objects.
+///
+/// The camera looks at the pivot along its own view direction, so it is rotated by mouse look while the cursor is
+/// captured, and by dragging otherwise.
+pub struct Orbit
+{
+	pub pivot: Point3<f32>,
+	distance: f32,
+}
+
+impl Orbit
+{
+	pub fn new(pivot: Point3<f32>, distance: f32) -> Orbit
+	{
+		Orbit {
+			pivot: pivot,
+			distance: distance.max(ORBIT_MIN_DISTANCE).min(ORBIT_MAX_DISTANCE),
+		}
+	}
+
+	/// Rotates the camera around the pivot, by a mouse drag in pixels.
+	pub fn rotate(&self, camera: &mut Camera, drag: (i32, i32))
+	{
+		camera.yaw(-drag.0 as f32 * ORBIT_ROTATE_SPEED);
+		camera.pitch(-drag.1 as f32 * ORBIT_ROTATE_SPEED);
+	}
+
+	/// Moves the pivot in the view plane of the camera, by a mouse drag in pixels. The pivot follows the cursor.
+	pub fn pan(&mut self, camera: &Camera, drag: (i32, i32))
+	{
+		let right = camera.get_right_vector();
+		let up = right.cross(camera.get_front_vector());
+		let scale = self.distance * ORBIT_PAN_SPEED;
+		self.pivot += (-right * drag.0 as f32 + up * drag.1 as f32) * scale;
+	}
+
+	/// Moves towards the pivot by the given number of mouse wheel steps, or away if negative.
+	pub fn zoom(&mut self, steps: f32)
+	{
+		self.distance = (self.distance * ORBIT_ZOOM_STEP.powf(-steps)).max(ORBIT_MIN_DISTANCE).min(ORBIT_MAX_DISTANCE);
+	}
+
+	/// Places the camera at the orbit distance from the pivot, looking at it.
+	pub fn apply(&self, camera: &mut Camera)
+	{
+		let position = self.pivot - camera.get_front_vector() * self.distance;
+		camera.set_position(position);
+	}
+}
+
+/// Eases the view from where the camera was when its mode changed to where the new mode puts it, so switching modes
+/// does not cut. The camera itself is moved by the mode right away, only the view is blended.
+pub struct CameraTransition
+{
+	// Where the camera was when the mode changed
+	from: Transform,
+	// Seconds since the mode changed
+	elapsed: f32,
+}
+
+impl CameraTransition
+{
+	pub fn new(camera: &Camera) -> CameraTransition
+	{
+		CameraTransition {
+			from: camera.get_transform().clone(),
+			elapsed: 0.0,
+		}
+	}
+
+	/// Advances the transition, returns false once it is over.
+	pub fn update(&mut self, timestep: f32) -> bool
+	{
+		self.elapsed += timestep;
+		return self.elapsed < TRANSITION_TIME;
+	}
+
+	/// Returns the view matrix between where the camera was and where it is now.
+	pub fn generate_view_matrix(&self, camera: &Camera) -> Matrix4<f32>
+	{
+		let t = (self.elapsed / TRANSITION_TIME).min(1.0);
+		// Smoothstep, so the view eases out of the old pose and into the new one
+		let amount = t * t * (3.0 - 2.0 * t);
+		return self.from.blend(camera.get_transform(), amount).generate_view_matrix();
+	}
+}
+
+/// Receives the request to switch between the free and the follow camera, see CameraMode.
+pub struct CameraToggleInput
+{
+	requested: bool,
+}
+
+impl CameraToggleInput
+{
+	pub fn new() -> CameraToggleInput
+	{
+		CameraToggleInput {
+			requested: false,
+		}
+	}
+
+	/// Returns true if switching was requested since the last call.
+	pub fn take_request(&mut self) -> bool
+	{
+		let requested = self.requested;
+		self.requested = false;
+		return requested;
+	}
+}
+
+impl InputConsumer for CameraToggleInput
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		handled_actions.set(Action::CAMERA_TOGGLE as usize, true);
+		return handled_actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		if actions.is_triggered(Action::CAMERA_TOGGLE)
+		{
+			self.requested = true;
+		}
+	}
+}
+
+/// Receives the request to frame the selection with the camera, see Scene::frame_selection.
+pub struct FramingInput
+{
+	requested: bool,
+}
+
+impl FramingInput
+{
+	pub fn new() -> FramingInput
+	{
+		FramingInput {
+			requested: false,
+		}
+	}
+
+	/// Returns true if framing was requested since the last call.
+	pub fn take_request(&mut self) -> bool
+	{
+		let requested = self.requested;
+		self.requested = false;
+		return requested;
+	}
+}
+
+impl InputConsumer for FramingInput
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		handled_actions.set(Action::FRAME_SELECTION as usize, true);
+		return handled_actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		if actions.is_triggered(Action::FRAME_SELECTION)
+		{
+			self.requested = true;
+		}
+	}
 }
 
 impl Transformable for Camera
@@ -56,57 +337,57 @@ impl InputConsumer for Camera
 
 		return handled_actions;
 	}
-	fn consume(&mut self, actions: BitVec)
+	fn consume(&mut self, actions: &ActionEvents)
 	{
 		let mut move_speed = 0.3;
-		if actions.get(Action::SPRINT as usize).unwrap()
+		if actions.is_triggered(Action::SPRINT)
 		{
 			move_speed *= 10.0;
 		}
 
-		if actions.get(Action::FORWARD as usize).unwrap()
+		if actions.is_triggered(Action::FORWARD)
 		{
 			let translation = self.get_front_vector();
 			self.translate(translation * move_speed);
 		}
-		if actions.get(Action::LEFT as usize).unwrap()
+		if actions.is_triggered(Action::LEFT)
 		{
 			let translation = self.get_right_vector() * -1.0;
 			self.translate(translation * move_speed);
 		}
-		if actions.get(Action::BACK as usize).unwrap()
+		if actions.is_triggered(Action::BACK)
 		{
 			let translation = self.get_front_vector() * -1.0;
 			self.translate(translation * move_speed);
 		}
-		if actions.get(Action::RIGHT as usize).unwrap()
+		if actions.is_triggered(Action::RIGHT)
 		{
 			let translation = self.get_right_vector();
 			self.translate(translation * move_speed);
 		}
-		if actions.get(Action::UP as usize).unwrap()
+		if actions.is_triggered(Action::UP)
 		{
 			let translation = Vector3::unit_y();
 			self.translate(translation * move_speed);
 		}
-		if actions.get(Action::DOWN as usize).unwrap()
+		if actions.is_triggered(Action::DOWN)
 		{
 			let translation = Vector3::unit_y() * -1.0;
 			self.translate(translation * move_speed);
 		}
-		if actions.get(Action::CAM_UP as usize).unwrap()
+		if actions.is_triggered(Action::CAM_UP)
 		{
 			self.pitch(5.0);
 		}
-		if actions.get(Action::CAM_LEFT as usize).unwrap()
+		if actions.is_triggered(Action::CAM_LEFT)
 		{
 			self.yaw(5.0);
 		}
-		if actions.get(Action::CAM_DOWN as usize).unwrap()
+		if actions.is_triggered(Action::CAM_DOWN)
 		{
 			self.pitch(-5.0);
 		}
-		if actions.get(Action::CAM_RIGHT as usize).unwrap()
+		if actions.is_triggered(Action::CAM_RIGHT)
 		{
 			self.yaw(-5.0);
 		}
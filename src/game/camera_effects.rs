@@ -0,0 +1,137 @@
+use crate::core::{Console, Rng};
+use cgmath::prelude::*;
+use cgmath::{Deg, Matrix4, Vector3};
+
+// Trauma lost per second
+const TRAUMA_DECAY: f32 = 0.8;
+// Shake at full trauma
+const MAX_SHAKE_ANGLE: Deg<f32> = Deg(4.0);
+const MAX_SHAKE_OFFSET: f32 = 0.15;
+// Speed of the shake noise, in noise periods per second
+const SHAKE_FREQUENCY: f32 = 18.0;
+
+// Spring pulling impulse offsets back to rest, critically damped
+const IMPULSE_STIFFNESS: f32 = 120.0;
+const MAX_IMPULSE_OFFSET: f32 = 1.0;
+// Rate at which FOV kicks return to zero, per second
+const FOV_RECOVERY: f32 = 6.0;
+const MAX_FOV_KICK: Deg<f32> = Deg(30.0);
+
+fn hash(x: i32, seed: u32) -> u32
+{
+	let mut h = (x as u32).wrapping_mul(0x27D4_EB2D) ^ seed.wrapping_mul(0x9E37_79B9);
+	h ^= h >> 15;
+	h = h.wrapping_mul(0x85EB_CA6B);
+	h ^= h >> 13;
+	return h;
+}
+
+/// 1D Perlin noise, returns a smooth value in roughly [-1, 1].
+fn perlin_noise(x: f32, seed: u32) -> f32
+{
+	let x0 = x.floor();
+	let t = x - x0;
+	let gradient = |i: i32| hash(i, seed) as f32 / std::u32::MAX as f32 * 2.0 - 1.0;
+	let g0 = gradient(x0 as i32) * t;
+	let g1 = gradient(x0 as i32 + 1) * (t - 1.0);
+	let fade = t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+	// Gradient noise peaks at 0.5 in 1D
+	return 2.0 * (g0 + fade * (g1 - g0));
+}
+
+/// Effects composited on top of the camera: trauma based shake, impulses and FOV kicks.
+///
+/// Shake is driven by trauma in [0, 1], which decays over time. The shake amount is trauma squared, so small hits
+/// barely register while big ones feel violent.
+pub struct CameraEffects
+{
+	trauma: f32,
+	time: f32,
+	// World space offset of the camera caused by impulses, and its velocity
+	impulse_offset: Vector3<f32>,
+	impulse_velocity: Vector3<f32>,
+	fov_offset: Deg<f32>,
+	scale: f32,
+	// Offsets the noise, so the shake differs between seeds
+	noise_seed: u32,
+}
+
+impl CameraEffects
+{
+	pub fn new(console: &mut Console, rng: &mut Rng) -> CameraEffects
+	{
+		console.register_float("cam_shake_scale", 1.0, "Strength of camera shake, impulses and FOV kicks");
+
+		CameraEffects {
+			trauma: 0.0,
+			time: 0.0,
+			impulse_offset: Vector3::zero(),
+			impulse_velocity: Vector3::zero(),
+			fov_offset: Deg(0.0),
+			scale: 1.0,
+			noise_seed: rng.next_u32(),
+		}
+	}
+
+	/// Adds trauma, making the camera shake. The total trauma is clamped to [0, 1].
+	pub fn add_trauma(&mut self, amount: f32)
+	{
+		self.trauma = (self.trauma + amount).max(0.0).min(1.0);
+	}
+
+	/// Pushes the camera in the given world space direction, after which it springs back.
+	/// The length of the impulse is the initial speed of the camera, in meters per second.
+	pub fn add_impulse(&mut self, impulse: Vector3<f32>)
+	{
+		self.impulse_velocity += impulse;
+	}
+
+	/// Widens (or narrows, if negative) the field of view, after which it returns to normal.
+	pub fn kick_fov(&mut self, angle: Deg<f32>)
+	{
+		self.fov_offset = Deg((self.fov_offset + angle).0.max(-MAX_FOV_KICK.0).min(MAX_FOV_KICK.0));
+	}
+
+	pub fn update(&mut self, console: &Console, timestep: f32)
+	{
+		self.scale = console.get_float("cam_shake_scale").max(0.0);
+		self.time += timestep;
+		self.trauma = (self.trauma - TRAUMA_DECAY * timestep).max(0.0);
+
+		// Critically damped spring, so impulses settle without oscillating
+		let damping = 2.0 * IMPULSE_STIFFNESS.sqrt();
+		let acceleration = -self.impulse_offset * IMPULSE_STIFFNESS - self.impulse_velocity * damping;
+		self.impulse_velocity += acceleration * timestep;
+		self.impulse_offset += self.impulse_velocity * timestep;
+		if self.impulse_offset.magnitude2() > MAX_IMPULSE_OFFSET * MAX_IMPULSE_OFFSET
+		{
+			self.impulse_offset = self.impulse_offset.normalize_to(MAX_IMPULSE_OFFSET);
+		}
+
+		self.fov_offset = self.fov_offset * (-FOV_RECOVERY * timestep).exp();
+	}
+
+	/// Returns the change to the camera's vertical field of view.
+	pub fn get_fov_offset(&self) -> Deg<f32>
+	{
+		return self.fov_offset * self.scale;
+	}
+
+	/// Applies the effects to the camera's view matrix.
+	pub fn apply(&self, view_matrix: Matrix4<f32>) -> Matrix4<f32>
+	{
+		let shake = self.trauma * self.trauma * self.scale;
+		let noise_time = self.time * SHAKE_FREQUENCY;
+		let noise = |axis: u32| perlin_noise(noise_time, self.noise_seed.wrapping_add(axis)) * shake;
+
+		// Shake happens in camera space, impulses move the camera in world space
+		let shake_rotation = Matrix4::from_angle_y(MAX_SHAKE_ANGLE * noise(0)) *
+			Matrix4::from_angle_x(MAX_SHAKE_ANGLE * noise(1)) *
+			Matrix4::from_angle_z(MAX_SHAKE_ANGLE * noise(2));
+		let shake_translation =
+			Matrix4::from_translation(Vector3::new(noise(3), noise(4), noise(5)) * MAX_SHAKE_OFFSET);
+		let impulse_translation = Matrix4::from_translation(-self.impulse_offset * self.scale);
+
+		return shake_rotation * shake_translation * view_matrix * impulse_translation;
+	}
+}
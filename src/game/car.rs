@@ -1,56 +1,176 @@
-use crate::core::{Action, Drawable, InputConsumer, Material, Mesh, Transform, Transformable};
+use crate::core::{
+	sim_sin_cos, Action, ActionEvents, AnalogChannel, BoundingBox, InputConsumer, Mesh, Transform, Transformable,
+};
+use crate::game::{ConvexHull, OrientedBox, PhysicsMaterial, RigidBody, TransformComponent};
 use bit_vec::BitVec;
 use cgmath::prelude::*;
-use cgmath::Vector3;
-use std::rc::Rc;
+use cgmath::{Matrix4, Point3, Quaternion, Vector3};
+use std::f32::consts::PI;
 
+const WHEEL_RADIUS: f32 = 0.3;
+// Half the size of the car's body, as created by the scene, in meters
+const HALF_EXTENTS: [f32; 3] = [0.9, 0.75, 2.15];
+// Distance of the wheels from the sides, and of the rear axle from the back, in meters
+const WHEEL_INSET: [f32; 2] = [0.15, 0.75];
+const IDLE_RPM: f32 = 800.0;
+const REDLINE_RPM: f32 = 7_000.0;
+// Shifts up to the next gear above this
+const SHIFT_RPM: f32 = 6_000.0;
+const GEAR_RATIOS: [f32; 5] = [3.5, 2.1, 1.4, 1.0, 0.8];
+const FINAL_DRIVE_RATIO: f32 = 3.9;
+// Named points on the car, as (right, up, forward) offsets from its center in meters
+const ATTACHMENT_POINTS: &[(&str, [f32; 3])] = &[
+	("driver_seat", [-0.4, 0.1, 0.2]),
+	// On the ground beside the driver's door, where the driver gets in and out
+	("driver_door", [-1.5, -0.75, 0.2]),
+	// The chase camera orbits this point
+	("chase_camera", [0.0, 1.5, 0.0]),
+	("hood_camera", [0.0, 0.85, 1.0]),
+	("exhaust", [0.5, -0.55, -2.2]),
+];
+
+/// The driver input of one engine tick.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct CarControls
+{
+	pub forward: bool,
+	pub back: bool,
+	pub left: bool,
+	pub right: bool,
+	/// Analog input from a controller, in [-1, 1], positive to the right and forward. Used instead of the keys when
+	/// not 0.
+	pub steering: f32,
+	pub throttle: f32,
+}
+
+/// The simulated state of a car, for restoring it later.
+#[derive(Clone)]
+pub struct CarState
+{
+	transform: Transform,
+	velocity: Vector3<f32>,
+}
+
+/// The driving state of the car entity, which drives the entity's TransformComponent, see update(). The entity is
+/// drawn with its RenderComponent like any other.
 pub struct Car
 {
 	force: Vector3<f32>,
 	velocity: Vector3<f32>,
 	mass: f32,
-	transform: Transform,
-	mesh: Rc<Mesh>,
-	material: Rc<Material>,
+	// Input since the last update, applied by it
+	controls: CarControls,
+	// Cooked from the mesh, in mesh space
+	hull: Option<ConvexHull>,
+	// Of the mesh, in mesh space
+	bounds: BoundingBox,
 }
 
 impl Car
 {
-	pub fn new(mass: f32, mesh: Rc<Mesh>, material: Rc<Material>) -> Car
+	/// Creates the driving state of a car with the given mass, colliding with the shape of the given mesh.
+	pub fn new(mass: f32, mesh: &Mesh) -> Car
 	{
 		let car = Car {
 			force: Vector3::new(0.0, 0.0, 0.0),
 			velocity: Vector3::new(0.0, 0.0, 0.0),
 			mass: mass,
-			transform: Transform::new(),
-			mesh: mesh,
-			material: material,
+			controls: CarControls::default(),
+			hull: ConvexHull::cook(mesh),
+			bounds: mesh.get_bounds(&Matrix4::identity()),
 		};
 		return car;
 	}
 
-	fn accelerate(&mut self, force: f32)
+	fn accelerate(&mut self, transform: &TransformComponent, force: f32)
 	{
-		self.force += self.get_front_vector() * force;
+		self.force += transform.get_front_vector() * force;
 	}
 
-	fn decelerate(&mut self, force: f32)
+	fn decelerate(&mut self, transform: &TransformComponent, force: f32)
 	{
-		self.accelerate(-force);
+		self.accelerate(transform, -force);
 	}
 
-	fn turn_left(&mut self, angle: f32)
+	/// Turns around the world up axis, by the given angle in degrees. Unlike yaw(), this is deterministic.
+	fn turn(transform: &mut TransformComponent, angle: f32)
 	{
-		self.yaw(angle);
+		let (sin, cos) = sim_sin_cos(0.5 * angle.to_radians());
+		transform.globally_rotate(Quaternion::new(cos, 0.0, sin, 0.0));
 	}
 
-	fn turn_right(&mut self, angle: f32)
+	fn turn_left(transform: &mut TransformComponent, angle: f32)
 	{
-		self.yaw(-angle);
+		Car::turn(transform, angle);
 	}
 
-	pub fn update(&mut self)
+	fn turn_right(transform: &mut TransformComponent, angle: f32)
 	{
+		Car::turn(transform, -angle);
+	}
+
+	/// Returns the input applied by the next update.
+	pub fn get_controls(&self) -> CarControls
+	{
+		return self.controls;
+	}
+
+	/// Replaces the input applied by the next update.
+	pub fn set_controls(&mut self, controls: CarControls)
+	{
+		self.controls = controls;
+	}
+
+	pub fn save_state(&self, transform: &TransformComponent) -> CarState
+	{
+		return CarState {
+			transform: transform.get_transform().clone(),
+			velocity: self.velocity,
+		};
+	}
+
+	pub fn restore_state(&mut self, transform: &mut TransformComponent, state: &CarState)
+	{
+		*transform.get_mutable_transform() = state.transform.clone();
+		self.velocity = state.velocity;
+		self.force = Vector3::new(0.0, 0.0, 0.0);
+	}
+
+	/// Drives the car entity, given by its transform, for one engine tick with the input since the last update.
+	pub fn update(&mut self, transform: &mut TransformComponent, timestep: f32)
+	{
+		if self.controls.throttle != 0.0
+		{
+			self.accelerate(transform, 100_000.0 * self.controls.throttle);
+		}
+		else
+		{
+			if self.controls.forward
+			{
+				self.accelerate(transform, 100_000.0);
+			}
+			if self.controls.back
+			{
+				self.decelerate(transform, 100_000.0);
+			}
+		}
+		if self.controls.steering != 0.0
+		{
+			Car::turn_right(transform, 2.0 * self.controls.steering);
+		}
+		else
+		{
+			if self.controls.left
+			{
+				Car::turn_left(transform, 2.0);
+			}
+			if self.controls.right
+			{
+				Car::turn_right(transform, 2.0);
+			}
+		}
+		self.controls = CarControls::default();
+
 		// Drag
 		let drag_coefficient = 20.0;
 		self.force -= self.velocity * self.velocity.magnitude() * drag_coefficient;
@@ -60,34 +180,102 @@ impl Car
 		// Reset force
 		self.force = Vector3::new(0.0, 0.0, 0.0);
 
-		// TODO ENGINE_TIMESTEP
-		self.velocity += acceleration * 1.0 / 60.0;
-		self.translate(self.velocity * 1.0 / 60.0);
+		self.velocity += acceleration * timestep;
+		transform.translate(self.velocity * timestep);
 	}
-}
 
-impl Transformable for Car
-{
-	fn get_transform(&self) -> &Transform
+	pub fn get_velocity(&self) -> Vector3<f32>
 	{
-		return &self.transform;
+		return self.velocity;
 	}
 
-	fn get_mutable_transform(&mut self) -> &mut Transform
+	pub fn get_mass(&self) -> f32
 	{
-		return &mut self.transform;
+		return self.mass;
 	}
-}
 
-impl Drawable for Car
-{
-	fn get_mesh(&self) -> &Mesh
+	/// Returns the convex hull of the car in world space, or None if its mesh is flat.
+	pub fn get_hull(&self, transform: &TransformComponent) -> Option<ConvexHull>
+	{
+		return self.hull.as_ref().map(|hull| hull.transformed(&transform.generate_transformation_matrix()));
+	}
+
+	/// Returns the car as a rigid body, boxed by the bounds of its mesh.
+	pub fn get_body(&self, transform: &TransformComponent, id: u32, material: PhysicsMaterial) -> RigidBody
+	{
+		return RigidBody {
+			id: id,
+			shape: OrientedBox::from_bounds(&self.bounds, &transform.generate_transformation_matrix()),
+			velocity: self.velocity,
+			mass: self.mass,
+			material: material,
+		};
+	}
+
+	/// Takes the position and velocity of the car back from its rigid body, after a RigidBodyWorld step.
+	pub fn apply_body(&mut self, transform: &mut TransformComponent, body: &RigidBody)
+	{
+		let center = Point3::from_homogeneous(
+			transform.generate_transformation_matrix() * self.bounds.get_center().to_homogeneous(),
+		);
+		transform.translate(body.shape.center - center);
+		self.velocity = body.velocity;
+	}
+
+	/// Returns the engine speed, in the lowest gear that keeps it below the shift point.
+	pub fn get_rpm(&self, transform: &TransformComponent) -> f32
+	{
+		let wheel_rpm = self.velocity.dot(transform.get_front_vector()).abs() / (2.0 * PI * WHEEL_RADIUS) * 60.0;
+		for ratio in GEAR_RATIOS.iter()
+		{
+			let rpm = wheel_rpm * ratio * FINAL_DRIVE_RATIO;
+			if rpm < SHIFT_RPM
+			{
+				return rpm.max(IDLE_RPM);
+			}
+		}
+		return (wheel_rpm * GEAR_RATIOS[GEAR_RATIOS.len() - 1] * FINAL_DRIVE_RATIO).min(REDLINE_RPM);
+	}
+
+	/// Returns how fast the tires slide sideways, in m/s. Turning does not change the direction of the velocity, so
+	/// the car slides until drag slows it down.
+	pub fn get_slip(&self, transform: &TransformComponent) -> f32
+	{
+		return self.velocity.dot(transform.get_right_vector()).abs();
+	}
+
+	/// Returns the points where the left and right rear wheels of the car with the given transform touch the ground.
+	pub fn get_rear_wheel_contacts(transform: &TransformComponent) -> [Point3<f32>; 2]
 	{
-		return &self.mesh;
+		let side = transform.get_right_vector() * (HALF_EXTENTS[0] - WHEEL_INSET[0]);
+		// The car only turns around the vertical axis
+		let center = transform.get_position() -
+			transform.get_front_vector() * (HALF_EXTENTS[2] - WHEEL_INSET[1]) -
+			Vector3::unit_y() * HALF_EXTENTS[1];
+		return [center - side, center + side];
 	}
-	fn get_material(&self) -> &Material
+
+	/// Returns the world position of the attachment point with the given name, on the car with the given transform, or
+	/// None if the car has none by that name.
+	pub fn get_attachment(transform: &TransformComponent, name: &str) -> Option<Point3<f32>>
+	{
+		let (_, offset) = ATTACHMENT_POINTS.iter().find(|(point_name, _)| *point_name == name)?;
+		// The car only turns around the vertical axis
+		return Some(
+			transform.get_position() +
+				transform.get_right_vector() * offset[0] +
+				Vector3::unit_y() * offset[1] +
+				transform.get_front_vector() * offset[2],
+		);
+	}
+
+	/// Returns the positions of the left and right tail lights, at the back corners of the car with the given
+	/// transform.
+	pub fn get_tail_lights(transform: &TransformComponent) -> [Point3<f32>; 2]
 	{
-		return &self.material;
+		let side = transform.get_right_vector() * (HALF_EXTENTS[0] - WHEEL_INSET[0]);
+		let center = transform.get_position() - transform.get_front_vector() * HALF_EXTENTS[2];
+		return [center - side, center + side];
 	}
 }
 
@@ -102,23 +290,16 @@ impl InputConsumer for Car
 		actions.set(Action::RIGHT as usize, true);
 		return actions;
 	}
-	fn consume(&mut self, actions: BitVec)
+	fn consume(&mut self, actions: &ActionEvents)
 	{
-		if actions.get(Action::FORWARD as usize).unwrap()
-		{
-			self.accelerate(100_000.0);
-		}
-		if actions.get(Action::BACK as usize).unwrap()
-		{
-			self.decelerate(100_000.0);
-		}
-		if actions.get(Action::LEFT as usize).unwrap()
-		{
-			self.turn_left(2.0);
-		}
-		if actions.get(Action::RIGHT as usize).unwrap()
-		{
-			self.turn_right(2.0);
-		}
+		// Applied by the next update, so that replays can record and replace it
+		self.controls = CarControls {
+			forward: actions.is_triggered(Action::FORWARD),
+			back: actions.is_triggered(Action::BACK),
+			left: actions.is_triggered(Action::LEFT),
+			right: actions.is_triggered(Action::RIGHT),
+			steering: actions.get_analog(AnalogChannel::STEERING),
+			throttle: actions.get_analog(AnalogChannel::THROTTLE),
+		};
 	}
 }
@@ -0,0 +1,141 @@
+use crate::core::{Action, ActionEvents, InputConsumer, Transform, Transformable};
+use bit_vec::BitVec;
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+
+const WALK_SPEED: f32 = 1.5;
+const RUN_SPEED: f32 = 5.0;
+// Height of the eyes above the feet, where the first person camera is placed
+const EYE_HEIGHT: f32 = 1.7;
+
+/// Gameplay events of the player getting in and out of the car, for scripting reactions to them.
+#[derive(Clone, Copy, PartialEq)]
+pub enum VehicleEvent
+{
+	/// The player got into the driver's seat, and now drives the car.
+	Entered,
+	/// The player got out by the driver's door, and is now on foot.
+	Exited,
+}
+
+/// The player on foot, walking on the ground with the movement keys while out of the car.
+pub struct Character
+{
+	// Movement keys held since the last update, as (forward, right) and whether sprinting
+	input: (f32, f32, bool),
+	transform: Transform,
+}
+
+impl Character
+{
+	pub fn new() -> Character
+	{
+		Character {
+			input: (0.0, 0.0, false),
+			transform: Transform::new(),
+		}
+	}
+
+	/// Walks along the ground, with forward being the horizontal part of the given view direction.
+	pub fn update(&mut self, view_direction: Vector3<f32>, timestep: f32)
+	{
+		let (forward, right, sprint) = std::mem::replace(&mut self.input, (0.0, 0.0, false));
+		let front = Vector3::new(view_direction.x, 0.0, view_direction.z);
+		if front.magnitude2() < 1e-8 || (forward == 0.0 && right == 0.0)
+		{
+			return;
+		}
+		let front = front.normalize();
+		let direction = front * forward + front.cross(Vector3::unit_y()) * right;
+		let speed = if sprint
+		{
+			RUN_SPEED
+		}
+		else
+		{
+			WALK_SPEED
+		};
+		self.translate(direction.normalize() * speed * timestep);
+	}
+
+	/// Returns where the eyes of the character are.
+	pub fn get_eye_position(&self) -> Point3<f32>
+	{
+		return self.get_position() + Vector3::unit_y() * EYE_HEIGHT;
+	}
+}
+
+impl Transformable for Character
+{
+	fn get_transform(&self) -> &Transform
+	{
+		return &self.transform;
+	}
+	fn get_mutable_transform(&mut self) -> &mut Transform
+	{
+		return &mut self.transform;
+	}
+}
+
+impl InputConsumer for Character
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		actions.set(Action::FORWARD as usize, true);
+		actions.set(Action::BACK as usize, true);
+		actions.set(Action::LEFT as usize, true);
+		actions.set(Action::RIGHT as usize, true);
+		actions.set(Action::SPRINT as usize, true);
+		return actions;
+	}
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		let held = |action: Action| actions.is_triggered(action) as u32 as f32;
+		self.input = (
+			held(Action::FORWARD) - held(Action::BACK),
+			held(Action::RIGHT) - held(Action::LEFT),
+			actions.is_triggered(Action::SPRINT),
+		);
+	}
+}
+
+/// Requests to enter or exit the car, from the interact action.
+pub struct InteractInput
+{
+	requested: bool,
+}
+
+impl InteractInput
+{
+	pub fn new() -> InteractInput
+	{
+		InteractInput {
+			requested: false,
+		}
+	}
+
+	/// Returns true if interacting was requested since the last call.
+	pub fn take_request(&mut self) -> bool
+	{
+		return std::mem::replace(&mut self.requested, false);
+	}
+}
+
+impl InputConsumer for InteractInput
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		actions.set(Action::INTERACT as usize, true);
+		return actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		if actions.is_triggered(Action::INTERACT)
+		{
+			self.requested = true;
+		}
+	}
+}
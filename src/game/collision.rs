@@ -0,0 +1,526 @@
+use crate::core::{unversioned_to_v1, BoundingBox, FileFormat, Mesh};
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector3};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+
+// Cooked colliders are cached here, keyed by a hash of the triangles they were cooked from
+const CACHE_DIR: &str = "collision_cache";
+const COLLISION_FORMAT: FileFormat = FileFormat {
+	name: "cooked collision",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+// Most triangles in a leaf of the bounding volume hierarchy
+const MAX_LEAF_TRIANGLES: usize = 4;
+// Points closer to a face than this fraction of the size of the point set are considered on it
+const HULL_EPSILON: f32 = 1e-5;
+
+/// Returns the cooked collider of the triangles from the cache, or cooks and caches it.
+fn cook_cached<T: Serialize + DeserializeOwned>(
+	kind: &str, triangles: &[[Point3<f32>; 3]], cook: impl FnOnce() -> T,
+) -> T
+{
+	let mut hasher = DefaultHasher::new();
+	for point in triangles.iter().flat_map(|triangle| triangle.iter())
+	{
+		point.x.to_bits().hash(&mut hasher);
+		point.y.to_bits().hash(&mut hasher);
+		point.z.to_bits().hash(&mut hasher);
+	}
+	let path = format!("{}/{}_{:016x}.json", CACHE_DIR, kind, hasher.finish());
+	if let Ok(cooked) = COLLISION_FORMAT.read(&path)
+	{
+		return cooked;
+	}
+
+	let cooked = cook();
+	// A failed write only costs cooking again next time
+	if let Err(e) =
+		fs::create_dir_all(CACHE_DIR).map_err(|e| e.to_string()).and_then(|_| COLLISION_FORMAT.write(&path, &cooked))
+	{
+		warning!("Could not cache {}: {}", path, e);
+	}
+	return cooked;
+}
+
+/// Returns the index of the point farthest by the given distance, and the distance.
+fn farthest(points: &[Point3<f32>], distance: impl Fn(Point3<f32>) -> f32) -> (usize, f32)
+{
+	let mut farthest = (0, std::f32::NEG_INFINITY);
+	for (idx, &point) in points.iter().enumerate()
+	{
+		let candidate = distance(point);
+		if candidate > farthest.1
+		{
+			farthest = (idx, candidate);
+		}
+	}
+	return farthest;
+}
+
+/// A convex hull around a set of points, for colliding dynamic objects by their shape rather than a sphere.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConvexHull
+{
+	vertices: Vec<[f32; 3]>,
+	// Counter-clockwise seen from outside
+	faces: Vec<[u32; 3]>,
+}
+
+/// A face of a hull under construction.
+struct HullFace
+{
+	vertices: [usize; 3],
+	normal: Vector3<f32>,
+	distance: f32,
+	// Points in front of the face, not yet inside the hull
+	outside: Vec<usize>,
+	removed: bool,
+}
+
+impl HullFace
+{
+	fn new(points: &[Point3<f32>], vertices: [usize; 3]) -> HullFace
+	{
+		let [a, b, c] = vertices;
+		let normal = (points[b] - points[a]).cross(points[c] - points[a]).normalize();
+		HullFace {
+			vertices: vertices,
+			normal: normal,
+			distance: normal.dot(points[a].to_vec()),
+			outside: Vec::new(),
+			removed: false,
+		}
+	}
+
+	fn get_height(&self, point: Point3<f32>) -> f32
+	{
+		return self.normal.dot(point.to_vec()) - self.distance;
+	}
+}
+
+impl ConvexHull
+{
+	/// Builds the hull of the points with quickhull, or returns None if they are all on a plane.
+	pub fn from_points(points: &[Point3<f32>]) -> Option<ConvexHull>
+	{
+		let bounds = BoundingBox::from_points(points.iter().cloned())?;
+		let epsilon = HULL_EPSILON * bounds.get_radius().max(1.0);
+
+		// Start from a tetrahedron of extreme points
+		let (a, _) = farthest(points, |point| (point - bounds.min).magnitude2());
+		let (b, ab) = farthest(points, |point| (point - points[a]).magnitude2());
+		if ab.sqrt() <= epsilon
+		{
+			return None;
+		}
+		let axis = (points[b] - points[a]).normalize();
+		let (c, ac) = farthest(points, |point| {
+			let offset = point - points[a];
+			(offset - axis * offset.dot(axis)).magnitude2()
+		});
+		if ac.sqrt() <= epsilon
+		{
+			return None;
+		}
+		let plane = HullFace::new(points, [a, b, c]);
+		let (d, height) = farthest(points, |point| plane.get_height(point).abs());
+		if height <= epsilon
+		{
+			return None;
+		}
+		let mut faces: Vec<HullFace> = if plane.get_height(points[d]) < 0.0
+		{
+			vec![[a, b, c], [a, d, b], [b, d, c], [c, d, a]]
+		}
+		else
+		{
+			vec![[a, c, b], [a, b, d], [b, c, d], [c, a, d]]
+		}
+		.into_iter()
+		.map(|vertices| HullFace::new(points, vertices))
+		.collect();
+		ConvexHull::assign_outside(points, &mut faces, 0, (0..points.len()).collect(), epsilon);
+
+		// Grow the hull towards the farthest outside point of a face, until no points are outside
+		while let Some(face_idx) = faces.iter().position(|face| !face.removed && !face.outside.is_empty())
+		{
+			let face = &faces[face_idx];
+			let eye = *face
+				.outside
+				.iter()
+				.max_by(|&&i, &&j| face.get_height(points[i]).partial_cmp(&face.get_height(points[j])).unwrap())
+				.unwrap();
+
+			// The faces seen from the eye are replaced by a cone from their outline to the eye
+			let mut edges = HashSet::new();
+			let mut orphans = Vec::new();
+			for face in faces.iter_mut().filter(|face| !face.removed && face.get_height(points[eye]) > epsilon)
+			{
+				let [i, j, k] = face.vertices;
+				edges.extend([(i, j), (j, k), (k, i)].iter().cloned());
+				orphans.append(&mut face.outside);
+				face.removed = true;
+			}
+			let first_new = faces.len();
+			for &(i, j) in edges.iter().filter(|&&(i, j)| !edges.contains(&(j, i)))
+			{
+				faces.push(HullFace::new(points, [i, j, eye]));
+			}
+			orphans.retain(|&idx| idx != eye);
+			ConvexHull::assign_outside(points, &mut faces, first_new, orphans, epsilon);
+		}
+
+		// Keep only the points used by the faces
+		let mut remap = vec![None; points.len()];
+		let mut vertices = Vec::new();
+		let mut hull_faces = Vec::new();
+		for face in faces.iter().filter(|face| !face.removed)
+		{
+			let mut hull_face = [0; 3];
+			for (corner, &idx) in hull_face.iter_mut().zip(face.vertices.iter())
+			{
+				*corner = *remap[idx].get_or_insert_with(|| {
+					vertices.push(points[idx].into());
+					(vertices.len() - 1) as u32
+				});
+			}
+			hull_faces.push(hull_face);
+		}
+		return Some(ConvexHull {
+			vertices: vertices,
+			faces: hull_faces,
+		});
+	}
+
+	/// Moves each point to the outside set of the first face from first_face on it is in front of. Points behind all
+	/// of them are inside the hull, and dropped.
+	fn assign_outside(
+		points: &[Point3<f32>], faces: &mut [HullFace], first_face: usize, candidates: Vec<usize>, epsilon: f32,
+	)
+	{
+		for idx in candidates
+		{
+			if let Some(face) =
+				faces[first_face..].iter_mut().find(|face| !face.removed && face.get_height(points[idx]) > epsilon)
+			{
+				face.outside.push(idx);
+			}
+		}
+	}
+
+	/// Returns the hull of the mesh's vertices, or None if the mesh is flat. Cooked hulls are cached on disk.
+	pub fn cook(mesh: &Mesh) -> Option<ConvexHull>
+	{
+		let triangles = mesh.get_triangles(&Matrix4::identity());
+		// Flat meshes are cached as hulls without faces
+		let hull = cook_cached("hull", &triangles, || {
+			let points: Vec<Point3<f32>> = triangles.iter().flat_map(|triangle| triangle.iter().cloned()).collect();
+			ConvexHull::from_points(&points).unwrap_or(ConvexHull {
+				vertices: Vec::new(),
+				faces: Vec::new(),
+			})
+		});
+		if hull.faces.is_empty()
+		{
+			return None;
+		}
+		return Some(hull);
+	}
+
+	/// Returns the hull transformed by the given model matrix, which must not skew or mirror it.
+	pub fn transformed(&self, model_matrix: &Matrix4<f32>) -> ConvexHull
+	{
+		ConvexHull {
+			vertices: self
+				.vertices
+				.iter()
+				.map(|&vertex| model_matrix.transform_point(Point3::from(vertex)).into())
+				.collect(),
+			faces: self.faces.clone(),
+		}
+	}
+
+	/// Returns the radius of the smallest sphere around the center containing the hull.
+	pub fn get_radius(&self, center: Point3<f32>) -> f32
+	{
+		return self.vertices.iter().map(|&vertex| (Point3::from(vertex) - center).magnitude()).fold(0.0, f32::max);
+	}
+
+	/// Returns the signed distance from the point to the surface of the hull, negative inside, and the outward normal
+	/// of the face it was measured to.
+	///
+	/// Outside, the distance is to the farthest face plane. Near edges and corners that is less than the distance to
+	/// the hull, so spheres there touch it a little early.
+	pub fn get_distance(&self, point: Point3<f32>) -> (f32, Vector3<f32>)
+	{
+		let mut closest = (std::f32::NEG_INFINITY, Vector3::unit_y());
+		for face in &self.faces
+		{
+			let a = Point3::from(self.vertices[face[0] as usize]);
+			let b = Point3::from(self.vertices[face[1] as usize]);
+			let c = Point3::from(self.vertices[face[2] as usize]);
+			let normal = (b - a).cross(c - a).normalize();
+			let distance = normal.dot(point - a);
+			if distance > closest.0
+			{
+				closest = (distance, normal);
+			}
+		}
+		return closest;
+	}
+}
+
+/// A node of a bounding volume hierarchy over triangles.
+#[derive(Serialize, Deserialize)]
+struct BvhNode
+{
+	min: [f32; 3],
+	max: [f32; 3],
+	// Leaves hold count triangles from first, other nodes have their two children at first and first + 1
+	first: u32,
+	count: u32,
+}
+
+/// Triangles in a bounding volume hierarchy, for colliding with static geometry by its render meshes.
+#[derive(Serialize, Deserialize)]
+pub struct TriangleMesh
+{
+	triangles: Vec<[[f32; 3]; 3]>,
+	// The root first, empty without triangles
+	nodes: Vec<BvhNode>,
+}
+
+impl TriangleMesh
+{
+	/// Builds the hierarchy over the triangles, splitting each node at the median along its longest axis.
+	pub fn new(triangles: &[[Point3<f32>; 3]]) -> TriangleMesh
+	{
+		let mut mesh = TriangleMesh {
+			triangles: Vec::with_capacity(triangles.len()),
+			nodes: Vec::new(),
+		};
+		if triangles.is_empty()
+		{
+			return mesh;
+		}
+		let centroids: Vec<Point3<f32>> = triangles.iter().map(|triangle| Point3::centroid(triangle)).collect();
+		let mut order: Vec<usize> = (0..triangles.len()).collect();
+		mesh.nodes.push(BvhNode {
+			min: [0.0; 3],
+			max: [0.0; 3],
+			first: 0,
+			count: 0,
+		});
+		mesh.split(triangles, &centroids, &mut order, 0, 0);
+		mesh.triangles = order
+			.iter()
+			.map(|&idx| [triangles[idx][0].into(), triangles[idx][1].into(), triangles[idx][2].into()])
+			.collect();
+		return mesh;
+	}
+
+	/// Fills in the node over the given triangles, splitting it until the leaves are small enough.
+	fn split(
+		&mut self, triangles: &[[Point3<f32>; 3]], centroids: &[Point3<f32>], order: &mut [usize], offset: usize,
+		node_idx: usize,
+	)
+	{
+		let bounds = BoundingBox::from_points(order.iter().flat_map(|&idx| triangles[idx].iter().cloned())).unwrap();
+		self.nodes[node_idx].min = bounds.min.into();
+		self.nodes[node_idx].max = bounds.max.into();
+		if order.len() <= MAX_LEAF_TRIANGLES
+		{
+			self.nodes[node_idx].first = offset as u32;
+			self.nodes[node_idx].count = order.len() as u32;
+			return;
+		}
+
+		let centroid_bounds = BoundingBox::from_points(order.iter().map(|&idx| centroids[idx])).unwrap();
+		let extents = centroid_bounds.get_extents();
+		let axis = if extents.x >= extents.y && extents.x >= extents.z
+		{
+			0
+		}
+		else if extents.y >= extents.z
+		{
+			1
+		}
+		else
+		{
+			2
+		};
+		order.sort_by(|&i, &j| centroids[i][axis].partial_cmp(&centroids[j][axis]).unwrap());
+		let middle = order.len() / 2;
+		let children = self.nodes.len();
+		self.nodes[node_idx].first = children as u32;
+		for _ in 0..2
+		{
+			self.nodes.push(BvhNode {
+				min: [0.0; 3],
+				max: [0.0; 3],
+				first: 0,
+				count: 0,
+			});
+		}
+		let (left, right) = order.split_at_mut(middle);
+		self.split(triangles, centroids, left, offset, children);
+		self.split(triangles, centroids, right, offset + middle, children + 1);
+	}
+
+	/// Returns the mesh of the triangles, in the same space as them. Cooked meshes are cached on disk.
+	pub fn cook(triangles: &[[Point3<f32>; 3]]) -> TriangleMesh
+	{
+		return cook_cached("mesh", triangles, || TriangleMesh::new(triangles));
+	}
+
+	/// Returns the distance along the ray to where a sphere moving along it first touches a triangle, 0 if it starts
+	/// touching, or None if it misses. The direction must be normalized.
+	pub fn cast_sphere(&self, origin: Point3<f32>, direction: Vector3<f32>, radius: f32) -> Option<f32>
+	{
+		let mut closest: Option<f32> = None;
+		let mut stack = Vec::new();
+		if !self.nodes.is_empty()
+		{
+			stack.push(0);
+		}
+		while let Some(node_idx) = stack.pop()
+		{
+			let node = &self.nodes[node_idx];
+			let bounds = BoundingBox {
+				min: Point3::from(node.min),
+				max: Point3::from(node.max),
+			};
+			match bounds.cast_sphere(origin, direction, radius)
+			{
+				Some(distance) if closest.map_or(true, |closest| distance < closest) => (),
+				_ => continue,
+			}
+			if node.count == 0
+			{
+				stack.push(node.first as usize);
+				stack.push(node.first as usize + 1);
+				continue;
+			}
+			let first = node.first as usize;
+			for triangle in &self.triangles[first..first + node.count as usize]
+			{
+				let triangle = [Point3::from(triangle[0]), Point3::from(triangle[1]), Point3::from(triangle[2])];
+				if let Some(distance) = cast_sphere_triangle(origin, direction, radius, &triangle)
+				{
+					closest = Some(closest.map_or(distance, |closest| closest.min(distance)));
+				}
+			}
+		}
+		return closest;
+	}
+}
+
+/// Returns true if the point, on the plane of the triangle, is within it. The normal is that of the triangle's
+/// winding.
+fn triangle_contains(triangle: &[Point3<f32>; 3], normal: Vector3<f32>, point: Point3<f32>) -> bool
+{
+	return (0..3).all(|idx| {
+		let (a, b) = (triangle[idx], triangle[(idx + 1) % 3]);
+		(b - a).cross(point - a).dot(normal) >= 0.0
+	});
+}
+
+/// Returns the distance along the ray to where the sphere first touches the triangle, from either side, 0 if it starts
+/// touching, or None if it misses. The direction must be normalized.
+fn cast_sphere_triangle(
+	origin: Point3<f32>, direction: Vector3<f32>, radius: f32, triangle: &[Point3<f32>; 3],
+) -> Option<f32>
+{
+	let mut closest: Option<f32> = None;
+	let mut hit = |distance: f32| closest = Some(closest.map_or(distance, |closest: f32| closest.min(distance)));
+
+	// The face, unless the triangle is degenerate
+	let normal = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+	if normal.magnitude2() > 0.0
+	{
+		let normal = normal.normalize();
+		let height = normal.dot(origin - triangle[0]);
+		// Towards the side of the sphere
+		let facing = if height < 0.0
+		{
+			-normal
+		}
+		else
+		{
+			normal
+		};
+		let height = height.abs();
+		let speed = facing.dot(direction);
+		if height <= radius
+		{
+			if triangle_contains(triangle, normal, origin - facing * height)
+			{
+				return Some(0.0);
+			}
+		}
+		else if speed < 0.0
+		{
+			let distance = (height - radius) / -speed;
+			if triangle_contains(triangle, normal, origin + direction * distance - facing * radius)
+			{
+				hit(distance);
+			}
+		}
+	}
+
+	for idx in 0..3
+	{
+		// The corner
+		let offset = origin - triangle[idx];
+		let b = offset.dot(direction);
+		let c = offset.magnitude2() - radius * radius;
+		if c <= 0.0
+		{
+			return Some(0.0);
+		}
+		if b < 0.0 && b * b >= c
+		{
+			hit(-b - (b * b - c).sqrt());
+		}
+
+		// The edge to the next corner, as a cylinder
+		let edge = triangle[(idx + 1) % 3] - triangle[idx];
+		let length2 = edge.magnitude2();
+		if length2 == 0.0
+		{
+			continue;
+		}
+		let across = direction - edge * (direction.dot(edge) / length2);
+		let offset_across = offset - edge * (offset.dot(edge) / length2);
+		let a = across.magnitude2();
+		let b = offset_across.dot(across);
+		let c = offset_across.magnitude2() - radius * radius;
+		if c <= 0.0
+		{
+			let along = offset.dot(edge) / length2;
+			if along >= 0.0 && along <= 1.0
+			{
+				return Some(0.0);
+			}
+			continue;
+		}
+		if a == 0.0 || b >= 0.0 || b * b < a * c
+		{
+			continue;
+		}
+		let distance = (-b - (b * b - a * c).sqrt()) / a;
+		let along = (offset + direction * distance).dot(edge) / length2;
+		if along >= 0.0 && along <= 1.0
+		{
+			hit(distance);
+		}
+	}
+	return closest;
+}
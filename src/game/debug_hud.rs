@@ -0,0 +1,98 @@
+use crate::core::{Action, ActionEvents, InputConsumer};
+use crate::game::Scene;
+use crate::renderer::Overlay;
+use bit_vec::BitVec;
+
+// Top left corner of the text, below the FPS counter
+const POSITION: (f32, f32) = (8.0, 28.0);
+const COLOR: [f32; 4] = [0.8, 1.0, 0.8, 1.0];
+// Frame times are summarized over this many seconds
+const STATS_PERIOD: f32 = 1.0;
+
+/// Shows frame times, entity counts and the camera position below the FPS counter, in the bitmap debug font. Toggled
+/// with TOGGLE_DEBUG_HUD, F4 by default.
+pub struct DebugHud
+{
+	visible: bool,
+	toggle_requested: bool,
+	// Frame times of the current period, in seconds
+	frame_times: Vec<f32>,
+	// Average, shortest and longest frame time of the last period, in milliseconds
+	frame_stats: (f32, f32, f32),
+}
+
+impl DebugHud
+{
+	pub fn new() -> DebugHud
+	{
+		DebugHud {
+			visible: false,
+			toggle_requested: false,
+			frame_times: Vec::new(),
+			frame_stats: (0.0, 0.0, 0.0),
+		}
+	}
+
+	/// Returns true if the HUD is about to be toggled.
+	pub fn is_animating(&self) -> bool
+	{
+		return self.toggle_requested;
+	}
+
+	/// Adds the time of a rendered frame, in seconds, to the frame time statistics.
+	pub fn record_frame(&mut self, frame_time: f32)
+	{
+		self.frame_times.push(frame_time);
+		let total: f32 = self.frame_times.iter().sum();
+		if total < STATS_PERIOD
+		{
+			return;
+		}
+		let shortest = self.frame_times.iter().cloned().fold(std::f32::INFINITY, f32::min);
+		let longest = self.frame_times.iter().cloned().fold(0.0, f32::max);
+		self.frame_stats = (total / self.frame_times.len() as f32 * 1000.0, shortest * 1000.0, longest * 1000.0);
+		self.frame_times.clear();
+	}
+
+	/// Queues the HUD on the overlay, if shown.
+	pub fn draw(&mut self, overlay: &mut Overlay, scene: &Scene)
+	{
+		if self.toggle_requested
+		{
+			self.toggle_requested = false;
+			self.visible = !self.visible;
+		}
+		if !self.visible
+		{
+			return;
+		}
+
+		let (average, shortest, longest) = self.frame_stats;
+		let mut text = format!("Frame {:.2} ms, {:.2}-{:.2}\n", average, shortest, longest);
+		for (name, count) in scene.get_entity_counts()
+		{
+			text += &format!("{} {}\n", name, count);
+		}
+		let position = scene.get_camera_position();
+		text += &format!("Camera {:.1} {:.1} {:.1}", position.x, position.y, position.z);
+		overlay.draw_debug_text(&text, POSITION, COLOR);
+	}
+}
+
+impl InputConsumer for DebugHud
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		handled_actions.set(Action::TOGGLE_DEBUG_HUD as usize, true);
+		return handled_actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		if actions.is_triggered(Action::TOGGLE_DEBUG_HUD)
+		{
+			self.toggle_requested = true;
+		}
+	}
+}
@@ -0,0 +1,90 @@
+use crate::core::Console;
+
+// Rate at which the focus follows the auto-focus target, per second
+const FOCUS_PULL_SPEED: f32 = 4.0;
+const MIN_FOCUS_DISTANCE: f32 = 0.5;
+
+/// Focus settings for the depth of field post effect.
+///
+/// The focus distance is either set from the console, or follows the object selected in the material editor when
+/// auto-focus is enabled, easing towards it like a focus pull.
+pub struct DepthOfField
+{
+	enabled: bool,
+	focus_distance: f32,
+	aperture: f32,
+	autofocus: bool,
+	debug: bool,
+}
+
+impl DepthOfField
+{
+	pub fn new(console: &mut Console) -> DepthOfField
+	{
+		console.register_bool("dof", false, "Blur the image outside the focus distance");
+		console.register_float("dof_focus_distance", 20.0, "Distance to the plane in focus, in meters");
+		console.register_float(
+			"dof_aperture",
+			0.01,
+			"Blur radius of distant objects, as a fraction of the screen height",
+		);
+		console.register_bool("dof_autofocus", true, "Focus on the object selected in the material editor");
+		console.register_bool(
+			"dof_debug",
+			false,
+			"Show the circle of confusion, red in front of the focus, blue behind",
+		);
+
+		DepthOfField {
+			enabled: false,
+			focus_distance: 20.0,
+			aperture: 0.0,
+			autofocus: false,
+			debug: false,
+		}
+	}
+
+	pub fn is_autofocus(&self) -> bool
+	{
+		return self.enabled && self.autofocus;
+	}
+
+	/// Updates the settings from the console. The focus eases towards the auto-focus target, given as a distance
+	/// along the view direction, if auto-focus is enabled and there is one.
+	pub fn update(&mut self, console: &Console, autofocus_target: Option<f32>, timestep: f32)
+	{
+		self.enabled = console.get_bool("dof");
+		self.aperture = console.get_float("dof_aperture").max(0.0);
+		self.autofocus = console.get_bool("dof_autofocus");
+		self.debug = console.get_bool("dof_debug");
+
+		let target = match autofocus_target
+		{
+			Some(target) if self.autofocus => target,
+			_ => console.get_float("dof_focus_distance"),
+		};
+		let target = target.max(MIN_FOCUS_DISTANCE);
+		let blend = 1.0 - (-FOCUS_PULL_SPEED * timestep).exp();
+		self.focus_distance += (target - self.focus_distance) * blend;
+	}
+
+	pub fn get_focus_distance(&self) -> f32
+	{
+		return self.focus_distance;
+	}
+
+	/// Returns the aperture, which is 0 when depth of field is disabled.
+	pub fn get_aperture(&self) -> f32
+	{
+		if !self.enabled
+		{
+			return 0.0;
+		}
+		return self.aperture;
+	}
+
+	pub fn is_debug(&self) -> bool
+	{
+		return self.enabled && self.debug;
+	}
+}
@@ -0,0 +1,413 @@
+use crate::core::{BoundingBox, Drawable, Material, Mesh, MorphWeights, Transform, Transformable};
+use crate::game::{OrientedBox, PhysicsMaterial, RigidBody};
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Quaternion, Rad, Vector3};
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+/// Identifies an entity of a World. The index of a despawned entity is reused with the next generation, so components
+/// are never found through stale entities.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Entity
+{
+	index: u32,
+	generation: u32,
+}
+
+/// Where an entity is, and how it is rotated and scaled.
+#[derive(Clone)]
+pub struct TransformComponent
+{
+	transform: Transform,
+}
+
+impl TransformComponent
+{
+	pub fn new() -> TransformComponent
+	{
+		TransformComponent {
+			transform: Transform::new(),
+		}
+	}
+}
+
+impl Transformable for TransformComponent
+{
+	fn get_transform(&self) -> &Transform
+	{
+		return &self.transform;
+	}
+	fn get_mutable_transform(&mut self) -> &mut Transform
+	{
+		return &mut self.transform;
+	}
+}
+
+/// How an entity is drawn, with its TransformComponent as the model matrix.
+pub struct RenderComponent
+{
+	pub mesh: Rc<Mesh>,
+	pub material: Rc<Material>,
+	/// Weights of the morph targets of the mesh, if it is morphed, see MorphComponent.
+	pub morph_weights: Option<MorphWeights>,
+}
+
+impl RenderComponent
+{
+	pub fn new(mesh: Rc<Mesh>, material: Rc<Material>) -> RenderComponent
+	{
+		RenderComponent {
+			mesh: mesh,
+			material: material,
+			morph_weights: None,
+		}
+	}
+}
+
+impl Drawable for RenderComponent
+{
+	fn get_mesh(&self) -> &Mesh
+	{
+		return &self.mesh;
+	}
+	fn get_material(&self) -> &Material
+	{
+		return &self.material;
+	}
+	fn get_morph_weights(&self) -> Option<&MorphWeights>
+	{
+		return self.morph_weights.as_ref();
+	}
+}
+
+/// How an entity moves by itself, integrated by World::update_physics().
+#[derive(Clone, Copy)]
+pub struct PhysicsComponent
+{
+	/// In meters per second.
+	pub velocity: Vector3<f32>,
+	/// The axis of rotation, around the world axes, scaled by the speed in radians per second.
+	pub angular_velocity: Vector3<f32>,
+	/// Factor the scale changes by per second.
+	pub growth: f32,
+}
+
+impl Default for PhysicsComponent
+{
+	fn default() -> PhysicsComponent
+	{
+		PhysicsComponent {
+			velocity: Vector3::zero(),
+			angular_velocity: Vector3::zero(),
+			growth: 1.0,
+		}
+	}
+}
+
+/// A box around the entity's mesh that rigid bodies collide with. The entity is not moved by collisions, see
+/// World::get_rigid_bodies().
+pub struct ColliderComponent
+{
+	// In mesh space
+	bounds: BoundingBox,
+	pub material: PhysicsMaterial,
+}
+
+impl ColliderComponent
+{
+	pub fn new(mesh: &Mesh, material: PhysicsMaterial) -> ColliderComponent
+	{
+		ColliderComponent {
+			bounds: mesh.get_bounds(&Matrix4::identity()),
+			material: material,
+		}
+	}
+}
+
+/// Blends the morph targets of the entity's mesh in and out in turn, set by World::update_morphs(). Needs morph weights
+/// in the RenderComponent.
+pub struct MorphComponent
+{
+	/// Seconds for a full cycle through the targets.
+	pub period: f32,
+	time: f32,
+}
+
+impl MorphComponent
+{
+	pub fn new(period: f32) -> MorphComponent
+	{
+		MorphComponent {
+			period: period.max(0.1),
+			time: 0.0,
+		}
+	}
+}
+
+/// Marks an entity as part of the static geometry of the scene, which is cooked into its collision and navmesh and
+/// drawn in static batches or instances rather than one by one.
+pub struct StaticComponent;
+
+/// The components of one kind, for the entities that have one.
+pub struct Components<T>
+{
+	// By entity index, with the generation of the entity the component belongs to
+	slots: Vec<Option<(u32, T)>>,
+}
+
+impl<T> Components<T>
+{
+	fn new() -> Components<T>
+	{
+		Components {
+			slots: Vec::new(),
+		}
+	}
+
+	/// Adds the component to the entity, replacing the one it had.
+	pub fn insert(&mut self, entity: Entity, component: T)
+	{
+		let index = entity.index as usize;
+		if index >= self.slots.len()
+		{
+			self.slots.resize_with(index + 1, || None);
+		}
+		self.slots[index] = Some((entity.generation, component));
+	}
+
+	pub fn remove(&mut self, entity: Entity) -> Option<T>
+	{
+		match self.slots.get_mut(entity.index as usize)
+		{
+			Some(slot) if slot.as_ref().map_or(false, |(generation, _)| *generation == entity.generation) =>
+			{
+				return slot.take().map(|(_, component)| component);
+			}
+			_ => return None,
+		}
+	}
+
+	pub fn get(&self, entity: Entity) -> Option<&T>
+	{
+		match self.slots.get(entity.index as usize)
+		{
+			Some(Some((generation, component))) if *generation == entity.generation => return Some(component),
+			_ => return None,
+		}
+	}
+
+	pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T>
+	{
+		match self.slots.get_mut(entity.index as usize)
+		{
+			Some(Some((generation, component))) if *generation == entity.generation => return Some(component),
+			_ => return None,
+		}
+	}
+
+	pub fn contains(&self, entity: Entity) -> bool
+	{
+		return self.get(entity).is_some();
+	}
+
+	/// Returns the components with their entities, in the order of the entities' indices.
+	pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)>
+	{
+		return self.slots.iter().enumerate().filter_map(|(index, slot)| {
+			slot.as_ref().map(|(generation, component)| {
+				let entity = Entity {
+					index: index as u32,
+					generation: *generation,
+				};
+				(entity, component)
+			})
+		});
+	}
+
+	/// Like iter(), with the components mutable.
+	pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)>
+	{
+		return self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+			slot.as_mut().map(|(generation, component)| {
+				let entity = Entity {
+					index: index as u32,
+					generation: *generation,
+				};
+				(entity, component)
+			})
+		});
+	}
+}
+
+/// The objects of a scene as entities made of components, so new kinds of objects are a new mix of components rather
+/// than a new struct. Systems, like update_physics(), go through the entities having the components they work on.
+///
+/// Every entity has a name, shown in the material editor. Entities with a TransformComponent and a RenderComponent are
+/// drawn, see get_drawables().
+pub struct World
+{
+	// Current generation of each entity index
+	generations: Vec<u32>,
+	// Indices of despawned entities, reused first
+	free_indices: Vec<u32>,
+	pub names: Components<String>,
+	pub transforms: Components<TransformComponent>,
+	pub renders: Components<RenderComponent>,
+	pub physics: Components<PhysicsComponent>,
+	pub morphs: Components<MorphComponent>,
+	pub colliders: Components<ColliderComponent>,
+	pub statics: Components<StaticComponent>,
+}
+
+impl World
+{
+	pub fn new() -> World
+	{
+		World {
+			generations: Vec::new(),
+			free_indices: Vec::new(),
+			names: Components::new(),
+			transforms: Components::new(),
+			renders: Components::new(),
+			physics: Components::new(),
+			morphs: Components::new(),
+			colliders: Components::new(),
+			statics: Components::new(),
+		}
+	}
+
+	/// Creates an entity with the given name, and no other components.
+	pub fn spawn(&mut self, name: &str) -> Entity
+	{
+		let entity = match self.free_indices.pop()
+		{
+			Some(index) => Entity {
+				index: index,
+				generation: self.generations[index as usize],
+			},
+			None =>
+			{
+				self.generations.push(0);
+				Entity {
+					index: self.generations.len() as u32 - 1,
+					generation: 0,
+				}
+			}
+		};
+		self.names.insert(entity, name.to_string());
+		return entity;
+	}
+
+	/// Creates a drawn entity, see spawn().
+	pub fn spawn_drawable(&mut self, name: &str, transform: TransformComponent, render: RenderComponent) -> Entity
+	{
+		let entity = self.spawn(name);
+		self.transforms.insert(entity, transform);
+		self.renders.insert(entity, render);
+		return entity;
+	}
+
+	/// Removes the entity with all of its components. Returns false if it was already despawned.
+	pub fn despawn(&mut self, entity: Entity) -> bool
+	{
+		if self.names.remove(entity).is_none()
+		{
+			return false;
+		}
+		self.transforms.remove(entity);
+		self.renders.remove(entity);
+		self.physics.remove(entity);
+		self.morphs.remove(entity);
+		self.colliders.remove(entity);
+		self.statics.remove(entity);
+		self.generations[entity.index as usize] += 1;
+		self.free_indices.push(entity.index);
+		return true;
+	}
+
+	/// Returns the transform and render components of a drawn entity.
+	pub fn get_drawable(&self, entity: Entity) -> Option<(&TransformComponent, &RenderComponent)>
+	{
+		return Some((self.transforms.get(entity)?, self.renders.get(entity)?));
+	}
+
+	/// Returns the drawn entities, static or not, in the order of their indices.
+	pub fn get_drawables(&self, statics: bool)
+		-> impl Iterator<Item = (Entity, &TransformComponent, &RenderComponent)>
+	{
+		return self.renders.iter().filter(move |(entity, _)| self.statics.contains(*entity) == statics).filter_map(
+			move |(entity, render)| self.transforms.get(entity).map(|transform| (entity, transform, render)),
+		);
+	}
+
+	/// Moves, rotates and scales the entities with a PhysicsComponent.
+	pub fn update_physics(&mut self, timestep: f32)
+	{
+		for (entity, physics) in self.physics.iter()
+		{
+			let transform = match self.transforms.get_mut(entity)
+			{
+				Some(transform) => transform,
+				None => continue,
+			};
+			transform.translate(physics.velocity * timestep);
+			let speed = physics.angular_velocity.magnitude();
+			if speed > 0.0
+			{
+				let axis = physics.angular_velocity / speed;
+				transform.globally_rotate(Quaternion::from_axis_angle(axis, Rad(speed * timestep)));
+			}
+			if physics.growth != 1.0
+			{
+				transform.scale(physics.growth.powf(timestep));
+			}
+		}
+	}
+
+	/// Returns the entities with a ColliderComponent as rigid bodies of infinite mass, placed by their transforms and
+	/// moving by their PhysicsComponent. The ids start at first_id, offset by the entity index.
+	pub fn get_rigid_bodies(&self, first_id: u32) -> Vec<RigidBody>
+	{
+		return self
+			.colliders
+			.iter()
+			.filter_map(|(entity, collider)| {
+				let transform = self.transforms.get(entity)?;
+				Some(RigidBody {
+					id: first_id + entity.index,
+					shape: OrientedBox::from_bounds(&collider.bounds, &transform.generate_transformation_matrix()),
+					velocity: self.physics.get(entity).map_or(Vector3::zero(), |physics| physics.velocity),
+					mass: std::f32::INFINITY,
+					material: collider.material,
+				})
+			})
+			.collect();
+	}
+
+	/// Advances the morph target blends of the entities with a MorphComponent.
+	pub fn update_morphs(&mut self, timestep: f32)
+	{
+		for (entity, morph) in self.morphs.iter_mut()
+		{
+			let render = match self.renders.get_mut(entity)
+			{
+				Some(render) => render,
+				None => continue,
+			};
+			morph.time += timestep;
+			let count = render.mesh.get_morph_target_names().len();
+			let phase = 2.0 * PI * morph.time / morph.period;
+			let weights: Vec<f32> = (0..count)
+				.map(|idx| {
+					// Staggered, so the targets blend into each other
+					let offset = 2.0 * PI * idx as f32 / count as f32;
+					0.5 - 0.5 * (phase - offset).cos()
+				})
+				.collect();
+			if let Some(morph_weights) = &mut render.morph_weights
+			{
+				morph_weights.set_weights(&weights);
+			}
+		}
+	}
+}
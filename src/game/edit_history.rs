@@ -0,0 +1,261 @@
+use crate::core::{Action, ActionEvents, InputConsumer, Material, MaterialDesc, UndoGroup, UndoStack};
+use crate::game::{Light, LightId, TexturePainter};
+use crate::renderer::RenderState;
+use bit_vec::BitVec;
+use image::RgbaImage;
+use std::rc::Weak;
+
+// Painted canvases are kept whole, so this bounds the memory used by strokes
+const MAX_UNDO_GROUPS: usize = 32;
+
+/// A change made with one of the editors. Holds what it replaced, which applying it swaps back in, see UndoStack.
+/// Materials are held weakly, so edits do not keep materials alive that the scene has let go of.
+pub enum Edit
+{
+	/// Tint, specular, emissive or textures changed in the material editor.
+	Material
+	{
+		material: Weak<Material>,
+		desc: MaterialDesc,
+	},
+	/// A stroke painted into the color texture of a material.
+	Paint
+	{
+		material: Weak<Material>,
+		texture_path: String,
+		image: RgbaImage,
+	},
+	/// A light added to or removed from the scene at runtime, None while it is not in the scene.
+	Light
+	{
+		id: LightId,
+		light: Option<Light>,
+	},
+}
+
+impl Edit
+{
+	fn describe(&self) -> String
+	{
+		return match self
+		{
+			Edit::Material {
+				..
+			} => "material change".to_string(),
+			Edit::Paint {
+				..
+			} => "paint stroke".to_string(),
+			Edit::Light {
+				id,
+				..
+			} => format!("light {}", id.0),
+		};
+	}
+
+	/// Whether applying the edit still changes anything, which it does not once its material has been freed, see
+	/// Scene::collect_garbage().
+	fn is_live(&self) -> bool
+	{
+		return match self
+		{
+			Edit::Material {
+				material,
+				..
+			} |
+			Edit::Paint {
+				material,
+				..
+			} => material.strong_count() > 0,
+			Edit::Light {
+				..
+			} => true,
+		};
+	}
+
+	/// Swaps the state held by the edit with the current one. Edits of materials that have been freed do nothing.
+	fn apply(&mut self, rs: &RenderState, painter: &mut TexturePainter, lights: &mut Vec<(LightId, Light)>)
+	{
+		match self
+		{
+			Edit::Material {
+				material,
+				desc,
+			} =>
+			{
+				let material = match material.upgrade()
+				{
+					Some(material) => material,
+					None => return,
+				};
+				let current = material.get_desc();
+				material.set_params(desc);
+				if desc.texture != current.texture
+				{
+					material.set_texture(rs, &desc.texture, false);
+				}
+				if desc.normal_map != current.normal_map
+				{
+					material.set_texture(rs, &desc.normal_map, true);
+				}
+				*desc = current;
+			}
+			Edit::Paint {
+				material,
+				texture_path,
+				image,
+			} =>
+			{
+				if let Some(material) = material.upgrade()
+				{
+					painter.swap_canvas(rs, &material, texture_path, image);
+				}
+			}
+			Edit::Light {
+				id,
+				light,
+			} =>
+			{
+				let current = lights.iter().position(|(light_id, _)| light_id == id).map(|idx| lights.remove(idx).1);
+				if let Some(restored) = light.take()
+				{
+					// Keep the lights in the order they were added
+					let idx = lights.iter().position(|(light_id, _)| light_id.0 > id.0).unwrap_or(lights.len());
+					lights.insert(idx, (*id, restored));
+				}
+				*light = current;
+			}
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HistoryCommand
+{
+	Undo,
+	Redo,
+}
+
+/// Undo and redo of the changes made with the material editor, the texture painter and the runtime light commands,
+/// shared so they are undone in the order they were made. Object placement, roads and props only change through the
+/// scene file, so they have no edits here.
+///
+/// Ctrl+Z undoes and Ctrl+Y or Ctrl+Shift+Z redoes, while the material editor or texture painting is open. The
+/// undo and redo console commands work at any time.
+pub struct EditHistory
+{
+	stack: UndoStack<Edit>,
+	pending_commands: Vec<HistoryCommand>,
+	// Whether an editor was open at the last update, for the shortcuts
+	editing: bool,
+}
+
+impl EditHistory
+{
+	pub fn new() -> EditHistory
+	{
+		EditHistory {
+			stack: UndoStack::new(MAX_UNDO_GROUPS),
+			pending_commands: Vec::new(),
+			editing: false,
+		}
+	}
+
+	/// Records an edit that has been made, see UndoStack::push().
+	pub fn push(&mut self, edit: Edit)
+	{
+		self.stack.push(edit);
+	}
+
+	/// Records an edit that has been made, undone along with the previous edits of the group, see
+	/// UndoStack::push_grouped().
+	pub fn push_grouped(&mut self, edit: Edit, group: UndoGroup)
+	{
+		self.stack.push_grouped(edit, group);
+	}
+
+	pub fn new_group(&mut self) -> UndoGroup
+	{
+		return self.stack.new_group();
+	}
+
+	/// Queues undoing the last edit, applied with the next update().
+	pub fn undo(&mut self)
+	{
+		self.pending_commands.push(HistoryCommand::Undo);
+	}
+
+	/// Queues redoing the last undone edit, applied with the next update().
+	pub fn redo(&mut self)
+	{
+		self.pending_commands.push(HistoryCommand::Redo);
+	}
+
+	/// Applies the queued undos and redos to the edited materials, the painted canvases and the runtime lights of the
+	/// scene. Edits of materials that have been freed are dropped first.
+	pub fn update(
+		&mut self, rs: &RenderState, painter: &mut TexturePainter, lights: &mut Vec<(LightId, Light)>, editing: bool,
+	)
+	{
+		self.editing = editing;
+		if !self.pending_commands.is_empty()
+		{
+			self.stack.retain(Edit::is_live);
+		}
+		for command in std::mem::take(&mut self.pending_commands)
+		{
+			let mut applied = Vec::new();
+			let mut apply = |edit: &mut Edit| {
+				edit.apply(rs, painter, lights);
+				applied.push(edit.describe());
+			};
+			let (done, verb) = match command
+			{
+				HistoryCommand::Undo => (self.stack.undo(&mut apply), "Undid"),
+				HistoryCommand::Redo => (self.stack.redo(&mut apply), "Redid"),
+			};
+			applied.dedup();
+			let (undo_count, redo_count) = self.stack.get_counts();
+			if done
+			{
+				println!("{} {} ({} to undo, {} to redo)", verb, applied.join(", "), undo_count, redo_count);
+			}
+			else if command == HistoryCommand::Undo
+			{
+				println!("Nothing to undo");
+			}
+			else
+			{
+				println!("Nothing to redo");
+			}
+		}
+	}
+}
+
+impl InputConsumer for EditHistory
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+
+		handled_actions.set(Action::EDIT_UNDO as usize, true);
+		handled_actions.set(Action::EDIT_REDO as usize, true);
+
+		return handled_actions;
+	}
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		// The shortcuts are only for the editors, the keys might do something else while playing
+		if !self.editing
+		{
+			return;
+		}
+		if actions.is_triggered(Action::EDIT_UNDO)
+		{
+			self.undo();
+		}
+		if actions.is_triggered(Action::EDIT_REDO)
+		{
+			self.redo();
+		}
+	}
+}
@@ -0,0 +1,193 @@
+use serde_json::{json, Value};
+use std::f32::consts::PI;
+
+// Console lines applied before those of every gallery scene, so each starts from the same state
+const BASELINE: &[&str] =
+	&["time_of_day 14", "time_of_day_speed 0", "night_lights auto", "ai_debug 0", "light_trails 0"];
+
+/// A small built-in demo scene, for checking a subsystem without any scene files.
+pub struct GalleryScene
+{
+	pub name: &'static str,
+	pub description: &'static str,
+	/// Console lines setting up the cvars and modes the scene is best viewed with.
+	pub console_lines: Vec<&'static str>,
+	/// The scene, in the format of the scene files.
+	pub settings: Value,
+}
+
+impl GalleryScene
+{
+	fn new(
+		name: &'static str, description: &'static str, console_lines: &[&'static str], settings: Value,
+	) -> GalleryScene
+	{
+		GalleryScene {
+			name: name,
+			description: description,
+			console_lines: BASELINE.iter().chain(console_lines.iter()).cloned().collect(),
+			settings: settings,
+		}
+	}
+}
+
+/// Returns the gallery scene with the given name, if any.
+pub fn find_gallery_scene(name: &str) -> Option<GalleryScene>
+{
+	return get_gallery_scenes().into_iter().find(|scene| scene.name == name);
+}
+
+pub fn get_gallery_scenes() -> Vec<GalleryScene>
+{
+	return vec![lighting_scene(), physics_scene(), spline_scene(), particle_scene(), morph_scene()];
+}
+
+/// Returns points on a circle around the origin on the ground.
+fn circle(radius: f32, count: usize, height: f32) -> Vec<[f32; 3]>
+{
+	return (0..count)
+		.map(|idx| {
+			let angle = 2.0 * PI * idx as f32 / count as f32;
+			[radius * angle.cos(), height, radius * angle.sin()]
+		})
+		.collect();
+}
+
+/// Colored point lights with shadows around a reflection probe, at night.
+fn lighting_scene() -> GalleryScene
+{
+	let colors = [[1.0, 0.3, 0.2], [1.0, 0.8, 0.3], [0.4, 1.0, 0.4], [0.3, 0.8, 1.0], [0.4, 0.4, 1.0], [1.0, 0.4, 1.0]];
+	let lights: Vec<Value> = circle(8.0, colors.len(), 3.0)
+		.iter()
+		.zip(colors.iter())
+		.map(|(position, color)| {
+			json!({
+				"position": position,
+				"radius": 15.0,
+				"color": color,
+				"shadow_resolution": 256
+			})
+		})
+		.collect();
+
+	return GalleryScene::new(
+		"lighting",
+		"Colored shadowed point lights around a reflection probe at night",
+		&["time_of_day 23", "night_lights on"],
+		json!({
+			"lights": lights,
+			"reflection_probes": [
+				{ "position": [0.0, 2.0, 0.0], "extents": [12.0, 6.0, 12.0] }
+			]
+		}),
+	);
+}
+
+/// A crowd of agents wandering and fleeing the car, to push around.
+fn physics_scene() -> GalleryScene
+{
+	let mut agents: Vec<Value> = Vec::new();
+	for x in -2..3
+	{
+		for z in -2..3
+		{
+			agents.push(json!({
+				"position": [4.0 * x as f32, 0.0, 4.0 * z as f32 - 12.0],
+				"behavior": "wander",
+				"max_speed": 2.0
+			}));
+		}
+	}
+	for position in circle(6.0, 4, 0.0)
+	{
+		agents.push(json!({
+			"position": position,
+			"behavior": { "flee": { "target": [0.0, 0.0, 0.0] } },
+			"max_speed": 4.0
+		}));
+	}
+
+	return GalleryScene::new(
+		"physics",
+		"Agents to bump into with the car and the camera, with their steering drawn",
+		&["ai_debug 1", "cam_mode follow"],
+		json!({ "agents": agents }),
+	);
+}
+
+/// A banked road loop along a spline, raced on by agents.
+fn spline_scene() -> GalleryScene
+{
+	let mut points: Vec<Value> =
+		circle(30.0, 8, 0.02).iter().map(|position| json!({ "position": position, "roll": 6.0 })).collect();
+	// Back to the start, closing the loop
+	points.push(points[0].clone());
+	let checkpoints: Vec<Value> =
+		circle(28.0, 4, 0.0).iter().map(|position| json!({ "position": position, "radius": 6.0 })).collect();
+	let agents: Vec<Value> = [6.0, 7.0, 8.0]
+		.iter()
+		.enumerate()
+		.map(|(idx, max_speed)| {
+			json!({
+				"position": [28.0, 0.0, 3.0 * idx as f32],
+				"behavior": "race",
+				"max_speed": max_speed
+			})
+		})
+		.collect();
+
+	return GalleryScene::new(
+		"spline",
+		"A banked road loop extruded along a spline, with AI racers following its checkpoints",
+		&["cam_mode follow"],
+		json!({
+			"roads": [{ "points": points, "material": "metal_panel" }],
+			"race": { "checkpoints": checkpoints, "laps": 2 },
+			"agents": agents
+		}),
+	);
+}
+
+/// Rain, wet surfaces and trails in the evening.
+fn particle_scene() -> GalleryScene
+{
+	return GalleryScene::new(
+		"particles",
+		"Heavy rain with screen droplets, and skidmarks and light trails behind the car",
+		&["time_of_day 20", "night_lights on", "light_trails 1", "skidmarks 1", "cam_mode follow"],
+		json!({
+			"weather": {
+				"rain_intensity": 1.0,
+				"wetting_rate": 0.5,
+				"screen_droplets": true
+			},
+			"lights": [
+				{ "position": [0.0, 6.0, 0.0], "radius": 40.0, "color": [1.0, 0.8, 0.6], "shadow_resolution": 512 }
+			]
+		}),
+	);
+}
+
+/// Boxes blending between squashed, twisted and tapered shapes, at different paces.
+fn morph_scene() -> GalleryScene
+{
+	let materials = ["cube", "metal_panel"];
+	let morph_objects: Vec<Value> = circle(5.0, 6, 1.0)
+		.iter()
+		.enumerate()
+		.map(|(idx, position)| {
+			json!({
+				"material": materials[idx % materials.len()],
+				"position": position,
+				"period": 2.0 + idx as f32
+			})
+		})
+		.collect();
+
+	return GalleryScene::new(
+		"morph",
+		"Boxes deformed by blending their morph targets in the vertex shader",
+		&[],
+		json!({ "morph_objects": morph_objects }),
+	);
+}
@@ -0,0 +1,401 @@
+use crate::core::{Action, ActionEvents, Console, InputConsumer, InputContext};
+use crate::game::{format_time, Standing};
+use crate::renderer::{Overlay, TextStyle};
+use bit_vec::BitVec;
+
+const TITLE_SIZE: f32 = 32.0;
+const TEXT_SIZE: f32 = 18.0;
+const LINE_SPACING: f32 = 28.0;
+// Left edge of the screens, from the center of the UI
+const SCREEN_OFFSET: f32 = -140.0;
+
+const TITLE_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+const ITEM_COLOR: [f32; 4] = [0.8, 0.8, 0.8, 1.0];
+const SELECTED_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+// Change of a setting per press of Left or Right
+const SETTING_STEP: f32 = 0.1;
+
+/// Where the player is in the flow of the game.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GameState
+{
+	/// The main menu, over the frozen scene.
+	Menu,
+	/// Setting the scene up for playing, with a loading screen.
+	Loading,
+	Playing,
+	/// The pause menu, over the frozen scene.
+	Paused,
+	/// The player finished a race. The other racers keep going behind the results.
+	Results,
+	/// The options screen, from the main or the pause menu.
+	Options,
+}
+
+/// What moves the game from one state to another. Events that do not apply to the current state are ignored.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GameEvent
+{
+	/// Play the scene freely, from the main menu.
+	FreeRoam,
+	/// Race through the checkpoints of the scene, from the main menu.
+	StartRace,
+	/// The scene is set up.
+	Loaded,
+	Pause,
+	Resume,
+	/// The player crossed the finish line.
+	RaceFinished,
+	QuitToMenu,
+	/// Opens the options screen, from the main or the pause menu.
+	OpenOptions,
+	/// Goes back from the options screen to the menu it was opened from.
+	CloseOptions,
+	/// Ends the game, from any state.
+	Quit,
+}
+
+impl GameState
+{
+	/// Returns the state the event leads to, if any.
+	fn get_next(self, event: GameEvent) -> Option<GameState>
+	{
+		match (self, event)
+		{
+			(GameState::Menu, GameEvent::FreeRoam) | (GameState::Menu, GameEvent::StartRace) =>
+			{
+				Some(GameState::Loading)
+			}
+			(GameState::Loading, GameEvent::Loaded) => Some(GameState::Playing),
+			(GameState::Playing, GameEvent::Pause) => Some(GameState::Paused),
+			(GameState::Paused, GameEvent::Resume) => Some(GameState::Playing),
+			(GameState::Playing, GameEvent::RaceFinished) => Some(GameState::Results),
+			(GameState::Paused, GameEvent::QuitToMenu) | (GameState::Results, GameEvent::QuitToMenu) =>
+			{
+				Some(GameState::Menu)
+			}
+			(GameState::Menu, GameEvent::OpenOptions) | (GameState::Paused, GameEvent::OpenOptions) =>
+			{
+				Some(GameState::Options)
+			}
+			// Where the options screen goes back to is up to GameStateMachine
+			_ => None,
+		}
+	}
+
+	/// Returns the items of the state's screen.
+	fn get_items(self) -> &'static [MenuItem]
+	{
+		match self
+		{
+			GameState::Menu => &[
+				MenuItem::Event("Free roam", GameEvent::FreeRoam),
+				MenuItem::Event("Race", GameEvent::StartRace),
+				MenuItem::Event("Options", GameEvent::OpenOptions),
+				MenuItem::Event("Quit", GameEvent::Quit),
+			],
+			GameState::Paused => &[
+				MenuItem::Event("Resume", GameEvent::Resume),
+				MenuItem::Event("Options", GameEvent::OpenOptions),
+				MenuItem::Event("Quit to menu", GameEvent::QuitToMenu),
+			],
+			GameState::Results => &[MenuItem::Event("Continue", GameEvent::QuitToMenu)],
+			GameState::Options => &[
+				MenuItem::Setting("Master volume", "volume_master"),
+				MenuItem::Setting("Music volume", "volume_music"),
+				MenuItem::Setting("Effects volume", "volume_sfx"),
+				MenuItem::Setting("Interface volume", "volume_ui"),
+				MenuItem::Event("Back", GameEvent::CloseOptions),
+			],
+			GameState::Loading | GameState::Playing => &[],
+		}
+	}
+
+	/// Returns the event of going back from the state, with Escape.
+	fn get_back_event(self) -> Option<GameEvent>
+	{
+		match self
+		{
+			GameState::Menu => Some(GameEvent::Quit),
+			GameState::Playing => Some(GameEvent::Pause),
+			GameState::Paused => Some(GameEvent::Resume),
+			GameState::Results => Some(GameEvent::QuitToMenu),
+			GameState::Options => Some(GameEvent::CloseOptions),
+			GameState::Loading => None,
+		}
+	}
+}
+
+/// An item of a menu screen.
+enum MenuItem
+{
+	/// Sends the event when selected.
+	Event(&'static str, GameEvent),
+	/// A float cvar in the range [0, 1], changed with Left and Right and shown as a percentage.
+	Setting(&'static str, &'static str),
+}
+
+/// The flow of the game through its states, see GameState. Each state decides whether the scene updates, which
+/// controls are active and which screen is shown.
+///
+/// Events are queued, by the menu controls or by the game, and applied by poll() once per frame, which returns the
+/// states entered so the game can react to them.
+pub struct GameStateMachine
+{
+	state: GameState,
+	events: Vec<GameEvent>,
+	running: bool,
+	// Whether the last game started from the menu is a race
+	race: bool,
+	// Item of the current screen
+	selected: usize,
+	// The menu the options screen was opened from
+	options_parent: GameState,
+	// Changes of settings by the menu, applied by apply_settings()
+	setting_steps: Vec<(&'static str, f32)>,
+}
+
+impl GameStateMachine
+{
+	pub fn new(state: GameState) -> GameStateMachine
+	{
+		GameStateMachine {
+			state: state,
+			events: Vec::new(),
+			running: true,
+			race: false,
+			selected: 0,
+			options_parent: GameState::Menu,
+			setting_steps: Vec::new(),
+		}
+	}
+
+	pub fn get_state(&self) -> GameState
+	{
+		return self.state;
+	}
+
+	/// Returns false once the game should end.
+	pub fn is_running(&self) -> bool
+	{
+		return self.running;
+	}
+
+	/// Returns true if the game was started from the menu as a race, rather than free roam.
+	pub fn is_race(&self) -> bool
+	{
+		return self.race;
+	}
+
+	/// Queues an event, applied by the next poll().
+	pub fn send(&mut self, event: GameEvent)
+	{
+		self.events.push(event);
+	}
+
+	/// Applies the queued events, returning the states entered in order.
+	pub fn poll(&mut self) -> Vec<GameState>
+	{
+		let mut entered = Vec::new();
+		for event in std::mem::take(&mut self.events)
+		{
+			if event == GameEvent::Quit
+			{
+				self.running = false;
+				continue;
+			}
+			let next = match (self.state, event)
+			{
+				(GameState::Options, GameEvent::CloseOptions) => self.options_parent,
+				_ => match self.state.get_next(event)
+				{
+					Some(next) => next,
+					None => continue,
+				},
+			};
+			match event
+			{
+				GameEvent::FreeRoam => self.race = false,
+				GameEvent::StartRace => self.race = true,
+				GameEvent::OpenOptions => self.options_parent = self.state,
+				_ => (),
+			}
+			self.state = next;
+			self.selected = 0;
+			entered.push(next);
+		}
+		return entered;
+	}
+
+	/// Applies the changes made to settings on the options screen to their cvars.
+	pub fn apply_settings(&mut self, console: &mut Console)
+	{
+		for (cvar, step) in self.setting_steps.drain(..)
+		{
+			let value = (console.get_float(cvar) + step).max(0.0).min(1.0);
+			// Rounded, so steps from values set in the console land on the same values as the others
+			console.set_value(cvar, (value / SETTING_STEP).round() * SETTING_STEP);
+		}
+	}
+
+	/// Returns true if the scene and game time advance in the current state.
+	pub fn updates_scene(&self) -> bool
+	{
+		return self.state == GameState::Playing || self.state == GameState::Results;
+	}
+
+	/// Returns the controls of the current state. While playing, they depend on whether the player is in the car.
+	pub fn get_input_context(&self, in_vehicle: bool) -> InputContext
+	{
+		match self.state
+		{
+			GameState::Playing if in_vehicle => InputContext::Vehicle,
+			GameState::Playing => InputContext::OnFoot,
+			_ => InputContext::Menu,
+		}
+	}
+
+	/// Returns true if the mouse steers the camera in the current state, rather than pointing at the screen.
+	pub fn captures_cursor(&self) -> bool
+	{
+		return self.state == GameState::Playing;
+	}
+
+	/// Queues the screen of the current state on the overlay, centered in a UI of the given size. The results screen
+	/// shows the given standings, the options screen the settings from the console.
+	pub fn draw(&self, overlay: &mut Overlay, ui_size: (f32, f32), standings: &[Standing], console: &Console)
+	{
+		let style = TextStyle {
+			outline_width: 1.5,
+			shadow_color: [0.0, 0.0, 0.0, 0.6],
+			shadow_offset: [2.0, 2.0],
+			..Default::default()
+		};
+		let title_style = TextStyle {
+			color: TITLE_COLOR,
+			..style
+		};
+		let x = 0.5 * ui_size.0 + SCREEN_OFFSET;
+		let mut y = 0.35 * ui_size.1;
+
+		let title = match self.state
+		{
+			GameState::Menu => "Project Peril",
+			GameState::Loading => "Loading...",
+			GameState::Paused => "Paused",
+			GameState::Results => "Race finished",
+			GameState::Options => "Options",
+			GameState::Playing => return,
+		};
+		overlay.draw_text(title, (x, y), TITLE_SIZE, &title_style);
+		y += TITLE_SIZE + LINE_SPACING;
+
+		if self.state == GameState::Results
+		{
+			if let Some(place) = standings.iter().position(|standing| standing.is_player)
+			{
+				let player = &standings[place];
+				let time = player.finish_time.map_or("-".to_string(), format_time);
+				let best_lap = player.best_lap.map_or("-".to_string(), format_time);
+				let lines = [
+					format!("Place {} of {}", place + 1, standings.len()),
+					format!("Time {}  best lap {}", time, best_lap),
+				];
+				for line in lines.iter()
+				{
+					overlay.draw_text(line, (x, y), TEXT_SIZE, &style);
+					y += LINE_SPACING;
+				}
+				y += LINE_SPACING;
+			}
+		}
+
+		for (idx, item) in self.state.get_items().iter().enumerate()
+		{
+			let name = match item
+			{
+				MenuItem::Event(name, _) => name.to_string(),
+				MenuItem::Setting(name, cvar) =>
+				{
+					format!("{}  < {}% >", name, (console.get_float(cvar) * 100.0).round() as i32)
+				}
+			};
+			let (text, color) = if idx == self.selected
+			{
+				(format!("> {}", name), SELECTED_COLOR)
+			}
+			else
+			{
+				(format!("  {}", name), ITEM_COLOR)
+			};
+			let item_style = TextStyle {
+				color: color,
+				..style
+			};
+			overlay.draw_text(&text, (x, y), TEXT_SIZE, &item_style);
+			y += LINE_SPACING;
+		}
+	}
+}
+
+impl InputConsumer for GameStateMachine
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		handled_actions.set(Action::TERMINATE as usize, true);
+		handled_actions.set(Action::MENU_UP as usize, true);
+		handled_actions.set(Action::MENU_DOWN as usize, true);
+		handled_actions.set(Action::MENU_LEFT as usize, true);
+		handled_actions.set(Action::MENU_RIGHT as usize, true);
+		handled_actions.set(Action::MENU_SELECT as usize, true);
+		return handled_actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		// Escape goes back, or quits from the main menu
+		if actions.is_triggered(Action::TERMINATE)
+		{
+			if let Some(event) = self.state.get_back_event()
+			{
+				self.send(event);
+			}
+		}
+
+		let items = self.state.get_items();
+		if items.is_empty()
+		{
+			return;
+		}
+		if actions.is_triggered(Action::MENU_UP)
+		{
+			self.selected = (self.selected + items.len() - 1) % items.len();
+		}
+		if actions.is_triggered(Action::MENU_DOWN)
+		{
+			self.selected = (self.selected + 1) % items.len();
+		}
+		match items[self.selected]
+		{
+			MenuItem::Event(_, event) =>
+			{
+				if actions.is_triggered(Action::MENU_SELECT)
+				{
+					self.send(event);
+				}
+			}
+			MenuItem::Setting(_, cvar) =>
+			{
+				if actions.is_triggered(Action::MENU_LEFT)
+				{
+					self.setting_steps.push((cvar, -SETTING_STEP));
+				}
+				if actions.is_triggered(Action::MENU_RIGHT)
+				{
+					self.setting_steps.push((cvar, SETTING_STEP));
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,109 @@
+use crate::renderer::{DirectionalLight, PointLight};
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+
+/// Where a light shines from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightKind
+{
+	/// Shines in all directions from a position, fading out at the radius, in meters. Casts shadows with a non-zero
+	/// shadow resolution, see PointLight.
+	Point
+	{
+		position: Point3<f32>,
+		radius: f32,
+		shadow_resolution: u32,
+	},
+	/// Shines on everything from a direction, like the sun. Casts no shadows.
+	Directional
+	{
+		/// Towards the light.
+		direction: Vector3<f32>,
+	},
+}
+
+/// A light of the scene, from the scene file or added at runtime, see Scene::add_light().
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Light
+{
+	pub kind: LightKind,
+	pub color: [f32; 3],
+	/// Scales the color.
+	pub intensity: f32,
+}
+
+/// Identifies a light added with Scene::add_light().
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LightId(pub u32);
+
+impl Light
+{
+	/// Creates a point light without shadows.
+	pub fn point(position: Point3<f32>, radius: f32, color: [f32; 3]) -> Light
+	{
+		return Light {
+			kind: LightKind::Point {
+				position: position,
+				radius: radius,
+				shadow_resolution: 0,
+			},
+			color: color,
+			intensity: 1.0,
+		};
+	}
+
+	pub fn directional(direction: Vector3<f32>, color: [f32; 3]) -> Light
+	{
+		return Light {
+			kind: LightKind::Directional {
+				direction: direction,
+			},
+			color: color,
+			intensity: 1.0,
+		};
+	}
+
+	/// Returns the color scaled by the intensity.
+	pub fn get_radiance(&self) -> [f32; 3]
+	{
+		return [self.color[0] * self.intensity, self.color[1] * self.intensity, self.color[2] * self.intensity];
+	}
+
+	/// Returns the light as seen by the renderer, if it is a point light.
+	pub fn to_point_light(&self) -> Option<PointLight>
+	{
+		return match self.kind
+		{
+			LightKind::Point {
+				position,
+				radius,
+				shadow_resolution,
+			} => Some(PointLight {
+				position: position,
+				radius: radius,
+				color: self.get_radiance(),
+				shadow_resolution: shadow_resolution,
+			}),
+			LightKind::Directional {
+				..
+			} => None,
+		};
+	}
+
+	/// Returns the light as seen by the renderer, if it is a directional light.
+	pub fn to_directional_light(&self) -> Option<DirectionalLight>
+	{
+		return match self.kind
+		{
+			LightKind::Directional {
+				direction,
+			} => Some(DirectionalLight {
+				direction: direction.normalize(),
+				color: self.get_radiance(),
+			}),
+			LightKind::Point {
+				..
+			} => None,
+		};
+	}
+}
@@ -0,0 +1,171 @@
+use crate::core::{Action, ActionEvents, InputConsumer, LogEntry, LogLevel, Logger};
+use crate::renderer::{Overlay, TextStyle};
+use bit_vec::BitVec;
+use std::time::Instant;
+
+// Recent warnings and errors are shown for this many seconds, fading out over the last one
+const TOAST_DURATION: f32 = 6.0;
+const MAX_TOASTS: usize = 4;
+// Lines shown by the log view
+const LOG_VIEW_LINES: usize = 20;
+// Longer messages are cut off
+const MAX_COLUMNS: usize = 110;
+
+// Distance of the bottom left corner of the text from the bottom left corner of the UI
+const MARGIN: f32 = 8.0;
+const TEXT_SIZE: f32 = 12.0;
+const LINE_SPACING: f32 = 16.0;
+
+const TITLE_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+const WARNING_COLOR: [f32; 4] = [1.0, 0.85, 0.3, 1.0];
+const ERROR_COLOR: [f32; 4] = [1.0, 0.35, 0.3, 1.0];
+
+/// Shows the warnings and errors of the Logger on the HUD, so problems are seen without a terminal.
+///
+/// New messages pop up in the bottom left corner for a few seconds. F3 toggles a log view of all kept messages, which
+/// the mouse wheel scrolls.
+pub struct LogOverlay
+{
+	visible: bool,
+	// Lines scrolled up from the newest message
+	scroll: usize,
+	toggle_requested: bool,
+}
+
+impl LogOverlay
+{
+	pub fn new() -> LogOverlay
+	{
+		LogOverlay {
+			visible: false,
+			scroll: 0,
+			toggle_requested: false,
+		}
+	}
+
+	/// Scrolls the log view, up for positive steps. Returns false if it is hidden, so the scrolling is left to others.
+	pub fn scroll(&mut self, steps: i32) -> bool
+	{
+		if !self.visible
+		{
+			return false;
+		}
+		// Clamped to the messages when drawn
+		self.scroll = (self.scroll as i32 + steps).max(0) as usize;
+		return true;
+	}
+
+	/// Returns true while messages are fading out, or the log view is about to be toggled.
+	pub fn is_animating(&self) -> bool
+	{
+		if self.toggle_requested
+		{
+			return true;
+		}
+		return match Logger::get_entries().last()
+		{
+			Some(entry) => entry.time.elapsed().as_secs_f32() < TOAST_DURATION,
+			None => false,
+		};
+	}
+
+	fn format_entry(entry: &LogEntry, now: Instant) -> String
+	{
+		let age = now.duration_since(entry.time).as_secs();
+		let prefix = match entry.level
+		{
+			LogLevel::Warning => "Warning",
+			LogLevel::Error => "Error",
+		};
+		// The overlay draws one line per line of text
+		let message = entry.message.lines().next().unwrap_or("");
+		let mut text = format!("{}s ago {}: {}", age, prefix, message);
+		if text.chars().count() > MAX_COLUMNS
+		{
+			text = text.chars().take(MAX_COLUMNS - 3).collect::<String>() + "...";
+		}
+		return text;
+	}
+
+	fn get_style(level: LogLevel, alpha: f32) -> TextStyle
+	{
+		let color = match level
+		{
+			LogLevel::Warning => WARNING_COLOR,
+			LogLevel::Error => ERROR_COLOR,
+		};
+		return TextStyle {
+			color: [color[0], color[1], color[2], color[3] * alpha],
+			outline_width: 1.5,
+			outline_color: [0.0, 0.0, 0.0, alpha],
+			shadow_color: [0.0, 0.0, 0.0, 0.6 * alpha],
+			shadow_offset: [2.0, 2.0],
+		};
+	}
+
+	/// Queues the recent messages, or the log view, on the overlay. The UI is ui_height units high.
+	pub fn draw(&mut self, overlay: &mut Overlay, ui_height: f32)
+	{
+		if self.toggle_requested
+		{
+			self.toggle_requested = false;
+			self.visible = !self.visible;
+			self.scroll = 0;
+		}
+
+		let entries = Logger::get_entries();
+		let now = Instant::now();
+		// Bottom up, newest first
+		let mut y = ui_height - MARGIN - LINE_SPACING;
+		if self.visible
+		{
+			self.scroll = self.scroll.min(entries.len().saturating_sub(LOG_VIEW_LINES));
+			for entry in entries.iter().rev().skip(self.scroll).take(LOG_VIEW_LINES)
+			{
+				let text = LogOverlay::format_entry(entry, now);
+				overlay.draw_text(&text, (MARGIN, y), TEXT_SIZE, &LogOverlay::get_style(entry.level, 1.0));
+				y -= LINE_SPACING;
+			}
+			let title = format!(
+				"Log: {}-{} of {} messages, scroll for more, F3 to close",
+				(self.scroll + 1).min(entries.len()),
+				(self.scroll + LOG_VIEW_LINES).min(entries.len()),
+				entries.len()
+			);
+			let title_style = TextStyle {
+				color: TITLE_COLOR,
+				..LogOverlay::get_style(LogLevel::Warning, 1.0)
+			};
+			overlay.draw_text(&title, (MARGIN, y), TEXT_SIZE, &title_style);
+			return;
+		}
+
+		let recent =
+			entries.iter().rev().take_while(|entry| now.duration_since(entry.time).as_secs_f32() < TOAST_DURATION);
+		for entry in recent.take(MAX_TOASTS)
+		{
+			let remaining = TOAST_DURATION - now.duration_since(entry.time).as_secs_f32();
+			let text = LogOverlay::format_entry(entry, now);
+			overlay.draw_text(&text, (MARGIN, y), TEXT_SIZE, &LogOverlay::get_style(entry.level, remaining.min(1.0)));
+			y -= LINE_SPACING;
+		}
+	}
+}
+
+impl InputConsumer for LogOverlay
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		handled_actions.set(Action::LOG_VIEW_TOGGLE as usize, true);
+		return handled_actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		if actions.is_triggered(Action::LOG_VIEW_TOGGLE)
+		{
+			self.toggle_requested = true;
+		}
+	}
+}
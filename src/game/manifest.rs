@@ -0,0 +1,147 @@
+use crate::core::{unversioned_to_v1, FileFormat, Material};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+const MANIFEST_FORMAT: FileFormat = FileFormat {
+	name: "asset manifest",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+
+/// The meshes and materials a scene needs, preloaded while the loading screen is shown. Written by the
+/// asset_manifest command from the assets loaded at the time.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssetManifest
+{
+	/// Paths of OBJ files.
+	pub meshes: Vec<String>,
+	/// Names of materials, see AssetCache::get_material().
+	pub materials: Vec<String>,
+}
+
+impl AssetManifest
+{
+	pub fn read(path: &str) -> Result<AssetManifest, String>
+	{
+		return MANIFEST_FORMAT.read(path);
+	}
+
+	pub fn write(&self, path: &str) -> Result<(), String>
+	{
+		return MANIFEST_FORMAT.write(path, self);
+	}
+}
+
+/// An asset file, as a node of the dependency graph.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum AssetRef
+{
+	Mesh(String),
+	/// Path of the material file.
+	Material(String),
+	/// Textures also own their sampler, so there are no separate sampler assets.
+	Texture(String),
+	Shader(String),
+}
+
+impl AssetRef
+{
+	fn get_path(&self) -> &str
+	{
+		match self
+		{
+			AssetRef::Mesh(path) | AssetRef::Material(path) | AssetRef::Texture(path) | AssetRef::Shader(path) => path,
+		}
+	}
+}
+
+impl fmt::Display for AssetRef
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		let kind = match self
+		{
+			AssetRef::Mesh(_) => "mesh",
+			AssetRef::Material(_) => "material",
+			AssetRef::Texture(_) => "texture",
+			AssetRef::Shader(_) => "shader",
+		};
+		return write!(f, "{} {}", kind, self.get_path());
+	}
+}
+
+/// Which asset files reference which, found by reading the files. Meshes have no dependencies, materials depend on
+/// their textures and shaders.
+pub struct AssetGraph
+{
+	dependencies: HashMap<AssetRef, Vec<AssetRef>>,
+}
+
+impl AssetGraph
+{
+	pub fn new() -> AssetGraph
+	{
+		AssetGraph {
+			dependencies: HashMap::new(),
+		}
+	}
+
+	/// Adds the asset and everything it depends on. Material files that cannot be read add no dependencies.
+	pub fn add(&mut self, asset: AssetRef)
+	{
+		if self.dependencies.contains_key(&asset)
+		{
+			return;
+		}
+		let mut dependencies = Vec::new();
+		if let AssetRef::Material(path) = &asset
+		{
+			if let Ok(desc) = Material::read_asset_desc(path)
+			{
+				for texture in [desc.texture, desc.normal_map].iter().filter(|texture| !texture.is_empty())
+				{
+					dependencies.push(AssetRef::Texture(texture.clone()));
+				}
+				dependencies.push(AssetRef::Shader(desc.vertex_shader));
+				dependencies.push(AssetRef::Shader(desc.fragment_shader));
+			}
+		}
+		for dependency in &dependencies
+		{
+			self.add(dependency.clone());
+		}
+		self.dependencies.insert(asset, dependencies);
+	}
+
+	/// Returns true if the files of the asset and everything it depends on exist.
+	pub fn is_available(&self, asset: &AssetRef) -> bool
+	{
+		return Path::new(asset.get_path()).is_file() &&
+			self.dependencies
+				.get(asset)
+				.map_or(true, |dependencies| dependencies.iter().all(|dependency| self.is_available(dependency)));
+	}
+
+	/// Returns the assets whose files are missing, along with an asset referencing each, if any.
+	pub fn find_missing(&self) -> Vec<(AssetRef, Option<AssetRef>)>
+	{
+		let mut missing: Vec<(AssetRef, Option<AssetRef>)> = self
+			.dependencies
+			.keys()
+			.filter(|asset| !Path::new(asset.get_path()).is_file())
+			.map(|asset| {
+				let user = self
+					.dependencies
+					.iter()
+					.find(|(_, dependencies)| dependencies.contains(asset))
+					.map(|(user, _)| user.clone());
+				(asset.clone(), user)
+			})
+			.collect();
+		missing.sort_by(|a, b| a.0.get_path().cmp(b.0.get_path()));
+		return missing;
+	}
+}
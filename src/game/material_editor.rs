@@ -0,0 +1,459 @@
+use crate::core::{Action, ActionEvents, InputConsumer, Material, Time, TimeDomain, Timer, UndoGroup};
+use crate::game::{Edit, EditHistory};
+use crate::renderer::{Icon, Overlay, RenderState, TextStyle};
+use bit_vec::BitVec;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+const TEXTURE_DIR: &str = "assets";
+const SLIDER_STEP: f32 = 0.05;
+const SLIDER_WIDTH: usize = 20;
+// Held changes take one more step at a time after this many repeats, so large ranges are quick to cover
+const CHANGE_ACCELERATION_REPEATS: u32 = 10;
+// How long save results are shown, in real time seconds
+const STATUS_DURATION: f32 = 3.0;
+
+const PANEL_POSITION: (f32, f32) = (8.0, 40.0);
+const TEXT_SIZE: f32 = 14.0;
+const LINE_SPACING: f32 = 20.0;
+// Space for the selection marker in front of the fields
+const FIELD_INDENT: f32 = 20.0;
+// Clicks this far to the right of the panel position are on the panel
+const PANEL_WIDTH: f32 = 420.0;
+// Title, object, file, the fields, key help and status
+const PANEL_LINES: usize = 3 + FIELDS.len() + 2;
+
+const TITLE_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+const SELECTED_COLOR: [f32; 4] = [0.3, 0.9, 1.0, 1.0];
+const ERROR_COLOR: [f32; 4] = [0.9, 0.2, 0.2, 1.0];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field
+{
+	Tint(usize),
+	Specular,
+	Emissive(usize),
+	Texture,
+	NormalMap,
+}
+
+const FIELDS: [Field; 9] = [
+	Field::Tint(0),
+	Field::Tint(1),
+	Field::Tint(2),
+	Field::Specular,
+	Field::Emissive(0),
+	Field::Emissive(1),
+	Field::Emissive(2),
+	Field::Texture,
+	Field::NormalMap,
+];
+
+impl Field
+{
+	fn get_name(&self) -> &'static str
+	{
+		match *self
+		{
+			Field::Tint(channel) => ["Tint R", "Tint G", "Tint B"][channel],
+			Field::Specular => "Specular",
+			Field::Emissive(channel) => ["Emissive R", "Emissive G", "Emissive B"][channel],
+			Field::Texture => "Texture",
+			Field::NormalMap => "Normal map",
+		}
+	}
+
+	/// Returns the allowed range of a slider field.
+	fn get_range(&self) -> (f32, f32)
+	{
+		match *self
+		{
+			Field::Specular => (0.0, 4.0),
+			_ => (0.0, 1.0),
+		}
+	}
+}
+
+enum EditorCommand
+{
+	Toggle,
+	NextObject,
+	PrevField,
+	NextField,
+	Change(i32),
+	Save,
+}
+
+/// Overlay for tweaking the materials of the scene's objects at runtime, and saving them back to their asset files.
+///
+/// F2 toggles the editor, Tab selects the next object, PageUp/PageDown select a field, -/= change the selected
+/// field, and F5 saves the selected object's material. With the cursor released, clicking an object selects it and
+/// clicking a field on the panel selects the field. Changes are undone with the EditHistory, the steps of a held key
+/// as one.
+pub struct MaterialEditor
+{
+	visible: bool,
+	pending_commands: Vec<EditorCommand>,
+	selected_object: usize,
+	selected_field: usize,
+	// Textures that can be swapped in, relative to the working directory
+	texture_files: Vec<String>,
+	status: Option<(Result<String, String>, Timer)>,
+	// Changes since the change key went down
+	change_repeats: u32,
+	// The history group of the changes since then
+	change_group: Option<UndoGroup>,
+}
+
+impl MaterialEditor
+{
+	pub fn new() -> MaterialEditor
+	{
+		let mut texture_files = Vec::new();
+		MaterialEditor::find_textures(Path::new(TEXTURE_DIR), &mut texture_files);
+		texture_files.sort();
+
+		MaterialEditor {
+			visible: false,
+			pending_commands: Vec::new(),
+			selected_object: 0,
+			selected_field: 0,
+			texture_files: texture_files,
+			status: None,
+			change_repeats: 0,
+			change_group: None,
+		}
+	}
+
+	fn find_textures(dir: &Path, texture_files: &mut Vec<String>)
+	{
+		let entries = match fs::read_dir(dir)
+		{
+			Ok(entries) => entries,
+			Err(e) =>
+			{
+				warning!("Could not list textures in {}: {}", dir.display(), e);
+				return;
+			}
+		};
+		for entry in entries.filter_map(|entry| entry.ok())
+		{
+			let path = entry.path();
+			if path.is_dir()
+			{
+				MaterialEditor::find_textures(&path, texture_files);
+				continue;
+			}
+			let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+			match extension.as_ref().map(|ext| ext.as_str())
+			{
+				Some("png") | Some("jpg") | Some("jpeg") => texture_files.push(path.to_string_lossy().into_owned()),
+				_ => (),
+			}
+		}
+	}
+
+	pub fn is_visible(&self) -> bool
+	{
+		return self.visible;
+	}
+
+	/// Returns the index of the selected object, if the editor is visible.
+	pub fn get_selected_object(&self, num_objects: usize) -> Option<usize>
+	{
+		if !self.visible || num_objects == 0
+		{
+			return None;
+		}
+		return Some(self.selected_object % num_objects);
+	}
+
+	/// Selects an object, showing the editor if it is hidden.
+	pub fn select_object(&mut self, idx: usize)
+	{
+		if !self.visible || self.selected_object != idx
+		{
+			self.status = None;
+		}
+		self.visible = true;
+		self.selected_object = idx;
+	}
+
+	/// Returns the line of the panel at the given position in UI coordinates, if the panel is shown there.
+	fn get_panel_line(&self, position: (f32, f32)) -> Option<usize>
+	{
+		let (x, y) = PANEL_POSITION;
+		let line = ((position.1 - y) / LINE_SPACING).floor();
+		if !self.visible || position.0 < x || position.0 > x + PANEL_WIDTH || line < 0.0 || line >= PANEL_LINES as f32
+		{
+			return None;
+		}
+		return Some(line as usize);
+	}
+
+	pub fn is_over_panel(&self, position: (f32, f32)) -> bool
+	{
+		return self.get_panel_line(position).is_some();
+	}
+
+	/// Handles a mouse click at the given position in UI coordinates, selecting the clicked field. Returns false if
+	/// the click was not on the panel.
+	pub fn click(&mut self, position: (f32, f32)) -> bool
+	{
+		let line = match self.get_panel_line(position)
+		{
+			Some(line) => line,
+			None => return false,
+		};
+		// Fields start after the three header lines
+		if line >= 3 && line < 3 + FIELDS.len()
+		{
+			self.selected_field = line - 3;
+		}
+		return true;
+	}
+
+	/// Applies the queued input to the selected object's material, recording the changes in the history, and queues
+	/// the editor panel on the overlay.
+	pub fn update(
+		&mut self, rs: &RenderState, time: &Time, objects: &[(String, Rc<Material>)], overlay: &mut Overlay,
+		history: &mut EditHistory,
+	)
+	{
+		let commands: Vec<EditorCommand> = self.pending_commands.drain(..).collect();
+		for command in commands
+		{
+			match command
+			{
+				EditorCommand::Toggle => self.visible = !self.visible,
+				// Ignore everything else while hidden
+				_ if !self.visible || objects.is_empty() => (),
+				EditorCommand::NextObject =>
+				{
+					self.selected_object = (self.selected_object + 1) % objects.len();
+					self.status = None;
+				}
+				EditorCommand::PrevField =>
+				{
+					self.selected_field = (self.selected_field + FIELDS.len() - 1) % FIELDS.len();
+				}
+				EditorCommand::NextField => self.selected_field = (self.selected_field + 1) % FIELDS.len(),
+				EditorCommand::Change(steps) =>
+				{
+					self.change_field(rs, &objects[self.selected_object % objects.len()].1, steps, history)
+				}
+				EditorCommand::Save =>
+				{
+					let material = &objects[self.selected_object % objects.len()].1;
+					let result = match material.save()
+					{
+						Ok(()) => Ok(format!("Saved {}", material.get_asset_path().unwrap_or(""))),
+						Err(e) => Err(format!("Save failed: {}", e)),
+					};
+					self.status = Some((result, Timer::new(time, STATUS_DURATION, TimeDomain::Unscaled)));
+				}
+			}
+		}
+
+		if self.status.as_ref().map_or(false, |(_, timer)| timer.is_done(time))
+		{
+			self.status = None;
+		}
+		if self.visible && !objects.is_empty()
+		{
+			self.draw(objects, overlay);
+		}
+	}
+
+	fn change_field(&mut self, rs: &RenderState, material: &Rc<Material>, steps: i32, history: &mut EditHistory)
+	{
+		let mut desc = material.get_desc();
+		let edit = Edit::Material {
+			material: Rc::downgrade(material),
+			desc: desc.clone(),
+		};
+		let group = *self.change_group.get_or_insert_with(|| history.new_group());
+		let field = FIELDS[self.selected_field];
+		let value = match field
+		{
+			Field::Tint(channel) => &mut desc.tint[channel],
+			Field::Specular => &mut desc.specular,
+			Field::Emissive(channel) => &mut desc.emissive[channel],
+			Field::Texture | Field::NormalMap =>
+			{
+				if self.texture_files.is_empty()
+				{
+					return;
+				}
+				let current = if field == Field::Texture
+				{
+					&desc.texture
+				}
+				else
+				{
+					&desc.normal_map
+				};
+				let count = self.texture_files.len() as i32;
+				let idx = match self.texture_files.iter().position(|path| path == current)
+				{
+					Some(idx) => (idx as i32 + steps).rem_euclid(count),
+					None => 0,
+				};
+				material.set_texture(rs, &self.texture_files[idx as usize], field == Field::NormalMap);
+				history.push_grouped(edit, group);
+				return;
+			}
+		};
+		let (min, max) = field.get_range();
+		let previous = *value;
+		*value = (*value + steps as f32 * SLIDER_STEP).max(min).min(max);
+		// Nothing to undo at the ends of the range
+		if *value != previous
+		{
+			material.set_params(&desc);
+			history.push_grouped(edit, group);
+		}
+	}
+
+	fn format_slider(value: f32, range: (f32, f32)) -> String
+	{
+		let filled = (((value - range.0) / (range.1 - range.0)) * SLIDER_WIDTH as f32).round() as usize;
+		let filled = filled.min(SLIDER_WIDTH);
+		return format!("[{}{}] {:.2}", "#".repeat(filled), "-".repeat(SLIDER_WIDTH - filled), value);
+	}
+
+	fn format_path(path: &str) -> String
+	{
+		if path.is_empty()
+		{
+			return "<none>".to_string();
+		}
+		return Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().into_owned());
+	}
+
+	fn draw(&self, objects: &[(String, Rc<Material>)], overlay: &mut Overlay)
+	{
+		let style = TextStyle {
+			outline_width: 1.5,
+			shadow_color: [0.0, 0.0, 0.0, 0.6],
+			shadow_offset: [2.0, 2.0],
+			..Default::default()
+		};
+		let title_style = TextStyle {
+			color: TITLE_COLOR,
+			..style
+		};
+		let selected_style = TextStyle {
+			color: SELECTED_COLOR,
+			..style
+		};
+
+		let (name, material) = &objects[self.selected_object % objects.len()];
+		let desc = material.get_desc();
+		let (x, mut y) = PANEL_POSITION;
+		// Fields start after the three header lines
+		let marker_y = y + (3 + self.selected_field) as f32 * LINE_SPACING;
+		let mut line = |text: &str, style: &TextStyle, indent: f32| {
+			overlay.draw_text(text, (x + indent, y), TEXT_SIZE, style);
+			y += LINE_SPACING;
+		};
+
+		line("Material editor", &title_style, 0.0);
+		line(&format!("Object: {} ({}/{})", name, self.selected_object + 1, objects.len()), &style, 0.0);
+		line(&format!("File: {}", material.get_asset_path().unwrap_or("<not saveable>")), &style, 0.0);
+
+		for (idx, field) in FIELDS.iter().enumerate()
+		{
+			let value = match *field
+			{
+				Field::Tint(channel) => MaterialEditor::format_slider(desc.tint[channel], field.get_range()),
+				Field::Specular => MaterialEditor::format_slider(desc.specular, field.get_range()),
+				Field::Emissive(channel) => MaterialEditor::format_slider(desc.emissive[channel], field.get_range()),
+				Field::Texture => MaterialEditor::format_path(&desc.texture),
+				Field::NormalMap => MaterialEditor::format_path(&desc.normal_map),
+			};
+			let field_style = if idx == self.selected_field
+			{
+				&selected_style
+			}
+			else
+			{
+				&style
+			};
+			line(&format!("{:<11}{}", field.get_name(), value), field_style, FIELD_INDENT);
+		}
+		line("Tab: object  PgUp/PgDn: field  -/=: change  F5: save", &style, 0.0);
+		match &self.status
+		{
+			Some((Ok(message), _)) => line(message, &style, 0.0),
+			Some((Err(message), _)) => line(
+				message,
+				&TextStyle {
+					color: ERROR_COLOR,
+					..style
+				},
+				0.0,
+			),
+			None => (),
+		}
+
+		overlay.draw_icon(Icon::Triangle, (x, marker_y), TEXT_SIZE, &selected_style);
+	}
+}
+
+impl InputConsumer for MaterialEditor
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+
+		handled_actions.set(Action::EDITOR_TOGGLE as usize, true);
+		handled_actions.set(Action::EDITOR_NEXT_OBJECT as usize, true);
+		handled_actions.set(Action::EDITOR_PREV_FIELD as usize, true);
+		handled_actions.set(Action::EDITOR_NEXT_FIELD as usize, true);
+		handled_actions.set(Action::EDITOR_DECREASE as usize, true);
+		handled_actions.set(Action::EDITOR_INCREASE as usize, true);
+		handled_actions.set(Action::EDITOR_SAVE as usize, true);
+
+		return handled_actions;
+	}
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		// Changes are applied in update(), where the materials are available
+		if actions.is_triggered(Action::EDITOR_TOGGLE)
+		{
+			self.pending_commands.push(EditorCommand::Toggle);
+		}
+		if actions.is_triggered(Action::EDITOR_NEXT_OBJECT)
+		{
+			self.pending_commands.push(EditorCommand::NextObject);
+		}
+		if actions.is_triggered(Action::EDITOR_PREV_FIELD)
+		{
+			self.pending_commands.push(EditorCommand::PrevField);
+		}
+		if actions.is_triggered(Action::EDITOR_NEXT_FIELD)
+		{
+			self.pending_commands.push(EditorCommand::NextField);
+		}
+		for &(action, direction) in [(Action::EDITOR_DECREASE, -1), (Action::EDITOR_INCREASE, 1)].iter()
+		{
+			if actions.was_pressed(action)
+			{
+				self.change_repeats = 0;
+				self.change_group = None;
+			}
+			if actions.is_triggered(action)
+			{
+				let steps = 1 + (self.change_repeats / CHANGE_ACCELERATION_REPEATS) as i32;
+				self.pending_commands.push(EditorCommand::Change(direction * steps));
+				self.change_repeats += 1;
+			}
+		}
+		if actions.is_triggered(Action::EDITOR_SAVE)
+		{
+			self.pending_commands.push(EditorCommand::Save);
+		}
+	}
+}
@@ -1,9 +1,68 @@
+mod agent;
 mod camera;
+mod camera_effects;
 mod car;
+mod character;
+mod collision;
+mod debug_hud;
+mod depth_of_field;
+mod ecs;
+mod edit_history;
+mod gallery;
+mod game_state;
+mod light;
+mod log_overlay;
+mod manifest;
+mod material_editor;
+mod navmesh;
 mod nurbs;
+mod particles;
+mod physics;
+mod post_effects;
+mod race;
+mod replay;
+mod road;
 mod scene;
+mod screenshot_gallery;
+mod texture_paint;
+mod time_of_day;
+mod timeline;
+mod trails;
+mod weather;
 
-pub use self::camera::Camera;
-pub use self::car::Car;
+pub use self::agent::{Agent, AgentSettings};
+pub use self::camera::{Camera, CameraBoom, CameraMode, CameraToggleInput, CameraTransition, FramingInput, Orbit};
+pub use self::camera_effects::CameraEffects;
+pub use self::car::{Car, CarControls, CarState};
+pub use self::character::{Character, InteractInput, VehicleEvent};
+pub use self::collision::{ConvexHull, TriangleMesh};
+pub use self::debug_hud::DebugHud;
+pub use self::depth_of_field::DepthOfField;
+pub use self::ecs::{
+	ColliderComponent, Entity, MorphComponent, PhysicsComponent, RenderComponent, StaticComponent, TransformComponent,
+	World,
+};
+pub use self::edit_history::{Edit, EditHistory};
+pub use self::gallery::{find_gallery_scene, get_gallery_scenes};
+pub use self::game_state::{GameEvent, GameState, GameStateMachine};
+pub use self::light::{Light, LightId, LightKind};
+pub use self::log_overlay::LogOverlay;
+pub use self::manifest::{AssetGraph, AssetManifest, AssetRef};
+pub use self::material_editor::MaterialEditor;
+pub use self::navmesh::{NavMesh, NavMeshBuilder, NavMeshConfig};
 pub use self::nurbs::{NURBSpline, Order};
+pub use self::particles::ParticleEmitter;
+pub use self::physics::{
+	Collider, CombineRule, ContactPhase, ContactWorld, OrientedBox, PhysicsMaterial, RigidBody, RigidBodyWorld,
+};
+pub use self::post_effects::PostEffects;
+pub use self::race::{format_time, RaceManager, RaceSettings, Standing};
+pub use self::replay::Replay;
+pub use self::road::{Road, RoadSettings};
 pub use self::scene::Scene;
+pub use self::screenshot_gallery::{new_screenshot_path, ScreenshotGallery};
+pub use self::texture_paint::TexturePainter;
+pub use self::time_of_day::{NightLightMode, TimeOfDay};
+pub use self::timeline::{TimelineTrigger, Timelines};
+pub use self::trails::Trail;
+pub use self::weather::{WeatherController, WeatherSettings};
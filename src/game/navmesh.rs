@@ -0,0 +1,757 @@
+use crate::core::ParticleVertex;
+use cgmath::prelude::*;
+use cgmath::{Deg, Point2, Point3, Vector2};
+
+const POLY_OUTLINE_COLOR: [f32; 4] = [0.1, 0.8, 0.2, 0.8];
+const PORTAL_COLOR: [f32; 4] = [0.2, 0.4, 1.0, 0.8];
+// Lift the debug lines slightly, so they don't z-fight with the geometry
+const DEBUG_LINE_OFFSET: f32 = 0.05;
+
+/// Parameters for building a navigation mesh.
+#[derive(Clone, Copy)]
+pub struct NavMeshConfig
+{
+	/// Horizontal size of a heightfield cell.
+	pub cell_size: f32,
+	pub agent_radius: f32,
+	pub agent_height: f32,
+	/// Steepest walkable slope.
+	pub max_slope: Deg<f32>,
+	/// Highest step an agent can walk up or down.
+	pub max_climb: f32,
+	/// The navmesh covers [-half_extent, half_extent] along x and z.
+	pub half_extent: f32,
+}
+
+impl Default for NavMeshConfig
+{
+	fn default() -> NavMeshConfig
+	{
+		NavMeshConfig {
+			cell_size: 0.5,
+			agent_radius: 0.5,
+			agent_height: 2.0,
+			max_slope: Deg(45.0),
+			max_climb: 0.4,
+			half_extent: 50.0,
+		}
+	}
+}
+
+/// Axis aligned rectangle of heightfield cells, the max corner is exclusive.
+#[derive(Clone, Copy)]
+struct CellRect
+{
+	min: [usize; 2],
+	max: [usize; 2],
+}
+
+struct Portal
+{
+	poly: usize,
+	// Endpoints of the shared edge, in the xz-plane
+	a: Point2<f32>,
+	b: Point2<f32>,
+}
+
+struct NavPoly
+{
+	rect: CellRect,
+	center: Point2<f32>,
+	height: f32,
+	portals: Vec<Portal>,
+}
+
+/// Voxelizes walkable geometry into a heightfield, and builds a navmesh from it.
+pub struct NavMeshBuilder
+{
+	config: NavMeshConfig,
+	cells_per_side: usize,
+	heights: Vec<Option<f32>>,
+	obstacles: Vec<(Point3<f32>, Point3<f32>)>,
+}
+
+impl NavMeshBuilder
+{
+	pub fn new(config: NavMeshConfig) -> NavMeshBuilder
+	{
+		let cells_per_side = (2.0 * config.half_extent / config.cell_size).ceil() as usize;
+		NavMeshBuilder {
+			config: config,
+			cells_per_side: cells_per_side,
+			heights: vec![None; cells_per_side * cells_per_side],
+			obstacles: Vec::new(),
+		}
+	}
+
+	fn cell_center(&self, x: usize, z: usize) -> Point2<f32>
+	{
+		let origin = -self.config.half_extent;
+		Point2::new(
+			origin + (x as f32 + 0.5) * self.config.cell_size,
+			origin + (z as f32 + 0.5) * self.config.cell_size,
+		)
+	}
+
+	/// Returns the range of cells overlapping [min, max) along one axis.
+	fn cell_range(&self, min: f32, max: f32) -> (usize, usize)
+	{
+		let to_cell = |v: f32| ((v + self.config.half_extent) / self.config.cell_size).max(0.0) as usize;
+		(to_cell(min), (to_cell(max) + 1).min(self.cells_per_side))
+	}
+
+	/// Rasterizes the given world space triangles into the heightfield. Triangles steeper than the max slope are
+	/// ignored. Where triangles overlap, the highest surface is kept.
+	pub fn add_walkable(&mut self, triangles: &[[Point3<f32>; 3]])
+	{
+		let min_normal_y = Deg::cos(self.config.max_slope);
+
+		for tri in triangles
+		{
+			let normal = (tri[1] - tri[0]).cross(tri[2] - tri[0]);
+			if normal.magnitude2() == 0.0 || normal.normalize().y.abs() < min_normal_y
+			{
+				continue;
+			}
+
+			let min_x = tri[0].x.min(tri[1].x).min(tri[2].x);
+			let max_x = tri[0].x.max(tri[1].x).max(tri[2].x);
+			let min_z = tri[0].z.min(tri[1].z).min(tri[2].z);
+			let max_z = tri[0].z.max(tri[1].z).max(tri[2].z);
+			let (x_begin, x_end) = self.cell_range(min_x, max_x);
+			let (z_begin, z_end) = self.cell_range(min_z, max_z);
+
+			for z in z_begin..z_end
+			{
+				for x in x_begin..x_end
+				{
+					let center = self.cell_center(x, z);
+					if let Some(height) = height_in_triangle(center, tri)
+					{
+						let cell = &mut self.heights[z * self.cells_per_side + x];
+						*cell = Some(cell.map_or(height, |h| h.max(height)));
+					}
+				}
+			}
+		}
+	}
+
+	/// Marks the bounding box of the given world space triangles as blocked for agents.
+	pub fn add_obstacle(&mut self, triangles: &[[Point3<f32>; 3]])
+	{
+		if triangles.is_empty()
+		{
+			return;
+		}
+
+		let mut min = triangles[0][0];
+		let mut max = triangles[0][0];
+		for point in triangles.iter().flat_map(|tri| tri.iter())
+		{
+			min = Point3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z));
+			max = Point3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z));
+		}
+		self.obstacles.push((min, max));
+	}
+
+	fn is_walkable(&self, x: isize, z: isize) -> bool
+	{
+		let side = self.cells_per_side as isize;
+		if x < 0 || z < 0 || x >= side || z >= side
+		{
+			return false;
+		}
+		return self.heights[(z * side + x) as usize].is_some();
+	}
+
+	/// Removes cells blocked by obstacles, and cells too close to non-walkable cells.
+	fn filter_cells(&mut self)
+	{
+		for &(min, max) in &self.obstacles
+		{
+			let (x_begin, x_end) = self.cell_range(min.x, max.x);
+			let (z_begin, z_end) = self.cell_range(min.z, max.z);
+			for z in z_begin..z_end
+			{
+				for x in x_begin..x_end
+				{
+					let center = self.cell_center(x, z);
+					if center.x < min.x || center.x > max.x || center.y < min.z || center.y > max.z
+					{
+						continue;
+					}
+					let cell = &mut self.heights[z * self.cells_per_side + x];
+					// Obstacles below the max climb can be stepped over
+					if let Some(height) = *cell
+					{
+						if min.y < height + self.config.agent_height && max.y > height + self.config.max_climb
+						{
+							*cell = None;
+						}
+					}
+				}
+			}
+		}
+
+		// Erode the walkable area by the agent radius, so agents can follow the navmesh without clipping walls.
+		let radius = (self.config.agent_radius / self.config.cell_size).ceil() as isize;
+		let mut eroded = self.heights.clone();
+		for z in 0..self.cells_per_side as isize
+		{
+			for x in 0..self.cells_per_side as isize
+			{
+				let mut clear = true;
+				for dz in -radius..=radius
+				{
+					for dx in -radius..=radius
+					{
+						if dx * dx + dz * dz <= radius * radius && !self.is_walkable(x + dx, z + dz)
+						{
+							clear = false;
+						}
+					}
+				}
+				if !clear
+				{
+					eroded[z as usize * self.cells_per_side + x as usize] = None;
+				}
+			}
+		}
+		self.heights = eroded;
+	}
+
+	pub fn build(mut self) -> NavMesh
+	{
+		self.filter_cells();
+
+		let side = self.cells_per_side;
+		let height_at = |x: usize, z: usize| self.heights[z * side + x];
+		// Keep the height variation within a polygon low enough that any two neighbouring cells can be climbed.
+		let max_variation = 0.5 * self.config.max_climb;
+
+		// Greedily merge the walkable cells into rectangles, first along x, then along z.
+		let mut cell_polys: Vec<Option<usize>> = vec![None; side * side];
+		let mut polys: Vec<NavPoly> = Vec::new();
+		for z0 in 0..side
+		{
+			for x0 in 0..side
+			{
+				let base = match height_at(x0, z0)
+				{
+					Some(height) if cell_polys[z0 * side + x0].is_none() => height,
+					_ => continue,
+				};
+				let fits = |x: usize, z: usize| {
+					cell_polys[z * side + x].is_none() &&
+						height_at(x, z).map_or(false, |h| (h - base).abs() <= max_variation)
+				};
+
+				let mut x1 = x0 + 1;
+				while x1 < side && fits(x1, z0)
+				{
+					x1 += 1;
+				}
+				let mut z1 = z0 + 1;
+				while z1 < side && (x0..x1).all(|x| fits(x, z1))
+				{
+					z1 += 1;
+				}
+
+				let poly_idx = polys.len();
+				let mut height_sum = 0.0;
+				for z in z0..z1
+				{
+					for x in x0..x1
+					{
+						cell_polys[z * side + x] = Some(poly_idx);
+						height_sum += height_at(x, z).unwrap();
+					}
+				}
+				let min_corner = self.cell_center(x0, z0);
+				let max_corner = self.cell_center(x1 - 1, z1 - 1);
+				polys.push(NavPoly {
+					rect: CellRect {
+						min: [x0, z0],
+						max: [x1, z1],
+					},
+					center: min_corner.midpoint(max_corner),
+					height: height_sum / ((x1 - x0) * (z1 - z0)) as f32,
+					portals: Vec::new(),
+				});
+			}
+		}
+
+		// Connect polygons sharing an edge, if the step between them can be climbed somewhere along the edge.
+		let origin = -self.config.half_extent;
+		let cell_size = self.config.cell_size;
+		for a in 0..polys.len()
+		{
+			for b in (a + 1)..polys.len()
+			{
+				let (ra, rb) = (polys[a].rect, polys[b].rect);
+				for axis in 0..2
+				{
+					let other = 1 - axis;
+					let (low, high) = if ra.max[axis] == rb.min[axis]
+					{
+						(ra, rb)
+					}
+					else if rb.max[axis] == ra.min[axis]
+					{
+						(rb, ra)
+					}
+					else
+					{
+						continue;
+					};
+					let begin = ra.min[other].max(rb.min[other]);
+					let end = ra.max[other].min(rb.max[other]);
+					if begin >= end
+					{
+						continue;
+					}
+
+					let climbable = (begin..end).any(|i| {
+						let mut low_cell = [0, 0];
+						let mut high_cell = [0, 0];
+						low_cell[axis] = low.max[axis] - 1;
+						high_cell[axis] = high.min[axis];
+						low_cell[other] = i;
+						high_cell[other] = i;
+						let low_height = height_at(low_cell[0], low_cell[1]).unwrap();
+						let high_height = height_at(high_cell[0], high_cell[1]).unwrap();
+						(low_height - high_height).abs() <= self.config.max_climb
+					});
+					if !climbable
+					{
+						continue;
+					}
+
+					let edge = origin + high.min[axis] as f32 * cell_size;
+					let mut p0 = [0.0; 2];
+					let mut p1 = [0.0; 2];
+					p0[axis] = edge;
+					p1[axis] = edge;
+					p0[other] = origin + begin as f32 * cell_size;
+					p1[other] = origin + end as f32 * cell_size;
+					let (p0, p1) = (Point2::new(p0[0], p0[1]), Point2::new(p1[0], p1[1]));
+
+					polys[a].portals.push(Portal {
+						poly: b,
+						a: p0,
+						b: p1,
+					});
+					polys[b].portals.push(Portal {
+						poly: a,
+						a: p0,
+						b: p1,
+					});
+				}
+			}
+		}
+
+		NavMesh {
+			config: self.config,
+			cells_per_side: side,
+			heights: self.heights,
+			cell_polys: cell_polys,
+			polys: polys,
+		}
+	}
+}
+
+/// Returns the height of the triangle at the given xz-position, if the position is inside it.
+fn height_in_triangle(p: Point2<f32>, tri: &[Point3<f32>; 3]) -> Option<f32>
+{
+	let a = Point2::new(tri[0].x, tri[0].z);
+	let b = Point2::new(tri[1].x, tri[1].z);
+	let c = Point2::new(tri[2].x, tri[2].z);
+	let area = (b - a).perp_dot(c - a);
+	if area.abs() < 1e-6
+	{
+		return None;
+	}
+
+	let u = (c - b).perp_dot(p - b) / area;
+	let v = (a - c).perp_dot(p - c) / area;
+	let w = 1.0 - u - v;
+	if u < 0.0 || v < 0.0 || w < 0.0
+	{
+		return None;
+	}
+	return Some(u * tri[0].y + v * tri[1].y + w * tri[2].y);
+}
+
+/// Twice the signed area of the triangle abc. Positive when c is to the right of ab.
+fn triarea2(a: Point2<f32>, b: Point2<f32>, c: Point2<f32>) -> f32
+{
+	(c - a).perp_dot(b - a)
+}
+
+/// A navigation mesh of convex polygons covering the walkable area of a scene.
+pub struct NavMesh
+{
+	config: NavMeshConfig,
+	cells_per_side: usize,
+	heights: Vec<Option<f32>>,
+	cell_polys: Vec<Option<usize>>,
+	polys: Vec<NavPoly>,
+}
+
+impl NavMesh
+{
+	fn cell_index(&self, x: f32, z: f32) -> Option<usize>
+	{
+		let to_cell = |v: f32| ((v + self.config.half_extent) / self.config.cell_size).floor();
+		let (cx, cz) = (to_cell(x), to_cell(z));
+		let side = self.cells_per_side as f32;
+		if cx < 0.0 || cz < 0.0 || cx >= side || cz >= side
+		{
+			return None;
+		}
+		return Some(cz as usize * self.cells_per_side + cx as usize);
+	}
+
+	fn find_poly(&self, position: Point3<f32>) -> Option<usize>
+	{
+		return self.cell_index(position.x, position.z).and_then(|idx| self.cell_polys[idx]);
+	}
+
+	/// Returns the walkable height at the given xz-position, if it is on the navmesh.
+	pub fn get_height(&self, x: f32, z: f32) -> Option<f32>
+	{
+		let idx = self.cell_index(x, z)?;
+		return self.cell_polys[idx].and(self.heights[idx]);
+	}
+
+	/// Finds a path between two points on the navmesh, including both endpoints.
+	///
+	/// Returns None if either point is outside the navmesh, or if there is no path between them.
+	pub fn find_path(&self, start: Point3<f32>, end: Point3<f32>) -> Option<Vec<Point3<f32>>>
+	{
+		let start_poly = self.find_poly(start)?;
+		let end_poly = self.find_poly(end)?;
+		let poly_path = self.find_poly_path(start, start_poly, end, end_poly)?;
+
+		let start_xz = Point2::new(start.x, start.z);
+		let end_xz = Point2::new(end.x, end.z);
+
+		// Portals along the polygon path as (left, right), as seen when walking through them.
+		let mut portals = vec![(start_xz, start_xz)];
+		for pair in poly_path.windows(2)
+		{
+			let from = &self.polys[pair[0]];
+			let portal = from.portals.iter().find(|portal| portal.poly == pair[1]).unwrap();
+			let direction = self.polys[pair[1]].center - from.center;
+			if direction.perp_dot(portal.a - from.center) > direction.perp_dot(portal.b - from.center)
+			{
+				portals.push((portal.a, portal.b));
+			}
+			else
+			{
+				portals.push((portal.b, portal.a));
+			}
+		}
+		portals.push((end_xz, end_xz));
+
+		let mut path = vec![start];
+		for point in string_pull(&portals).into_iter().skip(1)
+		{
+			let height = self.get_height(point.x, point.y).unwrap_or(end.y);
+			path.push(Point3::new(point.x, height, point.y));
+		}
+		if let Some(last) = path.last_mut()
+		{
+			*last = end;
+		}
+		return Some(path);
+	}
+
+	/// A* over the polygon graph, using the polygon centers as nodes.
+	fn find_poly_path(
+		&self, start: Point3<f32>, start_poly: usize, end: Point3<f32>, end_poly: usize,
+	) -> Option<Vec<usize>>
+	{
+		let start_xz = Point2::new(start.x, start.z);
+		let end_xz = Point2::new(end.x, end.z);
+		let node_pos = |poly: usize| match poly
+		{
+			p if p == start_poly => start_xz,
+			p if p == end_poly => end_xz,
+			p => self.polys[p].center,
+		};
+
+		let mut cost = vec![f32::INFINITY; self.polys.len()];
+		let mut parent: Vec<Option<usize>> = vec![None; self.polys.len()];
+		let mut closed = vec![false; self.polys.len()];
+		let mut open = vec![start_poly];
+		cost[start_poly] = 0.0;
+
+		while !open.is_empty()
+		{
+			// The polygon count is low, so a linear search for the best node is fine
+			let estimate = |poly: usize| cost[poly] + node_pos(poly).distance(end_xz);
+			let (open_idx, _) = open
+				.iter()
+				.enumerate()
+				.min_by(|(_, &a), (_, &b)| estimate(a).partial_cmp(&estimate(b)).unwrap())
+				.unwrap();
+			let current = open.swap_remove(open_idx);
+			if current == end_poly
+			{
+				let mut poly_path = vec![current];
+				while let Some(prev) = parent[*poly_path.last().unwrap()]
+				{
+					poly_path.push(prev);
+				}
+				poly_path.reverse();
+				return Some(poly_path);
+			}
+			closed[current] = true;
+
+			for portal in &self.polys[current].portals
+			{
+				let next = portal.poly;
+				if closed[next]
+				{
+					continue;
+				}
+				let next_cost = cost[current] + node_pos(current).distance(node_pos(next));
+				if next_cost < cost[next]
+				{
+					if cost[next] == f32::INFINITY
+					{
+						open.push(next);
+					}
+					cost[next] = next_cost;
+					parent[next] = Some(current);
+				}
+			}
+		}
+		return None;
+	}
+
+	/// Returns the polygon outlines and portals as line vertices, for debug rendering.
+	pub fn get_debug_lines(&self) -> Vec<ParticleVertex>
+	{
+		let origin = -self.config.half_extent;
+		let cell_size = self.config.cell_size;
+		let mut vertices = Vec::new();
+		let mut push_line = |a: Point2<f32>, b: Point2<f32>, height: f32, color: [f32; 4]| {
+			let y = height + DEBUG_LINE_OFFSET;
+			vertices.push(ParticleVertex {
+				pos: [a.x, y, a.y],
+				color: color,
+			});
+			vertices.push(ParticleVertex {
+				pos: [b.x, y, b.y],
+				color: color,
+			});
+		};
+
+		for poly in &self.polys
+		{
+			let min = Point2::new(origin, origin) +
+				Vector2::new(poly.rect.min[0] as f32, poly.rect.min[1] as f32) * cell_size;
+			let max = Point2::new(origin, origin) +
+				Vector2::new(poly.rect.max[0] as f32, poly.rect.max[1] as f32) * cell_size;
+			let corners = [min, Point2::new(max.x, min.y), max, Point2::new(min.x, max.y)];
+			for i in 0..corners.len()
+			{
+				push_line(corners[i], corners[(i + 1) % corners.len()], poly.height, POLY_OUTLINE_COLOR);
+			}
+			for portal in &poly.portals
+			{
+				push_line(portal.a, portal.b, poly.height + DEBUG_LINE_OFFSET, PORTAL_COLOR);
+			}
+		}
+		return vertices;
+	}
+}
+
+/// Simple stupid funnel algorithm, finds the shortest path through the given (left, right) portals.
+/// The first and last portals are expected to be the start and end points.
+fn string_pull(portals: &[(Point2<f32>, Point2<f32>)]) -> Vec<Point2<f32>>
+{
+	let mut path = vec![portals[0].0];
+	let mut apex = portals[0].0;
+	let (mut funnel_left, mut funnel_right) = portals[0];
+	let (mut left_idx, mut right_idx) = (0, 0);
+
+	let mut i = 1;
+	while i < portals.len()
+	{
+		let (left, right) = portals[i];
+
+		// Try to narrow the funnel from the right
+		if triarea2(apex, funnel_right, right) <= 0.0
+		{
+			if apex == funnel_right || triarea2(apex, funnel_left, right) > 0.0
+			{
+				funnel_right = right;
+				right_idx = i;
+			}
+			else
+			{
+				// Right crossed over left, the left point becomes a corner of the path
+				path.push(funnel_left);
+				apex = funnel_left;
+				let apex_idx = left_idx;
+				funnel_left = apex;
+				funnel_right = apex;
+				left_idx = apex_idx;
+				right_idx = apex_idx;
+				i = apex_idx + 1;
+				continue;
+			}
+		}
+
+		// Try to narrow the funnel from the left
+		if triarea2(apex, funnel_left, left) >= 0.0
+		{
+			if apex == funnel_left || triarea2(apex, funnel_right, left) < 0.0
+			{
+				funnel_left = left;
+				left_idx = i;
+			}
+			else
+			{
+				// Left crossed over right, the right point becomes a corner of the path
+				path.push(funnel_right);
+				apex = funnel_right;
+				let apex_idx = right_idx;
+				funnel_left = apex;
+				funnel_right = apex;
+				left_idx = apex_idx;
+				right_idx = apex_idx;
+				i = apex_idx + 1;
+				continue;
+			}
+		}
+
+		i += 1;
+	}
+
+	let end = portals[portals.len() - 1].0;
+	if *path.last().unwrap() != end
+	{
+		path.push(end);
+	}
+	return path;
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// A flat 10 x 10 grid of 1 m cells centered on the origin, with the boxes between the given corners blocked.
+	// Agents have no radius, so only blocked cells are left out.
+	fn build_grid(obstacles: &[([f32; 2], [f32; 2])]) -> NavMesh
+	{
+		let mut builder = NavMeshBuilder::new(NavMeshConfig {
+			cell_size: 1.0,
+			agent_radius: 0.0,
+			half_extent: 5.0,
+			..Default::default()
+		});
+		let corners = [
+			Point3::new(-5.0, 0.0, -5.0),
+			Point3::new(5.0, 0.0, -5.0),
+			Point3::new(5.0, 0.0, 5.0),
+			Point3::new(-5.0, 0.0, 5.0),
+		];
+		builder.add_walkable(&[[corners[0], corners[1], corners[2]], [corners[0], corners[2], corners[3]]]);
+		for &(min, max) in obstacles
+		{
+			builder.add_obstacle(&[[
+				Point3::new(min[0], 0.0, min[1]),
+				Point3::new(max[0], 2.0, max[1]),
+				Point3::new(min[0], 0.0, max[1]),
+			]]);
+		}
+		return builder.build();
+	}
+
+	fn path_length(path: &[Point3<f32>]) -> f32
+	{
+		return path.windows(2).map(|pair| pair[0].distance(pair[1])).sum();
+	}
+
+	#[test]
+	fn triarea2_is_positive_to_the_right()
+	{
+		let (a, b) = (Point2::new(0.0, 0.0), Point2::new(0.0, 1.0));
+		assert!(triarea2(a, b, Point2::new(1.0, 0.5)) > 0.0);
+		assert!(triarea2(a, b, Point2::new(-1.0, 0.5)) < 0.0);
+		assert_eq!(triarea2(a, b, Point2::new(0.0, 2.0)), 0.0);
+	}
+
+	#[test]
+	fn straight_line_without_obstacles()
+	{
+		// The corner obstacle splits the floor into several polygons, none of them in the way
+		let navmesh = build_grid(&[([3.0, 3.0], [4.0, 4.0])]);
+		let (start, end) = (Point3::new(-4.5, 0.0, -3.5), Point3::new(-4.5, 0.0, 4.5));
+		let start_poly = navmesh.find_poly(start).unwrap();
+		let end_poly = navmesh.find_poly(end).unwrap();
+		assert!(navmesh.find_poly_path(start, start_poly, end, end_poly).unwrap().len() > 1);
+		assert_eq!(navmesh.find_path(start, end), Some(vec![start, end]));
+	}
+
+	#[test]
+	fn route_around_blocked_cell()
+	{
+		let navmesh = build_grid(&[([0.0, 0.0], [1.0, 1.0])]);
+		assert_eq!(navmesh.get_height(0.5, 0.5), None);
+		let (start, end) = (Point3::new(-3.5, 0.0, 0.5), Point3::new(4.5, 0.0, 0.5));
+		let path = navmesh.find_path(start, end).unwrap();
+		assert_eq!(path.first(), Some(&start));
+		assert_eq!(path.last(), Some(&end));
+
+		// Around two corners of the blocked cell, on either side
+		assert_eq!(path.len(), 4);
+		for corner in &path[1..3]
+		{
+			assert!(corner.x == 0.0 || corner.x == 1.0, "{:?} is not a corner", corner);
+			assert!(corner.z == 0.0 || corner.z == 1.0, "{:?} is not a corner", corner);
+		}
+		let around = 2.0 * Point2::new(-3.5, 0.5).distance(Point2::new(0.0, 0.0)) + 1.0;
+		assert!((path_length(&path) - around).abs() < 1e-4);
+
+		// No part of the path crosses the blocked cell
+		for pair in path.windows(2)
+		{
+			for step in 0..=100
+			{
+				let point = pair[0] + (pair[1] - pair[0]) * (step as f32 / 100.0);
+				let inside = point.x > 1e-4 && point.x < 1.0 - 1e-4 && point.z > 1e-4 && point.z < 1.0 - 1e-4;
+				assert!(!inside, "{:?} is in the blocked cell", point);
+			}
+		}
+	}
+
+	#[test]
+	fn unreachable_goal_has_no_path()
+	{
+		// A wall across the whole grid
+		let navmesh = build_grid(&[([-5.0, 0.0], [5.0, 1.0])]);
+		let start = Point3::new(-2.5, 0.0, -2.5);
+		assert_eq!(navmesh.find_path(start, Point3::new(2.5, 0.0, 2.5)), None);
+		// Inside the wall, and outside the navmesh
+		assert_eq!(navmesh.find_path(start, Point3::new(2.5, 0.0, 0.5)), None);
+		assert_eq!(navmesh.find_path(start, Point3::new(7.5, 0.0, -2.5)), None);
+		// The same side of the wall is still reachable
+		assert!(navmesh.find_path(start, Point3::new(2.5, 0.0, -4.5)).is_some());
+	}
+
+	#[test]
+	fn start_and_goal_in_same_cell()
+	{
+		let navmesh = build_grid(&[([0.0, 0.0], [1.0, 1.0])]);
+		let (start, end) = (Point3::new(2.2, 0.0, 2.2), Point3::new(2.7, 0.0, 2.4));
+		assert_eq!(navmesh.find_path(start, end), Some(vec![start, end]));
+		// Both endpoints are the same point
+		assert_eq!(navmesh.find_path(start, start), Some(vec![start]));
+	}
+}
@@ -0,0 +1,151 @@
+use crate::core::{ParticleVertex, Rng};
+use crate::renderer::{LineBatch, MainPass, RenderState};
+use ash::vk;
+use cgmath::{Matrix4, Point3, Vector3};
+
+// Random change of the particles' velocity, in m/s
+const VELOCITY_JITTER: f32 = 0.3;
+// Random change of the particles' color, relative
+const COLOR_JITTER: f32 = 0.1;
+
+struct Particle
+{
+	position: Point3<f32>,
+	velocity: Vector3<f32>,
+	color: [f32; 4],
+}
+
+/// Simulates and draws particles as streaks (lines along their velocity).
+///
+/// Particles live inside a box around an anchor point, and wrap around when leaving it. This makes the emitter
+/// suitable for effects that should follow the camera, like rain.
+pub struct ParticleEmitter
+{
+	particles: Vec<Particle>,
+	active_count: usize,
+	anchor: Point3<f32>,
+	half_extents: Vector3<f32>,
+	base_velocity: Vector3<f32>,
+	streak_duration: f32,
+	color: [f32; 4],
+	rng: Rng,
+
+	// One line per particle
+	lines: LineBatch,
+}
+
+impl ParticleEmitter
+{
+	pub fn new(
+		rs: &RenderState, rng: Rng, max_particles: usize, half_extents: Vector3<f32>, base_velocity: Vector3<f32>,
+		streak_duration: f32, color: [f32; 4],
+	) -> ParticleEmitter
+	{
+		let mut emitter = ParticleEmitter {
+			particles: Vec::with_capacity(max_particles),
+			active_count: 0,
+			anchor: Point3::new(0.0, 0.0, 0.0),
+			half_extents: half_extents,
+			base_velocity: base_velocity,
+			streak_duration: streak_duration,
+			color: color,
+			rng: rng,
+			lines: LineBatch::new(rs, max_particles),
+		};
+		for _ in 0..max_particles
+		{
+			let position = emitter.random_point_in_volume();
+			let velocity = emitter.random_velocity();
+			let color = emitter.rng.jitter_color(emitter.color, COLOR_JITTER);
+			emitter.particles.push(Particle {
+				position: position,
+				velocity: velocity,
+				color: color,
+			});
+		}
+		emitter
+	}
+
+	fn random_point_in_volume(&mut self) -> Point3<f32>
+	{
+		let x = self.rng.range(-1.0, 1.0) * self.half_extents.x;
+		let y = self.rng.range(-1.0, 1.0) * self.half_extents.y;
+		let z = self.rng.range(-1.0, 1.0) * self.half_extents.z;
+		self.anchor + Vector3::new(x, y, z)
+	}
+
+	fn random_velocity(&mut self) -> Vector3<f32>
+	{
+		// Speed and direction variation keeps the streaks from moving in lockstep
+		self.base_velocity * self.rng.range(0.8, 1.2) + self.rng.unit_vector() * VELOCITY_JITTER
+	}
+
+	/// Moves the volume the particles live in.
+	pub fn set_anchor(&mut self, anchor: Point3<f32>)
+	{
+		self.anchor = anchor;
+	}
+
+	/// Sets how large part of the particles are simulated and drawn, in the range [0, 1].
+	pub fn set_active_fraction(&mut self, fraction: f32)
+	{
+		let fraction = fraction.max(0.0).min(1.0);
+		self.active_count = (self.particles.len() as f32 * fraction) as usize;
+	}
+
+	pub fn update(&mut self, timestep: f32)
+	{
+		for idx in 0..self.active_count
+		{
+			let mut position = self.particles[idx].position + self.particles[idx].velocity * timestep;
+			let offset = position - self.anchor;
+
+			// Particles falling below the volume (or the ground) respawn at the top
+			if offset.y < -self.half_extents.y || position.y < 0.0
+			{
+				position = self.random_point_in_volume();
+				position.y = self.anchor.y + self.half_extents.y;
+				self.particles[idx].velocity = self.random_velocity();
+			}
+			// Wrap horizontally so the volume follows the anchor
+			else
+			{
+				if offset.x.abs() > self.half_extents.x
+				{
+					position.x -= 2.0 * self.half_extents.x * offset.x.signum();
+				}
+				if offset.z.abs() > self.half_extents.z
+				{
+					position.z -= 2.0 * self.half_extents.z * offset.z.signum();
+				}
+			}
+			self.particles[idx].position = position;
+		}
+	}
+
+	/// Draws the active particles. Binds the particle pipeline, so call this after drawing opaque geometry.
+	pub fn draw(
+		&mut self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>,
+	)
+	{
+		let mut vertices = Vec::with_capacity(2 * self.active_count);
+		for particle in self.particles[..self.active_count].iter()
+		{
+			let tail = particle.position - particle.velocity * self.streak_duration;
+			let mut tail_color = particle.color;
+			tail_color[3] = 0.0;
+			vertices.push(ParticleVertex {
+				pos: [particle.position.x, particle.position.y, particle.position.z],
+				color: particle.color,
+			});
+			vertices.push(ParticleVertex {
+				pos: [tail.x, tail.y, tail.z],
+				color: tail_color,
+			});
+		}
+
+		self.lines.set_lines(&vertices);
+		self.lines.draw(cmd_buf, mp, view_matrix, projection_matrix);
+	}
+}
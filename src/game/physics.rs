@@ -0,0 +1,597 @@
+use crate::core::BoundingBox;
+use crate::game::ConvexHull;
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector3};
+use std::collections::HashSet;
+
+// Passes over the contacts each step, so stacked and wedged bodies settle
+const SOLVER_ITERATIONS: usize = 4;
+// Overlap left alone, so resting contacts do not jitter, and the fraction of the rest pushed apart per pass
+const PENETRATION_SLOP: f32 = 0.005;
+const PENETRATION_CORRECTION: f32 = 0.8;
+// Contacts closing slower than this, in m/s, do not bounce, so resting bodies stay at rest
+const BOUNCE_MIN_SPEED: f32 = 1.0;
+
+/// How the properties of two materials in contact are combined. When the two materials use different rules, the one
+/// furthest down the list is used.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum CombineRule
+{
+	Average,
+	Minimum,
+	Multiply,
+	Maximum,
+}
+
+impl CombineRule
+{
+	fn apply(self, a: f32, b: f32) -> f32
+	{
+		match self
+		{
+			CombineRule::Average => 0.5 * (a + b),
+			CombineRule::Minimum => a.min(b),
+			CombineRule::Multiply => a * b,
+			CombineRule::Maximum => a.max(b),
+		}
+	}
+}
+
+/// Surface properties of a collider.
+#[derive(Clone, Copy)]
+pub struct PhysicsMaterial
+{
+	/// Bounciness, 0 keeps objects together after a hit and 1 is a perfectly elastic bounce.
+	pub restitution: f32,
+	/// Friction coefficient keeping the surfaces from starting to slide.
+	pub static_friction: f32,
+	/// Friction coefficient while sliding.
+	pub dynamic_friction: f32,
+	pub friction_combine: CombineRule,
+	pub restitution_combine: CombineRule,
+}
+
+impl PhysicsMaterial
+{
+	/// Returns the material used for a contact between the two materials.
+	pub fn combine(&self, other: &PhysicsMaterial) -> PhysicsMaterial
+	{
+		let friction_combine = if self.friction_combine > other.friction_combine
+		{
+			self.friction_combine
+		}
+		else
+		{
+			other.friction_combine
+		};
+		let restitution_combine = if self.restitution_combine > other.restitution_combine
+		{
+			self.restitution_combine
+		}
+		else
+		{
+			other.restitution_combine
+		};
+		PhysicsMaterial {
+			restitution: restitution_combine.apply(self.restitution, other.restitution),
+			static_friction: friction_combine.apply(self.static_friction, other.static_friction),
+			dynamic_friction: friction_combine.apply(self.dynamic_friction, other.dynamic_friction),
+			friction_combine: friction_combine,
+			restitution_combine: restitution_combine,
+		}
+	}
+}
+
+/// A sphere or convex hull taking part in contact detection, as of the current tick.
+pub struct Collider
+{
+	/// Chosen by the owner, and must stay the same between ticks for contacts to persist.
+	pub id: u32,
+	pub position: Point3<f32>,
+	pub velocity: Vector3<f32>,
+	/// With a hull, the radius of a sphere around the position containing it.
+	pub radius: f32,
+	/// Shape in world space, or None for a sphere. Contacts between two hulls use their bounding spheres.
+	pub hull: Option<ConvexHull>,
+	/// Use infinity for colliders that are not moved by impacts.
+	pub mass: f32,
+	pub material: PhysicsMaterial,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ContactPhase
+{
+	/// The colliders started touching this tick.
+	Begin,
+	/// The colliders are still touching.
+	Persist,
+	/// The colliders stopped touching, or one of them is gone.
+	End,
+}
+
+/// A contact between two colliders. The normal points from the first collider towards the second.
+///
+/// Impulses are what it takes to resolve the contact with the combined material, in Ns. They are zero when the
+/// colliders are not moving towards each other, and for ended contacts.
+pub struct ContactEvent
+{
+	pub phase: ContactPhase,
+	pub ids: (u32, u32),
+	pub position: Point3<f32>,
+	pub normal: Vector3<f32>,
+	pub normal_impulse: f32,
+	pub friction_impulse: f32,
+}
+
+/// Finds contacts between colliders every tick, and reports them as a stream of events.
+///
+/// There is no collision response, gameplay systems decide what a contact does from the events.
+pub struct ContactWorld
+{
+	// Pairs of ids touching last tick, lowest id first
+	contacts: HashSet<(u32, u32)>,
+	events: Vec<ContactEvent>,
+}
+
+impl ContactWorld
+{
+	pub fn new() -> ContactWorld
+	{
+		ContactWorld {
+			contacts: HashSet::new(),
+			events: Vec::new(),
+		}
+	}
+
+	/// Finds the contacts between the given colliders, and queues events for them.
+	pub fn step(&mut self, colliders: &[Collider])
+	{
+		let mut contacts = HashSet::with_capacity(self.contacts.len());
+		for (idx, a) in colliders.iter().enumerate()
+		{
+			for b in colliders[idx + 1..].iter()
+			{
+				let (a, b) = if a.id < b.id
+				{
+					(a, b)
+				}
+				else
+				{
+					(b, a)
+				};
+				let (position, normal) = match ContactWorld::find_contact(a, b)
+				{
+					Some(contact) => contact,
+					None => continue,
+				};
+				let pair = (a.id, b.id);
+				let phase = if self.contacts.contains(&pair)
+				{
+					ContactPhase::Persist
+				}
+				else
+				{
+					ContactPhase::Begin
+				};
+				contacts.insert(pair);
+
+				let (normal_impulse, friction_impulse) = ContactWorld::get_impulses(a, b, normal);
+				self.events.push(ContactEvent {
+					phase: phase,
+					ids: pair,
+					position: position,
+					normal: normal,
+					normal_impulse: normal_impulse,
+					friction_impulse: friction_impulse,
+				});
+			}
+		}
+		// Sorted, as the iteration order of hash sets differs between runs
+		let mut ended: Vec<(u32, u32)> = self.contacts.difference(&contacts).cloned().collect();
+		ended.sort();
+		for pair in ended
+		{
+			self.events.push(ContactEvent {
+				phase: ContactPhase::End,
+				ids: pair,
+				position: Point3::origin(),
+				normal: Vector3::zero(),
+				normal_impulse: 0.0,
+				friction_impulse: 0.0,
+			});
+		}
+		self.contacts = contacts;
+	}
+
+	/// Returns the position and normal, pointing from a to b, of the contact between the colliders, if they touch.
+	fn find_contact(a: &Collider, b: &Collider) -> Option<(Point3<f32>, Vector3<f32>)>
+	{
+		let offset = b.position - a.position;
+		let distance = offset.magnitude();
+		if distance >= a.radius + b.radius
+		{
+			return None;
+		}
+		match (&a.hull, &b.hull)
+		{
+			(None, Some(hull)) => return ContactWorld::find_hull_contact(a.position, a.radius, hull),
+			(Some(hull), None) =>
+			{
+				return ContactWorld::find_hull_contact(b.position, b.radius, hull)
+					.map(|(position, normal)| (position, -normal))
+			}
+			_ => (),
+		}
+		let normal = if distance > 0.0
+		{
+			offset / distance
+		}
+		else
+		{
+			Vector3::unit_y()
+		};
+		return Some((a.position + normal * a.radius, normal));
+	}
+
+	/// Returns the position and normal, pointing from the sphere towards the hull, of the contact between a sphere and
+	/// a hull, if they touch. The position is on the surface of the hull.
+	fn find_hull_contact(center: Point3<f32>, radius: f32, hull: &ConvexHull) -> Option<(Point3<f32>, Vector3<f32>)>
+	{
+		let (distance, normal) = hull.get_distance(center);
+		if distance >= radius
+		{
+			return None;
+		}
+		return Some((center - normal * distance, -normal));
+	}
+
+	/// Returns the normal and friction impulses of a contact, with the normal pointing from a to b.
+	fn get_impulses(a: &Collider, b: &Collider, normal: Vector3<f32>) -> (f32, f32)
+	{
+		let material = a.material.combine(&b.material);
+		return get_impulses(b.velocity - a.velocity, normal, 1.0 / a.mass + 1.0 / b.mass, &material);
+	}
+
+	/// Returns the events since the last call.
+	pub fn drain_events(&mut self) -> Vec<ContactEvent>
+	{
+		return std::mem::replace(&mut self.events, Vec::new());
+	}
+}
+
+/// Returns the normal and friction impulses resolving a contact, given the velocity of the second body relative to the
+/// first, the normal pointing from the first to the second and the sum of their inverse masses.
+fn get_impulses(
+	relative_velocity: Vector3<f32>, normal: Vector3<f32>, inverse_mass: f32, material: &PhysicsMaterial,
+) -> (f32, f32)
+{
+	let normal_speed = relative_velocity.dot(normal);
+	if normal_speed >= 0.0 || inverse_mass == 0.0
+	{
+		return (0.0, 0.0);
+	}
+	let normal_impulse = -(1.0 + material.restitution) * normal_speed / inverse_mass;
+
+	// Coulomb friction, stopping the sliding if static friction is enough
+	let tangent_speed = (relative_velocity - normal * normal_speed).magnitude();
+	let mut friction_impulse = tangent_speed / inverse_mass;
+	if friction_impulse > material.static_friction * normal_impulse
+	{
+		friction_impulse = material.dynamic_friction * normal_impulse;
+	}
+	return (normal_impulse, friction_impulse);
+}
+
+/// A box in any orientation, for colliding rigid bodies.
+#[derive(Clone, Copy)]
+pub struct OrientedBox
+{
+	pub center: Point3<f32>,
+	/// Directions of the sides of the box, normalized.
+	pub axes: [Vector3<f32>; 3],
+	/// Half the size of the box along each axis.
+	pub half_extents: [f32; 3],
+}
+
+impl OrientedBox
+{
+	/// Returns the box around bounds in mesh space, placed by a model matrix of a translation, a rotation and a scale.
+	pub fn from_bounds(bounds: &BoundingBox, model_matrix: &Matrix4<f32>) -> OrientedBox
+	{
+		let half_size = bounds.get_extents() * 0.5;
+		let mut axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+		let mut half_extents = [0.0; 3];
+		for idx in 0..3
+		{
+			let column = model_matrix[idx].truncate();
+			let length = column.magnitude();
+			if length > 0.0
+			{
+				axes[idx] = column / length;
+			}
+			half_extents[idx] = half_size[idx] * length;
+		}
+		return OrientedBox {
+			center: model_matrix.transform_point(bounds.get_center()),
+			axes: axes,
+			half_extents: half_extents,
+		};
+	}
+
+	/// Returns how far the box reaches from its center along the normalized axis.
+	fn get_reach(&self, axis: Vector3<f32>) -> f32
+	{
+		return (0..3).map(|idx| self.half_extents[idx] * self.axes[idx].dot(axis).abs()).sum();
+	}
+
+	/// Returns the corner of the box furthest along the direction.
+	pub fn get_support(&self, direction: Vector3<f32>) -> Point3<f32>
+	{
+		let mut corner = self.center;
+		for idx in 0..3
+		{
+			let side = if self.axes[idx].dot(direction) < 0.0
+			{
+				-self.half_extents[idx]
+			}
+			else
+			{
+				self.half_extents[idx]
+			};
+			corner += self.axes[idx] * side;
+		}
+		return corner;
+	}
+
+	/// Returns the world space axis aligned box around the box.
+	pub fn get_bounds(&self) -> BoundingBox
+	{
+		let reach = Vector3::new(
+			self.get_reach(Vector3::unit_x()),
+			self.get_reach(Vector3::unit_y()),
+			self.get_reach(Vector3::unit_z()),
+		);
+		return BoundingBox {
+			min: self.center - reach,
+			max: self.center + reach,
+		};
+	}
+
+	/// Returns the normal, pointing from this box towards the other, and the depth of the overlap along it, if the
+	/// boxes overlap. Tests the separating axes: the sides of both boxes and the crossings of their edges.
+	pub fn intersect(&self, other: &OrientedBox) -> Option<(Vector3<f32>, f32)>
+	{
+		let offset = other.center - self.center;
+		let mut axes = Vec::with_capacity(15);
+		axes.extend_from_slice(&self.axes);
+		axes.extend_from_slice(&other.axes);
+		for a in self.axes.iter()
+		{
+			for b in other.axes.iter()
+			{
+				axes.push(a.cross(*b));
+			}
+		}
+
+		let mut shallowest: Option<(Vector3<f32>, f32)> = None;
+		for axis in axes
+		{
+			// Crossings of parallel edges are no axis, and are covered by the sides
+			let length = axis.magnitude();
+			if length < 1e-4
+			{
+				continue;
+			}
+			let axis = axis / length;
+			let distance = offset.dot(axis);
+			let depth = self.get_reach(axis) + other.get_reach(axis) - distance.abs();
+			if depth <= 0.0
+			{
+				return None;
+			}
+			if shallowest.map_or(true, |(_, shallowest_depth)| depth < shallowest_depth)
+			{
+				let normal = if distance < 0.0
+				{
+					-axis
+				}
+				else
+				{
+					axis
+				};
+				shallowest = Some((normal, depth));
+			}
+		}
+		return shallowest;
+	}
+}
+
+/// A box moved by collisions, or pushing others out of the way with an infinite mass.
+pub struct RigidBody
+{
+	/// Chosen by the owner, and must stay the same between steps for impacts to be reported once.
+	pub id: u32,
+	pub shape: OrientedBox,
+	pub velocity: Vector3<f32>,
+	/// Use infinity for static bodies, and kinematic ones moving by themselves.
+	pub mass: f32,
+	pub material: PhysicsMaterial,
+}
+
+impl RigidBody
+{
+	fn get_inverse_mass(&self) -> f32
+	{
+		return 1.0 / self.mass;
+	}
+}
+
+/// Two bodies starting to touch in a step. The normal points from the first body, the one with the lowest id, towards
+/// the second.
+pub struct Impact
+{
+	pub ids: (u32, u32),
+	pub position: Point3<f32>,
+	pub normal: Vector3<f32>,
+	/// The normal and friction impulses applied to resolve the impact, in Ns.
+	pub impulse: f32,
+}
+
+/// Applies gravity to rigid bodies, and resolves their collisions with each other and with a ground plane by impulses
+/// and by pushing them apart. Stepped once per engine tick.
+///
+/// Bodies are integrated by their owners, the step changes their velocities and pushes their positions out of
+/// contacts, for the owners to take back.
+pub struct RigidBodyWorld
+{
+	pub gravity: Vector3<f32>,
+	pub ground_height: f32,
+	pub ground_material: PhysicsMaterial,
+	// Pairs of ids touching last step, lowest id first
+	contacts: HashSet<(u32, u32)>,
+}
+
+impl RigidBodyWorld
+{
+	pub fn new(gravity: Vector3<f32>, ground_height: f32, ground_material: PhysicsMaterial) -> RigidBodyWorld
+	{
+		RigidBodyWorld {
+			gravity: gravity,
+			ground_height: ground_height,
+			ground_material: ground_material,
+			contacts: HashSet::new(),
+		}
+	}
+
+	/// Steps the bodies, and returns the impacts between those starting to touch. Bodies with infinite masses are not
+	/// changed, and never collide with each other or the ground.
+	pub fn step(&mut self, bodies: &mut [RigidBody], timestep: f32) -> Vec<Impact>
+	{
+		for body in bodies.iter_mut().filter(|body| body.mass.is_finite())
+		{
+			body.velocity += self.gravity * timestep;
+		}
+
+		let mut contacts = HashSet::with_capacity(self.contacts.len());
+		let mut impacts = Vec::new();
+		for iteration in 0..SOLVER_ITERATIONS
+		{
+			for idx in 0..bodies.len()
+			{
+				if bodies[idx].mass.is_finite()
+				{
+					self.resolve_ground_contact(&mut bodies[idx]);
+				}
+				for other_idx in idx + 1..bodies.len()
+				{
+					let (head, tail) = bodies.split_at_mut(other_idx);
+					let (a, b) = if head[idx].id < tail[0].id
+					{
+						(&mut head[idx], &mut tail[0])
+					}
+					else
+					{
+						(&mut tail[0], &mut head[idx])
+					};
+					if !a.mass.is_finite() && !b.mass.is_finite()
+					{
+						continue;
+					}
+					// Broad phase on the axis aligned bounds, before the separating axis test
+					if !a.shape.get_bounds().intersects(&b.shape.get_bounds())
+					{
+						continue;
+					}
+					let (normal, depth) = match a.shape.intersect(&b.shape)
+					{
+						Some(contact) => contact,
+						None => continue,
+					};
+					// The deepest corner of b, halfway out of a
+					let position = b.shape.get_support(-normal) + normal * (0.5 * depth);
+					let impulse = RigidBodyWorld::resolve_contact(a, b, normal, depth);
+
+					let pair = (a.id, b.id);
+					if iteration == 0
+					{
+						if !self.contacts.contains(&pair)
+						{
+							impacts.push(Impact {
+								ids: pair,
+								position: position,
+								normal: normal,
+								impulse: impulse,
+							});
+						}
+						contacts.insert(pair);
+					}
+				}
+			}
+		}
+		self.contacts = contacts;
+		return impacts;
+	}
+
+	/// Pushes a body out of the ground, and stops it from moving into it.
+	fn resolve_ground_contact(&self, body: &mut RigidBody)
+	{
+		let depth = self.ground_height - body.shape.get_bounds().min.y;
+		if depth <= 0.0
+		{
+			return;
+		}
+		body.shape.center.y += (depth - PENETRATION_SLOP).max(0.0) * PENETRATION_CORRECTION;
+		let normal = Vector3::unit_y();
+		let material = self.ground_material.combine(&body.material);
+		let (normal_impulse, friction_impulse) =
+			RigidBodyWorld::get_impulses(body.velocity, normal, body.get_inverse_mass(), material);
+		body.velocity += RigidBodyWorld::get_impulse_vector(body.velocity, normal, normal_impulse, friction_impulse) *
+			body.get_inverse_mass();
+	}
+
+	/// Pushes two overlapping bodies apart and changes their velocities so they stop moving into each other. Returns
+	/// the impulse applied.
+	fn resolve_contact(a: &mut RigidBody, b: &mut RigidBody, normal: Vector3<f32>, depth: f32) -> f32
+	{
+		let inverse_mass = a.get_inverse_mass() + b.get_inverse_mass();
+		let correction = normal * ((depth - PENETRATION_SLOP).max(0.0) * PENETRATION_CORRECTION / inverse_mass);
+		a.shape.center -= correction * a.get_inverse_mass();
+		b.shape.center += correction * b.get_inverse_mass();
+
+		let relative_velocity = b.velocity - a.velocity;
+		let material = a.material.combine(&b.material);
+		let (normal_impulse, friction_impulse) =
+			RigidBodyWorld::get_impulses(relative_velocity, normal, inverse_mass, material);
+		let impulse = RigidBodyWorld::get_impulse_vector(relative_velocity, normal, normal_impulse, friction_impulse);
+		a.velocity -= impulse * a.get_inverse_mass();
+		b.velocity += impulse * b.get_inverse_mass();
+		return normal_impulse + friction_impulse;
+	}
+
+	/// Returns the normal and friction impulses of a contact, without bouncing slow contacts.
+	fn get_impulses(
+		relative_velocity: Vector3<f32>, normal: Vector3<f32>, inverse_mass: f32, mut material: PhysicsMaterial,
+	) -> (f32, f32)
+	{
+		if relative_velocity.dot(normal) > -BOUNCE_MIN_SPEED
+		{
+			material.restitution = 0.0;
+		}
+		return get_impulses(relative_velocity, normal, inverse_mass, &material);
+	}
+
+	/// Returns the impulse on the second body of a contact, the friction opposing the sliding.
+	fn get_impulse_vector(
+		relative_velocity: Vector3<f32>, normal: Vector3<f32>, normal_impulse: f32, friction_impulse: f32,
+	) -> Vector3<f32>
+	{
+		let tangent_velocity = relative_velocity - normal * relative_velocity.dot(normal);
+		let tangent_speed = tangent_velocity.magnitude();
+		let mut impulse = normal * normal_impulse;
+		if tangent_speed > 0.0
+		{
+			impulse -= tangent_velocity * (friction_impulse / tangent_speed);
+		}
+		return impulse;
+	}
+}
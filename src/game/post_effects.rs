@@ -0,0 +1,66 @@
+use crate::core::Console;
+
+/// Stylistic post effects, each toggled and tuned from the console.
+///
+/// Getters return 0 for disabled effects, which skips them when presenting.
+pub struct PostEffects
+{
+	chromatic_aberration: f32,
+	film_grain: f32,
+	vignette: f32,
+}
+
+impl PostEffects
+{
+	pub fn new(console: &mut Console) -> PostEffects
+	{
+		console.register_bool("post_chromatic_aberration", false, "Split colors towards the screen edges");
+		console.register_float(
+			"post_chromatic_aberration_strength",
+			0.004,
+			"Color split at the screen corners, as a fraction of the screen height",
+		);
+		console.register_bool("post_film_grain", false, "Animated film grain");
+		console.register_float("post_film_grain_strength", 0.06, "Brightness variation of the film grain");
+		console.register_bool("post_vignette", false, "Darken the screen towards the corners");
+		console.register_float("post_vignette_strength", 0.4, "Darkening at the screen corners [0, 1]");
+
+		PostEffects {
+			chromatic_aberration: 0.0,
+			film_grain: 0.0,
+			vignette: 0.0,
+		}
+	}
+
+	pub fn update(&mut self, console: &Console)
+	{
+		let read = |toggle: &str, strength: &str| {
+			if console.get_bool(toggle)
+			{
+				console.get_float(strength).max(0.0)
+			}
+			else
+			{
+				0.0
+			}
+		};
+		self.chromatic_aberration = read("post_chromatic_aberration", "post_chromatic_aberration_strength");
+		self.film_grain = read("post_film_grain", "post_film_grain_strength");
+		self.vignette = read("post_vignette", "post_vignette_strength").min(1.0);
+	}
+
+	pub fn get_chromatic_aberration(&self) -> f32
+	{
+		return self.chromatic_aberration;
+	}
+
+	pub fn get_film_grain(&self) -> f32
+	{
+		return self.film_grain;
+	}
+
+	pub fn get_vignette(&self) -> f32
+	{
+		return self.vignette;
+	}
+}
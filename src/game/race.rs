@@ -0,0 +1,396 @@
+use crate::core::{Console, Transformable};
+use crate::game::Agent;
+use crate::renderer::{Icon, Overlay, TextStyle};
+use cgmath::prelude::*;
+use cgmath::Point3;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// The AI reaches its full rubber-banding speed change this many checkpoints behind or ahead of the player
+const RUBBER_BAND_CHECKPOINTS: f32 = 2.0;
+const MAX_RUBBER_BANDING: f32 = 0.9;
+
+const PANEL_POSITION: (f32, f32) = (440.0, 40.0);
+const TEXT_SIZE: f32 = 14.0;
+const LINE_SPACING: f32 = 20.0;
+const CHECKPOINT_ICON_SIZE: f32 = 20.0;
+
+const TITLE_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+const PLAYER_COLOR: [f32; 4] = [0.3, 0.9, 1.0, 1.0];
+const CHECKPOINT_COLOR: [f32; 4] = [0.3, 1.0, 0.4, 0.9];
+
+/// A checkpoint of the race course, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CheckpointSettings
+{
+	pub position: [f32; 3],
+	/// Distance from the position at which the checkpoint counts as passed, in meters.
+	#[serde(default = "CheckpointSettings::default_radius")]
+	pub radius: f32,
+}
+
+impl CheckpointSettings
+{
+	fn default_radius() -> f32
+	{
+		return 4.0;
+	}
+}
+
+/// The race course, as given in the scene file. The first checkpoint is the start and finish line, and checkpoints
+/// are passed in order.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RaceSettings
+{
+	pub checkpoints: Vec<CheckpointSettings>,
+	pub laps: u32,
+}
+
+impl Default for RaceSettings
+{
+	fn default() -> RaceSettings
+	{
+		RaceSettings {
+			checkpoints: Vec::new(),
+			laps: 3,
+		}
+	}
+}
+
+struct Racer
+{
+	name: String,
+	// None for the player's car
+	agent: Option<usize>,
+	// Checkpoints passed since the start, not counting the start line
+	passed: usize,
+	// How far the racer has come from the last checkpoint towards the next, in the range [0, 1]
+	progress: f32,
+	lap_start: f32,
+	best_lap: Option<f32>,
+	finish_time: Option<f32>,
+}
+
+impl Racer
+{
+	fn new(name: String, agent: Option<usize>) -> Racer
+	{
+		Racer {
+			name: name,
+			agent: agent,
+			passed: 0,
+			progress: 0.0,
+			lap_start: 0.0,
+			best_lap: None,
+			finish_time: None,
+		}
+	}
+
+	/// Returns how far the racer has come, in checkpoints.
+	fn get_score(&self) -> f32
+	{
+		return self.passed as f32 + self.progress;
+	}
+}
+
+/// A racer's place in the race, see RaceManager::get_standings().
+pub struct Standing
+{
+	pub name: String,
+	pub is_player: bool,
+	/// Lap the racer is on, starting at 1.
+	pub lap: u32,
+	pub best_lap: Option<f32>,
+	/// Race time at which the racer finished, if it has.
+	pub finish_time: Option<f32>,
+}
+
+/// Formats a time in seconds as minutes, seconds and tenths.
+pub fn format_time(seconds: f32) -> String
+{
+	return format!("{}:{:04.1}", (seconds / 60.0) as u32, seconds % 60.0);
+}
+
+/// Follows the player's car and the racing AI agents through the checkpoints of the race course, keeping track of
+/// their laps and standings. AI racers are steered towards their next checkpoint, and optionally rubber-banded to
+/// stay close to the player.
+pub struct RaceManager
+{
+	checkpoints: Vec<CheckpointSettings>,
+	laps: u32,
+	racers: Vec<Racer>,
+	// Seconds since the start of the race
+	time: f32,
+}
+
+impl RaceManager
+{
+	pub fn new(settings: &RaceSettings, console: &mut Console) -> RaceManager
+	{
+		console.register_float(
+			"race_rubber_banding",
+			0.0,
+			"How much AI racers speed up when behind the player, and slow down when ahead [0, 0.9]",
+		);
+
+		RaceManager {
+			checkpoints: settings.checkpoints.clone(),
+			laps: settings.laps.max(1),
+			racers: Vec::new(),
+			time: 0.0,
+		}
+	}
+
+	/// Replaces the race course, stopping the current race.
+	pub fn apply_settings(&mut self, settings: &RaceSettings)
+	{
+		self.checkpoints = settings.checkpoints.clone();
+		self.laps = settings.laps.max(1);
+		self.stop();
+	}
+
+	/// Starts a new race between the player's car and the agents with the race behavior.
+	pub fn start(&mut self, agents: &[Agent])
+	{
+		self.stop();
+		if self.checkpoints.len() < 2
+		{
+			warning!("A race needs at least two checkpoints");
+			return;
+		}
+		self.racers.push(Racer::new("Player".to_string(), None));
+		for (idx, _) in agents.iter().enumerate().filter(|(_, agent)| agent.is_racer())
+		{
+			self.racers.push(Racer::new(format!("Agent {}", idx), Some(idx)));
+		}
+	}
+
+	pub fn stop(&mut self)
+	{
+		self.racers.clear();
+		self.time = 0.0;
+	}
+
+	fn get_checkpoint(&self, passed: usize) -> Point3<f32>
+	{
+		return Point3::from(self.checkpoints[passed % self.checkpoints.len()].position);
+	}
+
+	/// Counts the checkpoints passed since the last update, and steers the AI racers towards their next checkpoint.
+	/// Agents that are not racing are left alone.
+	pub fn update(&mut self, console: &Console, car_position: Point3<f32>, agents: &mut [Agent], timestep: f32)
+	{
+		if self.racers.is_empty()
+		{
+			for agent in agents.iter_mut().filter(|agent| agent.is_racer())
+			{
+				agent.set_race_target(None);
+				agent.set_speed_scale(1.0);
+			}
+			return;
+		}
+		self.time += timestep;
+
+		let total_checkpoints = self.laps as usize * self.checkpoints.len();
+		for racer_idx in 0..self.racers.len()
+		{
+			let position = match self.racers[racer_idx].agent
+			{
+				Some(agent_idx) => match agents.get(agent_idx)
+				{
+					Some(agent) => agent.get_position(),
+					None => continue,
+				},
+				None => car_position,
+			};
+			let racer = &self.racers[racer_idx];
+			if racer.finish_time.is_some()
+			{
+				continue;
+			}
+
+			let next_idx = (racer.passed + 1) % self.checkpoints.len();
+			let next = &self.checkpoints[next_idx];
+			let to_next = position.distance(Point3::from(next.position));
+			let passed = to_next < next.radius;
+			let to_last = position.distance(self.get_checkpoint(racer.passed));
+			let time = self.time;
+
+			let racer = &mut self.racers[racer_idx];
+			racer.progress = (to_last / (to_last + to_next).max(1e-6)).min(1.0);
+			if !passed
+			{
+				continue;
+			}
+			racer.passed += 1;
+			racer.progress = 0.0;
+			if next_idx == 0
+			{
+				let lap_time = time - racer.lap_start;
+				racer.best_lap = Some(racer.best_lap.map_or(lap_time, |best| best.min(lap_time)));
+				racer.lap_start = time;
+				if racer.passed >= total_checkpoints
+				{
+					racer.finish_time = Some(time);
+				}
+			}
+		}
+
+		let rubber_banding = console.get_float("race_rubber_banding").max(0.0).min(MAX_RUBBER_BANDING);
+		let player_score = self.racers[0].get_score();
+		for racer in &self.racers[1..]
+		{
+			let agent = match racer.agent.and_then(|agent_idx| agents.get_mut(agent_idx))
+			{
+				Some(agent) => agent,
+				None => continue,
+			};
+			if racer.finish_time.is_some()
+			{
+				agent.set_race_target(None);
+				agent.set_speed_scale(1.0);
+				continue;
+			}
+			agent.set_race_target(Some(self.get_checkpoint(racer.passed + 1)));
+			// The player finishing ends the rubber-banding, so the AI races the rest on its own
+			let gap = if self.racers[0].finish_time.is_some()
+			{
+				0.0
+			}
+			else
+			{
+				player_score - racer.get_score()
+			};
+			let catch_up = (gap / RUBBER_BAND_CHECKPOINTS).max(-1.0).min(1.0);
+			agent.set_speed_scale(1.0 + rubber_banding * catch_up);
+		}
+	}
+
+	/// Returns the racers ordered by their place in the race. Finished racers are ordered by their finish time, the
+	/// others by how far they have come.
+	pub fn get_standings(&self) -> Vec<Standing>
+	{
+		let mut order: Vec<&Racer> = self.racers.iter().collect();
+		order.sort_by(|a, b| match (a.finish_time, b.finish_time)
+		{
+			(Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+			(Some(_), None) => Ordering::Less,
+			(None, Some(_)) => Ordering::Greater,
+			(None, None) => b.get_score().partial_cmp(&a.get_score()).unwrap_or(Ordering::Equal),
+		});
+		return order
+			.iter()
+			.map(|racer| Standing {
+				name: racer.name.clone(),
+				is_player: racer.agent.is_none(),
+				lap: (racer.passed / self.checkpoints.len()) as u32 + 1,
+				best_lap: racer.best_lap,
+				finish_time: racer.finish_time,
+			})
+			.collect();
+	}
+
+	/// Prints the standings, for the race_standings command.
+	pub fn print_standings(&self)
+	{
+		if self.racers.is_empty()
+		{
+			println!("No race is running, start one with race_start");
+			return;
+		}
+		for (place, standing) in self.get_standings().iter().enumerate()
+		{
+			let best_lap = standing.best_lap.map_or("-".to_string(), format_time);
+			match standing.finish_time
+			{
+				Some(time) => println!(
+					"{}. {}  finished in {}  best lap {}",
+					place + 1,
+					standing.name,
+					format_time(time),
+					best_lap
+				),
+				None => println!(
+					"{}. {}  lap {}/{}  best lap {}",
+					place + 1,
+					standing.name,
+					standing.lap,
+					self.laps,
+					best_lap
+				),
+			}
+		}
+	}
+
+	/// Queues the player's lap and time, the standings, and a marker on the player's next checkpoint on the overlay.
+	pub fn draw(&self, overlay: &mut Overlay)
+	{
+		let player = match self.racers.first()
+		{
+			Some(player) => player,
+			None => return,
+		};
+		let style = TextStyle {
+			outline_width: 1.5,
+			shadow_color: [0.0, 0.0, 0.0, 0.6],
+			shadow_offset: [2.0, 2.0],
+			..Default::default()
+		};
+		let title_style = TextStyle {
+			color: TITLE_COLOR,
+			..style
+		};
+		let player_style = TextStyle {
+			color: PLAYER_COLOR,
+			..style
+		};
+		let (x, mut y) = PANEL_POSITION;
+
+		let standings = self.get_standings();
+		let title = match player.finish_time
+		{
+			Some(time) => format!("Finished in {}", format_time(time)),
+			None =>
+			{
+				let lap = (player.passed / self.checkpoints.len()) as u32 + 1;
+				format!("Lap {}/{}  {}", lap, self.laps, format_time(self.time - player.lap_start))
+			}
+		};
+		overlay.draw_text(&title, (x, y), TEXT_SIZE, &title_style);
+		if let Some(best_lap) = player.best_lap
+		{
+			y += LINE_SPACING;
+			overlay.draw_text(&format!("Best lap {}", format_time(best_lap)), (x, y), TEXT_SIZE, &title_style);
+		}
+		for (place, standing) in standings.iter().enumerate()
+		{
+			y += LINE_SPACING;
+			let text = match standing.finish_time
+			{
+				Some(time) => format!("{}. {}  {}", place + 1, standing.name, format_time(time)),
+				None => format!("{}. {}  lap {}", place + 1, standing.name, standing.lap),
+			};
+			let standing_style = if standing.is_player
+			{
+				&player_style
+			}
+			else
+			{
+				&style
+			};
+			overlay.draw_text(&text, (x, y), TEXT_SIZE, standing_style);
+		}
+
+		if player.finish_time.is_none()
+		{
+			let checkpoint_style = TextStyle {
+				color: CHECKPOINT_COLOR,
+				outline_width: 1.0,
+				..Default::default()
+			};
+			let checkpoint = self.get_checkpoint(player.passed + 1);
+			overlay.draw_world_icon(Icon::Circle, checkpoint, CHECKPOINT_ICON_SIZE, &checkpoint_style, false);
+		}
+	}
+}
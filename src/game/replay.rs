@@ -0,0 +1,420 @@
+use crate::game::{Car, CarControls, CarState, TransformComponent};
+use crate::renderer::{Icon, Overlay, TextStyle};
+
+// The car state is saved this often while recording, in ticks, so seeking only simulates from the closest keyframe
+const KEYFRAME_INTERVAL: usize = 120;
+const MIN_SPEED: f32 = 0.125;
+const MAX_SPEED: f32 = 8.0;
+
+const PANEL_POSITION: (f32, f32) = (8.0, 340.0);
+const TEXT_SIZE: f32 = 14.0;
+const LINE_SPACING: f32 = 20.0;
+// The timeline is a row of squares, the played ones highlighted
+const TIMELINE_CELLS: usize = 50;
+const TIMELINE_CELL_SIZE: f32 = 8.0;
+// Buttons are laid out at fixed widths, so clicks can be mapped to them without measuring text
+const BUTTON_WIDTH: f32 = 60.0;
+const INPUT_KEY_WIDTH: f32 = 20.0;
+// Status, timeline, buttons and recorded inputs
+const PANEL_LINES: usize = 4;
+const PANEL_WIDTH: f32 = TIMELINE_CELLS as f32 * TIMELINE_CELL_SIZE;
+
+const TITLE_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+const PLAYED_COLOR: [f32; 4] = [0.3, 0.9, 1.0, 1.0];
+const UNPLAYED_COLOR: [f32; 4] = [0.4, 0.4, 0.4, 0.8];
+const RECORDING_COLOR: [f32; 4] = [0.9, 0.2, 0.2, 1.0];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Button
+{
+	Start,
+	StepBack,
+	PlayPause,
+	StepForward,
+	Slower,
+	Faster,
+}
+
+const BUTTONS: [Button; 6] =
+	[Button::Start, Button::StepBack, Button::PlayPause, Button::StepForward, Button::Slower, Button::Faster];
+
+/// One recorded engine tick.
+struct ReplayTick
+{
+	controls: CarControls,
+	timestep: f32,
+	/// Replay time at the end of the tick, in seconds.
+	end_time: f32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReplayMode
+{
+	Idle,
+	Recording,
+	Playback,
+}
+
+/// Records the car input of each tick, and plays it back deterministically, with a timeline to scrub through it.
+///
+/// Only the car is replayed, the rest of the scene keeps running during playback.
+pub struct Replay
+{
+	ticks: Vec<ReplayTick>,
+	// The car state before every KEYFRAME_INTERVAL ticks
+	keyframes: Vec<CarState>,
+	mode: ReplayMode,
+	/// Number of ticks played back.
+	position: usize,
+	playing: bool,
+	/// Ticks played back per engine tick.
+	speed: f32,
+	// Fractions of ticks left over at speeds below 1
+	tick_accumulator: f32,
+}
+
+impl Replay
+{
+	pub fn new() -> Replay
+	{
+		Replay {
+			ticks: Vec::new(),
+			keyframes: Vec::new(),
+			mode: ReplayMode::Idle,
+			position: 0,
+			playing: false,
+			speed: 1.0,
+			tick_accumulator: 0.0,
+		}
+	}
+
+	/// Returns true while a recording is being played back, when the car follows it instead of the player.
+	pub fn is_playing_back(&self) -> bool
+	{
+		return self.mode == ReplayMode::Playback;
+	}
+
+	/// Starts a new recording from the current state of the car, replacing the previous one.
+	pub fn record(&mut self)
+	{
+		self.ticks.clear();
+		self.keyframes.clear();
+		self.mode = ReplayMode::Recording;
+		println!("Recording replay");
+	}
+
+	/// Stops recording or playback. After playback, the player takes over the car where the playback stopped.
+	pub fn stop(&mut self)
+	{
+		if self.mode == ReplayMode::Recording
+		{
+			println!("Recorded {} ticks, {:.1} s", self.ticks.len(), self.get_duration());
+		}
+		self.mode = ReplayMode::Idle;
+		self.playing = false;
+	}
+
+	/// Plays the recording back from the start.
+	pub fn play(&mut self, car: &mut Car, transform: &mut TransformComponent)
+	{
+		if self.mode == ReplayMode::Recording
+		{
+			self.stop();
+		}
+		if self.ticks.is_empty()
+		{
+			warning!("Nothing recorded to replay, see replay_record");
+			return;
+		}
+		self.mode = ReplayMode::Playback;
+		self.seek(car, transform, 0);
+		self.playing = true;
+	}
+
+	/// Pauses or resumes playback. Resuming at the end plays from the start.
+	pub fn toggle_pause(&mut self, car: &mut Car, transform: &mut TransformComponent)
+	{
+		if self.mode != ReplayMode::Playback
+		{
+			return;
+		}
+		if !self.playing && self.position == self.ticks.len()
+		{
+			self.seek(car, transform, 0);
+		}
+		self.playing = !self.playing;
+	}
+
+	pub fn set_speed(&mut self, speed: f32)
+	{
+		self.speed = speed.max(MIN_SPEED).min(MAX_SPEED);
+	}
+
+	/// Pauses playback and moves the given number of ticks forward, or back if negative.
+	pub fn step(&mut self, car: &mut Car, transform: &mut TransformComponent, ticks: i32)
+	{
+		if self.mode != ReplayMode::Playback
+		{
+			return;
+		}
+		self.playing = false;
+		let position = (self.position as i64 + ticks as i64).max(0) as usize;
+		self.seek(car, transform, position);
+	}
+
+	/// Jumps to the given replay time, in seconds.
+	pub fn seek_time(&mut self, car: &mut Car, transform: &mut TransformComponent, seconds: f32)
+	{
+		if self.mode != ReplayMode::Playback
+		{
+			return;
+		}
+		let position = self.ticks.iter().position(|tick| tick.end_time > seconds).unwrap_or(self.ticks.len());
+		self.seek(car, transform, position);
+	}
+
+	/// Puts the car in its state after the given number of ticks, simulating from the closest earlier keyframe.
+	fn seek(&mut self, car: &mut Car, transform: &mut TransformComponent, position: usize)
+	{
+		let position = position.min(self.ticks.len());
+		let keyframe = (position / KEYFRAME_INTERVAL).min(self.keyframes.len() - 1);
+		car.restore_state(transform, &self.keyframes[keyframe]);
+		for tick in &self.ticks[keyframe * KEYFRAME_INTERVAL..position]
+		{
+			car.set_controls(tick.controls);
+			car.update(transform, tick.timestep);
+		}
+		self.position = position;
+		self.tick_accumulator = 0.0;
+	}
+
+	fn get_duration(&self) -> f32
+	{
+		return self.ticks.last().map_or(0.0, |tick| tick.end_time);
+	}
+
+	fn get_time(&self) -> f32
+	{
+		if self.position == 0
+		{
+			return 0.0;
+		}
+		return self.ticks[self.position - 1].end_time;
+	}
+
+	/// Updates the car, given by its driving state and the transform of its entity, for one engine tick, recording its
+	/// input or replacing it with the recorded one.
+	pub fn update(&mut self, car: &mut Car, transform: &mut TransformComponent, timestep: f32)
+	{
+		match self.mode
+		{
+			ReplayMode::Idle => car.update(transform, timestep),
+			ReplayMode::Recording =>
+			{
+				if self.ticks.len() % KEYFRAME_INTERVAL == 0
+				{
+					self.keyframes.push(car.save_state(transform));
+				}
+				self.ticks.push(ReplayTick {
+					controls: car.get_controls(),
+					timestep: timestep,
+					end_time: self.get_duration() + timestep,
+				});
+				car.update(transform, timestep);
+			}
+			ReplayMode::Playback =>
+			{
+				// The player's input is ignored, and the car stands still while paused
+				car.set_controls(CarControls::default());
+				if !self.playing
+				{
+					return;
+				}
+				self.tick_accumulator += self.speed;
+				while self.tick_accumulator >= 1.0 && self.position < self.ticks.len()
+				{
+					let tick = &self.ticks[self.position];
+					car.set_controls(tick.controls);
+					car.update(transform, tick.timestep);
+					self.position += 1;
+					self.tick_accumulator -= 1.0;
+				}
+				if self.position == self.ticks.len()
+				{
+					self.playing = false;
+				}
+			}
+		}
+	}
+
+	/// Returns the line of the panel at the given position in UI coordinates, if the panel is shown there.
+	fn get_panel_line(&self, position: (f32, f32)) -> Option<usize>
+	{
+		let (x, y) = PANEL_POSITION;
+		let line = ((position.1 - y) / LINE_SPACING).floor();
+		if self.mode != ReplayMode::Playback ||
+			position.0 < x ||
+			position.0 > x + PANEL_WIDTH ||
+			line < 0.0 ||
+			line >= PANEL_LINES as f32
+		{
+			return None;
+		}
+		return Some(line as usize);
+	}
+
+	pub fn is_over_panel(&self, position: (f32, f32)) -> bool
+	{
+		return self.get_panel_line(position).is_some();
+	}
+
+	/// Handles a mouse click at the given position in UI coordinates. Clicking the timeline jumps to the clicked
+	/// time. Returns false if the click was not on the panel.
+	pub fn click(&mut self, car: &mut Car, transform: &mut TransformComponent, position: (f32, f32)) -> bool
+	{
+		let line = match self.get_panel_line(position)
+		{
+			Some(line) => line,
+			None => return false,
+		};
+		let x = position.0 - PANEL_POSITION.0;
+		match line
+		{
+			1 =>
+			{
+				let fraction = x / PANEL_WIDTH;
+				self.seek_time(car, transform, fraction * self.get_duration());
+			}
+			2 => match BUTTONS.get((x / BUTTON_WIDTH) as usize)
+			{
+				Some(Button::Start) => self.step(car, transform, -(self.position as i32)),
+				Some(Button::StepBack) => self.step(car, transform, -1),
+				Some(Button::PlayPause) => self.toggle_pause(car, transform),
+				Some(Button::StepForward) => self.step(car, transform, 1),
+				Some(Button::Slower) => self.set_speed(0.5 * self.speed),
+				Some(Button::Faster) => self.set_speed(2.0 * self.speed),
+				None => (),
+			},
+			_ => (),
+		}
+		return true;
+	}
+
+	/// Queues the playback panel on the overlay, with the timeline and the recorded input of the current tick.
+	pub fn draw(&self, overlay: &mut Overlay)
+	{
+		if self.mode != ReplayMode::Playback
+		{
+			if self.mode == ReplayMode::Recording
+			{
+				let style = TextStyle {
+					color: RECORDING_COLOR,
+					outline_width: 1.5,
+					..Default::default()
+				};
+				overlay.draw_icon(Icon::Circle, PANEL_POSITION, TEXT_SIZE, &style);
+				let text = format!("REC {:.1} s", self.get_duration());
+				overlay.draw_text(&text, (PANEL_POSITION.0 + INPUT_KEY_WIDTH, PANEL_POSITION.1), TEXT_SIZE, &style);
+			}
+			return;
+		}
+
+		let style = TextStyle {
+			outline_width: 1.5,
+			shadow_color: [0.0, 0.0, 0.0, 0.6],
+			shadow_offset: [2.0, 2.0],
+			..Default::default()
+		};
+		let title_style = TextStyle {
+			color: TITLE_COLOR,
+			..style
+		};
+		let played_style = TextStyle {
+			color: PLAYED_COLOR,
+			..style
+		};
+		let unplayed_style = TextStyle {
+			color: UNPLAYED_COLOR,
+			..style
+		};
+		let (x, y) = PANEL_POSITION;
+
+		let state = if self.playing
+		{
+			"Playing"
+		}
+		else
+		{
+			"Paused"
+		};
+		let status = format!(
+			"Replay  {}  x{:.2}  {:.2} / {:.2} s  tick {}/{}",
+			state,
+			self.speed,
+			self.get_time(),
+			self.get_duration(),
+			self.position,
+			self.ticks.len()
+		);
+		overlay.draw_text(&status, (x, y), TEXT_SIZE, &title_style);
+
+		let timeline_y = y + LINE_SPACING;
+		let played = self.position as f32 / self.ticks.len() as f32;
+		let played_cells = (played * TIMELINE_CELLS as f32).round() as usize;
+		for cell in 0..TIMELINE_CELLS
+		{
+			let cell_style = if cell < played_cells
+			{
+				&played_style
+			}
+			else
+			{
+				&unplayed_style
+			};
+			let position = (x + cell as f32 * TIMELINE_CELL_SIZE, timeline_y);
+			overlay.draw_icon(Icon::Square, position, TIMELINE_CELL_SIZE - 1.0, cell_style);
+		}
+
+		let buttons_y = y + 2.0 * LINE_SPACING;
+		for (idx, button) in BUTTONS.iter().enumerate()
+		{
+			let label = match button
+			{
+				Button::Start => "|<",
+				Button::StepBack => "<",
+				Button::PlayPause if self.playing => "Pause",
+				Button::PlayPause => "Play",
+				Button::StepForward => ">",
+				Button::Slower => "-",
+				Button::Faster => "+",
+			};
+			overlay.draw_text(label, (x + idx as f32 * BUTTON_WIDTH, buttons_y), TEXT_SIZE, &style);
+		}
+
+		// The input of the last played tick
+		let controls = if self.position > 0
+		{
+			self.ticks[self.position - 1].controls
+		}
+		else
+		{
+			CarControls::default()
+		};
+		let inputs_y = y + 3.0 * LINE_SPACING;
+		overlay.draw_text("Input", (x, inputs_y), TEXT_SIZE, &style);
+		let keys = [("W", controls.forward), ("A", controls.left), ("S", controls.back), ("D", controls.right)];
+		for (idx, (key, pressed)) in keys.iter().enumerate()
+		{
+			let key_style = if *pressed
+			{
+				&played_style
+			}
+			else
+			{
+				&unplayed_style
+			};
+			let position = (x + BUTTON_WIDTH + idx as f32 * INPUT_KEY_WIDTH, inputs_y);
+			overlay.draw_text(key, position, TEXT_SIZE, key_style);
+		}
+	}
+}
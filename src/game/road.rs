@@ -0,0 +1,253 @@
+use crate::core::{Mesh, Vertex, VertexFormat};
+use crate::game::{NURBSpline, Order};
+use crate::renderer::RenderState;
+use cgmath::prelude::*;
+use cgmath::{Deg, Point3, Quaternion, Vector3};
+use serde_derive::{Deserialize, Serialize};
+use std::rc::Rc;
+
+/// Spline evaluations per knot span, when measuring the length of the center line.
+const SAMPLES_PER_SPAN: usize = 32;
+
+/// A control point of the center line of a road, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoadPoint
+{
+	pub position: [f32; 3],
+	/// Banking around the direction of the road, in degrees. Positive values lower the right edge.
+	#[serde(default)]
+	pub roll: f32,
+}
+
+/// A road extruded along a spline, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoadSettings
+{
+	/// Control points of the center line, at least two. The road starts and ends at the first and last point.
+	pub points: Vec<RoadPoint>,
+	/// Name of the material, see AssetCache::get_material().
+	pub material: String,
+	/// Cross section of the road, as (right, up) offsets from the center line in meters, from the left edge to the
+	/// right edge.
+	#[serde(default = "RoadSettings::default_profile")]
+	pub profile: Vec<[f32; 2]>,
+	/// Distance between cross sections, in meters.
+	#[serde(default = "RoadSettings::default_segment_length")]
+	pub segment_length: f32,
+	/// Length of road covered by the texture once, in meters. The texture spans the width of the profile.
+	#[serde(default = "RoadSettings::default_texture_length")]
+	pub texture_length: f32,
+}
+
+impl RoadSettings
+{
+	/// An 8 meter wide road with curbs on both sides.
+	fn default_profile() -> Vec<[f32; 2]>
+	{
+		return vec![[-4.5, 0.15], [-4.0, 0.15], [-4.0, 0.0], [4.0, 0.0], [4.0, 0.15], [4.5, 0.15]];
+	}
+
+	fn default_segment_length() -> f32
+	{
+		return 2.0;
+	}
+
+	fn default_texture_length() -> f32
+	{
+		return 8.0;
+	}
+}
+
+/// A cross section of the road along the center line.
+struct Section
+{
+	center: Point3<f32>,
+	forward: Vector3<f32>,
+	right: Vector3<f32>,
+	up: Vector3<f32>,
+	/// Distance from the start of the road, in meters.
+	distance: f32,
+}
+
+/// A road mesh in world space.
+pub struct Road
+{
+	mesh: Rc<Mesh>,
+}
+
+impl Road
+{
+	pub fn new(rs: &RenderState, settings: &RoadSettings, vertex_format: VertexFormat) -> Result<Road, String>
+	{
+		if settings.profile.len() < 2
+		{
+			return Err("a road profile needs at least two points".to_string());
+		}
+		let sections = Road::create_sections(settings)?;
+
+		// Texture u runs across the profile, by its length
+		let mut profile_u = vec![0.0];
+		for edge in settings.profile.windows(2)
+		{
+			let length = (edge[1][0] - edge[0][0]).hypot(edge[1][1] - edge[0][1]);
+			profile_u.push(profile_u.last().unwrap() + length);
+		}
+		let profile_length = *profile_u.last().unwrap();
+		if profile_length <= 0.0
+		{
+			return Err("a road profile needs a non-zero length".to_string());
+		}
+
+		// Each profile edge is a strip of its own, so the normals are flat across it
+		let num_vertices = 2 * (settings.profile.len() - 1) * sections.len();
+		if num_vertices > std::u16::MAX as usize + 1
+		{
+			return Err(format!("the road needs {} vertices, increase its segment_length", num_vertices));
+		}
+		let mut vertices = Vec::with_capacity(num_vertices);
+		let mut indices = Vec::new();
+		for (edge_idx, edge) in settings.profile.windows(2).enumerate()
+		{
+			let (dx, dy) = (edge[1][0] - edge[0][0], edge[1][1] - edge[0][1]);
+			let length = dx.hypot(dy);
+			if length <= 0.0
+			{
+				continue;
+			}
+			// Facing left of the edge's direction, so up for edges running left to right
+			let (dx, dy) = (dx / length, dy / length);
+			let (nx, ny) = (-dy, dx);
+
+			let base = vertices.len();
+			for section in &sections
+			{
+				let normal = section.right * nx + section.up * ny;
+				let tangent = section.right * dx + section.up * dy;
+				// Texture v increases along the road, while the bitangent should point towards decreasing v
+				let bitangent = -section.forward;
+				let v = section.distance / settings.texture_length;
+				for &(point, u) in [(edge[0], profile_u[edge_idx]), (edge[1], profile_u[edge_idx + 1])].iter()
+				{
+					let position = section.center + section.right * point[0] + section.up * point[1];
+					vertices.push(Vertex::new(position, normal, tangent, bitangent, [u / profile_length, v]));
+				}
+			}
+			for section_idx in 0..sections.len() - 1
+			{
+				let near = (base + 2 * section_idx) as u16;
+				let far = near + 2;
+				indices.extend_from_slice(&[near, near + 1, far + 1, near, far + 1, far]);
+			}
+		}
+
+		return Ok(Road {
+			mesh: Mesh::from_geometry(rs, &vertices, &indices, vertex_format),
+		});
+	}
+
+	/// Places cross sections along the center line, evenly spaced by distance, and banks them by the roll.
+	fn create_sections(settings: &RoadSettings) -> Result<Vec<Section>, String>
+	{
+		let points = &settings.points;
+		if points.len() < 2
+		{
+			return Err("a road needs at least two points".to_string());
+		}
+		if settings.segment_length <= 0.0
+		{
+			return Err("the segment_length of a road must be positive".to_string());
+		}
+		let order = match points.len()
+		{
+			2 => Order::LINEAR,
+			3 => Order::QUADRATIC,
+			_ => Order::CUBIC,
+		};
+		let center_line = NURBSpline::new(
+			order,
+			points.iter().map(|point| Point3::from(point.position).cast::<f64>().unwrap()).collect(),
+		);
+		// The roll is smoothed the same way as the center line
+		let roll =
+			NURBSpline::new(order, points.iter().map(|point| Point3::new(point.roll as f64, 0.0, 0.0)).collect());
+
+		// The splines start and end at their first and last points, but cannot be evaluated at the end. Samples are
+		// taken between the knots, since evaluating exactly at a knot counts both of its spans.
+		let limit = center_line.eval_limit();
+		let num_samples = limit.round() as usize * SAMPLES_PER_SPAN;
+		let first = &points[0];
+		let last = &points[points.len() - 1];
+		let mut samples = vec![(Point3::from(first.position), first.roll)];
+		for idx in 0..num_samples
+		{
+			let u = limit * (idx as f64 + 0.5) / num_samples as f64;
+			samples.push((center_line.evaluate_at(u).cast().unwrap(), roll.evaluate_at(u).x as f32));
+		}
+		samples.push((Point3::from(last.position), last.roll));
+
+		let mut distances = vec![0.0];
+		for pair in samples.windows(2)
+		{
+			distances.push(distances.last().unwrap() + pair[0].0.distance(pair[1].0));
+		}
+		let total_distance = *distances.last().unwrap();
+		if total_distance <= 0.0
+		{
+			return Err("a road needs a non-zero length".to_string());
+		}
+
+		// Resample at even distances
+		let num_segments = (total_distance / settings.segment_length).ceil().max(1.0) as usize;
+		let mut resampled = Vec::with_capacity(num_segments + 1);
+		let mut sample_idx = 0;
+		for idx in 0..=num_segments
+		{
+			let distance = total_distance * idx as f32 / num_segments as f32;
+			while sample_idx + 2 < samples.len() && distances[sample_idx + 1] < distance
+			{
+				sample_idx += 1;
+			}
+			let span = distances[sample_idx + 1] - distances[sample_idx];
+			let t = if span > 0.0
+			{
+				((distance - distances[sample_idx]) / span).max(0.0).min(1.0)
+			}
+			else
+			{
+				0.0
+			};
+			let (start, end) = (samples[sample_idx], samples[sample_idx + 1]);
+			resampled.push((start.0 + (end.0 - start.0) * t, start.1 + (end.1 - start.1) * t, distance));
+		}
+
+		let mut sections = Vec::with_capacity(resampled.len());
+		for idx in 0..resampled.len()
+		{
+			let previous = resampled[idx.saturating_sub(1)].0;
+			let next = resampled[(idx + 1).min(resampled.len() - 1)].0;
+			let forward = (next - previous).normalize();
+			let level_right = forward.cross(Vector3::unit_y());
+			if level_right.magnitude2() < 1e-6
+			{
+				return Err(format!("the road is vertical {:.1} meters from its start", resampled[idx].2));
+			}
+			let level_right = level_right.normalize();
+			let level_up = level_right.cross(forward);
+			// Rolling clockwise around the forward direction lowers the right edge
+			let bank = Quaternion::from_axis_angle(forward, Deg(resampled[idx].1));
+			sections.push(Section {
+				center: resampled[idx].0,
+				forward: forward,
+				right: bank * level_right,
+				up: bank * level_up,
+				distance: resampled[idx].2,
+			});
+		}
+		return Ok(sections);
+	}
+
+	pub fn get_mesh(&self) -> Rc<Mesh>
+	{
+		return self.mesh.clone();
+	}
+}
@@ -1,118 +1,814 @@
-use crate::core::{ActionType, Config, Drawable, InputHandler, Material, Mesh, Transform, Transformable};
-use crate::game::{Camera, Car, NURBSpline, Order};
-use crate::renderer::{MainPass, RenderState};
+use crate::audio::{AudioSystem, VehicleAudio};
+use crate::core::{
+	is_gltf, load_obj_with_materials, unversioned_to_v1, ActionType, BoundingBox, Config, Console, Constraint,
+	Drawable, FileFormat, FileWatcher, Frustum, InputContext, InputHandler, Material, Mesh, MorphWeights,
+	ParticleVertex, RandomService, RandomStream, Time, TimeDomain, Transform, Transformable, VertexFormat,
+};
+use crate::game::{
+	find_gallery_scene, get_gallery_scenes, Agent, AgentSettings, AssetGraph, AssetManifest, AssetRef, Camera,
+	CameraBoom, CameraEffects, CameraMode, CameraToggleInput, CameraTransition, Car, Character, Collider,
+	ColliderComponent, CombineRule, ContactPhase, ContactWorld, DepthOfField, Edit, EditHistory, Entity, FramingInput,
+	InteractInput, Light, LightId, LightKind, MaterialEditor, MorphComponent, NavMesh, NavMeshBuilder, NavMeshConfig,
+	NightLightMode, Orbit, PhysicsComponent, PhysicsMaterial, PostEffects, RaceManager, RaceSettings, RenderComponent,
+	Replay, RigidBodyWorld, Road, RoadSettings, Standing, StaticComponent, TexturePainter, TimeOfDay, TimelineTrigger,
+	Timelines, Trail, TransformComponent, TriangleMesh, VehicleEvent, WeatherController, WeatherSettings, World,
+};
+#[cfg(feature = "presence")]
+use crate::presence::{Activity, Presence};
+use crate::renderer::{
+	DamageBaker, DamageDecal, DirectionalLight, FlareElement, Icon, IrradianceVolume, LineBatch, MainPass, Overlay,
+	PointLight, ReflectionProbe, RenderState, ShadowPass, TextStyle, MAX_FLARE_ELEMENTS,
+};
+use ash::version::DeviceV1_0;
 use ash::{vk, Device};
 use cgmath::prelude::*;
-use cgmath::{Deg, Matrix4, Point3, Quaternion, Vector3};
+use cgmath::{Deg, Matrix4, Point3, Quaternion, Rad, Vector2, Vector3, Vector4};
+use serde_derive::{Deserialize, Serialize};
+use serde_json;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-struct StaticObject
+const SCENE_SETTINGS_FILE: &str = "assets/scenes/default.json";
+// Assets of the scene file, see AssetManifest
+const SCENE_MANIFEST_FILE: &str = "assets/scenes/default.manifest.json";
+const SCENE_FORMAT: FileFormat = FileFormat {
+	name: "scene",
+	version: 2,
+	migrations: &[unversioned_to_v1, material_paths_to_names],
+};
+// Materials are referenced by name, as the files in this directory without the extension
+const MATERIALS_DIR: &str = "assets/materials";
+const TIMELINES_DIR: &str = "assets/timelines";
+const NAV_PATH_COLOR: [f32; 4] = [1.0, 0.9, 0.1, 1.0];
+const MAX_NAV_PATH_LINES: usize = 256;
+const MAX_AGENT_DEBUG_LINES: usize = 4_096;
+const DEBUG_TABLE_COLOR: [f32; 4] = [0.8, 1.0, 0.8, 1.0];
+const WORLD_LABEL_SIZE: f32 = 12.0;
+const WAYPOINT_ICON_SIZE: f32 = 16.0;
+const WAYPOINT_COLOR: [f32; 4] = [1.0, 0.9, 0.1, 0.8];
+// Labels above the agents' heads, in meters
+const AGENT_LABEL_OFFSET: f32 = 0.3;
+const AGENT_HEIGHT: f32 = 1.8;
+const AGENT_BUMP_TRAUMA: f32 = 0.4;
+const AGENT_BUMP_IMPULSE: f32 = 6.0;
+const AGENT_BUMP_HIT_STOP: f32 = 0.08;
+// Bumping into agents faster than this, in m/s, is a big crash and triggers slow motion
+const BIG_CRASH_SPEED: f32 = 40.0;
+const BIG_CRASH_TIME_SCALE: f32 = 0.2;
+const BIG_CRASH_DURATION: f32 = 1.0;
+
+// Sideways slip of the tires, in m/s, above which they leave skidmarks
+const SKID_SLIP: f32 = 2.0;
+const MAX_SKIDMARK_SEGMENTS: usize = 512;
+const SKIDMARK_WIDTH: f32 = 0.25;
+const SKIDMARK_LIFETIME: f32 = 20.0;
+const SKIDMARK_COLOR: [f32; 4] = [0.05, 0.05, 0.05, 0.8];
+// Lifts skidmarks off the ground, so they are not hidden by it
+const SKIDMARK_HEIGHT: f32 = 0.01;
+// Speed in m/s above which the tail lights leave light trails, when enabled
+const LIGHT_TRAIL_SPEED: f32 = 1.0;
+const MAX_LIGHT_TRAIL_SEGMENTS: usize = 128;
+const LIGHT_TRAIL_WIDTH: f32 = 0.15;
+const LIGHT_TRAIL_LIFETIME: f32 = 0.8;
+const LIGHT_TRAIL_COLOR: [f32; 4] = [1.0, 0.1, 0.05, 0.7];
+// Height above the camera of the top-down debug view
+const TOP_DOWN_VIEW_HEIGHT: f32 = 100.0;
+// Size of the generated morph object box, in meters
+const MORPH_BOX_SIZE: f32 = 1.5;
+// Towards the sun, low over the horizon in front of the initial camera
+// Collider ids, agents follow from AGENT_COLLIDERS
+const CAMERA_COLLIDER: u32 = 0;
+const CAR_COLLIDER: u32 = 1;
+const AGENT_COLLIDERS: u32 = 2;
+// Collider radii, the camera bumps into agents closer than the sum of theirs
+const CAMERA_RADIUS: f32 = 0.6;
+// Used when the car mesh is flat and has no hull
+const CAR_RADIUS: f32 = 1.8;
+const AGENT_RADIUS: f32 = 0.6;
+const CAMERA_MASS: f32 = 80.0;
+// The chase camera orbits the car's chase_camera attachment point. Its collision sphere covers the near plane, so
+// geometry close to the camera is not clipped.
+const FOLLOW_CAMERA_RADIUS: f32 = 1.2;
+// The player can get in within this distance of the driver's door, and out below this speed in m/s
+const ENTER_DISTANCE: f32 = 2.0;
+const EXIT_SPEED: f32 = 1.0;
+// The orbit camera starts this far in front of the camera, and fits selected objects at this many times their radius
+const ORBIT_START_DISTANCE: f32 = 10.0;
+const ORBIT_FIT_SCALE: f32 = 3.0;
+const AGENT_MASS: f32 = 80.0;
+// Car collisions harder than this, in Ns, scratch its paint. Scratches grow with the impulse, up to the full size.
+const SCRATCH_MIN_IMPULSE: f32 = 200.0;
+const SCRATCH_FULL_IMPULSE: f32 = 2_000.0;
+const SCRATCH_MIN_RADIUS: f32 = 0.15;
+const SCRATCH_MAX_RADIUS: f32 = 0.65;
+const SCRATCH_COLOR: [f32; 4] = [0.6, 0.6, 0.62, 0.9];
+// Texels along each side of the damage texture of the car
+const CAR_DAMAGE_RESOLUTION: u32 = 1024;
+const CAR_MATERIAL: PhysicsMaterial = PhysicsMaterial {
+	restitution: 0.3,
+	static_friction: 0.6,
+	dynamic_friction: 0.4,
+	friction_combine: CombineRule::Multiply,
+	restitution_combine: CombineRule::Maximum,
+};
+const BODY_MATERIAL: PhysicsMaterial = PhysicsMaterial {
+	restitution: 0.1,
+	static_friction: 0.8,
+	dynamic_friction: 0.6,
+	friction_combine: CombineRule::Average,
+	restitution_combine: CombineRule::Minimum,
+};
+// Of the props, the spinning cube and the constrained and morph objects
+const OBJECT_MATERIAL: PhysicsMaterial = PhysicsMaterial {
+	restitution: 0.2,
+	static_friction: 0.7,
+	dynamic_friction: 0.5,
+	friction_combine: CombineRule::Average,
+	restitution_combine: CombineRule::Average,
+};
+// The car's drag stands in for its tires, so the ground adds no friction
+const GROUND_MATERIAL: PhysicsMaterial = PhysicsMaterial {
+	restitution: 0.0,
+	static_friction: 0.0,
+	dynamic_friction: 0.0,
+	friction_combine: CombineRule::Multiply,
+	restitution_combine: CombineRule::Average,
+};
+const GRAVITY: f32 = 9.81;
+const GROUND_HEIGHT: f32 = 0.0;
+// Rigid body ids, the car uses CAR_COLLIDER and the entities follow from ENTITY_BODIES
+const ENTITY_BODIES: u32 = 2;
+
+/// Returns the path of the material with the given name, see AssetCache::get_material().
+fn material_path(name: &str) -> String
+{
+	if is_gltf(name)
+	{
+		return name.to_string();
+	}
+	return format!("{}/{}.json", MATERIALS_DIR, name);
+}
+
+/// Returns the name of the material file at the path, see AssetCache::get_material().
+fn material_name(path: &str) -> String
 {
-	transform: Transform,
-	mesh: Rc<Mesh>,
-	material: Rc<Material>,
+	return path.trim_start_matches(MATERIALS_DIR).trim_start_matches('/').trim_end_matches(".json").to_string();
 }
 
-impl StaticObject
+/// Version 1 scenes referenced the materials of props by path.
+fn material_paths_to_names(value: &mut serde_json::Value) -> Result<(), String>
 {
-	fn new(mesh: Rc<Mesh>, material: Rc<Material>) -> StaticObject
+	let props = match value.get_mut("props").and_then(|props| props.as_array_mut())
 	{
-		let obj = StaticObject {
-			transform: Transform::new(),
-			mesh: mesh,
-			material: material,
-		};
-		return obj;
+		Some(props) => props,
+		None => return Ok(()),
+	};
+	for prop in props
+	{
+		if let Some(material) = prop.get_mut("material")
+		{
+			let path = material.as_str().ok_or("material is not a string")?;
+			*material = serde_json::Value::from(material_name(path));
+		}
+	}
+	return Ok(());
+}
+
+/// A static object loaded from a mesh file, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct PropSettings
+{
+	mesh: String,
+	/// Name of the material, see AssetCache::get_material().
+	material: String,
+	position: [f32; 3],
+	/// Rotation around the up axis, in degrees.
+	#[serde(default)]
+	yaw: f32,
+	/// Props with the same mesh and material are drawn as instances of a single draw, unless opted out.
+	#[serde(default = "PropSettings::default_instanced")]
+	instanced: bool,
+}
+
+impl PropSettings
+{
+	fn default_instanced() -> bool
+	{
+		return true;
 	}
 }
 
-impl Transformable for StaticObject
+/// A Wavefront OBJ model with its MTL materials, placed as static geometry, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct StaticModelSettings
+{
+	path: String,
+	#[serde(default)]
+	position: [f32; 3],
+	/// Rotation around the up axis, in degrees.
+	#[serde(default)]
+	yaw: f32,
+}
+
+/// A point light, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct LightSettings
+{
+	position: [f32; 3],
+	/// Distance at which the light fades out, in meters.
+	radius: f32,
+	#[serde(default = "LightSettings::default_color")]
+	color: [f32; 3],
+	/// Scales the color.
+	#[serde(default = "LightSettings::default_intensity")]
+	intensity: f32,
+	/// Size of each face of the shadow cube map, in texels. 0 disables shadows from the light.
+	#[serde(default)]
+	shadow_resolution: u32,
+	/// Lights only lit at night fade in as the sun sets, and out as it rises.
+	#[serde(default)]
+	night_only: bool,
+}
+
+impl LightSettings
 {
-	fn get_transform(&self) -> &Transform
+	fn default_color() -> [f32; 3]
 	{
-		return &self.transform;
+		return [1.0, 1.0, 1.0];
 	}
-	fn get_mutable_transform(&mut self) -> &mut Transform
+
+	fn default_intensity() -> f32
 	{
-		return &mut self.transform;
+		return 1.0;
 	}
 }
 
-impl Drawable for StaticObject
+/// A directional light without shadows, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct DirectionalLightSettings
+{
+	/// Towards the light.
+	direction: [f32; 3],
+	#[serde(default = "LightSettings::default_color")]
+	color: [f32; 3],
+	#[serde(default = "LightSettings::default_intensity")]
+	intensity: f32,
+}
+
+/// A reflection probe, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct ReflectionProbeSettings
+{
+	position: [f32; 3],
+	/// Half the size of the box volume along each axis, in meters.
+	extents: [f32; 3],
+	/// Size of each face of the cube map, in texels.
+	#[serde(default = "ReflectionProbeSettings::default_resolution")]
+	resolution: u32,
+}
+
+impl ReflectionProbeSettings
 {
-	fn get_mesh(&self) -> &Mesh
+	fn default_resolution() -> u32
 	{
-		return &self.mesh;
+		return 128;
 	}
-	fn get_material(&self) -> &Material
+}
+
+/// The irradiance grid, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct IrradianceGridSettings
+{
+	/// Center of the grid.
+	position: [f32; 3],
+	/// Half the size of the grid along each axis, in meters.
+	extents: [f32; 3],
+	/// Probes along each axis.
+	counts: [u32; 3],
+}
+
+/// An element of the lens flare, as given in the scene file. See FlareElement.
+#[derive(Clone, Serialize, Deserialize)]
+struct FlareElementSettings
+{
+	/// Position along the axis from the sun through the screen center, 0 at the sun and 1 at the center.
+	position: f32,
+	/// Radius, as a fraction of the screen height.
+	size: f32,
+	color: [f32; 3],
+	#[serde(default)]
+	ring: bool,
+}
+
+impl FlareElementSettings
+{
+	fn new(position: f32, size: f32, color: [f32; 3], ring: bool) -> FlareElementSettings
 	{
-		return &self.material;
+		FlareElementSettings {
+			position: position,
+			size: size,
+			color: color,
+			ring: ring,
+		}
 	}
 }
 
-struct SpinningCube
+/// What a constrained object is placed relative to.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConstraintTarget
+{
+	Car,
+	Camera,
+}
+
+/// A box moved by constraints relative to a target, as given in the scene file.
+#[derive(Clone, Serialize, Deserialize)]
+struct ConstrainedObjectSettings
+{
+	/// Size of the box along each axis, in meters.
+	size: [f32; 3],
+	/// Name of the material, see AssetCache::get_material().
+	material: String,
+	/// Where the object starts, before the constraints move it.
+	position: [f32; 3],
+	target: ConstraintTarget,
+	/// Applied in order, every frame.
+	constraints: Vec<Constraint>,
+}
+
+/// An object blending between the shapes of its morph targets, as given in the scene file. The weight of each target
+/// rises and falls in turn.
+#[derive(Clone, Serialize, Deserialize)]
+struct MorphObjectSettings
 {
-	transform: Transform,
-	mesh: Rc<Mesh>,
-	material: Rc<Material>,
+	/// A glTF model with morph targets. Without one, a box with generated squash, twist and taper targets is used.
+	#[serde(default)]
+	mesh: Option<String>,
+	/// Name of the material, see AssetCache::get_material().
+	material: String,
+	position: [f32; 3],
+	/// Seconds for each target to be blended in and out again.
+	#[serde(default = "MorphObjectSettings::default_period")]
+	period: f32,
 }
 
-impl SpinningCube
+impl MorphObjectSettings
 {
-	fn new(mesh: Rc<Mesh>, material: Rc<Material>) -> SpinningCube
+	fn default_period() -> f32
 	{
-		let obj = SpinningCube {
-			transform: Transform::new(),
-			mesh: mesh,
-			material: material,
-		};
-		return obj;
+		return 3.0;
 	}
+}
+
+/// Per-scene settings, read from the scene settings file.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct SceneSettings
+{
+	weather: WeatherSettings,
+	agents: Vec<AgentSettings>,
+	/// Without any, the scene gets a plain floor.
+	static_models: Vec<StaticModelSettings>,
+	props: Vec<PropSettings>,
+	roads: Vec<RoadSettings>,
+	race: RaceSettings,
+	lights: Vec<LightSettings>,
+	directional_lights: Vec<DirectionalLightSettings>,
+	reflection_probes: Vec<ReflectionProbeSettings>,
+	irradiance_grid: Option<IrradianceGridSettings>,
+	constrained_objects: Vec<ConstrainedObjectSettings>,
+	morph_objects: Vec<MorphObjectSettings>,
+	lens_flare: Vec<FlareElementSettings>,
+}
 
-	fn update(&mut self)
+impl Default for SceneSettings
+{
+	fn default() -> SceneSettings
 	{
-		self.globally_rotate(Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(-0.5)));
-		self.scale(1.001);
+		SceneSettings {
+			weather: WeatherSettings::default(),
+			agents: Vec::new(),
+			static_models: Vec::new(),
+			props: Vec::new(),
+			roads: Vec::new(),
+			race: RaceSettings::default(),
+			// A single light high above the scene
+			lights: vec![LightSettings {
+				position: [0.0, 5.0, 20.0],
+				radius: 1_000.0,
+				color: LightSettings::default_color(),
+				intensity: LightSettings::default_intensity(),
+				shadow_resolution: 1_024,
+				night_only: false,
+			}],
+			directional_lights: Vec::new(),
+			reflection_probes: Vec::new(),
+			irradiance_grid: None,
+			constrained_objects: Vec::new(),
+			morph_objects: Vec::new(),
+			// A glow around the sun, and faint ghosts along the axis
+			lens_flare: vec![
+				FlareElementSettings::new(0.0, 0.12, [0.15, 0.12, 0.08], false),
+				FlareElementSettings::new(0.0, 0.2, [0.06, 0.05, 0.03], true),
+				FlareElementSettings::new(0.5, 0.03, [0.05, 0.08, 0.12], false),
+				FlareElementSettings::new(1.2, 0.06, [0.08, 0.05, 0.1], false),
+				FlareElementSettings::new(1.5, 0.02, [0.1, 0.1, 0.05], false),
+				FlareElementSettings::new(2.0, 0.15, [0.03, 0.06, 0.04], true),
+			],
+		}
 	}
 }
 
-impl Transformable for SpinningCube
+impl SceneSettings
 {
-	fn get_transform(&self) -> &Transform
+	fn read(filename: &str) -> Result<SceneSettings, String>
+	{
+		let settings: SceneSettings = SCENE_FORMAT.read(filename).map_err(|e| format!("Could not read {}", e))?;
+		if settings.lens_flare.len() > MAX_FLARE_ELEMENTS
+		{
+			warning!("Only the first {} lens flare elements in {} are drawn.", MAX_FLARE_ELEMENTS, filename);
+		}
+		return Ok(settings);
+	}
+
+	/// Reads the scene settings from the given file, falling back to defaults if it cannot be read.
+	fn load(filename: &str) -> SceneSettings
 	{
-		return &self.transform;
+		match SceneSettings::read(filename)
+		{
+			Ok(settings) => settings,
+			Err(e) =>
+			{
+				warning!("{}, using default scene settings.", e);
+				SceneSettings::default()
+			}
+		}
 	}
-	fn get_mutable_transform(&mut self) -> &mut Transform
+}
+
+/// Returns true if the two values serialize differently.
+fn settings_differ<T: serde::Serialize>(a: &T, b: &T) -> bool
+{
+	return serde_json::to_value(a).ok() != serde_json::to_value(b).ok();
+}
+
+/// Returns the assets that nothing else has referenced for the timeout, and starts timing newly unreferenced ones.
+fn find_expired_assets<T>(
+	assets: &HashMap<String, Rc<T>>, unreferenced_since: &mut HashMap<String, Instant>, now: Instant, timeout: Duration,
+) -> Vec<String>
+{
+	let mut expired = Vec::new();
+	for (path, asset) in assets.iter()
 	{
-		return &mut self.transform;
+		if Rc::strong_count(asset) > 1
+		{
+			unreferenced_since.remove(path);
+		}
+		else
+		{
+			match unreferenced_since.get(path)
+			{
+				Some(since) if now.duration_since(*since) >= timeout => expired.push(path.clone()),
+				Some(_) => (),
+				None =>
+				{
+					unreferenced_since.insert(path.clone(), now);
+				}
+			}
+		}
 	}
+	return expired;
 }
 
-impl Drawable for SpinningCube
+/// A static model loaded with its materials, see load_obj_with_materials().
+struct StaticModel
 {
-	fn get_mesh(&self) -> &Mesh
+	parts: Vec<(Rc<Mesh>, Rc<Material>)>,
+	// MTL files the materials were read from
+	material_libs: Vec<String>,
+}
+
+/// Meshes, materials and static models loaded from files, which are watched for changes.
+///
+/// Assets nothing else has used for a while are released, and loaded again if needed later.
+struct AssetCache
+{
+	meshes: HashMap<String, Rc<Mesh>>,
+	materials: HashMap<String, Rc<Material>>,
+	models: HashMap<String, StaticModel>,
+	watcher: FileWatcher,
+	// Used when loading meshes
+	vertex_format: VertexFormat,
+	// When the cache started holding the only reference to an asset
+	unreferenced_since: HashMap<String, Instant>,
+}
+
+impl AssetCache
+{
+	fn get_mesh(&mut self, rs: &RenderState, path: &str) -> Result<Rc<Mesh>, String>
+	{
+		if let Some(mesh) = self.meshes.get(path)
+		{
+			return Ok(mesh.clone());
+		}
+		let mesh = Mesh::load(rs, path, self.vertex_format)?;
+		self.meshes.insert(path.to_string(), mesh.clone());
+		self.watcher.watch(path);
+		return Ok(mesh);
+	}
+
+	/// Returns the material with the given name, loaded from its file in MATERIALS_DIR. The name can also be the path
+	/// of a glTF model, for its material.
+	fn get_material(&mut self, rs: &RenderState, mp: &MainPass, name: &str) -> Result<Rc<Material>, String>
+	{
+		let path = material_path(name);
+		if let Some(material) = self.materials.get(&path)
+		{
+			return Ok(material.clone());
+		}
+		let material = Material::load(rs, mp, &path)?;
+		self.materials.insert(path.clone(), material.clone());
+		self.watcher.watch(&path);
+		return Ok(material);
+	}
+
+	/// Returns the meshes and materials of a static model. The model and its material libraries are watched.
+	fn get_model(
+		&mut self, rs: &RenderState, mp: &MainPass, path: &str,
+	) -> Result<Vec<(Rc<Mesh>, Rc<Material>)>, String>
+	{
+		if let Some(model) = self.models.get(path)
+		{
+			return Ok(model.parts.clone());
+		}
+		let (parts, material_libs) = load_obj_with_materials(rs, mp, path, self.vertex_format)?;
+		self.watcher.watch(path);
+		for lib in material_libs.iter()
+		{
+			self.watcher.watch(lib);
+		}
+		self.models.insert(
+			path.to_string(),
+			StaticModel {
+				parts: parts.clone(),
+				material_libs: material_libs,
+			},
+		);
+		return Ok(parts);
+	}
+
+	/// Reloads the static models read from the changed file, as the model or one of its material libraries. Returns
+	/// true if any was reloaded, the objects using them need to be recreated with get_model().
+	fn reload_models(&mut self, rs: &RenderState, mp: &MainPass, path: &str) -> bool
 	{
-		return &self.mesh;
+		let affected: Vec<String> = self
+			.models
+			.iter()
+			.filter(|(model_path, model)| *model_path == path || model.material_libs.iter().any(|lib| lib == path))
+			.map(|(model_path, _)| model_path.clone())
+			.collect();
+		let mut reloaded = false;
+		for model_path in affected
+		{
+			match load_obj_with_materials(rs, mp, &model_path, self.vertex_format)
+			{
+				Ok((parts, material_libs)) =>
+				{
+					for lib in material_libs.iter()
+					{
+						self.watcher.watch(lib);
+					}
+					self.models.insert(
+						model_path,
+						StaticModel {
+							parts: parts,
+							material_libs: material_libs,
+						},
+					);
+					reloaded = true;
+				}
+				Err(e) => warning!("Could not reload static model: {}", e),
+			}
+		}
+		return reloaded;
+	}
+
+	/// Returns whether any loaded asset was read from the file.
+	fn uses_file(&self, path: &str) -> bool
+	{
+		return self.meshes.contains_key(path) ||
+			self.materials.contains_key(path) ||
+			self.models
+				.iter()
+				.any(|(model_path, model)| model_path == path || model.material_libs.iter().any(|lib| lib == path));
 	}
-	fn get_material(&self) -> &Material
+
+	/// Releases the assets unreferenced for the timeout, through the deletion queue. Runs once per frame, and destroys
+	/// released GPU resources within the time budget.
+	fn collect_garbage(&mut self, rs: &RenderState, timeout: Duration, budget: Duration)
 	{
-		return &self.material;
+		let start = Instant::now();
+		let expired_meshes = find_expired_assets(&self.meshes, &mut self.unreferenced_since, start, timeout);
+		let expired_materials = find_expired_assets(&self.materials, &mut self.unreferenced_since, start, timeout);
+		for path in expired_meshes.iter().chain(expired_materials.iter())
+		{
+			println!("Releasing {}, unused for {} s", path, timeout.as_secs_f32());
+			self.unreferenced_since.remove(path);
+		}
+		// Mesh buffers are released when dropped, but materials also free their descriptor sets
+		for path in expired_meshes.iter()
+		{
+			self.meshes.remove(path);
+		}
+		for path in expired_materials.iter()
+		{
+			let material = self.materials.remove(path).unwrap();
+			rs.defer(material);
+		}
+		// Static models are only loaded again when the scene settings change, so they go with their last object
+		let unused_models: Vec<String> = self
+			.models
+			.iter()
+			.filter(|(_, model)| model.parts.iter().all(|(mesh, _)| Rc::strong_count(mesh) == 1))
+			.map(|(path, _)| path.clone())
+			.collect();
+		let mut unused_files = Vec::new();
+		for path in unused_models
+		{
+			println!("Releasing {}, no longer used", path);
+			let model = self.models.remove(&path).unwrap();
+			for (_, material) in model.parts
+			{
+				rs.defer(material);
+			}
+			unused_files.push(path);
+			unused_files.extend(model.material_libs);
+		}
+		// glTF models are both a mesh and a material, and material libraries can be shared by static models
+		for path in expired_meshes.iter().chain(expired_materials.iter()).chain(unused_files.iter())
+		{
+			if !self.uses_file(path)
+			{
+				self.watcher.unwatch(path);
+			}
+		}
+		rs.collect_garbage(budget.checked_sub(start.elapsed()).unwrap_or_default());
 	}
 }
 
+struct ConstrainedObject
+{
+	entity: Entity,
+	target: ConstraintTarget,
+	constraints: Vec<Constraint>,
+}
+
+/// Creates a box that squashes, twists and tapers.
+fn create_morph_box(rs: &RenderState) -> Result<Rc<Mesh>, String>
+{
+	let half = 0.5 * MORPH_BOX_SIZE;
+	let mesh = Mesh::new_cuboid(rs, MORPH_BOX_SIZE, MORPH_BOX_SIZE, MORPH_BOX_SIZE);
+	let targets = [
+		mesh.generate_morph_target("squash", |p| Point3::new(1.3 * p.x, 0.5 * p.y, 1.3 * p.z)),
+		mesh.generate_morph_target("twist", |p| {
+			let rotation = Quaternion::from_angle_y(Deg(45.0 * p.y / half));
+			return rotation.rotate_point(p);
+		}),
+		mesh.generate_morph_target("taper", |p| {
+			let scale = 1.0 - 0.3 * (p.y / half + 1.0);
+			return Point3::new(scale * p.x, p.y, scale * p.z);
+		}),
+	];
+	return mesh.with_morph_targets(rs, &targets);
+}
+
+/// Spawns static objects named after their kind and index. Spawned once all of them are created, so the world is left
+/// as it was when creating one fails.
+fn spawn_statics(world: &mut World, kind: &str, objects: Vec<(TransformComponent, RenderComponent)>) -> Vec<Entity>
+{
+	return objects
+		.into_iter()
+		.enumerate()
+		.map(|(idx, (transform, render))| {
+			let entity = world.spawn_drawable(&format!("{} {}", kind, idx), transform, render);
+			world.statics.insert(entity, StaticComponent);
+			entity
+		})
+		.collect();
+}
+
+/// Returns the triangles of a drawn entity, in world space.
+fn get_world_triangles(world: &World, entity: Entity) -> Vec<[Point3<f32>; 3]>
+{
+	return match world.get_drawable(entity)
+	{
+		Some((transform, render)) => render.get_mesh().get_triangles(&transform.generate_transformation_matrix()),
+		None => Vec::new(),
+	};
+}
+
 pub struct Scene
 {
 	camera: Rc<RefCell<Camera>>,
-	static_stuff: Vec<StaticObject>,
-	spinning_cube: SpinningCube,
+	framing_input: Rc<RefCell<FramingInput>>,
+	camera_toggle_input: Rc<RefCell<CameraToggleInput>>,
+	camera_effects: CameraEffects,
+	camera_mode: CameraMode,
+	// Blends the view after a camera mode switch, until it is over
+	camera_transition: Option<CameraTransition>,
+	// Where the free camera was when the camera last left the free mode, so switching back returns there
+	free_camera: Option<Transform>,
+	camera_boom: CameraBoom,
+	orbit: Orbit,
+	// Object whose center the orbit pivot was last moved to, see get_object_bounds()
+	orbit_object: Option<usize>,
+	depth_of_field: DepthOfField,
+	post_effects: PostEffects,
+	// Contacts between the camera, the car and the agents
+	contacts: ContactWorld,
+	// Collisions of the car with the ground and the entities with colliders
+	rigid_bodies: RigidBodyWorld,
+	// Positions and impulses of car collisions since the last audio update
+	car_impacts: Vec<(Point3<f32>, f32)>,
+	// Positions, normals and impulses of car collisions waiting to be baked as scratches, see bake_damage()
+	car_scratches: Vec<(Point3<f32>, Vector3<f32>, f32)>,
+	// Seeds the pattern of each scratch
+	scratch_count: u32,
+	vehicle_audio: VehicleAudio,
+	last_camera_position: Point3<f32>,
+	// The objects other than the car, the character and the agents
+	world: World,
+	static_stuff: Vec<Entity>,
+	props: Vec<Entity>,
+	// Indices of the props drawn together as instances, every prop is in exactly one group
+	prop_instances: Vec<Vec<usize>>,
+	roads: Vec<Entity>,
+	// The static objects, props and roads, for casting against instead of their bounds
+	static_collision: TriangleMesh,
+	// The static objects, props and roads merged by material, drawn instead of them when static batching is enabled
+	static_batches: Vec<RenderComponent>,
+	static_batching: bool,
+	frustum_culling: bool,
+	spinning_cube: Entity,
+	// Not part of the static batches or the navmesh, as they move
+	constrained_objects: Vec<ConstrainedObject>,
+	morph_objects: Vec<Entity>,
+	// The driving state of the car entity
 	car: Rc<RefCell<Car>>,
+	car_entity: Entity,
+	// The player while out of the car, riding in the driver's seat otherwise
+	character: Rc<RefCell<Character>>,
+	interact_input: Rc<RefCell<InteractInput>>,
+	in_vehicle: bool,
+	// Entering and exiting the car since the last drain_vehicle_events()
+	vehicle_events: Vec<VehicleEvent>,
+	replay: Replay,
+	// Behind the left and right rear wheels
+	skidmarks: Vec<Trail>,
+	// Behind the left and right tail lights
+	light_trails: Vec<Trail>,
+	weather: WeatherController,
+	time_of_day: TimeOfDay,
+	// Lit after those of the scene file, see add_light()
+	added_lights: Vec<(LightId, Light)>,
+	next_light_id: u32,
+	timelines: Timelines,
+	race: RaceManager,
+	navmesh: NavMesh,
+	nav_debug: bool,
+	nav_debug_lines: LineBatch,
+	nav_path_lines: LineBatch,
+	agents: Vec<Agent>,
+	agent_debug: bool,
+	agent_debug_lines: LineBatch,
+	debug_table: bool,
+	world_labels: bool,
+	sun_shafts_intensity: f32,
+	sun_shafts_decay: f32,
+	lens_flare_intensity: f32,
+	material_editor: Rc<RefCell<MaterialEditor>>,
+	texture_painter: Rc<RefCell<TexturePainter>>,
+	edit_history: Rc<RefCell<EditHistory>>,
+	settings: SceneSettings,
+	// Name of the loaded gallery scene, "default" for the scene file
+	scene_name: String,
+	assets: AssetCache,
+	agent_mesh: Rc<Mesh>,
+	agent_material: Rc<Material>,
 }
 
 impl Scene
 {
-	pub fn new(rs: &RenderState, mp: &MainPass, cfg: &Config, input_handler: &mut InputHandler) -> Scene
+	pub fn new(
+		rs: &RenderState, mp: &MainPass, cfg: &Config, random: &RandomService, input_handler: &mut InputHandler,
+		console: &mut Console, audio: &mut AudioSystem, damage_baker: &DamageBaker,
+	) -> Scene
 	{
+		let settings = SceneSettings::load(SCENE_SETTINGS_FILE);
+
 		let camera = Rc::new(RefCell::new(Camera::new(Point3::new(0.0, 10.0, 0.0), -Vector3::unit_z())));
 		// input_handler.register_actions(camera.clone(), ActionType::TICK);
 		input_handler.register_mouse_movement(
@@ -121,93 +817,2206 @@ impl Scene
 			cfg.mouse_sensitivity,
 		);
 
-		let metal_panel_surface = Material::new(
-			rs,
-			mp,
-			"assets/thirdparty/textures/Metal_Panel_004/Metal_Panel_004_COLOR.jpg",
-			"assets/thirdparty/textures/Metal_Panel_004/Metal_Panel_004_NORM.jpg",
-		);
-		let cube_surface = Material::new(
-			rs,
-			mp,
-			"assets/original/textures/cubemap.png",
-			"assets/original/textures/cubemap_normals.png",
-		);
+		let framing_input = Rc::new(RefCell::new(FramingInput::new()));
+		input_handler.register_actions(framing_input.clone(), ActionType::IMMEDIATE);
+		let camera_toggle_input = Rc::new(RefCell::new(CameraToggleInput::new()));
+		input_handler.register_actions(camera_toggle_input.clone(), ActionType::IMMEDIATE);
 
-		let mut static_stuff = Vec::new();
+		let mut gameplay_rng = random.create_rng(RandomStream::Gameplay, 0);
+		let camera_effects = CameraEffects::new(console, &mut gameplay_rng);
+		let depth_of_field = DepthOfField::new(console);
+		let post_effects = PostEffects::new(console);
+		let vehicle_audio = VehicleAudio::new(audio);
+		let camera_position = camera.borrow().get_position();
 
-		let floor_mesh = Mesh::new_quad(rs, 1_000.0, 1_000.0);
-		let mut floor = StaticObject::new(floor_mesh.clone(), metal_panel_surface.clone());
-		floor.globally_rotate(Quaternion::from_axis_angle(Vector3::new(-1.0, 0.0, 0.0), Deg(90.0)));
-		static_stuff.push(floor);
+		let mut assets = AssetCache {
+			meshes: HashMap::new(),
+			materials: HashMap::new(),
+			models: HashMap::new(),
+			watcher: FileWatcher::new(),
+			vertex_format: if cfg.packed_vertices
+			{
+				rs.get_packed_vertex_format()
+			}
+			else
+			{
+				VertexFormat::Full
+			},
+			unreferenced_since: HashMap::new(),
+		};
+		assets.watcher.watch(SCENE_SETTINGS_FILE);
+		Scene::preload_assets(rs, mp, &mut assets, &settings);
+		let cube_surface = assets.get_material(rs, mp, "cube").unwrap();
+
+		let mut world = World::new();
+		let static_stuff = Scene::create_static_stuff(rs, mp, &mut assets, &mut world, &settings.static_models)
+			.unwrap_or_else(|e| {
+				warning!("Could not load static models: {}", e);
+				Scene::create_floor(rs, mp, &mut assets, &mut world)
+			});
 
 		let cuboid_mesh = Mesh::new_cuboid(rs, 2.0, 2.0, 2.0);
-		let mut spinning_cube = SpinningCube::new(cuboid_mesh, cube_surface.clone());
-		spinning_cube.set_position(Point3::new(0.0, 5.0, -4.0));
+		let cube_collider = ColliderComponent::new(&cuboid_mesh, OBJECT_MATERIAL);
+		let mut cube_transform = TransformComponent::new();
+		cube_transform.set_position(Point3::new(0.0, 5.0, -4.0));
+		let spinning_cube = world.spawn_drawable(
+			"spinning cube",
+			cube_transform,
+			RenderComponent::new(cuboid_mesh, cube_surface.clone()),
+		);
+		world.physics.insert(
+			spinning_cube,
+			PhysicsComponent {
+				angular_velocity: Vector3::new(0.0, -Rad::from(Deg(30.0)).0, 0.0),
+				growth: 1.06,
+				..Default::default()
+			},
+		);
+		world.colliders.insert(spinning_cube, cube_collider);
+
+		let constrained_objects =
+			Scene::create_constrained_objects(rs, mp, &mut assets, &mut world, &settings.constrained_objects)
+				.unwrap_or_else(|e| {
+					warning!("Could not create constrained objects: {}", e);
+					Vec::new()
+				});
+		let morph_objects = Scene::create_morph_objects(rs, mp, &mut assets, &mut world, &settings.morph_objects)
+			.unwrap_or_else(|e| {
+				warning!("Could not create morph objects: {}", e);
+				Vec::new()
+			});
 
 		// Some standard car numbers (1.8m wide, 1.5m tall, 4.3m long, 1524kg)
 		let car_mesh = Mesh::new_cuboid(rs, 1.8, 1.5, 4.3);
-		let car = Rc::new(RefCell::new(Car::new(1_524.0, car_mesh, cube_surface.clone())));
-		car.borrow_mut().set_position(Point3::new(0.0, 0.75, 0.0));
-		input_handler.register_actions(car.clone(), ActionType::TICK);
-
-		// For now, this is just done to not have the code unused.
-		let points = vec![
-			Point3::new(1.0, 0.0, 0.0),
-			Point3::new(0.0, 1.0, 0.0),
-			Point3::new(-1.0, 0.0, 0.0),
-			Point3::new(0.0, -1.0, 0.0),
-			Point3::new(0.0, 0.0, 1.0),
-			Point3::new(0.0, 0.0, -1.0),
-			Point3::new(0.0, 1.0, -1.0),
-			Point3::new(1.0, 0.0, -1.0),
-		];
+		// Scratches are baked into a damage texture of its own
+		let car_surface = cube_surface.create_damaged(rs, mp, damage_baker.create_texture(rs, CAR_DAMAGE_RESOLUTION));
+		let car = Rc::new(RefCell::new(Car::new(1_524.0, &car_mesh)));
+		let mut car_transform = TransformComponent::new();
+		car_transform.set_position(Point3::new(0.0, 0.75, 0.0));
+		let car_entity = world.spawn_drawable("car", car_transform, RenderComponent::new(car_mesh, car_surface));
+		input_handler.register_context_actions(car.clone(), ActionType::TICK, InputContext::Vehicle);
+		let character = Rc::new(RefCell::new(Character::new()));
+		input_handler.register_context_actions(character.clone(), ActionType::TICK, InputContext::OnFoot);
+		let interact_input = Rc::new(RefCell::new(InteractInput::new()));
+		input_handler.register_actions(interact_input.clone(), ActionType::TICK);
+
+		let weather = WeatherController::new(rs, &settings.weather, random, console);
+		let time_of_day = TimeOfDay::new(console);
+		let timelines = Timelines::load(TIMELINES_DIR);
+		for path in timelines.get_paths()
+		{
+			assets.watcher.watch(&path);
+		}
+
+		let props = Scene::create_props(rs, mp, &mut assets, &mut world, &settings.props).unwrap_or_else(|e| {
+			warning!("Could not create props: {}", e);
+			Vec::new()
+		});
+		let prop_instances = Scene::group_prop_instances(&world, &props, &settings.props);
+		let roads = Scene::create_roads(rs, mp, &mut assets, &mut world, &settings.roads).unwrap_or_else(|e| {
+			warning!("Could not create roads: {}", e);
+			Vec::new()
+		});
+
+		// The navmesh is built from the walkable static geometry and the other objects' initial placement.
+		let mut obstacles = vec![get_world_triangles(&world, spinning_cube), get_world_triangles(&world, car_entity)];
+		obstacles.extend(props.iter().map(|&prop| get_world_triangles(&world, prop)));
+		let navmesh = Scene::build_navmesh(&world, static_stuff.iter().chain(roads.iter()), &obstacles);
+		let static_collision = Scene::cook_static_collision(&world);
+		let static_batches = Scene::create_static_batches(rs, &world, assets.vertex_format);
+		console.register_bool(
+			"static_batching",
+			true,
+			"Draw static objects sharing a material as one merged mesh, for fewer draw calls",
+		);
+		console.register_bool("frustum_culling", true, "Skip drawing objects outside the view");
+
+		console.register_float(
+			"asset_gc_timeout",
+			30.0,
+			"Seconds a loaded mesh or material can go unused before it is released",
+		);
+		console.register_float("asset_gc_budget", 0.5, "Time per frame spent releasing unused assets, in milliseconds");
 
-		let mut u = 0.0;
-		let step = 0.1;
-		let spline = NURBSpline::new(Order::CUBIC, points);
+		console.register_float("cam_follow_distance", 8.0, "Distance of the chase camera from the car, in meters");
 
-		while u < spline.eval_limit()
+		console.register_bool("skidmarks", true, "Leave skidmarks behind the car's rear wheels when sliding");
+		console.register_bool("light_trails", false, "Leave light trails behind the car's tail lights");
+		let skidmarks = (0..2)
+			.map(|_| Trail::new(rs, MAX_SKIDMARK_SEGMENTS, SKIDMARK_WIDTH, SKIDMARK_LIFETIME, SKIDMARK_COLOR))
+			.collect();
+		let light_trails = (0..2)
+			.map(|_| {
+				Trail::new(rs, MAX_LIGHT_TRAIL_SEGMENTS, LIGHT_TRAIL_WIDTH, LIGHT_TRAIL_LIFETIME, LIGHT_TRAIL_COLOR)
+			})
+			.collect();
+
+		console.register_bool("nav_debug", false, "Draw the navmesh, and a path from the camera to the cube");
+		let nav_debug_lines = Scene::create_nav_debug_lines(rs, &navmesh);
+		let nav_path_lines = LineBatch::new(rs, MAX_NAV_PATH_LINES);
+
+		let agent_mesh = Mesh::new_cuboid(rs, 0.6, AGENT_HEIGHT, 0.6);
+		let agents = Scene::create_agents(&settings.agents, random, &agent_mesh, &cube_surface);
+		let mut race = RaceManager::new(&settings.race, console);
+		if !settings.race.checkpoints.is_empty()
 		{
-			let _point = spline.evaluate_at(u);
-			u += step;
+			race.start(&agents);
 		}
+		console.register_bool("ai_debug", false, "Draw the targets, velocities and paths of AI agents");
+		let agent_debug_lines = LineBatch::new(rs, MAX_AGENT_DEBUG_LINES);
+		console.register_bool("debug_table", false, "Draw a table of the position and velocity of all AI agents");
+		console.register_bool(
+			"world_labels",
+			false,
+			"Label AI agents with their name and distance, and mark where they walk",
+		);
+		console.register_float(
+			"sun_shafts_intensity",
+			0.5,
+			"Brightness of the light shafts from the sun, 0 disables them",
+		);
+		console.register_float("sun_shafts_decay", 0.96, "Falloff of the sun shafts with distance from the sun [0, 1]");
+		console.register_float("lens_flare_intensity", 1.0, "Brightness of the lens flare from the sun, 0 disables it");
+
+		let material_editor = Rc::new(RefCell::new(MaterialEditor::new()));
+		input_handler.register_actions(material_editor.clone(), ActionType::IMMEDIATE);
+		let texture_painter = Rc::new(RefCell::new(TexturePainter::new()));
+		input_handler.register_actions(texture_painter.clone(), ActionType::IMMEDIATE);
+		let edit_history = Rc::new(RefCell::new(EditHistory::new()));
+		input_handler.register_actions(edit_history.clone(), ActionType::IMMEDIATE);
 
 		let scene = Scene {
 			camera: camera,
+			framing_input: framing_input,
+			camera_toggle_input: camera_toggle_input,
+			camera_effects: camera_effects,
+			camera_mode: CameraMode::Free,
+			camera_transition: None,
+			free_camera: None,
+			camera_boom: CameraBoom::new(),
+			orbit: Orbit::new(Point3::origin(), ORBIT_START_DISTANCE),
+			orbit_object: None,
+			depth_of_field: depth_of_field,
+			post_effects: post_effects,
+			contacts: ContactWorld::new(),
+			rigid_bodies: RigidBodyWorld::new(Vector3::new(0.0, -GRAVITY, 0.0), GROUND_HEIGHT, GROUND_MATERIAL),
+			car_impacts: Vec::new(),
+			car_scratches: Vec::new(),
+			scratch_count: 0,
+			vehicle_audio: vehicle_audio,
+			last_camera_position: camera_position,
+			world: world,
 			static_stuff: static_stuff,
+			props: props,
+			prop_instances: prop_instances,
+			roads: roads,
+			static_collision: static_collision,
+			static_batches: static_batches,
+			static_batching: true,
+			frustum_culling: true,
 			spinning_cube: spinning_cube,
+			constrained_objects: constrained_objects,
+			morph_objects: morph_objects,
 			car: car,
+			car_entity: car_entity,
+			character: character,
+			interact_input: interact_input,
+			in_vehicle: true,
+			vehicle_events: Vec::new(),
+			replay: Replay::new(),
+			skidmarks: skidmarks,
+			light_trails: light_trails,
+			weather: weather,
+			time_of_day: time_of_day,
+			added_lights: Vec::new(),
+			next_light_id: 0,
+			timelines: timelines,
+			race: race,
+			navmesh: navmesh,
+			nav_debug: false,
+			nav_debug_lines: nav_debug_lines,
+			nav_path_lines: nav_path_lines,
+			agents: agents,
+			agent_debug: false,
+			agent_debug_lines: agent_debug_lines,
+			debug_table: false,
+			world_labels: false,
+			sun_shafts_intensity: 0.0,
+			sun_shafts_decay: 0.0,
+			lens_flare_intensity: 0.0,
+			material_editor: material_editor,
+			texture_painter: texture_painter,
+			edit_history: edit_history,
+			settings: settings,
+			scene_name: "default".to_string(),
+			assets: assets,
+			agent_mesh: agent_mesh,
+			agent_material: cube_surface,
 		};
 
 		return scene;
 	}
 
-	pub fn get_view_matrix(&mut self) -> Matrix4<f32>
+	/// Warns about missing files of the assets in the scene manifest and settings, and loads the assets of the
+	/// manifest, while the loading screen is shown.
+	fn preload_assets(rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, settings: &SceneSettings)
+	{
+		let manifest = AssetManifest::read(SCENE_MANIFEST_FILE).unwrap_or_else(|e| {
+			warning!("Could not read {}, assets are loaded when first used", e);
+			AssetManifest::default()
+		});
+		let material_path = |name: &str| AssetRef::Material(material_path(name));
+
+		let mut graph = AssetGraph::new();
+		let manifest_assets: Vec<AssetRef> = manifest
+			.meshes
+			.iter()
+			.map(|path| AssetRef::Mesh(path.clone()))
+			.chain(manifest.materials.iter().map(|name| material_path(name)))
+			.collect();
+		for asset in &manifest_assets
+		{
+			graph.add(asset.clone());
+		}
+		let scene_assets = settings
+			.props
+			.iter()
+			.flat_map(|prop| vec![AssetRef::Mesh(prop.mesh.clone()), material_path(&prop.material)])
+			.chain(settings.roads.iter().map(|road| material_path(&road.material)));
+		for asset in scene_assets
+		{
+			if !manifest_assets.contains(&asset)
+			{
+				warning!("The scene uses {}, which is not in {}", asset, SCENE_MANIFEST_FILE);
+			}
+			graph.add(asset);
+		}
+		for (asset, user) in graph.find_missing()
+		{
+			match user
+			{
+				Some(user) => warning!("Missing {}, used by {}", asset, user),
+				None => warning!("Missing {}", asset),
+			}
+		}
+
+		// Loading an asset with missing files fails, or worse
+		let start = Instant::now();
+		let mut num_preloaded = 0;
+		for asset in manifest_assets.iter().filter(|asset| graph.is_available(asset))
+		{
+			let result = match asset
+			{
+				AssetRef::Mesh(path) => assets.get_mesh(rs, path).map(|_| ()),
+				AssetRef::Material(path) => assets.get_material(rs, mp, &material_name(path)).map(|_| ()),
+				_ => Ok(()),
+			};
+			match result
+			{
+				Ok(()) => num_preloaded += 1,
+				Err(e) => warning!("Could not preload {}: {}", asset, e),
+			}
+		}
+		println!("Preloaded {} assets in {} ms", num_preloaded, start.elapsed().as_millis());
+	}
+
+	/// Writes the meshes and materials loaded now as the manifest of the scene file.
+	fn write_asset_manifest(&self)
 	{
-		return self.camera.borrow().generate_view_matrix();
+		let mut manifest = AssetManifest::default();
+		manifest.meshes = self.assets.meshes.keys().cloned().collect();
+		manifest.materials = self.assets.materials.keys().map(|path| material_name(path)).collect();
+		manifest.meshes.sort();
+		manifest.materials.sort();
+		match manifest.write(SCENE_MANIFEST_FILE)
+		{
+			Ok(()) => println!(
+				"Wrote {} meshes and {} materials to {}",
+				manifest.meshes.len(),
+				manifest.materials.len(),
+				SCENE_MANIFEST_FILE
+			),
+			Err(e) => warning!("Could not write the asset manifest: {}", e),
+		}
 	}
 
-	pub fn update(&mut self)
+	/// Loads the static models, one object per material of each. Scenes without models get a plain floor.
+	fn create_static_stuff(
+		rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, world: &mut World, settings: &[StaticModelSettings],
+	) -> Result<Vec<Entity>, String>
 	{
-		self.spinning_cube.update();
-		self.car.borrow_mut().update();
+		if settings.is_empty()
+		{
+			return Ok(Scene::create_floor(rs, mp, assets, world));
+		}
+		let mut static_stuff = Vec::new();
+		for model_settings in settings
+		{
+			for (mesh, material) in assets.get_model(rs, mp, &model_settings.path)?
+			{
+				let mut transform = TransformComponent::new();
+				transform.set_position(Point3::from(model_settings.position));
+				transform.yaw(model_settings.yaw);
+				static_stuff.push((transform, RenderComponent::new(mesh, material)));
+			}
+		}
+		return Ok(spawn_statics(world, "static object", static_stuff));
 	}
 
-	pub fn draw(
-		&mut self, device: &Device, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout,
-		view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
-	)
+	fn create_floor(rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, world: &mut World) -> Vec<Entity>
+	{
+		let metal_panel_surface = assets.get_material(rs, mp, "metal_panel").unwrap();
+		let floor_mesh = Mesh::new_quad(rs, 1_000.0, 1_000.0);
+		let mut transform = TransformComponent::new();
+		transform.globally_rotate(Quaternion::from_axis_angle(Vector3::new(-1.0, 0.0, 0.0), Deg(90.0)));
+		return spawn_statics(
+			world,
+			"static object",
+			vec![(transform, RenderComponent::new(floor_mesh, metal_panel_surface))],
+		);
+	}
+
+	fn create_props(
+		rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, world: &mut World, settings: &[PropSettings],
+	) -> Result<Vec<Entity>, String>
+	{
+		let mut props = Vec::with_capacity(settings.len());
+		let mut colliders = Vec::with_capacity(settings.len());
+		for prop_settings in settings
+		{
+			let mesh = assets.get_mesh(rs, &prop_settings.mesh)?;
+			let material = assets.get_material(rs, mp, &prop_settings.material)?;
+			let mut transform = TransformComponent::new();
+			transform.set_position(Point3::from(prop_settings.position));
+			transform.yaw(prop_settings.yaw);
+			colliders.push(ColliderComponent::new(&mesh, OBJECT_MATERIAL));
+			props.push((transform, RenderComponent::new(mesh, material)));
+		}
+		let props = spawn_statics(world, "prop", props);
+		for (&prop, collider) in props.iter().zip(colliders)
+		{
+			world.colliders.insert(prop, collider);
+		}
+		return Ok(props);
+	}
+
+	fn create_constrained_objects(
+		rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, world: &mut World,
+		settings: &[ConstrainedObjectSettings],
+	) -> Result<Vec<ConstrainedObject>, String>
+	{
+		let mut parts = Vec::with_capacity(settings.len());
+		for object_settings in settings
+		{
+			let size = object_settings.size;
+			let mesh = Mesh::new_cuboid(rs, size[0], size[1], size[2]);
+			let material = assets.get_material(rs, mp, &object_settings.material)?;
+			let mut transform = TransformComponent::new();
+			transform.set_position(Point3::from(object_settings.position));
+			parts.push((transform, RenderComponent::new(mesh, material)));
+		}
+		let objects = parts
+			.into_iter()
+			.zip(settings.iter())
+			.enumerate()
+			.map(|(idx, ((transform, render), object_settings))| {
+				let collider = ColliderComponent::new(&render.mesh, OBJECT_MATERIAL);
+				let entity = world.spawn_drawable(&format!("constrained object {}", idx), transform, render);
+				world.colliders.insert(entity, collider);
+				ConstrainedObject {
+					entity: entity,
+					target: object_settings.target,
+					constraints: object_settings.constraints.clone(),
+				}
+			})
+			.collect();
+		return Ok(objects);
+	}
+
+	fn create_morph_objects(
+		rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, world: &mut World, settings: &[MorphObjectSettings],
+	) -> Result<Vec<Entity>, String>
+	{
+		let mut parts = Vec::with_capacity(settings.len());
+		for object_settings in settings
+		{
+			let mesh = match &object_settings.mesh
+			{
+				Some(path) => assets.get_mesh(rs, path)?,
+				None => create_morph_box(rs)?,
+			};
+			let material = assets.get_material(rs, mp, &object_settings.material)?;
+			let mut render = RenderComponent::new(mesh, material);
+			render.morph_weights = Some(MorphWeights::new(rs, mp, &render.mesh)?);
+			let mut transform = TransformComponent::new();
+			transform.set_position(Point3::from(object_settings.position));
+			parts.push((transform, render, MorphComponent::new(object_settings.period)));
+		}
+		let objects = parts
+			.into_iter()
+			.enumerate()
+			.map(|(idx, (transform, render, morph))| {
+				let collider = ColliderComponent::new(&render.mesh, OBJECT_MATERIAL);
+				let entity = world.spawn_drawable(&format!("morph object {}", idx), transform, render);
+				world.morphs.insert(entity, morph);
+				world.colliders.insert(entity, collider);
+				entity
+			})
+			.collect();
+		return Ok(objects);
+	}
+
+	/// Groups the props with the same mesh and material, to be drawn as instances. Opted out props get a group of their
+	/// own.
+	fn group_prop_instances(world: &World, props: &[Entity], settings: &[PropSettings]) -> Vec<Vec<usize>>
+	{
+		let renders: Vec<&RenderComponent> = props.iter().filter_map(|&prop| world.renders.get(prop)).collect();
+		// In the order the groups first appear
+		let mut groups: Vec<Vec<usize>> = Vec::new();
+		for (idx, (prop, prop_settings)) in renders.iter().zip(settings.iter()).enumerate()
+		{
+			let group = groups.iter_mut().find(|group| {
+				let first = renders[group[0]];
+				prop_settings.instanced &&
+					settings[group[0]].instanced &&
+					Rc::ptr_eq(&first.mesh, &prop.mesh) &&
+					Rc::ptr_eq(&first.material, &prop.material)
+			});
+			match group
+			{
+				Some(group) => group.push(idx),
+				None => groups.push(vec![idx]),
+			}
+		}
+		if groups.len() < props.len()
+		{
+			println!("Instanced {} props into {} draws", props.len(), groups.len());
+		}
+		return groups;
+	}
+
+	/// Generates the road meshes.
+	fn create_roads(
+		rs: &RenderState, mp: &MainPass, assets: &mut AssetCache, world: &mut World, settings: &[RoadSettings],
+	) -> Result<Vec<Entity>, String>
+	{
+		let mut roads = Vec::with_capacity(settings.len());
+		for road_settings in settings
+		{
+			let road = Road::new(rs, road_settings, assets.vertex_format)?;
+			let material = assets.get_material(rs, mp, &road_settings.material)?;
+			roads.push((TransformComponent::new(), RenderComponent::new(road.get_mesh(), material)));
+		}
+		return Ok(spawn_statics(world, "road", roads));
+	}
+
+	/// Cooks a triangle mesh collider from the render meshes of the static objects, in world space.
+	fn cook_static_collision(world: &World) -> TriangleMesh
+	{
+		let mut triangles = Vec::new();
+		for (entity, _, _) in world.get_drawables(true)
+		{
+			triangles.extend(get_world_triangles(world, entity));
+		}
+		return TriangleMesh::cook(&triangles);
+	}
+
+	/// Merges the static objects by material.
+	fn create_static_batches(rs: &RenderState, world: &World, vertex_format: VertexFormat) -> Vec<RenderComponent>
+	{
+		// In the order the materials first appear
+		let mut groups: Vec<(Rc<Material>, Vec<(&Mesh, Matrix4<f32>)>)> = Vec::new();
+		let mut num_objects = 0;
+		for (_, transform, render) in world.get_drawables(true)
+		{
+			num_objects += 1;
+			let part = (render.get_mesh(), transform.generate_transformation_matrix());
+			match groups.iter_mut().find(|(material, _)| Rc::ptr_eq(material, &render.material))
+			{
+				Some((_, parts)) => parts.push(part),
+				None => groups.push((render.material.clone(), vec![part])),
+			}
+		}
+
+		let mut batches = Vec::new();
+		for (material, parts) in groups
+		{
+			for mesh in Mesh::merge(rs, &parts, vertex_format)
+			{
+				batches.push(RenderComponent::new(mesh, material.clone()));
+			}
+		}
+		println!("Batched {} static objects into {} draws", num_objects, batches.len());
+		return batches;
+	}
+
+	fn create_agents(
+		settings: &[AgentSettings], random: &RandomService, mesh: &Rc<Mesh>, material: &Rc<Material>,
+	) -> Vec<Agent>
+	{
+		return settings
+			.iter()
+			.enumerate()
+			.map(|(idx, agent_settings)| {
+				let rng = random.create_rng(RandomStream::AI, idx as u32);
+				Agent::new(agent_settings, rng, AGENT_HEIGHT, mesh.clone(), material.clone())
+			})
+			.collect();
+	}
+
+	fn build_navmesh<'a>(
+		world: &World, walkable: impl Iterator<Item = &'a Entity>, obstacles: &[Vec<[Point3<f32>; 3]>],
+	) -> NavMesh
+	{
+		let mut navmesh_builder = NavMeshBuilder::new(NavMeshConfig::default());
+		for &entity in walkable
+		{
+			navmesh_builder.add_walkable(&get_world_triangles(world, entity));
+		}
+		for obstacle in obstacles
+		{
+			navmesh_builder.add_obstacle(obstacle);
+		}
+		return navmesh_builder.build();
+	}
+
+	fn create_nav_debug_lines(rs: &RenderState, navmesh: &NavMesh) -> LineBatch
+	{
+		let nav_debug_vertices = navmesh.get_debug_lines();
+		let mut nav_debug_lines = LineBatch::new(rs, nav_debug_vertices.len() / 2);
+		nav_debug_lines.set_lines(&nav_debug_vertices);
+		return nav_debug_lines;
+	}
+
+	/// Rebuilds the navmesh around the objects' current placement.
+	fn rebuild_navmesh(&mut self, rs: &RenderState)
+	{
+		let mut obstacles = vec![
+			get_world_triangles(&self.world, self.spinning_cube),
+			get_world_triangles(&self.world, self.car_entity),
+		];
+		obstacles.extend(self.props.iter().map(|&prop| get_world_triangles(&self.world, prop)));
+		self.navmesh = Scene::build_navmesh(&self.world, self.static_stuff.iter().chain(self.roads.iter()), &obstacles);
+		self.nav_debug_lines = Scene::create_nav_debug_lines(rs, &self.navmesh);
+	}
+
+	/// Reloads meshes, materials and scene settings changed on disk. Objects not affected by the changes keep their
+	/// runtime state. Returns true if anything was reloaded.
+	pub fn hot_reload(&mut self, rs: &RenderState, mp: &MainPass, random: &RandomService, console: &mut Console)
+		-> bool
+	{
+		let changed = self.assets.watcher.poll();
+		if changed.is_empty()
+		{
+			return false;
+		}
+		// Replaced objects might still be in use by the GPU
+		unsafe {
+			rs.device.device_wait_idle().unwrap();
+		}
+
+		let mut navmesh_dirty = false;
+		let mut models_dirty = false;
+		for path in changed
+		{
+			println!("Reloading {}", path);
+			if path == SCENE_SETTINGS_FILE
+			{
+				navmesh_dirty |= self.reload_settings(rs, mp, random, console);
+				continue;
+			}
+			if self.timelines.reload(&path)
+			{
+				continue;
+			}
+			// Static models are both a mesh and materials, and can be a prop mesh too
+			models_dirty |= self.assets.reload_models(rs, mp, &path);
+			// glTF models can be both a material and a mesh
+			if let Some(material) = self.assets.materials.get(&path)
+			{
+				if let Err(e) = material.reload(rs)
+				{
+					warning!("Could not reload material: {}", e);
+				}
+			}
+			if let Some(old_mesh) = self.assets.meshes.get(&path).cloned()
+			{
+				match Mesh::load(rs, &path, self.assets.vertex_format)
+				{
+					Ok(mesh) =>
+					{
+						for &prop in &self.props
+						{
+							match self.world.renders.get_mut(prop)
+							{
+								Some(render) if Rc::ptr_eq(&render.mesh, &old_mesh) => render.mesh = mesh.clone(),
+								_ => (),
+							}
+						}
+						self.assets.meshes.insert(path, mesh);
+						navmesh_dirty = true;
+					}
+					Err(e) => warning!("Could not reload mesh: {}", e),
+				}
+			}
+		}
+
+		if models_dirty
+		{
+			let settings = self.settings.static_models.clone();
+			navmesh_dirty |= self.recreate_static_stuff(rs, mp, &settings);
+		}
+		if navmesh_dirty
+		{
+			self.rebuild_static_geometry(rs);
+		}
+		return true;
+	}
+
+	/// Replaces the static objects with ones created from the settings. Returns true if they were replaced, the current
+	/// ones are kept when the models can't be loaded.
+	fn recreate_static_stuff(&mut self, rs: &RenderState, mp: &MainPass, settings: &[StaticModelSettings]) -> bool
 	{
-		for obj in &self.static_stuff
+		match Scene::create_static_stuff(rs, mp, &mut self.assets, &mut self.world, settings)
 		{
-			let model_matrix = obj.generate_transformation_matrix();
-			obj.draw(device, cmd_buf, pipeline_layout, &model_matrix, view_matrix, projection_matrix);
+			Ok(static_stuff) =>
+			{
+				for entity in std::mem::replace(&mut self.static_stuff, static_stuff)
+				{
+					self.world.despawn(entity);
+				}
+				return true;
+			}
+			Err(e) =>
+			{
+				warning!("Could not load static models: {}, keeping the current ones.", e);
+				return false;
+			}
 		}
-		let mut model_matrix = self.spinning_cube.generate_transformation_matrix();
-		self.spinning_cube.draw(device, cmd_buf, pipeline_layout, &model_matrix, view_matrix, projection_matrix);
+	}
+
+	/// Rebuilds the navmesh, the static collision and the static batches after static objects changed.
+	fn rebuild_static_geometry(&mut self, rs: &RenderState)
+	{
+		self.rebuild_navmesh(rs);
+		self.static_collision = Scene::cook_static_collision(&self.world);
+		self.static_batches = Scene::create_static_batches(rs, &self.world, self.assets.vertex_format);
+	}
 
-		model_matrix = self.car.borrow().generate_transformation_matrix();
-		self.car.borrow().draw(device, cmd_buf, pipeline_layout, &model_matrix, view_matrix, projection_matrix);
+	/// Replaces the scene settings with those of a built-in gallery scene, or the scene file for "default". Without a
+	/// name, lists the gallery scenes.
+	pub fn load_gallery_scene(
+		&mut self, rs: &RenderState, mp: &MainPass, random: &RandomService, console: &mut Console, name: Option<&str>,
+	)
+	{
+		let (settings, console_lines) = match name
+		{
+			None =>
+			{
+				let marker = |name: &str| {
+					if name == self.scene_name
+					{
+						"*"
+					}
+					else
+					{
+						" "
+					}
+				};
+				println!("{} default: The scene file, {}", marker("default"), SCENE_SETTINGS_FILE);
+				for scene in get_gallery_scenes()
+				{
+					println!("{} {}: {}", marker(scene.name), scene.name, scene.description);
+				}
+				return;
+			}
+			Some("default") => match SceneSettings::read(SCENE_SETTINGS_FILE)
+			{
+				Ok(settings) => (settings, Vec::new()),
+				Err(e) =>
+				{
+					warning!("{}, keeping the current scene.", e);
+					return;
+				}
+			},
+			Some(name) => match find_gallery_scene(name)
+			{
+				Some(scene) => match serde_json::from_value(scene.settings)
+				{
+					Ok(settings) => (settings, scene.console_lines),
+					Err(e) =>
+					{
+						warning!("Invalid gallery scene {}: {}", name, e);
+						return;
+					}
+				},
+				None =>
+				{
+					println!("Unknown gallery scene {}, see gallery for the list", name);
+					return;
+				}
+			},
+		};
+
+		// Replaced objects might still be in use by the GPU
+		unsafe {
+			rs.device.device_wait_idle().unwrap();
+		}
+		if self.apply_settings(rs, mp, random, console, settings)
+		{
+			self.rebuild_static_geometry(rs);
+		}
+		self.scene_name = name.unwrap().to_string();
+		for line in console_lines
+		{
+			if let Some(command) = console.execute(line)
+			{
+				self.execute_command(&command);
+			}
+		}
+	}
+
+	/// Releases meshes and materials that are no longer used, see AssetCache.
+	pub fn collect_garbage(&mut self, rs: &RenderState, console: &Console)
+	{
+		let timeout = Duration::from_secs_f32(console.get_float("asset_gc_timeout").max(0.0));
+		let budget = Duration::from_secs_f32(console.get_float("asset_gc_budget").max(0.0) / 1_000.0);
+		self.assets.collect_garbage(rs, timeout, budget);
+	}
+
+	/// Applies the parts of the scene file that changed. Returns true if the navmesh needs to be rebuilt.
+	fn reload_settings(
+		&mut self, rs: &RenderState, mp: &MainPass, random: &RandomService, console: &mut Console,
+	) -> bool
+	{
+		match SceneSettings::read(SCENE_SETTINGS_FILE)
+		{
+			Ok(settings) =>
+			{
+				self.scene_name = "default".to_string();
+				return self.apply_settings(rs, mp, random, console, settings);
+			}
+			Err(e) =>
+			{
+				warning!("{}, keeping the current scene.", e);
+				return false;
+			}
+		}
+	}
+
+	/// Applies the parts of the scene settings that changed. Returns true if the navmesh needs to be rebuilt.
+	fn apply_settings(
+		&mut self, rs: &RenderState, mp: &MainPass, random: &RandomService, console: &mut Console,
+		settings: SceneSettings,
+	) -> bool
+	{
+		let mut navmesh_dirty = false;
+		if settings_differ(&settings.weather, &self.settings.weather)
+		{
+			self.weather.apply_settings(&settings.weather, console);
+		}
+		if settings_differ(&settings.agents, &self.settings.agents)
+		{
+			self.agents = Scene::create_agents(&settings.agents, random, &self.agent_mesh, &self.agent_material);
+		}
+		// Racers refer to the agents, so the race restarts with them
+		if settings_differ(&settings.race, &self.settings.race) ||
+			settings_differ(&settings.agents, &self.settings.agents)
+		{
+			self.race.apply_settings(&settings.race);
+			if !settings.race.checkpoints.is_empty()
+			{
+				self.race.start(&self.agents);
+			}
+		}
+		if settings_differ(&settings.static_models, &self.settings.static_models)
+		{
+			navmesh_dirty |= self.recreate_static_stuff(rs, mp, &settings.static_models);
+		}
+		if settings_differ(&settings.props, &self.settings.props)
+		{
+			match Scene::create_props(rs, mp, &mut self.assets, &mut self.world, &settings.props)
+			{
+				Ok(props) =>
+				{
+					self.prop_instances = Scene::group_prop_instances(&self.world, &props, &settings.props);
+					for entity in std::mem::replace(&mut self.props, props)
+					{
+						self.world.despawn(entity);
+					}
+					navmesh_dirty = true;
+				}
+				Err(e) => warning!("Could not create props: {}, keeping the current ones.", e),
+			}
+		}
+		if settings_differ(&settings.roads, &self.settings.roads)
+		{
+			match Scene::create_roads(rs, mp, &mut self.assets, &mut self.world, &settings.roads)
+			{
+				Ok(roads) =>
+				{
+					for entity in std::mem::replace(&mut self.roads, roads)
+					{
+						self.world.despawn(entity);
+					}
+					navmesh_dirty = true;
+				}
+				Err(e) => warning!("Could not create roads: {}, keeping the current ones.", e),
+			}
+		}
+		if settings_differ(&settings.constrained_objects, &self.settings.constrained_objects)
+		{
+			match Scene::create_constrained_objects(
+				rs,
+				mp,
+				&mut self.assets,
+				&mut self.world,
+				&settings.constrained_objects,
+			)
+			{
+				Ok(objects) =>
+				{
+					for object in std::mem::replace(&mut self.constrained_objects, objects)
+					{
+						self.world.despawn(object.entity);
+					}
+				}
+				Err(e) => warning!("Could not create constrained objects: {}, keeping the current ones.", e),
+			}
+		}
+		if settings_differ(&settings.morph_objects, &self.settings.morph_objects)
+		{
+			match Scene::create_morph_objects(rs, mp, &mut self.assets, &mut self.world, &settings.morph_objects)
+			{
+				Ok(objects) =>
+				{
+					for entity in std::mem::replace(&mut self.morph_objects, objects)
+					{
+						self.world.despawn(entity);
+					}
+				}
+				Err(e) => warning!("Could not create morph objects: {}, keeping the current ones.", e),
+			}
+		}
+		self.settings = settings;
+		return navmesh_dirty;
+	}
+
+	pub fn get_view_matrix(&mut self) -> Matrix4<f32>
+	{
+		let camera = self.camera.borrow();
+		let view_matrix = match &self.camera_transition
+		{
+			Some(transition) => transition.generate_view_matrix(&camera),
+			None => camera.generate_view_matrix(),
+		};
+		return self.camera_effects.apply(view_matrix);
+	}
+
+	pub fn get_camera_position(&self) -> Point3<f32>
+	{
+		return self.camera.borrow().get_position();
+	}
+
+	/// Returns how many entities there are, how many of them are drawn and static, and how many agents, for the debug
+	/// HUD.
+	pub fn get_entity_counts(&self) -> Vec<(&'static str, usize)>
+	{
+		return vec![
+			("Entities", self.world.names.iter().count()),
+			("Drawn", self.world.renders.iter().count()),
+			("Static", self.world.statics.iter().count()),
+			("Agents", self.agents.len()),
+		];
+	}
+
+	/// Places the camera at the position, looking at the target, as for a scripted camera path. Use with the free
+	/// camera mode, or the camera mode moves it again.
+	pub fn place_camera(&mut self, position: Point3<f32>, target: Point3<f32>)
+	{
+		let mut camera = self.camera.borrow_mut();
+		camera.set_position(position);
+		camera.look_at(target);
+	}
+
+	/// Returns a view matrix looking straight down on the camera, with the camera's forward direction pointing up.
+	pub fn get_top_down_view_matrix(&self) -> Matrix4<f32>
+	{
+		let camera = self.camera.borrow();
+		let position = camera.get_position();
+		let front = camera.get_front_vector();
+		let mut up = Vector3::new(front.x, 0.0, front.z);
+		if up.magnitude2() < 1e-6
+		{
+			up = -Vector3::unit_z();
+		}
+		let eye = position + Vector3::unit_y() * TOP_DOWN_VIEW_HEIGHT;
+		return Matrix4::look_to_rh(eye, -Vector3::unit_y(), up.normalize());
+	}
+
+	/// Returns the change to the vertical field of view, caused by camera effects.
+	/// Returns the distance from the camera to the center of the object selected in the material editor, along the
+	/// view direction.
+	fn get_selected_depth(&self) -> Option<f32>
+	{
+		let object_bounds = self.get_object_bounds();
+		let selected = self.material_editor.borrow().get_selected_object(object_bounds.len())?;
+		let camera = self.camera.borrow();
+		return Some((object_bounds[selected].get_center() - camera.get_position()).dot(camera.get_front_vector()));
+	}
+
+	pub fn get_depth_of_field(&self) -> &DepthOfField
+	{
+		return &self.depth_of_field;
+	}
+
+	pub fn get_post_effects(&self) -> &PostEffects
+	{
+		return &self.post_effects;
+	}
+
+	/// Returns the normalized direction towards the sun.
+	pub fn get_sun_direction(&self) -> Vector3<f32>
+	{
+		return self.time_of_day.get_sun_direction();
+	}
+
+	/// Returns the position of the sun in texture coordinates, and a fade from 1 on screen to 0 as it leaves the
+	/// screen. The fade is 0 when the sun is behind the camera or below the horizon.
+	fn get_sun_on_screen(&self, view_projection: &Matrix4<f32>) -> (Vector2<f32>, f32)
+	{
+		let sun_direction = self.time_of_day.get_sun_direction();
+		let sun = view_projection * sun_direction.extend(0.0);
+		if sun.w <= 0.0 || sun_direction.y <= 0.0
+		{
+			return (Vector2::new(0.5, 0.5), 0.0);
+		}
+		let ndc = sun.truncate().truncate() / sun.w;
+		let outside = (ndc.x.abs().max(ndc.y.abs()) - 1.0).max(0.0);
+		return (ndc * 0.5 + Vector2::new(0.5, 0.5), (1.0 - outside).max(0.0));
+	}
+
+	/// Returns the position of the sun in texture coordinates, and the intensity and decay of the sun shafts.
+	///
+	/// The intensity fades out as the sun leaves the screen, and is 0 when the sun is behind the camera or below the
+	/// horizon.
+	pub fn get_sun_shafts(&self, view_projection: &Matrix4<f32>) -> (Vector2<f32>, f32, f32)
+	{
+		let (sun_position, fade) = self.get_sun_on_screen(view_projection);
+		return (sun_position, self.sun_shafts_intensity * fade, self.sun_shafts_decay);
+	}
+
+	/// Returns the intensity of the lens flare, fading out like the sun shafts. Occlusion of the sun is left to the
+	/// final pass.
+	pub fn get_lens_flare_intensity(&self, view_projection: &Matrix4<f32>) -> f32
+	{
+		return self.lens_flare_intensity * self.get_sun_on_screen(view_projection).1;
+	}
+
+	pub fn get_lens_flare_elements(&self) -> Vec<FlareElement>
+	{
+		return self
+			.settings
+			.lens_flare
+			.iter()
+			.map(|element| FlareElement {
+				position: element.position,
+				size: element.size.max(1e-3),
+				color: element.color,
+				ring: element.ring,
+			})
+			.collect();
+	}
+
+	pub fn get_fov_offset(&self) -> Deg<f32>
+	{
+		return self.camera_effects.get_fov_offset();
+	}
+
+	/// Returns the world bounds of each object, in the same order as the objects in the material editor.
+	fn get_object_bounds(&self) -> Vec<BoundingBox>
+	{
+		let mut bounds = Vec::new();
+		self.visit_objects(|mesh, model_matrix| bounds.push(mesh.get_bounds(model_matrix)));
+		return bounds;
+	}
+
+	/// Returns the static objects, props and roads, in that order.
+	fn get_static_objects(&self) -> impl Iterator<Item = (&TransformComponent, &RenderComponent)>
+	{
+		return self
+			.static_stuff
+			.iter()
+			.chain(self.props.iter())
+			.chain(self.roads.iter())
+			.filter_map(move |&entity| self.world.get_drawable(entity));
+	}
+
+	/// Calls visit with the mesh and model matrix of each object, in the order of get_object_bounds().
+	fn visit_objects(&self, mut visit: impl FnMut(&Mesh, &Matrix4<f32>))
+	{
+		for (transform, render) in self.get_static_objects()
+		{
+			visit(render.get_mesh(), &transform.generate_transformation_matrix());
+		}
+		for agent in &self.agents
+		{
+			visit(agent.get_mesh(), &agent.generate_transformation_matrix());
+		}
+		for (_, transform, render) in self.world.get_drawables(false)
+		{
+			visit(render.get_mesh(), &transform.generate_transformation_matrix());
+		}
+	}
+
+	/// Returns true if the position, in UI coordinates, is over a UI panel rather than the scene.
+	pub fn is_over_ui(&self, ui_position: (f32, f32)) -> bool
+	{
+		return self.material_editor.borrow().is_over_panel(ui_position) || self.replay.is_over_panel(ui_position);
+	}
+
+	/// Returns the world space ray through a position in normalized device coordinates, as origin and normalized
+	/// direction.
+	fn get_pick_ray(ndc: Option<(f32, f32)>, view_projection: &Matrix4<f32>) -> Option<(Point3<f32>, Vector3<f32>)>
+	{
+		let (ndc, inverse) = match (ndc, view_projection.invert())
+		{
+			(Some(ndc), Some(inverse)) => (ndc, inverse),
+			_ => return None,
+		};
+		let near = inverse * Vector4::new(ndc.0, ndc.1, 0.0, 1.0);
+		let far = inverse * Vector4::new(ndc.0, ndc.1, 1.0, 1.0);
+		let origin = Point3::from_homogeneous(near);
+		return Some((origin, (Point3::from_homogeneous(far) - origin).normalize()));
+	}
+
+	/// Paints where the ray first hits an object, if the texture painter is active, see TexturePainter.
+	fn paint(&self, origin: Point3<f32>, direction: Vector3<f32>, stroke_start: bool)
+	{
+		let mut closest: Option<(usize, f32, [f32; 2])> = None;
+		let mut idx = 0;
+		self.visit_objects(|mesh, model_matrix| {
+			if let Some((distance, uv)) = mesh.intersect_ray(model_matrix, origin, direction)
+			{
+				if closest.map_or(true, |(_, closest_distance, _)| distance < closest_distance)
+				{
+					closest = Some((idx, distance, uv));
+				}
+			}
+			idx += 1;
+		});
+		if let Some((idx, _, uv)) = closest
+		{
+			self.texture_painter.borrow_mut().paint(idx, uv, stroke_start);
+		}
+	}
+
+	/// Handles a mouse click at the given position in UI coordinates, and in normalized device coordinates if it is
+	/// on the rendered image. Clicks on the material editor and replay panels go to them, otherwise the clicked object
+	/// is painted on if the texture painter is active, or selected in the editor.
+	pub fn click(&mut self, ui_position: (f32, f32), ndc: Option<(f32, f32)>, view_projection: &Matrix4<f32>)
+	{
+		if self.material_editor.borrow_mut().click(ui_position) ||
+			self.replay.click(
+				&mut self.car.borrow_mut(),
+				self.world.transforms.get_mut(self.car_entity).unwrap(),
+				ui_position,
+			)
+		{
+			return;
+		}
+		let (origin, direction) = match Scene::get_pick_ray(ndc, view_projection)
+		{
+			Some(ray) => ray,
+			None => return,
+		};
+		if self.texture_painter.borrow().is_active()
+		{
+			self.paint(origin, direction, true);
+			return;
+		}
+
+		let mut closest: Option<(usize, f32)> = None;
+		for (idx, bounds) in self.get_object_bounds().iter().enumerate()
+		{
+			if let Some(distance) = bounds.intersect_ray(origin, direction)
+			{
+				if closest.map_or(true, |(_, closest_distance)| distance < closest_distance)
+				{
+					closest = Some((idx, distance));
+				}
+			}
+		}
+		if let Some((idx, _)) = closest
+		{
+			self.material_editor.borrow_mut().select_object(idx);
+		}
+	}
+
+	/// Moves the camera to frame the object selected in the material editor, or the whole scene if the editor is
+	/// hidden, when requested by the player. The ground is left out of the scene, since it is far too large to frame.
+	pub fn frame_selection(&mut self, vertical_fov: Rad<f32>, aspect_ratio: f32)
+	{
+		if !self.framing_input.borrow_mut().take_request()
+		{
+			return;
+		}
+
+		let object_bounds = self.get_object_bounds();
+		let selected = self.material_editor.borrow().get_selected_object(object_bounds.len());
+		let bounds = match selected
+		{
+			Some(idx) => Some(object_bounds[idx]),
+			None =>
+			{
+				object_bounds[self.static_stuff.len()..].iter().fold(None, |scene_bounds, bounds| match scene_bounds
+				{
+					Some(scene_bounds) => Some(bounds.union(&scene_bounds)),
+					None => Some(*bounds),
+				})
+			}
+		};
+		if let Some(bounds) = bounds
+		{
+			self.camera.borrow_mut().frame(&bounds, vertical_fov, aspect_ratio);
+			self.last_camera_position = self.camera.borrow().get_position();
+		}
+	}
+
+	/// Handles scene commands from the console, returns false if the command is unknown.
+	pub fn execute_command(&mut self, command: &[String]) -> bool
+	{
+		let args: Option<Vec<f32>> = command[1..].iter().map(|arg| arg.parse().ok()).collect();
+		let car_transform = self.world.transforms.get_mut(self.car_entity).unwrap();
+		match (command[0].as_str(), args.as_ref().map(|args| args.as_slice()))
+		{
+			("cam_shake", Some(&[trauma])) => self.camera_effects.add_trauma(trauma),
+			("cam_impulse", Some(&[x, y, z])) => self.camera_effects.add_impulse(Vector3::new(x, y, z)),
+			("cam_fov_kick", Some(&[angle])) => self.camera_effects.kick_fov(Deg(angle)),
+			("cam_shake", _) => println!("Usage: cam_shake <trauma>"),
+			("cam_impulse", _) => println!("Usage: cam_impulse <x> <y> <z>"),
+			("cam_fov_kick", _) => println!("Usage: cam_fov_kick <degrees>"),
+			("cam_mode", _) => match command.get(1).and_then(|name| CameraMode::from_name(name))
+			{
+				Some(mode) => self.set_camera_mode(mode),
+				None => println!("Usage: cam_mode <free|follow|orbit|hood>"),
+			},
+			("replay_record", Some(&[])) => self.replay.record(),
+			("replay_stop", Some(&[])) => self.replay.stop(),
+			("replay_play", Some(&[])) => self.replay.play(&mut self.car.borrow_mut(), car_transform),
+			("replay_pause", Some(&[])) => self.replay.toggle_pause(&mut self.car.borrow_mut(), car_transform),
+			("replay_speed", Some(&[speed])) => self.replay.set_speed(speed),
+			("replay_step", Some(&[ticks])) =>
+			{
+				self.replay.step(&mut self.car.borrow_mut(), car_transform, ticks as i32)
+			}
+			("replay_seek", Some(&[seconds])) =>
+			{
+				self.replay.seek_time(&mut self.car.borrow_mut(), car_transform, seconds)
+			}
+			("replay_speed", _) => println!("Usage: replay_speed <ticks per tick>"),
+			("replay_step", _) => println!("Usage: replay_step <ticks>"),
+			("replay_seek", _) => println!("Usage: replay_seek <seconds>"),
+			("asset_manifest", Some(&[])) => self.write_asset_manifest(),
+			("race_start", Some(&[])) => self.race.start(&self.agents),
+			("race_stop", Some(&[])) => self.race.stop(),
+			("race_standings", Some(&[])) => self.race.print_standings(),
+			("timelines", Some(&[])) => self.timelines.print(),
+			("timeline_play", _) => match command.get(1)
+			{
+				Some(name) if !self.timelines.play(name) => println!("Unknown timeline {}, see timelines", name),
+				Some(_) => (),
+				None => println!("Usage: timeline_play <name>"),
+			},
+			("timeline_stop", _) => match command.get(1)
+			{
+				Some(name) if !self.timelines.stop(name) => println!("Timeline {} is not playing", name),
+				Some(_) => (),
+				None => println!("Usage: timeline_stop <name>"),
+			},
+			("light_point", Some(&[x, y, z, radius, r, g, b])) =>
+			{
+				let id = self.add_light(Light::point(Point3::new(x, y, z), radius, [r, g, b]));
+				println!("Added light {}", id.0);
+			}
+			("light_directional", Some(&[x, y, z, r, g, b])) =>
+			{
+				let id = self.add_light(Light::directional(Vector3::new(x, y, z), [r, g, b]));
+				println!("Added light {}", id.0);
+			}
+			("light_remove", Some(&[id])) if !self.remove_light(LightId(id as u32)) =>
+			{
+				println!("No added light {}, see lights", id)
+			}
+			("light_remove", Some(&[_])) => (),
+			("lights", Some(&[])) => self.print_lights(),
+			("undo", Some(&[])) => self.edit_history.borrow_mut().undo(),
+			("redo", Some(&[])) => self.edit_history.borrow_mut().redo(),
+			("paint_brush", Some(&[radius, r, g, b, opacity])) =>
+			{
+				self.texture_painter.borrow_mut().set_brush(radius, [r, g, b], opacity)
+			}
+			("paint_brush", _) => println!("Usage: paint_brush <radius in texels> <r> <g> <b> <opacity>"),
+			("light_point", _) => println!("Usage: light_point <x> <y> <z> <radius> <r> <g> <b>"),
+			("light_directional", _) => println!("Usage: light_directional <x> <y> <z> <r> <g> <b>"),
+			("light_remove", _) => println!("Usage: light_remove <id>"),
+			("night_lights", _) => match command.get(1).and_then(|name| NightLightMode::from_name(name))
+			{
+				Some(mode) => self.time_of_day.set_night_light_mode(mode),
+				None => println!("Usage: night_lights <auto|on|off>"),
+			},
+			_ => return false,
+		}
+		return true;
+	}
+
+	/// Returns what the player is doing, for platform integrations.
+	#[cfg(feature = "presence")]
+	pub fn get_presence(&self) -> Presence
+	{
+		let standings = self.race.get_standings();
+		let player = standings.iter().position(|standing| standing.is_player);
+		let activity = match player
+		{
+			_ if self.replay.is_playing_back() => Activity::WatchingReplay,
+			Some(idx) if standings[idx].finish_time.is_some() => Activity::Finished {
+				place: idx + 1,
+				racers: standings.len(),
+			},
+			Some(idx) => Activity::Racing {
+				lap: standings[idx].lap,
+				place: idx + 1,
+				racers: standings.len(),
+			},
+			None if !self.in_vehicle => Activity::OnFoot,
+			None => Activity::Driving,
+		};
+		return Presence {
+			scene: self.scene_name.clone(),
+			activity: activity,
+		};
+	}
+
+	/// Returns how wet surfaces are, in the range [0, 1].
+	pub fn get_wetness(&self) -> f32
+	{
+		return self.weather.get_wetness();
+	}
+
+	/// Returns how many rain droplets should be on the screen, in the range [0, 1].
+	pub fn get_droplet_amount(&self) -> f32
+	{
+		return self.weather.get_droplet_amount();
+	}
+
+	pub fn update(&mut self, console: &mut Console, time: &mut Time)
+	{
+		let timestep = time.get_timestep(TimeDomain::Scaled);
+		// Camera movement and effects are not affected by slow motion
+		let unscaled_timestep = time.get_timestep(TimeDomain::Unscaled);
+
+		// Before the cvars, lights and materials they drive are used
+		let materials = &self.assets.materials;
+		self.timelines.update(timestep, console, |name| materials.get(&material_path(name)).cloned());
+
+		self.world.update_physics(timestep);
+		self.world.update_morphs(timestep);
+		self.replay.update(
+			&mut self.car.borrow_mut(),
+			self.world.transforms.get_mut(self.car_entity).unwrap(),
+			timestep,
+		);
+		self.update_rigid_bodies(timestep);
+		if self.interact_input.borrow_mut().take_request()
+		{
+			self.interact();
+		}
+		if self.in_vehicle
+		{
+			let seat = Car::get_attachment(self.get_car_transform(), "driver_seat").unwrap();
+			self.character.borrow_mut().set_position(seat);
+		}
+		else
+		{
+			let view_direction = self.camera.borrow().get_front_vector();
+			self.character.borrow_mut().update(view_direction, timestep);
+		}
+		if self.camera_toggle_input.borrow_mut().take_request()
+		{
+			let mode = if self.camera_mode == CameraMode::Free
+			{
+				CameraMode::Follow
+			}
+			else
+			{
+				CameraMode::Free
+			};
+			self.set_camera_mode(mode);
+		}
+		// The camera is free to look around during replays
+		let camera_mode = if self.replay.is_playing_back()
+		{
+			CameraMode::Free
+		}
+		else
+		{
+			self.camera_mode
+		};
+		match camera_mode
+		{
+			CameraMode::Free => (),
+			// On foot, the camera is carried by the character
+			CameraMode::Follow | CameraMode::Hood if !self.in_vehicle =>
+			{
+				let eyes = self.character.borrow().get_eye_position();
+				self.camera.borrow_mut().set_position(eyes);
+			}
+			CameraMode::Follow =>
+			{
+				self.update_follow_camera(console.get_float("cam_follow_distance").max(0.0), unscaled_timestep)
+			}
+			CameraMode::Hood =>
+			{
+				let car_transform = self.get_car_transform();
+				let mount = Car::get_attachment(car_transform, "hood_camera").unwrap();
+				let mut camera = self.camera.borrow_mut();
+				camera.set_position(mount);
+				camera.look_at(mount + car_transform.get_front_vector());
+			}
+			CameraMode::Orbit => self.update_orbit_camera(),
+		}
+		let transition_over =
+			self.camera_transition.as_mut().map_or(false, |transition| !transition.update(unscaled_timestep));
+		if transition_over
+		{
+			self.camera_transition = None;
+		}
+
+		let camera_position = self.camera.borrow().get_position();
+		let camera_velocity = (camera_position - self.last_camera_position) / unscaled_timestep;
+		self.last_camera_position = camera_position;
+		self.weather.update(console, camera_position, timestep);
+		self.time_of_day.update(console, timestep);
+
+		self.update_trails(console, timestep);
+
+		let car_position = self.get_car_transform().get_position();
+		self.race.update(console, car_position, &mut self.agents, timestep);
+		for agent in &mut self.agents
+		{
+			agent.update(&self.navmesh, timestep);
+		}
+		self.update_contacts(camera_velocity, time);
+		self.apply_constraints(timestep);
+		self.camera_effects.update(console, unscaled_timestep);
+		let autofocus_target = if self.depth_of_field.is_autofocus()
+		{
+			self.get_selected_depth()
+		}
+		else
+		{
+			None
+		};
+		self.depth_of_field.update(console, autofocus_target, unscaled_timestep);
+		self.post_effects.update(console);
+
+		self.nav_debug = console.get_bool("nav_debug");
+		self.static_batching = console.get_bool("static_batching");
+		self.frustum_culling = console.get_bool("frustum_culling");
+		self.agent_debug = console.get_bool("ai_debug");
+		self.debug_table = console.get_bool("debug_table");
+		self.world_labels = console.get_bool("world_labels");
+		self.sun_shafts_intensity = console.get_float("sun_shafts_intensity").max(0.0);
+		self.sun_shafts_decay = console.get_float("sun_shafts_decay").max(0.0).min(1.0);
+		self.lens_flare_intensity = console.get_float("lens_flare_intensity").max(0.0);
+	}
+
+	/// Gets the player out of the car by the driver's door if it is slow enough, or into it if the player is close
+	/// enough to the door.
+	fn interact(&mut self)
+	{
+		let car = self.car.borrow();
+		let door = Car::get_attachment(self.get_car_transform(), "driver_door").unwrap();
+		if self.in_vehicle
+		{
+			if car.get_velocity().magnitude() > EXIT_SPEED
+			{
+				println!("Slow down to get out of the car");
+				return;
+			}
+			self.character.borrow_mut().set_position(door);
+			self.vehicle_events.push(VehicleEvent::Exited);
+			self.timelines.trigger(TimelineTrigger::VehicleExited);
+		}
+		else
+		{
+			if (self.character.borrow().get_position() - door).magnitude() > ENTER_DISTANCE
+			{
+				println!("Walk up to the driver's door to get in");
+				return;
+			}
+			self.vehicle_events.push(VehicleEvent::Entered);
+			self.timelines.trigger(TimelineTrigger::VehicleEntered);
+		}
+		self.in_vehicle = !self.in_vehicle;
+		self.camera_boom.reset();
+	}
+
+	/// Returns the events of the player entering and exiting the car since the last call.
+	pub fn drain_vehicle_events(&mut self) -> Vec<VehicleEvent>
+	{
+		return std::mem::replace(&mut self.vehicle_events, Vec::new());
+	}
+
+	pub fn is_in_vehicle(&self) -> bool
+	{
+		return self.in_vehicle;
+	}
+
+	/// Sets the scene up for a new game from the menu, repairing the car and starting a race or stopping the current
+	/// one.
+	pub fn restart(&mut self, rs: &RenderState, damage_baker: &DamageBaker, race: bool)
+	{
+		self.repair_car(rs, damage_baker);
+		if race
+		{
+			self.race.start(&self.agents);
+		}
+		else
+		{
+			self.race.stop();
+		}
+	}
+
+	/// Returns the racers ordered by their place, empty without a race.
+	pub fn get_race_standings(&self) -> Vec<Standing>
+	{
+		return self.race.get_standings();
+	}
+
+	/// Returns true once the player has finished the current race.
+	pub fn is_race_finished(&self) -> bool
+	{
+		return self.race.get_standings().iter().any(|standing| standing.is_player && standing.finish_time.is_some());
+	}
+
+	/// Moves the constrained objects relative to their targets, after everything else has moved.
+	fn apply_constraints(&mut self, timestep: f32)
+	{
+		// Constrained objects are entities too, so the car's transform is copied
+		let car_transform = self.get_car_transform().clone();
+		let camera = self.camera.borrow();
+		for constrained in &self.constrained_objects
+		{
+			let transform = match self.world.transforms.get_mut(constrained.entity)
+			{
+				Some(transform) => transform,
+				None => continue,
+			};
+			for constraint in &constrained.constraints
+			{
+				match constrained.target
+				{
+					ConstraintTarget::Car => constraint.apply(transform, &car_transform, timestep),
+					ConstraintTarget::Camera => constraint.apply(transform, &*camera, timestep),
+				}
+			}
+		}
+	}
+
+	/// Extends the skidmarks while the car slides, and the light trails while it moves.
+	fn update_trails(&mut self, console: &Console, timestep: f32)
+	{
+		let car = self.car.borrow();
+		let car_transform = self.world.transforms.get(self.car_entity).unwrap();
+		let skidding = console.get_bool("skidmarks") && car.get_slip(car_transform) > SKID_SLIP;
+		for (trail, &contact) in self.skidmarks.iter_mut().zip(Car::get_rear_wheel_contacts(car_transform).iter())
+		{
+			trail.update(contact + Vector3::unit_y() * SKIDMARK_HEIGHT, Vector3::unit_y(), skidding, timestep);
+		}
+		// Standing upright, so they are seen from behind the car
+		let trailing = console.get_bool("light_trails") && car.get_velocity().magnitude() > LIGHT_TRAIL_SPEED;
+		for (trail, &light) in self.light_trails.iter_mut().zip(Car::get_tail_lights(car_transform).iter())
+		{
+			trail.update(light, car_transform.get_right_vector(), trailing, timestep);
+		}
+	}
+
+	/// Places the camera behind the car along its view direction, pulled in where the scene is in the way.
+	fn update_follow_camera(&mut self, distance: f32, timestep: f32)
+	{
+		let target = Car::get_attachment(self.get_car_transform(), "chase_camera").unwrap();
+		let direction = -self.camera.borrow().get_front_vector();
+		let obstruction = self.cast_sphere(target, direction, FOLLOW_CAMERA_RADIUS, Some(self.get_car_object_index()));
+		let length = self.camera_boom.update(distance, obstruction, timestep);
+		self.camera.borrow_mut().set_position(target + direction * length);
+	}
+
+	/// Places the camera around the orbit pivot, moving the pivot to the object selected in the material editor when
+	/// the selection changes.
+	fn update_orbit_camera(&mut self)
+	{
+		let object_bounds = self.get_object_bounds();
+		let selected = self.material_editor.borrow().get_selected_object(object_bounds.len());
+		if let Some(idx) = selected.filter(|&idx| Some(idx) != self.orbit_object)
+		{
+			self.orbit = Orbit::new(object_bounds[idx].get_center(), object_bounds[idx].get_radius() * ORBIT_FIT_SCALE);
+			self.orbit_object = Some(idx);
+		}
+		self.orbit.apply(&mut self.camera.borrow_mut());
+	}
+
+	/// Switches to the given camera mode, blending the view over. The free camera returns to where it was left.
+	fn set_camera_mode(&mut self, mode: CameraMode)
+	{
+		if mode == self.camera_mode
+		{
+			return;
+		}
+		self.camera_transition = Some(CameraTransition::new(&self.camera.borrow()));
+		if self.camera_mode == CameraMode::Free
+		{
+			self.free_camera = Some(self.camera.borrow().get_transform().clone());
+		}
+		match mode
+		{
+			CameraMode::Free =>
+			{
+				if let Some(transform) = &self.free_camera
+				{
+					*self.camera.borrow_mut().get_mutable_transform() = transform.clone();
+				}
+			}
+			CameraMode::Hood => (),
+			CameraMode::Follow => self.camera_boom.reset(),
+			CameraMode::Orbit =>
+			{
+				// Orbit what is in front of the camera, until an object is selected
+				let camera = self.camera.borrow();
+				let pivot = camera.get_position() + camera.get_front_vector() * ORBIT_START_DISTANCE;
+				self.orbit = Orbit::new(pivot, ORBIT_START_DISTANCE);
+				self.orbit_object = None;
+			}
+		}
+		self.camera_mode = mode;
+	}
+
+	/// Handles the mouse moving by the given delta in pixels while the cursor is not captured, with the left or middle
+	/// button held, at the given position in UI coordinates and normalized device coordinates as for click(). In orbit
+	/// mode, dragging over the scene rotates around the pivot or pans it.
+	pub fn drag(
+		&mut self, ui_position: (f32, f32), ndc: Option<(f32, f32)>, view_projection: &Matrix4<f32>, left: bool,
+		middle: bool, delta: (i32, i32),
+	)
+	{
+		// Dragging over objects paints a stroke, instead of orbiting
+		if left && self.texture_painter.borrow().is_active()
+		{
+			if let (false, Some((origin, direction))) =
+				(self.is_over_ui(ui_position), Scene::get_pick_ray(ndc, view_projection))
+			{
+				self.paint(origin, direction, false);
+			}
+			return;
+		}
+		if self.camera_mode != CameraMode::Orbit || self.is_over_ui(ui_position)
+		{
+			return;
+		}
+		if left
+		{
+			self.orbit.rotate(&mut self.camera.borrow_mut(), delta);
+		}
+		else if middle
+		{
+			self.orbit.pan(&self.camera.borrow(), delta);
+		}
+	}
+
+	/// Handles the mouse wheel, in steps away from the player. In orbit mode, this zooms in.
+	pub fn scroll(&mut self, steps: i32)
+	{
+		if self.camera_mode == CameraMode::Orbit
+		{
+			self.orbit.zoom(steps as f32);
+		}
+	}
+
+	/// Returns the distance along the ray to where a sphere moving along it first touches an object, optionally
+	/// ignoring one, see get_object_bounds(). Static objects are tested by their triangles, the others by their bounds.
+	fn cast_sphere(
+		&self, origin: Point3<f32>, direction: Vector3<f32>, radius: f32, ignored: Option<usize>,
+	) -> Option<f32>
+	{
+		let statics_end = self.static_stuff.len() + self.props.len() + self.roads.len();
+		return self
+			.get_object_bounds()
+			.iter()
+			.enumerate()
+			.filter(|(idx, _)| Some(*idx) != ignored && *idx >= statics_end)
+			.filter_map(|(_, bounds)| bounds.cast_sphere(origin, direction, radius))
+			.chain(self.static_collision.cast_sphere(origin, direction, radius))
+			.fold(None, |closest: Option<f32>, distance| {
+				Some(closest.map_or(distance, |closest| closest.min(distance)))
+			});
+	}
+
+	/// Returns the index of the car in get_object_bounds().
+	fn get_car_object_index(&self) -> usize
+	{
+		let drawable_index =
+			self.world.get_drawables(false).position(|(entity, _, _)| entity == self.car_entity).unwrap_or(0);
+		return self.static_stuff.len() + self.props.len() + self.roads.len() + self.agents.len() + drawable_index;
+	}
+
+	/// Returns the transform of the car entity, which is never despawned.
+	fn get_car_transform(&self) -> &TransformComponent
+	{
+		return self.world.transforms.get(self.car_entity).unwrap();
+	}
+
+	/// Applies gravity to the car, and resolves its collisions with the ground and the entities with colliders. Hitting
+	/// the entities plays impact sounds and scratches the car like hitting agents.
+	fn update_rigid_bodies(&mut self, timestep: f32)
+	{
+		let mut car = self.car.borrow_mut();
+		let mut bodies = vec![car.get_body(self.get_car_transform(), CAR_COLLIDER, CAR_MATERIAL)];
+		bodies.extend(self.world.get_rigid_bodies(ENTITY_BODIES));
+		for impact in self.rigid_bodies.step(&mut bodies, timestep)
+		{
+			if impact.ids.0 != CAR_COLLIDER
+			{
+				continue;
+			}
+			self.car_impacts.push((impact.position, impact.impulse));
+			if impact.impulse > SCRATCH_MIN_IMPULSE
+			{
+				self.car_scratches.push((impact.position, impact.normal, impact.impulse));
+			}
+		}
+		car.apply_body(self.world.transforms.get_mut(self.car_entity).unwrap(), &bodies[0]);
+	}
+
+	/// Finds contacts between the camera, the car and the agents. The camera bumps into agents, and the car plays
+	/// impact sounds when hitting them.
+	fn update_contacts(&mut self, camera_velocity: Vector3<f32>, time: &mut Time)
+	{
+		let mut colliders = Vec::with_capacity(self.agents.len() + 2);
+		colliders.push(Collider {
+			id: CAMERA_COLLIDER,
+			position: self.camera.borrow().get_position(),
+			velocity: camera_velocity,
+			radius: CAMERA_RADIUS,
+			hull: None,
+			mass: CAMERA_MASS,
+			material: BODY_MATERIAL,
+		});
+		let car = self.car.borrow();
+		let car_position = self.get_car_transform().get_position();
+		let car_hull = car.get_hull(self.get_car_transform());
+		colliders.push(Collider {
+			id: CAR_COLLIDER,
+			position: car_position,
+			velocity: car.get_velocity(),
+			radius: car_hull.as_ref().map_or(CAR_RADIUS, |hull| hull.get_radius(car_position)),
+			hull: car_hull,
+			mass: car.get_mass(),
+			material: CAR_MATERIAL,
+		});
+		for (idx, agent) in self.agents.iter().enumerate()
+		{
+			colliders.push(Collider {
+				id: AGENT_COLLIDERS + idx as u32,
+				position: agent.get_position(),
+				velocity: agent.get_velocity(),
+				radius: AGENT_RADIUS,
+				hull: None,
+				mass: AGENT_MASS,
+				material: BODY_MATERIAL,
+			});
+		}
+		self.contacts.step(&colliders);
+
+		for event in self.contacts.drain_events()
+		{
+			if event.phase != ContactPhase::Begin || event.ids.1 < AGENT_COLLIDERS
+			{
+				continue;
+			}
+			match event.ids.0
+			{
+				CAMERA_COLLIDER =>
+				{
+					self.camera_effects.add_impulse(-event.normal * AGENT_BUMP_IMPULSE);
+					self.camera_effects.add_trauma(AGENT_BUMP_TRAUMA);
+					if camera_velocity.magnitude() > BIG_CRASH_SPEED
+					{
+						time.slow_motion(BIG_CRASH_TIME_SCALE, BIG_CRASH_DURATION);
+						self.timelines.trigger(TimelineTrigger::BigCrash);
+					}
+					else
+					{
+						time.hit_stop(AGENT_BUMP_HIT_STOP);
+					}
+				}
+				CAR_COLLIDER =>
+				{
+					let impulse = event.normal_impulse + event.friction_impulse;
+					self.car_impacts.push((event.position, impulse));
+					if impulse > SCRATCH_MIN_IMPULSE
+					{
+						self.car_scratches.push((event.position, event.normal, impulse));
+					}
+				}
+				_ => (),
+			}
+		}
+	}
+
+	/// Bakes the scratches of the car collisions since the last call into its damage texture.
+	pub fn bake_damage(&mut self, rs: &RenderState, damage_baker: &DamageBaker)
+	{
+		if self.car_scratches.is_empty()
+		{
+			return;
+		}
+		let (car_transform, car_render) = self.world.get_drawable(self.car_entity).unwrap();
+		let world_to_mesh = car_transform.generate_transformation_matrix().invert().unwrap_or(Matrix4::identity());
+		let mut decals = Vec::with_capacity(self.car_scratches.len());
+		for (position, normal, impulse) in self.car_scratches.drain(..)
+		{
+			// The normal points away from the car, the scratch presses into it
+			let radius = SCRATCH_MIN_RADIUS +
+				(SCRATCH_MAX_RADIUS - SCRATCH_MIN_RADIUS) * (impulse / SCRATCH_FULL_IMPULSE).min(1.0);
+			decals.push(DamageDecal::new(
+				Point3::from_vec((world_to_mesh * position.to_vec().extend(1.0)).truncate()),
+				(world_to_mesh * (-normal).extend(0.0)).truncate(),
+				radius,
+				SCRATCH_COLOR,
+				self.scratch_count as f32,
+			));
+			self.scratch_count += 1;
+		}
+		if let Some(damage) = car_render.get_material().get_damage()
+		{
+			damage_baker.bake(rs, damage, car_render.get_mesh(), &decals, false);
+		}
+	}
+
+	/// Removes all scratches from the car.
+	pub fn repair_car(&mut self, rs: &RenderState, damage_baker: &DamageBaker)
+	{
+		self.car_scratches.clear();
+		let (_, car_render) = self.world.get_drawable(self.car_entity).unwrap();
+		if let Some(damage) = car_render.get_material().get_damage()
+		{
+			damage_baker.bake(rs, damage, car_render.get_mesh(), &[], true);
+		}
+	}
+
+	/// Moves the listener to the camera, and updates the sounds of the car.
+	pub fn update_audio(&mut self, audio: &mut AudioSystem)
+	{
+		let camera = self.camera.borrow();
+		audio.set_listener(camera.get_position(), camera.get_right_vector());
+
+		let car = self.car.borrow();
+		let car_transform = self.world.transforms.get(self.car_entity).unwrap();
+		let speed = car.get_velocity().magnitude();
+		self.vehicle_audio.update(
+			audio,
+			car_transform.get_position(),
+			car.get_rpm(car_transform),
+			speed,
+			car.get_slip(car_transform),
+		);
+		for (position, impulse) in self.car_impacts.drain(..)
+		{
+			self.vehicle_audio.play_impact(audio, position, impulse);
+		}
+	}
+
+	/// Applies material editor changes, and queues the editor panel on the overlay.
+	pub fn update_ui(&mut self, rs: &RenderState, time: &Time, overlay: &mut Overlay)
+	{
+		let mut objects = Vec::new();
+		let get_name = |entity: Entity| self.world.names.get(entity).cloned().unwrap_or_default();
+		for &entity in self.static_stuff.iter().chain(self.props.iter()).chain(self.roads.iter())
+		{
+			if let Some(render) = self.world.renders.get(entity)
+			{
+				objects.push((get_name(entity), render.material.clone()));
+			}
+		}
+		for (idx, agent) in self.agents.iter().enumerate()
+		{
+			objects.push((format!("agent {}", idx), agent.get_shared_material()));
+		}
+		for (entity, _, render) in self.world.get_drawables(false)
+		{
+			objects.push((get_name(entity), render.material.clone()));
+		}
+		let mut history = self.edit_history.borrow_mut();
+		let mut painter = self.texture_painter.borrow_mut();
+		let editing = self.material_editor.borrow().is_visible() || painter.is_active();
+		history.update(rs, &mut painter, &mut self.added_lights, editing);
+		self.material_editor.borrow_mut().update(rs, time, &objects, overlay, &mut history);
+		painter.update(rs, time, &objects, overlay, &mut history);
+		self.replay.draw(overlay);
+		self.race.draw(overlay);
+
+		if self.debug_table
+		{
+			let rows: Vec<Vec<f32>> = self
+				.agents
+				.iter()
+				.map(|agent| {
+					let position = agent.get_position();
+					let velocity = agent.get_velocity();
+					vec![position.x, position.y, position.z, velocity.x, velocity.z, velocity.magnitude()]
+				})
+				.collect();
+			overlay.draw_table(&["x", "y", "z", "vel x", "vel z", "speed"], &rows, (8.0, 40.0), DEBUG_TABLE_COLOR);
+		}
+
+		if self.world_labels
+		{
+			self.draw_world_labels(overlay);
+		}
+	}
+
+	/// Labels the agents with their name and distance from the camera, hidden behind the scene, and marks their
+	/// destinations with waypoint icons, visible through it.
+	fn draw_world_labels(&self, overlay: &mut Overlay)
+	{
+		let label_style = TextStyle {
+			outline_width: 1.5,
+			..Default::default()
+		};
+		let waypoint_style = TextStyle {
+			color: WAYPOINT_COLOR,
+			outline_width: 1.0,
+			..Default::default()
+		};
+		let camera_position = self.camera.borrow().get_position();
+		for (idx, agent) in self.agents.iter().enumerate()
+		{
+			let position = agent.get_position();
+			let anchor = position + Vector3::new(0.0, 0.5 * AGENT_HEIGHT + AGENT_LABEL_OFFSET, 0.0);
+			let text = format!("agent {}\n{:.0} m", idx, camera_position.distance(position));
+			overlay.draw_world_text(&text, anchor, WORLD_LABEL_SIZE, &label_style, true);
+			if let Some(destination) = agent.get_destination()
+			{
+				overlay.draw_world_icon(Icon::Triangle, destination, WAYPOINT_ICON_SIZE, &waypoint_style, false);
+			}
+		}
+	}
+
+	/// Finds a path along the ground, from below the camera to below the spinning cube.
+	fn find_debug_path(&self) -> Vec<ParticleVertex>
+	{
+		let camera_position = self.camera.borrow().get_position();
+		let cube_position = match self.world.transforms.get(self.spinning_cube)
+		{
+			Some(transform) => transform.get_position(),
+			None => return Vec::new(),
+		};
+		let start = Point3::new(camera_position.x, 0.0, camera_position.z);
+		let end = Point3::new(cube_position.x, 0.0, cube_position.z);
+
+		let mut vertices = Vec::new();
+		if let Some(path) = self.navmesh.find_path(start, end)
+		{
+			for segment in path.windows(2)
+			{
+				for point in segment
+				{
+					vertices.push(ParticleVertex {
+						pos: [point.x, point.y + 0.1, point.z],
+						color: NAV_PATH_COLOR,
+					});
+				}
+			}
+		}
+		return vertices;
+	}
+
+	/// Returns the point lights of the scene. Night-only lights are faded by the time of day, and left out while off.
+	/// Adds a light to the scene, lit along with those of the scene file until removed.
+	pub fn add_light(&mut self, light: Light) -> LightId
+	{
+		let id = LightId(self.next_light_id);
+		self.next_light_id += 1;
+		self.added_lights.push((id, light));
+		self.edit_history.borrow_mut().push(Edit::Light {
+			id: id,
+			light: None,
+		});
+		return id;
+	}
+
+	/// Removes a light added with add_light(). Returns false if there is no such light.
+	pub fn remove_light(&mut self, id: LightId) -> bool
+	{
+		let idx = match self.added_lights.iter().position(|(light_id, _)| *light_id == id)
+		{
+			Some(idx) => idx,
+			None => return false,
+		};
+		let (_, light) = self.added_lights.remove(idx);
+		self.edit_history.borrow_mut().push(Edit::Light {
+			id: id,
+			light: Some(light),
+		});
+		return true;
+	}
+
+	fn print_lights(&self)
+	{
+		println!("{} lights from the scene file", self.settings.lights.len() + self.settings.directional_lights.len());
+		for (id, light) in &self.added_lights
+		{
+			match light.kind
+			{
+				LightKind::Point {
+					position,
+					radius,
+					..
+				} => println!("{}: point light at {:?}, radius {}, color {:?}", id.0, position, radius, light.color),
+				LightKind::Directional {
+					direction,
+				} => println!("{}: directional light towards {:?}, color {:?}", id.0, direction, light.color),
+			}
+		}
+	}
+
+	/// Returns the lights of the scene file as lit now, followed by the added ones.
+	fn get_scene_lights(&self) -> Vec<Light>
+	{
+		let night_fade = self.time_of_day.get_night_light_fade();
+		let point_lights =
+			self.settings.lights.iter().enumerate().filter(|(_, light)| !light.night_only || night_fade > 0.0).map(
+				|(idx, light)| {
+					let fade = if light.night_only
+					{
+						night_fade
+					}
+					else
+					{
+						1.0
+					};
+					Light {
+						kind: LightKind::Point {
+							position: Point3::from(light.position),
+							radius: light.radius,
+							shadow_resolution: light.shadow_resolution,
+						},
+						color: self.timelines.get_light_color(idx, light.color),
+						intensity: light.intensity * fade,
+					}
+				},
+			);
+		let directional_lights = self.settings.directional_lights.iter().map(|light| Light {
+			intensity: light.intensity,
+			..Light::directional(Vector3::from(light.direction), light.color)
+		});
+		return point_lights
+			.chain(directional_lights)
+			.chain(self.added_lights.iter().map(|(_, light)| *light))
+			.collect();
+	}
+
+	/// Returns the point lights of the scene, see get_scene_lights().
+	pub fn get_lights(&self) -> Vec<PointLight>
+	{
+		return self.get_scene_lights().iter().filter_map(Light::to_point_light).collect();
+	}
+
+	/// Returns the directional lights of the scene, see get_scene_lights().
+	pub fn get_directional_lights(&self) -> Vec<DirectionalLight>
+	{
+		return self.get_scene_lights().iter().filter_map(Light::to_directional_light).collect();
+	}
+
+	/// Returns the reflection probes of the scene.
+	pub fn get_reflection_probes(&self) -> Vec<ReflectionProbe>
+	{
+		return self
+			.settings
+			.reflection_probes
+			.iter()
+			.map(|probe| ReflectionProbe {
+				position: Point3::from(probe.position),
+				extents: Vector3::from(probe.extents),
+				resolution: probe.resolution,
+			})
+			.collect();
+	}
+
+	/// Returns the irradiance grid of the scene, if it has one.
+	pub fn get_irradiance_volume(&self) -> Option<IrradianceVolume>
+	{
+		return self.settings.irradiance_grid.as_ref().map(|grid| IrradianceVolume {
+			position: Point3::from(grid.position),
+			extents: Vector3::from(grid.extents),
+			counts: grid.counts,
+		});
+	}
+
+	/// Draws the objects casting shadows into a shadow map face, see ShadowPass::render().
+	pub fn draw_shadow_casters(
+		&self, device: &Device, cmd_buf: vk::CommandBuffer, sp: &ShadowPass, view_projection_matrix: &Matrix4<f32>,
+		light_matrix: &Matrix4<f32>,
+	)
+	{
+		if self.static_batching
+		{
+			// The batches are merged in world space
+			for batch in &self.static_batches
+			{
+				batch.draw_shadow(device, cmd_buf, sp, &Matrix4::identity(), view_projection_matrix, light_matrix);
+			}
+		}
+		else
+		{
+			for (transform, render) in self.get_static_objects()
+			{
+				let model_matrix = transform.generate_transformation_matrix();
+				render.draw_shadow(device, cmd_buf, sp, &model_matrix, view_projection_matrix, light_matrix);
+			}
+		}
+		for (_, transform, render) in self.world.get_drawables(false)
+		{
+			let model_matrix = transform.generate_transformation_matrix();
+			render.draw_shadow(device, cmd_buf, sp, &model_matrix, view_projection_matrix, light_matrix);
+		}
+
+		for agent in &self.agents
+		{
+			let model_matrix = agent.generate_transformation_matrix();
+			agent.draw_shadow(device, cmd_buf, sp, &model_matrix, view_projection_matrix, light_matrix);
+		}
+	}
+
+	/// Draws the opaque surfaces of the scene to the depth prepass, see MainPass::finish_depth_prepass().
+	pub fn draw_depth(
+		&self, device: &Device, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>,
+	)
+	{
+		self.draw_meshes(device, cmd_buf, mp, view_matrix, projection_matrix);
+	}
+
+	/// Draws the meshes of the scene, but not the lines, trails and weather drawn on top.
+	fn draw_meshes(
+		&self, device: &Device, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>,
+	)
+	{
+		// Objects entirely outside the view are not drawn
+		let frustum = if self.frustum_culling
+		{
+			Some(Frustum::from_matrix(&(projection_matrix * view_matrix)))
+		}
+		else
+		{
+			None
+		};
+		let is_visible = |drawable: &dyn Drawable, model_matrix: &Matrix4<f32>| match &frustum
+		{
+			Some(frustum) => drawable.is_visible(model_matrix, frustum),
+			None => true,
+		};
+
+		if self.static_batching
+		{
+			// The batches are merged in world space
+			let model_matrix = Matrix4::identity();
+			for batch in &self.static_batches
+			{
+				if is_visible(batch, &model_matrix)
+				{
+					batch.draw(device, cmd_buf, mp, &model_matrix, view_matrix, projection_matrix);
+				}
+			}
+		}
+		else
+		{
+			for (transform, render) in
+				self.static_stuff.iter().chain(self.roads.iter()).filter_map(|&entity| self.world.get_drawable(entity))
+			{
+				let model_matrix = transform.generate_transformation_matrix();
+				if is_visible(render, &model_matrix)
+				{
+					render.draw(device, cmd_buf, mp, &model_matrix, view_matrix, projection_matrix);
+				}
+			}
+			//   Props are part of the static batches when enabled
+			let props: Vec<(&TransformComponent, &RenderComponent)> =
+				self.props.iter().filter_map(|&prop| self.world.get_drawable(prop)).collect();
+			for group in &self.prop_instances
+			{
+				let render = props[group[0]].1;
+				let model_matrices: Vec<Matrix4<f32>> = group
+					.iter()
+					.map(|&idx| props[idx].0.generate_transformation_matrix())
+					.filter(|model_matrix| is_visible(render, model_matrix))
+					.collect();
+				if model_matrices.is_empty()
+				{
+					continue;
+				}
+				render.draw_instances(device, cmd_buf, mp, &model_matrices, view_matrix, projection_matrix);
+			}
+		}
+		for (_, transform, render) in self.world.get_drawables(false)
+		{
+			let model_matrix = transform.generate_transformation_matrix();
+			if is_visible(render, &model_matrix)
+			{
+				render.draw(device, cmd_buf, mp, &model_matrix, view_matrix, projection_matrix);
+			}
+		}
+
+		for agent in &self.agents
+		{
+			let model_matrix = agent.generate_transformation_matrix();
+			if is_visible(agent, &model_matrix)
+			{
+				agent.draw(device, cmd_buf, mp, &model_matrix, view_matrix, projection_matrix);
+			}
+		}
+	}
+
+	pub fn draw(
+		&mut self, device: &Device, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>,
+	)
+	{
+		self.draw_meshes(device, cmd_buf, mp, view_matrix, projection_matrix);
+
+		// Blended, so draw last
+		if self.nav_debug
+		{
+			let path_vertices = self.find_debug_path();
+			self.nav_path_lines.set_lines(&path_vertices);
+			self.nav_debug_lines.draw(cmd_buf, mp, view_matrix, projection_matrix);
+			self.nav_path_lines.draw(cmd_buf, mp, view_matrix, projection_matrix);
+		}
+		if self.agent_debug
+		{
+			let mut agent_vertices = Vec::new();
+			for agent in &self.agents
+			{
+				agent.append_debug_lines(&mut agent_vertices);
+			}
+			self.agent_debug_lines.set_lines(&agent_vertices);
+			self.agent_debug_lines.draw(cmd_buf, mp, view_matrix, projection_matrix);
+		}
+		for trail in self.skidmarks.iter_mut().chain(self.light_trails.iter_mut())
+		{
+			trail.draw(cmd_buf, mp, view_matrix, projection_matrix);
+		}
+		self.weather.draw(cmd_buf, mp, view_matrix, projection_matrix);
 	}
 }
@@ -0,0 +1,286 @@
+use crate::core::{Action, ActionEvents, InputConsumer};
+use crate::renderer::{Overlay, OverlayImage, RenderState, TextStyle};
+use bit_vec::BitVec;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where screenshots are saved by default, and listed from by the gallery.
+pub const SCREENSHOT_DIR: &str = "screenshots";
+
+// Distance of the image from the edges of the UI, leaving room for the text above and below it
+const MARGIN: f32 = 16.0;
+const TEXT_SIZE: f32 = 14.0;
+const LINE_SPACING: f32 = 20.0;
+// Moved by each step of the slider, as a fraction of the width of the image
+const SLIDER_STEP: f32 = 0.02;
+const SLIDER_WIDTH: f32 = 2.0;
+const BACKGROUND_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 0.85];
+const SLIDER_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+
+/// Returns a new path in SCREENSHOT_DIR, named by the current time so the screenshots list in the order taken.
+pub fn new_screenshot_path() -> String
+{
+	let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis());
+	return format!("{}/screenshot_{}.png", SCREENSHOT_DIR, millis);
+}
+
+/// Lists the screenshots in SCREENSHOT_DIR, and compares two of them with a slider, for checking changes to the
+/// rendering by eye. Toggled with GALLERY_TOGGLE, F8 by default.
+///
+/// Screenshot A is shown left of the slider, and B right of it, both stretched over the size of A. Opening the
+/// gallery compares the last two screenshots taken. B steps through the list, and swapping A and B keeps one while
+/// stepping through the others.
+pub struct ScreenshotGallery
+{
+	visible: bool,
+	toggle_requested: bool,
+	// PNG files in SCREENSHOT_DIR, by name, listed when the gallery is opened
+	paths: Vec<PathBuf>,
+	// Indices into paths of screenshots A and B
+	a: usize,
+	b: usize,
+	// Requests since the last draw
+	b_steps: i32,
+	swap_requested: bool,
+	slider_steps: i32,
+	// Position of the slider, from the left edge of the image, in [0, 1]
+	slider: f32,
+	// The shown screenshots, None if they could not be loaded
+	images: Vec<(PathBuf, Option<OverlayImage>)>,
+	// A single white pixel, stretched for the background and the slider
+	blank: Option<OverlayImage>,
+}
+
+impl ScreenshotGallery
+{
+	pub fn new() -> ScreenshotGallery
+	{
+		ScreenshotGallery {
+			visible: false,
+			toggle_requested: false,
+			paths: Vec::new(),
+			a: 0,
+			b: 0,
+			b_steps: 0,
+			swap_requested: false,
+			slider_steps: 0,
+			slider: 0.5,
+			images: Vec::new(),
+			blank: None,
+		}
+	}
+
+	/// Returns true if the gallery is about to change.
+	pub fn is_animating(&self) -> bool
+	{
+		return self.toggle_requested || self.b_steps != 0 || self.swap_requested || self.slider_steps != 0;
+	}
+
+	fn list_screenshots() -> Vec<PathBuf>
+	{
+		let mut paths: Vec<PathBuf> = match fs::read_dir(SCREENSHOT_DIR)
+		{
+			Ok(entries) => entries
+				.filter_map(|entry| entry.ok())
+				.map(|entry| entry.path())
+				.filter(|path| path.extension().map_or(false, |extension| extension == "png"))
+				.collect(),
+			Err(_) => Vec::new(),
+		};
+		paths.sort();
+		return paths;
+	}
+
+	/// Keeps the images of screenshots A and B loaded, and releases the others.
+	fn load_images(&mut self, rs: &RenderState, overlay: &Overlay)
+	{
+		let wanted: Vec<PathBuf> = [self.a, self.b].iter().filter_map(|&idx| self.paths.get(idx).cloned()).collect();
+		for (path, image) in std::mem::take(&mut self.images)
+		{
+			if wanted.contains(&path)
+			{
+				self.images.push((path, image));
+			}
+			else if let Some(image) = image
+			{
+				rs.defer(image);
+			}
+		}
+		for path in wanted
+		{
+			if self.images.iter().any(|(loaded, _)| *loaded == path)
+			{
+				continue;
+			}
+			let image = match image::open(&path)
+			{
+				Ok(image) => Some(overlay.create_image(rs, &image.to_rgba8())),
+				Err(e) =>
+				{
+					warning!("Could not load screenshot {}: {}", path.display(), e);
+					None
+				}
+			};
+			self.images.push((path, image));
+		}
+	}
+
+	fn get_image(&self, idx: usize) -> Option<&OverlayImage>
+	{
+		let path = self.paths.get(idx)?;
+		return self.images.iter().find(|(loaded, _)| loaded == path).and_then(|(_, image)| image.as_ref());
+	}
+
+	fn get_name(&self, idx: usize) -> String
+	{
+		let name = self.paths.get(idx).and_then(|path| path.file_name());
+		return name.map_or(String::new(), |name| name.to_string_lossy().into_owned());
+	}
+
+	/// Queues the gallery on the overlay, over a UI of the given size, if shown. Loads the screenshots it shows.
+	pub fn draw(&mut self, rs: &RenderState, overlay: &mut Overlay, ui_size: (f32, f32))
+	{
+		if self.toggle_requested
+		{
+			self.toggle_requested = false;
+			self.visible = !self.visible;
+			if self.visible
+			{
+				self.paths = ScreenshotGallery::list_screenshots();
+				self.b = self.paths.len().saturating_sub(1);
+				self.a = self.b.saturating_sub(1);
+			}
+			else
+			{
+				self.paths.clear();
+			}
+		}
+		if !self.visible
+		{
+			// Releases the images once hidden
+			self.load_images(rs, overlay);
+			self.b_steps = 0;
+			self.swap_requested = false;
+			self.slider_steps = 0;
+			return;
+		}
+
+		if !self.paths.is_empty()
+		{
+			let count = self.paths.len() as i32;
+			self.b = ((self.b as i32 + self.b_steps) % count + count) as usize % self.paths.len();
+		}
+		if self.swap_requested
+		{
+			std::mem::swap(&mut self.a, &mut self.b);
+		}
+		self.slider = (self.slider + self.slider_steps as f32 * SLIDER_STEP).max(0.0).min(1.0);
+		self.b_steps = 0;
+		self.swap_requested = false;
+		self.slider_steps = 0;
+		self.load_images(rs, overlay);
+
+		if self.blank.is_none()
+		{
+			let pixel = image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]));
+			self.blank = Some(overlay.create_image(rs, &pixel));
+		}
+		let blank = self.blank.as_ref().unwrap();
+		let full = ([0.0, 0.0], [1.0, 1.0]);
+		overlay.draw_image(blank, full, (0.0, 0.0), ui_size, BACKGROUND_COLOR);
+		let style = TextStyle {
+			outline_width: 1.5,
+			..Default::default()
+		};
+		if self.paths.is_empty()
+		{
+			let text = format!("No screenshots in {}/, save them with capture_save", SCREENSHOT_DIR);
+			overlay.draw_text(&text, (MARGIN, MARGIN), TEXT_SIZE, &style);
+			return;
+		}
+
+		let title = format!(
+			"A: {} ({}/{})   B: {} ({}/{})",
+			self.get_name(self.a),
+			self.a + 1,
+			self.paths.len(),
+			self.get_name(self.b),
+			self.b + 1,
+			self.paths.len()
+		);
+		overlay.draw_text(&title, (MARGIN, MARGIN), TEXT_SIZE, &style);
+		let help = ", and . step through B, / swaps A and B, ; and ' move the slider";
+		overlay.draw_text(help, (MARGIN, ui_size.1 - MARGIN - TEXT_SIZE), TEXT_SIZE, &style);
+
+		// Fit A between the lines of text, keeping its aspect ratio
+		let area = (ui_size.0 - 2.0 * MARGIN, ui_size.1 - 2.0 * (MARGIN + LINE_SPACING));
+		let (width, height) = match self.get_image(self.a).or_else(|| self.get_image(self.b))
+		{
+			Some(image) => image.get_size(),
+			None => return,
+		};
+		let scale = (area.0 / width as f32).min(area.1 / height as f32);
+		let size = (width as f32 * scale, height as f32 * scale);
+		let position = (0.5 * (ui_size.0 - size.0), 0.5 * (ui_size.1 - size.1));
+		let split = size.0 * self.slider;
+		if let Some(image) = self.get_image(self.a)
+		{
+			overlay.draw_image(image, ([0.0, 0.0], [self.slider, 1.0]), position, (split, size.1), [1.0; 4]);
+		}
+		if let Some(image) = self.get_image(self.b)
+		{
+			let uv = ([self.slider, 0.0], [1.0, 1.0]);
+			overlay.draw_image(image, uv, (position.0 + split, position.1), (size.0 - split, size.1), [1.0; 4]);
+		}
+		let slider_position = (position.0 + split - 0.5 * SLIDER_WIDTH, position.1);
+		overlay.draw_image(blank, full, slider_position, (SLIDER_WIDTH, size.1), SLIDER_COLOR);
+	}
+}
+
+impl InputConsumer for ScreenshotGallery
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+		handled_actions.set(Action::GALLERY_TOGGLE as usize, true);
+		handled_actions.set(Action::GALLERY_PREV as usize, true);
+		handled_actions.set(Action::GALLERY_NEXT as usize, true);
+		handled_actions.set(Action::GALLERY_SWAP as usize, true);
+		handled_actions.set(Action::GALLERY_SLIDER_LEFT as usize, true);
+		handled_actions.set(Action::GALLERY_SLIDER_RIGHT as usize, true);
+		return handled_actions;
+	}
+
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		if actions.is_triggered(Action::GALLERY_TOGGLE)
+		{
+			self.toggle_requested = true;
+		}
+		if !self.visible
+		{
+			return;
+		}
+		if actions.is_triggered(Action::GALLERY_PREV)
+		{
+			self.b_steps -= 1;
+		}
+		if actions.is_triggered(Action::GALLERY_NEXT)
+		{
+			self.b_steps += 1;
+		}
+		if actions.is_triggered(Action::GALLERY_SWAP)
+		{
+			self.swap_requested = !self.swap_requested;
+		}
+		if actions.is_triggered(Action::GALLERY_SLIDER_LEFT)
+		{
+			self.slider_steps -= 1;
+		}
+		if actions.is_triggered(Action::GALLERY_SLIDER_RIGHT)
+		{
+			self.slider_steps += 1;
+		}
+	}
+}
@@ -0,0 +1,490 @@
+use crate::core::{write_atomic, Action, ActionEvents, InputConsumer, Material, Time, TimeDomain, Timer};
+use crate::game::{Edit, EditHistory};
+use crate::renderer::{Overlay, RenderState, TextStyle};
+use bit_vec::BitVec;
+use image::png::PngEncoder;
+use image::{ColorType, RgbaImage};
+use std::path::Path;
+use std::rc::{Rc, Weak};
+
+// Smaller textures, like the plain white one, are scaled up so there is something to paint into
+const MIN_CANVAS_SIZE: u32 = 256;
+const PAINTED_SUFFIX: &str = "_painted";
+const MIN_BRUSH_RADIUS: f32 = 1.0;
+const MAX_BRUSH_RADIUS: f32 = 256.0;
+const BRUSH_RESIZE_FACTOR: f32 = 1.25;
+// Dabs of a stroke further apart than this fraction of the texture are not joined, as they are likely across a seam
+const MAX_STROKE_GAP: f32 = 0.25;
+// How long save results are shown, in real time seconds
+const STATUS_DURATION: f32 = 3.0;
+
+// Below the material editor panel
+const PANEL_POSITION: (f32, f32) = (8.0, 340.0);
+const TEXT_SIZE: f32 = 14.0;
+const LINE_SPACING: f32 = 20.0;
+
+const TITLE_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+const ERROR_COLOR: [f32; 4] = [0.9, 0.2, 0.2, 1.0];
+
+/// What is painted, with sizes in texels of the painted texture.
+#[derive(Clone, Copy)]
+struct Brush
+{
+	radius: f32,
+	color: [f32; 3],
+	opacity: f32,
+}
+
+/// A point to paint at, on one of the objects passed to TexturePainter::update().
+struct Dab
+{
+	object: usize,
+	uv: [f32; 2],
+	// The first dab of a stroke, which is undone as a whole
+	stroke_start: bool,
+}
+
+/// The color texture of a material, as painted so far.
+struct Canvas
+{
+	// Held weakly, the canvas is dropped once the material is freed
+	material: Weak<Material>,
+	// The path of the texture the canvas was loaded from, which is replaced if the material switches textures
+	texture_path: String,
+	image: RgbaImage,
+}
+
+enum PaintCommand
+{
+	Toggle,
+	Save,
+	Resize(f32),
+}
+
+/// Debug tool for painting into the color textures of the scene's objects, to block out markings and the like
+/// without leaving the game.
+///
+/// F6 toggles painting. With the cursor released, clicking or dragging over an object paints into its texture at
+/// the texture coordinates under the cursor, [ and ] change the brush size and F7 saves the painted texture next to the
+/// original, with a _painted suffix, and points the material at it. The material itself is saved with the material
+/// editor. Strokes are undone with the EditHistory.
+///
+/// Textures are painted on the CPU and uploaded again once per frame while painting. Objects sharing a material share
+/// its texture, and meshes with overlapping texture coordinates are painted in several places at once.
+pub struct TexturePainter
+{
+	active: bool,
+	pending_commands: Vec<PaintCommand>,
+	dabs: Vec<Dab>,
+	brush: Brush,
+	canvases: Vec<Canvas>,
+	// The canvas painted last, and where, for joining the dabs of a stroke
+	last_dab: Option<(usize, [f32; 2])>,
+	// The canvas painted or undone last, which is the one saved
+	saved_canvas: Option<usize>,
+	status: Option<(Result<String, String>, Timer)>,
+}
+
+impl TexturePainter
+{
+	pub fn new() -> TexturePainter
+	{
+		TexturePainter {
+			active: false,
+			pending_commands: Vec::new(),
+			dabs: Vec::new(),
+			brush: Brush {
+				radius: 8.0,
+				color: [1.0, 1.0, 1.0],
+				opacity: 1.0,
+			},
+			canvases: Vec::new(),
+			last_dab: None,
+			saved_canvas: None,
+			status: None,
+		}
+	}
+
+	/// Returns true if clicks and drags over the scene paint, rather than select objects.
+	pub fn is_active(&self) -> bool
+	{
+		return self.active;
+	}
+
+	/// Sets the brush radius in texels, its color and its opacity.
+	pub fn set_brush(&mut self, radius: f32, color: [f32; 3], opacity: f32)
+	{
+		self.brush = Brush {
+			radius: radius.max(MIN_BRUSH_RADIUS).min(MAX_BRUSH_RADIUS),
+			color: [color[0].max(0.0).min(1.0), color[1].max(0.0).min(1.0), color[2].max(0.0).min(1.0)],
+			opacity: opacity.max(0.0).min(1.0),
+		};
+	}
+
+	/// Queues painting at the given texture coordinates of an object, by its index in the objects passed to update().
+	/// Starting a stroke lets it be undone separately from the previous one.
+	pub fn paint(&mut self, object: usize, uv: [f32; 2], stroke_start: bool)
+	{
+		if self.active
+		{
+			self.dabs.push(Dab {
+				object: object,
+				uv: uv,
+				stroke_start: stroke_start,
+			});
+		}
+	}
+
+	/// Applies the queued input and painting to the textures of the objects, and queues the paint panel on the
+	/// overlay. Objects are given by name and material, as for the material editor. Strokes are recorded in the
+	/// history.
+	pub fn update(
+		&mut self, rs: &RenderState, time: &Time, objects: &[(String, Rc<Material>)], overlay: &mut Overlay,
+		history: &mut EditHistory,
+	)
+	{
+		self.drop_freed_canvases();
+		let commands: Vec<PaintCommand> = self.pending_commands.drain(..).collect();
+		for command in commands
+		{
+			match command
+			{
+				PaintCommand::Toggle => self.active = !self.active,
+				// Ignore everything else while inactive
+				_ if !self.active => (),
+				PaintCommand::Save =>
+				{
+					let result = self.save(rs);
+					self.status = Some((result, Timer::new(time, STATUS_DURATION, TimeDomain::Unscaled)));
+				}
+				PaintCommand::Resize(factor) =>
+				{
+					let brush = self.brush;
+					self.set_brush(brush.radius * factor, brush.color, brush.opacity);
+				}
+			}
+		}
+
+		let mut painted: Vec<usize> = Vec::new();
+		for dab in std::mem::take(&mut self.dabs)
+		{
+			let material = match objects.get(dab.object)
+			{
+				Some((_, material)) => material,
+				None => continue,
+			};
+			let canvas = match self.get_canvas(material)
+			{
+				Ok(canvas) => canvas,
+				Err(e) =>
+				{
+					self.status = Some((Err(e), Timer::new(time, STATUS_DURATION, TimeDomain::Unscaled)));
+					continue;
+				}
+			};
+			if dab.stroke_start
+			{
+				let painted_canvas = &self.canvases[canvas];
+				history.push(Edit::Paint {
+					material: painted_canvas.material.clone(),
+					texture_path: painted_canvas.texture_path.clone(),
+					image: painted_canvas.image.clone(),
+				});
+				self.last_dab = None;
+			}
+			self.paint_stroke(canvas, dab.uv);
+			self.saved_canvas = Some(canvas);
+			if !painted.contains(&canvas)
+			{
+				painted.push(canvas);
+			}
+		}
+		for canvas in painted
+		{
+			if let Some(material) = self.canvases[canvas].material.upgrade()
+			{
+				material.set_texture_image(rs, &self.canvases[canvas].image);
+			}
+		}
+
+		if self.status.as_ref().map_or(false, |(_, timer)| timer.is_done(time))
+		{
+			self.status = None;
+		}
+		if self.active
+		{
+			self.draw(overlay);
+		}
+	}
+
+	/// Drops the canvases of materials that have been freed, along with the references to them.
+	fn drop_freed_canvases(&mut self)
+	{
+		if self.canvases.iter().all(|canvas| canvas.material.strong_count() > 0)
+		{
+			return;
+		}
+		let saved_material = self.saved_canvas.map(|canvas| self.canvases[canvas].material.clone());
+		self.canvases.retain(|canvas| canvas.material.strong_count() > 0);
+		self.saved_canvas = saved_material
+			.and_then(|material| self.canvases.iter().position(|canvas| canvas.material.ptr_eq(&material)));
+		self.last_dab = None;
+	}
+
+	/// Returns the canvas of the material, loading its texture the first time it is painted, or after the material
+	/// switched textures.
+	fn get_canvas(&mut self, material: &Rc<Material>) -> Result<usize, String>
+	{
+		let texture_path = material.get_desc().texture;
+		let existing = self.canvases.iter().position(|canvas| Weak::as_ptr(&canvas.material) == Rc::as_ptr(material));
+		if let Some(idx) = existing
+		{
+			if self.canvases[idx].texture_path == texture_path
+			{
+				return Ok(idx);
+			}
+		}
+
+		let mut image = image::open(&texture_path).map_err(|e| format!("{}: {}", texture_path, e))?.to_rgba8();
+		let (width, height) = image.dimensions();
+		if width < MIN_CANVAS_SIZE || height < MIN_CANVAS_SIZE
+		{
+			image = image::imageops::resize(
+				&image,
+				width.max(MIN_CANVAS_SIZE),
+				height.max(MIN_CANVAS_SIZE),
+				image::imageops::FilterType::Nearest,
+			);
+		}
+		let canvas = Canvas {
+			material: Rc::downgrade(material),
+			texture_path: texture_path,
+			image: image,
+		};
+		return match existing
+		{
+			Some(idx) =>
+			{
+				self.last_dab = None;
+				self.canvases[idx] = canvas;
+				Ok(idx)
+			}
+			None =>
+			{
+				self.canvases.push(canvas);
+				Ok(self.canvases.len() - 1)
+			}
+		};
+	}
+
+	/// Paints at the texture coordinates, joining them to the previous dab of the stroke.
+	fn paint_stroke(&mut self, canvas: usize, uv: [f32; 2])
+	{
+		// Textures are sampled with mirrored repeats
+		let mirror = |t: f32| {
+			let t = t.rem_euclid(2.0);
+			return if t > 1.0
+			{
+				2.0 - t
+			}
+			else
+			{
+				t
+			};
+		};
+		let uv = [mirror(uv[0]), mirror(uv[1])];
+		let brush = self.brush;
+		let image = &mut self.canvases[canvas].image;
+		let size = (image.width() as f32, image.height() as f32);
+		let to = (uv[0] * size.0, uv[1] * size.1);
+		let from = match self.last_dab
+		{
+			Some((last_canvas, last_uv)) if last_canvas == canvas => (last_uv[0] * size.0, last_uv[1] * size.1),
+			_ => to,
+		};
+		let gap = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+		// Dabs overlap by half the radius, so the stroke is even
+		let steps = if gap > MAX_STROKE_GAP * size.0.max(size.1)
+		{
+			0
+		}
+		else
+		{
+			(gap / (0.5 * brush.radius)).ceil() as usize
+		};
+		for step in 1..=steps.max(1)
+		{
+			let t = step as f32 / steps.max(1) as f32;
+			let center = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+			TexturePainter::paint_dab(image, center, &brush);
+		}
+		self.last_dab = Some((canvas, uv));
+	}
+
+	/// Paints a disc centered at the given texel coordinates, mirrored at the edges as the texture repeats.
+	fn paint_dab(image: &mut RgbaImage, center: (f32, f32), brush: &Brush)
+	{
+		let (width, height) = (image.width() as i64, image.height() as i64);
+		let color = [brush.color[0] * 255.0, brush.color[1] * 255.0, brush.color[2] * 255.0, 255.0];
+		let min = ((center.0 - brush.radius).floor() as i64, (center.1 - brush.radius).floor() as i64);
+		let max = ((center.0 + brush.radius).ceil() as i64, (center.1 + brush.radius).ceil() as i64);
+		let mirror = |t: i64, size: i64| {
+			let t = t.rem_euclid(2 * size);
+			return if t >= size
+			{
+				2 * size - 1 - t
+			}
+			else
+			{
+				t
+			};
+		};
+		for y in min.1..max.1
+		{
+			for x in min.0..max.0
+			{
+				let distance = ((x as f32 + 0.5 - center.0).powi(2) + (y as f32 + 0.5 - center.1).powi(2)).sqrt();
+				// Antialiased over one texel at the edge
+				let coverage = (brush.radius - distance + 0.5).max(0.0).min(1.0) * brush.opacity;
+				if coverage <= 0.0
+				{
+					continue;
+				}
+				let texel = image.get_pixel_mut(mirror(x, width) as u32, mirror(y, height) as u32);
+				for channel in 0..4
+				{
+					let value = texel[channel] as f32;
+					texel[channel] = (value + (color[channel] - value) * coverage).round() as u8;
+				}
+			}
+		}
+	}
+
+	/// Swaps the canvas of the material with the given image, painted into the texture at the path, and shows it. Used
+	/// for undoing and redoing strokes. Does nothing if the canvas has been loaded from another texture since, as
+	/// strokes on that one cannot be undone on this one.
+	pub fn swap_canvas(&mut self, rs: &RenderState, material: &Rc<Material>, texture_path: &str, image: &mut RgbaImage)
+	{
+		let canvas = self.canvases.iter().position(|canvas| {
+			Weak::as_ptr(&canvas.material) == Rc::as_ptr(material) && canvas.texture_path == texture_path
+		});
+		if let Some(canvas) = canvas
+		{
+			std::mem::swap(&mut self.canvases[canvas].image, image);
+			material.set_texture_image(rs, &self.canvases[canvas].image);
+			self.last_dab = None;
+			self.saved_canvas = Some(canvas);
+		}
+	}
+
+	/// Writes the canvas painted last next to the texture it was loaded from, and points the material at it.
+	fn save(&mut self, rs: &RenderState) -> Result<String, String>
+	{
+		let canvas = match self.saved_canvas
+		{
+			Some(canvas) => &mut self.canvases[canvas],
+			None => return Err("Nothing painted to save".to_string()),
+		};
+		let material =
+			canvas.material.upgrade().ok_or_else(|| "The painted material is no longer in the scene".to_string())?;
+
+		let path = Path::new(&canvas.texture_path);
+		let stem = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+		let file_name = if stem.ends_with(PAINTED_SUFFIX)
+		{
+			format!("{}.png", stem)
+		}
+		else
+		{
+			format!("{}{}.png", stem, PAINTED_SUFFIX)
+		};
+		let save_path = path.with_file_name(file_name).to_string_lossy().into_owned();
+
+		let mut data = Vec::new();
+		PngEncoder::new(&mut data)
+			.encode(canvas.image.as_raw(), canvas.image.width(), canvas.image.height(), ColorType::Rgba8)
+			.map_err(|e| format!("Save failed: {}", e))?;
+		write_atomic(&save_path, &data).map_err(|e| format!("Save failed: {}: {}", save_path, e))?;
+
+		material.set_texture(rs, &save_path, false);
+		canvas.texture_path = save_path.clone();
+		return Ok(format!("Saved {}", save_path));
+	}
+
+	fn draw(&self, overlay: &mut Overlay)
+	{
+		let style = TextStyle {
+			outline_width: 1.5,
+			shadow_color: [0.0, 0.0, 0.0, 0.6],
+			shadow_offset: [2.0, 2.0],
+			..Default::default()
+		};
+		let title_style = TextStyle {
+			color: TITLE_COLOR,
+			..style
+		};
+		let (x, mut y) = PANEL_POSITION;
+		let mut line = |text: &str, style: &TextStyle| {
+			overlay.draw_text(text, (x, y), TEXT_SIZE, style);
+			y += LINE_SPACING;
+		};
+
+		line("Texture paint", &title_style);
+		let brush = &self.brush;
+		line(
+			&format!(
+				"Brush: radius {:.0}  color {:.2} {:.2} {:.2}  opacity {:.2}",
+				brush.radius, brush.color[0], brush.color[1], brush.color[2], brush.opacity
+			),
+			&style,
+		);
+		line("Click/drag: paint  [/]: size  Ctrl+Z: undo  F7: save", &style);
+		match &self.status
+		{
+			Some((Ok(message), _)) => line(message, &style),
+			Some((Err(message), _)) => line(
+				message,
+				&TextStyle {
+					color: ERROR_COLOR,
+					..style
+				},
+			),
+			None => (),
+		}
+	}
+}
+
+impl InputConsumer for TexturePainter
+{
+	fn get_handled_actions(&self) -> BitVec
+	{
+		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
+
+		handled_actions.set(Action::PAINT_TOGGLE as usize, true);
+		handled_actions.set(Action::PAINT_SAVE as usize, true);
+		handled_actions.set(Action::PAINT_BRUSH_SMALLER as usize, true);
+		handled_actions.set(Action::PAINT_BRUSH_LARGER as usize, true);
+
+		return handled_actions;
+	}
+	fn consume(&mut self, actions: &ActionEvents)
+	{
+		// Changes are applied in update(), where the materials are available
+		if actions.is_triggered(Action::PAINT_TOGGLE)
+		{
+			self.pending_commands.push(PaintCommand::Toggle);
+		}
+		if actions.is_triggered(Action::PAINT_SAVE)
+		{
+			self.pending_commands.push(PaintCommand::Save);
+		}
+		if actions.is_triggered(Action::PAINT_BRUSH_SMALLER)
+		{
+			self.pending_commands.push(PaintCommand::Resize(1.0 / BRUSH_RESIZE_FACTOR));
+		}
+		if actions.is_triggered(Action::PAINT_BRUSH_LARGER)
+		{
+			self.pending_commands.push(PaintCommand::Resize(BRUSH_RESIZE_FACTOR));
+		}
+	}
+}
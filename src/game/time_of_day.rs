@@ -0,0 +1,137 @@
+use crate::core::Console;
+use cgmath::prelude::*;
+use cgmath::{Deg, Rad, Vector3};
+use std::f32::consts::PI;
+
+/// Angle between the noon sun and the zenith.
+const NOON_ZENITH_ANGLE: Deg<f32> = Deg(50.0);
+/// Night-only lights turn on when the sun sinks below this elevation, in degrees.
+const LIGHTS_ON_ELEVATION: f32 = 2.0;
+/// Night-only lights turn off when the sun rises above this elevation, in degrees. The gap to LIGHTS_ON_ELEVATION
+/// keeps the lights from flickering while the sun lingers around the threshold.
+const LIGHTS_OFF_ELEVATION: f32 = 6.0;
+/// How fast night-only lights fade in and out, in brightness per second.
+const LIGHTS_FADE_RATE: f32 = 0.5;
+
+/// Whether night-only lights follow the sun, or are forced on or off from the console.
+#[derive(Clone, Copy, PartialEq)]
+pub enum NightLightMode
+{
+	Auto,
+	On,
+	Off,
+}
+
+impl NightLightMode
+{
+	pub fn from_name(name: &str) -> Option<NightLightMode>
+	{
+		match name
+		{
+			"auto" => Some(NightLightMode::Auto),
+			"on" => Some(NightLightMode::On),
+			"off" => Some(NightLightMode::Off),
+			_ => None,
+		}
+	}
+}
+
+/// The in-game clock, moving the sun and switching the night-only lights.
+pub struct TimeOfDay
+{
+	/// Hours since midnight, in the range [0, 24).
+	hours: f32,
+	// The time_of_day cvar as of the last update, so that changing it sets the clock
+	cvar_hours: f32,
+	light_mode: NightLightMode,
+	lights_on: bool,
+	/// Brightness of the night-only lights, in the range [0, 1].
+	light_fade: f32,
+}
+
+impl TimeOfDay
+{
+	pub fn new(console: &mut Console) -> TimeOfDay
+	{
+		console.register_float("time_of_day", 14.0, "Hour of the day, setting it moves the clock [0, 24)");
+		console.register_float("time_of_day_speed", 0.0, "In-game hours passing per real minute, 0 stops the clock");
+
+		let hours = console.get_float("time_of_day").rem_euclid(24.0);
+		let lights_on = TimeOfDay::get_elevation_at(hours).0 < LIGHTS_ON_ELEVATION;
+		TimeOfDay {
+			hours: hours,
+			cvar_hours: console.get_float("time_of_day"),
+			light_mode: NightLightMode::Auto,
+			lights_on: lights_on,
+			light_fade: if lights_on
+			{
+				1.0
+			}
+			else
+			{
+				0.0
+			},
+		}
+	}
+
+	/// Elevation of the sun above the horizon at the given hour, negative at night.
+	fn get_elevation_at(hours: f32) -> Deg<f32>
+	{
+		return Deg::from(Rad(TimeOfDay::get_sun_direction_at(hours).y.asin()));
+	}
+
+	/// Direction towards the sun at the given hour. The sun rises in +x at 6, and sets in -x at 18.
+	fn get_sun_direction_at(hours: f32) -> Vector3<f32>
+	{
+		let (sin, cos) = ((hours - 6.0) / 12.0 * PI).sin_cos();
+		let (tilt_sin, tilt_cos) = NOON_ZENITH_ANGLE.sin_cos();
+		return Vector3::new(cos, sin * tilt_cos, -sin * tilt_sin);
+	}
+
+	/// Returns the normalized direction towards the sun.
+	pub fn get_sun_direction(&self) -> Vector3<f32>
+	{
+		return TimeOfDay::get_sun_direction_at(self.hours);
+	}
+
+	/// Returns how bright night-only lights are, in the range [0, 1].
+	pub fn get_night_light_fade(&self) -> f32
+	{
+		return self.light_fade;
+	}
+
+	/// Forces the night-only lights on or off, or lets them follow the sun again.
+	pub fn set_night_light_mode(&mut self, mode: NightLightMode)
+	{
+		self.light_mode = mode;
+	}
+
+	/// Advances the clock by the given timestep, in seconds, and fades the night-only lights.
+	pub fn update(&mut self, console: &Console, timestep: f32)
+	{
+		let cvar_hours = console.get_float("time_of_day");
+		if cvar_hours != self.cvar_hours
+		{
+			self.cvar_hours = cvar_hours;
+			self.hours = cvar_hours;
+		}
+		self.hours = (self.hours + console.get_float("time_of_day_speed") * timestep / 60.0).rem_euclid(24.0);
+
+		let elevation = TimeOfDay::get_elevation_at(self.hours).0;
+		if elevation < LIGHTS_ON_ELEVATION
+		{
+			self.lights_on = true;
+		}
+		else if elevation > LIGHTS_OFF_ELEVATION
+		{
+			self.lights_on = false;
+		}
+		let target = match (self.light_mode, self.lights_on)
+		{
+			(NightLightMode::On, _) | (NightLightMode::Auto, true) => 1.0,
+			(NightLightMode::Off, _) | (NightLightMode::Auto, false) => 0.0,
+		};
+		let max_change = LIGHTS_FADE_RATE * timestep;
+		self.light_fade += (target - self.light_fade).max(-max_change).min(max_change);
+	}
+}
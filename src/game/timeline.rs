@@ -0,0 +1,426 @@
+use crate::core::{unversioned_to_v1, Console, FileFormat, Material};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+const TIMELINE_FORMAT: FileFormat = FileFormat {
+	name: "timeline",
+	version: 1,
+	migrations: &[unversioned_to_v1],
+};
+
+/// What starts a timeline. Any timeline can also be started with the timeline_play command.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineTrigger
+{
+	/// Only started by command.
+	Manual,
+	/// When the scene is loaded, and when the timeline file changes.
+	SceneStart,
+	VehicleEntered,
+	VehicleExited,
+	/// When the camera runs into an agent fast enough for slow motion.
+	BigCrash,
+}
+
+impl Default for TimelineTrigger
+{
+	fn default() -> TimelineTrigger
+	{
+		return TimelineTrigger::Manual;
+	}
+}
+
+/// What a track animates. Lights are indexed as in the scene file, materials named as in it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TrackTarget
+{
+	/// A float cvar, or a bool cvar that is on from 0.5.
+	Cvar(String),
+	/// Replaces the color of a light.
+	LightColor(usize),
+	/// Multiplies the color of a light.
+	LightIntensity(usize),
+	MaterialTint(String),
+	MaterialEmissive(String),
+	MaterialOpacity(String),
+	MaterialSpecular(String),
+}
+
+impl TrackTarget
+{
+	/// Returns the number of values of each keyframe.
+	fn get_value_count(&self) -> usize
+	{
+		match self
+		{
+			TrackTarget::LightColor(_) | TrackTarget::MaterialTint(_) | TrackTarget::MaterialEmissive(_) => 3,
+			_ => 1,
+		}
+	}
+}
+
+/// How the values between two keyframes are found.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Interpolation
+{
+	/// Holds the value of the previous keyframe.
+	Step,
+	Linear,
+	/// Eases in and out of each keyframe.
+	Smooth,
+}
+
+impl Default for Interpolation
+{
+	fn default() -> Interpolation
+	{
+		return Interpolation::Linear;
+	}
+}
+
+/// A single number, or a color.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum KeyValue
+{
+	Scalar(f32),
+	Vector(Vec<f32>),
+}
+
+impl KeyValue
+{
+	fn get(&self) -> &[f32]
+	{
+		match self
+		{
+			KeyValue::Scalar(value) => std::slice::from_ref(value),
+			KeyValue::Vector(values) => values,
+		}
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Keyframe
+{
+	/// Seconds of game time from the start of the timeline.
+	time: f32,
+	value: KeyValue,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Track
+{
+	target: TrackTarget,
+	#[serde(default)]
+	interpolation: Interpolation,
+	keys: Vec<Keyframe>,
+	// Set once a missing target has been reported
+	#[serde(skip)]
+	warned: bool,
+}
+
+impl Track
+{
+	/// Returns the values at the given time, holding the first and last keyframes outside of them.
+	fn evaluate(&self, time: f32) -> Vec<f32>
+	{
+		let next_idx = self.keys.iter().position(|key| key.time > time).unwrap_or(self.keys.len());
+		if next_idx == 0
+		{
+			return self.keys[0].value.get().to_vec();
+		}
+		let prev = &self.keys[next_idx - 1];
+		let next = match self.keys.get(next_idx)
+		{
+			Some(next) => next,
+			None => return prev.value.get().to_vec(),
+		};
+		let t = (time - prev.time) / (next.time - prev.time);
+		let t = match self.interpolation
+		{
+			Interpolation::Step => 0.0,
+			Interpolation::Linear => t,
+			Interpolation::Smooth => t * t * (3.0 - 2.0 * t),
+		};
+		return prev.value.get().iter().zip(next.value.get()).map(|(a, b)| a + (b - a) * t).collect();
+	}
+}
+
+/// Keyframed values driving cvars, lights and material parameters, read from a file in the timelines directory.
+#[derive(Clone, Serialize, Deserialize)]
+struct Timeline
+{
+	#[serde(default)]
+	trigger: TimelineTrigger,
+	/// Starts over at the end, until stopped.
+	#[serde(default)]
+	looping: bool,
+	tracks: Vec<Track>,
+}
+
+impl Timeline
+{
+	fn read(path: &str) -> Result<Timeline, String>
+	{
+		let mut timeline: Timeline = TIMELINE_FORMAT.read(path)?;
+		for (track_idx, track) in timeline.tracks.iter_mut().enumerate()
+		{
+			let count = track.target.get_value_count();
+			if track.keys.is_empty()
+			{
+				return Err(format!("{}: track {} has no keyframes", path, track_idx));
+			}
+			if let Some(key) = track.keys.iter().find(|key| key.value.get().len() != count)
+			{
+				return Err(format!(
+					"{}: track {} has {} values at {} s, expected {}",
+					path,
+					track_idx,
+					key.value.get().len(),
+					key.time,
+					count
+				));
+			}
+			track.keys.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+		}
+		return Ok(timeline);
+	}
+
+	/// Returns the time of the last keyframe.
+	fn get_duration(&self) -> f32
+	{
+		return self.tracks.iter().filter_map(|track| track.keys.last()).map(|key| key.time).fold(0.0, f32::max);
+	}
+}
+
+struct Playback
+{
+	name: String,
+	time: f32,
+}
+
+/// The timelines of the timelines directory, by file name without the extension, and the ones playing.
+///
+/// Timelines play in game time, so slow motion slows them down. Values are applied every update while a timeline
+/// plays, and the last ones stay when it ends or is stopped. When several timelines drive the same target, the one
+/// started last wins.
+pub struct Timelines
+{
+	dir: String,
+	timelines: HashMap<String, Timeline>,
+	playing: Vec<Playback>,
+	// Set by the timelines, by light index
+	light_colors: HashMap<usize, [f32; 3]>,
+	light_intensities: HashMap<usize, f32>,
+}
+
+impl Timelines
+{
+	/// Loads the timelines in the given directory. Timelines that cannot be read are skipped with a warning.
+	pub fn load(dir: &str) -> Timelines
+	{
+		let mut timelines = Timelines {
+			dir: dir.to_string(),
+			timelines: HashMap::new(),
+			playing: Vec::new(),
+			light_colors: HashMap::new(),
+			light_intensities: HashMap::new(),
+		};
+		// A scene does not need any timelines
+		let entries = match fs::read_dir(dir)
+		{
+			Ok(entries) => entries,
+			Err(_) => return timelines,
+		};
+		for entry in entries.filter_map(|entry| entry.ok())
+		{
+			let path = entry.path();
+			if path.extension().and_then(|ext| ext.to_str()) == Some("json")
+			{
+				timelines.reload(&path.to_string_lossy());
+			}
+		}
+		return timelines;
+	}
+
+	/// Returns the paths of the loaded timelines, to watch for changes.
+	pub fn get_paths(&self) -> Vec<String>
+	{
+		return self.timelines.keys().map(|name| self.get_path(name)).collect();
+	}
+
+	fn get_path(&self, name: &str) -> String
+	{
+		return format!("{}/{}.json", self.dir, name);
+	}
+
+	/// Reads the timeline at the given path again, restarting it if it plays or starts with the scene. Returns false
+	/// if the path is not in the timelines directory.
+	pub fn reload(&mut self, path: &str) -> bool
+	{
+		let path = Path::new(path);
+		let name = match path.file_stem().and_then(|stem| stem.to_str())
+		{
+			Some(name) if path.parent() == Some(Path::new(&self.dir)) => name.to_string(),
+			_ => return false,
+		};
+		match Timeline::read(&path.to_string_lossy())
+		{
+			Ok(timeline) =>
+			{
+				let restart = timeline.trigger == TimelineTrigger::SceneStart ||
+					self.playing.iter().any(|playback| playback.name == name);
+				self.timelines.insert(name.clone(), timeline);
+				if restart
+				{
+					self.play(&name);
+				}
+			}
+			Err(e) => warning!("Could not read timeline: {}", e),
+		}
+		return true;
+	}
+
+	/// Starts the timelines with the given trigger.
+	pub fn trigger(&mut self, trigger: TimelineTrigger)
+	{
+		let mut names: Vec<String> = self
+			.timelines
+			.iter()
+			.filter(|(_, timeline)| timeline.trigger == trigger)
+			.map(|(name, _)| name.clone())
+			.collect();
+		// In a stable order, for the one started last to win
+		names.sort();
+		for name in names
+		{
+			self.play(&name);
+		}
+	}
+
+	/// Starts the timeline with the given name from the beginning. Returns false if there is none.
+	pub fn play(&mut self, name: &str) -> bool
+	{
+		if !self.timelines.contains_key(name)
+		{
+			return false;
+		}
+		self.playing.retain(|playback| playback.name != name);
+		self.playing.push(Playback {
+			name: name.to_string(),
+			time: 0.0,
+		});
+		return true;
+	}
+
+	/// Stops the timeline with the given name, keeping its current values. Returns false if it was not playing.
+	pub fn stop(&mut self, name: &str) -> bool
+	{
+		let playing = self.playing.len();
+		self.playing.retain(|playback| playback.name != name);
+		return self.playing.len() < playing;
+	}
+
+	/// Prints the timelines, and which are playing.
+	pub fn print(&self)
+	{
+		let mut names: Vec<&String> = self.timelines.keys().collect();
+		names.sort();
+		for name in names
+		{
+			let timeline = &self.timelines[name];
+			let playing = match self.playing.iter().find(|playback| &playback.name == name)
+			{
+				Some(playback) => format!(", playing at {:.1} s", playback.time),
+				None => String::new(),
+			};
+			println!("{}: {:.1} s, {} tracks{}", name, timeline.get_duration(), timeline.tracks.len(), playing);
+		}
+	}
+
+	/// Advances the playing timelines and applies their values. Materials are looked up by name, timelines skip
+	/// materials that are not loaded.
+	pub fn update(&mut self, timestep: f32, console: &mut Console, find_material: impl Fn(&str) -> Option<Rc<Material>>)
+	{
+		let timelines = &mut self.timelines;
+		let light_colors = &mut self.light_colors;
+		let light_intensities = &mut self.light_intensities;
+		self.playing.retain_mut(|playback| {
+			// Timelines are only replaced, never removed
+			let timeline = timelines.get_mut(&playback.name).unwrap();
+			let duration = timeline.get_duration();
+			playback.time += timestep;
+			if timeline.looping && duration > 0.0
+			{
+				playback.time %= duration;
+			}
+			for track in timeline.tracks.iter_mut()
+			{
+				let values = track.evaluate(playback.time);
+				let found = match &track.target
+				{
+					TrackTarget::Cvar(name) => console.set_value(name, values[0]),
+					TrackTarget::LightColor(idx) =>
+					{
+						light_colors.insert(*idx, [values[0], values[1], values[2]]);
+						true
+					}
+					TrackTarget::LightIntensity(idx) =>
+					{
+						light_intensities.insert(*idx, values[0]);
+						true
+					}
+					TrackTarget::MaterialTint(name) |
+					TrackTarget::MaterialEmissive(name) |
+					TrackTarget::MaterialOpacity(name) |
+					TrackTarget::MaterialSpecular(name) => match find_material(name)
+					{
+						Some(material) =>
+						{
+							let mut desc = material.get_desc();
+							match track.target
+							{
+								TrackTarget::MaterialTint(_) => desc.tint = [values[0], values[1], values[2]],
+								TrackTarget::MaterialEmissive(_) => desc.emissive = [values[0], values[1], values[2]],
+								TrackTarget::MaterialOpacity(_) => desc.opacity = values[0],
+								_ => desc.specular = values[0],
+							}
+							material.set_params(&desc);
+							true
+						}
+						None => false,
+					},
+				};
+				if !found && !track.warned
+				{
+					track.warned = true;
+					let target = match &track.target
+					{
+						TrackTarget::Cvar(name) => format!("cvar {}", name),
+						TrackTarget::MaterialTint(name) |
+						TrackTarget::MaterialEmissive(name) |
+						TrackTarget::MaterialOpacity(name) |
+						TrackTarget::MaterialSpecular(name) => format!("material {}", name),
+						TrackTarget::LightColor(idx) | TrackTarget::LightIntensity(idx) => format!("light {}", idx),
+					};
+					warning!("Timeline {}: no {} to animate", playback.name, target);
+				}
+			}
+			return timeline.looping || playback.time < duration;
+		});
+	}
+
+	/// Returns the color of the light with the given index in the scene file, as set by the timelines.
+	pub fn get_light_color(&self, idx: usize, color: [f32; 3]) -> [f32; 3]
+	{
+		let color = self.light_colors.get(&idx).cloned().unwrap_or(color);
+		let intensity = self.light_intensities.get(&idx).cloned().unwrap_or(1.0);
+		return [color[0] * intensity, color[1] * intensity, color[2] * intensity];
+	}
+}
@@ -0,0 +1,150 @@
+use crate::core::ParticleVertex;
+use crate::renderer::{MainPass, RenderState, TriangleBatch};
+use ash::vk;
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector3};
+use std::collections::VecDeque;
+
+// Distance the emitter moves before the trail gets a new segment, in meters
+const SEGMENT_LENGTH: f32 = 0.25;
+// Moving further than this in one update breaks the trail, as when a replay seeks, in meters
+const MAX_GAP: f32 = 5.0;
+
+struct TrailPoint
+{
+	position: Point3<f32>,
+	// Half the width of the trail, across the direction of travel
+	side: Vector3<f32>,
+	age: f32,
+	// Whether a segment connects the point to the one before it
+	connected: bool,
+}
+
+/// Leaves a ribbon behind a moving point while emitting, like skidmarks behind a wheel or light trails behind a lamp.
+///
+/// The ribbon lies across the given normal and fades out over its lifetime. When the trail has used up its segment
+/// budget, the oldest segments are dropped.
+pub struct Trail
+{
+	points: VecDeque<TrailPoint>,
+	max_segments: usize,
+	half_width: f32,
+	// Seconds until a segment has faded out
+	lifetime: f32,
+	color: [f32; 4],
+	// Whether the last point follows the emitter
+	emitting: bool,
+
+	// Two triangles per segment
+	triangles: TriangleBatch,
+}
+
+impl Trail
+{
+	pub fn new(rs: &RenderState, max_segments: usize, width: f32, lifetime: f32, color: [f32; 4]) -> Trail
+	{
+		Trail {
+			points: VecDeque::with_capacity(max_segments + 2),
+			max_segments: max_segments,
+			half_width: 0.5 * width,
+			lifetime: lifetime,
+			color: color,
+			emitting: false,
+			triangles: TriangleBatch::new(rs, 2 * max_segments),
+		}
+	}
+
+	/// Ages the trail, and extends it to the position while emitting. Stopping ends the ribbon, so emitting again
+	/// starts a new one.
+	pub fn update(&mut self, position: Point3<f32>, normal: Vector3<f32>, emitting: bool, timestep: f32)
+	{
+		for point in &mut self.points
+		{
+			point.age += timestep;
+		}
+		while self.points.front().map_or(false, |point| point.age >= self.lifetime)
+		{
+			self.points.pop_front();
+		}
+
+		let near_head = self.points.back().map_or(false, |head| head.position.distance(position) < MAX_GAP);
+		let continuing = self.emitting && emitting && near_head;
+		self.emitting = emitting;
+		if !emitting
+		{
+			return;
+		}
+		if !continuing
+		{
+			// A fixed start point, and a head following the emitter
+			for &connected in [false, true].iter()
+			{
+				self.points.push_back(TrailPoint {
+					position: position,
+					side: Vector3::zero(),
+					age: 0.0,
+					connected: connected,
+				});
+			}
+		}
+
+		let len = self.points.len();
+		let previous = self.points[len - 2].position;
+		let travel = position - previous;
+		if travel.magnitude2() > 1e-8
+		{
+			let side = normal.cross(travel).normalize() * self.half_width;
+			if !self.points[len - 2].connected
+			{
+				self.points[len - 2].side = side;
+			}
+			self.points[len - 1].side = side;
+		}
+		let head = &mut self.points[len - 1];
+		head.position = position;
+		head.age = 0.0;
+
+		// The head stays behind as a fixed point, and a new one follows the emitter
+		if travel.magnitude() >= SEGMENT_LENGTH
+		{
+			let side = head.side;
+			self.points.push_back(TrailPoint {
+				position: position,
+				side: side,
+				age: 0.0,
+				connected: true,
+			});
+		}
+		while self.points.len() > self.max_segments + 1
+		{
+			self.points.pop_front();
+		}
+	}
+
+	/// Draws the trail. Blended, so call this after drawing opaque geometry.
+	pub fn draw(
+		&mut self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>,
+	)
+	{
+		let mut vertices = Vec::with_capacity(6 * self.max_segments);
+		let vertex = |point: &TrailPoint, side: f32| {
+			let fade = (1.0 - point.age / self.lifetime).max(0.0);
+			ParticleVertex {
+				pos: (point.position + point.side * side).into(),
+				color: [self.color[0], self.color[1], self.color[2], self.color[3] * fade],
+			}
+		};
+		for (start, end) in self.points.iter().zip(self.points.iter().skip(1)).filter(|(_, end)| end.connected)
+		{
+			vertices.push(vertex(start, -1.0));
+			vertices.push(vertex(start, 1.0));
+			vertices.push(vertex(end, 1.0));
+			vertices.push(vertex(start, -1.0));
+			vertices.push(vertex(end, 1.0));
+			vertices.push(vertex(end, -1.0));
+		}
+		self.triangles.set_triangles(&vertices);
+		self.triangles.draw(cmd_buf, mp, view_matrix, projection_matrix);
+	}
+}
@@ -0,0 +1,126 @@
+use crate::core::{Console, RandomService, RandomStream};
+use crate::game::ParticleEmitter;
+use crate::renderer::{MainPass, RenderState};
+use ash::vk;
+use cgmath::{Matrix4, Point3, Vector3};
+use serde_derive::{Deserialize, Serialize};
+
+const MAX_RAIN_PARTICLES: usize = 4_000;
+
+/// Initial weather, as given by the scene settings.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WeatherSettings
+{
+	/// Amount of rain, in the range [0, 1].
+	pub rain_intensity: f32,
+	/// How fast surfaces get wet/dry, in wetness units per second.
+	pub wetting_rate: f32,
+	pub screen_droplets: bool,
+}
+
+impl Default for WeatherSettings
+{
+	fn default() -> WeatherSettings
+	{
+		WeatherSettings {
+			rain_intensity: 0.0,
+			wetting_rate: 0.05,
+			screen_droplets: true,
+		}
+	}
+}
+
+/// Drives rain, surface wetness and screen droplets from the weather cvars.
+pub struct WeatherController
+{
+	rain: ParticleEmitter,
+	wetness: f32,
+	droplets: f32,
+}
+
+impl WeatherController
+{
+	pub fn new(
+		rs: &RenderState, settings: &WeatherSettings, random: &RandomService, console: &mut Console,
+	) -> WeatherController
+	{
+		WeatherController::register_cvars(settings, console);
+
+		let rain = ParticleEmitter::new(
+			rs,
+			random.create_rng(RandomStream::Particles, 0),
+			MAX_RAIN_PARTICLES,
+			Vector3::new(20.0, 15.0, 20.0),
+			Vector3::new(0.5, -20.0, 0.3),
+			0.03,
+			[0.7, 0.75, 0.8, 0.6],
+		);
+
+		WeatherController {
+			rain: rain,
+			wetness: settings.rain_intensity,
+			droplets: 0.0,
+		}
+	}
+
+	fn register_cvars(settings: &WeatherSettings, console: &mut Console)
+	{
+		console.register_float("weather_rain", settings.rain_intensity, "Rain intensity [0, 1]");
+		console.register_float("weather_wetting_rate", settings.wetting_rate, "Surface wetting/drying per second");
+		console.register_bool("weather_droplets", settings.screen_droplets, "Rain droplets on the screen");
+	}
+
+	/// Changes the weather to the given settings. Surfaces get wet or dry gradually, as when changing the cvars.
+	pub fn apply_settings(&mut self, settings: &WeatherSettings, console: &mut Console)
+	{
+		WeatherController::register_cvars(settings, console);
+	}
+
+	/// Returns a value in [0, 1] describing how wet surfaces are.
+	pub fn get_wetness(&self) -> f32
+	{
+		self.wetness
+	}
+
+	/// Returns a value in [0, 1] describing how many droplets are on the screen.
+	pub fn get_droplet_amount(&self) -> f32
+	{
+		self.droplets
+	}
+
+	/// Moves the weather towards the state given by the cvars. The rain follows the given position.
+	pub fn update(&mut self, console: &Console, camera_position: Point3<f32>, timestep: f32)
+	{
+		let rain_intensity = console.get_float("weather_rain").max(0.0).min(1.0);
+		let wetting_rate = console.get_float("weather_wetting_rate").max(0.0);
+
+		// Surfaces approach the wetness given by the rain gradually
+		let max_change = wetting_rate * timestep;
+		self.wetness += (rain_intensity - self.wetness).max(-max_change).min(max_change);
+
+		// Droplets react faster than surfaces
+		let droplets_target = if console.get_bool("weather_droplets")
+		{
+			rain_intensity
+		}
+		else
+		{
+			0.0
+		};
+		let max_change = 0.5 * timestep;
+		self.droplets += (droplets_target - self.droplets).max(-max_change).min(max_change);
+
+		self.rain.set_anchor(camera_position);
+		self.rain.set_active_fraction(rain_intensity);
+		self.rain.update(timestep);
+	}
+
+	pub fn draw(
+		&mut self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>,
+	)
+	{
+		self.rain.draw(cmd_buf, mp, view_matrix, projection_matrix);
+	}
+}
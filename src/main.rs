@@ -1,28 +1,59 @@
+// First, so its macros can be used by the other modules
+#[macro_use]
 mod core;
+mod audio;
+mod benchmark;
 mod game;
+#[cfg(feature = "presence")]
+mod presence;
 mod renderer;
+mod selftest;
 
-use crate::core::{Action, ActionType, Config, InputConsumer, InputHandler, KeyEventState};
-use crate::game::Scene;
-use crate::renderer::{MainPass, PresentPass, RenderState};
-use ash::util::Align;
-use ash::version::DeviceV1_0;
+use crate::audio::AudioSystem;
+use crate::benchmark::{Benchmark, BenchmarkSettings};
+use crate::core::{
+	Action, ActionEvents, ActionType, Autosaver, Config, Console, CursorKind, Cursors, InputConsumer, InputHandler,
+	KeyEventState, RandomService, RandomStream, RedrawScheduler, Time, TimeDomain,
+};
+use crate::game::{
+	new_screenshot_path, DebugHud, GameEvent, GameState, GameStateMachine, LogOverlay, Scene, ScreenshotGallery,
+};
+#[cfg(feature = "presence")]
+use crate::presence::{LogBackend, PresenceReporter};
+use crate::renderer::{
+	get_display_scale, AutoQuality, CaptureReader, DamageBaker, DebugView, FrameUniforms, HeatmapMode, Heatmaps, Icon,
+	IrradianceGrid, LightUniforms, MainPass, PresentPass, ReflectionProbes, RenderState, ShadowPass, Stereo,
+	StereoMode, TextStyle, ViewportMapper, VolumetricFog,
+};
 use ash::vk;
 use bit_vec::BitVec;
 use cgmath::{Deg, Matrix4, Rad};
+use sdl2::controller::GameController;
 use sdl2::event::{Event, WindowEvent};
+use sdl2::mouse::{MouseButton, MouseWheelDirection};
 use std::cell::RefCell;
-use std::io::Write;
-use std::mem::{align_of, size_of};
+use std::fs;
+use std::path::Path;
 use std::rc::Rc;
-use std::time::{Duration, SystemTime};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 const ENGINE_TARGET_HZ: u64 = 60;
 const ENGINE_TIMESTEP: Duration = Duration::from_nanos(1_000_000_000 / ENGINE_TARGET_HZ);
+// Half the height of the area shown by the top-down debug view, in meters
+const DEBUG_VIEW_HALF_HEIGHT: f32 = 40.0;
+const FRAME_GRAPH_PATH: &str = "frame_graph.dot";
+// Changed cvars are saved here, and restored on the next start
+const CVARS_FILE: &str = "cvars.json";
+const BINDINGS_FILE: &str = "bindings.json";
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+const PASS_STATS_COLOR: [f32; 4] = [1.0, 0.9, 0.6, 1.0];
+// Software cursor shown while the hardware cursor is hidden by relative mouse mode
+const CROSSHAIR_SIZE: f32 = 16.0;
+const CROSSHAIR_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.8];
 
 struct EngineState
 {
-	pub running: bool,
 	pub cursor_captured: bool,
 	pub cursor_state_dirty: bool,
 }
@@ -32,7 +63,6 @@ impl EngineState
 	fn new() -> EngineState
 	{
 		return EngineState {
-			running: true,
 			cursor_captured: false,
 			cursor_state_dirty: true,
 		};
@@ -45,18 +75,13 @@ impl InputConsumer for EngineState
 	{
 		let mut handled_actions = BitVec::from_elem(Action::LENGTH_OF_ENUM as usize, false);
 
-		handled_actions.set(Action::TERMINATE as usize, true);
 		handled_actions.set(Action::CURSOR_CAPTURE_TOGGLE as usize, true);
 
 		return handled_actions;
 	}
-	fn consume(&mut self, actions: BitVec)
+	fn consume(&mut self, actions: &ActionEvents)
 	{
-		if actions.get(Action::TERMINATE as usize).unwrap()
-		{
-			self.running = false;
-		}
-		if actions.get(Action::CURSOR_CAPTURE_TOGGLE as usize).unwrap()
+		if actions.is_triggered(Action::CURSOR_CAPTURE_TOGGLE)
 		{
 			self.cursor_captured = !self.cursor_captured;
 			self.cursor_state_dirty = true;
@@ -64,11 +89,73 @@ impl InputConsumer for EngineState
 	}
 }
 
+/// Opens the debug view window, printing a warning if it fails.
+fn open_debug_view(rs: &RenderState, cfg: &Config, video_subsystem: &sdl2::VideoSubsystem) -> Option<DebugView>
+{
+	match DebugView::new(rs, cfg, video_subsystem)
+	{
+		Ok(debug_view) => Some(debug_view),
+		Err(e) =>
+		{
+			warning!("Could not open debug view: {}", e);
+			None
+		}
+	}
+}
+
+/// Returns the render size for a window size, keeping the configured ratio of render pixels to window units, times
+/// render_scale.
+fn get_render_extent(cfg: &Config, window_size: (u32, u32), render_scale: f32) -> vk::Extent2D
+{
+	let render_scale = render_scale.max(0.25).min(1.0);
+	let scale = |size: u32, render: u32, window: u32| {
+		((size as u64 * render as u64 / window.max(1) as u64) as f32 * render_scale).max(1.0) as u32
+	};
+	return vk::Extent2D {
+		width: scale(window_size.0, cfg.render_width, cfg.window_width),
+		height: scale(window_size.1, cfg.render_height, cfg.window_height),
+	};
+}
+
+/// Saves the latest presented frame as an image on a background thread, like a capture tool would. The directory of
+/// the path is created if missing.
+fn save_captured_frame(reader: CaptureReader, path: String)
+{
+	thread::spawn(move || {
+		if let Some(parent) = Path::new(&path).parent()
+		{
+			if let Err(e) = fs::create_dir_all(parent)
+			{
+				warning!("Could not create the directory of {}: {}", path, e);
+				return;
+			}
+		}
+		let mut image = None;
+		reader.read_latest(0, |frame| image = Some((frame.frame, frame.to_image())));
+		match image.map(|(frame, image)| (frame, image.save(&path)))
+		{
+			Some((frame, Ok(()))) => println!("Saved presented frame {} to {}", frame, path),
+			Some((_, Err(e))) => warning!("Could not save the presented frame to {}: {}", path, e),
+			None => warning!("No frame has been presented yet"),
+		}
+	});
+}
+
+/// Queues the config to be saved on the autosave thread, so changing settings never waits for the disk.
+fn save_config(autosaver: &Autosaver, cfg: &Config, options_file: &str)
+{
+	match cfg.to_json()
+	{
+		Ok(contents) => autosaver.save(options_file, contents),
+		Err(e) => warning!("Could not save the config to {}: {}", options_file, e),
+	}
+}
+
 fn main()
 {
 	// init stuff
 	let options_file = "options.json";
-	let cfg = match Config::read_config(options_file)
+	let mut cfg = match Config::read_config(options_file)
 	{
 		Ok(cfg) => cfg,
 		Err(e) =>
@@ -78,43 +165,325 @@ fn main()
 		}
 	};
 
+	// Command line options override the config
+	let mut random_seed = cfg.random_seed;
+	let mut gallery_scene = None;
+	let mut benchmark_duration = benchmark::DEFAULT_DURATION;
+	let mut benchmark_report = benchmark::DEFAULT_REPORT_PATH.to_string();
+	let args: Vec<String> = std::env::args().collect();
+	for option in args[1..].windows(2)
+	{
+		if option[0] == "--seed"
+		{
+			match option[1].parse()
+			{
+				Ok(seed) => random_seed = seed,
+				Err(e) => warning!("Invalid seed ({}): {}", option[1], e),
+			}
+		}
+		else if option[0] == "--gallery"
+		{
+			gallery_scene = Some(option[1].clone());
+		}
+		else if option[0] == "--benchmark-duration"
+		{
+			match option[1].parse()
+			{
+				Ok(duration) => benchmark_duration = duration,
+				Err(e) => warning!("Invalid benchmark duration ({}): {}", option[1], e),
+			}
+		}
+		else if option[0] == "--benchmark-report"
+		{
+			benchmark_report = option[1].clone();
+		}
+	}
+	//   The benchmark renders as fast as it can, and leaves the saved cvars alone so every run starts the same
+	let mut benchmark = if args.iter().any(|arg| arg == "--benchmark")
+	{
+		cfg.vsync = false;
+		Some(Benchmark::new(benchmark_duration, &benchmark_report))
+	}
+	else
+	{
+		None
+	};
+	let save_cvars = benchmark.is_none();
+	let gpu_info_mode = args.iter().any(|arg| arg == "--gpu-info");
+	let selftest_mode = args.iter().any(|arg| arg == "--selftest");
+	println!("Random seed: {}", random_seed);
+	let random = RandomService::new(random_seed);
+
 	let sdl_context = sdl2::init().unwrap();
-	let video_subsystem = sdl_context.video().unwrap();
+	let video_subsystem = RenderState::init_video(&sdl_context);
 	let renderstate = RenderState::init(&cfg, &video_subsystem);
 	let mut event_pump = sdl_context.event_pump().unwrap();
-	let mut presentpass = PresentPass::init(&renderstate);
+	// Controllers are opened as they are connected, including the ones connected at startup
+	let controller_subsystem = match sdl_context.game_controller()
+	{
+		Ok(controller_subsystem) => Some(controller_subsystem),
+		Err(e) =>
+		{
+			warning!("Could not initialize game controllers: {}", e);
+			None
+		}
+	};
+	let mut controllers: Vec<GameController> = Vec::new();
+	let mut cursors = match Cursors::new(cfg.system_cursors)
+	{
+		Ok(cursors) => Some(cursors),
+		Err(e) =>
+		{
+			warning!("Could not create cursors: {}", e);
+			None
+		}
+	};
+	let mut presentpass = PresentPass::init(&renderstate, &renderstate.window, "main");
+	if gpu_info_mode
+	{
+		print!("{}", presentpass.get_gpu_report(&renderstate));
+		return;
+	}
+	if selftest_mode
+	{
+		let passed = selftest::run(&renderstate, &cfg);
+		drop(presentpass);
+		drop(renderstate);
+		std::process::exit(
+			if passed
+			{
+				0
+			}
+			else
+			{
+				1
+			},
+		);
+	}
 	let mut loading_image = renderstate.load_image("assets/original/textures/project_peril_logo.png", true);
-	presentpass.present_image(&renderstate, &mut loading_image);
-	let mut mainpass = MainPass::init(&renderstate, &cfg);
+	presentpass.present_image(&renderstate, &mut loading_image, None);
+	let mut mainpass = MainPass::init(&renderstate, &cfg, "main");
+	let mut shadowpass = ShadowPass::new(&renderstate);
+	let damage_baker = DamageBaker::new(&renderstate);
+	let mut reflection_probes = ReflectionProbes::new();
+	let mut bake_reflection_probes = false;
+	let mut irradiance_grid = IrradianceGrid::new(&renderstate);
+	let mut bake_irradiance_grid = false;
+	// Set by the reload_shaders command, and when a loaded shader changes on disk
+	let mut reload_shaders = false;
+	let mut debug_view = None;
+	if args.iter().any(|arg| arg == "--debug-view")
+	{
+		debug_view = open_debug_view(&renderstate, &cfg, &video_subsystem);
+	}
 	let mut input_handler = InputHandler::new();
+	input_handler.load_bindings(BINDINGS_FILE);
 	let engine_state = Rc::new(RefCell::new(EngineState::new()));
 	input_handler.register_actions(engine_state.clone(), ActionType::IMMEDIATE);
-	let mut scene = Scene::new(&renderstate, &mainpass, &cfg, &mut input_handler);
-	let aspect_ratio = cfg.render_width as f32 / cfg.render_height as f32;
-	let vertical_fov = Rad::from(Deg(cfg.horizontal_fov as f32 / aspect_ratio));
+	let game_state = Rc::new(RefCell::new(GameStateMachine::new(GameState::Menu)));
+	input_handler.register_actions(game_state.clone(), ActionType::IMMEDIATE);
+	let log_overlay = Rc::new(RefCell::new(LogOverlay::new()));
+	input_handler.register_actions(log_overlay.clone(), ActionType::IMMEDIATE);
+	let debug_hud = Rc::new(RefCell::new(DebugHud::new()));
+	input_handler.register_actions(debug_hud.clone(), ActionType::IMMEDIATE);
+	let screenshot_gallery = Rc::new(RefCell::new(ScreenshotGallery::new()));
+	input_handler.register_actions(screenshot_gallery.clone(), ActionType::IMMEDIATE);
+	let mut console = Console::new();
+	if save_cvars
+	{
+		console.load_values(CVARS_FILE);
+	}
+	let autosaver = Autosaver::new();
+	let mut time = Time::new(&mut console);
+	console.register_bool("pass_stats", false, "Show the estimated memory use and bandwidth of each render pass");
+	console.register_float(
+		"pass_stats_bandwidth",
+		20.0,
+		"GPU memory bandwidth in GB/s, passes needing more than half the frame time at it are flagged",
+	);
+	console.register_float("shadow_updates_per_frame", 1.0, "Most point light shadow tiles rendered each frame");
+	console.register_float("shadow_resolution_scale", 1.0, "Scales the shadow resolution of the point lights");
+	console.register_float("render_scale", 1.0, "Scales the render resolution, from 0.25 to 1");
+	console.register_bool("irradiance_debug", false, "Draw the irradiance grid probes, colored by their irradiance");
+	console.register_float("ui_scale", 0.0, "Size of the UI, 0 follows the DPI of the display showing the window");
+	// The UI is rescaled when the window moves to a display with a different DPI
+	let mut display_index = renderstate.window.display_index().unwrap_or(0);
+	let mut display_scale = get_display_scale(&video_subsystem, display_index);
+	let mut stereo = Stereo::new(&mut console);
+	let mut heatmaps = Heatmaps::new(&renderstate, &mut console, cfg.render_width, cfg.render_height);
+	let mut volumetric_fog = VolumetricFog::new(&renderstate, &mut console);
+	let mut auto_quality = AutoQuality::new(&mut console);
+	let mut redraw = RedrawScheduler::new(&mut console);
+	let mut audio =
+		AudioSystem::new(&sdl_context, random.create_rng(RandomStream::Audio, 0), &mut console, &cfg.volumes);
+	let mut scene =
+		Scene::new(&renderstate, &mainpass, &cfg, &random, &mut input_handler, &mut console, &mut audio, &damage_baker);
+	if let Some(name) = gallery_scene
+	{
+		scene.load_gallery_scene(&renderstate, &mainpass, &random, &mut console, Some(&name));
+	}
+	if benchmark.is_some()
+	{
+		scene.load_gallery_scene(&renderstate, &mainpass, &random, &mut console, Some(benchmark::SCENE));
+		for line in benchmark::CONSOLE_LINES
+		{
+			if let Some(command) = console.execute(line)
+			{
+				scene.execute_command(&command);
+			}
+		}
+		game_state.borrow_mut().send(GameEvent::FreeRoam);
+		if !renderstate.set_gpu_timing(true)
+		{
+			warning!("The GPU cannot time passes, the benchmark report has no pass timings");
+		}
+	}
+	input_handler.set_context(game_state.borrow().get_input_context(scene.is_in_vehicle()));
+	// Platform integrations are added as presence backends here
+	#[cfg(feature = "presence")]
+	let mut presence = PresenceReporter::new();
+	#[cfg(feature = "presence")]
+	presence.add_backend(Box::new(LogBackend));
+	let mut aspect_ratio = cfg.render_width as f32 / cfg.render_height as f32;
+	let mut vertical_fov = Rad::from(Deg(cfg.horizontal_fov as f32 / aspect_ratio));
 	let near = 1.0;
 	let far = 1000.0;
 	// Need to flip projection matrix due to the Vulkan NDC coordinates.
 	// See https://matthewwellings.com/blog/the-new-vulkan-coordinate-system/ for details.
-	let glu_projection_matrix = cgmath::perspective(vertical_fov, aspect_ratio, near, far);
 	let vulkan_ndc = Matrix4::new(1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.0, 1.0);
-	let projection_matrix = vulkan_ndc * glu_projection_matrix;
 
 	// main loop
 	let mut frames_per_second: u32 = 0;
+	let mut last_fps: u32 = 0;
+	let hud_style = TextStyle {
+		outline_width: 1.5,
+		shadow_color: [0.0, 0.0, 0.0, 0.6],
+		shadow_offset: [2.0, 2.0],
+		..Default::default()
+	};
 	let mut second_accumulator = Duration::new(0, 0);
 	let mut engine_accumulator = Duration::new(0, 0);
+	let mut autosave_accumulator = Duration::new(0, 0);
+	// Whether the config changed since it was last queued for saving
+	let mut config_dirty = false;
 	let mut last_timestamp = SystemTime::now();
+	let start_timestamp = last_timestamp;
 
-	while engine_state.borrow().running
+	// Where to write the frame graph of the current frame, if a capture was requested
+	let mut frame_graph_path: Option<String> = None;
+	let mut window_resized = false;
+
+	while game_state.borrow().is_running()
 	{
+		// CONSOLE
+		for command in console.poll()
+		{
+			// Any command may change what is shown
+			redraw.invalidate();
+			if command[0] == "gpu_info"
+			{
+				print!("{}", presentpass.get_gpu_report(&renderstate));
+			}
+			else if command[0] == "capture_save"
+			{
+				match (presentpass.get_capture_reader(), command.get(1))
+				{
+					(Some(reader), path) =>
+					{
+						save_captured_frame(reader, path.cloned().unwrap_or_else(new_screenshot_path));
+					}
+					(None, _) => println!("Frames are only kept with capture set in the config"),
+				}
+			}
+			else if command[0] == "frame_graph"
+			{
+				frame_graph_path = Some(command.get(1).cloned().unwrap_or(FRAME_GRAPH_PATH.to_string()));
+				renderstate.start_frame_capture();
+			}
+			else if command[0] == "lut"
+			{
+				let duration = command.get(2).map_or(Ok(0.0), |duration| duration.parse::<f32>());
+				match (command.get(1), duration)
+				{
+					(Some(name), Ok(duration)) =>
+					{
+						if let Err(e) = presentpass.color_grading.set_lut(&renderstate, name, duration)
+						{
+							warning!("Failed to load LUT: {}", e);
+						}
+					}
+					_ => println!("Usage: lut <name> [blend seconds]"),
+				}
+			}
+			else if command[0] == "bake_probes"
+			{
+				bake_reflection_probes = true;
+			}
+			else if command[0] == "bake_irradiance"
+			{
+				bake_irradiance_grid = true;
+			}
+			else if command[0] == "reload_shaders"
+			{
+				reload_shaders = true;
+			}
+			else if command[0] == "stereo"
+			{
+				match command.get(1).and_then(|name| StereoMode::from_name(name))
+				{
+					Some(mode) => stereo.set_mode(mode),
+					None => println!("Usage: stereo <off|sbs|sequential>"),
+				}
+			}
+			else if command[0] == "heatmap"
+			{
+				match command.get(1).and_then(|name| HeatmapMode::from_name(name))
+				{
+					Some(mode) => heatmaps.set_mode(mode),
+					None => println!("Usage: heatmap <off|luminance|overdraw|depth_complexity>"),
+				}
+			}
+			else if command[0] == "gallery"
+			{
+				scene.load_gallery_scene(
+					&renderstate,
+					&mainpass,
+					&random,
+					&mut console,
+					command.get(1).map(|name| name.as_str()),
+				);
+			}
+			else if command[0] == "repair"
+			{
+				scene.repair_car(&renderstate, &damage_baker);
+			}
+			else if command[0] == "invalidate"
+			{
+				// Already invalidated like any other command
+			}
+			else if command[0] == "debug_view"
+			{
+				debug_view = match debug_view
+				{
+					Some(_) => None,
+					None => open_debug_view(&renderstate, &cfg, &video_subsystem),
+				};
+			}
+			else if !time.execute_command(&command) && !scene.execute_command(&command)
+			{
+				println!("Unknown command: {}", command.join(" "));
+			}
+		}
+
 		let current_timestamp = SystemTime::now();
 		let frame_time = current_timestamp.duration_since(last_timestamp).unwrap();
 		last_timestamp = current_timestamp;
 		engine_accumulator += frame_time;
 		second_accumulator += frame_time;
+		autosave_accumulator += frame_time;
 
 		// ENGINE
+		let update_start = Instant::now();
 		//   Mouse movement ticks once per frame
 		input_handler.mouse_movement_tick(engine_state.borrow().cursor_captured);
 		//   Fixed engine timestep
@@ -123,53 +492,474 @@ fn main()
 			// Actions tick once per timestep.
 			input_handler.actions_tick();
 
-			// animation, physics engine, scene progression etc. goes here
-			scene.update();
+			// animation, physics engine, scene progression etc. goes here, unless the game state freezes the scene
+			let updates_scene = game_state.borrow().updates_scene();
+			time.update(&console, ENGINE_TIMESTEP.as_secs_f32(), updates_scene);
+			if updates_scene
+			{
+				scene.update(&mut console, &mut time);
+				// The movement keys drive the car or walk, depending on where the player is
+				if !scene.drain_vehicle_events().is_empty()
+				{
+					input_handler.set_context(game_state.borrow().get_input_context(scene.is_in_vehicle()));
+				}
+				if scene.is_race_finished()
+				{
+					game_state.borrow_mut().send(GameEvent::RaceFinished);
+				}
+			}
 
 			engine_accumulator -= ENGINE_TIMESTEP;
 		}
 
+		// AUDIO
+		//   Volumes changed in the options or the console are kept in the config
+		game_state.borrow_mut().apply_settings(&mut console);
+		if let Some(volumes) = audio.update_volumes(&console)
+		{
+			cfg.volumes = volumes;
+			config_dirty = true;
+		}
+		scene.update_audio(&mut audio);
+
+		// ASSETS
+		if scene.hot_reload(&renderstate, &mainpass, &random, &mut console)
+		{
+			redraw.invalidate();
+		}
+		scene.collect_garbage(&renderstate, &console);
+		renderstate.submit_texture_uploads();
+		for path in renderstate.poll_shader_changes()
+		{
+			println!("{} changed, reloading shaders", path);
+			reload_shaders = true;
+		}
+		if reload_shaders
+		{
+			reload_shaders = false;
+			mainpass.reload_shaders(&renderstate);
+			presentpass.reload_shaders(&renderstate);
+			if let Some(debug_view) = &mut debug_view
+			{
+				debug_view.mainpass.reload_shaders(&renderstate);
+				debug_view.presentpass.reload_shaders(&renderstate);
+			}
+			redraw.invalidate();
+		}
+
+		// RESIZE
+		//   The render images follow the window, so the image keeps filling it, and render_scale
+		let extent = get_render_extent(&cfg, renderstate.window.size(), console.get_float("render_scale"));
+		if window_resized || extent != mainpass.get_render_extent()
+		{
+			window_resized = false;
+			if extent != mainpass.get_render_extent()
+			{
+				mainpass.resize(&renderstate, extent.width, extent.height);
+				heatmaps.resize(&renderstate, extent.width, extent.height);
+				aspect_ratio = extent.width as f32 / extent.height as f32;
+				vertical_fov = Rad::from(Deg(cfg.horizontal_fov as f32 / aspect_ratio));
+			}
+			redraw.invalidate();
+		}
+
 		// RENDER
-		//   Update the view matrix uniform buffer
+		let update_time = update_start.elapsed();
+		let render_start = Instant::now();
+		//   The benchmark flies the camera along its path
+		if let Some(benchmark) = &benchmark
+		{
+			let (position, target) = benchmark.get_camera();
+			scene.place_camera(position, target);
+		}
+		//   The FOV can change due to camera effects
+		let fov = vertical_fov + Rad::from(scene.get_fov_offset());
+		scene.frame_selection(fov, aspect_ratio);
 		let view_matrix = scene.get_view_matrix();
-		let view_matrix_buf_size = size_of::<Matrix4<f32>>() as u64;
-		unsafe {
-			let mem_ptr = renderstate
-				.device
-				.map_memory(mainpass.view_matrix_ub_mem, 0, view_matrix_buf_size, vk::MemoryMapFlags::empty())
-				.expect("Failed to view matrix uniform memory");
-			let mut mem_align = Align::new(mem_ptr, align_of::<Matrix4<f32>>() as u64, view_matrix_buf_size);
-			mem_align.copy_from_slice(&[view_matrix]);
-			renderstate.device.unmap_memory(mainpass.view_matrix_ub_mem);
-		}
-
-		//   Do the main rendering
-		let main_cmd_buf = mainpass.begin_frame(&renderstate);
-		scene.draw(&renderstate.device, main_cmd_buf, mainpass.pipeline_layout, &view_matrix, &projection_matrix);
-		mainpass.end_frame(&renderstate);
-
-		//   Present the rendered image
-		presentpass.present_image(&renderstate, &mut mainpass.render_image);
-
-		//   Update and potentially print FPS
-		frames_per_second += 1;
+		let projection_matrix = vulkan_ndc * cgmath::perspective(fov, aspect_ratio, near, far);
+		let ui_scale = match console.get_float("ui_scale")
+		{
+			scale if scale > 0.0 => scale,
+			_ => display_scale,
+		};
+		//   Also maps the input of this frame
+		let viewport_mapper = ViewportMapper::new(
+			renderstate.window.size(),
+			presentpass.get_surface_extent(),
+			mainpass.get_render_extent(),
+			ui_scale,
+		);
+
+		//   Only when something changed, if rendering on demand. Game time advancing, held actions, LUT blends and
+		//   alternating stereo eyes change every frame.
+		let animating = (game_state.borrow().updates_scene() && time.get_time_scale() > 0.0) ||
+			input_handler.is_active() ||
+			log_overlay.borrow().is_animating() ||
+			debug_hud.borrow().is_animating() ||
+			screenshot_gallery.borrow().is_animating() ||
+			presentpass.color_grading.get_blend() < 1.0 ||
+			stereo.get_mode() == StereoMode::FrameSequential ||
+			benchmark.is_some();
+		let rendered = redraw.should_render(&console, animating);
+		if rendered
+		{
+			//   Bake the damage of this frame's collisions
+			scene.bake_damage(&renderstate, &damage_baker);
+
+			//   Render the point light shadows used by this frame
+			let mut lights = scene.get_lights();
+			let shadow_scale = console.get_float("shadow_resolution_scale").max(0.0).min(1.0);
+			for light in lights.iter_mut().filter(|light| light.shadow_resolution > 0)
+			{
+				light.shadow_resolution = ((light.shadow_resolution as f32 * shadow_scale) as u32).max(1);
+			}
+			let shadow_updates = console.get_float("shadow_updates_per_frame").max(0.0) as usize;
+			shadowpass.render(
+				&renderstate,
+				&lights,
+				&view_matrix,
+				fov,
+				shadow_updates,
+				|sp, cmd_buf, view_projection, light_matrix| {
+					scene.draw_shadow_casters(&renderstate.device, cmd_buf, sp, view_projection, light_matrix);
+				},
+			);
+
+			//   Directional lights have no shadows, and come after the point lights
+			let mut light_uniforms = shadowpass.get_light_uniforms(&lights);
+			light_uniforms.extend(scene.get_directional_lights().iter().map(LightUniforms::from_directional));
+
+			//   Light the volumetric fog, seen from the center of the eyes
+			volumetric_fog.set_shadow_atlas(&renderstate, Some(shadowpass.get_shadow_atlas()));
+			volumetric_fog.render(
+				&renderstate,
+				&console,
+				&view_matrix,
+				&projection_matrix,
+				scene.get_sun_direction(),
+				&light_uniforms,
+			);
+
+			//   Bake the reflection probes when loaded or changed, or on demand. Automatic bakes wait for the textures,
+			//   so the probes do not capture placeholders
+			let probes = scene.get_reflection_probes();
+			let textures_loaded = !renderstate.is_loading_textures();
+			if bake_reflection_probes || (textures_loaded && !reflection_probes.is_baked(&probes))
+			{
+				reflection_probes.bake(
+					&renderstate,
+					&probes,
+					&light_uniforms,
+					shadowpass.get_shadow_atlas(),
+					scene.get_wetness(),
+					|mp, cmd_buf, view, projection| {
+						scene.draw(&renderstate.device, cmd_buf, mp, view, projection);
+					},
+				);
+				bake_reflection_probes = false;
+			}
+			let probe_uniforms = reflection_probes.get_uniforms();
+
+			//   Bake the irradiance grid likewise
+			let irradiance_volume = scene.get_irradiance_volume();
+			if bake_irradiance_grid || (textures_loaded && !irradiance_grid.is_baked(irradiance_volume.as_ref()))
+			{
+				irradiance_grid.bake(
+					&renderstate,
+					irradiance_volume.as_ref(),
+					&light_uniforms,
+					shadowpass.get_shadow_atlas(),
+					scene.get_wetness(),
+					|mp, cmd_buf, view, projection| {
+						scene.draw(&renderstate.device, cmd_buf, mp, view, projection);
+					},
+				);
+				bake_irradiance_grid = false;
+			}
+
+			//   Update the frame uniform buffer
+			mainpass.set_fragment_counting(heatmaps.get_mode().needs_fragment_counts());
+			mainpass.update_frame_uniforms(&FrameUniforms::new(
+				view_matrix,
+				scene.get_wetness(),
+				time.get_time(TimeDomain::Scaled),
+				&light_uniforms,
+				&probe_uniforms,
+				irradiance_grid.get_uniforms(),
+			));
+			mainpass.set_shadow_atlas(&renderstate, Some(shadowpass.get_shadow_atlas()));
+			mainpass.set_reflection_probes(&renderstate, &reflection_probes.get_cube_maps());
+			mainpass.set_irradiance_probes(&renderstate, irradiance_grid.get_probe_buffer());
+
+			//   Do the main rendering
+			let main_cmd_buf = mainpass.begin_frame(&renderstate);
+			let eyes = stereo.get_eyes(&console, view_matrix, aspect_ratio, |aspect_ratio| {
+				vulkan_ndc * cgmath::perspective(fov, aspect_ratio, near, far)
+			});
+			if mainpass.has_depth_prepass()
+			{
+				for eye in &eyes
+				{
+					mainpass.set_draw_region(main_cmd_buf, eye.region);
+					scene.draw_depth(
+						&renderstate.device,
+						main_cmd_buf,
+						&mainpass,
+						&eye.view_matrix,
+						&eye.projection_matrix,
+					);
+				}
+				mainpass.finish_depth_prepass(main_cmd_buf);
+			}
+			for eye in &eyes
+			{
+				mainpass.set_draw_region(main_cmd_buf, eye.region);
+				scene.draw(&renderstate.device, main_cmd_buf, &mainpass, &eye.view_matrix, &eye.projection_matrix);
+				if console.get_bool("irradiance_debug")
+				{
+					irradiance_grid.draw_debug(main_cmd_buf, &mainpass, &eye.view_matrix, &eye.projection_matrix);
+				}
+			}
+			mainpass.end_frame(&renderstate);
+			heatmaps.render(&renderstate, &console, &mut mainpass.render_image, &mut mainpass.fragment_counts);
+			presentpass.set_debug_overlay(heatmaps.get_output());
+			presentpass.post_params.debug_overlay = console.get_float("heatmap_opacity").max(0.0).min(1.0);
+
+			//   Queue the HUD, drawn on top when presenting
+			presentpass.overlay.set_scale(ui_scale);
+			let fps_color = if last_fps + 5 >= ENGINE_TARGET_HZ as u32
+			{
+				[0.2, 0.9, 0.2, 1.0]
+			}
+			else
+			{
+				[0.9, 0.2, 0.2, 1.0]
+			};
+			let fps_icon_style = TextStyle {
+				color: fps_color,
+				..hud_style
+			};
+			presentpass.overlay.draw_icon(Icon::Circle, (8.0, 8.0), 14.0, &fps_icon_style);
+			presentpass.overlay.draw_text(&format!("{} FPS", last_fps), (28.0, 8.0), 14.0, &hud_style);
+			if time.get_time_scale() != 1.0
+			{
+				let time_scale_text = format!("Time x{:.2}", time.get_time_scale());
+				presentpass.overlay.draw_text(&time_scale_text, (120.0, 8.0), 14.0, &hud_style);
+			}
+			debug_hud.borrow_mut().draw(&mut presentpass.overlay, &scene);
+			auto_quality.draw(&mut presentpass.overlay, &hud_style);
+			heatmaps.draw_legend(&console, &mut presentpass.overlay, &hud_style);
+			if console.get_bool("pass_stats")
+			{
+				let report = renderstate
+					.get_pass_report(frame_time.as_secs_f32(), console.get_float("pass_stats_bandwidth").max(0.1));
+				presentpass.overlay.draw_debug_text(&report, (8.0, 120.0), PASS_STATS_COLOR);
+			}
+			presentpass.overlay.set_view_projection(projection_matrix * view_matrix);
+			scene.update_ui(&renderstate, &time, &mut presentpass.overlay);
+			game_state.borrow().draw(
+				&mut presentpass.overlay,
+				viewport_mapper.get_ui_size(),
+				&scene.get_race_standings(),
+				&console,
+			);
+			log_overlay.borrow_mut().draw(&mut presentpass.overlay, viewport_mapper.get_ui_size().1);
+			screenshot_gallery.borrow_mut().draw(&renderstate, &mut presentpass.overlay, viewport_mapper.get_ui_size());
+
+			//   Present the rendered image, letterboxed to keep its aspect ratio
+			presentpass.set_image_area(viewport_mapper.get_image_area());
+			if engine_state.borrow().cursor_captured
+			{
+				let center = viewport_mapper.get_image_center_ui();
+				let crosshair_position = (center.0 - 0.5 * CROSSHAIR_SIZE, center.1 - 0.5 * CROSSHAIR_SIZE);
+				let crosshair_style = TextStyle {
+					color: CROSSHAIR_COLOR,
+					..hud_style
+				};
+				presentpass.overlay.draw_icon(Icon::Crosshair, crosshair_position, CROSSHAIR_SIZE, &crosshair_style);
+			}
+			presentpass.post_params.time = current_timestamp.duration_since(start_timestamp).unwrap().as_secs_f32();
+			presentpass.post_params.droplets = scene.get_droplet_amount();
+			let (sun_position, sun_shafts_intensity, sun_shafts_decay) =
+				scene.get_sun_shafts(&(projection_matrix * view_matrix));
+			presentpass.post_params.sun_position = sun_position.into();
+			// The sun shafts are traced from a single sun position on the image
+			presentpass.post_params.sun_shafts_intensity = if stereo.get_mode() == StereoMode::SideBySide
+			{
+				0.0
+			}
+			else
+			{
+				sun_shafts_intensity
+			};
+			presentpass.post_params.sun_shafts_decay = sun_shafts_decay;
+			// Likewise for the lens flare axis
+			presentpass.post_params.lens_flare_intensity = if stereo.get_mode() == StereoMode::SideBySide
+			{
+				0.0
+			}
+			else
+			{
+				scene.get_lens_flare_intensity(&(projection_matrix * view_matrix))
+			};
+			presentpass.lens_flare.set_elements(&scene.get_lens_flare_elements());
+			// Likewise for the froxels of the fog
+			if stereo.get_mode() == StereoMode::SideBySide
+			{
+				presentpass.set_volumetric_fog(None);
+			}
+			else
+			{
+				presentpass.set_volumetric_fog(volumetric_fog.get_output());
+			}
+			presentpass.post_params.fog_distance = volumetric_fog.get_distance();
+			let depth_of_field = scene.get_depth_of_field();
+			presentpass.post_params.depth_near = near;
+			presentpass.post_params.depth_far = far;
+			presentpass.post_params.focus_distance = depth_of_field.get_focus_distance();
+			presentpass.post_params.dof_aperture = depth_of_field.get_aperture();
+			presentpass.post_params.dof_debug = depth_of_field.is_debug() as u32;
+			let post_effects = scene.get_post_effects();
+			presentpass.post_params.chromatic_aberration = post_effects.get_chromatic_aberration();
+			presentpass.post_params.film_grain = post_effects.get_film_grain();
+			presentpass.post_params.vignette = post_effects.get_vignette();
+			presentpass.color_grading.update(frame_time.as_secs_f32());
+			presentpass.present_image(&renderstate, &mut mainpass.render_image, Some(&mut mainpass.depth_image));
+
+			//   Render and present the debug view, looking down on the camera
+			if let Some(debug_view) = &mut debug_view
+			{
+				let debug_view_matrix = scene.get_top_down_view_matrix();
+				let half_width = DEBUG_VIEW_HALF_HEIGHT * debug_view.get_aspect_ratio();
+				let debug_projection_matrix = vulkan_ndc *
+					cgmath::ortho(
+						-half_width,
+						half_width,
+						-DEBUG_VIEW_HALF_HEIGHT,
+						DEBUG_VIEW_HALF_HEIGHT,
+						near,
+						far,
+					);
+				debug_view.mainpass.update_frame_uniforms(&FrameUniforms::new(
+					debug_view_matrix,
+					scene.get_wetness(),
+					time.get_time(TimeDomain::Scaled),
+					&light_uniforms,
+					&probe_uniforms,
+					irradiance_grid.get_uniforms(),
+				));
+				debug_view.mainpass.set_shadow_atlas(&renderstate, Some(shadowpass.get_shadow_atlas()));
+				debug_view.mainpass.set_reflection_probes(&renderstate, &reflection_probes.get_cube_maps());
+				debug_view.mainpass.set_irradiance_probes(&renderstate, irradiance_grid.get_probe_buffer());
+				let debug_cmd_buf = debug_view.mainpass.begin_frame(&renderstate);
+				if debug_view.mainpass.has_depth_prepass()
+				{
+					scene.draw_depth(
+						&renderstate.device,
+						debug_cmd_buf,
+						&debug_view.mainpass,
+						&debug_view_matrix,
+						&debug_projection_matrix,
+					);
+					debug_view.mainpass.finish_depth_prepass(debug_cmd_buf);
+				}
+				scene.draw(
+					&renderstate.device,
+					debug_cmd_buf,
+					&debug_view.mainpass,
+					&debug_view_matrix,
+					&debug_projection_matrix,
+				);
+				debug_view.mainpass.end_frame(&renderstate);
+				debug_view.presentpass.overlay.draw_text("Top-down view", (8.0, 8.0), 14.0, &hud_style);
+				debug_view.presentpass.present_image(&renderstate, &mut debug_view.mainpass.render_image, None);
+			}
+
+			//   Write the captured frame graph
+			if let (Some(dot), Some(path)) = (renderstate.finish_frame_capture(), frame_graph_path.take())
+			{
+				match std::fs::write(&path, dot)
+				{
+					Ok(()) => println!("Wrote frame graph to {}", path),
+					Err(e) => warning!("Failed to write frame graph to {}: {}", path, e),
+				}
+			}
+
+			frames_per_second += 1;
+			debug_hud.borrow_mut().record_frame(frame_time.as_secs_f32());
+
+			//   Hold the GPU frame time by scaling the effects, unless benchmarking them as they are
+			if benchmark.is_none()
+			{
+				auto_quality.update(&renderstate, &mut console);
+			}
+
+			//   Record the frame for the benchmark, which ends the game once it has run its course
+			if let Some(benchmark) = &mut benchmark
+			{
+				let ready = textures_loaded && game_state.borrow().get_state() == GameState::Playing;
+				let gpu_timings = renderstate.collect_gpu_timings();
+				benchmark.record_frame(ready, frame_time, update_time, render_start.elapsed(), gpu_timings);
+				if benchmark.is_finished()
+				{
+					let settings = BenchmarkSettings::new(&renderstate, &cfg, &mainpass, &presentpass, random_seed);
+					if let Err(e) = benchmark.write_report(cfg.version_to_string(), &settings)
+					{
+						warning!("Could not write the benchmark report: {}", e);
+					}
+					game_state.borrow_mut().send(GameEvent::Quit);
+				}
+			}
+		}
+
+		//   Update the FPS shown on the HUD, counting the rendered frames
 		if second_accumulator > Duration::from_secs(1)
 		{
-			let term_fps = format!("\r{} FPS", frames_per_second).into_bytes();
-			std::io::stdout().write(&term_fps).unwrap();
-			std::io::stdout().flush().unwrap();
+			last_fps = frames_per_second;
 			frames_per_second = 0;
 			second_accumulator = Duration::new(0, 0);
 		}
 
+		// PRESENCE
+		#[cfg(feature = "presence")]
+		presence.update(scene.get_presence());
+
+		// AUTOSAVE
+		//   Saved periodically, so tweaks survive crashes and GPU hangs
+		if autosave_accumulator >= AUTOSAVE_INTERVAL
+		{
+			if let Some(contents) = console.save_values().filter(|_| save_cvars)
+			{
+				autosaver.save(CVARS_FILE, contents);
+			}
+			if config_dirty
+			{
+				save_config(&autosaver, &cfg, options_file);
+				config_dirty = false;
+			}
+			autosave_accumulator = Duration::new(0, 0);
+		}
+
 		// INPUT
-		for event in event_pump.poll_iter()
+		//   Without a rendered frame to pace the loop, sleep until an event arrives or the next engine tick is due
+		let first_event = if rendered
+		{
+			None
+		}
+		else
+		{
+			let wait = ENGINE_TIMESTEP.checked_sub(engine_accumulator).unwrap_or_default();
+			event_pump.wait_event_timeout(wait.as_millis() as u32)
+		};
+		for event in first_event.into_iter().chain(event_pump.poll_iter())
 		{
+			// Any event may change what is shown
+			redraw.invalidate();
 			match event
 			{
 				Event::Quit {
 					..
-				} => engine_state.borrow_mut().running = false,
+				} => game_state.borrow_mut().send(GameEvent::Quit),
 				Event::KeyDown {
 					scancode,
 					..
@@ -180,25 +970,150 @@ fn main()
 				} => input_handler.update_key(scancode.unwrap(), KeyEventState::RELEASED),
 				Event::MouseButtonDown {
 					mouse_btn,
+					x,
+					y,
 					..
-				} => input_handler.update_mouse_button(mouse_btn, KeyEventState::PRESSED),
+				} =>
+				{
+					input_handler.update_mouse_button(mouse_btn, KeyEventState::PRESSED);
+					// The cursor position is only meaningful when it is not captured
+					if mouse_btn == MouseButton::Left && !engine_state.borrow().cursor_captured
+					{
+						scene.click(
+							viewport_mapper.window_to_ui((x, y)),
+							viewport_mapper.window_to_ndc((x, y)),
+							&(projection_matrix * view_matrix),
+						);
+					}
+				}
 				Event::MouseButtonUp {
 					mouse_btn,
 					..
 				} => input_handler.update_mouse_button(mouse_btn, KeyEventState::RELEASED),
+				Event::ControllerDeviceAdded {
+					which,
+					..
+				} =>
+				{
+					if let Some(controller_subsystem) = &controller_subsystem
+					{
+						match controller_subsystem.open(which)
+						{
+							Ok(controller) =>
+							{
+								println!("Controller connected: {}", controller.name());
+								controllers.push(controller);
+							}
+							Err(e) => warning!("Could not open controller {}: {}", which, e),
+						}
+					}
+				}
+				Event::ControllerDeviceRemoved {
+					which,
+					..
+				} =>
+				{
+					controllers.retain(|controller| controller.instance_id() != which);
+					println!("Controller disconnected");
+					input_handler.release_controllers();
+				}
+				Event::ControllerAxisMotion {
+					axis,
+					value,
+					..
+				} => input_handler.update_controller_axis(axis, value),
+				Event::ControllerButtonDown {
+					button,
+					..
+				} => input_handler.update_controller_button(button, KeyEventState::PRESSED),
+				Event::ControllerButtonUp {
+					button,
+					..
+				} => input_handler.update_controller_button(button, KeyEventState::RELEASED),
 				Event::MouseMotion {
+					mousestate,
+					x,
+					y,
 					xrel,
 					yrel,
 					..
-				} => input_handler.update_mouse_movement((xrel, yrel)),
+				} =>
+				{
+					input_handler.update_mouse_movement((xrel, yrel));
+					// Dragging uses the cursor position, so only when it is not captured
+					if (mousestate.left() || mousestate.middle()) && !engine_state.borrow().cursor_captured
+					{
+						scene.drag(
+							viewport_mapper.window_to_ui((x, y)),
+							viewport_mapper.window_to_ndc((x, y)),
+							&(projection_matrix * view_matrix),
+							mousestate.left(),
+							mousestate.middle(),
+							(xrel, yrel),
+						);
+					}
+				}
+				Event::MouseWheel {
+					y,
+					direction,
+					..
+				} =>
+				{
+					let steps = if direction == MouseWheelDirection::Flipped
+					{
+						-y
+					}
+					else
+					{
+						y
+					};
+					if !log_overlay.borrow_mut().scroll(steps)
+					{
+						scene.scroll(steps);
+					}
+				}
+				// The default audio device might have changed
+				Event::AudioDeviceAdded {
+					iscapture: false,
+					..
+				} |
+				Event::AudioDeviceRemoved {
+					iscapture: false,
+					..
+				} => audio.handle_device_change(),
+				// Events for the debug view. It handles resizing by itself when presenting.
+				Event::Window {
+					win_event,
+					window_id,
+					..
+				} if debug_view.as_ref().map_or(false, |debug_view| debug_view.get_window_id() == window_id) =>
+				{
+					if let WindowEvent::Close = win_event
+					{
+						debug_view = None;
+					}
+				}
 				Event::Window {
 					win_event,
 					..
 				} => match win_event
 				{
+					// Closing the main window only sends Quit if it is the last window
+					WindowEvent::Close => game_state.borrow_mut().send(GameEvent::Quit),
+					WindowEvent::SizeChanged(..) => window_resized = true,
+					WindowEvent::Moved(..) =>
+					{
+						let moved_to = renderstate.window.display_index().unwrap_or(display_index);
+						if moved_to != display_index
+						{
+							display_index = moved_to;
+							display_scale = get_display_scale(&video_subsystem, display_index);
+							println!("Moved to display {}, UI scale {}", display_index, display_scale);
+						}
+					}
 					WindowEvent::FocusGained =>
 					{
-						engine_state.borrow_mut().cursor_captured = true;
+						engine_state.borrow_mut().cursor_captured = game_state.borrow().captures_cursor();
 						engine_state.borrow_mut().cursor_state_dirty = true;
 					}
 					WindowEvent::FocusLost =>
@@ -214,6 +1129,22 @@ fn main()
 			}
 		}
 
+		// GAME STATE
+		//   The scene is set up once a frame has shown the loading screen
+		if game_state.borrow().get_state() == GameState::Loading && rendered
+		{
+			scene.restart(&renderstate, &damage_baker, game_state.borrow().is_race());
+			game_state.borrow_mut().send(GameEvent::Loaded);
+		}
+		//   Entered states set up their controls and cursor
+		if !game_state.borrow_mut().poll().is_empty()
+		{
+			redraw.invalidate();
+			input_handler.set_context(game_state.borrow().get_input_context(scene.is_in_vehicle()));
+			engine_state.borrow_mut().cursor_captured = game_state.borrow().captures_cursor();
+			engine_state.borrow_mut().cursor_state_dirty = true;
+		}
+
 		if engine_state.borrow().cursor_state_dirty
 		{
 			if engine_state.borrow().cursor_captured
@@ -226,9 +1157,32 @@ fn main()
 			}
 			engine_state.borrow_mut().cursor_state_dirty = false;
 		}
+		//   Pick the cursor for what it is over, it is hidden while captured
+		if let (Some(cursors), false) = (&mut cursors, engine_state.borrow().cursor_captured)
+		{
+			let mouse_state = event_pump.mouse_state();
+			let position = (mouse_state.x(), mouse_state.y());
+			if scene.is_over_ui(viewport_mapper.window_to_ui(position)) ||
+				viewport_mapper.window_to_render(position).is_none()
+			{
+				cursors.set(CursorKind::Arrow);
+			}
+			else
+			{
+				cursors.set(CursorKind::Crosshair);
+			}
+		}
 	}
 
 	// Cleanup
-	loading_image.destroy(&renderstate.device);
-	print!("\n");
+	if let Some(contents) = console.save_values().filter(|_| save_cvars)
+	{
+		autosaver.save(CVARS_FILE, contents);
+	}
+	if config_dirty
+	{
+		save_config(&autosaver, &cfg, options_file);
+	}
+	// Waits for the queued saves to be written
+	drop(autosaver);
 }
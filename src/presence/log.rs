@@ -0,0 +1,17 @@
+use crate::presence::{Presence, PresenceBackend};
+
+/// Prints the presence, for checking the reports without a platform SDK, and as an example backend.
+pub struct LogBackend;
+
+impl PresenceBackend for LogBackend
+{
+	fn update(&mut self, presence: &Presence)
+	{
+		println!("Presence: {}", presence.describe());
+	}
+
+	fn clear(&mut self)
+	{
+		println!("Presence cleared");
+	}
+}
@@ -0,0 +1,144 @@
+mod log;
+
+pub use self::log::LogBackend;
+
+use std::time::{Duration, Instant};
+
+// Platforms rate limit presence updates, so changes are reported at most this often
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// What the player is currently doing.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Activity
+{
+	Driving,
+	/// Out of the car, walking around.
+	OnFoot,
+	/// Racing the AI, with the player's lap and place among the racers.
+	Racing
+	{
+		lap: u32,
+		place: usize,
+		racers: usize,
+	},
+	Finished
+	{
+		place: usize,
+		racers: usize,
+	},
+	WatchingReplay,
+}
+
+/// The state reported to platform integrations. Only plain data, so that backends map it to their SDK's types and no
+/// SDK types reach the engine.
+#[derive(Clone, PartialEq)]
+pub struct Presence
+{
+	/// Name of the loaded scene, see Scene::load_gallery_scene().
+	pub scene: String,
+	pub activity: Activity,
+}
+
+impl Presence
+{
+	/// Returns a short description, like "Racing in spline, lap 2, 1st of 4".
+	pub fn describe(&self) -> String
+	{
+		let place = |place: usize, racers: usize| {
+			let suffix = match (place % 10, place % 100)
+			{
+				(_, 11..=13) => "th",
+				(1, _) => "st",
+				(2, _) => "nd",
+				(3, _) => "rd",
+				_ => "th",
+			};
+			format!("{}{} of {}", place, suffix, racers)
+		};
+		match self.activity
+		{
+			Activity::Driving => format!("Driving in {}", self.scene),
+			Activity::OnFoot => format!("Walking around {}", self.scene),
+			Activity::Racing {
+				lap,
+				place: p,
+				racers,
+			} =>
+			{
+				format!("Racing in {}, lap {}, {}", self.scene, lap, place(p, racers))
+			}
+			Activity::Finished {
+				place: p,
+				racers,
+			} => format!("Finished a race in {}, {}", self.scene, place(p, racers)),
+			Activity::WatchingReplay => format!("Watching a replay in {}", self.scene),
+		}
+	}
+}
+
+/// A platform integration, like a Steam or Discord rich presence, receiving the player's presence.
+pub trait PresenceBackend
+{
+	/// Reports a changed presence.
+	fn update(&mut self, presence: &Presence);
+
+	/// Clears the presence, when the game exits.
+	fn clear(&mut self);
+}
+
+/// Reports the presence to the registered backends when it changes, rate limited.
+pub struct PresenceReporter
+{
+	backends: Vec<Box<dyn PresenceBackend>>,
+	reported: Option<Presence>,
+	last_update: Option<Instant>,
+}
+
+impl PresenceReporter
+{
+	pub fn new() -> PresenceReporter
+	{
+		PresenceReporter {
+			backends: Vec::new(),
+			reported: None,
+			last_update: None,
+		}
+	}
+
+	pub fn add_backend(&mut self, backend: Box<dyn PresenceBackend>)
+	{
+		self.backends.push(backend);
+	}
+
+	/// Reports the presence if it changed, unless the last report was too recent. A rate limited change is reported
+	/// by a later call.
+	pub fn update(&mut self, presence: Presence)
+	{
+		if self.reported.as_ref() == Some(&presence)
+		{
+			return;
+		}
+		let now = Instant::now();
+		if self.last_update.map_or(false, |last_update| now.duration_since(last_update) < MIN_UPDATE_INTERVAL)
+		{
+			return;
+		}
+		for backend in &mut self.backends
+		{
+			backend.update(&presence);
+		}
+		self.reported = Some(presence);
+		self.last_update = Some(now);
+	}
+}
+
+impl Drop for PresenceReporter
+{
+	fn drop(&mut self)
+	{
+		for backend in &mut self.backends
+		{
+			backend.clear();
+		}
+	}
+}
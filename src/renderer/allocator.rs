@@ -0,0 +1,432 @@
+use ash::version::DeviceV1_0;
+use ash::{vk, Device};
+use std::ffi::c_void;
+use std::ptr;
+
+// Size of the blocks sub-allocated from, by whether they are device local
+const DEVICE_LOCAL_BLOCK_SIZE: vk::DeviceSize = 64 << 20;
+const HOST_VISIBLE_BLOCK_SIZE: vk::DeviceSize = 16 << 20;
+// Allocations larger than this part of a block get a block of their own
+const DEDICATED_FRACTION: vk::DeviceSize = 2;
+
+/// A range of a memory block, from MemoryAllocator::allocate(). Freed with MemoryAllocator::free().
+#[derive(Clone, Copy)]
+pub struct Allocation
+{
+	pub memory: vk::DeviceMemory,
+	pub offset: vk::DeviceSize,
+	pub size: vk::DeviceSize,
+	/// The start of the range in host memory, null unless the memory is host visible.
+	pub mapped: *mut c_void,
+}
+
+// A single vk::DeviceMemory, handed out in ranges
+struct Block
+{
+	memory: vk::DeviceMemory,
+	size: vk::DeviceSize,
+	// Host visible blocks are mapped for their lifetime
+	mapped: *mut c_void,
+	// Free ranges as offset and size, ordered by offset and never adjacent
+	free: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+}
+
+impl Block
+{
+	fn new(memory: vk::DeviceMemory, size: vk::DeviceSize, mapped: *mut c_void) -> Block
+	{
+		return Block {
+			memory: memory,
+			size: size,
+			mapped: mapped,
+			free: vec![(0, size)],
+		};
+	}
+
+	fn is_empty(&self) -> bool
+	{
+		return self.free.len() == 1 && self.free[0] == (0, self.size);
+	}
+
+	/// Takes the first free range fitting the size at the alignment, returning its offset.
+	fn take(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize>
+	{
+		for idx in 0..self.free.len()
+		{
+			let (start, free_size) = self.free[idx];
+			let offset = (start + alignment - 1) / alignment * alignment;
+			let end = start + free_size;
+			if offset + size > end
+			{
+				continue;
+			}
+			// The padding in front and the rest after stay free
+			let mut remaining = Vec::new();
+			if offset > start
+			{
+				remaining.push((start, offset - start));
+			}
+			if offset + size < end
+			{
+				remaining.push((offset + size, end - offset - size));
+			}
+			self.free.splice(idx..idx + 1, remaining);
+			return Some(offset);
+		}
+		return None;
+	}
+
+	/// Returns a range to the free list, merged with the free ranges next to it.
+	fn give_back(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize)
+	{
+		let idx = self.free.iter().position(|&(start, _)| start > offset).unwrap_or(self.free.len());
+		self.free.insert(idx, (offset, size));
+		if idx + 1 < self.free.len() && offset + size == self.free[idx + 1].0
+		{
+			self.free[idx].1 += self.free[idx + 1].1;
+			self.free.remove(idx + 1);
+		}
+		if idx > 0 && self.free[idx - 1].0 + self.free[idx - 1].1 == offset
+		{
+			self.free[idx - 1].1 += self.free[idx].1;
+			self.free.remove(idx);
+		}
+	}
+}
+
+// The blocks of one memory type, for either buffers or images. Keeping them apart satisfies bufferImageGranularity.
+struct Pool
+{
+	memory_type: u32,
+	images: bool,
+	host_visible: bool,
+	blocks: Vec<Block>,
+}
+
+impl Pool
+{
+	/// Takes a range from the first block with room for it, or from a block added with new_block(size) if none has.
+	/// Returns the index of the block and the offset in it.
+	fn take(
+		&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize, block_size: vk::DeviceSize,
+		new_block: impl FnOnce(vk::DeviceSize) -> Block,
+	) -> (usize, vk::DeviceSize)
+	{
+		for (idx, block) in self.blocks.iter_mut().enumerate()
+		{
+			if let Some(offset) = block.take(size, alignment)
+			{
+				return (idx, offset);
+			}
+		}
+		// Large allocations get a block of their exact size, released when freed
+		let mut block = new_block(
+			if size > block_size / DEDICATED_FRACTION
+			{
+				size
+			}
+			else
+			{
+				block_size
+			},
+		);
+		let offset = block.take(size, alignment).unwrap();
+		self.blocks.push(block);
+		return (self.blocks.len() - 1, offset);
+	}
+}
+
+/// Sub-allocates the memory of buffers and images from large blocks, one pool per memory type, so the number of
+/// driver allocations stays far below the device's limit. Freed ranges are reused by later allocations, and blocks
+/// are released once empty, except the last one of each pool.
+///
+/// Host visible blocks are mapped for their lifetime, so their ranges must not be mapped with vkMapMemory, see
+/// Allocation::mapped.
+pub struct MemoryAllocator
+{
+	memory_properties: vk::PhysicalDeviceMemoryProperties,
+	pools: Vec<Pool>,
+}
+
+impl MemoryAllocator
+{
+	pub fn new(memory_properties: vk::PhysicalDeviceMemoryProperties) -> MemoryAllocator
+	{
+		MemoryAllocator {
+			memory_properties: memory_properties,
+			pools: Vec::new(),
+		}
+	}
+
+	fn allocate_block(device: &Device, memory_type: u32, host_visible: bool, size: vk::DeviceSize) -> Block
+	{
+		let allocate_info = vk::MemoryAllocateInfo {
+			s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			allocation_size: size,
+			memory_type_index: memory_type,
+		};
+		let memory;
+		let mut mapped = ptr::null_mut();
+		unsafe {
+			memory = device.allocate_memory(&allocate_info, None).expect("Failed to allocate memory block");
+			if host_visible
+			{
+				mapped = device
+					.map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+					.expect("Failed to map memory block");
+			}
+		}
+		return Block::new(memory, size, mapped);
+	}
+
+	/// Allocates memory of the given type for a buffer, or an image with optimal tiling.
+	pub fn allocate(
+		&mut self, device: &Device, requirements: vk::MemoryRequirements, memory_type: u32, image: bool,
+	) -> Allocation
+	{
+		let property_flags = self.memory_properties.memory_types[memory_type as usize].property_flags;
+		let pool_idx = match self.pools.iter().position(|pool| pool.memory_type == memory_type && pool.images == image)
+		{
+			Some(idx) => idx,
+			None =>
+			{
+				self.pools.push(Pool {
+					memory_type: memory_type,
+					images: image,
+					host_visible: property_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE),
+					blocks: Vec::new(),
+				});
+				self.pools.len() - 1
+			}
+		};
+		let pool = &mut self.pools[pool_idx];
+		let block_size = if property_flags.contains(vk::MemoryPropertyFlags::DEVICE_LOCAL)
+		{
+			DEVICE_LOCAL_BLOCK_SIZE
+		}
+		else
+		{
+			HOST_VISIBLE_BLOCK_SIZE
+		};
+		let alignment = requirements.alignment.max(1);
+		let (memory_type, host_visible) = (pool.memory_type, pool.host_visible);
+		let (block_idx, offset) = pool.take(requirements.size, alignment, block_size, |size| {
+			MemoryAllocator::allocate_block(device, memory_type, host_visible, size)
+		});
+
+		let block = &pool.blocks[block_idx];
+		let mapped = if block.mapped.is_null()
+		{
+			ptr::null_mut()
+		}
+		else
+		{
+			unsafe { (block.mapped as *mut u8).add(offset as usize) as *mut c_void }
+		};
+		return Allocation {
+			memory: block.memory,
+			offset: offset,
+			size: requirements.size,
+			mapped: mapped,
+		};
+	}
+
+	/// Returns the allocation to its block. The GPU must be done with it.
+	pub fn free(&mut self, device: &Device, allocation: Allocation)
+	{
+		for pool in self.pools.iter_mut()
+		{
+			let block_idx = match pool.blocks.iter().position(|block| block.memory == allocation.memory)
+			{
+				Some(idx) => idx,
+				None => continue,
+			};
+			pool.blocks[block_idx].give_back(allocation.offset, allocation.size);
+			// An empty block is kept while it is the only one, so freeing and allocating again does not churn
+			if pool.blocks[block_idx].is_empty() && pool.blocks.len() > 1
+			{
+				let block = pool.blocks.remove(block_idx);
+				unsafe {
+					device.free_memory(block.memory, None);
+				}
+			}
+			return;
+		}
+		debug_assert!(false, "Freed memory that was not allocated");
+	}
+
+	/// Returns the number of blocks, and the bytes allocated from them and in total.
+	pub fn get_usage(&self) -> (usize, vk::DeviceSize, vk::DeviceSize)
+	{
+		let blocks = self.pools.iter().flat_map(|pool| pool.blocks.iter());
+		let (count, free, total) = blocks.fold((0, 0, 0), |(count, free, total), block| {
+			(count + 1, free + block.free.iter().map(|&(_, size)| size).sum::<vk::DeviceSize>(), total + block.size)
+		});
+		return (count, total - free, total);
+	}
+
+	/// Releases all blocks. Everything allocated from them must have been freed, and the device be idle.
+	pub fn destroy(&mut self, device: &Device)
+	{
+		for pool in self.pools.drain(..)
+		{
+			for block in pool.blocks
+			{
+				debug_assert!(block.is_empty(), "Memory block still in use");
+				unsafe {
+					device.free_memory(block.memory, None);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// Without device memory, only the free lists are used
+	fn new_block(size: vk::DeviceSize) -> Block
+	{
+		return Block::new(vk::DeviceMemory::null(), size, ptr::null_mut());
+	}
+
+	fn new_pool(block_sizes: &[vk::DeviceSize]) -> Pool
+	{
+		return Pool {
+			memory_type: 0,
+			images: false,
+			host_visible: false,
+			blocks: block_sizes.iter().map(|&size| new_block(size)).collect(),
+		};
+	}
+
+	#[test]
+	fn give_back_merges_with_neighbours()
+	{
+		let mut block = new_block(400);
+		let offsets: Vec<_> = (0..4).map(|_| block.take(100, 1).unwrap()).collect();
+		assert_eq!(offsets, [0, 100, 200, 300]);
+		assert!(block.free.is_empty());
+
+		// No free neighbours
+		block.give_back(100, 100);
+		assert_eq!(block.free, [(100, 100)]);
+		// Merged with the previous range
+		block.give_back(200, 100);
+		assert_eq!(block.free, [(100, 200)]);
+		// Merged with the next range
+		block.give_back(0, 100);
+		assert_eq!(block.free, [(0, 300)]);
+		// Merged with the previous range, and the block is whole again
+		block.give_back(300, 100);
+		assert_eq!(block.free, [(0, 400)]);
+		assert!(block.is_empty());
+	}
+
+	#[test]
+	fn give_back_merges_between_ranges()
+	{
+		let mut block = new_block(300);
+		for _ in 0..3
+		{
+			block.take(100, 1).unwrap();
+		}
+		block.give_back(0, 100);
+		block.give_back(200, 100);
+		assert_eq!(block.free, [(0, 100), (200, 100)]);
+		block.give_back(100, 100);
+		assert_eq!(block.free, [(0, 300)]);
+	}
+
+	#[test]
+	fn take_pads_to_alignment()
+	{
+		let mut block = new_block(1024);
+		assert_eq!(block.take(10, 1), Some(0));
+		// The padding in front stays free
+		assert_eq!(block.take(100, 256), Some(256));
+		assert_eq!(block.free, [(10, 246), (356, 668)]);
+		// Fits in the padding
+		assert_eq!(block.take(200, 16), Some(16));
+		assert_eq!(block.free, [(10, 6), (216, 40), (356, 668)]);
+		// Too large for the padding at the alignment
+		assert_eq!(block.take(40, 64), Some(384));
+		assert_eq!(block.free, [(10, 6), (216, 40), (356, 28), (424, 600)]);
+	}
+
+	#[test]
+	fn take_fills_block_exactly()
+	{
+		let mut block = new_block(256);
+		assert_eq!(block.take(128, 64), Some(0));
+		assert_eq!(block.take(128, 64), Some(128));
+		assert!(block.free.is_empty());
+		assert_eq!(block.take(1, 1), None);
+		block.give_back(0, 128);
+		block.give_back(128, 128);
+		assert!(block.is_empty());
+	}
+
+	#[test]
+	fn take_fails_when_padding_overflows()
+	{
+		let mut block = new_block(256);
+		assert_eq!(block.take(8, 1), Some(0));
+		// 248 bytes are free, but only 128 after aligning
+		assert_eq!(block.take(200, 128), None);
+		assert_eq!(block.free, [(8, 248)]);
+	}
+
+	#[test]
+	fn pool_adds_block_when_full()
+	{
+		let mut pool = new_pool(&[]);
+		let mut new_sizes = Vec::new();
+		for &(size, expected) in [(512, (0, 0)), (512, (0, 512)), (256, (1, 0)), (256, (1, 256))].iter()
+		{
+			// The third allocation finds the first block full
+			let taken = pool.take(size, 16, 1024, |size| {
+				new_sizes.push(size);
+				return new_block(size);
+			});
+			assert_eq!(taken, expected);
+		}
+		assert_eq!(new_sizes, [1024, 1024]);
+		assert_eq!(pool.blocks.len(), 2);
+	}
+
+	#[test]
+	fn pool_reuses_freed_ranges()
+	{
+		let mut pool = new_pool(&[1024, 1024]);
+		pool.blocks[0].take(1024, 1).unwrap();
+		pool.blocks[1].take(1024, 1).unwrap();
+		pool.blocks[0].give_back(256, 256);
+		let new_block = |_| -> Block { panic!("A freed range fits") };
+		assert_eq!(pool.take(256, 256, 1024, new_block), (0, 256));
+	}
+
+	#[test]
+	fn pool_gives_large_allocations_their_own_block()
+	{
+		let mut pool = new_pool(&[1024]);
+		let mut new_size = None;
+		let (block_idx, offset) = pool.take(600, 16, 1024, |size| {
+			new_size = Some(size);
+			return new_block(size);
+		});
+		assert_eq!((block_idx, offset), (0, 0));
+		assert_eq!(new_size, None);
+		// More than half a block, and no room left
+		let (block_idx, offset) = pool.take(513, 16, 1024, |size| {
+			new_size = Some(size);
+			return new_block(size);
+		});
+		assert_eq!((block_idx, offset), (1, 0));
+		assert_eq!(new_size, Some(513));
+		assert!(pool.blocks[1].free.is_empty());
+	}
+}
@@ -0,0 +1,194 @@
+use crate::core::Console;
+use crate::renderer::{Overlay, RenderState, TextStyle};
+
+// GPU frames averaged before deciding on a change
+const WINDOW_FRAMES: usize = 30;
+// Frames skipped after a change, until the new settings show in the timings
+const SETTLE_FRAMES: usize = 30;
+// Quality is lowered above the target frame time times this, and raised below it times RAISE_BELOW. The gap keeps a
+// change from being undone right away.
+const LOWER_ABOVE: f32 = 1.05;
+const RAISE_BELOW: f32 = 0.75;
+const INDICATOR_POSITION: (f32, f32) = (8.0, 104.0);
+const INDICATOR_SIZE: f32 = 14.0;
+
+// An effect setting the controller moves between levels
+struct Knob
+{
+	// Shown on the HUD
+	label: &'static str,
+	cvar: &'static str,
+	// Values of the cvar, from the lowest quality to the highest
+	levels: &'static [f32],
+	// Timed passes with this in their name are the cost of the effect
+	pass: &'static str,
+	level: usize,
+	// The player's value of the cvar, restored when the controller is disabled, and the highest level it allows
+	player_value: f32,
+	ceiling: usize,
+}
+
+/// Holds a target GPU frame time by lowering and raising the quality of individual effects: the render resolution,
+/// the point light shadow resolution and the volumetric fog resolution. Enabled with the auto_quality cvar.
+///
+/// The passes are timed on the GPU, and read back after each frame. When the frames take too long, the effect whose
+/// passes took the longest is lowered a level. When they are well within the target, the effect lowered last is raised
+/// again, up to the player's setting.
+pub struct AutoQuality
+{
+	enabled: bool,
+	knobs: Vec<Knob>,
+	// GPU time of each frame in the window, and of the passes of each knob over the window, in milliseconds
+	frame_times: Vec<f32>,
+	knob_times: Vec<f32>,
+	// Frames still to skip after the last change
+	settling: usize,
+	// Indices of the knobs lowered, the last lowered last
+	lowered: Vec<usize>,
+}
+
+impl AutoQuality
+{
+	pub fn new(console: &mut Console) -> AutoQuality
+	{
+		console.register_bool("auto_quality", false, "Lower and raise effect quality to hold auto_quality_target_ms");
+		console.register_float("auto_quality_target_ms", 16.0, "GPU frame time held by auto_quality, in milliseconds");
+		let knob = |label, cvar, levels, pass| Knob {
+			label: label,
+			cvar: cvar,
+			levels: levels,
+			pass: pass,
+			level: 0,
+			player_value: 0.0,
+			ceiling: 0,
+		};
+		let knobs = vec![
+			knob("Resolution", "render_scale", &[0.5, 0.6, 0.7, 0.85, 1.0][..], ": scene"),
+			knob("Shadows", "shadow_resolution_scale", &[0.25, 0.5, 1.0][..], "shadows"),
+			knob("Fog", "fog_quality", &[0.0, 1.0, 2.0][..], "volumetric fog"),
+		];
+		AutoQuality {
+			enabled: false,
+			knob_times: vec![0.0; knobs.len()],
+			knobs: knobs,
+			frame_times: Vec::with_capacity(WINDOW_FRAMES),
+			settling: 0,
+			lowered: Vec::new(),
+		}
+	}
+
+	fn set_level(&mut self, console: &mut Console, idx: usize, level: usize)
+	{
+		let knob = &mut self.knobs[idx];
+		knob.level = level;
+		console.set_value(knob.cvar, knob.levels[level]);
+		self.frame_times.clear();
+		self.knob_times.iter_mut().for_each(|time| *time = 0.0);
+		self.settling = SETTLE_FRAMES;
+	}
+
+	/// Starts or stops controlling the effects when the auto_quality cvar changes. While enabled, the effect cvars are
+	/// set by the controller, and the player's values are restored when it is disabled.
+	fn apply_enabled(&mut self, rs: &RenderState, console: &mut Console)
+	{
+		let enabled = console.get_bool("auto_quality");
+		if enabled == self.enabled
+		{
+			return;
+		}
+		if enabled && !rs.set_gpu_timing(true)
+		{
+			warning!("The GPU cannot time passes, auto_quality is disabled");
+			console.set_value("auto_quality", 0.0);
+			return;
+		}
+		self.enabled = enabled;
+		self.frame_times.clear();
+		self.knob_times.iter_mut().for_each(|time| *time = 0.0);
+		self.settling = 0;
+		self.lowered.clear();
+		for knob in self.knobs.iter_mut()
+		{
+			if enabled
+			{
+				knob.player_value = console.get_float(knob.cvar);
+				knob.ceiling = knob.levels.iter().rposition(|&value| value <= knob.player_value + 1e-3).unwrap_or(0);
+				knob.level = knob.ceiling;
+			}
+			else
+			{
+				console.set_value(knob.cvar, knob.player_value);
+			}
+		}
+		if !enabled
+		{
+			rs.set_gpu_timing(false);
+		}
+	}
+
+	/// Reads back the GPU timings of the frame, and changes the quality of an effect if the frame time has been off
+	/// the target. Call after each rendered frame is done on the GPU, except while benchmarking.
+	pub fn update(&mut self, rs: &RenderState, console: &mut Console)
+	{
+		self.apply_enabled(rs, console);
+		if !self.enabled
+		{
+			return;
+		}
+		let timings = rs.collect_gpu_timings();
+		if self.settling > 0
+		{
+			self.settling -= 1;
+			return;
+		}
+		self.frame_times.push(timings.iter().map(|(_, time)| time).sum());
+		for (knob, knob_time) in self.knobs.iter().zip(self.knob_times.iter_mut())
+		{
+			*knob_time +=
+				timings.iter().filter(|(name, _)| name.contains(knob.pass)).map(|(_, time)| time).sum::<f32>();
+		}
+		if self.frame_times.len() < WINDOW_FRAMES
+		{
+			return;
+		}
+
+		let average = self.frame_times.iter().sum::<f32>() / self.frame_times.len() as f32;
+		let target = console.get_float("auto_quality_target_ms").max(1.0);
+		if average > target * LOWER_ABOVE
+		{
+			// The costliest effect that can still be lowered
+			let costliest = (0..self.knobs.len())
+				.filter(|&idx| self.knobs[idx].level > 0)
+				.max_by(|&a, &b| self.knob_times[a].partial_cmp(&self.knob_times[b]).unwrap());
+			if let Some(idx) = costliest
+			{
+				self.set_level(console, idx, self.knobs[idx].level - 1);
+				self.lowered.push(idx);
+				return;
+			}
+		}
+		else if average < target * RAISE_BELOW
+		{
+			if let Some(idx) = self.lowered.pop()
+			{
+				let level = (self.knobs[idx].level + 1).min(self.knobs[idx].ceiling);
+				self.set_level(console, idx, level);
+				return;
+			}
+		}
+		self.frame_times.clear();
+		self.knob_times.iter_mut().for_each(|time| *time = 0.0);
+	}
+
+	/// Shows the current level of each effect on the HUD while enabled, as a fraction of the player's setting.
+	pub fn draw(&self, overlay: &mut Overlay, style: &TextStyle)
+	{
+		if !self.enabled
+		{
+			return;
+		}
+		let levels: Vec<String> =
+			self.knobs.iter().map(|knob| format!("{} {}/{}", knob.label, knob.level + 1, knob.ceiling + 1)).collect();
+		overlay.draw_text(&format!("Auto quality: {}", levels.join(", ")), INDICATOR_POSITION, INDICATOR_SIZE, style);
+	}
+}
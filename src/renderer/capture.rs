@@ -0,0 +1,314 @@
+use crate::renderer::{BufferHandle, RenderState};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use std::sync::{Arc, Mutex};
+use std::{ptr, slice};
+
+// Presented frames kept at once. A reader holds one while reading, the others keep taking new frames.
+const SLOT_COUNT: usize = 3;
+
+/// A presented frame, as passed to CaptureReader::read_latest().
+pub struct CapturedFrame<'a>
+{
+	/// Counts the frames presented with capture, starting at 1.
+	pub frame: u64,
+	pub width: u32,
+	pub height: u32,
+	/// The format of the swapchain, see Config::capture.
+	pub format: vk::Format,
+	/// Tightly packed rows of 4 byte pixels, top row first.
+	pub pixels: &'a [u8],
+}
+
+impl<'a> CapturedFrame<'a>
+{
+	/// Returns a copy of the frame with its pixels in RGBA order.
+	pub fn to_image(&self) -> image::RgbaImage
+	{
+		let mut pixels = self.pixels.to_vec();
+		if self.format == vk::Format::B8G8R8A8_UNORM || self.format == vk::Format::B8G8R8A8_SRGB
+		{
+			for pixel in pixels.chunks_exact_mut(4)
+			{
+				pixel.swap(0, 2);
+			}
+		}
+		return image::RgbaImage::from_raw(self.width, self.height, pixels).unwrap();
+	}
+}
+
+// One kept frame, shared between the render loop and the readers
+struct Slot
+{
+	// 0 while empty or being written
+	frame: u64,
+	width: u32,
+	height: u32,
+	format: vk::Format,
+	// Persistently mapped memory of the slot's buffer, null without one
+	pixels: *const u8,
+	size: usize,
+}
+
+// The mapped memory is only touched while holding the lock of the slot
+unsafe impl Send for Slot {}
+
+/// Reads the frames kept by a CaptureRing, from any thread.
+#[derive(Clone)]
+pub struct CaptureReader
+{
+	slots: Arc<Vec<Mutex<Slot>>>,
+}
+
+impl CaptureReader
+{
+	/// Passes the latest presented frame to read if it is newer than last_frame, and returns its frame number. The
+	/// render loop does not write the frame while it is read, so read should be quick to keep a frame for the next
+	/// read.
+	pub fn read_latest<F: FnOnce(&CapturedFrame)>(&self, last_frame: u64, read: F) -> Option<u64>
+	{
+		let (idx, frame) = self
+			.slots
+			.iter()
+			.enumerate()
+			.map(|(idx, slot)| (idx, slot.lock().unwrap().frame))
+			.max_by_key(|&(_, frame)| frame)?;
+		let slot = self.slots[idx].lock().unwrap();
+		// Overwritten since
+		if slot.frame != frame || frame <= last_frame || slot.pixels.is_null()
+		{
+			return None;
+		}
+		let pixels;
+		unsafe {
+			pixels = slice::from_raw_parts(slot.pixels, slot.size);
+		}
+		read(&CapturedFrame {
+			frame: slot.frame,
+			width: slot.width,
+			height: slot.height,
+			format: slot.format,
+			pixels: pixels,
+		});
+		return Some(frame);
+	}
+}
+
+/// Keeps a copy of each presented frame in a ring of host visible buffers, for capture and streaming tools reading
+/// them through a CaptureReader.
+///
+/// The copy is recorded with the present pass. A slot held by a reader is skipped rather than waited for, so readers
+/// never stall the render loop, they only miss frames.
+pub struct CaptureRing
+{
+	slots: Arc<Vec<Mutex<Slot>>>,
+	// Backing the mapped memory of each slot, reallocated when the size of the frames changes
+	buffers: Vec<Option<BufferHandle>>,
+	// Tried first for the next frame, the one written the longest ago
+	next_slot: usize,
+	// The slot the current frame is copied to, until finish_copy()
+	pending_slot: Option<usize>,
+	frame: u64,
+}
+
+impl CaptureRing
+{
+	pub fn new() -> CaptureRing
+	{
+		let slots = (0..SLOT_COUNT)
+			.map(|_| {
+				Mutex::new(Slot {
+					frame: 0,
+					width: 0,
+					height: 0,
+					format: vk::Format::UNDEFINED,
+					pixels: ptr::null(),
+					size: 0,
+				})
+			})
+			.collect();
+		CaptureRing {
+			slots: Arc::new(slots),
+			buffers: (0..SLOT_COUNT).map(|_| None).collect(),
+			next_slot: 0,
+			pending_slot: None,
+			frame: 0,
+		}
+	}
+
+	/// Returns true if frames of the given format can be kept.
+	pub fn supports_format(format: vk::Format) -> bool
+	{
+		return [
+			vk::Format::B8G8R8A8_UNORM,
+			vk::Format::B8G8R8A8_SRGB,
+			vk::Format::R8G8B8A8_UNORM,
+			vk::Format::R8G8B8A8_SRGB,
+		]
+		.contains(&format);
+	}
+
+	pub fn get_reader(&self) -> CaptureReader
+	{
+		return CaptureReader {
+			slots: Arc::clone(&self.slots),
+		};
+	}
+
+	/// Records copying the presentable image to a free slot, after its render pass has ended. The image must be in
+	/// the PRESENT_SRC_KHR layout, and is left in it. Skips the frame if readers hold all slots.
+	pub fn record_copy(
+		&mut self, rs: &RenderState, cmd_buf: vk::CommandBuffer, image: vk::Image, extent: vk::Extent2D,
+		format: vk::Format,
+	)
+	{
+		debug_assert!(self.pending_slot.is_none());
+		let idx = match (0..SLOT_COUNT)
+			.map(|offset| (self.next_slot + offset) % SLOT_COUNT)
+			.find(|&idx| self.slots[idx].try_lock().is_ok())
+		{
+			Some(idx) => idx,
+			None => return,
+		};
+		{
+			let mut slot = match self.slots[idx].try_lock()
+			{
+				Ok(slot) => slot,
+				// Taken by a reader in between
+				Err(_) => return,
+			};
+			// Readers skip it until finish_copy()
+			slot.frame = 0;
+			let size = extent.width as usize * extent.height as usize * 4;
+			if slot.size != size || self.buffers[idx].is_none()
+			{
+				let buffer = rs.create_buffer(
+					vk::BufferUsageFlags::TRANSFER_DST,
+					vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+					size as vk::DeviceSize,
+				);
+				slot.pixels = buffer.get_mapped() as *const u8;
+				slot.size = size;
+				self.buffers[idx] = Some(buffer);
+			}
+			slot.width = extent.width;
+			slot.height = extent.height;
+			slot.format = format;
+		}
+
+		let subresource_range = vk::ImageSubresourceRange {
+			aspect_mask: vk::ImageAspectFlags::COLOR,
+			base_mip_level: 0,
+			level_count: 1,
+			base_array_layer: 0,
+			layer_count: 1,
+		};
+		let to_transfer = vk::ImageMemoryBarrier {
+			s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+			src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+			dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+			old_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+			new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+			dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+			image: image,
+			subresource_range: subresource_range,
+			..Default::default()
+		};
+		let to_present = vk::ImageMemoryBarrier {
+			src_access_mask: vk::AccessFlags::TRANSFER_READ,
+			dst_access_mask: vk::AccessFlags::MEMORY_READ,
+			old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+			..to_transfer
+		};
+		let buffer = self.buffers[idx].as_ref().unwrap().get();
+		let to_host = vk::BufferMemoryBarrier {
+			s_type: vk::StructureType::BUFFER_MEMORY_BARRIER,
+			src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+			dst_access_mask: vk::AccessFlags::HOST_READ,
+			src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+			dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+			buffer: buffer,
+			offset: 0,
+			size: vk::WHOLE_SIZE,
+			..Default::default()
+		};
+		let copy_region = vk::BufferImageCopy {
+			buffer_offset: 0,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image_offset: vk::Offset3D {
+				x: 0,
+				y: 0,
+				z: 0,
+			},
+			image_extent: vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			},
+		};
+		unsafe {
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+				vk::PipelineStageFlags::TRANSFER,
+				vk::DependencyFlags::empty(),
+				&[],
+				&[],
+				&[to_transfer],
+			);
+			rs.device.cmd_copy_image_to_buffer(
+				cmd_buf,
+				image,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				buffer,
+				&[copy_region],
+			);
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				vk::PipelineStageFlags::TRANSFER,
+				vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::HOST,
+				vk::DependencyFlags::empty(),
+				&[],
+				&[to_host],
+				&[to_present],
+			);
+		}
+		self.pending_slot = Some(idx);
+		self.next_slot = (idx + 1) % SLOT_COUNT;
+	}
+
+	/// Hands the frame copied by record_copy() to the readers, once the copy has finished on the GPU.
+	pub fn finish_copy(&mut self)
+	{
+		if let Some(idx) = self.pending_slot.take()
+		{
+			self.frame += 1;
+			// Readers do not hold a slot being written for longer than it takes to check it
+			self.slots[idx].lock().unwrap().frame = self.frame;
+		}
+	}
+}
+
+impl Drop for CaptureRing
+{
+	/// Keeps readers that outlive the ring from reading its released buffers.
+	fn drop(&mut self)
+	{
+		for slot in self.slots.iter()
+		{
+			let mut slot = slot.lock().unwrap();
+			slot.frame = 0;
+			slot.pixels = ptr::null();
+			slot.size = 0;
+		}
+	}
+}
@@ -0,0 +1,230 @@
+use crate::renderer::{RenderState, Texture};
+use ash::vk;
+use image;
+use std::fs;
+use std::path::Path;
+
+const LUT_DIRECTORY: &str = "assets/original/luts";
+// Linear filtering between the corners of a 2x2x2 table reproduces the input exactly
+const IDENTITY_LUT_SIZE: usize = 2;
+
+/// A 3D color lookup table, size^3 RGBA texels with red varying fastest, then green, then blue.
+struct LutData
+{
+	size: usize,
+	texels: Vec<u8>,
+}
+
+impl LutData
+{
+	fn identity() -> LutData
+	{
+		let size = IDENTITY_LUT_SIZE;
+		let mut texels = Vec::with_capacity(4 * size * size * size);
+		for b in 0..size
+		{
+			for g in 0..size
+			{
+				for r in 0..size
+				{
+					let scale = |c: usize| (c * 255 / (size - 1)) as u8;
+					texels.extend_from_slice(&[scale(r), scale(g), scale(b), 255]);
+				}
+			}
+		}
+		return LutData {
+			size: size,
+			texels: texels,
+		};
+	}
+
+	/// Parses an Adobe/Resolve .cube file. Only 3D tables with the default [0, 1] domain are supported.
+	fn parse_cube(text: &str) -> Result<LutData, String>
+	{
+		let mut size = 0;
+		let mut texels = Vec::new();
+		for line in text.lines().map(|line| line.trim())
+		{
+			if line.is_empty() || line.starts_with('#')
+			{
+				continue;
+			}
+			let words: Vec<&str> = line.split_whitespace().collect();
+			match words[0]
+			{
+				"TITLE" => (),
+				"LUT_3D_SIZE" =>
+				{
+					size = words.get(1).and_then(|word| word.parse().ok()).ok_or("invalid LUT_3D_SIZE")?;
+					texels.reserve(4 * size * size * size);
+				}
+				"LUT_1D_SIZE" => return Err("1D tables are not supported".to_string()),
+				"DOMAIN_MIN" | "DOMAIN_MAX" =>
+				{
+					let default = if words[0] == "DOMAIN_MIN"
+					{
+						0.0
+					}
+					else
+					{
+						1.0
+					};
+					let values: Vec<f32> = words[1..].iter().filter_map(|word| word.parse().ok()).collect();
+					if values.len() != 3 || values.iter().any(|&value| value != default)
+					{
+						return Err(format!("unsupported {}", line));
+					}
+				}
+				_ =>
+				{
+					let values: Vec<f32> = words.iter().filter_map(|word| word.parse().ok()).collect();
+					if values.len() != 3
+					{
+						return Err(format!("invalid line: {}", line));
+					}
+					for value in values
+					{
+						texels.push((value.max(0.0).min(1.0) * 255.0).round() as u8);
+					}
+					texels.push(255);
+				}
+			}
+		}
+		if size < 2 || texels.len() != 4 * size * size * size
+		{
+			return Err(format!("expected {} entries, found {}", size * size * size, texels.len() / 4));
+		}
+		return Ok(LutData {
+			size: size,
+			texels: texels,
+		});
+	}
+
+	/// Converts a strip image, with one size x size slice per blue value laid out horizontally, red increasing to
+	/// the right and green downwards.
+	fn from_strip(strip: &image::RgbaImage) -> Result<LutData, String>
+	{
+		let size = strip.height() as usize;
+		if size < 2 || strip.width() as usize != size * size
+		{
+			return Err(format!(
+				"expected a {}x{} strip, found {}x{}",
+				size * size,
+				size,
+				strip.width(),
+				strip.height()
+			));
+		}
+		let mut texels = Vec::with_capacity(4 * size * size * size);
+		for b in 0..size
+		{
+			for g in 0..size
+			{
+				for r in 0..size
+				{
+					texels.extend_from_slice(&strip.get_pixel((b * size + r) as u32, g as u32).0);
+				}
+			}
+		}
+		return Ok(LutData {
+			size: size,
+			texels: texels,
+		});
+	}
+
+	/// Loads a .cube file, or a strip image in any format supported by the image crate.
+	fn load(path: &Path) -> Result<LutData, String>
+	{
+		if path.extension().map_or(false, |extension| extension == "cube")
+		{
+			let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+			return LutData::parse_cube(&text);
+		}
+		let strip = image::open(path).map_err(|e| e.to_string())?.to_rgba8();
+		return LutData::from_strip(&strip);
+	}
+}
+
+/// Color grading with 3D lookup tables, applied when presenting.
+///
+/// Switching tables blends from the previous one over a given time, for smooth transitions between e.g. day and
+/// night.
+pub struct ColorGrading
+{
+	current: Texture,
+	// Blended out during transitions
+	previous: Texture,
+	transition_duration: f32,
+	transition_time: f32,
+}
+
+impl ColorGrading
+{
+	pub fn new(rs: &RenderState) -> ColorGrading
+	{
+		ColorGrading {
+			current: ColorGrading::create_lut(rs, &LutData::identity()),
+			previous: ColorGrading::create_lut(rs, &LutData::identity()),
+			transition_duration: 0.0,
+			transition_time: 0.0,
+		}
+	}
+
+	fn create_lut(rs: &RenderState, lut: &LutData) -> Texture
+	{
+		return rs.create_3d_image_from_data(lut.size as u32, vk::Format::R8G8B8A8_UNORM, &lut.texels);
+	}
+
+	/// Switches to the named table in the LUT directory, a .cube file or a .png strip, blending over the given
+	/// number of seconds. "identity" disables grading.
+	///
+	/// A transition in progress is cut short, starting the new one from the table it was blending to.
+	pub fn set_lut(&mut self, rs: &RenderState, name: &str, duration: f32) -> Result<(), String>
+	{
+		let lut = if name == "identity"
+		{
+			LutData::identity()
+		}
+		else
+		{
+			let cube_path = Path::new(LUT_DIRECTORY).join(format!("{}.cube", name));
+			let path = if cube_path.exists()
+			{
+				cube_path
+			}
+			else
+			{
+				Path::new(LUT_DIRECTORY).join(format!("{}.png", name))
+			};
+			LutData::load(&path).map_err(|e| format!("{}: {}", path.display(), e))?
+		};
+		let new_lut = ColorGrading::create_lut(rs, &lut);
+
+		// The old table is released once the frames using it are done
+		self.previous = std::mem::replace(&mut self.current, new_lut);
+		self.transition_duration = duration.max(0.0);
+		self.transition_time = 0.0;
+		return Ok(());
+	}
+
+	pub fn update(&mut self, timestep: f32)
+	{
+		self.transition_time += timestep;
+	}
+
+	/// Returns the weight of the current table, blending from the previous one, in the range [0, 1].
+	pub fn get_blend(&self) -> f32
+	{
+		if self.transition_time >= self.transition_duration
+		{
+			return 1.0;
+		}
+		return self.transition_time / self.transition_duration;
+	}
+
+	/// Returns the previous and current tables.
+	pub fn get_luts(&self) -> (&Texture, &Texture)
+	{
+		return (&self.previous, &self.current);
+	}
+}
@@ -0,0 +1,534 @@
+use crate::core::{Mesh, VertexFormat};
+use crate::renderer::{track_create, track_destroy, ObjectKind, RenderState, Texture};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use ash::Device;
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Matrix4, Point3, Vector3};
+use std::ffi::CString;
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+const DAMAGE_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+const PASS_NAME: &str = "damage";
+
+/// A decal to bake into a damage texture, projected onto the surface along the z axis of its box. Must match the
+/// DecalBlock push constants in damage_bake.vert.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DamageDecal
+{
+	/// From mesh space to the decal box, which spans [-1, 1] along each axis.
+	pub matrix: Matrix4<f32>,
+	/// Color of the damage, with its opacity in a.
+	pub color: [f32; 4],
+	/// Varies the pattern of the scratches.
+	pub seed: f32,
+	_padding: [f32; 3],
+}
+
+impl DamageDecal
+{
+	/// Creates a decal of the given radius at a point on the surface, in mesh space, pressing into it along direction.
+	pub fn new(position: Point3<f32>, direction: Vector3<f32>, radius: f32, color: [f32; 4], seed: f32) -> DamageDecal
+	{
+		let z = direction.normalize();
+		let up = if z.y.abs() < 0.9
+		{
+			Vector3::unit_y()
+		}
+		else
+		{
+			Vector3::unit_x()
+		};
+		let x = up.cross(z).normalize();
+		// The transpose of the decal to mesh rotation
+		let rotation = Matrix3::from_cols(x, z.cross(x), z).transpose();
+		return DamageDecal {
+			matrix: Matrix4::from_scale(1.0 / radius) *
+				Matrix4::from(rotation) *
+				Matrix4::from_translation(-position.to_vec()),
+			color: color,
+			seed: seed,
+			_padding: [0.0; 3],
+		};
+	}
+}
+
+/// Damage painted onto an object, in the texture coordinates of its mesh. Sampled by the material of the object, see
+/// Material::create_damaged(), and painted by DamageBaker::bake().
+pub struct DamageTexture
+{
+	pub texture: Texture,
+	resolution: u32,
+	framebuffer: vk::Framebuffer,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl Drop for DamageTexture
+{
+	fn drop(&mut self)
+	{
+		unsafe {
+			// The framebuffer might still be in use, the texture is released when dropped
+			self.device.device_wait_idle().unwrap();
+			self.device.destroy_framebuffer(self.framebuffer, None);
+		}
+	}
+}
+
+/// Bakes decals into damage textures, for persistent marks on specific objects such as scratches on the car.
+///
+/// Each decal is rendered once, by unwrapping the mesh onto the texture and painting the texels whose surface falls
+/// in the decal box. Objects then only sample their damage texture, however many decals it holds. The meshes need
+/// texture coordinates that do not overlap.
+pub struct DamageBaker
+{
+	renderpass: vk::RenderPass,
+	pipeline_layout: vk::PipelineLayout,
+	// For meshes with full and packed vertices, indexed by VertexFormat
+	pipelines: [vk::Pipeline; 2],
+	commandbuffer: vk::CommandBuffer,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl DamageBaker
+{
+	/// Creates the renderpass, painting over the damage already in the texture. Leaves the texture ready to be
+	/// sampled.
+	fn create_renderpass(rs: &RenderState) -> vk::RenderPass
+	{
+		let renderpass_attachments = [vk::AttachmentDescription {
+			format: DAMAGE_FORMAT,
+			flags: vk::AttachmentDescriptionFlags::empty(),
+			samples: vk::SampleCountFlags::TYPE_1,
+			load_op: vk::AttachmentLoadOp::LOAD,
+			store_op: vk::AttachmentStoreOp::STORE,
+			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		}];
+		let color_attachment_ref = vk::AttachmentReference {
+			attachment: 0,
+			layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+		};
+		let subpass = vk::SubpassDescription {
+			color_attachment_count: 1,
+			p_color_attachments: &color_attachment_ref,
+			pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+			..Default::default()
+		};
+		// Wait for the previous frames to finish sampling the texture, and make the new damage visible to them
+		let dependencies = [
+			vk::SubpassDependency {
+				src_subpass: vk::SUBPASS_EXTERNAL,
+				dst_subpass: 0,
+				src_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+				dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+				src_access_mask: vk::AccessFlags::empty(),
+				dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+				dependency_flags: vk::DependencyFlags::empty(),
+			},
+			vk::SubpassDependency {
+				src_subpass: 0,
+				dst_subpass: vk::SUBPASS_EXTERNAL,
+				src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+				dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+				src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+				dst_access_mask: vk::AccessFlags::SHADER_READ,
+				dependency_flags: vk::DependencyFlags::empty(),
+			},
+		];
+		let renderpass_create_info = vk::RenderPassCreateInfo {
+			s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
+			attachment_count: renderpass_attachments.len() as u32,
+			p_attachments: renderpass_attachments.as_ptr(),
+			subpass_count: 1,
+			p_subpasses: &subpass,
+			dependency_count: dependencies.len() as u32,
+			p_dependencies: dependencies.as_ptr(),
+			..Default::default()
+		};
+		let renderpass;
+		unsafe {
+			renderpass = rs.device.create_render_pass(&renderpass_create_info, None).unwrap();
+		}
+		rs.register_pass(PASS_NAME, &renderpass_attachments);
+
+		renderpass
+	}
+
+	/// Creates the pipelines for meshes with full and packed vertices, reading the positions, normals and texture
+	/// coordinates.
+	fn create_pipelines(rs: &RenderState, renderpass: vk::RenderPass) -> (vk::PipelineLayout, Vec<vk::Pipeline>)
+	{
+		let decal_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+			size: size_of::<DamageDecal>() as u32,
+			offset: 0,
+		};
+		let layout_create_info = vk::PipelineLayoutCreateInfo {
+			s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &decal_push_constant,
+			..Default::default()
+		};
+		let pipeline_layout;
+		unsafe {
+			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
+		}
+
+		let vertex_shader_module = rs.load_shader("shaders/damage_bake_vert.spv", &[]);
+		let fragment_shader_module = rs.load_shader("shaders/damage_bake_frag.spv", &[]);
+
+		let shader_entry_name = CString::new("main").unwrap();
+		let shader_stage_create_infos = [
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: vertex_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::VERTEX,
+				..Default::default()
+			},
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: fragment_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::FRAGMENT,
+				..Default::default()
+			},
+		];
+
+		// Pipelines for packed vertices the device can't read are never bound
+		let vertex_formats = [VertexFormat::Full, rs.get_packed_vertex_format()];
+		let vertex_input_binding_descriptions: Vec<vk::VertexInputBindingDescription> = vertex_formats
+			.iter()
+			.map(|format| vk::VertexInputBindingDescription {
+				binding: 0,
+				stride: format.get_stride(),
+				input_rate: vk::VertexInputRate::VERTEX,
+			})
+			.collect();
+		// Position, normal and texture coordinates
+		let vertex_input_attribute_descriptions: Vec<Vec<vk::VertexInputAttributeDescription>> = vertex_formats
+			.iter()
+			.map(|format| {
+				let attributes = format.get_attribute_descriptions();
+				vec![attributes[0], attributes[1], attributes[4]]
+			})
+			.collect();
+		let vertex_input_state_infos: Vec<vk::PipelineVertexInputStateCreateInfo> = vertex_input_binding_descriptions
+			.iter()
+			.zip(vertex_input_attribute_descriptions.iter())
+			.map(|(binding, attributes)| vk::PipelineVertexInputStateCreateInfo {
+				s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
+				vertex_attribute_description_count: attributes.len() as u32,
+				p_vertex_attribute_descriptions: attributes.as_ptr(),
+				vertex_binding_description_count: 1,
+				p_vertex_binding_descriptions: binding,
+				..Default::default()
+			})
+			.collect();
+		let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_INPUT_ASSEMBLY_STATE_CREATE_INFO,
+			topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+			..Default::default()
+		};
+		// Viewport and scissor are dynamic, as each damage texture has its own resolution
+		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VIEWPORT_STATE_CREATE_INFO,
+			scissor_count: 1,
+			viewport_count: 1,
+			..Default::default()
+		};
+		// Unwrapped triangles face either way, depending on the texture coordinates
+		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
+			cull_mode: vk::CullModeFlags::NONE,
+			front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+			line_width: 1.0,
+			polygon_mode: vk::PolygonMode::FILL,
+			..Default::default()
+		};
+		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
+			rasterization_samples: vk::SampleCountFlags::TYPE_1,
+			..Default::default()
+		};
+		// Blended over the damage already baked, accumulating its coverage in alpha
+		let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
+			blend_enable: 1,
+			src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+			dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+			color_blend_op: vk::BlendOp::ADD,
+			src_alpha_blend_factor: vk::BlendFactor::ONE,
+			dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+			alpha_blend_op: vk::BlendOp::ADD,
+			color_write_mask: vk::ColorComponentFlags::all(),
+		}];
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
+			attachment_count: color_blend_attachment_states.len() as u32,
+			p_attachments: color_blend_attachment_states.as_ptr(),
+			..Default::default()
+		};
+		let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
+			dynamic_state_count: dynamic_state.len() as u32,
+			p_dynamic_states: dynamic_state.as_ptr(),
+			..Default::default()
+		};
+		let pipeline_infos: Vec<vk::GraphicsPipelineCreateInfo> = vertex_input_state_infos
+			.iter()
+			.map(|vertex_input_state_info| vk::GraphicsPipelineCreateInfo {
+				s_type: vk::StructureType::GRAPHICS_PIPELINE_CREATE_INFO,
+				stage_count: shader_stage_create_infos.len() as u32,
+				p_stages: shader_stage_create_infos.as_ptr(),
+				p_vertex_input_state: vertex_input_state_info,
+				p_input_assembly_state: &vertex_input_assembly_state_info,
+				p_viewport_state: &viewport_state_info,
+				p_rasterization_state: &rasterization_info,
+				p_multisample_state: &multisample_state_info,
+				p_color_blend_state: &color_blend_state,
+				p_dynamic_state: &dynamic_state_info,
+				layout: pipeline_layout,
+				render_pass: renderpass,
+				..Default::default()
+			})
+			.collect();
+		let graphics_pipelines;
+		unsafe {
+			graphics_pipelines = rs
+				.device
+				.create_graphics_pipelines(rs.pipeline_cache, &pipeline_infos, None)
+				.expect("Unable to create damage pipeline");
+
+			rs.device.destroy_shader_module(fragment_shader_module, None);
+			rs.device.destroy_shader_module(vertex_shader_module, None);
+		}
+		track_create(ObjectKind::Pipeline, graphics_pipelines[0], "damage");
+		track_create(ObjectKind::Pipeline, graphics_pipelines[1], "damage packed");
+
+		(pipeline_layout, graphics_pipelines)
+	}
+
+	pub fn new(rs: &RenderState) -> DamageBaker
+	{
+		let renderpass = DamageBaker::create_renderpass(rs);
+		let (pipeline_layout, pipelines) = DamageBaker::create_pipelines(rs, renderpass);
+
+		let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			command_buffer_count: 1,
+			command_pool: rs.commandpool,
+			level: vk::CommandBufferLevel::PRIMARY,
+		};
+		let commandbuffers;
+		unsafe {
+			commandbuffers = rs.device.allocate_command_buffers(&command_buffer_allocate_info).unwrap();
+		}
+
+		DamageBaker {
+			renderpass: renderpass,
+			pipeline_layout: pipeline_layout,
+			pipelines: [pipelines[0], pipelines[1]],
+			commandbuffer: commandbuffers[0],
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Creates an undamaged texture of resolution x resolution texels to bake decals into.
+	pub fn create_texture(&self, rs: &RenderState, resolution: u32) -> DamageTexture
+	{
+		let upload_buffer = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::TRANSFER_SRC,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&vec![0u8; (4 * resolution * resolution) as usize],
+			false,
+		);
+		let texture = rs.create_texture(
+			vk::Extent3D {
+				width: resolution,
+				height: resolution,
+				depth: 1,
+			},
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			DAMAGE_FORMAT,
+			vk::ImageAspectFlags::COLOR,
+			vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			Some(upload_buffer.get()),
+		);
+		let extent = vk::Extent2D {
+			width: resolution,
+			height: resolution,
+		};
+		rs.register_image(texture.image, "damage", extent, DAMAGE_FORMAT);
+
+		let framebuffer_create_info = vk::FramebufferCreateInfo {
+			s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+			render_pass: self.renderpass,
+			attachment_count: 1,
+			p_attachments: &texture.view,
+			width: resolution,
+			height: resolution,
+			layers: 1,
+			..Default::default()
+		};
+		let framebuffer;
+		unsafe {
+			framebuffer = rs.device.create_framebuffer(&framebuffer_create_info, None).unwrap();
+		}
+
+		DamageTexture {
+			texture: texture,
+			resolution: resolution,
+			framebuffer: framebuffer,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Bakes the decals into the damage texture of the mesh, on top of the damage already in it. With clear, the
+	/// texture is cleared of all damage first.
+	pub fn bake(&self, rs: &RenderState, damage: &DamageTexture, mesh: &Mesh, decals: &[DamageDecal], clear: bool)
+	{
+		if decals.is_empty() && !clear
+		{
+			return;
+		}
+
+		let cmd_buf = self.commandbuffer;
+		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+			flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+			..Default::default()
+		};
+		let area = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: 0,
+				y: 0,
+			},
+			extent: vk::Extent2D {
+				width: damage.resolution,
+				height: damage.resolution,
+			},
+		};
+		let viewport = vk::Viewport {
+			x: 0.0,
+			y: 0.0,
+			width: damage.resolution as f32,
+			height: damage.resolution as f32,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		};
+		let render_pass_begin_info = vk::RenderPassBeginInfo {
+			s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
+			p_next: ptr::null(),
+			render_pass: self.renderpass,
+			framebuffer: damage.framebuffer,
+			render_area: area,
+			clear_value_count: 0,
+			p_clear_values: ptr::null(),
+		};
+		unsafe {
+			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+			rs.trace_pass(cmd_buf, PASS_NAME, &[], &[damage.texture.image]);
+			rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::INLINE);
+			rs.device.cmd_set_viewport(cmd_buf, 0, &[viewport]);
+			rs.device.cmd_set_scissor(cmd_buf, 0, &[area]);
+			if clear
+			{
+				let clear_attachment = vk::ClearAttachment {
+					aspect_mask: vk::ImageAspectFlags::COLOR,
+					color_attachment: 0,
+					clear_value: vk::ClearValue {
+						color: vk::ClearColorValue {
+							float32: [0.0, 0.0, 0.0, 0.0],
+						},
+					},
+				};
+				let clear_rect = vk::ClearRect {
+					rect: area,
+					base_array_layer: 0,
+					layer_count: 1,
+				};
+				rs.device.cmd_clear_attachments(cmd_buf, &[clear_attachment], &[clear_rect]);
+			}
+			rs.device.cmd_bind_pipeline(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				self.pipelines[mesh.get_vertex_format() as usize],
+			);
+		}
+		mesh.bind_buffers(cmd_buf, 0);
+		for decal in decals
+		{
+			unsafe {
+				let decal_bytes =
+					slice::from_raw_parts(decal as *const DamageDecal as *const u8, size_of::<DamageDecal>());
+				rs.device.cmd_push_constants(
+					cmd_buf,
+					self.pipeline_layout,
+					vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+					0,
+					decal_bytes,
+				);
+				rs.device.cmd_draw_indexed(cmd_buf, mesh.get_num_indices(0), 1, 0, 0, 1);
+			}
+		}
+		unsafe {
+			rs.device.cmd_end_render_pass(cmd_buf);
+		}
+		rs.end_pass(cmd_buf);
+		unsafe {
+			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+		}
+
+		let submit_info = vk::SubmitInfo {
+			s_type: vk::StructureType::SUBMIT_INFO,
+			command_buffer_count: 1,
+			p_command_buffers: &cmd_buf,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.queue_submit(rs.graphics_queue, &[submit_info], vk::Fence::null()).expect("queue submit failed.");
+		}
+	}
+}
+
+impl Drop for DamageBaker
+{
+	fn drop(&mut self)
+	{
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+
+		for &pipeline in self.pipelines.iter()
+		{
+			track_destroy(ObjectKind::Pipeline, pipeline);
+		}
+		unsafe {
+			// Always wait for device idle
+			self.device.device_wait_idle().unwrap();
+
+			for &pipeline in self.pipelines.iter()
+			{
+				self.device.destroy_pipeline(pipeline, None);
+			}
+			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+			self.device.destroy_render_pass(self.renderpass, None);
+		}
+	}
+}
@@ -0,0 +1,53 @@
+use crate::core::Config;
+use crate::renderer::{get_display_scale, MainPass, PresentPass, RenderState};
+
+/// A second window showing the scene from a different view, to aid debugging without cluttering the main view.
+///
+/// It has its own main pass and swapchain, so it is rendered and resized independently of the main window.
+pub struct DebugView
+{
+	pub mainpass: MainPass,
+	pub presentpass: PresentPass,
+	// Declared last, since the window must outlive the presentpass' surface
+	window: sdl2::video::Window,
+}
+
+impl DebugView
+{
+	pub fn new(rs: &RenderState, cfg: &Config, video_subsystem: &sdl2::VideoSubsystem) -> Result<DebugView, String>
+	{
+		// Sized and scaled for the display of the main window, where it likely opens
+		let display_scale = get_display_scale(video_subsystem, rs.window.display_index().unwrap_or(0));
+		let window = video_subsystem
+			.window(
+				&format!("{} debug view", cfg.app_name),
+				(cfg.window_width as f32 * display_scale) as u32 / 2,
+				(cfg.window_height as f32 * display_scale) as u32 / 2,
+			)
+			.vulkan()
+			.resizable()
+			.build()
+			.map_err(|e| e.to_string())?;
+		let mut presentpass = PresentPass::init(rs, &window, "debug view");
+		presentpass.overlay.set_scale(display_scale);
+		let mainpass = MainPass::init(rs, cfg, "debug view");
+
+		return Ok(DebugView {
+			mainpass: mainpass,
+			presentpass: presentpass,
+			window: window,
+		});
+	}
+
+	pub fn get_window_id(&self) -> u32
+	{
+		return self.window.id();
+	}
+
+	/// Returns the aspect ratio of the window, which can differ from the main window.
+	pub fn get_aspect_ratio(&self) -> f32
+	{
+		let (width, height) = self.window.size();
+		return width as f32 / height.max(1) as f32;
+	}
+}
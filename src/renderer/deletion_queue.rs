@@ -0,0 +1,54 @@
+use std::any::Any;
+use std::collections::VecDeque;
+
+/// Frames a released resource is kept alive for, so command buffers still using it have completed.
+const DELETION_DELAY_FRAMES: u64 = 3;
+
+/// Holds on to GPU resources for a few frames after they were released, destroying them by dropping their last
+/// reference once the GPU is done with them.
+pub struct DeletionQueue
+{
+	frame: u64,
+	// Frame each resource was released in, oldest first
+	pending: VecDeque<(u64, Box<dyn Any>)>,
+}
+
+impl DeletionQueue
+{
+	pub fn new() -> DeletionQueue
+	{
+		DeletionQueue {
+			frame: 0,
+			pending: VecDeque::new(),
+		}
+	}
+
+	/// Queues the resource for destruction. It should hold the last reference to its GPU objects.
+	pub fn defer<T: Any>(&mut self, resource: T)
+	{
+		self.pending.push_back((self.frame, Box::new(resource)));
+	}
+
+	pub fn next_frame(&mut self)
+	{
+		self.frame += 1;
+	}
+
+	/// Returns the oldest resource if it was released long enough ago, for the caller to destroy by dropping it.
+	/// Dropping a resource may release more into the queue, so it must not be dropped while the queue is borrowed.
+	pub fn pop_expired(&mut self) -> Option<Box<dyn Any>>
+	{
+		match self.pending.front()
+		{
+			Some(&(frame, _)) if frame + DELETION_DELAY_FRAMES <= self.frame => (),
+			_ => return None,
+		}
+		return self.pending.pop_front().map(|(_, resource)| resource);
+	}
+
+	/// Returns the oldest resource regardless of when it was released, for when the device is idle.
+	pub fn pop(&mut self) -> Option<Box<dyn Any>>
+	{
+		return self.pending.pop_front().map(|(_, resource)| resource);
+	}
+}
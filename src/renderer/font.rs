@@ -0,0 +1,332 @@
+//! Built-in 5x7 pixel font, and generation of a signed distance field atlas from it.
+
+/// First character in the font.
+pub const FIRST_CHAR: u8 = b' ';
+/// Last character in the font.
+pub const LAST_CHAR: u8 = b'~';
+
+pub const GLYPH_WIDTH: usize = 5;
+pub const GLYPH_HEIGHT: usize = 7;
+/// Horizontal distance between glyphs, in font pixels.
+pub const GLYPH_ADVANCE: usize = 6;
+/// Vertical distance between lines, in font pixels.
+pub const LINE_HEIGHT: usize = 10;
+
+/// Atlas texels per font pixel.
+const GLYPH_SCALE: usize = 4;
+/// Distance in atlas texels covered by the SDF on each side of an edge. Also used as padding around glyphs.
+pub const SDF_SPREAD: usize = 6;
+pub const CELL_WIDTH: usize = GLYPH_WIDTH * GLYPH_SCALE + 2 * SDF_SPREAD;
+pub const CELL_HEIGHT: usize = GLYPH_HEIGHT * GLYPH_SCALE + 2 * SDF_SPREAD;
+/// Size of icons within their cell, in atlas texels.
+pub const ICON_SIZE: usize = CELL_WIDTH - 2 * SDF_SPREAD;
+pub const ATLAS_COLUMNS: usize = 16;
+
+/// Icons drawn from the same atlas as the glyphs.
+#[derive(Clone, Copy)]
+pub enum Icon
+{
+	Circle,
+	Square,
+	Triangle,
+	Cross,
+	/// A plus with a gap in the middle, for aiming.
+	Crosshair,
+}
+
+const ICONS: [Icon; 5] = [Icon::Circle, Icon::Square, Icon::Triangle, Icon::Cross, Icon::Crosshair];
+
+/// One row per line, top to bottom. Bit 4 is the leftmost pixel.
+const GLYPHS: [[u8; GLYPH_HEIGHT]; (LAST_CHAR - FIRST_CHAR + 1) as usize] = [
+	[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+	[0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04], // !
+	[0x0A, 0x0A, 0x0A, 0x00, 0x00, 0x00, 0x00], // "
+	[0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A], // #
+	[0x04, 0x0F, 0x14, 0x0E, 0x05, 0x1E, 0x04], // $
+	[0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // %
+	[0x0C, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0D], // &
+	[0x0C, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00], // '
+	[0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // (
+	[0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // )
+	[0x00, 0x04, 0x15, 0x0E, 0x15, 0x04, 0x00], // *
+	[0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00], // +
+	[0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08], // ,
+	[0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00], // -
+	[0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C], // .
+	[0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // /
+	[0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E], // 0
+	[0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E], // 1
+	[0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F], // 2
+	[0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E], // 3
+	[0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02], // 4
+	[0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E], // 5
+	[0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E], // 6
+	[0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // 7
+	[0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E], // 8
+	[0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C], // 9
+	[0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00], // :
+	[0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x04, 0x08], // ;
+	[0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // <
+	[0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00], // =
+	[0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // >
+	[0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // ?
+	[0x0E, 0x11, 0x01, 0x0D, 0x15, 0x15, 0x0E], // @
+	[0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11], // A
+	[0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E], // B
+	[0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E], // C
+	[0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C], // D
+	[0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F], // E
+	[0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10], // F
+	[0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F], // G
+	[0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11], // H
+	[0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E], // I
+	[0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C], // J
+	[0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // K
+	[0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F], // L
+	[0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11], // M
+	[0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // N
+	[0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E], // O
+	[0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10], // P
+	[0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D], // Q
+	[0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11], // R
+	[0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E], // S
+	[0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // T
+	[0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E], // U
+	[0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04], // V
+	[0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A], // W
+	[0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11], // X
+	[0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04], // Y
+	[0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F], // Z
+	[0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E], // [
+	[0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // \
+	[0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E], // ]
+	[0x04, 0x0A, 0x11, 0x00, 0x00, 0x00, 0x00], // ^
+	[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F], // _
+	[0x08, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00], // `
+	[0x00, 0x00, 0x0E, 0x01, 0x0F, 0x11, 0x0F], // a
+	[0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1E], // b
+	[0x00, 0x00, 0x0E, 0x10, 0x10, 0x11, 0x0E], // c
+	[0x01, 0x01, 0x0D, 0x13, 0x11, 0x11, 0x0F], // d
+	[0x00, 0x00, 0x0E, 0x11, 0x1F, 0x10, 0x0E], // e
+	[0x06, 0x09, 0x08, 0x1C, 0x08, 0x08, 0x08], // f
+	[0x00, 0x0F, 0x11, 0x11, 0x0F, 0x01, 0x0E], // g
+	[0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11], // h
+	[0x04, 0x00, 0x0C, 0x04, 0x04, 0x04, 0x0E], // i
+	[0x02, 0x00, 0x06, 0x02, 0x02, 0x12, 0x0C], // j
+	[0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12], // k
+	[0x0C, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E], // l
+	[0x00, 0x00, 0x1A, 0x15, 0x15, 0x11, 0x11], // m
+	[0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11], // n
+	[0x00, 0x00, 0x0E, 0x11, 0x11, 0x11, 0x0E], // o
+	[0x00, 0x00, 0x1E, 0x11, 0x1E, 0x10, 0x10], // p
+	[0x00, 0x00, 0x0D, 0x13, 0x0F, 0x01, 0x01], // q
+	[0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10], // r
+	[0x00, 0x00, 0x0E, 0x10, 0x0E, 0x01, 0x1E], // s
+	[0x08, 0x08, 0x1C, 0x08, 0x08, 0x09, 0x06], // t
+	[0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0D], // u
+	[0x00, 0x00, 0x11, 0x11, 0x11, 0x0A, 0x04], // v
+	[0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0A], // w
+	[0x00, 0x00, 0x11, 0x0A, 0x04, 0x0A, 0x11], // x
+	[0x00, 0x00, 0x11, 0x11, 0x0F, 0x01, 0x0E], // y
+	[0x00, 0x00, 0x1F, 0x02, 0x04, 0x08, 0x1F], // z
+	[0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02], // {
+	[0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // |
+	[0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08], // }
+	[0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00], // ~
+];
+
+/// A signed distance field atlas of all glyphs and icons, one byte per texel.
+///
+/// Texels are 0.5 on edges, increasing towards 1.0 inside shapes.
+pub struct FontAtlas
+{
+	pub width: usize,
+	pub height: usize,
+	pub data: Vec<u8>,
+}
+
+fn cell_count() -> usize
+{
+	GLYPHS.len() + ICONS.len()
+}
+
+/// Returns the top left corner of the given atlas cell, in texels.
+fn cell_origin(cell: usize) -> (usize, usize)
+{
+	((cell % ATLAS_COLUMNS) * CELL_WIDTH, (cell / ATLAS_COLUMNS) * CELL_HEIGHT)
+}
+
+/// Returns the atlas cell of the given character. Characters not in the font map to '?'.
+pub fn glyph_cell(c: char) -> usize
+{
+	let code = c as u32;
+	if code < FIRST_CHAR as u32 || code > LAST_CHAR as u32
+	{
+		return (b'?' - FIRST_CHAR) as usize;
+	}
+	return (code - FIRST_CHAR as u32) as usize;
+}
+
+fn icon_cell(icon: Icon) -> usize
+{
+	GLYPHS.len() + icon as usize
+}
+
+/// Distance from the point to the axis aligned box, zero if inside.
+fn distance_to_box(px: f32, py: f32, min_x: f32, min_y: f32, max_x: f32, max_y: f32) -> f32
+{
+	let dx = (min_x - px).max(px - max_x).max(0.0);
+	let dy = (min_y - py).max(py - max_y).max(0.0);
+	(dx * dx + dy * dy).sqrt()
+}
+
+/// Signed distance from a point in cell texels to the glyph, positive inside.
+fn glyph_distance(glyph: &[u8; GLYPH_HEIGHT], px: f32, py: f32) -> f32
+{
+	let pixel_size = GLYPH_SCALE as f32;
+	let origin = SDF_SPREAD as f32;
+	let is_set = |x: usize, y: usize| glyph[y] & (0x10 >> x) != 0;
+
+	let fx = ((px - origin) / pixel_size).floor();
+	let fy = ((py - origin) / pixel_size).floor();
+	let inside = fx >= 0.0 &&
+		fy >= 0.0 &&
+		(fx as usize) < GLYPH_WIDTH &&
+		(fy as usize) < GLYPH_HEIGHT &&
+		is_set(fx as usize, fy as usize);
+
+	// Distance to the nearest font pixel of the opposite state
+	let mut distance = std::f32::MAX;
+	if inside
+	{
+		// Everything outside the glyph is unset
+		let grid_max_x = origin + GLYPH_WIDTH as f32 * pixel_size;
+		let grid_max_y = origin + GLYPH_HEIGHT as f32 * pixel_size;
+		distance = (px - origin).min(grid_max_x - px).min(py - origin).min(grid_max_y - py);
+	}
+	for y in 0..GLYPH_HEIGHT
+	{
+		for x in 0..GLYPH_WIDTH
+		{
+			if is_set(x, y) != inside
+			{
+				let min_x = origin + x as f32 * pixel_size;
+				let min_y = origin + y as f32 * pixel_size;
+				let d = distance_to_box(px, py, min_x, min_y, min_x + pixel_size, min_y + pixel_size);
+				distance = distance.min(d);
+			}
+		}
+	}
+
+	if inside
+	{
+		distance
+	}
+	else
+	{
+		-distance
+	}
+}
+
+/// Signed distance from a point in cell texels to the icon, positive inside.
+fn icon_distance(icon: Icon, px: f32, py: f32) -> f32
+{
+	// Relative to the cell center, scaled so the icon spans [-1, 1]
+	let half_size = 0.5 * ICON_SIZE as f32;
+	let x = (px - 0.5 * CELL_WIDTH as f32) / half_size;
+	let y = (py - 0.5 * CELL_HEIGHT as f32) / half_size;
+	let box_distance = |x: f32, y: f32, half_width: f32, half_height: f32| {
+		let qx = x.abs() - half_width;
+		let qy = y.abs() - half_height;
+		let outside = (qx.max(0.0).powi(2) + qy.max(0.0).powi(2)).sqrt();
+		-(outside + qx.max(qy).min(0.0))
+	};
+
+	let distance = match icon
+	{
+		Icon::Circle => 1.0 - (x * x + y * y).sqrt(),
+		Icon::Square => box_distance(x, y, 1.0, 1.0),
+		Icon::Triangle =>
+		{
+			// Pointing right, intersection of three half planes
+			let slope_norm = (1.0f32 + 4.0).sqrt();
+			let top = (-2.0 * y - x + 1.0) / slope_norm;
+			let bottom = (2.0 * y - x + 1.0) / slope_norm;
+			let left = x + 1.0;
+			top.min(bottom).min(left)
+		}
+		Icon::Cross =>
+		{
+			// Two thin boxes rotated by +-45 degrees
+			let s = std::f32::consts::FRAC_1_SQRT_2;
+			let (u, v) = (s * (x + y), s * (x - y));
+			box_distance(u, v, 1.2, 0.25).max(box_distance(v, u, 1.2, 0.25))
+		}
+		Icon::Crosshair =>
+		{
+			let arms = box_distance(x, y, 1.0, 0.15).max(box_distance(y, x, 1.0, 0.15));
+			arms.min((x * x + y * y).sqrt() - 0.4)
+		}
+	};
+	distance * half_size
+}
+
+impl FontAtlas
+{
+	/// Renders the distance fields of all glyphs and icons into a new atlas.
+	pub fn generate() -> FontAtlas
+	{
+		let rows = (cell_count() + ATLAS_COLUMNS - 1) / ATLAS_COLUMNS;
+		let width = ATLAS_COLUMNS * CELL_WIDTH;
+		let height = rows * CELL_HEIGHT;
+		let mut data = vec![0u8; width * height];
+
+		for cell in 0..cell_count()
+		{
+			let (origin_x, origin_y) = cell_origin(cell);
+			for y in 0..CELL_HEIGHT
+			{
+				for x in 0..CELL_WIDTH
+				{
+					// Sample at texel centers
+					let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+					let distance = if cell < GLYPHS.len()
+					{
+						glyph_distance(&GLYPHS[cell], px, py)
+					}
+					else
+					{
+						icon_distance(ICONS[cell - GLYPHS.len()], px, py)
+					};
+					let value = (0.5 + 0.5 * distance / SDF_SPREAD as f32).max(0.0).min(1.0);
+					data[(origin_y + y) * width + origin_x + x] = (value * 255.0).round() as u8;
+				}
+			}
+		}
+
+		FontAtlas {
+			width: width,
+			height: height,
+			data: data,
+		}
+	}
+
+	/// Returns the texture coordinates (min, max) of the cell holding the given character.
+	pub fn get_glyph_uv(&self, c: char) -> ([f32; 2], [f32; 2])
+	{
+		self.get_cell_uv(glyph_cell(c))
+	}
+
+	/// Returns the texture coordinates (min, max) of the cell holding the given icon.
+	pub fn get_icon_uv(&self, icon: Icon) -> ([f32; 2], [f32; 2])
+	{
+		self.get_cell_uv(icon_cell(icon))
+	}
+
+	fn get_cell_uv(&self, cell: usize) -> ([f32; 2], [f32; 2])
+	{
+		let (x, y) = cell_origin(cell);
+		let (width, height) = (self.width as f32, self.height as f32);
+		([x as f32 / width, y as f32 / height], [(x + CELL_WIDTH) as f32 / width, (y + CELL_HEIGHT) as f32 / height])
+	}
+}
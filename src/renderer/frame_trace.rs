@@ -0,0 +1,144 @@
+use ash::vk;
+use ash::vk::Handle;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+struct TracedPass
+{
+	name: String,
+	reads: Vec<vk::Image>,
+	writes: Vec<vk::Image>,
+	barriers: Vec<String>,
+}
+
+/// Records the passes of a frame, the images they read and write and the barriers inserted between them, and exports
+/// them as a Graphviz dot graph. Only records when a capture has been requested, so it costs nothing otherwise.
+pub struct FrameTrace
+{
+	recording: bool,
+	passes: Vec<TracedPass>,
+	image_names: HashMap<u64, String>,
+}
+
+impl FrameTrace
+{
+	pub fn new() -> FrameTrace
+	{
+		FrameTrace {
+			recording: false,
+			passes: Vec::new(),
+			image_names: HashMap::new(),
+		}
+	}
+
+	/// Starts recording, until finish() is called at the end of the frame.
+	pub fn start(&mut self)
+	{
+		self.recording = true;
+		self.passes.clear();
+	}
+
+	/// Names an image in the exported graph. Unnamed images are shown by their handle.
+	pub fn set_image_name(&mut self, image: vk::Image, name: &str)
+	{
+		self.image_names.insert(image.as_raw(), name.to_string());
+	}
+
+	pub fn begin_pass(&mut self, name: &str, reads: &[vk::Image], writes: &[vk::Image])
+	{
+		if !self.recording
+		{
+			return;
+		}
+		self.passes.push(TracedPass {
+			name: name.to_string(),
+			reads: reads.to_vec(),
+			writes: writes.to_vec(),
+			barriers: Vec::new(),
+		});
+	}
+
+	/// Records a barrier. It is attributed to the most recently begun pass.
+	pub fn record_barrier(
+		&mut self, image: vk::Image, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout,
+		src_stage: vk::PipelineStageFlags, dst_stage: vk::PipelineStageFlags,
+	)
+	{
+		if !self.recording
+		{
+			return;
+		}
+		let description = format!(
+			"barrier {}: {:?} -> {:?}, {:?} -> {:?}",
+			self.get_image_name(image),
+			old_layout,
+			new_layout,
+			src_stage,
+			dst_stage
+		);
+		if self.passes.is_empty()
+		{
+			self.begin_pass("(before first pass)", &[], &[]);
+		}
+		self.passes.last_mut().unwrap().barriers.push(description);
+	}
+
+	fn get_image_name(&self, image: vk::Image) -> String
+	{
+		return match self.image_names.get(&image.as_raw())
+		{
+			Some(name) => name.clone(),
+			None => format!("image 0x{:x}", image.as_raw()),
+		};
+	}
+
+	/// Stops recording and returns the recorded frame as a dot graph, or None if nothing was being recorded.
+	pub fn finish(&mut self) -> Option<String>
+	{
+		if !self.recording
+		{
+			return None;
+		}
+		self.recording = false;
+
+		let mut dot = String::new();
+		// Writing to a String cannot fail
+		writeln!(dot, "digraph frame {{").unwrap();
+		writeln!(dot, "\trankdir=LR;").unwrap();
+		writeln!(dot, "\tnode [fontname=\"monospace\"];").unwrap();
+
+		let mut images = Vec::new();
+		for (pass_idx, pass) in self.passes.iter().enumerate()
+		{
+			let mut label = format!("{}: {}\\l", pass_idx, pass.name);
+			for barrier in &pass.barriers
+			{
+				write!(label, "{}\\l", barrier).unwrap();
+			}
+			writeln!(dot, "\tpass{} [shape=box, label=\"{}\"];", pass_idx, label).unwrap();
+			if pass_idx > 0
+			{
+				writeln!(dot, "\tpass{} -> pass{} [style=dashed, color=gray];", pass_idx - 1, pass_idx).unwrap();
+			}
+			for &image in &pass.reads
+			{
+				writeln!(dot, "\timage{:x} -> pass{} [label=\"read\"];", image.as_raw(), pass_idx).unwrap();
+				images.push(image);
+			}
+			for &image in &pass.writes
+			{
+				writeln!(dot, "\tpass{} -> image{:x} [label=\"write\", color=red];", pass_idx, image.as_raw()).unwrap();
+				images.push(image);
+			}
+		}
+		images.sort_by_key(|image| image.as_raw());
+		images.dedup();
+		for image in images
+		{
+			writeln!(dot, "\timage{:x} [shape=ellipse, label=\"{}\"];", image.as_raw(), self.get_image_name(image))
+				.unwrap();
+		}
+		writeln!(dot, "}}").unwrap();
+		return Some(dot);
+	}
+}
@@ -0,0 +1,102 @@
+use crate::renderer::RenderState;
+use ash::extensions::khr::Surface;
+use ash::version::InstanceV1_0;
+use ash::vk;
+use std::ffi::CStr;
+use std::fmt::Write;
+
+fn version_to_string(version: u32) -> String
+{
+	format!("{}.{}.{}", vk::version_major(version), vk::version_minor(version), vk::version_patch(version))
+}
+
+/// Generates a report of the capabilities of all GPUs, for triaging bug reports.
+/// Surface support is reported for the given surface, which is the one the game presents to.
+pub fn generate_report(rs: &RenderState, surface_loader: &Surface, surface: vk::SurfaceKHR) -> String
+{
+	let mut report = String::new();
+	// Writing to a String cannot fail
+	macro_rules! line {
+		($($arg:tt)*) => { writeln!(report, $($arg)*).unwrap() };
+	}
+
+	let pdevices = unsafe { rs.instance.enumerate_physical_devices() }.unwrap_or(Vec::new());
+	line!("GPU report");
+	line!("Physical devices: {}", pdevices.len());
+	for (device_idx, &pdevice) in pdevices.iter().enumerate()
+	{
+		let properties = unsafe { rs.instance.get_physical_device_properties(pdevice) };
+		let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+		let in_use = if pdevice == rs.pdevice
+		{
+			" (in use)"
+		}
+		else
+		{
+			""
+		};
+		line!("[{}] {} {:?}{}", device_idx, device_name.to_string_lossy(), properties.device_type, in_use);
+		line!(
+			"  Vulkan {}, driver version 0x{:08x}, vendor id 0x{:04x}, device id 0x{:04x}",
+			version_to_string(properties.api_version),
+			properties.driver_version,
+			properties.vendor_id,
+			properties.device_id
+		);
+
+		line!("  Queue families:");
+		let queue_families = unsafe { rs.instance.get_physical_device_queue_family_properties(pdevice) };
+		for (family_idx, family) in queue_families.iter().enumerate()
+		{
+			let present =
+				unsafe { surface_loader.get_physical_device_surface_support(pdevice, family_idx as u32, surface) }
+					.unwrap_or(false);
+			line!(
+				"    [{}] {:?}, {} queues, present support: {}",
+				family_idx,
+				family.queue_flags,
+				family.queue_count,
+				present
+			);
+		}
+
+		let memory = unsafe { rs.instance.get_physical_device_memory_properties(pdevice) };
+		line!("  Memory heaps:");
+		for (heap_idx, heap) in memory.memory_heaps[..memory.memory_heap_count as usize].iter().enumerate()
+		{
+			line!("    [{}] {} MiB {:?}", heap_idx, heap.size / (1024 * 1024), heap.flags);
+		}
+		line!("  Memory types:");
+		for (type_idx, memory_type) in memory.memory_types[..memory.memory_type_count as usize].iter().enumerate()
+		{
+			line!("    [{}] heap {}, {:?}", type_idx, memory_type.heap_index, memory_type.property_flags);
+		}
+
+		let surface_formats =
+			unsafe { surface_loader.get_physical_device_surface_formats(pdevice, surface) }.unwrap_or(Vec::new());
+		line!("  Surface formats:");
+		for format in surface_formats
+		{
+			line!("    {:?} {:?}", format.format, format.color_space);
+		}
+		let present_modes =
+			unsafe { surface_loader.get_physical_device_surface_present_modes(pdevice, surface) }.unwrap_or(Vec::new());
+		line!("  Present modes: {:?}", present_modes);
+
+		let limits = &properties.limits;
+		line!("  Limits:");
+		line!("    Max 2D texture size: {}", limits.max_image_dimension2_d);
+		line!("    Max cube texture size: {}", limits.max_image_dimension_cube);
+		line!("    Max push constant size: {}", limits.max_push_constants_size);
+		line!("    Max uniform buffer range: {}", limits.max_uniform_buffer_range);
+		line!("    Max bound descriptor sets: {}", limits.max_bound_descriptor_sets);
+		line!("    Max color attachments: {}", limits.max_color_attachments);
+		line!("    Max framebuffer size: {}x{}", limits.max_framebuffer_width, limits.max_framebuffer_height);
+		line!("    Max sampler anisotropy: {}", limits.max_sampler_anisotropy);
+		line!("    Min uniform buffer offset alignment: {}", limits.min_uniform_buffer_offset_alignment);
+		line!("    Max memory allocations: {}", limits.max_memory_allocation_count);
+	}
+	let (blocks, used, total) = rs.get_memory_usage();
+	line!("Memory in use: {} MiB of {} MiB in {} blocks", used / (1024 * 1024), total / (1024 * 1024), blocks);
+	return report;
+}
@@ -0,0 +1,172 @@
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+use ash::{Device, Instance};
+
+// Two timestamps per pass, passes beyond this in a frame are not timed
+const MAX_QUERIES: u32 = 128;
+
+/// Measures how long each pass of a frame takes on the GPU, with a timestamp written when a pass starts and one when
+/// it ends. Only times passes while enabled, so it costs nothing otherwise.
+///
+/// The passes are timed from begin_pass() until collect(), which must come after the frame is done on the GPU.
+pub struct GpuTimer
+{
+	// None if the queue does not support timestamps
+	query_pool: Option<vk::QueryPool>,
+	// Nanoseconds per timestamp tick
+	timestamp_period: f32,
+	timestamp_mask: u64,
+	enabled: bool,
+	// Passes timed this frame, each with the queries from 2 * index
+	passes: Vec<String>,
+	// Whether the last pass has ended
+	ended: bool,
+}
+
+impl GpuTimer
+{
+	pub fn new(instance: &Instance, pdevice: vk::PhysicalDevice, device: &Device, queue_family_index: u32) -> GpuTimer
+	{
+		let (valid_bits, timestamp_period) = unsafe {
+			let queue_families = instance.get_physical_device_queue_family_properties(pdevice);
+			let properties = instance.get_physical_device_properties(pdevice);
+			(queue_families[queue_family_index as usize].timestamp_valid_bits, properties.limits.timestamp_period)
+		};
+		let query_pool = if valid_bits > 0
+		{
+			let query_pool_info = vk::QueryPoolCreateInfo {
+				s_type: vk::StructureType::QUERY_POOL_CREATE_INFO,
+				query_type: vk::QueryType::TIMESTAMP,
+				query_count: MAX_QUERIES,
+				..Default::default()
+			};
+			unsafe { device.create_query_pool(&query_pool_info, None) }.ok()
+		}
+		else
+		{
+			None
+		};
+
+		GpuTimer {
+			query_pool: query_pool,
+			timestamp_period: timestamp_period,
+			timestamp_mask: if valid_bits >= 64
+			{
+				std::u64::MAX
+			}
+			else
+			{
+				(1 << valid_bits) - 1
+			},
+			enabled: false,
+			passes: Vec::new(),
+			ended: true,
+		}
+	}
+
+	/// Returns false if the GPU cannot time passes.
+	pub fn is_supported(&self) -> bool
+	{
+		return self.query_pool.is_some();
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool)
+	{
+		self.enabled = enabled;
+		self.passes.clear();
+		self.ended = true;
+	}
+
+	/// Writes the starting timestamp of a pass, ending the previous one if it has not been ended. Call outside of a
+	/// render pass.
+	pub fn begin_pass(&mut self, device: &Device, cmd_buf: vk::CommandBuffer, name: &str)
+	{
+		let query_pool = match self.query_pool
+		{
+			Some(query_pool) if self.enabled => query_pool,
+			_ => return,
+		};
+		if !self.ended
+		{
+			self.end_pass(device, cmd_buf);
+		}
+		let first_query = 2 * self.passes.len() as u32;
+		if first_query + 2 > MAX_QUERIES
+		{
+			return;
+		}
+		unsafe {
+			device.cmd_reset_query_pool(cmd_buf, query_pool, first_query, 2);
+			device.cmd_write_timestamp(cmd_buf, vk::PipelineStageFlags::TOP_OF_PIPE, query_pool, first_query);
+		}
+		self.passes.push(name.to_string());
+		self.ended = false;
+	}
+
+	/// Writes the ending timestamp of the last pass begun.
+	pub fn end_pass(&mut self, device: &Device, cmd_buf: vk::CommandBuffer)
+	{
+		let query_pool = match self.query_pool
+		{
+			Some(query_pool) if self.enabled && !self.ended => query_pool,
+			_ => return,
+		};
+		let last_query = 2 * self.passes.len() as u32 - 1;
+		unsafe {
+			device.cmd_write_timestamp(cmd_buf, vk::PipelineStageFlags::BOTTOM_OF_PIPE, query_pool, last_query);
+		}
+		self.ended = true;
+	}
+
+	/// Returns the GPU time of each pass timed since the last call, in milliseconds. Passes that ran more than once,
+	/// like the faces of a probe bake, are added up. Waits for the GPU if the passes are not done yet.
+	pub fn collect(&mut self, device: &Device) -> Vec<(String, f32)>
+	{
+		let query_pool = match self.query_pool
+		{
+			Some(query_pool) if self.enabled && self.ended && !self.passes.is_empty() => query_pool,
+			_ => return Vec::new(),
+		};
+		let mut timestamps = vec![0u64; 2 * self.passes.len()];
+		let result = unsafe {
+			device.get_query_pool_results(
+				query_pool,
+				0,
+				timestamps.len() as u32,
+				&mut timestamps,
+				vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+			)
+		};
+		let passes = std::mem::take(&mut self.passes);
+		if let Err(e) = result
+		{
+			warning!("Could not read the pass timestamps: {}", e);
+			return Vec::new();
+		}
+
+		let mut timings: Vec<(String, f32)> = Vec::new();
+		for (idx, name) in passes.into_iter().enumerate()
+		{
+			let ticks = (timestamps[2 * idx + 1] & self.timestamp_mask)
+				.wrapping_sub(timestamps[2 * idx] & self.timestamp_mask) &
+				self.timestamp_mask;
+			let milliseconds = ticks as f32 * self.timestamp_period / 1_000_000.0;
+			match timings.iter_mut().find(|(timed, _)| *timed == name)
+			{
+				Some((_, time)) => *time += milliseconds,
+				None => timings.push((name, milliseconds)),
+			}
+		}
+		return timings;
+	}
+
+	pub fn destroy(&mut self, device: &Device)
+	{
+		if let Some(query_pool) = self.query_pool.take()
+		{
+			unsafe {
+				device.destroy_query_pool(query_pool, None);
+			}
+		}
+	}
+}
@@ -0,0 +1,195 @@
+use crate::renderer::{track_destroy, Allocation, DeletionQueue, MemoryAllocator, ObjectKind};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use ash::Device;
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::rc::Rc;
+
+/// Vulkan objects owned by a handle, along with their memory.
+enum Objects
+{
+	Buffer(vk::Buffer, Allocation),
+	Image(vk::Image, Allocation, vk::ImageView, vk::Sampler),
+	Pipeline(vk::Pipeline),
+}
+
+/// Objects of a dropped handle, waiting in the deletion queue. Destroyed when dropped from the queue.
+struct Released
+{
+	objects: Objects,
+	device: Rc<Device>,
+	allocator: Rc<RefCell<MemoryAllocator>>,
+}
+
+impl Drop for Released
+{
+	fn drop(&mut self)
+	{
+		unsafe {
+			match self.objects
+			{
+				Objects::Buffer(buffer, allocation) =>
+				{
+					track_destroy(ObjectKind::Buffer, buffer);
+					self.device.destroy_buffer(buffer, None);
+					self.allocator.borrow_mut().free(&self.device, allocation);
+				}
+				Objects::Image(image, allocation, view, sampler) =>
+				{
+					track_destroy(ObjectKind::Sampler, sampler);
+					track_destroy(ObjectKind::ImageView, view);
+					track_destroy(ObjectKind::Image, image);
+					self.device.destroy_sampler(sampler, None);
+					self.device.destroy_image_view(view, None);
+					self.device.destroy_image(image, None);
+					self.allocator.borrow_mut().free(&self.device, allocation);
+				}
+				Objects::Pipeline(pipeline) =>
+				{
+					track_destroy(ObjectKind::Pipeline, pipeline);
+					self.device.destroy_pipeline(pipeline, None);
+				}
+			}
+		}
+	}
+}
+
+/// Releases the objects of dropped handles through the deletion queue of the RenderState that created them, so they
+/// outlive the command buffers using them.
+#[derive(Clone)]
+pub struct Releaser
+{
+	device: Rc<Device>,
+	queue: Rc<RefCell<DeletionQueue>>,
+	allocator: Rc<RefCell<MemoryAllocator>>,
+}
+
+impl Releaser
+{
+	pub fn new(
+		device: &Rc<Device>, queue: &Rc<RefCell<DeletionQueue>>, allocator: &Rc<RefCell<MemoryAllocator>>,
+	) -> Releaser
+	{
+		Releaser {
+			device: Rc::clone(device),
+			queue: Rc::clone(queue),
+			allocator: Rc::clone(allocator),
+		}
+	}
+
+	fn release(&self, objects: Objects)
+	{
+		self.queue.borrow_mut().defer(Released {
+			objects: objects,
+			device: Rc::clone(&self.device),
+			allocator: Rc::clone(&self.allocator),
+		});
+	}
+}
+
+/// A buffer and its memory, released when dropped. See RenderState::create_buffer().
+pub struct BufferHandle
+{
+	buffer: vk::Buffer,
+	allocation: Allocation,
+	releaser: Releaser,
+}
+
+impl BufferHandle
+{
+	pub fn new(releaser: &Releaser, buffer: vk::Buffer, allocation: Allocation) -> BufferHandle
+	{
+		BufferHandle {
+			buffer: buffer,
+			allocation: allocation,
+			releaser: releaser.clone(),
+		}
+	}
+
+	pub fn get(&self) -> vk::Buffer
+	{
+		return self.buffer;
+	}
+
+	/// Returns the start of the buffer in host memory, mapped for the lifetime of the buffer. The buffer must be host
+	/// visible. Its memory is shared with other buffers, so it must not be mapped with vkMapMemory.
+	pub fn get_mapped(&self) -> *mut c_void
+	{
+		debug_assert!(!self.allocation.mapped.is_null(), "The buffer is not host visible");
+		return self.allocation.mapped;
+	}
+}
+
+impl Drop for BufferHandle
+{
+	fn drop(&mut self)
+	{
+		self.releaser.release(Objects::Buffer(self.buffer, self.allocation));
+	}
+}
+
+/// An image with its memory, view and sampler, released when dropped. Owned by a Texture.
+pub struct ImageHandle
+{
+	image: vk::Image,
+	allocation: Allocation,
+	view: vk::ImageView,
+	sampler: vk::Sampler,
+	releaser: Releaser,
+}
+
+impl ImageHandle
+{
+	pub fn new(
+		releaser: &Releaser, image: vk::Image, allocation: Allocation, view: vk::ImageView, sampler: vk::Sampler,
+	) -> ImageHandle
+	{
+		ImageHandle {
+			image: image,
+			allocation: allocation,
+			view: view,
+			sampler: sampler,
+			releaser: releaser.clone(),
+		}
+	}
+}
+
+impl Drop for ImageHandle
+{
+	fn drop(&mut self)
+	{
+		self.releaser.release(Objects::Image(self.image, self.allocation, self.view, self.sampler));
+	}
+}
+
+/// A pipeline, released when dropped. See RenderState::create_pipeline_handle().
+pub struct PipelineHandle
+{
+	pipeline: vk::Pipeline,
+	releaser: Releaser,
+}
+
+impl PipelineHandle
+{
+	pub fn new(releaser: &Releaser, pipeline: vk::Pipeline) -> PipelineHandle
+	{
+		PipelineHandle {
+			pipeline: pipeline,
+			releaser: releaser.clone(),
+		}
+	}
+
+	pub fn get(&self) -> vk::Pipeline
+	{
+		return self.pipeline;
+	}
+}
+
+impl Drop for PipelineHandle
+{
+	fn drop(&mut self)
+	{
+		self.releaser.release(Objects::Pipeline(self.pipeline));
+	}
+}
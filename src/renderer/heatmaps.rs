@@ -0,0 +1,519 @@
+use crate::core::Console;
+use crate::renderer::{
+	track_create, track_destroy, BufferHandle, ObjectKind, Overlay, RenderState, TextStyle, Texture,
+};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use ash::Device;
+use std::ffi::CString;
+use std::mem::size_of;
+use std::rc::Rc;
+use std::{ptr, slice};
+
+// Must match the constants in heatmap.comp
+const GROUP_SIZE: u32 = 8;
+const HISTOGRAM_BINS: u64 = 64;
+const HISTOGRAM_SIZE: (u32, u32) = (256, 96);
+
+const LEGEND_POSITION: (f32, f32) = (220.0, 8.0);
+const LEGEND_SIZE: f32 = 14.0;
+
+/// What the debug heatmap shows, see Heatmaps.
+#[derive(Clone, Copy, PartialEq)]
+pub enum HeatmapMode
+{
+	Off = 0,
+	/// Luminance of the rendered image.
+	Luminance = 1,
+	/// Fragments shaded for each pixel, both opaque and blended. 1 is ideal, anything more is wasted shading.
+	Overdraw = 2,
+	/// Blended fragments layered over each pixel, the shading cost that depth testing cannot save.
+	DepthComplexity = 3,
+}
+
+impl HeatmapMode
+{
+	pub fn from_name(name: &str) -> Option<HeatmapMode>
+	{
+		match name
+		{
+			"off" => Some(HeatmapMode::Off),
+			"luminance" => Some(HeatmapMode::Luminance),
+			"overdraw" => Some(HeatmapMode::Overdraw),
+			"depth_complexity" => Some(HeatmapMode::DepthComplexity),
+			_ => None,
+		}
+	}
+
+	/// Returns true if the main pass needs to count its fragments for the heatmap.
+	pub fn needs_fragment_counts(&self) -> bool
+	{
+		return *self == HeatmapMode::Overdraw || *self == HeatmapMode::DepthComplexity;
+	}
+}
+
+/// Parameters of a heatmap.comp dispatch. Must match the HeatmapBlock push constants.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct HeatmapParams
+{
+	mode: u32,
+	stage: u32,
+	max_count: f32,
+}
+
+/// Debug heatmaps of the main pass outputs, generated by a compute pass and composited over the presented image.
+///
+/// A histogram of the heatmap values is drawn in the bottom left corner. The fragment counts of the overdraw and depth
+/// complexity modes are written by the main pass, see MainPass::set_fragment_counting().
+pub struct Heatmaps
+{
+	mode: HeatmapMode,
+	// Whether the overdraw and depth complexity modes can be shown, see RenderState::supports_fragment_counting()
+	fragment_counting: bool,
+	descriptor_pool: vk::DescriptorPool,
+	descriptor_set_layout: vk::DescriptorSetLayout,
+	descriptor_set: vk::DescriptorSet,
+	pipeline_layout: vk::PipelineLayout,
+	pipeline: vk::Pipeline,
+	commandbuffer: vk::CommandBuffer,
+	// The heatmap, transparent where it shows nothing
+	output: Texture,
+	extent: vk::Extent2D,
+	histogram: BufferHandle,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl Heatmaps
+{
+	/// Creates the heatmap pass for render images of the given size.
+	pub fn new(rs: &RenderState, console: &mut Console, width: u32, height: u32) -> Heatmaps
+	{
+		console.register_float("heatmap_max_count", 8.0, "Fragments per pixel shown in red by the heatmaps");
+		console.register_float("heatmap_opacity", 0.8, "Opacity of the heatmaps over the image [0, 1]");
+
+		let descriptor_sizes = [
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::STORAGE_IMAGE,
+				descriptor_count: 2,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::STORAGE_BUFFER,
+				descriptor_count: 1,
+			},
+		];
+		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+			pool_size_count: descriptor_sizes.len() as u32,
+			p_pool_sizes: descriptor_sizes.as_ptr(),
+			max_sets: 1,
+			..Default::default()
+		};
+		// Scene image, fragment counts, heatmap and histogram
+		let descriptor_types = [
+			vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			vk::DescriptorType::STORAGE_IMAGE,
+			vk::DescriptorType::STORAGE_IMAGE,
+			vk::DescriptorType::STORAGE_BUFFER,
+		];
+		let dsl_bindings: Vec<vk::DescriptorSetLayoutBinding> = descriptor_types
+			.iter()
+			.enumerate()
+			.map(|(binding, &descriptor_type)| vk::DescriptorSetLayoutBinding {
+				binding: binding as u32,
+				descriptor_type: descriptor_type,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::COMPUTE,
+				p_immutable_samplers: ptr::null(),
+			})
+			.collect();
+		let dsl_info = vk::DescriptorSetLayoutCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+			binding_count: dsl_bindings.len() as u32,
+			p_bindings: dsl_bindings.as_ptr(),
+			..Default::default()
+		};
+		let params_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::COMPUTE,
+			size: size_of::<HeatmapParams>() as u32,
+			offset: 0,
+		};
+
+		let descriptor_pool;
+		let descriptor_set_layout;
+		let descriptor_set;
+		let pipeline_layout;
+		unsafe {
+			descriptor_pool = rs.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
+			descriptor_set_layout = rs.device.create_descriptor_set_layout(&dsl_info, None).unwrap();
+			let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+				s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+				descriptor_pool: descriptor_pool,
+				descriptor_set_count: 1,
+				p_set_layouts: &descriptor_set_layout,
+				..Default::default()
+			};
+			descriptor_set = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap()[0];
+			let layout_create_info = vk::PipelineLayoutCreateInfo {
+				s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+				set_layout_count: 1,
+				p_set_layouts: &descriptor_set_layout,
+				push_constant_range_count: 1,
+				p_push_constant_ranges: &params_push_constant,
+				..Default::default()
+			};
+			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
+		}
+
+		let shader_module = rs.load_shader("shaders/heatmap_comp.spv", &[]);
+		let shader_entry_name = CString::new("main").unwrap();
+		let pipeline_info = vk::ComputePipelineCreateInfo {
+			s_type: vk::StructureType::COMPUTE_PIPELINE_CREATE_INFO,
+			stage: vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::COMPUTE,
+				..Default::default()
+			},
+			layout: pipeline_layout,
+			..Default::default()
+		};
+		let pipeline;
+		unsafe {
+			pipeline = rs
+				.device
+				.create_compute_pipelines(rs.pipeline_cache, &[pipeline_info], None)
+				.expect("Unable to create heatmap pipeline")[0];
+			rs.device.destroy_shader_module(shader_module, None);
+		}
+		track_create(ObjectKind::Pipeline, pipeline, "heatmaps");
+
+		let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
+			command_buffer_count: 1,
+			command_pool: rs.commandpool,
+			level: vk::CommandBufferLevel::PRIMARY,
+			..Default::default()
+		};
+		let commandbuffer;
+		unsafe {
+			commandbuffer = rs.device.allocate_command_buffers(&command_buffer_allocate_info).unwrap()[0];
+		}
+
+		let (output, output_extent) = Heatmaps::create_output(rs, width, height);
+		let histogram = rs.create_buffer(
+			vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+			vk::MemoryPropertyFlags::DEVICE_LOCAL,
+			HISTOGRAM_BINS * size_of::<u32>() as u64,
+		);
+
+		Heatmaps {
+			mode: HeatmapMode::Off,
+			fragment_counting: rs.supports_fragment_counting(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_layout: descriptor_set_layout,
+			descriptor_set: descriptor_set,
+			pipeline_layout: pipeline_layout,
+			pipeline: pipeline,
+			commandbuffer: commandbuffer,
+			output: output,
+			extent: output_extent,
+			histogram: histogram,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	fn create_output(rs: &RenderState, width: u32, height: u32) -> (Texture, vk::Extent2D)
+	{
+		let extent = vk::Extent3D {
+			width: width,
+			height: height,
+			depth: 1,
+		};
+		let output = rs.create_texture(
+			extent,
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::R8G8B8A8_UNORM,
+			vk::ImageAspectFlags::COLOR,
+			vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			None,
+		);
+		let output_extent = vk::Extent2D {
+			width: width,
+			height: height,
+		};
+		rs.register_image(output.image, "heatmap", output_extent, vk::Format::R8G8B8A8_UNORM);
+		return (output, output_extent);
+	}
+
+	/// Recreates the heatmap for render images of a new size, see MainPass::resize().
+	pub fn resize(&mut self, rs: &RenderState, width: u32, height: u32)
+	{
+		// The old heatmap might still be in use by the GPU
+		unsafe {
+			rs.device.device_wait_idle().unwrap();
+		}
+		let (output, extent) = Heatmaps::create_output(rs, width.max(1), height.max(1));
+		self.output = output;
+		self.extent = extent;
+	}
+
+	/// Shows a heatmap mode, modes needing fragment counts are refused when the device can't count fragments.
+	pub fn set_mode(&mut self, mode: HeatmapMode)
+	{
+		if mode.needs_fragment_counts() && !self.fragment_counting
+		{
+			warning!(
+				"This heatmap counts fragments, which needs fragment stores and atomics the device doesn't support"
+			);
+			return;
+		}
+		self.mode = mode;
+	}
+
+	pub fn get_mode(&self) -> HeatmapMode
+	{
+		return self.mode;
+	}
+
+	/// Returns the heatmap to composite over the presented image, if one is shown.
+	pub fn get_output(&self) -> Option<&Texture>
+	{
+		if self.mode == HeatmapMode::Off
+		{
+			return None;
+		}
+		return Some(&self.output);
+	}
+
+	/// Generates the heatmap from the render image and fragment counts of a main pass, after its end_frame().
+	pub fn render(
+		&mut self, rs: &RenderState, console: &Console, render_image: &mut Texture, fragment_counts: &mut Texture,
+	)
+	{
+		if self.mode == HeatmapMode::Off
+		{
+			return;
+		}
+		let cmd_buf = self.commandbuffer;
+		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+			flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+		}
+		let mut reads = vec![render_image.image];
+		if self.mode.needs_fragment_counts()
+		{
+			reads.push(fragment_counts.image);
+		}
+		rs.trace_pass(cmd_buf, "heatmaps", &reads, &[self.output.image]);
+
+		rs.transition_texture(
+			render_image,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			vk::PipelineStageFlags::COMPUTE_SHADER,
+			Some(cmd_buf),
+		);
+		rs.transition_texture(
+			fragment_counts,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::COMPUTE_SHADER,
+			Some(cmd_buf),
+		);
+		rs.transition_texture(
+			&mut self.output,
+			vk::AccessFlags::SHADER_WRITE,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::COMPUTE_SHADER,
+			Some(cmd_buf),
+		);
+
+		let image_descriptors = [
+			vk::DescriptorImageInfo {
+				image_layout: render_image.current_layout,
+				image_view: render_image.view,
+				sampler: render_image.sampler,
+			},
+			vk::DescriptorImageInfo {
+				image_layout: vk::ImageLayout::GENERAL,
+				image_view: fragment_counts.view,
+				sampler: vk::Sampler::null(),
+			},
+			vk::DescriptorImageInfo {
+				image_layout: vk::ImageLayout::GENERAL,
+				image_view: self.output.view,
+				sampler: vk::Sampler::null(),
+			},
+		];
+		let histogram_descriptor = vk::DescriptorBufferInfo {
+			buffer: self.histogram.get(),
+			offset: 0,
+			range: vk::WHOLE_SIZE,
+		};
+		let mut write_desc_sets: Vec<vk::WriteDescriptorSet> = image_descriptors
+			.iter()
+			.enumerate()
+			.map(|(binding, image_descriptor)| vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_set,
+				dst_binding: binding as u32,
+				descriptor_count: 1,
+				descriptor_type: if binding == 0
+				{
+					vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+				}
+				else
+				{
+					vk::DescriptorType::STORAGE_IMAGE
+				},
+				p_image_info: image_descriptor,
+				..Default::default()
+			})
+			.collect();
+		write_desc_sets.push(vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: self.descriptor_set,
+			dst_binding: 3,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+			p_buffer_info: &histogram_descriptor,
+			..Default::default()
+		});
+
+		let mut params = HeatmapParams {
+			mode: self.mode as u32,
+			stage: 0,
+			max_count: console.get_float("heatmap_max_count").max(1.0),
+		};
+		let extent = self.extent;
+		// Between the histogram being cleared, counted and drawn, and the heatmap being drawn under it
+		let memory_barrier = |src_access: vk::AccessFlags, src_stage: vk::PipelineStageFlags| unsafe {
+			let barrier = vk::MemoryBarrier {
+				s_type: vk::StructureType::MEMORY_BARRIER,
+				src_access_mask: src_access,
+				dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+				..Default::default()
+			};
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				src_stage,
+				vk::PipelineStageFlags::COMPUTE_SHADER,
+				vk::DependencyFlags::empty(),
+				&[barrier],
+				&[],
+				&[],
+			);
+		};
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+			rs.device.cmd_fill_buffer(cmd_buf, self.histogram.get(), 0, vk::WHOLE_SIZE, 0);
+			memory_barrier(vk::AccessFlags::TRANSFER_WRITE, vk::PipelineStageFlags::TRANSFER);
+
+			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+			rs.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::COMPUTE,
+				self.pipeline_layout,
+				0,
+				&[self.descriptor_set],
+				&[],
+			);
+			for &(stage, width, height) in
+				[(0, extent.width, extent.height), (1, HISTOGRAM_SIZE.0, HISTOGRAM_SIZE.1)].iter()
+			{
+				if stage == 1
+				{
+					memory_barrier(vk::AccessFlags::SHADER_WRITE, vk::PipelineStageFlags::COMPUTE_SHADER);
+				}
+				params.stage = stage;
+				let params_bytes =
+					slice::from_raw_parts(&params as *const HeatmapParams as *const u8, size_of::<HeatmapParams>());
+				rs.device.cmd_push_constants(
+					cmd_buf,
+					self.pipeline_layout,
+					vk::ShaderStageFlags::COMPUTE,
+					0,
+					params_bytes,
+				);
+				rs.device.cmd_dispatch(
+					cmd_buf,
+					(width + GROUP_SIZE - 1) / GROUP_SIZE,
+					(height + GROUP_SIZE - 1) / GROUP_SIZE,
+					1,
+				);
+			}
+		}
+
+		// Sampled when presenting
+		rs.transition_texture(
+			&mut self.output,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			Some(cmd_buf),
+		);
+		rs.end_pass(cmd_buf);
+		unsafe {
+			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+		}
+		let submit_info = vk::SubmitInfo {
+			s_type: vk::StructureType::SUBMIT_INFO,
+			command_buffer_count: 1,
+			p_command_buffers: &cmd_buf,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.queue_submit(rs.graphics_queue, &[submit_info], vk::Fence::null()).expect("queue submit failed.");
+		}
+	}
+
+	/// Queues a line explaining the colors of the heatmap on the overlay.
+	pub fn draw_legend(&self, console: &Console, overlay: &mut Overlay, style: &TextStyle)
+	{
+		let max_count = console.get_float("heatmap_max_count").max(1.0);
+		let legend = match self.mode
+		{
+			HeatmapMode::Off => return,
+			HeatmapMode::Luminance => "Luminance: blue dark, red bright".to_string(),
+			HeatmapMode::Overdraw => format!("Overdraw: red at {} shaded fragments", max_count),
+			HeatmapMode::DepthComplexity => format!("Depth complexity: red at {} blended layers", max_count),
+		};
+		overlay.draw_text(&legend, LEGEND_POSITION, LEGEND_SIZE, style);
+	}
+}
+
+impl Drop for Heatmaps
+{
+	fn drop(&mut self)
+	{
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+
+		track_destroy(ObjectKind::Pipeline, self.pipeline);
+		unsafe {
+			// The command buffer may still be executing
+			self.device.device_wait_idle().unwrap();
+
+			self.device.destroy_pipeline(self.pipeline, None);
+			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+			self.device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+			self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+		}
+	}
+}
@@ -0,0 +1,312 @@
+use crate::core::{Material, MaterialUniforms, Mesh, VertexFormat};
+use crate::renderer::{BufferHandle, RenderState};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use ash::vk::Handle;
+use ash::Device;
+use cgmath::Matrix4;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+
+/// Most draws and materials that can be written in a frame, the rest is drawn directly.
+const MAX_DRAWS: usize = 1_024;
+
+/// Must match DrawData in phong.vert (std430).
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrawData
+{
+	model_matrix: Matrix4<f32>,
+	mvp_matrix: Matrix4<f32>,
+	material: u32,
+	_padding: [u32; 3],
+}
+
+/// A mesh draw of one or more instances, waiting to be written to the indirect buffers.
+struct QueuedDraw
+{
+	vertex_format: VertexFormat,
+	vertex_buffer: vk::Buffer,
+	index_buffer: vk::Buffer,
+	index_count: u32,
+	// Set 0 of the material, only its textures are used by indirect draws
+	material_set: vk::DescriptorSet,
+	texture_views: [vk::ImageView; 3],
+	material: MaterialUniforms,
+	// Range of the instances in IndirectDraws::instances
+	first_instance: usize,
+	instance_count: usize,
+}
+
+impl QueuedDraw
+{
+	/// Draws with the same key can be issued by a single indirect multi-draw.
+	fn get_key(&self) -> (u8, u64, u64, u64, u64, u64)
+	{
+		return (
+			self.vertex_format as u8,
+			self.vertex_buffer.as_raw(),
+			self.index_buffer.as_raw(),
+			self.texture_views[0].as_raw(),
+			self.texture_views[1].as_raw(),
+			self.texture_views[2].as_raw(),
+		);
+	}
+}
+
+/// Collects the mesh draws of a main pass, and issues them as a few indirect multi-draws.
+///
+/// Matrices and material parameters of each draw are written to storage buffers (set 2), and read by the shaders at
+/// the index given as first instance. Draws are grouped by mesh and textures, so materials only differing in their
+/// parameters share a draw call.
+pub struct IndirectDraws
+{
+	enabled: bool,
+	descriptor_set: vk::DescriptorSet,
+	draws_buffer: BufferHandle,
+	materials_buffer: BufferHandle,
+	commands_buffer: BufferHandle,
+	queued: Vec<QueuedDraw>,
+	// Model and MVP matrices of the instances of the queued draws
+	instances: Vec<(Matrix4<f32>, Matrix4<f32>)>,
+	// Instances, commands and materials written in the current frame
+	num_draws: usize,
+	num_commands: usize,
+	num_materials: usize,
+
+	// Keep a pointer to the device for writing the buffers
+	device: Rc<Device>,
+}
+
+impl IndirectDraws
+{
+	/// Creates the buffers and their descriptor set, which must be bound even when indirect draws are not supported.
+	pub fn new(rs: &RenderState, descriptor_pool: vk::DescriptorPool, layout: vk::DescriptorSetLayout)
+		-> IndirectDraws
+	{
+		let enabled = rs.supports_multi_draw_indirect();
+		if !enabled
+		{
+			warning!("Multi-draw indirect is not supported, meshes will be drawn one by one");
+		}
+
+		let host_visible = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+		let draws_buffer = rs.create_buffer(
+			vk::BufferUsageFlags::STORAGE_BUFFER,
+			host_visible,
+			(MAX_DRAWS * size_of::<DrawData>()) as u64,
+		);
+		let materials_buffer = rs.create_buffer(
+			vk::BufferUsageFlags::STORAGE_BUFFER,
+			host_visible,
+			(MAX_DRAWS * size_of::<MaterialUniforms>()) as u64,
+		);
+		let commands_buffer = rs.create_buffer(
+			vk::BufferUsageFlags::INDIRECT_BUFFER,
+			host_visible,
+			(MAX_DRAWS * size_of::<vk::DrawIndexedIndirectCommand>()) as u64,
+		);
+
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_count: 1,
+			p_set_layouts: &layout,
+		};
+		let descriptor_set;
+		unsafe {
+			descriptor_set = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap()[0];
+		}
+		let buffer_descriptors = [
+			vk::DescriptorBufferInfo {
+				buffer: draws_buffer.get(),
+				offset: 0,
+				range: vk::WHOLE_SIZE,
+			},
+			vk::DescriptorBufferInfo {
+				buffer: materials_buffer.get(),
+				offset: 0,
+				range: vk::WHOLE_SIZE,
+			},
+		];
+		let write_desc_sets: Vec<vk::WriteDescriptorSet> = buffer_descriptors
+			.iter()
+			.enumerate()
+			.map(|(binding, buffer_descriptor)| vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: descriptor_set,
+				dst_binding: binding as u32,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+				p_buffer_info: buffer_descriptor,
+				..Default::default()
+			})
+			.collect();
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+
+		IndirectDraws {
+			enabled: enabled,
+			descriptor_set: descriptor_set,
+			draws_buffer: draws_buffer,
+			materials_buffer: materials_buffer,
+			commands_buffer: commands_buffer,
+			queued: Vec::new(),
+			instances: Vec::new(),
+			num_draws: 0,
+			num_commands: 0,
+			num_materials: 0,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	pub fn get_descriptor_set(&self) -> vk::DescriptorSet
+	{
+		return self.descriptor_set;
+	}
+
+	/// Starts a new frame, reusing the buffers from the start.
+	pub fn reset(&mut self)
+	{
+		self.queued.clear();
+		self.instances.clear();
+		self.num_draws = 0;
+		self.num_commands = 0;
+		self.num_materials = 0;
+	}
+
+	/// Queues a draw of the instances, given as model and MVP matrices, until the next flush. Returns false if indirect
+	/// draws are not supported, or the buffers are full for this frame, in which case the mesh must be drawn directly.
+	pub fn queue(
+		&mut self, mesh: &Mesh, lod: usize, material: &Material, instances: &[(Matrix4<f32>, Matrix4<f32>)],
+	) -> bool
+	{
+		// Every instance might need its own material
+		if !self.enabled || self.num_draws + self.instances.len() + instances.len() > MAX_DRAWS
+		{
+			return false;
+		}
+		let (vertex_buffer, index_buffer) = mesh.get_buffers(lod);
+		self.queued.push(QueuedDraw {
+			vertex_format: mesh.get_vertex_format(),
+			vertex_buffer: vertex_buffer,
+			index_buffer: index_buffer,
+			index_count: mesh.get_num_indices(lod),
+			material_set: material.get_descriptor_set(),
+			texture_views: material.get_texture_views(),
+			material: material.get_uniforms(),
+			first_instance: self.instances.len(),
+			instance_count: instances.len(),
+		});
+		self.instances.extend_from_slice(instances);
+		return true;
+	}
+
+	/// Writes the queued draws to the buffers and issues them, one multi-draw per mesh and set of textures. The
+	/// instances of a draw read consecutive draw data.
+	///
+	/// Pipelines are the indirect mesh pipelines, indexed by vertex format. Leaves one of them bound.
+	pub fn flush(
+		&mut self, cmd_buf: vk::CommandBuffer, pipelines: [vk::Pipeline; 2], pipeline_layout: vk::PipelineLayout,
+	)
+	{
+		if self.queued.is_empty()
+		{
+			return;
+		}
+		// Stable, so draws keep their order within a group
+		self.queued.sort_by_key(QueuedDraw::get_key);
+
+		let mut draws = Vec::with_capacity(self.queued.len());
+		let mut materials = Vec::new();
+		let mut material_indices: HashMap<u64, u32> = HashMap::new();
+		let mut commands = Vec::with_capacity(self.queued.len());
+		for queued in self.queued.iter()
+		{
+			let num_materials = self.num_materials;
+			let material = *material_indices.entry(queued.material_set.as_raw()).or_insert_with(|| {
+				materials.push(queued.material);
+				(num_materials + materials.len() - 1) as u32
+			});
+			commands.push(vk::DrawIndexedIndirectCommand {
+				index_count: queued.index_count,
+				instance_count: queued.instance_count as u32,
+				first_index: 0,
+				vertex_offset: 0,
+				first_instance: (self.num_draws + draws.len()) as u32,
+			});
+			let instances = &self.instances[queued.first_instance..queued.first_instance + queued.instance_count];
+			for &(model_matrix, mvp_matrix) in instances
+			{
+				draws.push(DrawData {
+					model_matrix: model_matrix,
+					mvp_matrix: mvp_matrix,
+					material: material,
+					_padding: [0; 3],
+				});
+			}
+		}
+		IndirectDraws::write(&self.draws_buffer, self.num_draws, &draws);
+		IndirectDraws::write(&self.materials_buffer, self.num_materials, &materials);
+		IndirectDraws::write(&self.commands_buffer, self.num_commands, &commands);
+
+		let command_size = size_of::<vk::DrawIndexedIndirectCommand>();
+		let mut bound_pipeline = vk::Pipeline::null();
+		let mut start = 0;
+		while start < self.queued.len()
+		{
+			let first = &self.queued[start];
+			let key = first.get_key();
+			let count = self.queued[start..].iter().take_while(|queued| queued.get_key() == key).count();
+			let pipeline = pipelines[first.vertex_format as usize];
+			unsafe {
+				if pipeline != bound_pipeline
+				{
+					self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline);
+					bound_pipeline = pipeline;
+				}
+				self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[first.vertex_buffer], &[0]);
+				self.device.cmd_bind_index_buffer(cmd_buf, first.index_buffer, 0, vk::IndexType::UINT16);
+				self.device.cmd_bind_descriptor_sets(
+					cmd_buf,
+					vk::PipelineBindPoint::GRAPHICS,
+					pipeline_layout,
+					0,
+					&[first.material_set],
+					&[],
+				);
+				self.device.cmd_draw_indexed_indirect(
+					cmd_buf,
+					self.commands_buffer.get(),
+					((self.num_commands + start) * command_size) as u64,
+					count as u32,
+					command_size as u32,
+				);
+			}
+			start += count;
+		}
+
+		self.num_draws += draws.len();
+		self.num_commands += commands.len();
+		self.num_materials += materials.len();
+		self.queued.clear();
+		self.instances.clear();
+	}
+
+	/// Copies data to the given host visible buffer, starting at element offset.
+	fn write<T: Copy>(buffer: &BufferHandle, offset: usize, data: &[T])
+	{
+		if data.is_empty()
+		{
+			return;
+		}
+		unsafe {
+			ptr::copy_nonoverlapping(data.as_ptr(), (buffer.get_mapped() as *mut T).add(offset), data.len());
+		}
+	}
+}
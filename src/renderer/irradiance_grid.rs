@@ -0,0 +1,375 @@
+use crate::core::ParticleVertex;
+use crate::renderer::shadowpass::get_cube_face_matrices;
+use crate::renderer::{
+	BufferHandle, FrameUniforms, IrradianceGridUniforms, LightUniforms, LineBatch, MainPass, RenderState,
+};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector3, Vector4};
+use std::f32::consts::PI;
+use std::slice;
+
+/// Most probes in an irradiance grid, as each is baked by rendering the scene six times.
+pub const MAX_IRRADIANCE_PROBES: usize = 1_024;
+// Irradiance varies slowly with direction, so the faces rendered for each probe can be tiny
+const PROBE_RESOLUTION: u32 = 16;
+// Bytes per texel of the main pass render image the faces are read back from
+const PROBE_TEXEL_BYTES: u32 = 4;
+const PROBE_NEAR: f32 = 0.1;
+const PROBE_FAR: f32 = 1_000.0;
+// Length of the lines showing the irradiance of each probe along the axes, see draw_debug()
+const DEBUG_LINE_LENGTH: f32 = 0.4;
+const DEBUG_DIRECTIONS: [[f32; 3]; 6] =
+	[[1.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0, -1.0]];
+
+/// The irradiance of a probe, as second order spherical harmonics with the cosine lobe convolution and 1/pi folded in.
+/// Must match IrradianceProbe in phong.frag (std430).
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IrradianceProbe
+{
+	// RGB coefficients, padded to vec4
+	sh: [[f32; 4]; 9],
+}
+
+/// Returns the 9 second order spherical harmonics basis functions in the given direction.
+fn sh_basis(d: Vector3<f32>) -> [f32; 9]
+{
+	return [
+		0.282_095,
+		0.488_603 * d.y,
+		0.488_603 * d.z,
+		0.488_603 * d.x,
+		1.092_548 * d.x * d.y,
+		1.092_548 * d.y * d.z,
+		0.315_392 * (3.0 * d.z * d.z - 1.0),
+		1.092_548 * d.x * d.z,
+		0.546_274 * (d.x * d.x - d.y * d.y),
+	];
+}
+
+impl IrradianceProbe
+{
+	/// Returns the irradiance over pi in the given direction, the light reflected by a white diffuse surface facing it.
+	fn evaluate(&self, direction: Vector3<f32>) -> [f32; 3]
+	{
+		let mut irradiance = [0.0; 3];
+		for (coefficient, basis) in self.sh.iter().zip(sh_basis(direction).iter())
+		{
+			for channel in 0..3
+			{
+				irradiance[channel] += coefficient[channel] * basis;
+			}
+		}
+		return [irradiance[0].max(0.0), irradiance[1].max(0.0), irradiance[2].max(0.0)];
+	}
+}
+
+/// A grid of irradiance probes spanning a box volume. A single probe along an axis sits at the center.
+#[derive(Clone, Copy, PartialEq)]
+pub struct IrradianceVolume
+{
+	/// Center of the volume.
+	pub position: Point3<f32>,
+	/// Half the size of the volume along each axis.
+	pub extents: Vector3<f32>,
+	/// Probes along each axis.
+	pub counts: [u32; 3],
+}
+
+impl IrradianceVolume
+{
+	/// Returns the position of the first probe, and the distance between probes along each axis.
+	fn get_origin_and_spacing(&self) -> (Point3<f32>, Vector3<f32>)
+	{
+		let mut origin = self.position;
+		let mut spacing = Vector3::zero();
+		for axis in 0..3
+		{
+			if self.counts[axis] > 1
+			{
+				origin[axis] -= self.extents[axis];
+				spacing[axis] = 2.0 * self.extents[axis] / (self.counts[axis] - 1) as f32;
+			}
+		}
+		return (origin, spacing);
+	}
+
+	fn get_probe_count(&self) -> usize
+	{
+		return self.counts.iter().map(|&count| count as usize).product();
+	}
+
+	/// Returns the probe positions, x first, then y and z, as indexed by the shaders.
+	fn get_probe_positions(&self) -> Vec<Point3<f32>>
+	{
+		let (origin, spacing) = self.get_origin_and_spacing();
+		let mut positions = Vec::with_capacity(self.get_probe_count());
+		for z in 0..self.counts[2]
+		{
+			for y in 0..self.counts[1]
+			{
+				for x in 0..self.counts[0]
+				{
+					let offset = Vector3::new(x as f32 * spacing.x, y as f32 * spacing.y, z as f32 * spacing.z);
+					positions.push(origin + offset);
+				}
+			}
+		}
+		return positions;
+	}
+}
+
+/// A grid of light probes approximating bounce lighting, the light reflected by the scene onto other surfaces.
+///
+/// Each probe is baked by rendering the scene around it with direct lighting only, and projecting what it sees onto
+/// spherical harmonics. The shaders interpolate the eight probes around a fragment, and light it by their irradiance in
+/// the direction of its normal.
+pub struct IrradianceGrid
+{
+	volume: Option<IrradianceVolume>,
+	probes: Vec<IrradianceProbe>,
+	probe_buffer: Option<BufferHandle>,
+	debug_lines: LineBatch,
+}
+
+impl IrradianceGrid
+{
+	/// Creates an empty grid, see bake().
+	pub fn new(rs: &RenderState) -> IrradianceGrid
+	{
+		IrradianceGrid {
+			volume: None,
+			probes: Vec::new(),
+			probe_buffer: None,
+			debug_lines: LineBatch::new(rs, DEBUG_DIRECTIONS.len() * MAX_IRRADIANCE_PROBES),
+		}
+	}
+
+	/// Returns true if the given volume is the baked one.
+	pub fn is_baked(&self, volume: Option<&IrradianceVolume>) -> bool
+	{
+		return self.volume.as_ref() == volume;
+	}
+
+	/// Returns the grid as seen by the shaders, empty if there is none.
+	pub fn get_uniforms(&self) -> IrradianceGridUniforms
+	{
+		return match (&self.volume, &self.probe_buffer)
+		{
+			(Some(volume), Some(_)) =>
+			{
+				let (origin, spacing) = volume.get_origin_and_spacing();
+				let inverse_spacing = spacing.map(|distance| {
+					if distance > 0.0
+					{
+						1.0 / distance
+					}
+					else
+					{
+						0.0
+					}
+				});
+				IrradianceGridUniforms::new(origin, inverse_spacing, volume.counts)
+			}
+			_ => IrradianceGridUniforms::default(),
+		};
+	}
+
+	/// Returns the buffer of baked probes, see MainPass::set_irradiance_probes().
+	pub fn get_probe_buffer(&self) -> Option<vk::Buffer>
+	{
+		return self.probe_buffer.as_ref().map(|buffer| buffer.get());
+	}
+
+	/// Replaces the baked grid with the given one, rendering the scene around each probe. The scene is drawn by
+	/// draw_scene, given a main pass rendering a cube face, and the view and projection matrices of the face.
+	///
+	/// Lights and the shadow atlas are the ones of the current frame. This stalls the GPU for every face, so it should
+	/// only be done when loading or on demand.
+	pub fn bake<F>(
+		&mut self, rs: &RenderState, volume: Option<&IrradianceVolume>, lights: &[LightUniforms],
+		shadow_atlas: (vk::ImageView, vk::Sampler), wetness: f32, mut draw_scene: F,
+	) where
+		F: FnMut(&MainPass, vk::CommandBuffer, &Matrix4<f32>, &Matrix4<f32>),
+	{
+		self.volume = volume.cloned();
+		self.probes.clear();
+		self.probe_buffer = None;
+		self.debug_lines.set_lines(&[]);
+		let volume = match volume
+		{
+			Some(volume) => volume,
+			None => return,
+		};
+		if volume.get_probe_count() > MAX_IRRADIANCE_PROBES
+		{
+			warning!(
+				"Irradiance grid of {} probes is over the limit of {}, not baked",
+				volume.get_probe_count(),
+				MAX_IRRADIANCE_PROBES
+			);
+			return;
+		}
+
+		let mut mp = MainPass::init_with_size(rs, PROBE_RESOLUTION, PROBE_RESOLUTION, "irradiance probe");
+		mp.set_shadow_atlas(rs, Some(shadow_atlas));
+		let face_bytes = (PROBE_RESOLUTION * PROBE_RESOLUTION * PROBE_TEXEL_BYTES) as vk::DeviceSize;
+		let readback = rs.create_buffer(
+			vk::BufferUsageFlags::TRANSFER_DST,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			face_bytes,
+		);
+		// Rendered mirrored in y like the reflection probes, see ReflectionProbes::bake()
+		let mirror = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+		let positions = volume.get_probe_positions();
+		for position in positions.iter()
+		{
+			let mirrored_position = Point3::new(position.x, -position.y, position.z);
+			let (view_matrices, projection_matrix) = get_cube_face_matrices(mirrored_position, PROBE_NEAR, PROBE_FAR);
+			let mut radiance_sh = [[0.0f32; 3]; 9];
+			let mut total_weight = 0.0;
+			for view_matrix in view_matrices.iter()
+			{
+				let view_matrix = view_matrix * mirror;
+				// Only direct light is baked, without the grid itself
+				mp.update_frame_uniforms(&FrameUniforms::new(
+					view_matrix,
+					wetness,
+					0.0,
+					lights,
+					&[],
+					IrradianceGridUniforms::default(),
+				));
+				let cmd_buf = mp.begin_frame(rs);
+				draw_scene(&mp, cmd_buf, &view_matrix, &projection_matrix);
+				mp.end_frame(rs);
+				IrradianceGrid::read_back(rs, &mut mp, &readback);
+
+				// Project the face onto the basis, each texel weighted by the solid angle it covers
+				let clip_to_world = (projection_matrix * view_matrix).invert().unwrap();
+				unsafe {
+					let mem_ptr = readback.get_mapped();
+					let texels = slice::from_raw_parts(mem_ptr as *const u8, face_bytes as usize);
+					for y in 0..PROBE_RESOLUTION
+					{
+						for x in 0..PROBE_RESOLUTION
+						{
+							let u = 2.0 * (x as f32 + 0.5) / PROBE_RESOLUTION as f32 - 1.0;
+							let v = 2.0 * (y as f32 + 0.5) / PROBE_RESOLUTION as f32 - 1.0;
+							let world = clip_to_world * Vector4::new(u, v, 0.5, 1.0);
+							let direction = (Point3::from_homogeneous(world) - position).normalize();
+							let weight = 1.0 / (1.0 + u * u + v * v).powf(1.5);
+							let texel = ((y * PROBE_RESOLUTION + x) * PROBE_TEXEL_BYTES) as usize;
+							for (coefficient, basis) in radiance_sh.iter_mut().zip(sh_basis(direction).iter())
+							{
+								for channel in 0..3
+								{
+									coefficient[channel] += texels[texel + channel] as f32 / 255.0 * basis * weight;
+								}
+							}
+							total_weight += weight;
+						}
+					}
+				}
+			}
+
+			// Normalize the weights to the full sphere, and convolve with the cosine lobe over pi
+			let band_scales = [1.0, 2.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 0.25, 0.25, 0.25, 0.25, 0.25];
+			let mut probe = IrradianceProbe {
+				sh: [[0.0; 4]; 9],
+			};
+			for (idx, coefficient) in radiance_sh.iter().enumerate()
+			{
+				for channel in 0..3
+				{
+					probe.sh[idx][channel] = coefficient[channel] * 4.0 * PI / total_weight * band_scales[idx];
+				}
+			}
+			self.probes.push(probe);
+		}
+
+		self.probe_buffer = Some(rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::STORAGE_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&self.probes,
+			false,
+		));
+
+		// A line along each axis from each probe, colored by the irradiance in its direction
+		let mut debug_vertices = Vec::with_capacity(2 * DEBUG_DIRECTIONS.len() * positions.len());
+		for (position, probe) in positions.iter().zip(self.probes.iter())
+		{
+			for &direction in DEBUG_DIRECTIONS.iter()
+			{
+				let direction = Vector3::from(direction);
+				let irradiance = probe.evaluate(direction);
+				let color = [irradiance[0].min(1.0), irradiance[1].min(1.0), irradiance[2].min(1.0), 1.0];
+				debug_vertices.push(ParticleVertex {
+					pos: (*position).into(),
+					color: color,
+				});
+				debug_vertices.push(ParticleVertex {
+					pos: (position + direction * DEBUG_LINE_LENGTH).into(),
+					color: color,
+				});
+			}
+		}
+		self.debug_lines.set_lines(&debug_vertices);
+		println!("Baked an irradiance grid of {} probes", self.probes.len());
+	}
+
+	/// Copies the render image of the main pass into the readback buffer, waiting for the copy to finish.
+	fn read_back(rs: &RenderState, mp: &mut MainPass, readback: &BufferHandle)
+	{
+		let copy_region = vk::BufferImageCopy {
+			buffer_offset: 0,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image_offset: vk::Offset3D {
+				x: 0,
+				y: 0,
+				z: 0,
+			},
+			image_extent: vk::Extent3D {
+				width: PROBE_RESOLUTION,
+				height: PROBE_RESOLUTION,
+				depth: 1,
+			},
+		};
+
+		let cmd_buf = rs.begin_single_time_commands();
+		rs.transition_texture(
+			&mut mp.render_image,
+			vk::AccessFlags::TRANSFER_READ,
+			vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			vk::PipelineStageFlags::TRANSFER,
+			Some(cmd_buf),
+		);
+		unsafe {
+			rs.device.cmd_copy_image_to_buffer(
+				cmd_buf,
+				mp.render_image.image,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				readback.get(),
+				&[copy_region],
+			);
+		}
+		rs.end_single_time_commands(cmd_buf);
+	}
+
+	/// Draws the probes as lines along the axes, colored by their irradiance in each direction.
+	pub fn draw_debug(
+		&self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+	)
+	{
+		self.debug_lines.draw(cmd_buf, mp, view_matrix, projection_matrix);
+	}
+}
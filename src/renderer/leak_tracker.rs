@@ -0,0 +1,83 @@
+use ash::vk::Handle;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::Location;
+
+/// The kinds of Vulkan objects that are tracked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ObjectKind
+{
+	Buffer,
+	Image,
+	ImageView,
+	Sampler,
+	Pipeline,
+}
+
+struct LiveObject
+{
+	tag: String,
+	location: &'static Location<'static>,
+}
+
+// Objects are destroyed from Drop implementations that only have the device, so the registry is global. Vulkan objects
+// are only created and destroyed from the main thread.
+thread_local! {
+	static LIVE_OBJECTS: RefCell<HashMap<(ObjectKind, u64), LiveObject>> = RefCell::new(HashMap::new());
+}
+
+/// Records the creation of a Vulkan object in debug builds, along with a tag describing it and the call site.
+///
+/// Functions forwarding to this should be #[track_caller] too, so that the reported site is the interesting one.
+#[track_caller]
+pub fn track_create<H: Handle>(kind: ObjectKind, handle: H, tag: &str)
+{
+	if !cfg!(debug_assertions)
+	{
+		return;
+	}
+	let object = LiveObject {
+		tag: tag.to_string(),
+		location: Location::caller(),
+	};
+	LIVE_OBJECTS.with(|objects| {
+		objects.borrow_mut().insert((kind, handle.as_raw()), object);
+	});
+}
+
+/// Records the destruction of a Vulkan object in debug builds. Panics if the object is not alive, which means it was
+/// destroyed twice or its creation was never tracked.
+pub fn track_destroy<H: Handle>(kind: ObjectKind, handle: H)
+{
+	if !cfg!(debug_assertions)
+	{
+		return;
+	}
+	let raw = handle.as_raw();
+	let removed = LIVE_OBJECTS.with(|objects| objects.borrow_mut().remove(&(kind, raw)));
+	assert!(removed.is_some(), "{:?} 0x{:x} was destroyed twice, or never tracked", kind, raw);
+}
+
+/// Prints all objects that are still alive, with the site that created them. Should be called right before the device
+/// is destroyed, when everything else has been dropped.
+pub fn report_leaks()
+{
+	if !cfg!(debug_assertions)
+	{
+		return;
+	}
+	LIVE_OBJECTS.with(|objects| {
+		let objects = objects.borrow();
+		if objects.is_empty()
+		{
+			return;
+		}
+		let mut leaks: Vec<_> = objects.iter().collect();
+		leaks.sort_by_key(|((kind, _), object)| (format!("{:?}", kind), object.location.to_string()));
+		warning!("{} Vulkan objects were leaked:", leaks.len());
+		for ((kind, raw), object) in leaks
+		{
+			println!("  {:?} 0x{:x} ({}) created at {}", kind, raw, object.tag, object.location);
+		}
+	});
+}
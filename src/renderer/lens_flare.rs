@@ -0,0 +1,93 @@
+use crate::renderer::{BufferHandle, RenderState};
+use ash::util::Align;
+use ash::vk;
+use std::mem::{align_of, size_of};
+
+/// Most flare elements drawn, must match MAX_FLARE_ELEMENTS in final_pass.frag.
+pub const MAX_FLARE_ELEMENTS: usize = 8;
+
+/// A reflection of the sun in the lens, placed on the axis from the sun through the screen center.
+#[derive(Clone, Copy)]
+pub struct FlareElement
+{
+	/// Position along the axis, 0 at the sun, 1 at the screen center and 2 mirrored across it.
+	pub position: f32,
+	/// Radius, as a fraction of the screen height.
+	pub size: f32,
+	pub color: [f32; 3],
+	/// A thin ring instead of a filled disc.
+	pub ring: bool,
+}
+
+/// Layout of a flare element in the FlareBlock uniforms of final_pass.frag.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FlareElementUniforms
+{
+	// Position, size, and 1 for rings
+	placement: [f32; 4],
+	color: [f32; 4],
+}
+
+/// Must match the FlareBlock uniforms in final_pass.frag.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FlareUniforms
+{
+	count: u32,
+	_padding: [u32; 3],
+	elements: [FlareElementUniforms; MAX_FLARE_ELEMENTS],
+}
+
+/// The elements of the lens flare drawn by the final pass, in a uniform buffer.
+///
+/// How bright the flare is, and whether the sun is occluded, is left to the final pass.
+pub struct LensFlare
+{
+	buffer: BufferHandle,
+}
+
+impl LensFlare
+{
+	/// Creates a lens flare without elements, see set_elements().
+	pub fn new(rs: &RenderState) -> LensFlare
+	{
+		let buffer = rs.create_buffer(
+			vk::BufferUsageFlags::UNIFORM_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			size_of::<FlareUniforms>() as u64,
+		);
+		let lens_flare = LensFlare {
+			buffer: buffer,
+		};
+		lens_flare.set_elements(&[]);
+		return lens_flare;
+	}
+
+	/// Sets the elements drawn by the following frames. Elements beyond MAX_FLARE_ELEMENTS are ignored.
+	pub fn set_elements(&self, elements: &[FlareElement])
+	{
+		let mut uniforms = FlareUniforms::default();
+		uniforms.count = elements.len().min(MAX_FLARE_ELEMENTS) as u32;
+		for (uniform, element) in uniforms.elements.iter_mut().zip(elements.iter())
+		{
+			uniform.placement = [element.position, element.size, element.ring as u32 as f32, 0.0];
+			uniform.color = [element.color[0], element.color[1], element.color[2], 1.0];
+		}
+		let buffer_size = size_of::<FlareUniforms>() as u64;
+		unsafe {
+			let mem_ptr = self.buffer.get_mapped();
+			let mut mem_align = Align::new(mem_ptr, align_of::<FlareUniforms>() as u64, buffer_size);
+			mem_align.copy_from_slice(&[uniforms]);
+		}
+	}
+
+	pub fn get_descriptor(&self) -> vk::DescriptorBufferInfo
+	{
+		return vk::DescriptorBufferInfo {
+			buffer: self.buffer.get(),
+			offset: 0,
+			range: size_of::<FlareUniforms>() as u64,
+		};
+	}
+}
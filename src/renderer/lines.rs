@@ -0,0 +1,155 @@
+use crate::core::ParticleVertex;
+use crate::renderer::{BufferHandle, MainPass, RenderState};
+use ash::util::Align;
+use ash::version::DeviceV1_0;
+use ash::{vk, Device};
+use cgmath::prelude::*;
+use cgmath::Matrix4;
+use std::mem::{align_of, size_of};
+use std::rc::Rc;
+use std::{mem, slice};
+
+/// A host visible buffer of world space lines, drawn with the particle pipeline.
+///
+/// Used for particles and debug visualizations, which change often and need no lighting.
+pub struct LineBatch
+{
+	vertices: BufferHandle,
+	max_vertices: usize,
+	num_vertices: usize,
+
+	// Keep a pointer to the device for writing and drawing the lines
+	device: Rc<Device>,
+}
+
+impl LineBatch
+{
+	pub fn new(rs: &RenderState, max_lines: usize) -> LineBatch
+	{
+		return LineBatch::with_vertices(rs, 2 * max_lines);
+	}
+
+	fn with_vertices(rs: &RenderState, max_vertices: usize) -> LineBatch
+	{
+		let initial_vertices = vec![
+			ParticleVertex {
+				pos: [0.0; 3],
+				color: [0.0; 4],
+			};
+			max_vertices
+		];
+		let vert_buffer = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::VERTEX_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&initial_vertices,
+			false,
+		);
+
+		LineBatch {
+			vertices: vert_buffer,
+			max_vertices: max_vertices,
+			num_vertices: 0,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Replaces the lines in the batch. Every two vertices make up a line.
+	///
+	/// Lines exceeding the capacity of the batch are dropped. The GPU must not be using the batch while setting it.
+	pub fn set_lines(&mut self, vertices: &[ParticleVertex])
+	{
+		debug_assert!(vertices.len() % 2 == 0);
+		self.set_vertices(vertices);
+	}
+
+	fn set_vertices(&mut self, vertices: &[ParticleVertex])
+	{
+		self.num_vertices = vertices.len().min(self.max_vertices);
+		if self.num_vertices == 0
+		{
+			return;
+		}
+
+		let copy_size = (size_of::<ParticleVertex>() * self.num_vertices) as u64;
+		unsafe {
+			let mem_ptr = self.vertices.get_mapped();
+			let mut mem_align = Align::new(mem_ptr, align_of::<ParticleVertex>() as u64, copy_size);
+			mem_align.copy_from_slice(&vertices[..self.num_vertices]);
+		}
+	}
+
+	/// Draws the lines. Binds the particle pipeline, so call this after drawing opaque geometry.
+	pub fn draw(
+		&self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+	)
+	{
+		if self.num_vertices == 0
+		{
+			return;
+		}
+		mp.bind_particle_pipeline(cmd_buf);
+		self.draw_vertices(cmd_buf, mp, view_matrix, projection_matrix);
+	}
+
+	/// Draws the vertices with the pipeline bound by the caller.
+	fn draw_vertices(
+		&self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+	)
+	{
+		let model_matrix: Matrix4<f32> = Matrix4::identity();
+		let matrices = [model_matrix, projection_matrix * view_matrix];
+
+		unsafe {
+			self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.vertices.get()], &[0]);
+			let matrices_bytes = slice::from_raw_parts(matrices.as_ptr() as *const u8, mem::size_of_val(&matrices));
+			self.device.cmd_push_constants(
+				cmd_buf,
+				mp.pipeline_layout,
+				vk::ShaderStageFlags::VERTEX,
+				0,
+				matrices_bytes,
+			);
+			self.device.cmd_draw(cmd_buf, self.num_vertices as u32, 1, 0, 0);
+		}
+	}
+}
+
+/// A host visible buffer of world space triangles, drawn with the ribbon pipeline.
+///
+/// Like LineBatch, but for effects with a width, like trails.
+pub struct TriangleBatch
+{
+	batch: LineBatch,
+}
+
+impl TriangleBatch
+{
+	pub fn new(rs: &RenderState, max_triangles: usize) -> TriangleBatch
+	{
+		TriangleBatch {
+			batch: LineBatch::with_vertices(rs, 3 * max_triangles),
+		}
+	}
+
+	/// Replaces the triangles in the batch. Every three vertices make up a triangle.
+	///
+	/// Triangles exceeding the capacity of the batch are dropped. The GPU must not be using the batch while setting it.
+	pub fn set_triangles(&mut self, vertices: &[ParticleVertex])
+	{
+		debug_assert!(vertices.len() % 3 == 0);
+		self.batch.set_vertices(vertices);
+	}
+
+	/// Draws the triangles. Binds the ribbon pipeline, so call this after drawing opaque geometry.
+	pub fn draw(
+		&self, cmd_buf: vk::CommandBuffer, mp: &MainPass, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+	)
+	{
+		if self.batch.num_vertices == 0
+		{
+			return;
+		}
+		mp.bind_ribbon_pipeline(cmd_buf);
+		self.batch.draw_vertices(cmd_buf, mp, view_matrix, projection_matrix);
+	}
+}
@@ -1,34 +1,321 @@
-use crate::core::{Config, Vertex};
-use crate::renderer::{RenderState, Texture};
-use ash::version::DeviceV1_0;
+use crate::core::{BlendMode, Config, Material, Mesh, MorphUniforms, ParticleVertex, VertexFormat};
+use crate::renderer::{
+	track_create, BufferHandle, IndirectDraws, ObjectKind, PipelineHandle, RenderState, ShadowTile, Texture,
+	MAX_REFLECTION_PROBES,
+};
+use ash::util::Align;
+use ash::version::{DeviceV1_0, DeviceV1_2};
 use ash::vk;
 use ash::Device;
-use cgmath::Matrix4;
+use cgmath::{Matrix4, Point3, Vector3};
+use std::cell::{Cell, RefCell};
 use std::ffi::CString;
-use std::mem::size_of;
+use std::mem::{align_of, size_of, size_of_val};
+use std::os::raw::c_void;
 use std::ptr;
 use std::rc::Rc;
+use std::thread;
+
+/// Most point and directional lights lighting a frame, must match MAX_LIGHTS in phong.frag.
+pub const MAX_LIGHTS: usize = 4;
+// Bindings in the frame descriptor set of the shadow atlas, the first reflection probe, the fragment counts, the
+// irradiance probes and the first G-buffer image
+const SHADOW_ATLAS_BINDING: u32 = 1;
+const FIRST_PROBE_BINDING: u32 = 2;
+const FRAGMENT_COUNTS_BINDING: u32 = FIRST_PROBE_BINDING + MAX_REFLECTION_PROBES as u32;
+const IRRADIANCE_PROBES_BINDING: u32 = FRAGMENT_COUNTS_BINDING + 1;
+const FIRST_GBUFFER_BINDING: u32 = IRRADIANCE_PROBES_BINDING + 1;
+const RENDER_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+// Albedo with the specular strength in alpha, world space normal with the coverage in alpha, and world space position
+const GBUFFER_FORMATS: [vk::Format; 3] =
+	[vk::Format::R16G16B16A16_SFLOAT, vk::Format::R16G16B16A16_SFLOAT, vk::Format::R32G32B32A32_SFLOAT];
+const GBUFFER_NAMES: [&str; 3] = ["albedo", "normal", "position"];
+// Objects with morph weights the descriptor pool has room for, see MorphWeights
+const MAX_MORPHED_OBJECTS: u32 = 32;
+
+/// A light shining on everything from one direction, without shadows.
+#[derive(Clone, Copy)]
+pub struct DirectionalLight
+{
+	/// Towards the light, normalized.
+	pub direction: Vector3<f32>,
+	pub color: [f32; 3],
+}
+
+/// A point or directional light, as seen by the shaders. Must match PointLight in phong.frag (std140).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct LightUniforms
+{
+	// The direction towards directional lights in xyz, with a radius of 0
+	position_radius: [f32; 4],
+	color: [f32; 4],
+	// Atlas texel of the top left corner of the light's faces in xy and the face resolution in z, 0 without shadows
+	shadow_tile: [f32; 4],
+}
+
+impl LightUniforms
+{
+	pub fn new(position: Point3<f32>, radius: f32, color: [f32; 3], shadow_tile: Option<ShadowTile>) -> LightUniforms
+	{
+		let shadow_tile =
+			shadow_tile.map_or([0.0; 4], |tile| [tile.x as f32, tile.y as f32, tile.resolution as f32, 0.0]);
+		LightUniforms {
+			// A radius of 0 would make it a directional light
+			position_radius: [position.x, position.y, position.z, radius.max(std::f32::EPSILON)],
+			color: [color[0], color[1], color[2], 1.0],
+			shadow_tile: shadow_tile,
+		}
+	}
+
+	pub fn from_directional(light: &DirectionalLight) -> LightUniforms
+	{
+		LightUniforms {
+			position_radius: [light.direction.x, light.direction.y, light.direction.z, 0.0],
+			color: [light.color[0], light.color[1], light.color[2], 1.0],
+			shadow_tile: [0.0; 4],
+		}
+	}
+}
+
+/// A reflection probe volume, as seen by the shaders. Must match ReflectionProbe in phong.frag (std140).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ProbeUniforms
+{
+	position: [f32; 4],
+	// Half the size of the box volume along each axis
+	extents: [f32; 4],
+}
+
+impl ProbeUniforms
+{
+	pub fn new(position: Point3<f32>, extents: Vector3<f32>) -> ProbeUniforms
+	{
+		ProbeUniforms {
+			position: [position.x, position.y, position.z, 1.0],
+			extents: [extents.x, extents.y, extents.z, 0.0],
+		}
+	}
+}
+
+/// An irradiance grid, as seen by the shaders. Must match the irradiance members of FrameBlock in phong.frag (std140).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct IrradianceGridUniforms
+{
+	// Position of the first probe
+	origin: [f32; 4],
+	// Probes per meter along each axis, 0 along axes with a single probe
+	inverse_spacing: [f32; 4],
+	// Probes along each axis, 0 without a grid
+	counts: [u32; 4],
+}
+
+impl IrradianceGridUniforms
+{
+	pub fn new(origin: Point3<f32>, inverse_spacing: Vector3<f32>, counts: [u32; 3]) -> IrradianceGridUniforms
+	{
+		IrradianceGridUniforms {
+			origin: [origin.x, origin.y, origin.z, 1.0],
+			inverse_spacing: [inverse_spacing.x, inverse_spacing.y, inverse_spacing.z, 0.0],
+			counts: [counts[0], counts[1], counts[2], 0],
+		}
+	}
+}
+
+/// Per-frame data shared by all draws in the main pass.
+///
+/// Must match the FrameBlock uniform in the shaders (std140).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FrameUniforms
+{
+	pub view_matrix: Matrix4<f32>,
+	pub wetness: f32,
+	light_count: u32,
+	probe_count: u32,
+	// Non-zero to count the fragments drawn to each pixel, see MainPass::set_fragment_counting()
+	count_fragments: u32,
+	// Game time in seconds, for animated materials
+	time: f32,
+	_padding: [f32; 3],
+	lights: [LightUniforms; MAX_LIGHTS],
+	probes: [ProbeUniforms; MAX_REFLECTION_PROBES],
+	irradiance_grid: IrradianceGridUniforms,
+}
+
+impl FrameUniforms
+{
+	/// Lights past MAX_LIGHTS and probes past MAX_REFLECTION_PROBES are ignored.
+	pub fn new(
+		view_matrix: Matrix4<f32>, wetness: f32, time: f32, lights: &[LightUniforms], probes: &[ProbeUniforms],
+		irradiance_grid: IrradianceGridUniforms,
+	) -> FrameUniforms
+	{
+		let light_count = lights.len().min(MAX_LIGHTS);
+		let mut light_uniforms = [LightUniforms::default(); MAX_LIGHTS];
+		light_uniforms[..light_count].copy_from_slice(&lights[..light_count]);
+		let probe_count = probes.len().min(MAX_REFLECTION_PROBES);
+		let mut probe_uniforms = [ProbeUniforms::default(); MAX_REFLECTION_PROBES];
+		probe_uniforms[..probe_count].copy_from_slice(&probes[..probe_count]);
+		FrameUniforms {
+			view_matrix: view_matrix,
+			wetness: wetness,
+			light_count: light_count as u32,
+			probe_count: probe_count as u32,
+			count_fragments: 0,
+			time: time,
+			_padding: [0.0; 3],
+			lights: light_uniforms,
+			probes: probe_uniforms,
+			irradiance_grid: irradiance_grid,
+		}
+	}
+}
+
+/// Shaders and blend state of a material, see MaterialDesc. Each permutation gets its own mesh pipelines.
+#[derive(Clone, PartialEq)]
+pub struct PipelinePermutation
+{
+	/// Precompiled shaders, using the vertex formats and descriptor sets of phong.vert and phong.frag.
+	pub vertex_shader: String,
+	pub fragment_shader: String,
+	/// Set as the NORMAL_MAPPING and EMISSIVE specialization constants.
+	pub normal_mapping: bool,
+	pub emissive: bool,
+	pub blend: BlendMode,
+}
+
+impl Default for PipelinePermutation
+{
+	fn default() -> PipelinePermutation
+	{
+		PipelinePermutation {
+			vertex_shader: "shaders/phong_vert.spv".to_string(),
+			fragment_shader: "shaders/phong_frag.spv".to_string(),
+			normal_mapping: true,
+			emissive: true,
+			blend: BlendMode::Opaque,
+		}
+	}
+}
+
+/// What the mesh pipelines of a permutation draw, see MainPass::create_mesh_pipelines().
+#[derive(Clone, Copy, PartialEq)]
+enum MeshOutput
+{
+	/// The lit surface to the render image.
+	Shaded,
+	/// The surface without the lights to the render image, and the G-buffer to light it from.
+	GBuffer,
+	/// Only the depth, in the depth prepass.
+	Depth,
+}
+
+const MESH_OUTPUTS: [MeshOutput; 3] = [MeshOutput::Shaded, MeshOutput::GBuffer, MeshOutput::Depth];
+
+impl MeshOutput
+{
+	/// Returns the subpass of the main renderpass drawing to the output, see MainPass::create_renderpass().
+	fn get_subpass(self, deferred: bool, depth_prepass: bool) -> u32
+	{
+		return match self
+		{
+			MeshOutput::Depth => 0,
+			MeshOutput::GBuffer => depth_prepass as u32,
+			MeshOutput::Shaded => depth_prepass as u32 + deferred as u32,
+		};
+	}
+
+	/// Whether meshes with the permutation are drawn to the output. Only opaque surfaces are drawn to the depth
+	/// prepass, and only those with the default fragment shader, which writes the G-buffer, are deferred.
+	fn is_drawn(self, permutation: &PipelinePermutation, deferred: bool, depth_prepass: bool) -> bool
+	{
+		let opaque = permutation.blend == BlendMode::Opaque;
+		return match self
+		{
+			MeshOutput::Shaded => true,
+			MeshOutput::GBuffer =>
+			{
+				deferred && opaque && permutation.fragment_shader == PipelinePermutation::default().fragment_shader
+			}
+			MeshOutput::Depth => depth_prepass && opaque,
+		};
+	}
+}
 
 pub struct MainPass
 {
+	// Identifies the pass in captured frame graphs
+	name: &'static str,
 	renderpass: vk::RenderPass,
 	pub descriptor_pool: vk::DescriptorPool,
 	pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
 	pub pipeline_layout: vk::PipelineLayout,
 	viewport: vk::Viewport,
 	scissor: vk::Rect2D,
-	pipeline: vk::Pipeline,
+	// For meshes with full and packed vertices, indexed by permutation and VertexFormat. See
+	// RenderState::get_permutation_index(), the default permutation comes first.
+	pipelines: Vec<[PipelineHandle; 2]>,
+	// Only the default permutation is drawn indirectly
+	indirect_pipelines: [PipelineHandle; 2],
+	// Drawing to the G-buffer when deferred shading, for the permutations that can be, see MeshOutput::is_drawn().
+	// The pipelines above are then used in the lighting subpass.
+	gbuffer_pipelines: Vec<Option<[PipelineHandle; 2]>>,
+	gbuffer_indirect_pipelines: Option<[PipelineHandle; 2]>,
+	// Drawing only the depth in the depth prepass, for the permutations that can be
+	depth_pipelines: Vec<Option<[PipelineHandle; 2]>>,
+	depth_indirect_pipelines: Option<[PipelineHandle; 2]>,
+	lighting_pipeline: Option<PipelineHandle>,
+	particle_pipeline: PipelineHandle,
+	ribbon_pipeline: PipelineHandle,
+	// Permutation and vertex format of the direct mesh pipeline bound in the current frame, if any
+	bound_pipeline: Cell<Option<(usize, VertexFormat)>>,
+	// Whether the current frame still draws to the G-buffer, see finish_geometry()
+	geometry_subpass: Cell<bool>,
+	// Whether the pass starts with a depth prepass, and whether the current frame still draws to it, see
+	// finish_depth_prepass()
+	depth_prepass: bool,
+	drawing_depth: Cell<bool>,
+	// Viewport and scissor of the region drawn to, see set_draw_region()
+	draw_region: Cell<(vk::Viewport, vk::Rect2D)>,
+	indirect_draws: RefCell<IndirectDraws>,
+	// Size of the vertex buffers drawn in the current frame
+	vertex_bytes: Cell<u64>,
 	// one framebuffer/commandbuffer per image
 	framebuffer: vk::Framebuffer,
 	commandbuffer: vk::CommandBuffer,
 
 	// Image to render to.
 	pub render_image: Texture,
-	depth_image: Texture,
+	pub depth_image: Texture,
+	// Rendered to instead of the render and depth images when multisampling, and resolved to them at the end of the
+	// pass
+	multisampled_images: Option<(Texture, Texture)>,
+	samples: vk::SampleCountFlags,
+	// Fragments drawn to each pixel, all in the low and blended ones in the high 16 bits. Only written while counting.
+	pub fragment_counts: Texture,
+	count_fragments: bool,
+	// Images of the geometry subpass when deferred shading, in the order of GBUFFER_FORMATS
+	gbuffer: Option<Vec<Texture>>,
 
-	view_matrix_ub: vk::Buffer,
-	pub view_matrix_ub_mem: vk::DeviceMemory,
-	view_matrix_ds: Vec<vk::DescriptorSet>,
+	frame_ub: BufferHandle,
+	frame_ds: Vec<vk::DescriptorSet>,
+	// Bound until a shadow atlas is given, and to the reflection probe slots without a cube map
+	empty_shadow_atlas: Texture,
+	empty_cube_map: Texture,
+	// Bound to the damage texture of materials without damage
+	empty_damage_texture: Texture,
+	// Bound until an irradiance grid is given
+	empty_irradiance_probes: BufferHandle,
+	irradiance_probes: Cell<vk::Buffer>,
+	// Bound for meshes drawn without morphing, with no targets
+	empty_morph_deltas: BufferHandle,
+	empty_morph_weights: BufferHandle,
+	default_morph_ds: vk::DescriptorSet,
+	// Views bound to the image bindings of the frame descriptor set, starting at the shadow atlas binding
+	image_views: RefCell<Vec<vk::ImageView>>,
 
 	// Keep a pointer to the device for cleanup
 	device: Rc<Device>,
@@ -37,8 +324,25 @@ pub struct MainPass
 impl MainPass
 {
 	/// Creates a main renderpass.
-	fn create_renderpass(rs: &RenderState, render_format: vk::Format) -> vk::RenderPass
+	///
+	/// When multisampling, the pass renders to multisampled color and depth attachments, and resolves them to the
+	/// render and depth images at the end. The depth resolve needs a Vulkan 1.2 renderpass. When deferred shading, the
+	/// pass has a geometry and a lighting subpass, see create_deferred_renderpass(). With a depth prepass, a subpass
+	/// drawing only the depth comes before them, see get_depth_prepass_dependency().
+	fn create_renderpass(
+		rs: &RenderState, name: &str, render_format: vk::Format, samples: vk::SampleCountFlags, deferred: bool,
+		depth_prepass: bool,
+	) -> vk::RenderPass
 	{
+		if samples != vk::SampleCountFlags::TYPE_1
+		{
+			return MainPass::create_multisampled_renderpass(rs, name, render_format, samples, depth_prepass);
+		}
+		if deferred
+		{
+			return MainPass::create_deferred_renderpass(rs, name, render_format, depth_prepass);
+		}
+
 		// One attachment, color only. Will produce the presentable image.
 		let renderpass_attachments = [
 			vk::AttachmentDescription {
@@ -57,7 +361,8 @@ impl MainPass
 				flags: vk::AttachmentDescriptionFlags::empty(),
 				samples: vk::SampleCountFlags::TYPE_1,
 				load_op: vk::AttachmentLoadOp::CLEAR,
-				store_op: vk::AttachmentStoreOp::DONT_CARE,
+				// Kept for the sun shafts when presenting
+				store_op: vk::AttachmentStoreOp::STORE,
 				stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
 				stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
 				initial_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
@@ -72,50 +377,350 @@ impl MainPass
 			attachment: 1,
 			layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
 		};
-		let subpass = vk::SubpassDescription {
+		let mut subpasses = Vec::new();
+		if depth_prepass
+		{
+			subpasses.push(MainPass::get_depth_prepass_subpass(&depth_attachment_ref));
+		}
+		subpasses.push(vk::SubpassDescription {
 			color_attachment_count: 1,
 			p_color_attachments: &color_attachment_ref,
 			p_depth_stencil_attachment: &depth_attachment_ref,
 			pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
 			..Default::default()
+		});
+		let dependency = MainPass::get_depth_prepass_dependency();
+		let renderpass_create_info = vk::RenderPassCreateInfo {
+			s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
+			attachment_count: renderpass_attachments.len() as u32,
+			p_attachments: renderpass_attachments.as_ptr(),
+			subpass_count: subpasses.len() as u32,
+			p_subpasses: subpasses.as_ptr(),
+			dependency_count: depth_prepass as u32,
+			p_dependencies: &dependency,
+			..Default::default()
+		};
+		let renderpass;
+		unsafe {
+			renderpass = rs.device.create_render_pass(&renderpass_create_info, None).unwrap();
+		}
+		rs.register_pass(&format!("{}: scene", name), &renderpass_attachments);
+
+		renderpass
+	}
+
+	/// Returns the depth prepass subpass, which only draws to the depth attachment.
+	fn get_depth_prepass_subpass(depth_attachment_ref: &vk::AttachmentReference) -> vk::SubpassDescription
+	{
+		return vk::SubpassDescription {
+			p_depth_stencil_attachment: depth_attachment_ref,
+			pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+			..Default::default()
+		};
+	}
+
+	/// Returns the dependency of the first subpass after the depth prepass on it: the depth must be written before it
+	/// is tested against.
+	fn get_depth_prepass_dependency() -> vk::SubpassDependency
+	{
+		return vk::SubpassDependency {
+			src_subpass: 0,
+			dst_subpass: 1,
+			src_stage_mask: vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+			dst_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+			src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			dst_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+				vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			dependency_flags: vk::DependencyFlags::BY_REGION,
 		};
+	}
+
+	/// Creates a main renderpass that shades the opaque surfaces once per pixel, after they are drawn.
+	///
+	/// The geometry subpass writes the surfaces to the G-buffer, and their bounce light, reflections and emission to
+	/// the render image. The lighting subpass then adds the lights of each G-buffer pixel to the render image, and
+	/// draws whatever cannot be deferred forward on top, see finish_geometry(). The G-buffer is never stored.
+	fn create_deferred_renderpass(
+		rs: &RenderState, name: &str, render_format: vk::Format, depth_prepass: bool,
+	) -> vk::RenderPass
+	{
+		let mut renderpass_attachments = vec![
+			vk::AttachmentDescription {
+				format: render_format,
+				flags: vk::AttachmentDescriptionFlags::empty(),
+				samples: vk::SampleCountFlags::TYPE_1,
+				load_op: vk::AttachmentLoadOp::CLEAR,
+				store_op: vk::AttachmentStoreOp::STORE,
+				stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+				stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+				initial_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+				final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+			},
+			vk::AttachmentDescription {
+				format: vk::Format::D32_SFLOAT,
+				flags: vk::AttachmentDescriptionFlags::empty(),
+				samples: vk::SampleCountFlags::TYPE_1,
+				load_op: vk::AttachmentLoadOp::CLEAR,
+				// Kept for the sun shafts when presenting
+				store_op: vk::AttachmentStoreOp::STORE,
+				stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+				stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+				initial_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+				final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+			},
+		];
+		renderpass_attachments.extend(GBUFFER_FORMATS.iter().map(|&format| vk::AttachmentDescription {
+			format: format,
+			flags: vk::AttachmentDescriptionFlags::empty(),
+			samples: vk::SampleCountFlags::TYPE_1,
+			load_op: vk::AttachmentLoadOp::CLEAR,
+			store_op: vk::AttachmentStoreOp::DONT_CARE,
+			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			initial_layout: vk::ImageLayout::UNDEFINED,
+			final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+		}));
+		let reference = |attachment: u32, layout: vk::ImageLayout| vk::AttachmentReference {
+			attachment: attachment,
+			layout: layout,
+		};
+		let geometry_color_refs = [
+			reference(0, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+			reference(2, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+			reference(3, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+			reference(4, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+		];
+		let depth_attachment_ref = reference(1, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+		let input_refs = [
+			reference(2, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+			reference(3, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+			reference(4, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+		];
+		let mut subpasses = Vec::new();
+		if depth_prepass
+		{
+			subpasses.push(MainPass::get_depth_prepass_subpass(&depth_attachment_ref));
+		}
+		subpasses.extend_from_slice(&[
+			vk::SubpassDescription {
+				color_attachment_count: geometry_color_refs.len() as u32,
+				p_color_attachments: geometry_color_refs.as_ptr(),
+				p_depth_stencil_attachment: &depth_attachment_ref,
+				pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+				..Default::default()
+			},
+			vk::SubpassDescription {
+				color_attachment_count: 1,
+				p_color_attachments: &geometry_color_refs[0],
+				input_attachment_count: input_refs.len() as u32,
+				p_input_attachments: input_refs.as_ptr(),
+				p_depth_stencil_attachment: &depth_attachment_ref,
+				pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+				..Default::default()
+			},
+		]);
+		// The lighting reads the G-buffer pixel written at the same place, and the forward draws test against the depth
+		// of the geometry
+		let geometry_subpass = depth_prepass as u32;
+		let mut dependencies = vec![vk::SubpassDependency {
+			src_subpass: geometry_subpass,
+			dst_subpass: geometry_subpass + 1,
+			src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+				vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+			dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER |
+				vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+				vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+			src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			dst_access_mask: vk::AccessFlags::INPUT_ATTACHMENT_READ |
+				vk::AccessFlags::COLOR_ATTACHMENT_READ |
+				vk::AccessFlags::COLOR_ATTACHMENT_WRITE |
+				vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+				vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			dependency_flags: vk::DependencyFlags::BY_REGION,
+		}];
+		if depth_prepass
+		{
+			dependencies.push(MainPass::get_depth_prepass_dependency());
+		}
 		let renderpass_create_info = vk::RenderPassCreateInfo {
 			s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
 			attachment_count: renderpass_attachments.len() as u32,
 			p_attachments: renderpass_attachments.as_ptr(),
-			subpass_count: 1,
-			p_subpasses: &subpass,
+			subpass_count: subpasses.len() as u32,
+			p_subpasses: subpasses.as_ptr(),
+			dependency_count: dependencies.len() as u32,
+			p_dependencies: dependencies.as_ptr(),
 			..Default::default()
 		};
 		let renderpass;
 		unsafe {
 			renderpass = rs.device.create_render_pass(&renderpass_create_info, None).unwrap();
 		}
+		rs.register_pass(&format!("{}: scene", name), &renderpass_attachments);
+
+		renderpass
+	}
+
+	fn create_multisampled_renderpass(
+		rs: &RenderState, name: &str, render_format: vk::Format, samples: vk::SampleCountFlags, depth_prepass: bool,
+	) -> vk::RenderPass
+	{
+		// Only the resolved images are kept
+		let multisampled_attachment = |format: vk::Format, layout: vk::ImageLayout| vk::AttachmentDescription {
+			format: format,
+			flags: vk::AttachmentDescriptionFlags::empty(),
+			samples: samples,
+			load_op: vk::AttachmentLoadOp::CLEAR,
+			store_op: vk::AttachmentStoreOp::DONT_CARE,
+			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			initial_layout: vk::ImageLayout::UNDEFINED,
+			final_layout: layout,
+		};
+		let resolve_attachment = |format: vk::Format, layout: vk::ImageLayout| vk::AttachmentDescription {
+			format: format,
+			flags: vk::AttachmentDescriptionFlags::empty(),
+			samples: vk::SampleCountFlags::TYPE_1,
+			load_op: vk::AttachmentLoadOp::DONT_CARE,
+			store_op: vk::AttachmentStoreOp::STORE,
+			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			initial_layout: layout,
+			final_layout: layout,
+		};
+		let renderpass_attachments = [
+			multisampled_attachment(render_format, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+			multisampled_attachment(vk::Format::D32_SFLOAT, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+			resolve_attachment(render_format, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+			// Kept for the sun shafts when presenting, from the first sample
+			resolve_attachment(vk::Format::D32_SFLOAT, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+		];
+		let attachments2: Vec<vk::AttachmentDescription2> = renderpass_attachments
+			.iter()
+			.map(|attachment| vk::AttachmentDescription2 {
+				flags: attachment.flags,
+				format: attachment.format,
+				samples: attachment.samples,
+				load_op: attachment.load_op,
+				store_op: attachment.store_op,
+				stencil_load_op: attachment.stencil_load_op,
+				stencil_store_op: attachment.stencil_store_op,
+				initial_layout: attachment.initial_layout,
+				final_layout: attachment.final_layout,
+				..Default::default()
+			})
+			.collect();
+		let reference =
+			|attachment: u32, layout: vk::ImageLayout, aspect_mask: vk::ImageAspectFlags| vk::AttachmentReference2 {
+				attachment: attachment,
+				layout: layout,
+				aspect_mask: aspect_mask,
+				..Default::default()
+			};
+		let color_attachment_ref = reference(0, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, vk::ImageAspectFlags::COLOR);
+		let depth_attachment_ref =
+			reference(1, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL, vk::ImageAspectFlags::DEPTH);
+		let color_resolve_ref = reference(2, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, vk::ImageAspectFlags::COLOR);
+		let depth_resolve_ref =
+			reference(3, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL, vk::ImageAspectFlags::DEPTH);
+		let depth_resolve = vk::SubpassDescriptionDepthStencilResolve {
+			depth_resolve_mode: vk::ResolveModeFlags::SAMPLE_ZERO,
+			stencil_resolve_mode: vk::ResolveModeFlags::NONE,
+			p_depth_stencil_resolve_attachment: &depth_resolve_ref,
+			..Default::default()
+		};
+		let mut subpasses = Vec::new();
+		if depth_prepass
+		{
+			subpasses.push(vk::SubpassDescription2 {
+				pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+				p_depth_stencil_attachment: &depth_attachment_ref,
+				..Default::default()
+			});
+		}
+		subpasses.push(vk::SubpassDescription2 {
+			p_next: &depth_resolve as *const vk::SubpassDescriptionDepthStencilResolve as *const c_void,
+			pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+			color_attachment_count: 1,
+			p_color_attachments: &color_attachment_ref,
+			p_resolve_attachments: &color_resolve_ref,
+			p_depth_stencil_attachment: &depth_attachment_ref,
+			..Default::default()
+		});
+		let dependency = MainPass::get_depth_prepass_dependency();
+		let dependency2 = vk::SubpassDependency2 {
+			src_subpass: dependency.src_subpass,
+			dst_subpass: dependency.dst_subpass,
+			src_stage_mask: dependency.src_stage_mask,
+			dst_stage_mask: dependency.dst_stage_mask,
+			src_access_mask: dependency.src_access_mask,
+			dst_access_mask: dependency.dst_access_mask,
+			dependency_flags: dependency.dependency_flags,
+			..Default::default()
+		};
+		let renderpass_create_info = vk::RenderPassCreateInfo2 {
+			attachment_count: attachments2.len() as u32,
+			p_attachments: attachments2.as_ptr(),
+			subpass_count: subpasses.len() as u32,
+			p_subpasses: subpasses.as_ptr(),
+			dependency_count: depth_prepass as u32,
+			p_dependencies: &dependency2,
+			..Default::default()
+		};
+		let renderpass;
+		unsafe {
+			renderpass = rs.device.create_render_pass2(&renderpass_create_info, None).unwrap();
+		}
+		rs.register_pass(&format!("{}: scene", name), &renderpass_attachments);
 
 		renderpass
 	}
 
-	/// Creates a pipeline for the renderpass.
+	/// Creates the descriptor sets, pipeline layout and the pipelines of the default permutation, see
+	/// create_default_pipelines().
 	fn create_pipeline(
-		rs: &RenderState, render_size: vk::Extent3D, renderpass: vk::RenderPass,
-	) -> (vk::DescriptorPool, Vec<vk::DescriptorSetLayout>, vk::PipelineLayout, vk::Viewport, vk::Rect2D, vk::Pipeline)
+		rs: &RenderState, render_size: vk::Extent3D, renderpass: vk::RenderPass, samples: vk::SampleCountFlags,
+		deferred: bool, depth_prepass: bool,
+	) -> (
+		vk::DescriptorPool,
+		Vec<vk::DescriptorSetLayout>,
+		vk::PipelineLayout,
+		vk::Viewport,
+		vk::Rect2D,
+		[Vec<vk::Pipeline>; 3],
+	)
 	{
 		// Descriptors
 		let descriptor_sizes = [
 			vk::DescriptorPoolSize {
 				ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-				descriptor_count: 14,
+				descriptor_count: 29,
 			},
+			// Morph sets of the morphed objects, and the default one
 			vk::DescriptorPoolSize {
 				ty: vk::DescriptorType::UNIFORM_BUFFER,
+				descriptor_count: 9 + MAX_MORPHED_OBJECTS + 1,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::STORAGE_BUFFER,
+				descriptor_count: 3 + MAX_MORPHED_OBJECTS + 1,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::STORAGE_IMAGE,
 				descriptor_count: 1,
 			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::INPUT_ATTACHMENT,
+				descriptor_count: GBUFFER_FORMATS.len() as u32,
+			},
 		];
 		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
 			pool_size_count: descriptor_sizes.len() as u32,
 			p_pool_sizes: descriptor_sizes.as_ptr(),
-			max_sets: 8, // TODO figure out how to properly do this
+			max_sets: 10 + MAX_MORPHED_OBJECTS + 1, // TODO figure out how to properly do this
+			// Materials are released while running
+			flags: vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
 			..Default::default()
 		};
 		let descriptor_pool;
@@ -137,24 +742,118 @@ impl MainPass
 				stage_flags: vk::ShaderStageFlags::FRAGMENT,
 				p_immutable_samplers: ptr::null(),
 			},
+			vk::DescriptorSetLayoutBinding {
+				binding: 2,
+				descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// Damage texture, see DamageBaker
+			vk::DescriptorSetLayoutBinding {
+				binding: 3,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
 		];
-		let view_matrix_dsl_binding = [vk::DescriptorSetLayoutBinding {
+		// Frame uniforms, followed by the shadow atlas, reflection probes, fragment counts, irradiance probes and the
+		// G-buffer, which is only read by the lighting subpass
+		let frame_dsl_binding: Vec<vk::DescriptorSetLayoutBinding> = [vk::DescriptorSetLayoutBinding {
 			binding: 0,
 			descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
 			descriptor_count: 1,
-			stage_flags: vk::ShaderStageFlags::VERTEX,
+			stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
 			p_immutable_samplers: ptr::null(),
-		}];
+		}]
+		.iter()
+		.cloned()
+		.chain((SHADOW_ATLAS_BINDING..FRAGMENT_COUNTS_BINDING).map(|binding| vk::DescriptorSetLayoutBinding {
+			binding: binding,
+			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			descriptor_count: 1,
+			stage_flags: vk::ShaderStageFlags::FRAGMENT,
+			p_immutable_samplers: ptr::null(),
+		}))
+		.chain(std::iter::once(vk::DescriptorSetLayoutBinding {
+			binding: FRAGMENT_COUNTS_BINDING,
+			descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+			descriptor_count: 1,
+			stage_flags: vk::ShaderStageFlags::FRAGMENT,
+			p_immutable_samplers: ptr::null(),
+		}))
+		.chain(std::iter::once(vk::DescriptorSetLayoutBinding {
+			binding: IRRADIANCE_PROBES_BINDING,
+			descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+			descriptor_count: 1,
+			stage_flags: vk::ShaderStageFlags::FRAGMENT,
+			p_immutable_samplers: ptr::null(),
+		}))
+		.chain((0..GBUFFER_FORMATS.len() as u32).map(|idx| vk::DescriptorSetLayoutBinding {
+			binding: FIRST_GBUFFER_BINDING + idx,
+			descriptor_type: vk::DescriptorType::INPUT_ATTACHMENT,
+			descriptor_count: 1,
+			stage_flags: vk::ShaderStageFlags::FRAGMENT,
+			p_immutable_samplers: ptr::null(),
+		}))
+		.collect();
+		// Draws and materials of indirect draws
+		let indirect_dsl_bindings = [
+			vk::DescriptorSetLayoutBinding {
+				binding: 0,
+				descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::VERTEX,
+				p_immutable_samplers: ptr::null(),
+			},
+			vk::DescriptorSetLayoutBinding {
+				binding: 1,
+				descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+		];
+		// Morph targets of the mesh and weights of the object, see MorphWeights
+		let morph_dsl_bindings = [
+			vk::DescriptorSetLayoutBinding {
+				binding: 0,
+				descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::VERTEX,
+				p_immutable_samplers: ptr::null(),
+			},
+			vk::DescriptorSetLayoutBinding {
+				binding: 1,
+				descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::VERTEX,
+				p_immutable_samplers: ptr::null(),
+			},
+		];
 		let color_normal_tex_info = vk::DescriptorSetLayoutCreateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
 			binding_count: color_normal_tex_dsl_bindings.len() as u32,
 			p_bindings: color_normal_tex_dsl_bindings.as_ptr(),
 			..Default::default()
 		};
-		let view_matrix_info = vk::DescriptorSetLayoutCreateInfo {
+		let frame_info = vk::DescriptorSetLayoutCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+			binding_count: frame_dsl_binding.len() as u32,
+			p_bindings: frame_dsl_binding.as_ptr(),
+			..Default::default()
+		};
+		let indirect_info = vk::DescriptorSetLayoutCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+			binding_count: indirect_dsl_bindings.len() as u32,
+			p_bindings: indirect_dsl_bindings.as_ptr(),
+			..Default::default()
+		};
+		let morph_info = vk::DescriptorSetLayoutCreateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
-			binding_count: view_matrix_dsl_binding.len() as u32,
-			p_bindings: view_matrix_dsl_binding.as_ptr(),
+			binding_count: morph_dsl_bindings.len() as u32,
+			p_bindings: morph_dsl_bindings.as_ptr(),
 			..Default::default()
 		};
 
@@ -162,7 +861,9 @@ impl MainPass
 		unsafe {
 			descriptor_set_layouts = [
 				rs.device.create_descriptor_set_layout(&color_normal_tex_info, None).unwrap(),
-				rs.device.create_descriptor_set_layout(&view_matrix_info, None).unwrap(),
+				rs.device.create_descriptor_set_layout(&frame_info, None).unwrap(),
+				rs.device.create_descriptor_set_layout(&indirect_info, None).unwrap(),
+				rs.device.create_descriptor_set_layout(&morph_info, None).unwrap(),
 			];
 		}
 
@@ -186,8 +887,132 @@ impl MainPass
 			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
 		}
 
-		let vertex_shader_module = rs.load_shader("shaders/phong_vert.spv");
-		let fragment_shader_module = rs.load_shader("shaders/phong_frag.spv");
+		let (viewport, scissor) = MainPass::get_viewport(render_size);
+		let graphics_pipelines =
+			MainPass::create_default_pipelines(rs, renderpass, pipeline_layout, samples, deferred, depth_prepass);
+
+		(descriptor_pool, descriptor_set_layouts.to_vec(), pipeline_layout, viewport, scissor, graphics_pipelines)
+	}
+
+	/// Creates the direct and indirect pipelines of the default permutation for each output, see
+	/// create_permutation_pipelines().
+	fn create_default_pipelines(
+		rs: &RenderState, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout,
+		samples: vk::SampleCountFlags, deferred: bool, depth_prepass: bool,
+	) -> [Vec<vk::Pipeline>; 3]
+	{
+		let permutation = PipelinePermutation::default();
+		let shader_modules = MainPass::load_mesh_shaders(rs, &permutation);
+		let graphics_pipelines = MainPass::create_permutation_pipelines(
+			&rs.device,
+			rs.pipeline_cache,
+			renderpass,
+			pipeline_layout,
+			&permutation,
+			shader_modules,
+			None,
+			samples,
+			rs.get_packed_vertex_format(),
+			deferred,
+			depth_prepass,
+		);
+		MainPass::finish_mesh_pipelines(rs, &permutation, shader_modules, &graphics_pipelines);
+		return graphics_pipelines;
+	}
+
+	/// Creates the pipelines of a permutation for each output it is drawn to, in the order of MESH_OUTPUTS, see
+	/// create_mesh_pipelines(). Outputs it is not drawn to get none. Base pipelines are those of the default
+	/// permutation, in the same order.
+	fn create_permutation_pipelines(
+		device: &Device, pipeline_cache: vk::PipelineCache, renderpass: vk::RenderPass,
+		pipeline_layout: vk::PipelineLayout, permutation: &PipelinePermutation, shader_modules: [vk::ShaderModule; 2],
+		base_pipelines: Option<&[Vec<vk::Pipeline>; 3]>, samples: vk::SampleCountFlags,
+		packed_vertex_format: VertexFormat, deferred: bool, depth_prepass: bool,
+	) -> [Vec<vk::Pipeline>; 3]
+	{
+		let mut pipelines: [Vec<vk::Pipeline>; 3] = Default::default();
+		for (idx, &output) in MESH_OUTPUTS.iter().enumerate()
+		{
+			if output.is_drawn(permutation, deferred, depth_prepass)
+			{
+				pipelines[idx] = MainPass::create_mesh_pipelines(
+					device,
+					pipeline_cache,
+					renderpass,
+					pipeline_layout,
+					permutation,
+					shader_modules,
+					base_pipelines.map(|base_pipelines| [base_pipelines[idx][0], base_pipelines[idx][1]]),
+					samples,
+					packed_vertex_format,
+					output.get_subpass(deferred, depth_prepass),
+					output,
+				);
+			}
+		}
+		return pipelines;
+	}
+
+	/// Loads the vertex and fragment shaders of a permutation.
+	fn load_mesh_shaders(rs: &RenderState, permutation: &PipelinePermutation) -> [vk::ShaderModule; 2]
+	{
+		let vertex_shader_module = rs.load_shader(&permutation.vertex_shader, &[]);
+		let fragment_shader_module = rs.load_shader(
+			&permutation.fragment_shader,
+			&[
+				("MAX_LIGHTS", format!("{}u", MAX_LIGHTS)),
+				("MAX_REFLECTION_PROBES", format!("{}u", MAX_REFLECTION_PROBES)),
+			],
+		);
+		return [vertex_shader_module, fragment_shader_module];
+	}
+
+	/// Creates the pipelines of a permutation for meshes with full and packed vertices, see VertexFormat, from its
+	/// loaded shaders.
+	///
+	/// Without base pipelines, this is the default permutation: its pipelines allow derivatives, and are followed by
+	/// the indirect ones, see IndirectDraws. Other permutations derive from the default full and packed pipelines,
+	/// which lets the driver reuse their state. Only the device is used, so permutations can be created on several
+	/// threads.
+	///
+	/// The pipelines are drawn in the given subpass, to the given output. Depth pipelines have no fragment shader.
+	fn create_mesh_pipelines(
+		device: &Device, pipeline_cache: vk::PipelineCache, renderpass: vk::RenderPass,
+		pipeline_layout: vk::PipelineLayout, permutation: &PipelinePermutation, shader_modules: [vk::ShaderModule; 2],
+		base_pipelines: Option<[vk::Pipeline; 2]>, samples: vk::SampleCountFlags, packed_vertex_format: VertexFormat,
+		subpass: u32, output: MeshOutput,
+	) -> Vec<vk::Pipeline>
+	{
+		let [vertex_shader_module, fragment_shader_module] = shader_modules;
+
+		// The INDIRECT, NORMAL_MAPPING, EMISSIVE, BLENDED and DEFERRED specialization constants, of the direct and
+		// indirect pipelines
+		let direct_constants = [
+			vk::FALSE,
+			permutation.normal_mapping as vk::Bool32,
+			permutation.emissive as vk::Bool32,
+			(permutation.blend != BlendMode::Opaque) as vk::Bool32,
+			(output == MeshOutput::GBuffer) as vk::Bool32,
+		];
+		let mut indirect_constants = direct_constants;
+		indirect_constants[0] = vk::TRUE;
+		let constants = [direct_constants, indirect_constants];
+		let map_entries: Vec<vk::SpecializationMapEntry> = (0..direct_constants.len() as u32)
+			.map(|id| vk::SpecializationMapEntry {
+				constant_id: id,
+				offset: id * size_of::<vk::Bool32>() as u32,
+				size: size_of::<vk::Bool32>(),
+			})
+			.collect();
+		let specialization_infos: Vec<vk::SpecializationInfo> = constants
+			.iter()
+			.map(|constants| vk::SpecializationInfo {
+				map_entry_count: map_entries.len() as u32,
+				p_map_entries: map_entries.as_ptr(),
+				data_size: size_of_val(constants),
+				p_data: constants.as_ptr() as *const _,
+			})
+			.collect();
 
 		let shader_entry_name = CString::new("main").unwrap();
 		let shader_stage_create_infos = [
@@ -196,6 +1021,7 @@ impl MainPass
 				module: vertex_shader_module,
 				p_name: shader_entry_name.as_ptr(),
 				stage: vk::ShaderStageFlags::VERTEX,
+				p_specialization_info: &specialization_infos[0],
 				..Default::default()
 			},
 			vk::PipelineShaderStageCreateInfo {
@@ -203,105 +1029,313 @@ impl MainPass
 				module: fragment_shader_module,
 				p_name: shader_entry_name.as_ptr(),
 				stage: vk::ShaderStageFlags::FRAGMENT,
+				p_specialization_info: &specialization_infos[0],
 				..Default::default()
 			},
 		];
+		let indirect_shader_stage_create_infos = [
+			vk::PipelineShaderStageCreateInfo {
+				p_specialization_info: &specialization_infos[1],
+				..shader_stage_create_infos[0]
+			},
+			vk::PipelineShaderStageCreateInfo {
+				p_specialization_info: &specialization_infos[1],
+				..shader_stage_create_infos[1]
+			},
+		];
 
-		// TODO: These would probably do better to live where the Vertex struct is defined.
-		let vertex_binding_description = vk::VertexInputBindingDescription {
-			binding: 0,
-			stride: size_of::<Vertex>() as u32,
-			input_rate: vk::VertexInputRate::VERTEX,
-		};
-
-		let vertex_position_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 0,
-			format: vk::Format::R32G32B32_SFLOAT,
-			offset: 0 as u32,
+		// Pipelines for packed vertices the device can't read are never bound, see
+		// RenderState::get_packed_vertex_format()
+		let vertex_formats = [VertexFormat::Full, packed_vertex_format];
+		let vertex_input_binding_descriptions: Vec<[vk::VertexInputBindingDescription; 1]> = vertex_formats
+			.iter()
+			.map(|format| {
+				[vk::VertexInputBindingDescription {
+					binding: 0,
+					stride: format.get_stride(),
+					input_rate: vk::VertexInputRate::VERTEX,
+				}]
+			})
+			.collect();
+		let vertex_input_attribute_descriptions =
+			[vertex_formats[0].get_attribute_descriptions(), vertex_formats[1].get_attribute_descriptions()];
+		let vertex_input_state_infos: Vec<vk::PipelineVertexInputStateCreateInfo> = vertex_input_binding_descriptions
+			.iter()
+			.zip(vertex_input_attribute_descriptions.iter())
+			.map(|(bindings, attributes)| vk::PipelineVertexInputStateCreateInfo {
+				s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
+				p_next: ptr::null(),
+				flags: Default::default(),
+				vertex_attribute_description_count: attributes.len() as u32,
+				p_vertex_attribute_descriptions: attributes.as_ptr(),
+				vertex_binding_description_count: bindings.len() as u32,
+				p_vertex_binding_descriptions: bindings.as_ptr(),
+			})
+			.collect();
+		let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_INPUT_ASSEMBLY_STATE_CREATE_INFO,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			primitive_restart_enable: 0,
+			topology: vk::PrimitiveTopology::TRIANGLE_LIST,
 		};
-
-		let vertex_normal_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 1,
-			format: vk::Format::R32G32B32_SFLOAT,
-			offset: 3 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
+		// Viewport and scissor are dynamic, but the counts must still be given.
+		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VIEWPORT_STATE_CREATE_INFO,
+			scissor_count: 1,
+			viewport_count: 1,
+			..Default::default()
 		};
-
-		let vertex_tangent_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 2,
-			format: vk::Format::R32G32B32_SFLOAT,
-			offset: 6 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
+		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
+			cull_mode: vk::CullModeFlags::BACK,
+			front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+			line_width: 1.0,
+			polygon_mode: vk::PolygonMode::FILL,
+			..Default::default()
 		};
-
-		let vertex_bitangent_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 3,
-			format: vk::Format::R32G32B32_SFLOAT,
-			offset: 9 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
+		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
+			rasterization_samples: samples,
+			..Default::default()
 		};
-
-		let vertex_texcoord_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 4,
-			format: vk::Format::R32G32_SFLOAT,
-			offset: 12 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
+		let noop_stencil_state = vk::StencilOpState {
+			fail_op: vk::StencilOp::KEEP,
+			pass_op: vk::StencilOp::KEEP,
+			depth_fail_op: vk::StencilOp::KEEP,
+			compare_op: vk::CompareOp::ALWAYS,
+			..Default::default()
 		};
+		let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DEPTH_STENCIL_STATE_CREATE_INFO,
+			depth_test_enable: 1,
+			// Blended surfaces do not hide what is behind them
+			depth_write_enable: (permutation.blend == BlendMode::Opaque) as vk::Bool32,
+			depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+			front: noop_stencil_state.clone(),
+			back: noop_stencil_state.clone(),
+			max_depth_bounds: 1.0,
+			min_depth_bounds: 0.0,
+			..Default::default()
+		};
+		let blend_attachment_state = match permutation.blend
+		{
+			BlendMode::Opaque => vk::PipelineColorBlendAttachmentState {
+				blend_enable: 0,
+				color_write_mask: vk::ColorComponentFlags::all(),
+				..Default::default()
+			},
+			BlendMode::Alpha | BlendMode::Additive => vk::PipelineColorBlendAttachmentState {
+				blend_enable: 1,
+				src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+				dst_color_blend_factor: if permutation.blend == BlendMode::Alpha
+				{
+					vk::BlendFactor::ONE_MINUS_SRC_ALPHA
+				}
+				else
+				{
+					vk::BlendFactor::ONE
+				},
+				color_blend_op: vk::BlendOp::ADD,
+				src_alpha_blend_factor: vk::BlendFactor::ONE,
+				dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+				alpha_blend_op: vk::BlendOp::ADD,
+				color_write_mask: vk::ColorComponentFlags::all(),
+			},
+		};
+		// The G-buffer is written like the render image
+		let attachment_count = match output
+		{
+			MeshOutput::Shaded => 1,
+			MeshOutput::GBuffer => 1 + GBUFFER_FORMATS.len(),
+			MeshOutput::Depth => 0,
+		};
+		let stage_count = if output == MeshOutput::Depth
+		{
+			1
+		}
+		else
+		{
+			shader_stage_create_infos.len() as u32
+		};
+		let color_blend_attachment_states = vec![blend_attachment_state; attachment_count];
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
+			attachment_count: color_blend_attachment_states.len() as u32,
+			p_attachments: color_blend_attachment_states.as_ptr(),
+			..Default::default()
+		};
+		let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
+			dynamic_state_count: dynamic_state.len() as u32,
+			p_dynamic_states: dynamic_state.as_ptr(),
+			..Default::default()
+		};
+		let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			s_type: vk::StructureType::GRAPHICS_PIPELINE_CREATE_INFO,
+			stage_count: stage_count,
+			p_stages: shader_stage_create_infos.as_ptr(),
+			p_vertex_input_state: &vertex_input_state_infos[0],
+			p_input_assembly_state: &vertex_input_assembly_state_info,
+			p_viewport_state: &viewport_state_info,
+			p_rasterization_state: &rasterization_info,
+			p_multisample_state: &multisample_state_info,
+			p_depth_stencil_state: &depth_state_info,
+			p_color_blend_state: &color_blend_state,
+			p_dynamic_state: &dynamic_state_info,
+			layout: pipeline_layout,
+			render_pass: renderpass,
+			subpass: subpass,
+			..Default::default()
+		};
+		let (graphic_pipeline_info, packed_base_pipeline) = match base_pipelines
+		{
+			Some([base_pipeline, packed_base_pipeline]) => (
+				vk::GraphicsPipelineCreateInfo {
+					flags: vk::PipelineCreateFlags::DERIVATIVE,
+					base_pipeline_handle: base_pipeline,
+					base_pipeline_index: -1,
+					..graphic_pipeline_info
+				},
+				packed_base_pipeline,
+			),
+			None => (
+				vk::GraphicsPipelineCreateInfo {
+					flags: vk::PipelineCreateFlags::ALLOW_DERIVATIVES,
+					..graphic_pipeline_info
+				},
+				vk::Pipeline::null(),
+			),
+		};
+		let packed_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			p_vertex_input_state: &vertex_input_state_infos[1],
+			base_pipeline_handle: packed_base_pipeline,
+			..graphic_pipeline_info
+		};
+		let indirect_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			p_stages: indirect_shader_stage_create_infos.as_ptr(),
+			..graphic_pipeline_info
+		};
+		let packed_indirect_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			p_stages: indirect_shader_stage_create_infos.as_ptr(),
+			..packed_pipeline_info
+		};
+		let mut pipeline_infos = vec![graphic_pipeline_info, packed_pipeline_info];
+		if base_pipelines.is_none()
+		{
+			pipeline_infos.extend_from_slice(&[indirect_pipeline_info, packed_indirect_pipeline_info]);
+		}
+		let graphics_pipelines;
+		unsafe {
+			graphics_pipelines = device
+				.create_graphics_pipelines(pipeline_cache, &pipeline_infos, None)
+				.expect("Unable to create graphics pipeline");
+		}
+
+		graphics_pipelines
+	}
+
+	/// Tracks the pipelines of a permutation for each output, see create_permutation_pipelines(), and destroys its
+	/// shaders now that the pipelines are created. Must be called on the main thread, which tracks the Vulkan objects.
+	fn finish_mesh_pipelines(
+		rs: &RenderState, permutation: &PipelinePermutation, shader_modules: [vk::ShaderModule; 2],
+		pipelines: &[Vec<vk::Pipeline>; 3],
+	)
+	{
+		unsafe {
+			for &shader_module in shader_modules.iter()
+			{
+				rs.device.destroy_shader_module(shader_module, None);
+			}
+		}
+		let tags = ["main pass", "main pass packed", "main pass indirect", "main pass packed indirect"];
+		let output_tags = ["", " G-buffer", " depth"];
+		for (output_pipelines, output_tag) in pipelines.iter().zip(output_tags.iter())
+		{
+			for (&pipeline, tag) in output_pipelines.iter().zip(tags.iter())
+			{
+				track_create(
+					ObjectKind::Pipeline,
+					pipeline,
+					&format!("{}{} ({})", tag, output_tag, permutation.fragment_shader),
+				);
+			}
+		}
+	}
+
+	/// Creates a pipeline for drawing particles as alpha blended lines, or ribbons as alpha blended triangles.
+	///
+	/// Shares the pipeline layout with the main pipeline, so bound descriptor sets stay valid when switching.
+	fn create_particle_pipeline(
+		rs: &RenderState, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout,
+		topology: vk::PrimitiveTopology, samples: vk::SampleCountFlags, subpass: u32, tag: &str,
+	) -> vk::Pipeline
+	{
+		let vertex_shader_module = rs.load_shader("shaders/particle_vert.spv", &[]);
+		let fragment_shader_module = rs.load_shader("shaders/particle_frag.spv", &[]);
+
+		let shader_entry_name = CString::new("main").unwrap();
+		let shader_stage_create_infos = [
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: vertex_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::VERTEX,
+				..Default::default()
+			},
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: fragment_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::FRAGMENT,
+				..Default::default()
+			},
+		];
 
-		let vertex_input_binding_descriptions = [vertex_binding_description];
+		let vertex_input_binding_descriptions = [vk::VertexInputBindingDescription {
+			binding: 0,
+			stride: size_of::<ParticleVertex>() as u32,
+			input_rate: vk::VertexInputRate::VERTEX,
+		}];
 		let vertex_input_attribute_descriptions = [
-			vertex_position_attribute_description,
-			vertex_normal_attribute_description,
-			vertex_tangent_attribute_description,
-			vertex_bitangent_attribute_description,
-			vertex_texcoord_attribute_description,
+			vk::VertexInputAttributeDescription {
+				binding: 0,
+				location: 0,
+				format: vk::Format::R32G32B32_SFLOAT,
+				offset: 0,
+			},
+			vk::VertexInputAttributeDescription {
+				binding: 0,
+				location: 1,
+				format: vk::Format::R32G32B32A32_SFLOAT,
+				offset: 3 * size_of::<f32>() as u32,
+			},
 		];
 		let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
-			p_next: ptr::null(),
-			flags: Default::default(),
 			vertex_attribute_description_count: vertex_input_attribute_descriptions.len() as u32,
 			p_vertex_attribute_descriptions: vertex_input_attribute_descriptions.as_ptr(),
 			vertex_binding_description_count: vertex_input_binding_descriptions.len() as u32,
 			p_vertex_binding_descriptions: vertex_input_binding_descriptions.as_ptr(),
+			..Default::default()
 		};
 		let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_INPUT_ASSEMBLY_STATE_CREATE_INFO,
-			p_next: ptr::null(),
-			flags: Default::default(),
-			primitive_restart_enable: 0,
-			topology: vk::PrimitiveTopology::TRIANGLE_LIST,
-		};
-		let viewport = vk::Viewport {
-			x: 0.0,
-			y: 0.0,
-			width: render_size.width as f32,
-			height: render_size.height as f32,
-			min_depth: 0.0,
-			max_depth: 1.0,
-		};
-		let scissor = vk::Rect2D {
-			offset: vk::Offset2D {
-				x: 0,
-				y: 0,
-			},
-			extent: vk::Extent2D {
-				width: render_size.width,
-				height: render_size.height,
-			},
+			topology: topology,
+			..Default::default()
 		};
+		// Viewport and scissor are dynamic, but the counts must still be given.
 		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_VIEWPORT_STATE_CREATE_INFO,
 			scissor_count: 1,
-			p_scissors: &scissor,
 			viewport_count: 1,
-			p_viewports: &viewport,
 			..Default::default()
 		};
+		// Ribbons are seen from both sides
 		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
-			cull_mode: vk::CullModeFlags::BACK,
+			cull_mode: vk::CullModeFlags::NONE,
 			front_face: vk::FrontFace::COUNTER_CLOCKWISE,
 			line_width: 1.0,
 			polygon_mode: vk::PolygonMode::FILL,
@@ -309,7 +1343,7 @@ impl MainPass
 		};
 		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
-			rasterization_samples: vk::SampleCountFlags::TYPE_1,
+			rasterization_samples: samples,
 			..Default::default()
 		};
 		let noop_stencil_state = vk::StencilOpState {
@@ -319,21 +1353,154 @@ impl MainPass
 			compare_op: vk::CompareOp::ALWAYS,
 			..Default::default()
 		};
+		// Particles are depth tested against the scene, but do not occlude each other
 		let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_DEPTH_STENCIL_STATE_CREATE_INFO,
 			depth_test_enable: 1,
-			depth_write_enable: 1,
+			depth_write_enable: 0,
 			depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
-			front: noop_stencil_state.clone(),
-			back: noop_stencil_state.clone(),
+			front: noop_stencil_state,
+			back: noop_stencil_state,
 			max_depth_bounds: 1.0,
 			min_depth_bounds: 0.0,
 			..Default::default()
 		};
 		let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
-			blend_enable: 0,
+			blend_enable: 1,
+			src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+			dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+			color_blend_op: vk::BlendOp::ADD,
+			src_alpha_blend_factor: vk::BlendFactor::ONE,
+			dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+			alpha_blend_op: vk::BlendOp::ADD,
 			color_write_mask: vk::ColorComponentFlags::all(),
+		}];
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
+			attachment_count: color_blend_attachment_states.len() as u32,
+			p_attachments: color_blend_attachment_states.as_ptr(),
+			..Default::default()
+		};
+		let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
+			dynamic_state_count: dynamic_state.len() as u32,
+			p_dynamic_states: dynamic_state.as_ptr(),
+			..Default::default()
+		};
+		let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			s_type: vk::StructureType::GRAPHICS_PIPELINE_CREATE_INFO,
+			stage_count: shader_stage_create_infos.len() as u32,
+			p_stages: shader_stage_create_infos.as_ptr(),
+			p_vertex_input_state: &vertex_input_state_info,
+			p_input_assembly_state: &vertex_input_assembly_state_info,
+			p_viewport_state: &viewport_state_info,
+			p_rasterization_state: &rasterization_info,
+			p_multisample_state: &multisample_state_info,
+			p_depth_stencil_state: &depth_state_info,
+			p_color_blend_state: &color_blend_state,
+			p_dynamic_state: &dynamic_state_info,
+			layout: pipeline_layout,
+			render_pass: renderpass,
+			subpass: subpass,
+			..Default::default()
+		};
+		let graphics_pipelines;
+		unsafe {
+			graphics_pipelines = rs
+				.device
+				.create_graphics_pipelines(rs.pipeline_cache, &[graphic_pipeline_info], None)
+				.expect("Unable to create particle pipeline");
+
+			rs.device.destroy_shader_module(fragment_shader_module, None);
+			rs.device.destroy_shader_module(vertex_shader_module, None);
+		}
+		track_create(ObjectKind::Pipeline, graphics_pipelines[0], tag);
+
+		graphics_pipelines[0]
+	}
+
+	/// Creates the pipeline of the lighting subpass, which adds the lights of each G-buffer pixel to the render image
+	/// with a fullscreen triangle, see create_deferred_renderpass().
+	///
+	/// Shares the pipeline layout with the main pipeline, and reads the G-buffer and lights from the frame set.
+	fn create_lighting_pipeline(
+		rs: &RenderState, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout, subpass: u32,
+	) -> vk::Pipeline
+	{
+		let vertex_shader_module = rs.load_shader("shaders/final_pass_vert.spv", &[]);
+		let fragment_shader_module = rs.load_shader(
+			"shaders/deferred_lighting_frag.spv",
+			&[
+				("MAX_LIGHTS", format!("{}u", MAX_LIGHTS)),
+				("MAX_REFLECTION_PROBES", format!("{}u", MAX_REFLECTION_PROBES)),
+			],
+		);
+		let shader_entry_name = CString::new("main").unwrap();
+		let shader_stage_create_infos = [
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: vertex_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::VERTEX,
+				..Default::default()
+			},
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: fragment_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::FRAGMENT,
+				..Default::default()
+			},
+		];
+		// The triangle is generated from the vertex index
+		let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
+			..Default::default()
+		};
+		let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_INPUT_ASSEMBLY_STATE_CREATE_INFO,
+			topology: vk::PrimitiveTopology::TRIANGLE_LIST,
 			..Default::default()
+		};
+		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VIEWPORT_STATE_CREATE_INFO,
+			scissor_count: 1,
+			viewport_count: 1,
+			..Default::default()
+		};
+		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
+			cull_mode: vk::CullModeFlags::NONE,
+			front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+			line_width: 1.0,
+			polygon_mode: vk::PolygonMode::FILL,
+			..Default::default()
+		};
+		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
+			rasterization_samples: vk::SampleCountFlags::TYPE_1,
+			..Default::default()
+		};
+		// Pixels without a surface are discarded instead of depth tested
+		let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DEPTH_STENCIL_STATE_CREATE_INFO,
+			depth_test_enable: 0,
+			depth_write_enable: 0,
+			max_depth_bounds: 1.0,
+			min_depth_bounds: 0.0,
+			..Default::default()
+		};
+		// Added to the bounce light, reflections and emission, keeping the opacity
+		let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
+			blend_enable: 1,
+			src_color_blend_factor: vk::BlendFactor::ONE,
+			dst_color_blend_factor: vk::BlendFactor::ONE,
+			color_blend_op: vk::BlendOp::ADD,
+			src_alpha_blend_factor: vk::BlendFactor::ZERO,
+			dst_alpha_blend_factor: vk::BlendFactor::ONE,
+			alpha_blend_op: vk::BlendOp::ADD,
+			color_write_mask: vk::ColorComponentFlags::all(),
 		}];
 		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
 			s_type: vk::StructureType::PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
@@ -362,30 +1529,204 @@ impl MainPass
 			p_dynamic_state: &dynamic_state_info,
 			layout: pipeline_layout,
 			render_pass: renderpass,
+			subpass: subpass,
 			..Default::default()
 		};
 		let graphics_pipelines;
 		unsafe {
 			graphics_pipelines = rs
 				.device
-				.create_graphics_pipelines(vk::PipelineCache::null(), &[graphic_pipeline_info], None)
-				.expect("Unable to create graphics pipeline");
+				.create_graphics_pipelines(rs.pipeline_cache, &[graphic_pipeline_info], None)
+				.expect("Unable to create lighting pipeline");
 
-			// Graphics pipeline created, we no longer need the shader modules
 			rs.device.destroy_shader_module(fragment_shader_module, None);
 			rs.device.destroy_shader_module(vertex_shader_module, None);
 		}
+		track_create(ObjectKind::Pipeline, graphics_pipelines[0], "deferred lighting");
+
+		graphics_pipelines[0]
+	}
+
+	/// Returns the viewport and scissor covering the render images.
+	fn get_viewport(render_size: vk::Extent3D) -> (vk::Viewport, vk::Rect2D)
+	{
+		let viewport = vk::Viewport {
+			x: 0.0,
+			y: 0.0,
+			width: render_size.width as f32,
+			height: render_size.height as f32,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		};
+		let scissor = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: 0,
+				y: 0,
+			},
+			extent: vk::Extent2D {
+				width: render_size.width,
+				height: render_size.height,
+			},
+		};
+		return (viewport, scissor);
+	}
+
+	/// Creates the color, depth and fragment count images to render to.
+	fn create_images(rs: &RenderState, render_size: vk::Extent3D, name: &str) -> (Texture, Texture, Texture)
+	{
+		let render_image = rs.create_texture(
+			render_size,
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			RENDER_FORMAT,
+			vk::ImageAspectFlags::COLOR,
+			// Copied from when baking reflection probes
+			vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC,
+			vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+			vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+			vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+			None,
+		);
+		let depth_image = rs.create_texture(
+			render_size,
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::D32_SFLOAT,
+			vk::ImageAspectFlags::DEPTH,
+			vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+			vk::PipelineStageFlags::ALL_GRAPHICS,
+			None,
+		);
+
+		let render_extent = vk::Extent2D {
+			width: render_size.width,
+			height: render_size.height,
+		};
+		rs.register_image(render_image.image, &format!("{} color", name), render_extent, RENDER_FORMAT);
+		rs.register_image(depth_image.image, &format!("{} depth", name), render_extent, vk::Format::D32_SFLOAT);
+		let fragment_counts = rs.create_texture(
+			render_size,
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::R32_UINT,
+			vk::ImageAspectFlags::COLOR,
+			// Cleared before counting
+			vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_DST,
+			vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			None,
+		);
+		rs.register_image(
+			fragment_counts.image,
+			&format!("{} fragment counts", name),
+			render_extent,
+			vk::Format::R32_UINT,
+		);
+		return (render_image, depth_image, fragment_counts);
+	}
+
+	/// Creates the color and depth images rendered to when multisampling, if the sample count is above one.
+	fn create_multisampled_images(
+		rs: &RenderState, render_size: vk::Extent3D, samples: vk::SampleCountFlags, name: &str,
+	) -> Option<(Texture, Texture)>
+	{
+		if samples == vk::SampleCountFlags::TYPE_1
+		{
+			return None;
+		}
+		let color_image = rs.create_multisampled_texture(
+			render_size,
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			RENDER_FORMAT,
+			vk::ImageAspectFlags::COLOR,
+			vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+			vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+			vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+			vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+			None,
+			samples,
+		);
+		let depth_image = rs.create_multisampled_texture(
+			render_size,
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::D32_SFLOAT,
+			vk::ImageAspectFlags::DEPTH,
+			vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+			vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+			vk::PipelineStageFlags::ALL_GRAPHICS,
+			None,
+			samples,
+		);
 
-		(descriptor_pool, descriptor_set_layouts.to_vec(), pipeline_layout, viewport, scissor, graphics_pipelines[0])
+		// Sizes are per sample
+		let render_extent = vk::Extent2D {
+			width: render_size.width,
+			height: render_size.height,
+		};
+		rs.register_image(color_image.image, &format!("{} multisampled color", name), render_extent, RENDER_FORMAT);
+		rs.register_image(
+			depth_image.image,
+			&format!("{} multisampled depth", name),
+			render_extent,
+			vk::Format::D32_SFLOAT,
+		);
+		return Some((color_image, depth_image));
 	}
 
-	/// Creates framebuffers for the presentable images, one per image.
+	/// Creates the G-buffer images written by the geometry subpass when deferred shading, see
+	/// create_deferred_renderpass(). They only live within the pass.
+	fn create_gbuffer(rs: &RenderState, render_size: vk::Extent3D, name: &str) -> Vec<Texture>
+	{
+		let render_extent = vk::Extent2D {
+			width: render_size.width,
+			height: render_size.height,
+		};
+		return GBUFFER_FORMATS
+			.iter()
+			.zip(GBUFFER_NAMES.iter())
+			.map(|(&format, image_name)| {
+				let image = rs.create_texture(
+					render_size,
+					vk::ImageType::TYPE_2D,
+					vk::ImageViewType::TYPE_2D,
+					format,
+					vk::ImageAspectFlags::COLOR,
+					vk::ImageUsageFlags::COLOR_ATTACHMENT |
+						vk::ImageUsageFlags::INPUT_ATTACHMENT |
+						vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+					vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+					vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+					vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+					None,
+				);
+				rs.register_image(image.image, &format!("{} G-buffer {}", name, image_name), render_extent, format);
+				image
+			})
+			.collect();
+	}
+
+	/// Creates framebuffers for the presentable images, one per image. The multisampled images come first if there
+	/// are any, see create_multisampled_renderpass(), and the G-buffer images last, see create_deferred_renderpass().
 	fn create_framebuffer(
 		rs: &RenderState, render_size: vk::Extent3D, color_view: vk::ImageView, depth_view: vk::ImageView,
-		renderpass: vk::RenderPass,
+		multisampled_images: &Option<(Texture, Texture)>, gbuffer: &Option<Vec<Texture>>, renderpass: vk::RenderPass,
 	) -> vk::Framebuffer
 	{
-		let framebuffer_attachments = [color_view, depth_view];
+		let mut framebuffer_attachments = match multisampled_images
+		{
+			Some((color_image, depth_image)) => vec![color_image.view, depth_image.view, color_view, depth_view],
+			None => vec![color_view, depth_view],
+		};
+		if let Some(gbuffer) = gbuffer
+		{
+			framebuffer_attachments.extend(gbuffer.iter().map(|image| image.view));
+		}
 		let frame_buffer_create_info = vk::FramebufferCreateInfo {
 			s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
 			render_pass: renderpass,
@@ -423,53 +1764,94 @@ impl MainPass
 
 	/// Initializes the MainPass based on a RenderState
 	///
-	/// This will set up the renderpass, etc.
-	pub fn init(rs: &RenderState, cfg: &Config) -> MainPass
+	/// This will set up the renderpass, etc. Shades deferred unless the config asks for forward shading, or
+	/// multisampling is enabled, and starts with a depth prepass if the config asks for it.
+	pub fn init(rs: &RenderState, cfg: &Config, name: &'static str) -> MainPass
+	{
+		let samples = rs.get_msaa_samples();
+		let deferred = !cfg.forward_shading && samples == vk::SampleCountFlags::TYPE_1;
+		return MainPass::create(rs, cfg.render_width, cfg.render_height, samples, deferred, cfg.depth_prepass, name);
+	}
+
+	/// Initializes a MainPass rendering images of the given size, instead of the configured render size. Renders
+	/// without multisampling nor depth prepass, and shades forward.
+	pub fn init_with_size(rs: &RenderState, width: u32, height: u32, name: &'static str) -> MainPass
+	{
+		return MainPass::create(rs, width, height, vk::SampleCountFlags::TYPE_1, false, false, name);
+	}
+
+	fn create(
+		rs: &RenderState, width: u32, height: u32, samples: vk::SampleCountFlags, deferred: bool, depth_prepass: bool,
+		name: &'static str,
+	) -> MainPass
 	{
-		let render_format = vk::Format::R8G8B8A8_UNORM;
 		let render_size = vk::Extent3D {
-			width: cfg.render_width,
-			height: cfg.render_height,
+			width: width,
+			height: height,
 			depth: 1,
-		};
-
-		// Create image to render to.
-		let render_image = rs.create_texture(
-			render_size,
-			vk::ImageType::TYPE_2D,
-			vk::ImageViewType::TYPE_2D,
-			render_format,
-			vk::ImageAspectFlags::COLOR,
-			vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
-			vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-			vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-			vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-			None,
-		);
-		let depth_image = rs.create_texture(
-			render_size,
-			vk::ImageType::TYPE_2D,
-			vk::ImageViewType::TYPE_2D,
-			vk::Format::D32_SFLOAT,
-			vk::ImageAspectFlags::DEPTH,
-			vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-			vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-			vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
-			vk::PipelineStageFlags::ALL_GRAPHICS,
-			None,
-		);
+		};
+		let (render_image, depth_image, fragment_counts) = MainPass::create_images(rs, render_size, name);
+		let multisampled_images = MainPass::create_multisampled_images(rs, render_size, samples, name);
+		let gbuffer = if deferred
+		{
+			Some(MainPass::create_gbuffer(rs, render_size, name))
+		}
+		else
+		{
+			None
+		};
 
-		let renderpass = MainPass::create_renderpass(rs, render_format);
-		let (descriptor_pool, descriptor_set_layouts, pipeline_layout, viewport, scissor, pipeline) =
-			MainPass::create_pipeline(rs, render_size, renderpass);
-		let framebuffer =
-			MainPass::create_framebuffer(rs, render_size, render_image.view, depth_image.view, renderpass);
+		let renderpass = MainPass::create_renderpass(rs, name, RENDER_FORMAT, samples, deferred, depth_prepass);
+		let (descriptor_pool, descriptor_set_layouts, pipeline_layout, viewport, scissor, mesh_pipelines) =
+			MainPass::create_pipeline(rs, render_size, renderpass, samples, deferred, depth_prepass);
+		// Blended, so drawn after the lights when deferred shading
+		let shaded_subpass = MeshOutput::Shaded.get_subpass(deferred, depth_prepass);
+		let particle_pipeline = rs.create_pipeline_handle(MainPass::create_particle_pipeline(
+			rs,
+			renderpass,
+			pipeline_layout,
+			vk::PrimitiveTopology::LINE_LIST,
+			samples,
+			shaded_subpass,
+			"particles",
+		));
+		let ribbon_pipeline = rs.create_pipeline_handle(MainPass::create_particle_pipeline(
+			rs,
+			renderpass,
+			pipeline_layout,
+			vk::PrimitiveTopology::TRIANGLE_LIST,
+			samples,
+			shaded_subpass,
+			"ribbons",
+		));
+		let lighting_pipeline = if deferred
+		{
+			Some(rs.create_pipeline_handle(MainPass::create_lighting_pipeline(
+				rs,
+				renderpass,
+				pipeline_layout,
+				shaded_subpass,
+			)))
+		}
+		else
+		{
+			None
+		};
+		let framebuffer = MainPass::create_framebuffer(
+			rs,
+			render_size,
+			render_image.view,
+			depth_image.view,
+			&multisampled_images,
+			&gbuffer,
+			renderpass,
+		);
 		let commandbuffer = MainPass::create_commandbuffer(rs);
 
-		let (vmat_buf, vmat_mem) = rs.create_buffer(
+		let frame_buf = rs.create_buffer(
 			vk::BufferUsageFlags::UNIFORM_BUFFER,
 			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-			size_of::<Matrix4<f32>>() as u64,
+			size_of::<FrameUniforms>() as u64,
 		);
 		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
@@ -478,33 +1860,510 @@ impl MainPass
 			descriptor_set_count: 1,
 			p_set_layouts: &descriptor_set_layouts[1],
 		};
-		let view_matrix_ds;
+		let frame_ds;
 		unsafe {
-			view_matrix_ds = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
+			frame_ds = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
 		}
+		let indirect_draws = IndirectDraws::new(rs, descriptor_pool, descriptor_set_layouts[2]);
+		// Meshes drawn without morphing read no offsets, but the set must still be valid
+		let empty_morph_deltas = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::STORAGE_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&[[0.0f32; 8]],
+			false,
+		);
+		let empty_morph_weights = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::UNIFORM_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&[MorphUniforms::new(0, 0, &[])],
+			false,
+		);
+		let empty_shadow_atlas = rs.create_texture(
+			vk::Extent3D {
+				width: 1,
+				height: 1,
+				depth: 1,
+			},
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::D32_SFLOAT,
+			vk::ImageAspectFlags::DEPTH,
+			vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			None,
+		);
+		let empty_cube_map = rs.create_cube_texture(
+			1,
+			vk::Format::D32_SFLOAT,
+			vk::ImageAspectFlags::DEPTH,
+			vk::ImageUsageFlags::SAMPLED,
+		);
+		let empty_damage_texture = rs.create_texture_from_data(
+			vk::Extent3D {
+				width: 1,
+				height: 1,
+				depth: 1,
+			},
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::R8G8B8A8_UNORM,
+			&[0; 4],
+		);
+		// A single probe without irradiance
+		let empty_irradiance_probes = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::STORAGE_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&[[0.0f32; 4]; 9],
+			false,
+		);
 
-		MainPass {
+		let mut mainpass = MainPass {
+			name: name,
 			renderpass: renderpass,
 			descriptor_pool: descriptor_pool,
 			descriptor_set_layouts: descriptor_set_layouts,
 			pipeline_layout: pipeline_layout,
 			viewport: viewport,
 			scissor: scissor,
-			pipeline: pipeline,
+			pipelines: vec![MainPass::create_pipeline_handles(rs, &mesh_pipelines[0][0..2])],
+			indirect_pipelines: MainPass::create_pipeline_handles(rs, &mesh_pipelines[0][2..4]),
+			gbuffer_pipelines: vec![mesh_pipelines[1]
+				.get(0..2)
+				.map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines))],
+			gbuffer_indirect_pipelines: mesh_pipelines[1]
+				.get(2..4)
+				.map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines)),
+			depth_pipelines: vec![mesh_pipelines[2]
+				.get(0..2)
+				.map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines))],
+			depth_indirect_pipelines: mesh_pipelines[2]
+				.get(2..4)
+				.map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines)),
+			lighting_pipeline: lighting_pipeline,
+			particle_pipeline: particle_pipeline,
+			ribbon_pipeline: ribbon_pipeline,
+			bound_pipeline: Cell::new(None),
+			geometry_subpass: Cell::new(false),
+			depth_prepass: depth_prepass,
+			drawing_depth: Cell::new(false),
+			draw_region: Cell::new((viewport, scissor)),
+			indirect_draws: RefCell::new(indirect_draws),
+			vertex_bytes: Cell::new(0),
 			framebuffer: framebuffer,
 			commandbuffer: commandbuffer,
 
 			render_image: render_image,
 			depth_image: depth_image,
+			multisampled_images: multisampled_images,
+			samples: samples,
+			fragment_counts: fragment_counts,
+			count_fragments: false,
+			gbuffer: gbuffer,
 
-			view_matrix_ub: vmat_buf,
-			view_matrix_ub_mem: vmat_mem,
-			view_matrix_ds: view_matrix_ds,
+			frame_ub: frame_buf,
+			frame_ds: frame_ds,
+			empty_shadow_atlas: empty_shadow_atlas,
+			empty_cube_map: empty_cube_map,
+			empty_damage_texture: empty_damage_texture,
+			empty_irradiance_probes: empty_irradiance_probes,
+			irradiance_probes: Cell::new(vk::Buffer::null()),
+			empty_morph_deltas: empty_morph_deltas,
+			empty_morph_weights: empty_morph_weights,
+			default_morph_ds: vk::DescriptorSet::null(),
+			image_views: RefCell::new(vec![
+				vk::ImageView::null();
+				(FRAGMENT_COUNTS_BINDING - SHADOW_ATLAS_BINDING) as usize
+			]),
 
 			// Keep a pointer to the device for cleanup
 			device: Rc::clone(&rs.device),
+		};
+		mainpass.set_shadow_atlas(rs, None);
+		mainpass.set_reflection_probes(rs, &[]);
+		mainpass.set_irradiance_probes(rs, None);
+		mainpass.default_morph_ds = MainPass::create_morph_set(
+			rs,
+			mainpass.descriptor_pool,
+			mainpass.descriptor_set_layouts[3],
+			mainpass.empty_morph_deltas.get(),
+			mainpass.empty_morph_weights.get(),
+		)
+		.unwrap();
+		mainpass.create_permutations(rs);
+		mainpass.write_fragment_counts_descriptor(rs);
+		mainpass.write_gbuffer_descriptors(rs);
+		return mainpass;
+	}
+
+	/// Recreates the images, framebuffer and viewport for a new render size, e.g. after the window was resized.
+	pub fn resize(&mut self, rs: &RenderState, width: u32, height: u32)
+	{
+		let render_size = vk::Extent3D {
+			width: width.max(1),
+			height: height.max(1),
+			depth: 1,
+		};
+		// The old images might still be in use by the GPU
+		unsafe {
+			rs.device.device_wait_idle().unwrap();
+			rs.device.destroy_framebuffer(self.framebuffer, None);
+		}
+		let (render_image, depth_image, fragment_counts) = MainPass::create_images(rs, render_size, self.name);
+		let multisampled_images = MainPass::create_multisampled_images(rs, render_size, self.samples, self.name);
+		let gbuffer = self.gbuffer.as_ref().map(|_| MainPass::create_gbuffer(rs, render_size, self.name));
+		self.framebuffer = MainPass::create_framebuffer(
+			rs,
+			render_size,
+			render_image.view,
+			depth_image.view,
+			&multisampled_images,
+			&gbuffer,
+			self.renderpass,
+		);
+		self.multisampled_images = multisampled_images;
+		self.gbuffer = gbuffer;
+		self.render_image = render_image;
+		self.depth_image = depth_image;
+		self.fragment_counts = fragment_counts;
+		let (viewport, scissor) = MainPass::get_viewport(render_size);
+		self.viewport = viewport;
+		self.scissor = scissor;
+		self.draw_region.set((viewport, scissor));
+		self.write_fragment_counts_descriptor(rs);
+		self.write_gbuffer_descriptors(rs);
+	}
+
+	/// Recreates the mesh, particle, ribbon and lighting pipelines from their shaders on disk, e.g. after they were
+	/// rebuilt, see RenderState::poll_shader_changes(). The old pipelines are released once the frames using them are
+	/// done.
+	pub fn reload_shaders(&mut self, rs: &RenderState)
+	{
+		let deferred = self.gbuffer.is_some();
+		let mesh_pipelines = MainPass::create_default_pipelines(
+			rs,
+			self.renderpass,
+			self.pipeline_layout,
+			self.samples,
+			deferred,
+			self.depth_prepass,
+		);
+		self.pipelines = vec![MainPass::create_pipeline_handles(rs, &mesh_pipelines[0][0..2])];
+		self.indirect_pipelines = MainPass::create_pipeline_handles(rs, &mesh_pipelines[0][2..4]);
+		self.gbuffer_pipelines =
+			vec![mesh_pipelines[1].get(0..2).map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines))];
+		self.gbuffer_indirect_pipelines =
+			mesh_pipelines[1].get(2..4).map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines));
+		self.depth_pipelines =
+			vec![mesh_pipelines[2].get(0..2).map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines))];
+		self.depth_indirect_pipelines =
+			mesh_pipelines[2].get(2..4).map(|pipelines| MainPass::create_pipeline_handles(rs, pipelines));
+		// The other permutations derive from the new default pipelines
+		self.create_permutations(rs);
+		let shaded_subpass = MeshOutput::Shaded.get_subpass(deferred, self.depth_prepass);
+		self.particle_pipeline = rs.create_pipeline_handle(MainPass::create_particle_pipeline(
+			rs,
+			self.renderpass,
+			self.pipeline_layout,
+			vk::PrimitiveTopology::LINE_LIST,
+			self.samples,
+			shaded_subpass,
+			"particles",
+		));
+		self.ribbon_pipeline = rs.create_pipeline_handle(MainPass::create_particle_pipeline(
+			rs,
+			self.renderpass,
+			self.pipeline_layout,
+			vk::PrimitiveTopology::TRIANGLE_LIST,
+			self.samples,
+			shaded_subpass,
+			"ribbons",
+		));
+		if deferred
+		{
+			self.lighting_pipeline = Some(rs.create_pipeline_handle(MainPass::create_lighting_pipeline(
+				rs,
+				self.renderpass,
+				self.pipeline_layout,
+				shaded_subpass,
+			)));
+		}
+		self.bound_pipeline.set(None);
+	}
+
+	/// Returns the size of the render images.
+	pub fn get_render_extent(&self) -> vk::Extent2D
+	{
+		return self.scissor.extent;
+	}
+
+	fn write_fragment_counts_descriptor(&self, rs: &RenderState)
+	{
+		// Always in the general layout, so the descriptor is only written when the image changes
+		let fragment_counts_descriptor = vk::DescriptorImageInfo {
+			image_layout: vk::ImageLayout::GENERAL,
+			image_view: self.fragment_counts.view,
+			sampler: vk::Sampler::null(),
+		};
+		let write_desc_set = vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: self.frame_ds[0],
+			dst_binding: FRAGMENT_COUNTS_BINDING,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+			p_image_info: &fragment_counts_descriptor,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.update_descriptor_sets(&[write_desc_set], &[]);
+		}
+	}
+
+	/// Writes the G-buffer images to the input attachment bindings of the frame set, when deferred shading.
+	fn write_gbuffer_descriptors(&self, rs: &RenderState)
+	{
+		let gbuffer = match &self.gbuffer
+		{
+			Some(gbuffer) => gbuffer,
+			None => return,
+		};
+		let image_descriptors: Vec<vk::DescriptorImageInfo> = gbuffer
+			.iter()
+			.map(|image| vk::DescriptorImageInfo {
+				image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+				image_view: image.view,
+				sampler: vk::Sampler::null(),
+			})
+			.collect();
+		let write_desc_sets: Vec<vk::WriteDescriptorSet> = image_descriptors
+			.iter()
+			.enumerate()
+			.map(|(idx, image_descriptor)| vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.frame_ds[0],
+				dst_binding: FIRST_GBUFFER_BINDING + idx as u32,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::INPUT_ATTACHMENT,
+				p_image_info: image_descriptor,
+				..Default::default()
+			})
+			.collect();
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+	}
+
+	/// Creates the pipelines of the permutations that materials have requested since the last call, and their G-buffer
+	/// and depth prepass pipelines if they are drawn to those, see MeshOutput::is_drawn().
+	///
+	/// Compiling pipelines is slow, so when a scene requests many permutations at once, they are spread over a thread
+	/// per core. Shaders are loaded up front, as the shader compiler is not shared between threads.
+	fn create_permutations(&mut self, rs: &RenderState)
+	{
+		let permutations = rs.pipeline_permutations.borrow();
+		let new_permutations = &permutations[self.pipelines.len()..];
+		if new_permutations.is_empty()
+		{
+			return;
+		}
+		let shader_modules: Vec<[vk::ShaderModule; 2]> =
+			new_permutations.iter().map(|permutation| MainPass::load_mesh_shaders(rs, permutation)).collect();
+
+		let get_pipelines = |pipelines: &Option<[PipelineHandle; 2]>| match pipelines
+		{
+			Some(pipelines) => vec![pipelines[0].get(), pipelines[1].get()],
+			None => Vec::new(),
+		};
+		let base_pipelines = [
+			vec![self.pipelines[0][0].get(), self.pipelines[0][1].get()],
+			get_pipelines(&self.gbuffer_pipelines[0]),
+			get_pipelines(&self.depth_pipelines[0]),
+		];
+		let base_pipelines = &base_pipelines;
+		let (device, pipeline_cache, renderpass, pipeline_layout, samples) =
+			(&*rs.device, rs.pipeline_cache, self.renderpass, self.pipeline_layout, self.samples);
+		let packed_vertex_format = rs.get_packed_vertex_format();
+		let (deferred, depth_prepass) = (self.gbuffer.is_some(), self.depth_prepass);
+		let thread_count = thread::available_parallelism().map(|count| count.get()).unwrap_or(1);
+		let chunk_size = new_permutations.len().div_ceil(thread_count);
+		let pipelines: Vec<[Vec<vk::Pipeline>; 3]> = thread::scope(|scope| {
+			let threads: Vec<_> = new_permutations
+				.chunks(chunk_size)
+				.zip(shader_modules.chunks(chunk_size))
+				.map(|(permutations, shader_modules)| {
+					scope.spawn(move || {
+						permutations
+							.iter()
+							.zip(shader_modules.iter())
+							.map(|(permutation, &shader_modules)| {
+								MainPass::create_permutation_pipelines(
+									device,
+									pipeline_cache,
+									renderpass,
+									pipeline_layout,
+									permutation,
+									shader_modules,
+									Some(base_pipelines),
+									samples,
+									packed_vertex_format,
+									deferred,
+									depth_prepass,
+								)
+							})
+							.collect::<Vec<_>>()
+					})
+				})
+				.collect();
+			return threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+		});
+
+		for ((permutation, &shader_modules), pipelines) in
+			new_permutations.iter().zip(shader_modules.iter()).zip(pipelines.iter())
+		{
+			MainPass::finish_mesh_pipelines(rs, permutation, shader_modules, pipelines);
+			self.pipelines.push(MainPass::create_pipeline_handles(rs, &pipelines[0]));
+			self.gbuffer_pipelines.push(MainPass::create_optional_pipeline_handles(rs, &pipelines[1]));
+			self.depth_pipelines.push(MainPass::create_optional_pipeline_handles(rs, &pipelines[2]));
+		}
+	}
+
+	/// Takes ownership of the pipelines for meshes with full and packed vertices, if the permutation has any.
+	fn create_optional_pipeline_handles(rs: &RenderState, pipelines: &[vk::Pipeline]) -> Option<[PipelineHandle; 2]>
+	{
+		if pipelines.is_empty()
+		{
+			return None;
+		}
+		return Some(MainPass::create_pipeline_handles(rs, pipelines));
+	}
+
+	/// Takes ownership of the pipelines for meshes with full and packed vertices.
+	fn create_pipeline_handles(rs: &RenderState, pipelines: &[vk::Pipeline]) -> [PipelineHandle; 2]
+	{
+		return [rs.create_pipeline_handle(pipelines[0]), rs.create_pipeline_handle(pipelines[1])];
+	}
+
+	/// Returns a transparent texture, for materials without damage.
+	pub fn get_empty_damage_texture(&self) -> &Texture
+	{
+		return &self.empty_damage_texture;
+	}
+
+	/// Binds the shadow atlas of the point lights, as a (view, sampler) pair, see ShadowPass::get_shadow_atlas(). None
+	/// binds an empty atlas.
+	pub fn set_shadow_atlas(&self, rs: &RenderState, shadow_atlas: Option<(vk::ImageView, vk::Sampler)>)
+	{
+		let shadow_atlas = shadow_atlas.unwrap_or((self.empty_shadow_atlas.view, self.empty_shadow_atlas.sampler));
+		self.set_images(rs, SHADOW_ATLAS_BINDING, &[shadow_atlas]);
+	}
+
+	/// Binds the cube maps of the reflection probes, as (view, sampler) pairs. Slots without a probe are given None.
+	pub fn set_reflection_probes(&self, rs: &RenderState, probes: &[Option<(vk::ImageView, vk::Sampler)>])
+	{
+		let cube_maps: Vec<(vk::ImageView, vk::Sampler)> = (0..MAX_REFLECTION_PROBES)
+			.map(|idx| {
+				probes.get(idx).cloned().flatten().unwrap_or((self.empty_cube_map.view, self.empty_cube_map.sampler))
+			})
+			.collect();
+		self.set_images(rs, FIRST_PROBE_BINDING, &cube_maps);
+	}
+
+	/// Binds the baked probes of the irradiance grid, see IrradianceGrid::get_probe_buffer(). None binds a single probe
+	/// without irradiance.
+	pub fn set_irradiance_probes(&self, rs: &RenderState, probes: Option<vk::Buffer>)
+	{
+		let probes = probes.unwrap_or(self.empty_irradiance_probes.get());
+		// Only rewritten when the grid was baked again
+		if self.irradiance_probes.get() == probes
+		{
+			return;
+		}
+		self.irradiance_probes.set(probes);
+		let buffer_descriptor = vk::DescriptorBufferInfo {
+			buffer: probes,
+			offset: 0,
+			range: vk::WHOLE_SIZE,
+		};
+		let write_desc_set = vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: self.frame_ds[0],
+			dst_binding: IRRADIANCE_PROBES_BINDING,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+			p_buffer_info: &buffer_descriptor,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.update_descriptor_sets(&[write_desc_set], &[]);
+		}
+	}
+
+	/// Binds the images to the frame descriptor set, starting at the given binding.
+	fn set_images(&self, rs: &RenderState, first_binding: u32, images: &[(vk::ImageView, vk::Sampler)])
+	{
+		let image_descriptors: Vec<vk::DescriptorImageInfo> = images
+			.iter()
+			.map(|&(view, sampler)| vk::DescriptorImageInfo {
+				image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+				image_view: view,
+				sampler: sampler,
+			})
+			.collect();
+		// Descriptors are only rewritten when an image was created or replaced
+		let views: Vec<vk::ImageView> = images.iter().map(|&(view, _)| view).collect();
+		let first_slot = (first_binding - SHADOW_ATLAS_BINDING) as usize;
+		let mut bound_views = self.image_views.borrow_mut();
+		if bound_views[first_slot..first_slot + images.len()] == views[..]
+		{
+			return;
+		}
+		bound_views[first_slot..first_slot + images.len()].copy_from_slice(&views);
+
+		let write_desc_sets: Vec<vk::WriteDescriptorSet> = image_descriptors
+			.iter()
+			.enumerate()
+			.map(|(idx, image_descriptor)| vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.frame_ds[0],
+				dst_binding: first_binding + idx as u32,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: image_descriptor,
+				..Default::default()
+			})
+			.collect();
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+	}
+
+	/// Enables counting the fragments drawn to each pixel in the following frames, into fragment_counts. Takes effect
+	/// at the next update_frame_uniforms().
+	pub fn set_fragment_counting(&mut self, enabled: bool)
+	{
+		self.count_fragments = enabled;
+	}
+
+	/// Writes the per-frame uniforms used by the next frame.
+	pub fn update_frame_uniforms(&self, frame_uniforms: &FrameUniforms)
+	{
+		let frame_uniforms = FrameUniforms {
+			count_fragments: self.count_fragments as u32,
+			..*frame_uniforms
+		};
+		let frame_buf_size = size_of::<FrameUniforms>() as u64;
+		unsafe {
+			let mem_ptr = self.frame_ub.get_mapped();
+			let mut mem_align = Align::new(mem_ptr, align_of::<FrameUniforms>() as u64, frame_buf_size);
+			mem_align.copy_from_slice(&[frame_uniforms]);
 		}
 	}
+
 	/// Begins the main render pass
 	///
 	/// Returns a command buffer to be used in rendering.
@@ -517,10 +2376,28 @@ impl MainPass
 			..Default::default()
 		};
 		let cmd_buf = self.commandbuffer;
+		self.create_permutations(rs);
 		unsafe {
 			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
 		}
 
+		// In the order of the attachments
+		let mut writes = Vec::new();
+		if let Some((color_image, depth_image)) = &self.multisampled_images
+		{
+			writes.extend_from_slice(&[color_image.image, depth_image.image]);
+		}
+		writes.extend_from_slice(&[self.render_image.image, self.depth_image.image]);
+		if let Some(gbuffer) = &self.gbuffer
+		{
+			writes.extend(gbuffer.iter().map(|image| image.image));
+		}
+		if self.count_fragments
+		{
+			writes.push(self.fragment_counts.image);
+		}
+		rs.trace_pass(cmd_buf, &format!("{}: scene", self.name), &[], &writes);
+
 		// Transition the mainpass output to a renderable image
 		rs.transition_texture(
 			&mut self.render_image,
@@ -529,9 +2406,52 @@ impl MainPass
 			vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
 			Some(cmd_buf),
 		);
+		rs.transition_texture(
+			&mut self.depth_image,
+			vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+			vk::PipelineStageFlags::ALL_GRAPHICS,
+			Some(cmd_buf),
+		);
+		if self.count_fragments
+		{
+			rs.transition_texture(
+				&mut self.fragment_counts,
+				vk::AccessFlags::TRANSFER_WRITE,
+				vk::ImageLayout::GENERAL,
+				vk::PipelineStageFlags::TRANSFER,
+				Some(cmd_buf),
+			);
+			let clear_value = vk::ClearColorValue {
+				uint32: [0; 4],
+			};
+			let range = vk::ImageSubresourceRange {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				base_mip_level: 0,
+				level_count: 1,
+				base_array_layer: 0,
+				layer_count: 1,
+			};
+			unsafe {
+				rs.device.cmd_clear_color_image(
+					cmd_buf,
+					self.fragment_counts.image,
+					vk::ImageLayout::GENERAL,
+					&clear_value,
+					&[range],
+				);
+			}
+			rs.transition_texture(
+				&mut self.fragment_counts,
+				vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+				vk::ImageLayout::GENERAL,
+				vk::PipelineStageFlags::FRAGMENT_SHADER,
+				Some(cmd_buf),
+			);
+		}
 
 		// Begin renderpass
-		let clear_values = [
+		let mut clear_values = vec![
 			vk::ClearValue {
 				color: vk::ClearColorValue {
 					float32: [0.0, 1.0, 0.0, 1.0],
@@ -544,6 +2464,15 @@ impl MainPass
 				},
 			},
 		];
+		// Pixels without a surface have no coverage in the G-buffer
+		if let Some(gbuffer) = &self.gbuffer
+		{
+			clear_values.extend(gbuffer.iter().map(|_| vk::ClearValue {
+				color: vk::ClearColorValue {
+					float32: [0.0; 4],
+				},
+			}));
+		}
 
 		let render_pass_begin_info = vk::RenderPassBeginInfo {
 			s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
@@ -555,19 +2484,19 @@ impl MainPass
 			p_clear_values: clear_values.as_ptr(),
 		};
 
-		let view_matrix_ub_descriptor = vk::DescriptorBufferInfo {
-			buffer: self.view_matrix_ub,
+		let frame_ub_descriptor = vk::DescriptorBufferInfo {
+			buffer: self.frame_ub.get(),
 			offset: 0,
-			range: size_of::<Matrix4<f32>>() as u64,
+			range: size_of::<FrameUniforms>() as u64,
 		};
 		let write_desc_sets = [vk::WriteDescriptorSet {
 			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
-			dst_set: self.view_matrix_ds[0],
+			dst_set: self.frame_ds[0],
 			dst_binding: 0,
 			dst_array_element: 0,
 			descriptor_count: 1,
 			descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
-			p_buffer_info: &view_matrix_ub_descriptor,
+			p_buffer_info: &frame_ub_descriptor,
 			..Default::default()
 		}];
 
@@ -578,33 +2507,329 @@ impl MainPass
 			// Start the render pass
 			rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::INLINE);
 
+			// The indirect draw and morph sets are statically used by the shaders, so they are bound for all draws
+			let mut indirect_draws = self.indirect_draws.borrow_mut();
+			indirect_draws.reset();
 			rs.device.cmd_bind_descriptor_sets(
 				cmd_buf,
 				vk::PipelineBindPoint::GRAPHICS,
 				self.pipeline_layout,
 				1,
-				&self.view_matrix_ds[..],
+				&[self.frame_ds[0], indirect_draws.get_descriptor_set(), self.default_morph_ds],
 				&[],
 			);
 
 			// Bind pipeline
-			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+			self.drawing_depth.set(self.depth_prepass);
+			self.geometry_subpass.set(self.gbuffer.is_some() && !self.depth_prepass);
+			rs.device.cmd_bind_pipeline(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				self.get_mesh_pipeline(0, VertexFormat::Full),
+			);
+			self.bound_pipeline.set(Some((0, VertexFormat::Full)));
+			self.vertex_bytes.set(0);
 
 			rs.device.cmd_set_viewport(cmd_buf, 0, &[self.viewport]);
 			rs.device.cmd_set_scissor(cmd_buf, 0, &[self.scissor]);
+			self.draw_region.set((self.viewport, self.scissor));
 		}
 
 		cmd_buf
 	}
 
+	/// Restricts the following draws to a horizontal slice of the render image, given as the start and width in
+	/// fractions of its width, like one eye of a side by side stereo image. Draws queued before are issued first.
+	pub fn set_draw_region(&self, cmd_buf: vk::CommandBuffer, region: (f32, f32))
+	{
+		self.flush_indirect_draws(cmd_buf);
+		let full_width = self.scissor.extent.width as f32;
+		let x = (region.0 * full_width).round();
+		let width = (region.1 * full_width).round();
+		let viewport = vk::Viewport {
+			x: x,
+			width: width,
+			..self.viewport
+		};
+		let scissor = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: x as i32,
+				y: 0,
+			},
+			extent: vk::Extent2D {
+				width: width as u32,
+				height: self.scissor.extent.height,
+			},
+		};
+		unsafe {
+			self.device.cmd_set_viewport(cmd_buf, 0, &[viewport]);
+			self.device.cmd_set_scissor(cmd_buf, 0, &[scissor]);
+		}
+		self.draw_region.set((viewport, scissor));
+	}
+
+	/// Binds the particle pipeline to the command buffer returned by begin_frame().
+	///
+	/// Particles are blended, so they should be drawn after all opaque geometry.
+	pub fn bind_particle_pipeline(&self, cmd_buf: vk::CommandBuffer)
+	{
+		self.finish_geometry(cmd_buf);
+		unsafe {
+			self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.particle_pipeline.get());
+		}
+		self.bound_pipeline.set(None);
+	}
+
+	/// Binds the ribbon pipeline to the command buffer returned by begin_frame(). Like the particle pipeline, but draws
+	/// triangles.
+	pub fn bind_ribbon_pipeline(&self, cmd_buf: vk::CommandBuffer)
+	{
+		self.finish_geometry(cmd_buf);
+		unsafe {
+			self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.ribbon_pipeline.get());
+		}
+		self.bound_pipeline.set(None);
+	}
+
+	/// Binds the pipeline for meshes with the given material permutation and vertex format, unless it is already
+	/// bound, and counts the vertex buffer of the mesh for the pass statistics.
+	///
+	/// Permutations requested during the frame are created at the next, until then the default one is used. When
+	/// deferred shading, a permutation that cannot be drawn to the G-buffer ends the geometry subpass, see
+	/// finish_geometry(). Permutations not drawn in the depth prepass must not be bound during it, see
+	/// draws_permutation().
+	pub fn bind_mesh_pipeline(
+		&self, cmd_buf: vk::CommandBuffer, permutation: usize, vertex_format: VertexFormat, vertex_bytes: u64,
+	)
+	{
+		self.vertex_bytes.set(self.vertex_bytes.get() + vertex_bytes);
+		let permutation = if permutation < self.pipelines.len()
+		{
+			permutation
+		}
+		else
+		{
+			0
+		};
+		if self.geometry_subpass.get() && self.gbuffer_pipelines[permutation].is_none()
+		{
+			self.finish_geometry(cmd_buf);
+		}
+		if self.bound_pipeline.get() == Some((permutation, vertex_format))
+		{
+			return;
+		}
+		unsafe {
+			self.device.cmd_bind_pipeline(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				self.get_mesh_pipeline(permutation, vertex_format),
+			);
+		}
+		self.bound_pipeline.set(Some((permutation, vertex_format)));
+	}
+
+	/// Returns the pipeline for meshes with the given permutation and vertex format in the current subpass.
+	fn get_mesh_pipeline(&self, permutation: usize, vertex_format: VertexFormat) -> vk::Pipeline
+	{
+		let pipelines = match (&self.depth_pipelines[permutation], &self.gbuffer_pipelines[permutation])
+		{
+			(Some(depth_pipelines), _) if self.drawing_depth.get() => depth_pipelines,
+			(_, Some(gbuffer_pipelines)) if self.geometry_subpass.get() => gbuffer_pipelines,
+			_ => &self.pipelines[permutation],
+		};
+		return pipelines[vertex_format as usize].get();
+	}
+
+	/// Whether meshes with the given permutation are drawn in the current subpass. Only opaque surfaces are drawn in
+	/// the depth prepass, all are drawn after.
+	pub fn draws_permutation(&self, permutation: usize) -> bool
+	{
+		if !self.drawing_depth.get()
+		{
+			return true;
+		}
+		// Permutations requested during the frame are drawn with the default one, see bind_mesh_pipeline()
+		return !matches!(self.depth_pipelines.get(permutation), Some(None));
+	}
+
+	/// Whether the frame starts with a depth prepass, drawing the opaque surfaces before they are shaded, see
+	/// finish_depth_prepass().
+	pub fn has_depth_prepass(&self) -> bool
+	{
+		return self.depth_prepass;
+	}
+
+	/// Issues the queued indirect draws, and when drawing the depth prepass, moves on to shading. The surfaces are
+	/// then drawn again against the finished depth, so only the nearest one of each pixel is shaded.
+	pub fn finish_depth_prepass(&self, cmd_buf: vk::CommandBuffer)
+	{
+		if !self.drawing_depth.get()
+		{
+			return;
+		}
+		self.flush_indirect_draws(cmd_buf);
+		self.drawing_depth.set(false);
+		self.geometry_subpass.set(self.gbuffer.is_some());
+		unsafe {
+			self.device.cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+		}
+		self.bound_pipeline.set(None);
+	}
+
+	/// Issues the queued indirect draws, and when deferred shading and still drawing to the G-buffer, moves on to the
+	/// lighting subpass: the G-buffer is lit over the whole render image, as stereo eyes share it. The rest of the
+	/// frame, e.g. blended surfaces, custom shaders and particles, is drawn forward on top of the lit surfaces.
+	fn finish_geometry(&self, cmd_buf: vk::CommandBuffer)
+	{
+		self.flush_indirect_draws(cmd_buf);
+		let lighting_pipeline = match &self.lighting_pipeline
+		{
+			Some(lighting_pipeline) if self.geometry_subpass.get() => lighting_pipeline.get(),
+			_ => return,
+		};
+		self.geometry_subpass.set(false);
+		let (viewport, scissor) = self.draw_region.get();
+		unsafe {
+			self.device.cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+			self.device.cmd_set_viewport(cmd_buf, 0, &[self.viewport]);
+			self.device.cmd_set_scissor(cmd_buf, 0, &[self.scissor]);
+			self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, lighting_pipeline);
+			self.device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+			// Back to the region being drawn
+			self.device.cmd_set_viewport(cmd_buf, 0, &[viewport]);
+			self.device.cmd_set_scissor(cmd_buf, 0, &[scissor]);
+		}
+		self.bound_pipeline.set(None);
+	}
+
+	/// Allocates a morph set for the given morph target offsets and weights, see MorphWeights.
+	pub fn create_morph_set(
+		rs: &RenderState, descriptor_pool: vk::DescriptorPool, layout: vk::DescriptorSetLayout, deltas: vk::Buffer,
+		weights: vk::Buffer,
+	) -> Result<vk::DescriptorSet, String>
+	{
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_count: 1,
+			p_set_layouts: &layout,
+		};
+		let descriptor_set;
+		unsafe {
+			descriptor_set = rs
+				.device
+				.allocate_descriptor_sets(&desc_alloc_info)
+				.map_err(|e| format!("could not allocate morph descriptor set: {}", e))?[0];
+		}
+		let buffer_descriptors = [
+			vk::DescriptorBufferInfo {
+				buffer: deltas,
+				offset: 0,
+				range: vk::WHOLE_SIZE,
+			},
+			vk::DescriptorBufferInfo {
+				buffer: weights,
+				offset: 0,
+				range: size_of::<MorphUniforms>() as u64,
+			},
+		];
+		let write_desc_sets = [
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				p_next: ptr::null(),
+				dst_set: descriptor_set,
+				dst_binding: 0,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+				p_image_info: ptr::null(),
+				p_buffer_info: &buffer_descriptors[0],
+				p_texel_buffer_view: ptr::null(),
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				p_next: ptr::null(),
+				dst_set: descriptor_set,
+				dst_binding: 1,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+				p_image_info: ptr::null(),
+				p_buffer_info: &buffer_descriptors[1],
+				p_texel_buffer_view: ptr::null(),
+			},
+		];
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+		return Ok(descriptor_set);
+	}
+
+	/// Binds the morph set without targets again, after drawing a morphed object.
+	pub fn bind_default_morph_set(&self, cmd_buf: vk::CommandBuffer)
+	{
+		unsafe {
+			self.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				self.pipeline_layout,
+				3,
+				&[self.default_morph_ds],
+				&[],
+			);
+		}
+	}
+
+	/// Queues a mesh draw of the instances, given as model and MVP matrices, to be issued as part of an indirect
+	/// multi-draw. Returns false if the mesh must be drawn directly instead, see IndirectDraws.
+	pub fn queue_indirect_draw(
+		&self, mesh: &Mesh, lod: usize, material: &Material, instances: &[(Matrix4<f32>, Matrix4<f32>)],
+	) -> bool
+	{
+		// Indirect draws share the pipelines of the default permutation
+		if material.get_permutation_index() != 0
+		{
+			return false;
+		}
+		let queued = self.indirect_draws.borrow_mut().queue(mesh, lod, material, instances);
+		if queued
+		{
+			self.vertex_bytes.set(self.vertex_bytes.get() + instances.len() as u64 * mesh.get_vertex_bytes());
+		}
+		return queued;
+	}
+
+	/// Issues the queued indirect draws, to the depth prepass or G-buffer if still drawing to them.
+	fn flush_indirect_draws(&self, cmd_buf: vk::CommandBuffer)
+	{
+		let indirect_pipelines = match (&self.depth_indirect_pipelines, &self.gbuffer_indirect_pipelines)
+		{
+			(Some(depth_indirect_pipelines), _) if self.drawing_depth.get() => depth_indirect_pipelines,
+			(_, Some(gbuffer_indirect_pipelines)) if self.geometry_subpass.get() => gbuffer_indirect_pipelines,
+			_ => &self.indirect_pipelines,
+		};
+		let indirect_pipelines = [indirect_pipelines[0].get(), indirect_pipelines[1].get()];
+		self.indirect_draws.borrow_mut().flush(cmd_buf, indirect_pipelines, self.pipeline_layout);
+		// Whatever was bound, it is no longer a direct mesh pipeline
+		self.bound_pipeline.set(None);
+	}
+
 	/// Ends the main render frame
 	pub fn end_frame(&mut self, rs: &RenderState)
 	{
 		let cmd_buf = self.commandbuffer;
+		self.finish_depth_prepass(cmd_buf);
+		self.finish_geometry(cmd_buf);
+		rs.add_pass_vertex_bytes(&format!("{}: scene", self.name), self.vertex_bytes.get());
 
 		unsafe {
 			// End render pass and command buffer
 			rs.device.cmd_end_render_pass(cmd_buf);
+		}
+		rs.end_pass(cmd_buf);
+		unsafe {
 			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
 		}
 
@@ -628,26 +2853,12 @@ impl Drop for MainPass
 		// We cannot have the last reference to device at this point
 		debug_assert!(1 < Rc::strong_count(&self.device));
 
+		// Buffers, images and pipelines are released when dropped
 		unsafe {
 			// Always wait for device idle
 			self.device.device_wait_idle().unwrap();
 
-			self.device.destroy_buffer(self.view_matrix_ub, None);
-			self.device.free_memory(self.view_matrix_ub_mem, None);
-
-			self.device.destroy_sampler(self.depth_image.sampler, None);
-			self.device.destroy_image_view(self.depth_image.view, None);
-			self.device.destroy_image(self.depth_image.image, None);
-			self.device.free_memory(self.depth_image.memory, None);
-
-			self.device.destroy_sampler(self.render_image.sampler, None);
-			self.device.destroy_image_view(self.render_image.view, None);
-			self.device.destroy_image(self.render_image.image, None);
-			self.device.free_memory(self.render_image.memory, None);
-
 			self.device.destroy_framebuffer(self.framebuffer, None);
-
-			self.device.destroy_pipeline(self.pipeline, None);
 			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
 
 			for &dset_layout in self.descriptor_set_layouts.iter()
@@ -1,50 +1,113 @@
-use crate::core::Config;
+use crate::core::{write_atomic, AssetLoader, Config, FileWatcher, ImageRequest, LogLevel, Logger, VertexFormat};
 use ash::extensions::{
 	ext::DebugReport,
-	khr::{Surface, Swapchain, XlibSurface},
+	khr::{Surface, Swapchain},
 };
 use ash::util::Align;
-use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
+use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0, InstanceV1_1};
 use ash::vk;
 use ash::{Device, Entry, Instance};
 use image;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
 use std::ffi::{CStr, CString};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::mem::{align_of, size_of};
 use std::os::raw::{c_char, c_void};
 use std::path::Path;
 use std::ptr;
 use std::rc::Rc;
-
+use std::time::{Duration, Instant};
+
+mod allocator;
+mod auto_quality;
+mod capture;
+mod color_grading;
+mod damage;
+mod debug_view;
+mod deletion_queue;
+mod font;
+mod frame_trace;
+mod gpu_info;
+mod gpu_timer;
+mod handles;
+mod heatmaps;
+mod indirect;
+mod irradiance_grid;
+mod leak_tracker;
+mod lens_flare;
+mod lines;
 mod mainpass;
+mod overlay;
+mod pass_stats;
 mod presentpass;
-
-pub use self::mainpass::MainPass;
+mod reflection_probes;
+#[cfg(feature = "runtime_shaders")]
+mod shader_compiler;
+mod shadowpass;
+mod stereo;
+mod viewport_mapper;
+mod volumetric_fog;
+
+// Compiled pipelines are kept here between runs, see RenderState::create_pipeline_cache()
+const PIPELINE_CACHE_PATH: &str = "pipeline_cache.bin";
+
+use self::allocator::{Allocation, MemoryAllocator};
+pub use self::auto_quality::AutoQuality;
+pub use self::capture::{CaptureReader, CaptureRing};
+pub use self::color_grading::ColorGrading;
+pub use self::damage::{DamageBaker, DamageDecal, DamageTexture};
+pub use self::debug_view::DebugView;
+use self::deletion_queue::DeletionQueue;
+pub use self::font::Icon;
+use self::frame_trace::FrameTrace;
+use self::gpu_timer::GpuTimer;
+use self::handles::Releaser;
+pub use self::handles::{BufferHandle, ImageHandle, PipelineHandle};
+pub use self::heatmaps::{HeatmapMode, Heatmaps};
+use self::indirect::IndirectDraws;
+pub use self::irradiance_grid::{IrradianceGrid, IrradianceVolume};
+pub use self::leak_tracker::{track_create, track_destroy, ObjectKind};
+pub use self::lens_flare::{FlareElement, LensFlare, MAX_FLARE_ELEMENTS};
+pub use self::lines::{LineBatch, TriangleBatch};
+pub use self::mainpass::{
+	DirectionalLight, FrameUniforms, IrradianceGridUniforms, LightUniforms, MainPass, PipelinePermutation,
+	ProbeUniforms,
+};
+pub use self::overlay::{Overlay, OverlayImage, TextStyle};
+use self::pass_stats::PassStats;
 pub use self::presentpass::PresentPass;
-
+pub use self::reflection_probes::{ReflectionProbe, ReflectionProbes, MAX_REFLECTION_PROBES};
+#[cfg(feature = "runtime_shaders")]
+use self::shader_compiler::ShaderCompiler;
+pub use self::shadowpass::{PointLight, ShadowPass, ShadowTile};
+pub use self::stereo::{Stereo, StereoMode};
+pub use self::viewport_mapper::{get_display_scale, ViewportMapper};
+pub use self::volumetric_fog::VolumetricFog;
+
+/// An image with its view and sampler, and the state it was last transitioned to. Released when dropped.
 pub struct Texture
 {
+	// Owned by the handle
 	pub image: vk::Image,
-	pub memory: vk::DeviceMemory,
 	pub view: vk::ImageView,
 	pub sampler: vk::Sampler,
+	aspect_mask: vk::ImageAspectFlags,
 	current_access_mask: vk::AccessFlags,
 	pub current_layout: vk::ImageLayout,
 	current_stage: vk::PipelineStageFlags,
+	_handle: ImageHandle,
 }
 
-impl Texture
+/// A texture requested with RenderState::load_image_async(), waiting to be decoded and uploaded.
+struct TextureUpload
 {
-	pub fn destroy(&mut self, device: &Device)
-	{
-		unsafe {
-			device.destroy_sampler(self.sampler, None);
-			device.destroy_image_view(self.view, None);
-			device.destroy_image(self.image, None);
-			device.free_memory(self.memory, None);
-		}
-	}
+	request: ImageRequest,
+	srgb: bool,
+	on_ready: Box<dyn FnOnce(Texture)>,
 }
 
 pub struct RenderState
@@ -59,28 +122,73 @@ pub struct RenderState
 	device_memory_properties: vk::PhysicalDeviceMemoryProperties,
 	queue_family_index: u32,
 	graphics_queue: vk::Queue,
+	// Whether meshes can be drawn with indirect multi-draws
+	multi_draw_indirect: bool,
+	// Whether fragment shaders can write storage images, for the fragment counting heatmaps
+	fragment_stores_and_atomics: bool,
+	// Format of meshes loaded with packed vertices, full where the GPU can't read the packed formats
+	packed_vertex_format: VertexFormat,
+	// Samples per pixel of the main passes, see Config::msaa_samples
+	msaa_samples: vk::SampleCountFlags,
+	// Whether presenting waits for the display, see Config::vsync
+	vsync: bool,
+	// Whether the window is presented for capture tools, see Config::capture
+	capture: bool,
 
 	pub window: sdl2::video::Window,
 
 	// Pools
 	commandpool: vk::CommandPool,
+	// Shared by all pipelines. Pipeline caches are internally synchronized, so pipelines can be created on any thread.
+	pub pipeline_cache: vk::PipelineCache,
+
+	// Passes and barriers of the current frame, when a capture has been requested
+	frame_trace: RefCell<FrameTrace>,
+	pass_stats: RefCell<PassStats>,
+	gpu_timer: RefCell<GpuTimer>,
+	// Material permutations requested so far, each main pass creates pipelines for all of them
+	pipeline_permutations: RefCell<Vec<PipelinePermutation>>,
+	// Objects of dropped handles, destroyed once the GPU is done with them
+	deletion_queue: Rc<RefCell<DeletionQueue>>,
+	// Memory of the buffers and images, shared with the handles freeing it
+	allocator: Rc<RefCell<MemoryAllocator>>,
+	// Decodes textures in the background, which are uploaded in order with a budget per frame
+	asset_loader: AssetLoader,
+	texture_uploads: RefCell<VecDeque<TextureUpload>>,
+	texture_upload_budget: usize,
+
+	// The shaders loaded so far, and with runtime_shaders their sources, see poll_shader_changes()
+	shader_watcher: RefCell<FileWatcher>,
+	// Compiles the shaders from source when they are loaded, if enabled
+	#[cfg(feature = "runtime_shaders")]
+	shader_compiler: Option<ShaderCompiler>,
 }
 
 impl RenderState
 {
-	/// Lists the extensions required by the application.
-	fn extension_names() -> Vec<*const i8>
+	/// Lists the extensions required by the application. The surface extensions are the ones of the video driver the
+	/// window was created with, e.g. VK_KHR_xlib_surface on X11, VK_KHR_wayland_surface on Wayland and
+	/// VK_KHR_win32_surface on Windows.
+	fn extension_names(window: &sdl2::video::Window) -> Vec<CString>
 	{
-		let mut extensions = vec![Surface::name().as_ptr(), XlibSurface::name().as_ptr()];
+		let mut extensions = vec![Surface::name().to_owned()];
+		for name in window.vulkan_instance_extensions().expect("Could not get the surface extensions of the window")
+		{
+			let name = CString::new(name).unwrap();
+			if !extensions.contains(&name)
+			{
+				extensions.push(name);
+			}
+		}
 		if cfg!(feature = "debug_layer")
 		{
-			extensions.push(DebugReport::name().as_ptr());
+			extensions.push(DebugReport::name().to_owned());
 		}
 		extensions
 	}
 
-	/// Creates a Vulkan instance.
-	fn create_instance(cfg: &Config, entry: &Entry) -> Instance
+	/// Creates a Vulkan instance, for presenting to the given window and others created like it.
+	fn create_instance(cfg: &Config, entry: &Entry, window: &sdl2::video::Window) -> Instance
 	{
 		// Application info
 		let app_name = CString::new(cfg.app_name.clone()).unwrap();
@@ -124,7 +232,8 @@ impl RenderState
 		}
 
 		// Instance
-		let extension_names_raw = RenderState::extension_names();
+		let extension_names = RenderState::extension_names(window);
+		let extension_names_raw: Vec<*const i8> = extension_names.iter().map(|name| name.as_ptr()).collect();
 		let create_info = vk::InstanceCreateInfo {
 			s_type: vk::StructureType::INSTANCE_CREATE_INFO,
 			p_application_info: &appinfo,
@@ -146,11 +255,19 @@ impl RenderState
 	///
 	/// This function is called from the debug layer if an issue is identified.
 	unsafe extern "system" fn vulkan_debug_callback(
-		_: vk::DebugReportFlagsEXT, _: vk::DebugReportObjectTypeEXT, _: u64, _: usize, _: i32, _: *const c_char,
+		flags: vk::DebugReportFlagsEXT, _: vk::DebugReportObjectTypeEXT, _: u64, _: usize, _: i32, _: *const c_char,
 		p_message: *const c_char, _: *mut c_void,
 	) -> u32
 	{
-		println!("{:?}", CStr::from_ptr(p_message));
+		let level = if flags.contains(vk::DebugReportFlagsEXT::ERROR)
+		{
+			LogLevel::Error
+		}
+		else
+		{
+			LogLevel::Warning
+		};
+		Logger::log(level, CStr::from_ptr(p_message).to_string_lossy().to_string());
 		1
 	}
 
@@ -211,8 +328,78 @@ impl RenderState
 		(pdevice, queue_family_index as u32)
 	}
 
+	/// Returns VertexFormat::Packed if the device can read all of its attribute formats from vertex buffers, otherwise
+	/// VertexFormat::Full. Only the formats of full vertices are required by Vulkan.
+	fn pick_packed_vertex_format(instance: &Instance, pdevice: vk::PhysicalDevice, requested: bool) -> VertexFormat
+	{
+		let supported = VertexFormat::Packed.get_attribute_descriptions().iter().all(|attribute| {
+			let properties;
+			unsafe {
+				properties = instance.get_physical_device_format_properties(pdevice, attribute.format);
+			}
+			properties.buffer_features.contains(vk::FormatFeatureFlags::VERTEX_BUFFER)
+		});
+		if !supported
+		{
+			if requested
+			{
+				warning!("Packed vertices requested, but the GPU cannot read their formats. Using full vertices.");
+			}
+			return VertexFormat::Full;
+		}
+		return VertexFormat::Packed;
+	}
+
+	/// Returns the highest sample count up to the requested one that the device can render the main passes with.
+	/// Their depth is resolved for sampling after the pass, which needs Vulkan 1.2.
+	fn pick_msaa_samples(instance: &Instance, pdevice: vk::PhysicalDevice, requested: u32) -> vk::SampleCountFlags
+	{
+		if requested <= 1
+		{
+			return vk::SampleCountFlags::TYPE_1;
+		}
+		let properties;
+		let mut resolve_properties = vk::PhysicalDeviceDepthStencilResolveProperties::default();
+		unsafe {
+			properties = instance.get_physical_device_properties(pdevice);
+			if vk::version_major(properties.api_version) > 1 || vk::version_minor(properties.api_version) >= 2
+			{
+				let mut properties2 = vk::PhysicalDeviceProperties2 {
+					p_next: &mut resolve_properties as *mut vk::PhysicalDeviceDepthStencilResolveProperties
+						as *mut c_void,
+					..Default::default()
+				};
+				instance.get_physical_device_properties2(pdevice, &mut properties2);
+			}
+		}
+		if !resolve_properties.supported_depth_resolve_modes.contains(vk::ResolveModeFlags::SAMPLE_ZERO)
+		{
+			warning!("{} MSAA samples requested, but the GPU cannot resolve depth. Rendering without MSAA.", requested);
+			return vk::SampleCountFlags::TYPE_1;
+		}
+
+		let limits = &properties.limits;
+		let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+		// Sample count flags are the counts themselves
+		let count = [64, 32, 16, 8, 4, 2, 1]
+			.iter()
+			.cloned()
+			.find(|&count| count <= requested && supported.contains(vk::SampleCountFlags::from_raw(count)))
+			.unwrap_or(1);
+		if count != requested
+		{
+			warning!("{} MSAA samples are not supported by the GPU, using {}", requested, count);
+		}
+		return vk::SampleCountFlags::from_raw(count);
+	}
+
 	/// Creates a Vulkan device (logical) based on the instance and physical device.
-	fn create_logical_device(instance: &Instance, pdevice: vk::PhysicalDevice, queue_family_index: u32) -> Device
+	///
+	/// Also returns whether indirect multi-draws with a first instance, and fragment stores and atomics, are supported,
+	/// and enabled.
+	fn create_logical_device(
+		instance: &Instance, pdevice: vk::PhysicalDevice, queue_family_index: u32,
+	) -> (Device, bool, bool)
 	{
 		let queue_priorities = [1.0]; // One queue of priority 1.0
 		let queue_info = vk::DeviceQueueCreateInfo {
@@ -223,8 +410,23 @@ impl RenderState
 			..Default::default()
 		};
 		let device_extension_names_raw = [Swapchain::name().as_ptr()]; // VK_KHR_swapchain
+		let supported_features;
+		unsafe {
+			supported_features = instance.get_physical_device_features(pdevice);
+		}
+		let multi_draw_indirect = supported_features.multi_draw_indirect == vk::TRUE &&
+			supported_features.draw_indirect_first_instance == vk::TRUE;
+		let fragment_stores_and_atomics = supported_features.fragment_stores_and_atomics == vk::TRUE;
+		if !fragment_stores_and_atomics
+		{
+			warning!("Fragment stores and atomics are not supported, the fragment counting heatmaps are disabled");
+		}
 		let features = vk::PhysicalDeviceFeatures {
 			shader_clip_distance: vk::TRUE,
+			multi_draw_indirect: multi_draw_indirect as vk::Bool32,
+			draw_indirect_first_instance: multi_draw_indirect as vk::Bool32,
+			// Fragment counting for the debug heatmaps, see Heatmaps
+			fragment_stores_and_atomics: fragment_stores_and_atomics as vk::Bool32,
 			// Can request more stuff here later
 			..Default::default()
 		};
@@ -243,7 +445,7 @@ impl RenderState
 				instance.create_device(pdevice, &device_create_info, None).expect("Failed to create logical device");
 		}
 
-		device
+		(device, multi_draw_indirect, fragment_stores_and_atomics)
 	}
 
 	/// Creates various pools required by the RenderState.
@@ -261,26 +463,149 @@ impl RenderState
 		commandpool
 	}
 
+	/// Returns true if the saved pipeline cache data was written by this driver and device. Some drivers do not check
+	/// the header themselves, and fail on data from another device, or on a truncated file.
+	fn is_pipeline_cache_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool
+	{
+		// Header of version one: length, version, vendor id, device id and cache UUID
+		const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+		if data.len() < HEADER_SIZE
+		{
+			return false;
+		}
+		let read_u32 = |offset: usize| {
+			let mut bytes = [0u8; 4];
+			bytes.copy_from_slice(&data[offset..offset + 4]);
+			u32::from_ne_bytes(bytes)
+		};
+		return read_u32(0) as usize >= HEADER_SIZE &&
+			read_u32(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32 &&
+			read_u32(8) == properties.vendor_id &&
+			read_u32(12) == properties.device_id &&
+			data[16..HEADER_SIZE] == properties.pipeline_cache_uuid;
+	}
+
+	/// Creates the pipeline cache, with the pipelines saved by the last run if they are from the same driver and
+	/// device. Pipelines from other drivers are compiled again, and replace the saved ones on exit.
+	fn create_pipeline_cache(instance: &Instance, pdevice: vk::PhysicalDevice, device: &Device) -> vk::PipelineCache
+	{
+		let properties = unsafe { instance.get_physical_device_properties(pdevice) };
+		let mut initial_data = fs::read(PIPELINE_CACHE_PATH).unwrap_or_default();
+		if !initial_data.is_empty() && !RenderState::is_pipeline_cache_compatible(&initial_data, &properties)
+		{
+			warning!("Ignoring {}, it was saved by another driver or device", PIPELINE_CACHE_PATH);
+			initial_data.clear();
+		}
+		let pipeline_cache_create_info = vk::PipelineCacheCreateInfo {
+			s_type: vk::StructureType::PIPELINE_CACHE_CREATE_INFO,
+			initial_data_size: initial_data.len(),
+			p_initial_data: initial_data.as_ptr() as *const c_void,
+			..Default::default()
+		};
+		let pipeline_cache;
+		unsafe {
+			pipeline_cache = device.create_pipeline_cache(&pipeline_cache_create_info, None).unwrap();
+		}
+		return pipeline_cache;
+	}
+
+	/// Writes the pipelines of the pipeline cache to disk, for the next run to start faster.
+	fn save_pipeline_cache(&self)
+	{
+		let data;
+		unsafe {
+			data = self.device.get_pipeline_cache_data(self.pipeline_cache);
+		}
+		match data
+		{
+			Ok(data) =>
+			{
+				if let Err(e) = write_atomic(PIPELINE_CACHE_PATH, &data)
+				{
+					warning!("Could not save the pipeline cache: {}", e);
+				}
+			}
+			Err(e) => warning!("Could not read the pipeline cache: {}", e),
+		}
+	}
+
+	/// Sets the window icon from the given image file.
+	fn set_window_icon(window: &mut sdl2::video::Window, path: &str) -> Result<(), String>
+	{
+		let image = image::open(path).map_err(|e| e.to_string())?.to_rgba8();
+		let (width, height) = image.dimensions();
+		let mut pixels = image.into_raw();
+		let icon = sdl2::surface::Surface::from_data(
+			&mut pixels,
+			width,
+			height,
+			4 * width,
+			sdl2::pixels::PixelFormatEnum::RGBA32,
+		)?;
+		window.set_icon(icon);
+		return Ok(());
+	}
+
+	/// Initializes the video subsystem of SDL. Wayland sessions get the Wayland video driver, falling back to X11
+	/// through XWayland if SDL was built without it or cannot connect. Setting SDL_VIDEODRIVER overrides the choice.
+	/// Other platforms get SDL's default driver, e.g. Windows gets the Win32 one.
+	pub fn init_video(sdl_context: &sdl2::Sdl) -> sdl2::VideoSubsystem
+	{
+		let wayland_session = cfg!(unix) &&
+			(env::var("XDG_SESSION_TYPE").map_or(false, |session| session == "wayland") ||
+				env::var_os("WAYLAND_DISPLAY").is_some());
+		if wayland_session && env::var_os("SDL_VIDEODRIVER").is_none()
+		{
+			sdl2::hint::set("SDL_VIDEODRIVER", "wayland");
+			match sdl_context.video()
+			{
+				Ok(video_subsystem) =>
+				{
+					println!("Video driver: {}", video_subsystem.current_video_driver());
+					return video_subsystem;
+				}
+				Err(e) =>
+				{
+					warning!("Could not use the Wayland video driver, falling back to X11: {}", e);
+					sdl2::hint::set("SDL_VIDEODRIVER", "x11");
+				}
+			}
+		}
+		let video_subsystem = sdl_context.video().unwrap();
+		println!("Video driver: {}", video_subsystem.current_video_driver());
+		return video_subsystem;
+	}
+
 	/// Initializes the RenderState based in the passed Config.
 	pub fn init(cfg: &Config, video_subsystem: &sdl2::VideoSubsystem) -> RenderState
 	{
-		// Window
-		let window = video_subsystem
-			.window(
-				format!("{} {}", cfg.app_name, cfg.version_to_string()).as_str(),
-				cfg.window_width,
-				cfg.window_height,
-			)
-			.vulkan()
-			.resizable()
-			.build()
-			.unwrap();
+		// Window, sized for the DPI of the primary display
+		let display_scale = get_display_scale(video_subsystem, 0);
+		let mut window_builder = video_subsystem.window(
+			format!("{} {}", cfg.app_name, cfg.version_to_string()).as_str(),
+			(cfg.window_width as f32 * display_scale) as u32,
+			(cfg.window_height as f32 * display_scale) as u32,
+		);
+		window_builder.vulkan().resizable();
+		// Window capture picks up the whole window, decorations included
+		if cfg.capture
+		{
+			window_builder.borderless();
+		}
+		let mut window = window_builder.build().unwrap();
+		if !cfg.window_icon.is_empty()
+		{
+			if let Err(e) = RenderState::set_window_icon(&mut window, &cfg.window_icon)
+			{
+				warning!("Could not set window icon from {}: {}", cfg.window_icon, e);
+			}
+		}
 
 		// ash entry point
 		let entry = Entry::new().unwrap();
 
 		// Vulkan init
-		let instance = RenderState::create_instance(&cfg, &entry);
+		let instance = RenderState::create_instance(&cfg, &entry, &window);
 		let mut debug_report_loader = None;
 		let mut debug_callback = None;
 		if cfg!(feature = "debug_layer")
@@ -294,7 +619,10 @@ impl RenderState
 		unsafe {
 			device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
 		}
-		let device = RenderState::create_logical_device(&instance, pdevice, queue_family_index);
+		let (device, multi_draw_indirect, fragment_stores_and_atomics) =
+			RenderState::create_logical_device(&instance, pdevice, queue_family_index);
+		let msaa_samples = RenderState::pick_msaa_samples(&instance, pdevice, cfg.msaa_samples);
+		let packed_vertex_format = RenderState::pick_packed_vertex_format(&instance, pdevice, cfg.packed_vertices);
 		let graphics_queue;
 		unsafe {
 			graphics_queue = device.get_device_queue(queue_family_index, 0);
@@ -302,6 +630,8 @@ impl RenderState
 
 		// Other stuff
 		let commandpool = RenderState::create_pools(&device, queue_family_index);
+		let pipeline_cache = RenderState::create_pipeline_cache(&instance, pdevice, &device);
+		let gpu_timer = GpuTimer::new(&instance, pdevice, &device, queue_family_index);
 
 		// Return the RenderState
 		RenderState {
@@ -315,13 +645,117 @@ impl RenderState
 			device_memory_properties: device_memory_properties,
 			queue_family_index: queue_family_index,
 			graphics_queue: graphics_queue,
+			multi_draw_indirect: multi_draw_indirect,
+			fragment_stores_and_atomics: fragment_stores_and_atomics,
+			packed_vertex_format: packed_vertex_format,
+			msaa_samples: msaa_samples,
+			vsync: cfg.vsync,
+			capture: cfg.capture,
 
 			// Window
 			window: window,
 
 			// Pools
 			commandpool: commandpool,
+			pipeline_cache: pipeline_cache,
+
+			frame_trace: RefCell::new(FrameTrace::new()),
+			pass_stats: RefCell::new(PassStats::new()),
+			gpu_timer: RefCell::new(gpu_timer),
+			pipeline_permutations: RefCell::new(vec![PipelinePermutation::default()]),
+			deletion_queue: Rc::new(RefCell::new(DeletionQueue::new())),
+			allocator: Rc::new(RefCell::new(MemoryAllocator::new(device_memory_properties))),
+			asset_loader: AssetLoader::new(),
+			texture_uploads: RefCell::new(VecDeque::new()),
+			texture_upload_budget: (cfg.texture_upload_budget.max(0.0) * 1_000_000.0) as usize,
+
+			shader_watcher: RefCell::new(FileWatcher::new()),
+			#[cfg(feature = "runtime_shaders")]
+			shader_compiler: ShaderCompiler::new(),
+		}
+	}
+
+	/// Returns the index of the given material permutation, requesting its pipelines from the main passes if it is new.
+	/// The default permutation is 0.
+	pub fn get_permutation_index(&self, permutation: &PipelinePermutation) -> usize
+	{
+		let mut permutations = self.pipeline_permutations.borrow_mut();
+		if let Some(index) = permutations.iter().position(|known| known == permutation)
+		{
+			return index;
 		}
+		permutations.push(permutation.clone());
+		return permutations.len() - 1;
+	}
+
+	/// Returns where handles created by the RenderState release their objects.
+	fn get_releaser(&self) -> Releaser
+	{
+		return Releaser::new(&self.device, &self.deletion_queue, &self.allocator);
+	}
+
+	/// Takes ownership of a pipeline, which is released when the returned handle is dropped.
+	pub fn create_pipeline_handle(&self, pipeline: vk::Pipeline) -> PipelineHandle
+	{
+		return PipelineHandle::new(&self.get_releaser(), pipeline);
+	}
+
+	/// Queues a resource holding handles or other GPU objects for destruction once command buffers still using it have
+	/// completed.
+	pub fn defer<T: Any>(&self, resource: T)
+	{
+		self.deletion_queue.borrow_mut().defer(resource);
+	}
+
+	/// Advances the deletion queue to the next frame, and destroys the resources released long enough ago until the
+	/// time budget runs out. The rest are destroyed in later frames. Returns the number of resources destroyed.
+	pub fn collect_garbage(&self, budget: Duration) -> usize
+	{
+		self.deletion_queue.borrow_mut().next_frame();
+		let start = Instant::now();
+		let mut destroyed = 0;
+		while destroyed == 0 || start.elapsed() < budget
+		{
+			let resource = self.deletion_queue.borrow_mut().pop_expired();
+			match resource
+			{
+				Some(resource) => drop(resource),
+				None => break,
+			}
+			destroyed += 1;
+		}
+		return destroyed;
+	}
+
+	/// Returns whether the device supports drawing meshes with indirect multi-draws.
+	pub fn supports_multi_draw_indirect(&self) -> bool
+	{
+		return self.multi_draw_indirect;
+	}
+
+	/// Returns whether the device supports counting fragments for the heatmaps, see Heatmaps.
+	pub fn supports_fragment_counting(&self) -> bool
+	{
+		return self.fragment_stores_and_atomics;
+	}
+
+	/// Returns VertexFormat::Packed, or VertexFormat::Full where the device can't read packed vertices. See
+	/// Config::packed_vertices.
+	pub fn get_packed_vertex_format(&self) -> VertexFormat
+	{
+		return self.packed_vertex_format;
+	}
+
+	/// Returns the properties of the GPU in use, like its name and driver version.
+	pub fn get_device_properties(&self) -> vk::PhysicalDeviceProperties
+	{
+		return unsafe { self.instance.get_physical_device_properties(self.pdevice) };
+	}
+
+	/// Returns the samples per pixel to render the main passes with, see Config::msaa_samples.
+	pub fn get_msaa_samples(&self) -> vk::SampleCountFlags
+	{
+		return self.msaa_samples;
 	}
 
 	/// Returns a suitable memory type for the requirements based in the physical Vulkan device.
@@ -337,6 +771,22 @@ impl RenderState
 		panic!("Cannot find memory type!");
 	}
 
+	/// Allocates memory with the properties for a buffer, or an image with optimal tiling, from the blocks of the
+	/// allocator.
+	fn allocate_memory(
+		&self, requirements: vk::MemoryRequirements, properties: vk::MemoryPropertyFlags, image: bool,
+	) -> Allocation
+	{
+		let memory_type = self.find_memory_type(requirements.memory_type_bits, properties);
+		return self.allocator.borrow_mut().allocate(&self.device, requirements, memory_type, image);
+	}
+
+	/// Returns the number of memory blocks allocated from the driver, and the bytes used of them and in total.
+	pub fn get_memory_usage(&self) -> (usize, u64, u64)
+	{
+		return self.allocator.borrow().get_usage();
+	}
+
 	/// Begins a commandbuffer that can be used for small GPU operations.
 	fn begin_single_time_commands(&self) -> vk::CommandBuffer
 	{
@@ -387,10 +837,23 @@ impl RenderState
 		}
 	}
 
-	/// Creates a vk::Buffer based on the requirements.
+	/// Creates a buffer based on the requirements, released when the returned handle is dropped.
+	#[track_caller]
 	fn create_buffer(
 		&self, usage: vk::BufferUsageFlags, properties: vk::MemoryPropertyFlags, buffersize: vk::DeviceSize,
-	) -> (vk::Buffer, vk::DeviceMemory)
+	) -> BufferHandle
+	{
+		let (buffer, allocation) = self.allocate_buffer(usage, properties, buffersize);
+		return BufferHandle::new(&self.get_releaser(), buffer, allocation);
+	}
+
+	/// Creates a vk::Buffer based on the requirements.
+	///
+	/// Note: The caller is responsible for destroying the buffer and freeing its memory.
+	#[track_caller]
+	fn allocate_buffer(
+		&self, usage: vk::BufferUsageFlags, properties: vk::MemoryPropertyFlags, buffersize: vk::DeviceSize,
+	) -> (vk::Buffer, Allocation)
 	{
 		let bufferinfo = vk::BufferCreateInfo {
 			s_type: vk::StructureType::BUFFER_CREATE_INFO,
@@ -406,31 +869,28 @@ impl RenderState
 			buffer = self.device.create_buffer(&bufferinfo, None).expect("Failed to create buffer");
 			mem_req = self.device.get_buffer_memory_requirements(buffer);
 		}
+		track_create(ObjectKind::Buffer, buffer, &format!("{:?}, {} bytes", usage, buffersize));
 
-		let alloc_info = vk::MemoryAllocateInfo {
-			s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
-			p_next: ptr::null(),
-			allocation_size: mem_req.size,
-			memory_type_index: self.find_memory_type(mem_req.memory_type_bits, properties),
-		};
-		let memory;
+		let allocation = self.allocate_memory(mem_req, properties, false);
 		unsafe {
-			memory = self.device.allocate_memory(&alloc_info, None).expect("Failed to allocate buffer memory");
-
-			self.device.bind_buffer_memory(buffer, memory, 0).expect("Failed to bind memory");
+			self.device
+				.bind_buffer_memory(buffer, allocation.memory, allocation.offset)
+				.expect("Failed to bind memory");
 		}
 
-		(buffer, memory)
+		(buffer, allocation)
 	}
 
-	/// Creates a vk::Buffer based on the requirements and fills it with the passed data.
+	/// Creates a buffer based on the requirements and fills it with the passed data. Released when the returned handle
+	/// is dropped.
+	#[track_caller]
 	pub fn create_buffer_and_upload<T: Copy>(
 		&self, usage: vk::BufferUsageFlags, properties: vk::MemoryPropertyFlags, upload_data: &[T],
 		optimal_layout: bool,
-	) -> (vk::Buffer, vk::DeviceMemory)
+	) -> BufferHandle
 	{
 		let mut buffer;
-		let mut memory;
+		let mut allocation;
 		let buffersize: vk::DeviceSize = (size_of::<T>() * upload_data.len()) as u64;
 
 		// Create a temporary staging buffer
@@ -440,13 +900,13 @@ impl RenderState
 				(properties & vk::MemoryPropertyFlags::DEVICE_LOCAL) == vk::MemoryPropertyFlags::DEVICE_LOCAL
 			);
 
-			let (buf, mem) = self.create_buffer(
+			let (buf, alloc) = self.allocate_buffer(
 				vk::BufferUsageFlags::TRANSFER_SRC,
 				vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
 				buffersize,
 			);
 			buffer = buf;
-			memory = mem;
+			allocation = alloc;
 		// Create the actual buffer
 		}
 		else
@@ -456,20 +916,15 @@ impl RenderState
 					(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT)
 			);
 
-			let (buf, mem) = self.create_buffer(usage, properties, buffersize);
+			let (buf, alloc) = self.allocate_buffer(usage, properties, buffersize);
 			buffer = buf;
-			memory = mem;
+			allocation = alloc;
 		}
 
 		// Upload data to the buffer we just created
 		unsafe {
-			let mem_ptr = self
-				.device
-				.map_memory(memory, 0, buffersize, vk::MemoryMapFlags::empty())
-				.expect("Failed to map index memory");
-			let mut mem_align = Align::new(mem_ptr, align_of::<T>() as u64, buffersize);
+			let mut mem_align = Align::new(allocation.mapped, align_of::<T>() as u64, buffersize);
 			mem_align.copy_from_slice(upload_data);
-			self.device.unmap_memory(memory);
 		}
 
 		// For optimal buffers: create a new buffer with the optimal layout and copy the staging
@@ -477,12 +932,12 @@ impl RenderState
 		if optimal_layout
 		{
 			let staging_buffer = buffer;
-			let staging_memory = memory;
+			let staging_allocation = allocation;
 
 			// Create final buffer
-			let (buf, mem) = self.create_buffer(vk::BufferUsageFlags::TRANSFER_DST | usage, properties, buffersize);
+			let (buf, alloc) = self.allocate_buffer(vk::BufferUsageFlags::TRANSFER_DST | usage, properties, buffersize);
 			buffer = buf;
-			memory = mem;
+			allocation = alloc;
 
 			// Copy contents
 			let cmd_buf = self.begin_single_time_commands();
@@ -497,22 +952,42 @@ impl RenderState
 			self.end_single_time_commands(cmd_buf);
 
 			// Free staging buffer
+			track_destroy(ObjectKind::Buffer, staging_buffer);
 			unsafe {
 				self.device.destroy_buffer(staging_buffer, None);
-				self.device.free_memory(staging_memory, None);
 			}
+			self.allocator.borrow_mut().free(&self.device, staging_allocation);
 		}
 
-		(buffer, memory)
+		return BufferHandle::new(&self.get_releaser(), buffer, allocation);
 	}
 
 	/// Creates a vk::ShaderModule from the given path.
 	///
-	/// Note: The path must point to a .spv file.
-	fn load_shader(&self, path: &str) -> vk::ShaderModule
+	/// Note: The path must point to a .spv file. With the runtime_shaders feature, the shader is compiled from its GLSL
+	/// source instead, with the given defines, falling back to the .spv file.
+	fn load_shader(&self, path: &str, defines: &[(&str, String)]) -> vk::ShaderModule
 	{
-		let spv_file = File::open(Path::new(path)).expect("Could not find spv file");
-		let shader_bytes: Vec<u8> = spv_file.bytes().filter_map(|byte| byte.ok()).collect();
+		let mut watcher = self.shader_watcher.borrow_mut();
+		watcher.watch(path);
+		#[cfg(feature = "runtime_shaders")]
+		for source in ShaderCompiler::get_source_files(path)
+		{
+			watcher.watch(&source.to_string_lossy());
+		}
+
+		#[cfg(feature = "runtime_shaders")]
+		let compiled = self.shader_compiler.as_ref().and_then(|compiler| compiler.compile(path, defines));
+		#[cfg(not(feature = "runtime_shaders"))]
+		let compiled: Option<Vec<u8>> = {
+			// Precompiled shaders have the defaults of the defines baked in
+			let _ = defines;
+			None
+		};
+		let shader_bytes = compiled.unwrap_or_else(|| {
+			let spv_file = File::open(Path::new(path)).expect("Could not find spv file");
+			spv_file.bytes().filter_map(|byte| byte.ok()).collect()
+		});
 		let shader_info = vk::ShaderModuleCreateInfo {
 			s_type: vk::StructureType::SHADER_MODULE_CREATE_INFO,
 			code_size: shader_bytes.len(),
@@ -526,16 +1001,51 @@ impl RenderState
 		shader_module
 	}
 
+	/// Returns the loaded shaders that changed on disk since the last call, e.g. rebuilt by cargo build. With the
+	/// runtime_shaders feature, changes to their sources and includes are returned too. See MainPass::reload_shaders()
+	/// and PresentPass::reload_shaders().
+	pub fn poll_shader_changes(&self) -> Vec<String>
+	{
+		return self.shader_watcher.borrow_mut().poll();
+	}
+
 	/// Creates a texture, view and sampler based on the passed options.
 	///
 	/// A vk::Buffer can optionally be passed to fill the texture with initial data.
+	#[track_caller]
 	fn create_texture(
 		&self, texture_dimensions: vk::Extent3D, texture_type: vk::ImageType, texture_view_type: vk::ImageViewType,
-		texture_format: vk::Format, texture_aspect_mask: vk::ImageAspectFlags, mut texture_usage: vk::ImageUsageFlags,
+		texture_format: vk::Format, texture_aspect_mask: vk::ImageAspectFlags, texture_usage: vk::ImageUsageFlags,
 		initial_access_mask: vk::AccessFlags, initial_layout: vk::ImageLayout, initial_stage: vk::PipelineStageFlags,
 		upload_buffer: Option<vk::Buffer>,
 	) -> Texture
 	{
+		return self.create_multisampled_texture(
+			texture_dimensions,
+			texture_type,
+			texture_view_type,
+			texture_format,
+			texture_aspect_mask,
+			texture_usage,
+			initial_access_mask,
+			initial_layout,
+			initial_stage,
+			upload_buffer,
+			vk::SampleCountFlags::TYPE_1,
+		);
+	}
+
+	/// Creates a texture as create_texture() does, with the given samples per texel. Multisampled textures cannot be
+	/// filled with initial data.
+	#[track_caller]
+	fn create_multisampled_texture(
+		&self, texture_dimensions: vk::Extent3D, texture_type: vk::ImageType, texture_view_type: vk::ImageViewType,
+		texture_format: vk::Format, texture_aspect_mask: vk::ImageAspectFlags, mut texture_usage: vk::ImageUsageFlags,
+		initial_access_mask: vk::AccessFlags, initial_layout: vk::ImageLayout, initial_stage: vk::PipelineStageFlags,
+		upload_buffer: Option<vk::Buffer>, samples: vk::SampleCountFlags,
+	) -> Texture
+	{
+		debug_assert!(upload_buffer.is_none() || samples == vk::SampleCountFlags::TYPE_1);
 		// In case we need to upload to the texture, mark it for transfer dst
 		if upload_buffer.is_some()
 		{
@@ -549,7 +1059,7 @@ impl RenderState
 			extent: texture_dimensions,
 			mip_levels: 1,
 			array_layers: 1,
-			samples: vk::SampleCountFlags::TYPE_1,
+			samples: samples,
 			tiling: vk::ImageTiling::OPTIMAL,
 			usage: texture_usage,
 			sharing_mode: vk::SharingMode::EXCLUSIVE,
@@ -562,18 +1072,14 @@ impl RenderState
 			texture_image = self.device.create_image(&texture_create_info, None).unwrap();
 			texture_memory_req = self.device.get_image_memory_requirements(texture_image);
 		}
+		let tag = format!("{:?} {}x{}", texture_format, texture_dimensions.width, texture_dimensions.height);
+		track_create(ObjectKind::Image, texture_image, &tag);
 
-		let texture_allocate_info = vk::MemoryAllocateInfo {
-			s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
-			p_next: ptr::null(),
-			allocation_size: texture_memory_req.size,
-			memory_type_index: self
-				.find_memory_type(texture_memory_req.memory_type_bits, vk::MemoryPropertyFlags::DEVICE_LOCAL),
-		};
-		let texture_memory;
+		let texture_allocation = self.allocate_memory(texture_memory_req, vk::MemoryPropertyFlags::DEVICE_LOCAL, true);
 		unsafe {
-			texture_memory = self.device.allocate_memory(&texture_allocate_info, None).unwrap();
-			self.device.bind_image_memory(texture_image, texture_memory, 0).expect("Failed to bind memory");
+			self.device
+				.bind_image_memory(texture_image, texture_allocation.memory, texture_allocation.offset)
+				.expect("Failed to bind memory");
 		}
 
 		// Transition the Image and potentially upload
@@ -724,6 +1230,7 @@ impl RenderState
 		unsafe {
 			texture_view = self.device.create_image_view(&tex_image_view_info, None).unwrap();
 		}
+		track_create(ObjectKind::ImageView, texture_view, &tag);
 
 		// Create sampler
 		let sampler_info = vk::SamplerCreateInfo {
@@ -741,69 +1248,306 @@ impl RenderState
 		unsafe {
 			sampler = self.device.create_sampler(&sampler_info, None).unwrap();
 		}
+		track_create(ObjectKind::Sampler, sampler, &tag);
 
 		return Texture {
 			image: texture_image,
-			memory: texture_memory,
 			view: texture_view,
 			sampler: sampler,
+			aspect_mask: texture_aspect_mask,
 			current_access_mask: initial_access_mask,
 			current_layout: initial_layout,
 			current_stage: initial_stage,
+			_handle: ImageHandle::new(&self.get_releaser(), texture_image, texture_allocation, texture_view, sampler),
+		};
+	}
+
+	/// Creates a cube texture of size x size texels per face, in the shader read only layout. The view is a cube view
+	/// of all faces, and the sampler clamps to the edges.
+	///
+	/// Note: The caller is responsible for cleaning up the returned vulkan types.
+	#[track_caller]
+	fn create_cube_texture(
+		&self, size: u32, format: vk::Format, aspect_mask: vk::ImageAspectFlags, usage: vk::ImageUsageFlags,
+	) -> Texture
+	{
+		let subresource_range = vk::ImageSubresourceRange {
+			aspect_mask: aspect_mask,
+			base_mip_level: 0,
+			level_count: 1,
+			base_array_layer: 0,
+			layer_count: 6,
+		};
+		let image_create_info = vk::ImageCreateInfo {
+			s_type: vk::StructureType::IMAGE_CREATE_INFO,
+			flags: vk::ImageCreateFlags::CUBE_COMPATIBLE,
+			image_type: vk::ImageType::TYPE_2D,
+			format: format,
+			extent: vk::Extent3D {
+				width: size,
+				height: size,
+				depth: 1,
+			},
+			mip_levels: 1,
+			array_layers: 6,
+			samples: vk::SampleCountFlags::TYPE_1,
+			tiling: vk::ImageTiling::OPTIMAL,
+			usage: usage | vk::ImageUsageFlags::SAMPLED,
+			sharing_mode: vk::SharingMode::EXCLUSIVE,
+			initial_layout: vk::ImageLayout::UNDEFINED,
+			..Default::default()
+		};
+		let image;
+		let memory_req;
+		unsafe {
+			image = self.device.create_image(&image_create_info, None).unwrap();
+			memory_req = self.device.get_image_memory_requirements(image);
+		}
+		let tag = format!("{:?} {}x{} cube", format, size, size);
+		track_create(ObjectKind::Image, image, &tag);
+
+		let allocation = self.allocate_memory(memory_req, vk::MemoryPropertyFlags::DEVICE_LOCAL, true);
+		unsafe {
+			self.device.bind_image_memory(image, allocation.memory, allocation.offset).expect("Failed to bind memory");
+		}
+
+		// Sampled before anything is rendered to it, so it must be in a readable layout from the start
+		let barrier = vk::ImageMemoryBarrier {
+			s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+			dst_access_mask: vk::AccessFlags::SHADER_READ,
+			old_layout: vk::ImageLayout::UNDEFINED,
+			new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			image: image,
+			subresource_range: subresource_range,
+			..Default::default()
+		};
+		let cmd_buf = self.begin_single_time_commands();
+		unsafe {
+			self.device.cmd_pipeline_barrier(
+				cmd_buf,
+				vk::PipelineStageFlags::TOP_OF_PIPE,
+				vk::PipelineStageFlags::FRAGMENT_SHADER,
+				vk::DependencyFlags::empty(),
+				&[],
+				&[],
+				&[barrier],
+			);
+		}
+		self.end_single_time_commands(cmd_buf);
+
+		let view_info = vk::ImageViewCreateInfo {
+			s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
+			view_type: vk::ImageViewType::CUBE,
+			format: format,
+			subresource_range: subresource_range,
+			image: image,
+			..Default::default()
+		};
+		let view;
+		unsafe {
+			view = self.device.create_image_view(&view_info, None).unwrap();
+		}
+		track_create(ObjectKind::ImageView, view, &tag);
+
+		let sampler_info = vk::SamplerCreateInfo {
+			s_type: vk::StructureType::SAMPLER_CREATE_INFO,
+			mag_filter: vk::Filter::NEAREST,
+			min_filter: vk::Filter::NEAREST,
+			mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+			address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+			address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+			address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+			border_color: vk::BorderColor::FLOAT_OPAQUE_WHITE,
+			..Default::default()
+		};
+		let sampler;
+		unsafe {
+			sampler = self.device.create_sampler(&sampler_info, None).unwrap();
+		}
+		track_create(ObjectKind::Sampler, sampler, &tag);
+
+		return Texture {
+			image: image,
+			view: view,
+			sampler: sampler,
+			aspect_mask: aspect_mask,
+			current_access_mask: vk::AccessFlags::SHADER_READ,
+			current_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			current_stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+			_handle: ImageHandle::new(&self.get_releaser(), image, allocation, view, sampler),
 		};
 	}
 
 	/// Loads the image given by the path into read only texture.
 	///
 	/// Note: The caller is responsible for cleaning up the returned vulkan types.
+	#[track_caller]
 	pub fn load_image(&self, path: &str, srgb: bool) -> Texture
 	{
-		// Load the image data into a vk::Buffer
 		let image = image::open(path).unwrap().to_rgba8();
-		let image_extent;
+		return self.create_image_from_rgba(&image, srgb);
+	}
+
+	/// Starts loading the image given by the path into a read only texture in the background, and returns a
+	/// placeholder of a single texel of the given color to use until then. Once the image has been decoded and
+	/// uploaded, see submit_texture_uploads(), the texture is passed to on_ready. Images that cannot be loaded keep
+	/// the placeholder, with a warning.
+	#[track_caller]
+	pub fn load_image_async(
+		&self, path: &str, srgb: bool, placeholder: [u8; 4], on_ready: impl FnOnce(Texture) + 'static,
+	) -> Texture
+	{
+		self.texture_uploads.borrow_mut().push_back(TextureUpload {
+			request: self.asset_loader.load_image(path),
+			srgb: srgb,
+			on_ready: Box::new(on_ready),
+		});
+		return self.create_image_from_rgba(&image::RgbaImage::from_pixel(1, 1, image::Rgba(placeholder)), srgb);
+	}
+
+	/// Uploads the textures decoded since the last call, until the upload budget of the frame is spent, and passes
+	/// them on. The rest wait for the next frames. Must be called between frames, as the textures replace ones that
+	/// are bound.
+	pub fn submit_texture_uploads(&self)
+	{
+		let mut uploaded = 0;
+		let mut ready = Vec::new();
 		{
-			let image_dims = image.dimensions();
-			image_extent = vk::Extent3D {
-				width: image_dims.0,
-				height: image_dims.1,
-				depth: 1,
+			let mut uploads = self.texture_uploads.borrow_mut();
+			let mut idx = 0;
+			// Textures that are still decoding do not hold up the ones after them
+			while idx < uploads.len() && (uploaded == 0 || uploaded < self.texture_upload_budget)
+			{
+				let result = match uploads[idx].request.poll()
+				{
+					Some(result) => result,
+					None =>
+					{
+						idx += 1;
+						continue;
+					}
+				};
+				let upload = uploads.remove(idx).unwrap();
+				match result
+				{
+					Ok(image) =>
+					{
+						uploaded += image.len();
+						ready.push((upload.on_ready, self.create_image_from_rgba(&image, upload.srgb)));
+					}
+					Err(e) => warning!("Could not load texture {}", e),
+				}
+			}
+		}
+		// Outside the borrow, in case textures are requested from the callbacks
+		for (on_ready, texture) in ready
+		{
+			on_ready(texture);
+		}
+	}
+
+	/// Returns true while textures requested with load_image_async() have not been uploaded yet.
+	pub fn is_loading_textures(&self) -> bool
+	{
+		return !self.texture_uploads.borrow().is_empty();
+	}
+
+	/// Waits for all requested textures to be decoded, and uploads them regardless of the budget. Returns the errors
+	/// of those that could not be loaded, which keep their placeholders.
+	pub fn finish_texture_uploads(&self) -> Result<(), String>
+	{
+		let mut errors = Vec::new();
+		loop
+		{
+			let upload = match self.texture_uploads.borrow_mut().pop_front()
+			{
+				Some(upload) => upload,
+				None => break,
 			};
+			match upload.request.wait()
+			{
+				Ok(image) => (upload.on_ready)(self.create_image_from_rgba(&image, upload.srgb)),
+				Err(e) => errors.push(e),
+			}
+		}
+		if !errors.is_empty()
+		{
+			return Err(errors.join(", "));
+		}
+		return Ok(());
+	}
+
+	#[track_caller]
+	fn create_image_from_rgba(&self, image: &image::RgbaImage, srgb: bool) -> Texture
+	{
+		let format = if srgb
+		{
+			vk::Format::R8G8B8A8_SRGB
 		}
-		let image_data = image.into_raw();
-		let (image_buffer, image_memory) = self.create_buffer_and_upload(
+		else
+		{
+			vk::Format::R8G8B8A8_UNORM
+		};
+		let (width, height) = image.dimensions();
+		return self.create_image_from_data(width, height, format, image.as_raw());
+	}
+
+	/// Creates a read only texture of the given size and format, filled with the passed data.
+	///
+	/// Note: The caller is responsible for cleaning up the returned vulkan types.
+	#[track_caller]
+	pub fn create_image_from_data(&self, width: u32, height: u32, format: vk::Format, data: &[u8]) -> Texture
+	{
+		let image_extent = vk::Extent3D {
+			width: width,
+			height: height,
+			depth: 1,
+		};
+		self.create_texture_from_data(image_extent, vk::ImageType::TYPE_2D, vk::ImageViewType::TYPE_2D, format, data)
+	}
+
+	/// Creates a read only 3D texture of size^3 texels and the given format, filled with the passed data.
+	///
+	/// Note: The caller is responsible for cleaning up the returned vulkan types.
+	#[track_caller]
+	pub fn create_3d_image_from_data(&self, size: u32, format: vk::Format, data: &[u8]) -> Texture
+	{
+		let image_extent = vk::Extent3D {
+			width: size,
+			height: size,
+			depth: size,
+		};
+		self.create_texture_from_data(image_extent, vk::ImageType::TYPE_3D, vk::ImageViewType::TYPE_3D, format, data)
+	}
+
+	#[track_caller]
+	fn create_texture_from_data(
+		&self, image_extent: vk::Extent3D, image_type: vk::ImageType, view_type: vk::ImageViewType, format: vk::Format,
+		data: &[u8],
+	) -> Texture
+	{
+		// Load the image data into a vk::Buffer, released once the texture holds the data
+		let image_buffer = self.create_buffer_and_upload(
 			vk::BufferUsageFlags::TRANSFER_SRC,
 			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-			&image_data,
+			data,
 			false,
 		);
 
 		// Create a texture from the buffer data
 		let texture = self.create_texture(
 			image_extent,
-			vk::ImageType::TYPE_2D,
-			vk::ImageViewType::TYPE_2D,
-			if srgb
-			{
-				vk::Format::R8G8B8A8_SRGB
-			}
-			else
-			{
-				vk::Format::R8G8B8A8_UNORM
-			},
+			image_type,
+			view_type,
+			format,
 			vk::ImageAspectFlags::COLOR,
 			vk::ImageUsageFlags::SAMPLED,
 			vk::AccessFlags::SHADER_READ,
 			vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
 			vk::PipelineStageFlags::FRAGMENT_SHADER,
-			Some(image_buffer),
+			Some(image_buffer.get()),
 		);
 
-		// Texture now holds the data, can delete image buffer and memory
-		unsafe {
-			self.device.destroy_buffer(image_buffer, None);
-			self.device.free_memory(image_memory, None);
-		}
-
 		texture
 	}
 
@@ -832,7 +1576,7 @@ impl RenderState
 			new_layout: new_layout,
 			image: texture.image,
 			subresource_range: vk::ImageSubresourceRange {
-				aspect_mask: vk::ImageAspectFlags::COLOR,
+				aspect_mask: texture.aspect_mask,
 				base_mip_level: 0,
 				level_count: 1,
 				base_array_layer: 0,
@@ -872,10 +1616,84 @@ impl RenderState
 				self.end_single_time_commands(cmd_buf);
 			}
 		}
+		self.frame_trace.borrow_mut().record_barrier(
+			texture.image,
+			texture.current_layout,
+			new_layout,
+			texture.current_stage,
+			new_stage,
+		);
 		texture.current_access_mask = new_access_mask;
 		texture.current_layout = new_layout;
 		texture.current_stage = new_stage;
 	}
+
+	/// Names an image in captured frame graphs, and records its size for the pass statistics.
+	pub fn register_image(&self, image: vk::Image, name: &str, extent: vk::Extent2D, format: vk::Format)
+	{
+		self.frame_trace.borrow_mut().set_image_name(image, name);
+		self.pass_stats.borrow_mut().set_image_size(image, extent, format);
+	}
+
+	/// Registers the attachments of a pass for the pass statistics, in the order they are written in trace_pass().
+	pub fn register_pass(&self, name: &str, attachments: &[vk::AttachmentDescription])
+	{
+		self.pass_stats.borrow_mut().register_pass(name, attachments);
+	}
+
+	/// Records the start of a pass in the frame graph, if a capture is in progress, and in the pass statistics. Starts
+	/// timing the pass on the GPU if enabled, so call it outside of a render pass.
+	pub fn trace_pass(&self, cmd_buf: vk::CommandBuffer, name: &str, reads: &[vk::Image], writes: &[vk::Image])
+	{
+		self.frame_trace.borrow_mut().begin_pass(name, reads, writes);
+		self.pass_stats.borrow_mut().record_pass(name, reads, writes);
+		self.gpu_timer.borrow_mut().begin_pass(&self.device, cmd_buf, name);
+	}
+
+	/// Records the end of the pass last traced, for the GPU timings. Call before ending its command buffer.
+	pub fn end_pass(&self, cmd_buf: vk::CommandBuffer)
+	{
+		self.gpu_timer.borrow_mut().end_pass(&self.device, cmd_buf);
+	}
+
+	/// Enables timing the passes on the GPU, see GpuTimer. Returns false if the GPU cannot time them.
+	pub fn set_gpu_timing(&self, enabled: bool) -> bool
+	{
+		let mut gpu_timer = self.gpu_timer.borrow_mut();
+		gpu_timer.set_enabled(enabled);
+		return gpu_timer.is_supported();
+	}
+
+	/// Returns the GPU time of each pass since the last call, in milliseconds, if timing is enabled. Call once the
+	/// frame has been presented.
+	pub fn collect_gpu_timings(&self) -> Vec<(String, f32)>
+	{
+		return self.gpu_timer.borrow_mut().collect(&self.device);
+	}
+
+	/// Adds the vertex buffers drawn by a pass this frame to the pass statistics. Call after trace_pass().
+	pub fn add_pass_vertex_bytes(&self, name: &str, bytes: u64)
+	{
+		self.pass_stats.borrow_mut().add_vertex_bytes(name, bytes);
+	}
+
+	/// Returns a table of the estimated memory use and bandwidth of each pass, see PassStats::report().
+	pub fn get_pass_report(&self, frame_time: f32, bandwidth: f32) -> String
+	{
+		return self.pass_stats.borrow().report(frame_time, bandwidth);
+	}
+
+	/// Starts capturing the passes and barriers of the frame, until finish_frame_capture() is called.
+	pub fn start_frame_capture(&self)
+	{
+		self.frame_trace.borrow_mut().start();
+	}
+
+	/// Ends the frame capture, returning the captured frame graph in dot format.
+	pub fn finish_frame_capture(&self) -> Option<String>
+	{
+		return self.frame_trace.borrow_mut().finish();
+	}
 }
 
 impl Drop for RenderState
@@ -886,13 +1704,30 @@ impl Drop for RenderState
 	/// been freed at this point.
 	fn drop(&mut self)
 	{
+		unsafe {
+			// Always wait for device idle
+			self.device.device_wait_idle().unwrap();
+		}
+		// Released objects hold on to the device until destroyed
+		loop
+		{
+			let resource = self.deletion_queue.borrow_mut().pop();
+			match resource
+			{
+				Some(resource) => drop(resource),
+				None => break,
+			}
+		}
 		// We must have the only reference to device at this point
 		debug_assert!(1 == Rc::strong_count(&self.device));
 
 		unsafe {
-			// Always wait for device idle
-			self.device.device_wait_idle().unwrap();
+			leak_tracker::report_leaks();
 
+			self.save_pipeline_cache();
+			self.device.destroy_pipeline_cache(self.pipeline_cache, None);
+			self.gpu_timer.borrow_mut().destroy(&self.device);
+			self.allocator.borrow_mut().destroy(&self.device);
 			self.device.destroy_command_pool(self.commandpool, None);
 			self.device.destroy_device(None);
 			if cfg!(feature = "debug_layer")
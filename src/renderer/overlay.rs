@@ -0,0 +1,1150 @@
+use crate::renderer::font::{self, FontAtlas, Icon};
+use crate::renderer::{track_create, track_destroy, BufferHandle, ObjectKind, RenderState, Texture};
+use ash::util::Align;
+use ash::version::DeviceV1_0;
+use ash::{vk, Device};
+use cgmath::{Matrix4, Point3, SquareMatrix};
+use std::ffi::CString;
+use std::fmt::Write;
+use std::mem::{align_of, size_of};
+use std::rc::Rc;
+use std::{mem, ptr, slice};
+
+const MAX_OVERLAY_QUADS: usize = 8_192;
+const MAX_DEBUG_GLYPHS: usize = 65_536;
+/// Height of debug text glyphs, in pixels.
+const DEBUG_TEXT_SIZE: f32 = 10.0;
+/// Width of each column in debug tables, in characters.
+const DEBUG_TABLE_COLUMN_WIDTH: usize = 10;
+/// Label depth meaning the label is drawn on top of everything.
+const NO_DEPTH_TEST: f32 = -1.0;
+
+/// How text and icons are drawn. Sizes are in UI units, see Overlay::set_scale().
+#[derive(Clone, Copy)]
+pub struct TextStyle
+{
+	pub color: [f32; 4],
+	pub outline_color: [f32; 4],
+	/// Set to zero to disable the outline.
+	pub outline_width: f32,
+	pub shadow_color: [f32; 4],
+	pub shadow_offset: [f32; 2],
+}
+
+impl Default for TextStyle
+{
+	fn default() -> TextStyle
+	{
+		TextStyle {
+			color: [1.0, 1.0, 1.0, 1.0],
+			outline_color: [0.0, 0.0, 0.0, 1.0],
+			outline_width: 0.0,
+			shadow_color: [0.0, 0.0, 0.0, 0.0],
+			shadow_offset: [0.0, 0.0],
+		}
+	}
+}
+
+// Must match the inputs of overlay.vert
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OverlayVertex
+{
+	pos: [f32; 2],
+	uv: [f32; 2],
+	color: [f32; 4],
+	outline_color: [f32; 4],
+	shadow_color: [f32; 4],
+	// Outline width in SDF units, shadow offset in texture coordinates, and depth to test against the scene
+	params: [f32; 4],
+}
+
+enum LabelContent
+{
+	Text(String),
+	Icon(Icon),
+}
+
+/// Text or an icon attached to a point in the world, projected to the screen when drawing.
+struct WorldLabel
+{
+	content: LabelContent,
+	anchor: Point3<f32>,
+	size: f32,
+	style: TextStyle,
+	depth_test: bool,
+}
+
+// Must match the instance inputs of debug_text.vert
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GlyphInstance
+{
+	pos: [f32; 2],
+	cell: u32,
+	color: [u8; 4],
+}
+
+// Must match the GlyphBlock push constants in debug_text.vert
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GlyphParams
+{
+	screen_size: [f32; 2],
+	cell_size: [f32; 2],
+	cell_uv_size: [f32; 2],
+	padding: f32,
+	outline_width: f32,
+	atlas_columns: u32,
+}
+
+/// An image drawn on the overlay with Overlay::draw_image(), see Overlay::create_image(). Must outlive the frames
+/// drawing it, e.g. by releasing it with RenderState::defer().
+pub struct OverlayImage
+{
+	_texture: Texture,
+	// Holds the descriptor set of the image alone
+	descriptor_pool: vk::DescriptorPool,
+	descriptor_set: vk::DescriptorSet,
+	size: (u32, u32),
+	device: Rc<Device>,
+}
+
+impl OverlayImage
+{
+	/// Returns the width and height of the image, in pixels.
+	pub fn get_size(&self) -> (u32, u32)
+	{
+		return self.size;
+	}
+}
+
+impl Drop for OverlayImage
+{
+	fn drop(&mut self)
+	{
+		unsafe {
+			self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+		}
+	}
+}
+
+/// Draws screen space text and icons on top of the presented image, using signed distance fields.
+///
+/// Text is queued through draw_text()/draw_icon() during the frame, and drawn (and cleared) when presenting.
+/// Labels attached to points in the world are queued with draw_world_text()/draw_world_icon(), and projected with the
+/// camera set through set_view_projection(). They keep their screen size, and can be hidden behind the scene.
+/// Large amounts of debug text should use draw_debug_text()/draw_table() instead, which only supports a single size
+/// and style, but writes a few bytes per glyph straight to a persistently mapped buffer, drawn with instancing.
+/// Images are queued with draw_image(), and drawn below everything else.
+pub struct Overlay
+{
+	atlas: Texture,
+	atlas_size: (f32, f32),
+	descriptor_pool: vk::DescriptorPool,
+	descriptor_set_layout: vk::DescriptorSetLayout,
+	descriptor_set: vk::DescriptorSet,
+	pipeline_layout: vk::PipelineLayout,
+	pipeline: vk::Pipeline,
+	image_pipeline: vk::Pipeline,
+	// Whether the presentable images encode to sRGB, so images are sampled the same way to keep their pixels
+	srgb_target: bool,
+
+	vertices: BufferHandle,
+	queued_vertices: Vec<OverlayVertex>,
+	// Six vertices per image, drawn with the descriptor set of the image
+	queued_images: Vec<vk::DescriptorSet>,
+	queued_image_vertices: Vec<OverlayVertex>,
+	queued_labels: Vec<WorldLabel>,
+	view_projection: Matrix4<f32>,
+	font_atlas: FontAtlas,
+	// Screen pixels per UI unit
+	scale: f32,
+
+	glyph_pipeline_layout: vk::PipelineLayout,
+	glyph_pipeline: vk::Pipeline,
+	glyph_instances: BufferHandle,
+	// Mapped for the lifetime of the overlay
+	glyph_ptr: *mut GlyphInstance,
+	glyph_count: usize,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl Overlay
+{
+	pub fn init(rs: &RenderState, renderpass: vk::RenderPass, format: vk::Format) -> Overlay
+	{
+		let font_atlas = FontAtlas::generate();
+		let atlas = rs.create_image_from_data(
+			font_atlas.width as u32,
+			font_atlas.height as u32,
+			vk::Format::R8_UNORM,
+			&font_atlas.data,
+		);
+
+		// Descriptors
+		let descriptor_sizes = [vk::DescriptorPoolSize {
+			ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			descriptor_count: 2,
+		}];
+		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+			pool_size_count: descriptor_sizes.len() as u32,
+			p_pool_sizes: descriptor_sizes.as_ptr(),
+			max_sets: 1,
+			..Default::default()
+		};
+		let desc_layout_bindings = [
+			// atlas
+			vk::DescriptorSetLayoutBinding {
+				binding: 0,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// scene depth, for world labels
+			vk::DescriptorSetLayoutBinding {
+				binding: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+		];
+		let descriptor_info = vk::DescriptorSetLayoutCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+			binding_count: desc_layout_bindings.len() as u32,
+			p_bindings: desc_layout_bindings.as_ptr(),
+			..Default::default()
+		};
+		let descriptor_pool;
+		let descriptor_set_layout;
+		unsafe {
+			descriptor_pool = rs.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
+			descriptor_set_layout = rs.device.create_descriptor_set_layout(&descriptor_info, None).unwrap();
+		}
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_count: 1,
+			p_set_layouts: &descriptor_set_layout,
+		};
+		let descriptor_set;
+		unsafe {
+			descriptor_set = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap()[0];
+		}
+		let atlas_descriptor = vk::DescriptorImageInfo {
+			image_layout: atlas.current_layout,
+			image_view: atlas.view,
+			sampler: atlas.sampler,
+		};
+		// The depth is bound when drawing, until then the atlas keeps the descriptor valid
+		let write_desc_sets = [
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: descriptor_set,
+				dst_binding: 0,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: &atlas_descriptor,
+				..Default::default()
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: descriptor_set,
+				dst_binding: 1,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: &atlas_descriptor,
+				..Default::default()
+			},
+		];
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+
+		// The screen size is pushed, to convert from pixels to NDC
+		let screen_params_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::VERTEX,
+			size: size_of::<[f32; 6]>() as u32,
+			offset: 0,
+		};
+		let layout_create_info = vk::PipelineLayoutCreateInfo {
+			s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+			set_layout_count: 1,
+			p_set_layouts: &descriptor_set_layout,
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &screen_params_push_constant,
+			..Default::default()
+		};
+		let pipeline_layout;
+		unsafe {
+			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
+		}
+		let pipeline = Overlay::create_overlay_pipeline(rs, renderpass, pipeline_layout, "shaders/overlay_frag.spv");
+		let image_pipeline =
+			Overlay::create_overlay_pipeline(rs, renderpass, pipeline_layout, "shaders/overlay_image_frag.spv");
+
+		let glyph_params_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::VERTEX,
+			size: size_of::<GlyphParams>() as u32,
+			offset: 0,
+		};
+		let glyph_layout_create_info = vk::PipelineLayoutCreateInfo {
+			s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+			set_layout_count: 1,
+			p_set_layouts: &descriptor_set_layout,
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &glyph_params_push_constant,
+			..Default::default()
+		};
+		let glyph_pipeline_layout;
+		unsafe {
+			glyph_pipeline_layout = rs.device.create_pipeline_layout(&glyph_layout_create_info, None).unwrap();
+		}
+		let glyph_pipeline = Overlay::create_glyph_pipeline(rs, renderpass, glyph_pipeline_layout);
+		let glyph_buffer_size = (size_of::<GlyphInstance>() * MAX_DEBUG_GLYPHS) as u64;
+		let glyph_instances = rs.create_buffer(
+			vk::BufferUsageFlags::VERTEX_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			glyph_buffer_size,
+		);
+		let glyph_ptr = glyph_instances.get_mapped() as *mut GlyphInstance;
+
+		// Six vertices per quad, rewritten every frame
+		let initial_vertices = vec![
+			OverlayVertex {
+				pos: [0.0; 2],
+				uv: [0.0; 2],
+				color: [0.0; 4],
+				outline_color: [0.0; 4],
+				shadow_color: [0.0; 4],
+				params: [0.0; 4],
+			};
+			6 * MAX_OVERLAY_QUADS
+		];
+		let vert_buffer = rs.create_buffer_and_upload(
+			vk::BufferUsageFlags::VERTEX_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			&initial_vertices,
+			false,
+		);
+
+		Overlay {
+			atlas_size: (font_atlas.width as f32, font_atlas.height as f32),
+			atlas: atlas,
+			descriptor_pool: descriptor_pool,
+			descriptor_set_layout: descriptor_set_layout,
+			descriptor_set: descriptor_set,
+			pipeline_layout: pipeline_layout,
+			pipeline: pipeline,
+			image_pipeline: image_pipeline,
+			srgb_target: format == vk::Format::B8G8R8A8_SRGB || format == vk::Format::R8G8B8A8_SRGB,
+			vertices: vert_buffer,
+			queued_vertices: Vec::new(),
+			queued_images: Vec::new(),
+			queued_image_vertices: Vec::new(),
+			queued_labels: Vec::new(),
+			view_projection: Matrix4::identity(),
+			font_atlas: font_atlas,
+			scale: 1.0,
+			glyph_pipeline_layout: glyph_pipeline_layout,
+			glyph_pipeline: glyph_pipeline,
+			glyph_instances: glyph_instances,
+			glyph_ptr: glyph_ptr,
+			glyph_count: 0,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Creates the pipeline for text and icons queued with draw_text()/draw_icon(), or with the image fragment shader
+	/// for images queued with draw_image().
+	fn create_overlay_pipeline(
+		rs: &RenderState, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout, fragment_shader: &str,
+	) -> vk::Pipeline
+	{
+		let vertex_input_binding_descriptions = [vk::VertexInputBindingDescription {
+			binding: 0,
+			stride: size_of::<OverlayVertex>() as u32,
+			input_rate: vk::VertexInputRate::VERTEX,
+		}];
+		let vec4_size = size_of::<[f32; 4]>() as u32;
+		let vertex_input_attribute_descriptions = [
+			// position
+			vk::VertexInputAttributeDescription {
+				location: 0,
+				binding: 0,
+				format: vk::Format::R32G32_SFLOAT,
+				offset: 0,
+			},
+			// uv
+			vk::VertexInputAttributeDescription {
+				location: 1,
+				binding: 0,
+				format: vk::Format::R32G32_SFLOAT,
+				offset: size_of::<[f32; 2]>() as u32,
+			},
+			// color
+			vk::VertexInputAttributeDescription {
+				location: 2,
+				binding: 0,
+				format: vk::Format::R32G32B32A32_SFLOAT,
+				offset: vec4_size,
+			},
+			// outline color
+			vk::VertexInputAttributeDescription {
+				location: 3,
+				binding: 0,
+				format: vk::Format::R32G32B32A32_SFLOAT,
+				offset: 2 * vec4_size,
+			},
+			// shadow color
+			vk::VertexInputAttributeDescription {
+				location: 4,
+				binding: 0,
+				format: vk::Format::R32G32B32A32_SFLOAT,
+				offset: 3 * vec4_size,
+			},
+			// params
+			vk::VertexInputAttributeDescription {
+				location: 5,
+				binding: 0,
+				format: vk::Format::R32G32B32A32_SFLOAT,
+				offset: 4 * vec4_size,
+			},
+		];
+		return Overlay::create_pipeline(
+			rs,
+			renderpass,
+			pipeline_layout,
+			"shaders/overlay_vert.spv",
+			fragment_shader,
+			&vertex_input_binding_descriptions,
+			&vertex_input_attribute_descriptions,
+		);
+	}
+
+	/// Creates the instanced pipeline for debug text, with one instance per glyph.
+	fn create_glyph_pipeline(
+		rs: &RenderState, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout,
+	) -> vk::Pipeline
+	{
+		let vertex_input_binding_descriptions = [vk::VertexInputBindingDescription {
+			binding: 0,
+			stride: size_of::<GlyphInstance>() as u32,
+			input_rate: vk::VertexInputRate::INSTANCE,
+		}];
+		let vertex_input_attribute_descriptions = [
+			// position
+			vk::VertexInputAttributeDescription {
+				location: 0,
+				binding: 0,
+				format: vk::Format::R32G32_SFLOAT,
+				offset: 0,
+			},
+			// atlas cell
+			vk::VertexInputAttributeDescription {
+				location: 1,
+				binding: 0,
+				format: vk::Format::R32_UINT,
+				offset: size_of::<[f32; 2]>() as u32,
+			},
+			// color
+			vk::VertexInputAttributeDescription {
+				location: 2,
+				binding: 0,
+				format: vk::Format::R8G8B8A8_UNORM,
+				offset: (size_of::<[f32; 2]>() + size_of::<u32>()) as u32,
+			},
+		];
+		return Overlay::create_pipeline(
+			rs,
+			renderpass,
+			pipeline_layout,
+			"shaders/debug_text_vert.spv",
+			"shaders/overlay_frag.spv",
+			&vertex_input_binding_descriptions,
+			&vertex_input_attribute_descriptions,
+		);
+	}
+
+	/// Creates an alpha blended pipeline for the given (presentable) renderpass.
+	fn create_pipeline(
+		rs: &RenderState, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout, vertex_shader: &str,
+		fragment_shader: &str, vertex_input_binding_descriptions: &[vk::VertexInputBindingDescription],
+		vertex_input_attribute_descriptions: &[vk::VertexInputAttributeDescription],
+	) -> vk::Pipeline
+	{
+		let vertex_shader_module = rs.load_shader(vertex_shader, &[]);
+		let fragment_shader_module = rs.load_shader(fragment_shader, &[]);
+
+		let shader_entry_name = CString::new("main").unwrap();
+		let shader_stage_create_infos = [
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: vertex_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::VERTEX,
+				..Default::default()
+			},
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: fragment_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::FRAGMENT,
+				..Default::default()
+			},
+		];
+		let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
+			vertex_attribute_description_count: vertex_input_attribute_descriptions.len() as u32,
+			p_vertex_attribute_descriptions: vertex_input_attribute_descriptions.as_ptr(),
+			vertex_binding_description_count: vertex_input_binding_descriptions.len() as u32,
+			p_vertex_binding_descriptions: vertex_input_binding_descriptions.as_ptr(),
+			..Default::default()
+		};
+		let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_INPUT_ASSEMBLY_STATE_CREATE_INFO,
+			topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+			..Default::default()
+		};
+		// Viewport and scissor are dynamic, set by the present pass
+		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VIEWPORT_STATE_CREATE_INFO,
+			scissor_count: 1,
+			viewport_count: 1,
+			..Default::default()
+		};
+		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
+			cull_mode: vk::CullModeFlags::NONE,
+			front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+			line_width: 1.0,
+			polygon_mode: vk::PolygonMode::FILL,
+			..Default::default()
+		};
+		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
+			rasterization_samples: vk::SampleCountFlags::TYPE_1,
+			..Default::default()
+		};
+		let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DEPTH_STENCIL_STATE_CREATE_INFO,
+			depth_test_enable: 0,
+			depth_write_enable: 0,
+			..Default::default()
+		};
+		let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
+			blend_enable: 1,
+			src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+			dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+			color_blend_op: vk::BlendOp::ADD,
+			src_alpha_blend_factor: vk::BlendFactor::ONE,
+			dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+			alpha_blend_op: vk::BlendOp::ADD,
+			color_write_mask: vk::ColorComponentFlags::all(),
+		}];
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
+			attachment_count: color_blend_attachment_states.len() as u32,
+			p_attachments: color_blend_attachment_states.as_ptr(),
+			..Default::default()
+		};
+		let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
+			dynamic_state_count: dynamic_state.len() as u32,
+			p_dynamic_states: dynamic_state.as_ptr(),
+			..Default::default()
+		};
+		let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			s_type: vk::StructureType::GRAPHICS_PIPELINE_CREATE_INFO,
+			stage_count: shader_stage_create_infos.len() as u32,
+			p_stages: shader_stage_create_infos.as_ptr(),
+			p_vertex_input_state: &vertex_input_state_info,
+			p_input_assembly_state: &vertex_input_assembly_state_info,
+			p_viewport_state: &viewport_state_info,
+			p_rasterization_state: &rasterization_info,
+			p_multisample_state: &multisample_state_info,
+			p_depth_stencil_state: &depth_state_info,
+			p_color_blend_state: &color_blend_state,
+			p_dynamic_state: &dynamic_state_info,
+			layout: pipeline_layout,
+			render_pass: renderpass,
+			..Default::default()
+		};
+		let graphics_pipelines;
+		unsafe {
+			graphics_pipelines = rs
+				.device
+				.create_graphics_pipelines(rs.pipeline_cache, &[graphic_pipeline_info], None)
+				.expect("Unable to create overlay pipeline");
+
+			// Graphics pipeline created, we no longer need the shader modules
+			rs.device.destroy_shader_module(fragment_shader_module, None);
+			rs.device.destroy_shader_module(vertex_shader_module, None);
+		}
+		track_create(ObjectKind::Pipeline, graphics_pipelines[0], vertex_shader);
+
+		graphics_pipelines[0]
+	}
+
+	/// Recreates the pipeline for a new renderpass, e.g. after the swapchain has been recreated.
+	///
+	/// The device must be idle.
+	pub fn recreate_pipeline(&mut self, rs: &RenderState, renderpass: vk::RenderPass)
+	{
+		track_destroy(ObjectKind::Pipeline, self.pipeline);
+		track_destroy(ObjectKind::Pipeline, self.image_pipeline);
+		track_destroy(ObjectKind::Pipeline, self.glyph_pipeline);
+		unsafe {
+			self.device.destroy_pipeline(self.pipeline, None);
+			self.device.destroy_pipeline(self.image_pipeline, None);
+			self.device.destroy_pipeline(self.glyph_pipeline, None);
+		}
+		self.pipeline =
+			Overlay::create_overlay_pipeline(rs, renderpass, self.pipeline_layout, "shaders/overlay_frag.spv");
+		self.image_pipeline =
+			Overlay::create_overlay_pipeline(rs, renderpass, self.pipeline_layout, "shaders/overlay_image_frag.spv");
+		self.glyph_pipeline = Overlay::create_glyph_pipeline(rs, renderpass, self.glyph_pipeline_layout);
+	}
+
+	/// Queues a quad covering a full atlas cell, with its top left corner at the given pixel position.
+	///
+	/// Fragments farther away than the given depth, in [0, 1], are hidden behind the scene unless it is NO_DEPTH_TEST.
+	fn queue_cell(
+		&mut self, uv: ([f32; 2], [f32; 2]), position: (f32, f32), cell_size: (f32, f32), pixels_per_texel: f32,
+		style: &TextStyle, depth: f32,
+	)
+	{
+		if self.queued_vertices.len() + self.queued_image_vertices.len() + 6 > 6 * MAX_OVERLAY_QUADS
+		{
+			return;
+		}
+
+		let (min_uv, max_uv) = uv;
+		let texels_per_pixel = 1.0 / pixels_per_texel;
+		// Keep the outline and shadow within the padding of the cell
+		let spread = font::SDF_SPREAD as f32;
+		let outline_width = (style.outline_width * texels_per_pixel).min(spread - 1.0) / (2.0 * spread);
+		let shadow_x = (style.shadow_offset[0] * texels_per_pixel).max(1.0 - spread).min(spread - 1.0);
+		let shadow_y = (style.shadow_offset[1] * texels_per_pixel).max(1.0 - spread).min(spread - 1.0);
+		let params = [outline_width, shadow_x / self.atlas_size.0, shadow_y / self.atlas_size.1, depth];
+
+		let (x0, y0) = position;
+		let (x1, y1) = (x0 + cell_size.0, y0 + cell_size.1);
+		let corners = [
+			([x0, y0], [min_uv[0], min_uv[1]]),
+			([x1, y0], [max_uv[0], min_uv[1]]),
+			([x1, y1], [max_uv[0], max_uv[1]]),
+			([x0, y0], [min_uv[0], min_uv[1]]),
+			([x1, y1], [max_uv[0], max_uv[1]]),
+			([x0, y1], [min_uv[0], max_uv[1]]),
+		];
+		for &(pos, uv) in corners.iter()
+		{
+			self.queued_vertices.push(OverlayVertex {
+				pos: pos,
+				uv: uv,
+				color: style.color,
+				outline_color: style.outline_color,
+				shadow_color: style.shadow_color,
+				params: params,
+			});
+		}
+	}
+
+	/// Sets the number of screen pixels per UI unit, for the text and icons queued after it. Positions and sizes are
+	/// given in UI units, so the UI keeps its size on high DPI displays.
+	pub fn set_scale(&mut self, scale: f32)
+	{
+		self.scale = scale;
+	}
+
+	/// Returns the style with its outline and shadow in screen pixels.
+	fn scale_style(&self, style: &TextStyle) -> TextStyle
+	{
+		return TextStyle {
+			outline_width: style.outline_width * self.scale,
+			shadow_offset: [style.shadow_offset[0] * self.scale, style.shadow_offset[1] * self.scale],
+			..*style
+		};
+	}
+
+	/// Queues text with its top left corner at the given position. Size is the height of the glyphs.
+	pub fn draw_text(&mut self, text: &str, position: (f32, f32), size: f32, style: &TextStyle)
+	{
+		let position = (position.0 * self.scale, position.1 * self.scale);
+		self.queue_text(text, position, size * self.scale, &self.scale_style(style), NO_DEPTH_TEST);
+	}
+
+	fn queue_text(&mut self, text: &str, position: (f32, f32), size: f32, style: &TextStyle, depth: f32)
+	{
+		let pixel = size / font::GLYPH_HEIGHT as f32;
+		let pixels_per_texel = size / (font::CELL_HEIGHT - 2 * font::SDF_SPREAD) as f32;
+		let padding = font::SDF_SPREAD as f32 * pixels_per_texel;
+		let cell_size = (font::CELL_WIDTH as f32 * pixels_per_texel, font::CELL_HEIGHT as f32 * pixels_per_texel);
+
+		for (line_idx, line) in text.lines().enumerate()
+		{
+			let y = position.1 + (line_idx * font::LINE_HEIGHT) as f32 * pixel;
+			for (column, c) in line.chars().enumerate()
+			{
+				if c == ' '
+				{
+					continue;
+				}
+				let x = position.0 + (column * font::GLYPH_ADVANCE) as f32 * pixel;
+				let uv = self.font_atlas.get_glyph_uv(c);
+				self.queue_cell(uv, (x - padding, y - padding), cell_size, pixels_per_texel, style, depth);
+			}
+		}
+	}
+
+	/// Queues an icon with its top left corner at the given position.
+	pub fn draw_icon(&mut self, icon: Icon, position: (f32, f32), size: f32, style: &TextStyle)
+	{
+		let position = (position.0 * self.scale, position.1 * self.scale);
+		self.queue_icon(icon, position, size * self.scale, &self.scale_style(style), NO_DEPTH_TEST);
+	}
+
+	/// Uploads an image for draw_image(), e.g. a screenshot. Its pixels are presented as they are.
+	pub fn create_image(&self, rs: &RenderState, image: &image::RgbaImage) -> OverlayImage
+	{
+		let texture = rs.create_image_from_rgba(image, self.srgb_target);
+		let descriptor_sizes = [vk::DescriptorPoolSize {
+			ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			descriptor_count: 2,
+		}];
+		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+			pool_size_count: descriptor_sizes.len() as u32,
+			p_pool_sizes: descriptor_sizes.as_ptr(),
+			max_sets: 1,
+			..Default::default()
+		};
+		let descriptor_pool;
+		unsafe {
+			descriptor_pool = rs.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
+		}
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_count: 1,
+			p_set_layouts: &self.descriptor_set_layout,
+		};
+		let descriptor_set;
+		unsafe {
+			descriptor_set = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap()[0];
+		}
+		let image_descriptor = vk::DescriptorImageInfo {
+			image_layout: texture.current_layout,
+			image_view: texture.view,
+			sampler: texture.sampler,
+		};
+		// The image shader has no use for the scene depth, the image keeps the descriptor valid
+		let write_desc_sets = [0, 1].iter().map(|&binding| vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: descriptor_set,
+			dst_binding: binding,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			p_image_info: &image_descriptor,
+			..Default::default()
+		});
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets.collect::<Vec<_>>(), &[]);
+		}
+
+		return OverlayImage {
+			_texture: texture,
+			descriptor_pool: descriptor_pool,
+			descriptor_set: descriptor_set,
+			size: image.dimensions(),
+			device: Rc::clone(&rs.device),
+		};
+	}
+
+	/// Queues the part of the image within the texture coordinates uv, from its top left to its bottom right corner,
+	/// stretched over a rectangle with its top left corner at the given position. Color multiplies its pixels.
+	pub fn draw_image(
+		&mut self, image: &OverlayImage, uv: ([f32; 2], [f32; 2]), position: (f32, f32), size: (f32, f32),
+		color: [f32; 4],
+	)
+	{
+		if self.queued_vertices.len() + self.queued_image_vertices.len() + 6 > 6 * MAX_OVERLAY_QUADS
+		{
+			return;
+		}
+
+		let (min_uv, max_uv) = uv;
+		let (x0, y0) = (position.0 * self.scale, position.1 * self.scale);
+		let (x1, y1) = (x0 + size.0 * self.scale, y0 + size.1 * self.scale);
+		let corners = [
+			([x0, y0], [min_uv[0], min_uv[1]]),
+			([x1, y0], [max_uv[0], min_uv[1]]),
+			([x1, y1], [max_uv[0], max_uv[1]]),
+			([x0, y0], [min_uv[0], min_uv[1]]),
+			([x1, y1], [max_uv[0], max_uv[1]]),
+			([x0, y1], [min_uv[0], max_uv[1]]),
+		];
+		for &(pos, uv) in corners.iter()
+		{
+			self.queued_image_vertices.push(OverlayVertex {
+				pos: pos,
+				uv: uv,
+				color: color,
+				outline_color: [0.0; 4],
+				shadow_color: [0.0; 4],
+				params: [0.0, 0.0, 0.0, NO_DEPTH_TEST],
+			});
+		}
+		self.queued_images.push(image.descriptor_set);
+	}
+
+	fn queue_icon(&mut self, icon: Icon, position: (f32, f32), size: f32, style: &TextStyle, depth: f32)
+	{
+		let pixels_per_texel = size / font::ICON_SIZE as f32;
+		let cell_size = (font::CELL_WIDTH as f32 * pixels_per_texel, font::CELL_HEIGHT as f32 * pixels_per_texel);
+		// Icons are centered in their cell
+		let x = position.0 + 0.5 * size - 0.5 * cell_size.0;
+		let y = position.1 + 0.5 * size - 0.5 * cell_size.1;
+		let uv = self.font_atlas.get_icon_uv(icon);
+		self.queue_cell(uv, (x, y), cell_size, pixels_per_texel, style, depth);
+	}
+
+	/// Sets the camera used to project world labels to the screen, for the labels drawn this frame.
+	pub fn set_view_projection(&mut self, view_projection: Matrix4<f32>)
+	{
+		self.view_projection = view_projection;
+	}
+
+	/// Queues text centered above a point in the world. Size is the height of the glyphs.
+	///
+	/// With depth testing, the parts of the text behind the scene, as seen from the anchor's depth, are hidden.
+	pub fn draw_world_text(&mut self, text: &str, anchor: Point3<f32>, size: f32, style: &TextStyle, depth_test: bool)
+	{
+		self.queued_labels.push(WorldLabel {
+			content: LabelContent::Text(text.to_string()),
+			anchor: anchor,
+			size: size * self.scale,
+			style: self.scale_style(style),
+			depth_test: depth_test,
+		});
+	}
+
+	/// Queues an icon centered on a point in the world, see draw_world_text().
+	pub fn draw_world_icon(&mut self, icon: Icon, anchor: Point3<f32>, size: f32, style: &TextStyle, depth_test: bool)
+	{
+		self.queued_labels.push(WorldLabel {
+			content: LabelContent::Icon(icon),
+			anchor: anchor,
+			size: size * self.scale,
+			style: self.scale_style(style),
+			depth_test: depth_test,
+		});
+	}
+
+	/// Projects the world labels to the screen and queues them as regular text and icons. Labels behind the camera
+	/// are dropped.
+	fn queue_world_labels(&mut self, image_area: [f32; 4], has_depth: bool)
+	{
+		let mut labels = mem::replace(&mut self.queued_labels, Vec::new());
+		for label in labels.iter()
+		{
+			let clip = self.view_projection * label.anchor.to_homogeneous();
+			if clip.w <= 0.0
+			{
+				continue;
+			}
+			let ndc = clip.truncate() / clip.w;
+			let x = image_area[0] + 0.5 * (ndc.x + 1.0) * image_area[2];
+			let y = image_area[1] + 0.5 * (ndc.y + 1.0) * image_area[3];
+			let depth = if label.depth_test && has_depth
+			{
+				ndc.z
+			}
+			else
+			{
+				NO_DEPTH_TEST
+			};
+			match &label.content
+			{
+				LabelContent::Text(text) =>
+				{
+					let pixel = label.size / font::GLYPH_HEIGHT as f32;
+					let columns = text.lines().map(|line| line.chars().count()).max().unwrap_or(0).max(1);
+					let lines = text.lines().count().max(1);
+					// The last column has no spacing after it
+					let width = ((columns - 1) * font::GLYPH_ADVANCE + font::GLYPH_WIDTH) as f32 * pixel;
+					let height = ((lines - 1) * font::LINE_HEIGHT) as f32 * pixel + label.size;
+					self.queue_text(text, (x - 0.5 * width, y - height), label.size, &label.style, depth);
+				}
+				LabelContent::Icon(icon) =>
+				{
+					let position = (x - 0.5 * label.size, y - 0.5 * label.size);
+					self.queue_icon(*icon, position, label.size, &label.style, depth);
+				}
+			}
+		}
+		// Keep the allocation for the next frame
+		labels.clear();
+		self.queued_labels = labels;
+	}
+
+	/// Writes debug text with its top left corner at the given position. Glyphs beyond the capacity of the debug text
+	/// buffer are dropped.
+	pub fn draw_debug_text(&mut self, text: &str, position: (f32, f32), color: [f32; 4])
+	{
+		let pixel = DEBUG_TEXT_SIZE * self.scale / font::GLYPH_HEIGHT as f32;
+		let position = (position.0 * self.scale, position.1 * self.scale);
+		let packed_color =
+			[(color[0] * 255.0) as u8, (color[1] * 255.0) as u8, (color[2] * 255.0) as u8, (color[3] * 255.0) as u8];
+		for (line_idx, line) in text.lines().enumerate()
+		{
+			let y = position.1 + (line_idx * font::LINE_HEIGHT) as f32 * pixel;
+			for (column, c) in line.chars().enumerate()
+			{
+				if c == ' '
+				{
+					continue;
+				}
+				if self.glyph_count == MAX_DEBUG_GLYPHS
+				{
+					return;
+				}
+				let instance = GlyphInstance {
+					pos: [position.0 + (column * font::GLYPH_ADVANCE) as f32 * pixel, y],
+					cell: font::glyph_cell(c) as u32,
+					color: packed_color,
+				};
+				unsafe {
+					*self.glyph_ptr.add(self.glyph_count) = instance;
+				}
+				self.glyph_count += 1;
+			}
+		}
+	}
+
+	/// Writes a table of numbers as debug text, with a header row naming the columns. Each row is labeled with its
+	/// index.
+	pub fn draw_table(&mut self, columns: &[&str], rows: &[Vec<f32>], position: (f32, f32), color: [f32; 4])
+	{
+		let pixel = DEBUG_TEXT_SIZE / font::GLYPH_HEIGHT as f32;
+		let line_height = font::LINE_HEIGHT as f32 * pixel;
+		let column_width = (DEBUG_TABLE_COLUMN_WIDTH * font::GLYPH_ADVANCE) as f32 * pixel;
+		let mut cell = String::with_capacity(2 * DEBUG_TABLE_COLUMN_WIDTH);
+
+		// The first column holds the row index
+		for (column_idx, name) in columns.iter().enumerate()
+		{
+			cell.clear();
+			write!(cell, "{:>width$}", name, width = DEBUG_TABLE_COLUMN_WIDTH).unwrap();
+			self.draw_debug_text(&cell, (position.0 + (column_idx + 1) as f32 * column_width, position.1), color);
+		}
+		for (row_idx, row) in rows.iter().enumerate()
+		{
+			let y = position.1 + (row_idx + 1) as f32 * line_height;
+			cell.clear();
+			write!(cell, "{:>width$}", row_idx, width = DEBUG_TABLE_COLUMN_WIDTH).unwrap();
+			self.draw_debug_text(&cell, (position.0, y), color);
+			for (column_idx, value) in row.iter().enumerate()
+			{
+				cell.clear();
+				write!(cell, "{:>width$.3}", value, width = DEBUG_TABLE_COLUMN_WIDTH).unwrap();
+				self.draw_debug_text(&cell, (position.0 + (column_idx + 1) as f32 * column_width, y), color);
+			}
+		}
+	}
+
+	/// Draws the debug text, see draw().
+	fn draw_debug_glyphs(&mut self, cmd_buf: vk::CommandBuffer, screen_size: [f32; 2])
+	{
+		let pixels_per_texel = DEBUG_TEXT_SIZE * self.scale / (font::CELL_HEIGHT - 2 * font::SDF_SPREAD) as f32;
+		let spread = font::SDF_SPREAD as f32;
+		let glyph_params = GlyphParams {
+			screen_size: screen_size,
+			cell_size: [font::CELL_WIDTH as f32 * pixels_per_texel, font::CELL_HEIGHT as f32 * pixels_per_texel],
+			cell_uv_size: [font::CELL_WIDTH as f32 / self.atlas_size.0, font::CELL_HEIGHT as f32 / self.atlas_size.1],
+			padding: spread * pixels_per_texel,
+			// One pixel outline, in distance field units
+			outline_width: (1.0 / pixels_per_texel).min(spread - 1.0) / (2.0 * spread),
+			atlas_columns: font::ATLAS_COLUMNS as u32,
+		};
+		unsafe {
+			self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.glyph_pipeline);
+			self.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				self.glyph_pipeline_layout,
+				0,
+				&[self.descriptor_set],
+				&[],
+			);
+			self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.glyph_instances.get()], &[0]);
+			let glyph_params_bytes =
+				slice::from_raw_parts(&glyph_params as *const GlyphParams as *const u8, size_of::<GlyphParams>());
+			self.device.cmd_push_constants(
+				cmd_buf,
+				self.glyph_pipeline_layout,
+				vk::ShaderStageFlags::VERTEX,
+				0,
+				glyph_params_bytes,
+			);
+			// Six vertices per glyph quad
+			self.device.cmd_draw(cmd_buf, 6, self.glyph_count as u32, 0, 0);
+		}
+		self.glyph_count = 0;
+	}
+
+	/// Draws and clears everything queued. Must be called within the present renderpass.
+	///
+	/// World labels are placed in the area of the screen the scene is presented in, and depth tested against the
+	/// given depth buffer, which must be readable by shaders. Without it, they are drawn on top of everything.
+	pub fn draw(
+		&mut self, cmd_buf: vk::CommandBuffer, screen_size: vk::Extent2D, image_area: vk::Rect2D,
+		depth: Option<&Texture>,
+	)
+	{
+		let screen_size = [screen_size.width as f32, screen_size.height as f32];
+		let image_area = [
+			image_area.offset.x as f32,
+			image_area.offset.y as f32,
+			image_area.extent.width as f32,
+			image_area.extent.height as f32,
+		];
+		let depth_texture = depth.unwrap_or(&self.atlas);
+		let depth_descriptor = vk::DescriptorImageInfo {
+			image_layout: depth_texture.current_layout,
+			image_view: depth_texture.view,
+			sampler: depth_texture.sampler,
+		};
+		let write_desc_sets = [vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: self.descriptor_set,
+			dst_binding: 1,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			p_image_info: &depth_descriptor,
+			..Default::default()
+		}];
+		unsafe {
+			self.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+		self.queue_world_labels(image_area, depth.is_some());
+
+		// Must match ScreenBlock in overlay.vert
+		let screen_params =
+			[screen_size[0], screen_size[1], image_area[0], image_area[1], image_area[2], image_area[3]];
+		let screen_params_bytes;
+		unsafe {
+			screen_params_bytes =
+				slice::from_raw_parts(screen_params.as_ptr() as *const u8, mem::size_of_val(&screen_params));
+		}
+
+		// The images come first in the vertex buffer, followed by the text and icons
+		let image_vertex_count = self.queued_image_vertices.len();
+		let vertex_count = image_vertex_count + self.queued_vertices.len();
+		if vertex_count > 0
+		{
+			let copy_size = (size_of::<OverlayVertex>() * vertex_count) as u64;
+			unsafe {
+				let mem_ptr = self.vertices.get_mapped();
+				let mut mem_align = Align::new(mem_ptr, align_of::<OverlayVertex>() as u64, copy_size);
+				mem_align.copy_from_slice(&[&self.queued_image_vertices[..], &self.queued_vertices[..]].concat());
+			}
+		}
+
+		// Images go below everything else
+		if image_vertex_count > 0
+		{
+			unsafe {
+				self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.image_pipeline);
+				self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.vertices.get()], &[0]);
+				self.device.cmd_push_constants(
+					cmd_buf,
+					self.pipeline_layout,
+					vk::ShaderStageFlags::VERTEX,
+					0,
+					screen_params_bytes,
+				);
+				for (idx, &descriptor_set) in self.queued_images.iter().enumerate()
+				{
+					self.device.cmd_bind_descriptor_sets(
+						cmd_buf,
+						vk::PipelineBindPoint::GRAPHICS,
+						self.pipeline_layout,
+						0,
+						&[descriptor_set],
+						&[],
+					);
+					self.device.cmd_draw(cmd_buf, 6, 1, 6 * idx as u32, 0);
+				}
+			}
+		}
+
+		// Debug text goes below the regular overlay
+		if self.glyph_count > 0
+		{
+			self.draw_debug_glyphs(cmd_buf, screen_size);
+		}
+
+		if !self.queued_vertices.is_empty()
+		{
+			unsafe {
+				self.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+				self.device.cmd_bind_descriptor_sets(
+					cmd_buf,
+					vk::PipelineBindPoint::GRAPHICS,
+					self.pipeline_layout,
+					0,
+					&[self.descriptor_set],
+					&[],
+				);
+				self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.vertices.get()], &[0]);
+				self.device.cmd_push_constants(
+					cmd_buf,
+					self.pipeline_layout,
+					vk::ShaderStageFlags::VERTEX,
+					0,
+					screen_params_bytes,
+				);
+				self.device.cmd_draw(cmd_buf, self.queued_vertices.len() as u32, 1, image_vertex_count as u32, 0);
+			}
+		}
+		self.queued_vertices.clear();
+		self.queued_images.clear();
+		self.queued_image_vertices.clear();
+	}
+}
+
+impl Drop for Overlay
+{
+	fn drop(&mut self)
+	{
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+
+		track_destroy(ObjectKind::Pipeline, self.pipeline);
+		track_destroy(ObjectKind::Pipeline, self.image_pipeline);
+		track_destroy(ObjectKind::Pipeline, self.glyph_pipeline);
+		unsafe {
+			self.device.destroy_pipeline(self.pipeline, None);
+			self.device.destroy_pipeline(self.image_pipeline, None);
+			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+			self.device.destroy_pipeline(self.glyph_pipeline, None);
+			self.device.destroy_pipeline_layout(self.glyph_pipeline_layout, None);
+			self.device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+			self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+		}
+	}
+}
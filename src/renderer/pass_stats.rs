@@ -0,0 +1,153 @@
+use ash::vk;
+use ash::vk::Handle;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const MEGABYTE: f32 = 1_000_000.0;
+const GIGABYTE: f32 = 1_000_000_000.0;
+
+/// Returns the size of a texel of the given format, in bytes. Unknown formats are assumed to be 4 bytes.
+fn texel_size(format: vk::Format) -> u64
+{
+	match format
+	{
+		vk::Format::R8_UNORM => 1,
+		vk::Format::R16G16B16A16_SFLOAT => 8,
+		vk::Format::R32G32B32A32_SFLOAT => 16,
+		_ => 4,
+	}
+}
+
+/// Memory traffic of a pass in the last frame it ran.
+struct PassTraffic
+{
+	name: String,
+	attachment_bytes: u64,
+	vertex_bytes: u64,
+	bytes_per_frame: u64,
+}
+
+/// Estimates the memory used by the attachments of each pass, and the bandwidth of a frame: attachments are read when
+/// loaded and written when stored, and sampled images and vertex buffers are assumed to be read once in full per use.
+pub struct PassStats
+{
+	image_sizes: HashMap<u64, u64>,
+	// Load and store ops of each pass' attachments, in the order they are written
+	attachment_ops: HashMap<String, Vec<(vk::AttachmentLoadOp, vk::AttachmentStoreOp)>>,
+	// In the order the passes first ran
+	passes: Vec<PassTraffic>,
+}
+
+impl PassStats
+{
+	pub fn new() -> PassStats
+	{
+		PassStats {
+			image_sizes: HashMap::new(),
+			attachment_ops: HashMap::new(),
+			passes: Vec::new(),
+		}
+	}
+
+	pub fn set_image_size(&mut self, image: vk::Image, extent: vk::Extent2D, format: vk::Format)
+	{
+		let size = extent.width as u64 * extent.height as u64 * texel_size(format);
+		self.image_sizes.insert(image.as_raw(), size);
+	}
+
+	/// Registers the attachments of a pass. Warns about attachments loaded for no reason, as loading costs a full read
+	/// of the attachment.
+	pub fn register_pass(&mut self, name: &str, attachments: &[vk::AttachmentDescription])
+	{
+		for (idx, attachment) in attachments.iter().enumerate()
+		{
+			if attachment.load_op == vk::AttachmentLoadOp::LOAD &&
+				attachment.initial_layout == vk::ImageLayout::UNDEFINED
+			{
+				warning!(
+					"{} attachment {} uses LOAD_OP_LOAD with an undefined initial layout, there is nothing to \
+					 load. Use DONT_CARE or CLEAR instead.",
+					name,
+					idx
+				);
+			}
+		}
+		let ops = attachments.iter().map(|attachment| (attachment.load_op, attachment.store_op)).collect();
+		self.attachment_ops.insert(name.to_string(), ops);
+	}
+
+	/// Records the traffic of a pass this frame. Writes are its attachments, in the order they were registered.
+	pub fn record_pass(&mut self, name: &str, reads: &[vk::Image], writes: &[vk::Image])
+	{
+		let image_size = |image: &vk::Image| *self.image_sizes.get(&image.as_raw()).unwrap_or(&0);
+		let mut attachment_bytes = 0;
+		let mut bytes_per_frame: u64 = reads.iter().map(image_size).sum();
+		let no_ops = Vec::new();
+		let ops = self.attachment_ops.get(name).unwrap_or(&no_ops);
+		for (idx, image) in writes.iter().enumerate()
+		{
+			let size = image_size(image);
+			attachment_bytes += size;
+			// Unregistered passes are assumed to clear and store
+			let (load_op, store_op) =
+				ops.get(idx).cloned().unwrap_or((vk::AttachmentLoadOp::CLEAR, vk::AttachmentStoreOp::STORE));
+			if load_op == vk::AttachmentLoadOp::LOAD
+			{
+				bytes_per_frame += size;
+			}
+			if store_op == vk::AttachmentStoreOp::STORE
+			{
+				bytes_per_frame += size;
+			}
+		}
+
+		let traffic = PassTraffic {
+			name: name.to_string(),
+			attachment_bytes: attachment_bytes,
+			vertex_bytes: 0,
+			bytes_per_frame: bytes_per_frame,
+		};
+		match self.passes.iter_mut().find(|pass| pass.name == name)
+		{
+			Some(pass) => *pass = traffic,
+			None => self.passes.push(traffic),
+		}
+	}
+
+	/// Adds vertex buffer reads to the traffic of a pass this frame, after it was recorded.
+	pub fn add_vertex_bytes(&mut self, name: &str, bytes: u64)
+	{
+		if let Some(pass) = self.passes.iter_mut().find(|pass| pass.name == name)
+		{
+			pass.vertex_bytes += bytes;
+			pass.bytes_per_frame += bytes;
+		}
+	}
+
+	/// Returns a table of the passes. Passes that would take more than half the frame time to move their memory at
+	/// the given bandwidth, in GB/s, are flagged as bandwidth bound.
+	pub fn report(&self, frame_time: f32, bandwidth: f32) -> String
+	{
+		let mut report = format!("{:<24}{:>10}{:>10}{:>10}{:>8}\n", "pass", "VRAM MB", "vertex MB", "MB/frame", "GB/s");
+		for pass in &self.passes
+		{
+			let bytes = pass.bytes_per_frame as f32;
+			write!(
+				report,
+				"{:<24}{:>10.2}{:>10.2}{:>10.2}{:>8.2}",
+				pass.name,
+				pass.attachment_bytes as f32 / MEGABYTE,
+				pass.vertex_bytes as f32 / MEGABYTE,
+				bytes / MEGABYTE,
+				bytes / frame_time.max(0.000_1) / GIGABYTE
+			)
+			.unwrap();
+			if bytes / (bandwidth * GIGABYTE) > 0.5 * frame_time
+			{
+				report.push_str("  bandwidth bound");
+			}
+			report.push('\n');
+		}
+		return report;
+	}
+}
@@ -1,17 +1,67 @@
-use crate::renderer::{RenderState, Texture};
+use crate::renderer::{
+	gpu_info, track_create, track_destroy, CaptureReader, CaptureRing, ColorGrading, LensFlare, ObjectKind, Overlay,
+	RenderState, Texture,
+};
 use ash::extensions::khr::{Surface, Swapchain};
 use ash::version::{DeviceV1_0, InstanceV1_0};
 use ash::vk;
 use ash::vk::Handle;
 use ash::Device;
+use sdl2::video::Window;
 use std;
 use std::convert::TryInto;
 use std::ffi::CString;
-use std::ptr;
+use std::mem::size_of;
 use std::rc::Rc;
+use std::{mem, ptr, slice};
+
+/// Parameters for the post effects applied when presenting.
+///
+/// Must match the PostBlock push constants in final_pass.frag.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct PostParams
+{
+	/// Time in seconds, used for animated effects.
+	pub time: f32,
+	/// Amount of rain droplets on the screen, in the range [0, 1].
+	pub droplets: f32,
+	/// Position of the sun in texture coordinates, which can be outside the screen.
+	pub sun_position: [f32; 2],
+	/// Brightness of the sun shafts, 0 disables them.
+	pub sun_shafts_intensity: f32,
+	/// Falloff of the sun shafts with distance from the sun, per sample.
+	pub sun_shafts_decay: f32,
+	/// Near and far planes of the projection, to linearize the depth buffer.
+	pub depth_near: f32,
+	pub depth_far: f32,
+	/// Distance to the plane in focus.
+	pub focus_distance: f32,
+	/// Blur radius of infinitely distant objects, as a fraction of the screen height. 0 disables depth of field.
+	pub dof_aperture: f32,
+	/// Non-zero to show the circle of confusion instead of the image.
+	pub dof_debug: u32,
+	/// Color split at the screen corners, as a fraction of the screen height. 0 disables it.
+	pub chromatic_aberration: f32,
+	/// Brightness variation of the film grain. 0 disables it.
+	pub film_grain: f32,
+	/// Darkening at the screen corners, in the range [0, 1]. 0 disables it.
+	pub vignette: f32,
+	/// Weight of the current color grading table, blending from the previous one. Set from the color grading.
+	pub lut_blend: f32,
+	/// Opacity of the debug overlay, see PresentPass::set_debug_overlay(). Ignored without one.
+	pub debug_overlay: f32,
+	/// Brightness of the lens flare while the sun is unoccluded, 0 disables it. Uses sun_position.
+	pub lens_flare_intensity: f32,
+	/// Far edge of the volumetric fog, see PresentPass::set_volumetric_fog(). Ignored without fog.
+	pub fog_distance: f32,
+}
 
 pub struct PresentPass
 {
+	// Identifies the pass in captured frame graphs
+	name: &'static str,
+
 	// Surface
 	surface_loader: Surface,
 	surface: vk::SurfaceKHR,
@@ -26,6 +76,7 @@ pub struct PresentPass
 	// Swapchain
 	swapchain: vk::SwapchainKHR,
 	// presentable images for the screen
+	present_images: Vec<vk::Image>,
 	present_image_views: Vec<vk::ImageView>,
 	renderpass: vk::RenderPass,
 	descriptor_pool: vk::DescriptorPool,
@@ -34,6 +85,12 @@ pub struct PresentPass
 	pipeline_layout: vk::PipelineLayout,
 	viewport: vk::Viewport,
 	scissor: vk::Rect2D,
+	// Where the image is drawn, the whole surface if None
+	image_area: Option<vk::Rect2D>,
+	// Blended over the image by the final pass, if any
+	debug_overlay: Option<vk::DescriptorImageInfo>,
+	// Froxels of the volumetric fog composited over the image, if any
+	volumetric_fog: Option<vk::DescriptorImageInfo>,
 	pipeline: vk::Pipeline,
 	// one framebuffer/commandbuffer per image
 	framebuffers: Vec<vk::Framebuffer>,
@@ -41,6 +98,15 @@ pub struct PresentPass
 
 	// The current idx
 	current_present_idx: usize,
+	// Copies of the presented frames, in capture mode
+	capture: Option<CaptureRing>,
+
+	// Post effect parameters used when presenting
+	pub post_params: PostParams,
+	// Text and icons drawn on top of the presented image
+	pub overlay: Overlay,
+	pub color_grading: ColorGrading,
+	pub lens_flare: LensFlare,
 
 	// Keep a pointer to the device for cleanup
 	device: Rc<Device>,
@@ -48,12 +114,107 @@ pub struct PresentPass
 
 impl PresentPass
 {
+	/// Returns the present mode to use. With vsync, FIFO blocks on acquire_next_image until the display is ready for
+	/// the image. Without it, immediate presents right away and mailbox replaces the queued image instead, falling back
+	/// to FIFO if neither is supported.
+	fn pick_present_mode(rs: &RenderState, surface_loader: &Surface, surface: &vk::SurfaceKHR) -> vk::PresentModeKHR
+	{
+		let present_modes;
+		unsafe {
+			present_modes = surface_loader.get_physical_device_surface_present_modes(rs.pdevice, *surface).unwrap();
+		}
+		if !rs.vsync
+		{
+			for &mode in [vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX].iter()
+			{
+				if present_modes.contains(&mode)
+				{
+					return mode;
+				}
+			}
+		}
+		// FIFO support is required by the spec
+		return vk::PresentModeKHR::FIFO;
+	}
+
+	/// Returns true if presenting waits for the display, see Config::vsync.
+	pub fn has_vsync(&self, rs: &RenderState) -> bool
+	{
+		return PresentPass::pick_present_mode(rs, &self.surface_loader, &self.surface) == vk::PresentModeKHR::FIFO;
+	}
+
+	/// Returns the format of the presentable images. Capture mode wants B8G8R8A8_UNORM, the format capture tools take
+	/// as is, and falls back to the first format the surface supports.
+	fn pick_surface_format(surface_formats: &[vk::SurfaceFormatKHR], capture: bool) -> vk::SurfaceFormatKHR
+	{
+		let surface_format = surface_formats
+			.iter()
+			.map(|sfmt| match sfmt.format
+			{
+				vk::Format::UNDEFINED => vk::SurfaceFormatKHR {
+					format: if capture
+					{
+						vk::Format::B8G8R8A8_UNORM
+					}
+					else
+					{
+						vk::Format::B8G8R8_UNORM
+					},
+					color_space: sfmt.color_space,
+				},
+				_ => sfmt.clone(),
+			})
+			.nth(0)
+			.expect("Unable to find suitable surface format.");
+		if !capture
+		{
+			return surface_format;
+		}
+		match surface_formats.iter().find(|sfmt| {
+			sfmt.format == vk::Format::B8G8R8A8_UNORM && sfmt.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+		})
+		{
+			Some(sfmt) => return *sfmt,
+			None =>
+			{
+				if surface_format.format != vk::Format::B8G8R8A8_UNORM
+				{
+					warning!("The surface does not support B8G8R8A8_UNORM, capturing {:?}", surface_format.format);
+				}
+				return surface_format;
+			}
+		}
+	}
+
+	/// Returns true if the presentable images of the surface can be copied to a CaptureRing.
+	fn supports_capture(
+		rs: &RenderState, surface_loader: &Surface, surface: &vk::SurfaceKHR, surface_format: &vk::SurfaceFormatKHR,
+	) -> bool
+	{
+		let surface_capabilities;
+		unsafe {
+			surface_capabilities =
+				surface_loader.get_physical_device_surface_capabilities(rs.pdevice, *surface).unwrap();
+		}
+		if !surface_capabilities.supported_usage_flags.contains(vk::ImageUsageFlags::TRANSFER_SRC)
+		{
+			warning!("The presentable images cannot be copied, capture is disabled");
+			return false;
+		}
+		if !CaptureRing::supports_format(surface_format.format)
+		{
+			warning!("Frames of format {:?} cannot be captured, capture is disabled", surface_format.format);
+			return false;
+		}
+		return true;
+	}
+
 	/// Creates a vk::Swapchain and a vk::Rect2D for the current RenderState and surface.
 	///
 	/// Swapchain is used to queue and present stuff to the screen.
 	fn create_swapchain(
 		rs: &RenderState, surface_loader: &Surface, surface: &vk::SurfaceKHR, surface_format: &vk::SurfaceFormatKHR,
-		old_swapchain: vk::SwapchainKHR, swapchain_loader: &Swapchain,
+		old_swapchain: vk::SwapchainKHR, swapchain_loader: &Swapchain, capture: bool,
 	) -> (vk::SwapchainKHR, vk::Rect2D)
 	{
 		let surface_capabilities;
@@ -80,12 +241,16 @@ impl PresentPass
 				surface_capabilities.current_transform
 			};
 
-		let present_modes;
-		unsafe {
-			present_modes = surface_loader.get_physical_device_surface_present_modes(rs.pdevice, *surface).unwrap();
+		let present_mode = PresentPass::pick_present_mode(rs, surface_loader, surface);
+		// Captured frames are copied out of the presentable images
+		let image_usage = if capture
+		{
+			vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC
 		}
-		// Use FIFO presentmode to block on acquire_next_image, thus enabling vsync.
-		let present_mode = present_modes.iter().cloned().find(|&mode| mode == vk::PresentModeKHR::FIFO).unwrap();
+		else
+		{
+			vk::ImageUsageFlags::COLOR_ATTACHMENT
+		};
 		let swapchain_create_info = vk::SwapchainCreateInfoKHR {
 			s_type: vk::StructureType::SWAPCHAIN_CREATE_INFO_KHR,
 			surface: *surface,
@@ -93,9 +258,10 @@ impl PresentPass
 			image_color_space: surface_format.color_space,
 			image_format: surface_format.format,
 			image_extent: surface_capabilities.current_extent.clone(),
-			image_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+			image_usage: image_usage,
 			image_sharing_mode: vk::SharingMode::EXCLUSIVE,
 			pre_transform: pre_transform,
+			// Also keeps capture tools from showing the desktop through the alpha of the image
 			composite_alpha: vk::CompositeAlphaFlagsKHR::OPAQUE,
 			present_mode: present_mode,
 			clipped: 1,
@@ -123,16 +289,25 @@ impl PresentPass
 	/// Creates a Vec of vk::ImageViews for the presentable images in the swapchain.
 	///
 	/// This will create two imageviews for double-buffering, three imageviews for
-	/// tripple-buffering etc.
+	/// tripple-buffering etc. The images are returned alongside their views.
 	fn create_imageviews(
-		rs: &RenderState, surface_format: &vk::SurfaceFormatKHR, swapchain_loader: &Swapchain,
-		swapchain: vk::SwapchainKHR,
-	) -> Vec<vk::ImageView>
+		rs: &RenderState, name: &str, surface_format: &vk::SurfaceFormatKHR, surface_extent: vk::Extent2D,
+		swapchain_loader: &Swapchain, swapchain: vk::SwapchainKHR,
+	) -> (Vec<vk::Image>, Vec<vk::ImageView>)
 	{
 		let present_images;
 		unsafe {
 			present_images = swapchain_loader.get_swapchain_images(swapchain).unwrap();
 		}
+		for (idx, &image) in present_images.iter().enumerate()
+		{
+			rs.register_image(
+				image,
+				&format!("{} swapchain image {}", name, idx),
+				surface_extent,
+				surface_format.format,
+			);
+		}
 		let present_image_views: Vec<vk::ImageView> = present_images
 			.iter()
 			.map(|&image| {
@@ -158,24 +333,26 @@ impl PresentPass
 				};
 				let result;
 				unsafe { result = rs.device.create_image_view(&create_view_info, None).unwrap() }
+				track_create(ObjectKind::ImageView, result, &format!("{} swapchain", name));
 				result
 			})
 			.collect();
 
-		present_image_views
+		(present_images, present_image_views)
 	}
 
 	/// Creates a presentable renderpass.
 	///
 	/// Produces a color-only renderpass, perfect for direct drawing.
-	fn create_renderpass(rs: &RenderState, surface_format: &vk::SurfaceFormatKHR) -> vk::RenderPass
+	fn create_renderpass(rs: &RenderState, name: &str, surface_format: &vk::SurfaceFormatKHR) -> vk::RenderPass
 	{
 		// One attachment, color only. Will produce the presentable image.
 		let renderpass_attachments = [vk::AttachmentDescription {
 			format: surface_format.format,
 			flags: vk::AttachmentDescriptionFlags::empty(),
 			samples: vk::SampleCountFlags::TYPE_1,
-			load_op: vk::AttachmentLoadOp::DONT_CARE,
+			// Cleared for the bars around a letterboxed image
+			load_op: vk::AttachmentLoadOp::CLEAR,
 			store_op: vk::AttachmentStoreOp::STORE,
 			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
 			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
@@ -204,6 +381,7 @@ impl PresentPass
 		unsafe {
 			renderpass = rs.device.create_render_pass(&renderpass_create_info, None).unwrap();
 		}
+		rs.register_pass(&format!("{}: present", name), &renderpass_attachments);
 
 		renderpass
 	}
@@ -224,10 +402,16 @@ impl PresentPass
 	)
 	{
 		// Descriptors
-		let descriptor_sizes = [vk::DescriptorPoolSize {
-			ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-			descriptor_count: 1,
-		}];
+		let descriptor_sizes = [
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 6,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::UNIFORM_BUFFER,
+				descriptor_count: 1,
+			},
+		];
 		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
 			pool_size_count: descriptor_sizes.len() as u32,
@@ -239,13 +423,55 @@ impl PresentPass
 		unsafe {
 			descriptor_pool = rs.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
 		}
-		let desc_layout_bindings = [vk::DescriptorSetLayoutBinding {
-			binding: 0,
-			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-			descriptor_count: 1,
-			stage_flags: vk::ShaderStageFlags::FRAGMENT,
-			p_immutable_samplers: ptr::null(),
-		}];
+		let desc_layout_bindings = [
+			vk::DescriptorSetLayoutBinding {
+				binding: 0,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// Depth, for masking the sun shafts
+			vk::DescriptorSetLayoutBinding {
+				binding: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// Previous and current color grading tables
+			vk::DescriptorSetLayoutBinding {
+				binding: 2,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 2,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// Debug overlay, like the heatmaps
+			vk::DescriptorSetLayoutBinding {
+				binding: 3,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// Lens flare elements
+			vk::DescriptorSetLayoutBinding {
+				binding: 4,
+				descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+			// Volumetric fog froxels
+			vk::DescriptorSetLayoutBinding {
+				binding: 5,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::FRAGMENT,
+				p_immutable_samplers: ptr::null(),
+			},
+		];
 		let descriptor_info = vk::DescriptorSetLayoutCreateInfo {
 			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
 			binding_count: desc_layout_bindings.len() as u32,
@@ -267,10 +493,17 @@ impl PresentPass
 		unsafe {
 			descriptor_sets = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
 		}
+		let post_params_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::FRAGMENT,
+			size: size_of::<PostParams>() as u32,
+			offset: 0,
+		};
 		let layout_create_info = vk::PipelineLayoutCreateInfo {
 			s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
 			set_layout_count: descriptor_set_layouts.len() as u32,
 			p_set_layouts: descriptor_set_layouts.as_ptr(),
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &post_params_push_constant,
 			..Default::default()
 		};
 
@@ -279,8 +512,8 @@ impl PresentPass
 			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
 		}
 
-		let vertex_shader_module = rs.load_shader("shaders/final_pass_vert.spv");
-		let fragment_shader_module = rs.load_shader("shaders/final_pass_frag.spv");
+		let vertex_shader_module = rs.load_shader("shaders/final_pass_vert.spv", &[]);
+		let fragment_shader_module = rs.load_shader("shaders/final_pass_frag.spv", &[]);
 
 		let shader_entry_name = CString::new("main").unwrap();
 		let shader_stage_create_infos = [
@@ -399,13 +632,14 @@ impl PresentPass
 		unsafe {
 			graphics_pipelines = rs
 				.device
-				.create_graphics_pipelines(vk::PipelineCache::null(), &[graphic_pipeline_info], None)
+				.create_graphics_pipelines(rs.pipeline_cache, &[graphic_pipeline_info], None)
 				.expect("Unable to create graphics pipeline");
 
 			// Graphics pipeline created, we no longer need the shader modules
 			rs.device.destroy_shader_module(fragment_shader_module, None);
 			rs.device.destroy_shader_module(vertex_shader_module, None);
 		}
+		track_create(ObjectKind::Pipeline, graphics_pipelines[0], "present");
 
 		(
 			descriptor_pool,
@@ -467,15 +701,14 @@ impl PresentPass
 		command_buffers
 	}
 
-	/// Initializes the PresentPass based on a RenderState
+	/// Initializes the PresentPass based on a RenderState, presenting to the given window.
 	///
-	/// This will set up the swapchain, renderpass, etc.
-	pub fn init(rs: &RenderState) -> PresentPass
+	/// This will set up the swapchain, renderpass, etc. The window must outlive the PresentPass.
+	pub fn init(rs: &RenderState, window: &Window, name: &'static str) -> PresentPass
 	{
 		// Surface
 		let vk_instance: vk::Instance = rs.instance.handle();
-		let raw_surface = rs
-			.window
+		let raw_surface = window
 			.vulkan_create_surface(vk_instance.as_raw().try_into().unwrap())
 			.expect("Faied to create vulkan surface from SDL2 window");
 		let surface = vk::SurfaceKHR::from_raw(raw_surface);
@@ -487,18 +720,10 @@ impl PresentPass
 				.unwrap());
 			surface_formats = surface_loader.get_physical_device_surface_formats(rs.pdevice, surface).unwrap();
 		}
-		let surface_format = surface_formats
-			.iter()
-			.map(|sfmt| match sfmt.format
-			{
-				vk::Format::UNDEFINED => vk::SurfaceFormatKHR {
-					format: vk::Format::B8G8R8_UNORM,
-					color_space: sfmt.color_space,
-				},
-				_ => sfmt.clone(),
-			})
-			.nth(0)
-			.expect("Unable to find suitable surface format.");
+		// Only the main window is captured
+		let capture = rs.capture && window.id() == rs.window.id();
+		let surface_format = PresentPass::pick_surface_format(&surface_formats, capture);
+		let capture = capture && PresentPass::supports_capture(rs, &surface_loader, &surface, &surface_format);
 
 		let sem_create_info = vk::SemaphoreCreateInfo {
 			s_type: vk::StructureType::SEMAPHORE_CREATE_INFO,
@@ -520,15 +745,28 @@ impl PresentPass
 			&surface_format,
 			vk::SwapchainKHR::null(),
 			&swapchain_loader,
+			capture,
 		);
-		let present_image_views = PresentPass::create_imageviews(rs, &surface_format, &swapchain_loader, swapchain);
-		let renderpass = PresentPass::create_renderpass(rs, &surface_format);
+		let (present_images, present_image_views) = PresentPass::create_imageviews(
+			rs,
+			name,
+			&surface_format,
+			surface_size.extent,
+			&swapchain_loader,
+			swapchain,
+		);
+		let renderpass = PresentPass::create_renderpass(rs, name, &surface_format);
 		let (descriptor_pool, descriptor_set_layouts, descriptor_sets, pipeline_layout, viewport, scissor, pipeline) =
 			PresentPass::create_pipeline(rs, surface_size, renderpass);
 		let framebuffers = PresentPass::create_framebuffers(rs, surface_size, &present_image_views, renderpass);
 		let command_buffers = PresentPass::create_commandbuffers(rs, &framebuffers);
+		let overlay = Overlay::init(rs, renderpass, surface_format.format);
+		let color_grading = ColorGrading::new(rs);
+		let lens_flare = LensFlare::new(rs);
 
 		PresentPass {
+			name: name,
+
 			// Surface
 			surface_loader: surface_loader,
 			surface: surface,
@@ -542,6 +780,7 @@ impl PresentPass
 
 			// Swapchain
 			swapchain: swapchain,
+			present_images: present_images,
 			present_image_views: present_image_views,
 			renderpass: renderpass,
 			descriptor_pool: descriptor_pool,
@@ -550,6 +789,9 @@ impl PresentPass
 			pipeline_layout: pipeline_layout,
 			viewport: viewport,
 			scissor: scissor,
+			image_area: None,
+			debug_overlay: None,
+			volumetric_fog: None,
 			pipeline: pipeline,
 			// one framebuffer/commandbuffer per image
 			framebuffers: framebuffers,
@@ -557,6 +799,19 @@ impl PresentPass
 
 			// The current idx
 			current_present_idx: std::usize::MAX,
+			capture: if capture
+			{
+				Some(CaptureRing::new())
+			}
+			else
+			{
+				None
+			},
+
+			post_params: PostParams::default(),
+			overlay: overlay,
+			color_grading: color_grading,
+			lens_flare: lens_flare,
 
 			// Keep a pointer to the device for cleanup
 			device: Rc::clone(&rs.device),
@@ -568,6 +823,11 @@ impl PresentPass
 	/// The user is responsible for not calling this function without a swapchain.
 	fn cleanup_swapchain(&mut self)
 	{
+		track_destroy(ObjectKind::Pipeline, self.pipeline);
+		for &image_view in self.present_image_views.iter()
+		{
+			track_destroy(ObjectKind::ImageView, image_view);
+		}
 		unsafe {
 			// Always wait for device idle
 			self.device.device_wait_idle().unwrap();
@@ -612,12 +872,20 @@ impl PresentPass
 			&self.surface_format,
 			vk::SwapchainKHR::null(),
 			&self.swapchain_loader,
+			self.capture.is_some(),
 		);
 		self.swapchain = swapchain;
-		let present_image_views =
-			PresentPass::create_imageviews(rs, &self.surface_format, &self.swapchain_loader, swapchain);
+		let (present_images, present_image_views) = PresentPass::create_imageviews(
+			rs,
+			self.name,
+			&self.surface_format,
+			surface_size.extent,
+			&self.swapchain_loader,
+			swapchain,
+		);
+		self.present_images = present_images;
 		self.present_image_views = present_image_views;
-		let renderpass = PresentPass::create_renderpass(rs, &self.surface_format);
+		let renderpass = PresentPass::create_renderpass(rs, self.name, &self.surface_format);
 		self.renderpass = renderpass;
 		let (descriptor_pool, descriptor_set_layouts, descriptor_sets, pipeline_layout, viewport, scissor, pipeline) =
 			PresentPass::create_pipeline(rs, surface_size, renderpass);
@@ -628,18 +896,28 @@ impl PresentPass
 		self.viewport = viewport;
 		self.scissor = scissor;
 		self.pipeline = pipeline;
+		self.overlay.recreate_pipeline(rs, renderpass);
 		let framebuffers = PresentPass::create_framebuffers(rs, surface_size, &self.present_image_views, renderpass);
 		self.framebuffers = framebuffers;
 		let command_buffers = PresentPass::create_commandbuffers(rs, &self.framebuffers);
 		self.commandbuffers = command_buffers;
 	}
 
+	/// Recreates the present and overlay pipelines from their shaders on disk, e.g. after they were rebuilt, see
+	/// RenderState::poll_shader_changes(). They are built with the swapchain, so it is recreated along with them.
+	pub fn reload_shaders(&mut self, rs: &RenderState)
+	{
+		self.recreate_swapchain(rs);
+	}
+
 	/// Starts a frame for the current swapchain. The returned commandbuffer should be used for
 	/// rendering.
 	///
 	/// On error (for example when the swapchain needs to be recreated), this function returns
 	/// None, meaning that the current frame should be skipped.
-	fn begin_frame(&mut self, rs: &RenderState, image: &mut Texture) -> Option<vk::CommandBuffer>
+	fn begin_frame(
+		&mut self, rs: &RenderState, image: &mut Texture, depth: Option<&mut Texture>,
+	) -> Option<vk::CommandBuffer>
 	{
 		let result;
 		unsafe {
@@ -680,6 +958,15 @@ impl PresentPass
 			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
 		}
 
+		let mut reads = vec![image.image];
+		reads.extend(depth.as_ref().map(|depth| depth.image));
+		rs.trace_pass(
+			cmd_buf,
+			&format!("{}: present", self.name),
+			&reads,
+			&[self.present_images[self.current_present_idx]],
+		);
+
 		// Transition the mainpass output to a samplable image
 		rs.transition_texture(
 			image,
@@ -688,15 +975,43 @@ impl PresentPass
 			vk::PipelineStageFlags::FRAGMENT_SHADER,
 			Some(cmd_buf),
 		);
+		if let Some(depth) = depth
+		{
+			rs.transition_texture(
+				depth,
+				vk::AccessFlags::SHADER_READ,
+				vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+				vk::PipelineStageFlags::FRAGMENT_SHADER,
+				Some(cmd_buf),
+			);
+		}
 
 		// Begin renderpass
+		let clear_values = [vk::ClearValue {
+			color: vk::ClearColorValue {
+				float32: [0.0, 0.0, 0.0, 1.0],
+			},
+		}];
 		let render_pass_begin_info = vk::RenderPassBeginInfo {
 			s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
 			render_pass: self.renderpass,
 			framebuffer: self.framebuffers[self.current_present_idx],
 			render_area: self.scissor,
+			clear_value_count: clear_values.len() as u32,
+			p_clear_values: clear_values.as_ptr(),
 			..Default::default()
 		};
+		let viewport = match self.image_area
+		{
+			Some(area) => vk::Viewport {
+				x: area.offset.x as f32,
+				y: area.offset.y as f32,
+				width: area.extent.width as f32,
+				height: area.extent.height as f32,
+				..self.viewport
+			},
+			None => self.viewport,
+		};
 		unsafe {
 			// Start the render pass
 			rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::INLINE);
@@ -704,7 +1019,7 @@ impl PresentPass
 			// Bind pipeline
 			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
 
-			rs.device.cmd_set_viewport(cmd_buf, 0, &[self.viewport]);
+			rs.device.cmd_set_viewport(cmd_buf, 0, &[viewport]);
 			rs.device.cmd_set_scissor(cmd_buf, 0, &[self.scissor]);
 		}
 
@@ -722,6 +1037,19 @@ impl PresentPass
 		unsafe {
 			// End render pass and command buffer
 			rs.device.cmd_end_render_pass(cmd_buf);
+		}
+		if let Some(capture) = &mut self.capture
+		{
+			capture.record_copy(
+				rs,
+				cmd_buf,
+				self.present_images[self.current_present_idx],
+				self.scissor.extent,
+				self.surface_format.format,
+			);
+		}
+		rs.end_pass(cmd_buf);
+		unsafe {
 			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
 		}
 
@@ -750,6 +1078,10 @@ impl PresentPass
 			rs.device.wait_for_fences(&[submit_fence], true, std::u64::MAX).expect("Wait for fence failed.");
 			rs.device.destroy_fence(submit_fence, None);
 		}
+		if let Some(capture) = &mut self.capture
+		{
+			capture.finish_copy();
+		}
 
 		let present_info = vk::PresentInfoKHR {
 			s_type: vk::StructureType::PRESENT_INFO_KHR,
@@ -782,13 +1114,62 @@ impl PresentPass
 		self.current_present_idx = std::usize::MAX;
 	}
 
+	/// Returns a reader of the presented frames, or None if they are not kept. See Config::capture.
+	pub fn get_capture_reader(&self) -> Option<CaptureReader>
+	{
+		return self.capture.as_ref().map(|capture| capture.get_reader());
+	}
+
+	pub fn get_surface_extent(&self) -> vk::Extent2D
+	{
+		return self.scissor.extent;
+	}
+
+	/// Sets the area of the surface the presented image is drawn in, see ViewportMapper::get_image_area(). The image
+	/// is stretched to the whole surface by default.
+	pub fn set_image_area(&mut self, area: vk::Rect2D)
+	{
+		self.image_area = Some(area);
+	}
+
+	/// Sets the image blended over the presented image by its alpha and the debug_overlay post parameter. It must
+	/// stay readable by the fragment shader until replaced, and have the size of the presented images.
+	pub fn set_debug_overlay(&mut self, overlay: Option<&Texture>)
+	{
+		self.debug_overlay = overlay.map(|overlay| vk::DescriptorImageInfo {
+			image_layout: overlay.current_layout,
+			image_view: overlay.view,
+			sampler: overlay.sampler,
+		});
+	}
+
+	/// Sets the froxels of the volumetric fog composited over the image, up to the fog_distance post parameter. They
+	/// must stay readable by the fragment shader until replaced. See VolumetricFog.
+	pub fn set_volumetric_fog(&mut self, froxels: Option<&Texture>)
+	{
+		self.volumetric_fog = froxels.map(|froxels| vk::DescriptorImageInfo {
+			image_layout: froxels.current_layout,
+			image_view: froxels.view,
+			sampler: froxels.sampler,
+		});
+	}
+
+	/// Returns a report of the capabilities of all GPUs, including support for the presented surface.
+	pub fn get_gpu_report(&self, rs: &RenderState) -> String
+	{
+		return gpu_info::generate_report(rs, &self.surface_loader, self.surface);
+	}
+
 	/// Presents the passed image to the screen.
 	///
 	/// If swapchain is outdated, a new one is created, but no image output is done.
-	pub fn present_image(&mut self, rs: &RenderState, image: &mut Texture)
+	///
+	/// The depth buffer the image was rendered with is needed for the sun shafts, lens flare, volumetric fog and depth
+	/// of field, they are disabled without it.
+	pub fn present_image(&mut self, rs: &RenderState, image: &mut Texture, mut depth: Option<&mut Texture>)
 	{
 		let cmd_buf;
-		let res = self.begin_frame(rs, image);
+		let res = self.begin_frame(rs, image, depth.as_deref_mut());
 		match res
 		{
 			Some(buf) =>
@@ -808,16 +1189,108 @@ impl PresentPass
 			image_view: image.view,
 			sampler: image.sampler,
 		};
-		let write_desc_sets = [vk::WriteDescriptorSet {
-			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
-			dst_set: self.descriptor_sets[0],
-			dst_binding: 0,
-			dst_array_element: 0,
-			descriptor_count: 1,
-			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-			p_image_info: &image_descriptor,
-			..Default::default()
-		}];
+		let mut post_params = self.post_params;
+		post_params.lut_blend = self.color_grading.get_blend();
+		let (previous_lut, current_lut) = self.color_grading.get_luts();
+		let lut_descriptors: Vec<vk::DescriptorImageInfo> = [previous_lut, current_lut]
+			.iter()
+			.map(|lut| vk::DescriptorImageInfo {
+				image_layout: lut.current_layout,
+				image_view: lut.view,
+				sampler: lut.sampler,
+			})
+			.collect();
+		// Without depth, the image is bound in its place to keep the descriptor valid, but it must not be used
+		let depth_descriptor = match &depth
+		{
+			Some(depth) => vk::DescriptorImageInfo {
+				image_layout: depth.current_layout,
+				image_view: depth.view,
+				sampler: depth.sampler,
+			},
+			None =>
+			{
+				post_params.sun_shafts_intensity = 0.0;
+				post_params.lens_flare_intensity = 0.0;
+				post_params.fog_distance = 0.0;
+				post_params.dof_aperture = 0.0;
+				post_params.dof_debug = 0;
+				image_descriptor
+			}
+		};
+		// Likewise for the debug overlay
+		let debug_overlay_descriptor = self.debug_overlay.unwrap_or_else(|| {
+			post_params.debug_overlay = 0.0;
+			image_descriptor
+		});
+		let lens_flare_descriptor = self.lens_flare.get_descriptor();
+		// The froxels are a 3D texture, so a color grading table takes their place
+		let fog_descriptor = self.volumetric_fog.unwrap_or_else(|| {
+			post_params.fog_distance = 0.0;
+			lut_descriptors[1]
+		});
+		let write_desc_sets = [
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_sets[0],
+				dst_binding: 0,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: &image_descriptor,
+				..Default::default()
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_sets[0],
+				dst_binding: 1,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: &depth_descriptor,
+				..Default::default()
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_sets[0],
+				dst_binding: 2,
+				dst_array_element: 0,
+				descriptor_count: lut_descriptors.len() as u32,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: lut_descriptors.as_ptr(),
+				..Default::default()
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_sets[0],
+				dst_binding: 3,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: &debug_overlay_descriptor,
+				..Default::default()
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_sets[0],
+				dst_binding: 4,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+				p_buffer_info: &lens_flare_descriptor,
+				..Default::default()
+			},
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+				dst_set: self.descriptor_sets[0],
+				dst_binding: 5,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				p_image_info: &fog_descriptor,
+				..Default::default()
+			},
+		];
 		unsafe {
 			// Update the descriptor set for the image to draw
 			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
@@ -831,9 +1304,25 @@ impl PresentPass
 				&[],
 			);
 
+			let post_params_bytes =
+				slice::from_raw_parts(&post_params as *const PostParams as *const u8, mem::size_of::<PostParams>());
+			rs.device.cmd_push_constants(
+				cmd_buf,
+				self.pipeline_layout,
+				vk::ShaderStageFlags::FRAGMENT,
+				0,
+				post_params_bytes,
+			);
+
 			// We have a hardcoded quad shader, so just draw three vertices
 			rs.device.cmd_draw(cmd_buf, 3, 1, 0, 0);
 		}
+		// Overlay on top, at full window resolution
+		unsafe {
+			rs.device.cmd_set_viewport(cmd_buf, 0, &[self.viewport]);
+		}
+		let image_area = self.image_area.unwrap_or(self.scissor);
+		self.overlay.draw(cmd_buf, self.scissor.extent, image_area, depth.as_deref());
 		// then swapbuffers etc.
 		self.end_frame_and_present(rs);
 	}
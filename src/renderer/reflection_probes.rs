@@ -0,0 +1,226 @@
+use crate::renderer::shadowpass::get_cube_face_matrices;
+use crate::renderer::{
+	FrameUniforms, IrradianceGridUniforms, LightUniforms, MainPass, ProbeUniforms, RenderState, Texture,
+};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{Matrix4, Point3, Vector3};
+use std::collections::HashMap;
+
+/// Most reflection probes in a scene, must match MAX_REFLECTION_PROBES in phong.frag.
+pub const MAX_REFLECTION_PROBES: usize = 4;
+// Same as the main pass render format, as the faces are copied from its render image
+const PROBE_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+const PROBE_NEAR: f32 = 0.1;
+const PROBE_FAR: f32 = 1_000.0;
+
+/// A reflection probe of the scene, lighting reflections within its box volume.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ReflectionProbe
+{
+	/// Where the probe is baked from, the center of its volume.
+	pub position: Point3<f32>,
+	/// Half the size of the volume along each axis.
+	pub extents: Vector3<f32>,
+	/// Size of each face of the cube map, in texels.
+	pub resolution: u32,
+}
+
+/// The cube maps of the reflection probes, baked by rendering the scene from each probe.
+///
+/// The shaders blend the two nearest probes whose volumes contain the fragment. Fragments outside all volumes get no
+/// reflections.
+pub struct ReflectionProbes
+{
+	probes: Vec<ReflectionProbe>,
+	cube_maps: Vec<Texture>,
+}
+
+impl ReflectionProbes
+{
+	/// Creates an empty set of probes, see bake().
+	pub fn new() -> ReflectionProbes
+	{
+		ReflectionProbes {
+			probes: Vec::new(),
+			cube_maps: Vec::new(),
+		}
+	}
+
+	/// Returns true if the given probes are the baked ones.
+	pub fn is_baked(&self, probes: &[ReflectionProbe]) -> bool
+	{
+		return self.probes[..] == probes[..probes.len().min(MAX_REFLECTION_PROBES)];
+	}
+
+	/// Returns the probe volumes, as seen by the shaders.
+	pub fn get_uniforms(&self) -> Vec<ProbeUniforms>
+	{
+		return self.probes.iter().map(|probe| ProbeUniforms::new(probe.position, probe.extents)).collect();
+	}
+
+	/// Returns the view and sampler of each probe's cube map, see MainPass::set_reflection_probes().
+	pub fn get_cube_maps(&self) -> Vec<Option<(vk::ImageView, vk::Sampler)>>
+	{
+		return self.cube_maps.iter().map(|cube_map| Some((cube_map.view, cube_map.sampler))).collect();
+	}
+
+	/// Replaces the baked probes with the given ones, rendering the scene into the cube map of each. The scene is
+	/// drawn by draw_scene, given a main pass rendering a cube face, and the view and projection matrices of the face.
+	///
+	/// Lights and the shadow atlas are the ones of the current frame. This stalls the GPU, so it should only be done
+	/// when loading or on demand.
+	pub fn bake<F>(
+		&mut self, rs: &RenderState, probes: &[ReflectionProbe], lights: &[LightUniforms],
+		shadow_atlas: (vk::ImageView, vk::Sampler), wetness: f32, mut draw_scene: F,
+	) where
+		F: FnMut(&MainPass, vk::CommandBuffer, &Matrix4<f32>, &Matrix4<f32>),
+	{
+		self.cube_maps.clear();
+		if probes.len() > MAX_REFLECTION_PROBES
+		{
+			warning!("Only the first {} of {} reflection probes are used", MAX_REFLECTION_PROBES, probes.len());
+		}
+		self.probes = probes.iter().take(MAX_REFLECTION_PROBES).cloned().collect();
+
+		// The faces are rendered mirrored in y, see get_cube_face_matrices(), so the scene is mirrored back. The
+		// shaders look up the probes with mirrored directions.
+		let mirror = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+		// One main pass per resolution, rendering a face at a time
+		let mut mainpasses: HashMap<u32, MainPass> = HashMap::new();
+		for probe in self.probes.iter()
+		{
+			let cube_map = rs.create_cube_texture(
+				probe.resolution,
+				PROBE_FORMAT,
+				vk::ImageAspectFlags::COLOR,
+				vk::ImageUsageFlags::TRANSFER_DST,
+			);
+			let mp = mainpasses.entry(probe.resolution).or_insert_with(|| {
+				MainPass::init_with_size(rs, probe.resolution, probe.resolution, "reflection probe")
+			});
+			mp.set_shadow_atlas(rs, Some(shadow_atlas));
+
+			let mirrored_position = Point3::new(probe.position.x, -probe.position.y, probe.position.z);
+			let (view_matrices, projection_matrix) = get_cube_face_matrices(mirrored_position, PROBE_NEAR, PROBE_FAR);
+			for (face, view_matrix) in view_matrices.iter().enumerate()
+			{
+				let view_matrix = view_matrix * mirror;
+				// Animated materials are baked as they start
+				mp.update_frame_uniforms(&FrameUniforms::new(
+					view_matrix,
+					wetness,
+					0.0,
+					lights,
+					&[],
+					IrradianceGridUniforms::default(),
+				));
+				let cmd_buf = mp.begin_frame(rs);
+				draw_scene(mp, cmd_buf, &view_matrix, &projection_matrix);
+				mp.end_frame(rs);
+				ReflectionProbes::copy_to_face(rs, &mut mp.render_image, &cube_map, face as u32, probe.resolution);
+			}
+			self.cube_maps.push(cube_map);
+		}
+		println!("Baked {} reflection probes", self.probes.len());
+	}
+
+	/// Copies a rendered image into a face of a cube map, leaving the cube map ready to be sampled.
+	fn copy_to_face(rs: &RenderState, image: &mut Texture, cube_map: &Texture, face: u32, size: u32)
+	{
+		let face_range = vk::ImageSubresourceRange {
+			aspect_mask: vk::ImageAspectFlags::COLOR,
+			base_mip_level: 0,
+			level_count: 1,
+			base_array_layer: face,
+			layer_count: 1,
+		};
+		let to_transfer_barrier = vk::ImageMemoryBarrier {
+			s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+			src_access_mask: vk::AccessFlags::SHADER_READ,
+			dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+			old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			image: cube_map.image,
+			subresource_range: face_range,
+			..Default::default()
+		};
+		let to_shader_barrier = vk::ImageMemoryBarrier {
+			s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+			src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+			dst_access_mask: vk::AccessFlags::SHADER_READ,
+			old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			image: cube_map.image,
+			subresource_range: face_range,
+			..Default::default()
+		};
+		let copy_region = vk::ImageCopy {
+			src_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			src_offset: vk::Offset3D {
+				x: 0,
+				y: 0,
+				z: 0,
+			},
+			dst_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				mip_level: 0,
+				base_array_layer: face,
+				layer_count: 1,
+			},
+			dst_offset: vk::Offset3D {
+				x: 0,
+				y: 0,
+				z: 0,
+			},
+			extent: vk::Extent3D {
+				width: size,
+				height: size,
+				depth: 1,
+			},
+		};
+
+		let cmd_buf = rs.begin_single_time_commands();
+		rs.transition_texture(
+			image,
+			vk::AccessFlags::TRANSFER_READ,
+			vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			vk::PipelineStageFlags::TRANSFER,
+			Some(cmd_buf),
+		);
+		unsafe {
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				vk::PipelineStageFlags::FRAGMENT_SHADER,
+				vk::PipelineStageFlags::TRANSFER,
+				vk::DependencyFlags::empty(),
+				&[],
+				&[],
+				&[to_transfer_barrier],
+			);
+			rs.device.cmd_copy_image(
+				cmd_buf,
+				image.image,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				cube_map.image,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				&[copy_region],
+			);
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				vk::PipelineStageFlags::TRANSFER,
+				vk::PipelineStageFlags::FRAGMENT_SHADER,
+				vk::DependencyFlags::empty(),
+				&[],
+				&[],
+				&[to_shader_barrier],
+			);
+		}
+		rs.end_single_time_commands(cmd_buf);
+	}
+}
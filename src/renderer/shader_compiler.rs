@@ -0,0 +1,173 @@
+//! Runtime compilation of the GLSL shaders, enabled by the runtime_shaders feature.
+//!
+//! Shaders are compiled from the source next to their precompiled .spv file, so changes take effect without
+//! rebuilding the game. Compiled shaders are cached on disk, keyed by a hash of their source, includes and defines.
+
+use shaderc;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+const CACHE_DIR: &str = "shader_cache";
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Returns the GLSL source and stage of a shader, given the path of its precompiled .spv file. See build.rs for the
+/// naming of the .spv files.
+fn source_path(spv_path: &str) -> Option<(PathBuf, shaderc::ShaderKind)>
+{
+	let stages = [
+		("_vert.spv", ".vert", shaderc::ShaderKind::Vertex),
+		("_frag.spv", ".frag", shaderc::ShaderKind::Fragment),
+		("_comp.spv", ".comp", shaderc::ShaderKind::Compute),
+	];
+	for (spv_suffix, glsl_suffix, kind) in stages.iter()
+	{
+		if spv_path.ends_with(spv_suffix)
+		{
+			let source = spv_path[..spv_path.len() - spv_suffix.len()].to_string() + glsl_suffix;
+			return Some((PathBuf::from(source), *kind));
+		}
+	}
+	return None;
+}
+
+/// Reads a source file, replacing each #include "file" with the contents of the file, relative to the including file.
+/// Files are only included once.
+fn preprocess(path: &Path, included: &mut Vec<PathBuf>, depth: usize) -> Result<String, String>
+{
+	if depth > MAX_INCLUDE_DEPTH
+	{
+		return Err(format!("{}: includes nested too deep", path.display()));
+	}
+	let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+	let mut expanded = String::with_capacity(source.len());
+	for (line_idx, line) in source.lines().enumerate()
+	{
+		let directive = line.trim_start();
+		// The includes are resolved here, the extension is only needed by glslangValidator
+		if directive.starts_with("#extension GL_GOOGLE_include_directive")
+		{
+			expanded.push('\n');
+			continue;
+		}
+		if !directive.starts_with("#include")
+		{
+			expanded.push_str(line);
+			expanded.push('\n');
+			continue;
+		}
+
+		let name = directive["#include".len()..].trim().trim_matches('"');
+		let include_path = path.parent().unwrap_or(Path::new("")).join(name);
+		let canonical = include_path.canonicalize().map_err(|e| format!("{}: {}", include_path.display(), e))?;
+		if !included.contains(&canonical)
+		{
+			included.push(canonical);
+			expanded.push_str("#line 1\n");
+			expanded.push_str(&preprocess(&include_path, included, depth + 1)?);
+		}
+		// Keep the line numbers of errors in the including file
+		expanded.push_str(&format!("#line {}\n", line_idx + 2));
+	}
+	return Ok(expanded);
+}
+
+/// Compiles GLSL shaders into SPIR-V, caching the results.
+pub struct ShaderCompiler
+{
+	// Compiling needs a mutable compiler, while shaders are loaded through a shared RenderState
+	compiler: RefCell<shaderc::Compiler>,
+}
+
+impl ShaderCompiler
+{
+	/// Creates the compiler, or returns None if shaderc could not be initialized.
+	pub fn new() -> Option<ShaderCompiler>
+	{
+		let compiler = shaderc::Compiler::new()?;
+		Some(ShaderCompiler {
+			compiler: RefCell::new(compiler),
+		})
+	}
+
+	/// Returns the source of the given precompiled shader and the files it includes, to watch them for changes.
+	pub fn get_source_files(spv_path: &str) -> Vec<PathBuf>
+	{
+		let path = match source_path(spv_path)
+		{
+			Some((path, _)) => path,
+			None => return Vec::new(),
+		};
+		// A source that fails to preprocess is watched all the same, so fixing it is noticed
+		let mut included = Vec::new();
+		let _ = preprocess(&path, &mut included, 0);
+		included.insert(0, path);
+		return included;
+	}
+
+	/// Compiles the source of the given precompiled shader, with the given defines, or loads it from the cache.
+	///
+	/// Returns None if there is no source, or it fails to compile, in which case the precompiled shader should be used.
+	pub fn compile(&self, spv_path: &str, defines: &[(&str, String)]) -> Option<Vec<u8>>
+	{
+		let (path, kind) = source_path(spv_path)?;
+		if !path.exists()
+		{
+			return None;
+		}
+		match self.compile_source(&path, kind, defines)
+		{
+			Ok(spirv) => return Some(spirv),
+			Err(e) =>
+			{
+				warning!("Could not compile {}, using the precompiled shader: {}", path.display(), e);
+				return None;
+			}
+		}
+	}
+
+	fn compile_source(
+		&self, path: &Path, kind: shaderc::ShaderKind, defines: &[(&str, String)],
+	) -> Result<Vec<u8>, String>
+	{
+		let source = preprocess(path, &mut Vec::new(), 0)?;
+
+		let mut hasher = DefaultHasher::new();
+		source.hash(&mut hasher);
+		defines.hash(&mut hasher);
+		let stem = path.file_name().unwrap().to_string_lossy().replace(".", "_");
+		let cache_path = Path::new(CACHE_DIR).join(format!("{}_{:016x}.spv", stem, hasher.finish()));
+		if let Ok(spirv) = fs::read(&cache_path)
+		{
+			return Ok(spirv);
+		}
+
+		let mut options = shaderc::CompileOptions::new().ok_or("could not create compile options")?;
+		options.set_target_env(shaderc::TargetEnv::Vulkan, shaderc::EnvVersion::Vulkan1_0 as u32);
+		for (name, value) in defines
+		{
+			options.add_macro_definition(name, Some(value));
+		}
+		let artifact = self
+			.compiler
+			.borrow_mut()
+			.compile_into_spirv(&source, kind, &path.to_string_lossy(), "main", Some(&options))
+			.map_err(|e| e.to_string())?;
+		if artifact.get_num_warnings() > 0
+		{
+			warning!("{}", artifact.get_warning_messages());
+		}
+		let spirv = artifact.as_binary_u8().to_vec();
+
+		// A failed write only costs a recompile next time
+		if let Err(e) = fs::create_dir_all(CACHE_DIR).and_then(|_| fs::write(&cache_path, &spirv))
+		{
+			warning!("Could not cache {}: {}", cache_path.display(), e);
+		}
+		println!("Compiled {}", path.display());
+		return Ok(spirv);
+	}
+}
@@ -0,0 +1,784 @@
+use crate::core::VertexFormat;
+use crate::renderer::mainpass::MAX_LIGHTS;
+use crate::renderer::{track_create, track_destroy, LightUniforms, ObjectKind, RenderState, Texture};
+use ash::version::DeviceV1_0;
+use ash::vk;
+use ash::Device;
+use cgmath::prelude::*;
+use cgmath::{Deg, Matrix4, Point3, Rad, Vector3};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::ffi::CString;
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+
+const SHADOW_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+// Width and height of the shadow atlas shared by the lights, in texels
+const SHADOW_ATLAS_SIZE: u32 = 4_096;
+// Smallest face resolution a light with shadows is given, in texels
+const MIN_SHADOW_RESOLUTION: u32 = 64;
+// Most frames between shadow updates of the least important lights
+const MAX_UPDATE_INTERVAL: u64 = 8;
+// Lights keep their resolution until their importance asks for less than this fraction of it
+const RESOLUTION_HYSTERESIS: f32 = 0.75;
+// Geometry closer to the light than this does not cast shadows, in meters
+const SHADOW_NEAR: f32 = 0.05;
+const PASS_NAME: &str = "shadows";
+
+/// View direction and up vector of each cube map face, in the order of the array layers.
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+	([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+	([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+	([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+	([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+	([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+	([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+/// Returns the view matrix of each cube map face seen from the position, in the order of the array layers, and the
+/// 90 degree projection matrix shared by the faces, with Vulkan's [0, 1] depth range.
+///
+/// The faces are rendered without flipping y, so the matrices mirror the scene compared to the camera's.
+pub fn get_cube_face_matrices(position: Point3<f32>, near: f32, far: f32) -> (Vec<Matrix4<f32>>, Matrix4<f32>)
+{
+	let views = CUBE_FACES
+		.iter()
+		.map(|&(direction, up)| Matrix4::look_at_rh(position, position + Vector3::from(direction), Vector3::from(up)))
+		.collect();
+	let depth_range = Matrix4::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5, 1.0);
+	return (views, depth_range * cgmath::perspective(Deg(90.0), 1.0, near, far));
+}
+
+/// A point light of the scene.
+#[derive(Clone, Copy)]
+pub struct PointLight
+{
+	pub position: Point3<f32>,
+	pub radius: f32,
+	pub color: [f32; 3],
+	/// Size of each face of the shadow map when the light covers the screen, in texels. Lights covering less of it get
+	/// less. 0 for lights without shadows.
+	pub shadow_resolution: u32,
+}
+
+/// Where the cube map faces of a shadowed light are in the shadow atlas. The six faces are laid out in a tile of 3 by 2
+/// faces, in the order of the cube map layers.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ShadowTile
+{
+	/// Atlas texel of the top left corner of the tile.
+	pub x: u32,
+	pub y: u32,
+	/// Size of each face, in texels.
+	pub resolution: u32,
+}
+
+impl ShadowTile
+{
+	/// Returns the area of the whole tile in the atlas.
+	fn get_rect(&self) -> vk::Rect2D
+	{
+		return vk::Rect2D {
+			offset: vk::Offset2D {
+				x: self.x as i32,
+				y: self.y as i32,
+			},
+			extent: vk::Extent2D {
+				width: 3 * self.resolution,
+				height: 2 * self.resolution,
+			},
+		};
+	}
+
+	/// Returns the area of a face in the atlas.
+	fn get_face_rect(&self, face: u32) -> vk::Rect2D
+	{
+		return vk::Rect2D {
+			offset: vk::Offset2D {
+				x: (self.x + face % 3 * self.resolution) as i32,
+				y: (self.y + face / 3 * self.resolution) as i32,
+			},
+			extent: vk::Extent2D {
+				width: self.resolution,
+				height: self.resolution,
+			},
+		};
+	}
+}
+
+/// The share of the shadow budget given to a light.
+struct ShadowAllocation
+{
+	tile: ShadowTile,
+	// Frames between updates, more for less important lights
+	interval: u64,
+	// Frame the tile was last rendered in, None until it has been rendered once since it was placed
+	rendered_frame: Option<u64>,
+}
+
+/// Returns the largest power of two not above n, which must not be 0.
+fn floor_power_of_two(n: u32) -> u32
+{
+	return 1 << (31 - n.leading_zeros());
+}
+
+/// Renders omnidirectional shadows of point lights into a shared shadow atlas, one cube map face at a time.
+///
+/// A shadow budget keeps the cost bounded as lights are added: each frame, the lights are given a tile of the atlas
+/// sized by their importance, the part of the screen they cover seen from the camera, so distant and small lights get
+/// low resolution shadows. Less important lights are also updated less often, and only a few tiles are rendered each
+/// frame, the most overdue first.
+pub struct ShadowPass
+{
+	renderpass: vk::RenderPass,
+	pub pipeline_layout: vk::PipelineLayout,
+	// For meshes with full and packed vertices, indexed by VertexFormat
+	pipelines: [vk::Pipeline; 2],
+	bound_vertex_format: Cell<Option<VertexFormat>>,
+	commandbuffer: vk::CommandBuffer,
+	atlas: Texture,
+	framebuffer: vk::Framebuffer,
+	// Indexed by light, None for lights without shadows
+	allocations: Vec<Option<ShadowAllocation>>,
+	frame: u64,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl ShadowPass
+{
+	/// Creates the depth only renderpass, keeping the tiles that are not rendered. Leaves the atlas ready to be
+	/// sampled.
+	fn create_renderpass(rs: &RenderState) -> vk::RenderPass
+	{
+		let renderpass_attachments = [vk::AttachmentDescription {
+			format: SHADOW_FORMAT,
+			flags: vk::AttachmentDescriptionFlags::empty(),
+			samples: vk::SampleCountFlags::TYPE_1,
+			load_op: vk::AttachmentLoadOp::LOAD,
+			store_op: vk::AttachmentStoreOp::STORE,
+			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		}];
+		let depth_attachment_ref = vk::AttachmentReference {
+			attachment: 0,
+			layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+		};
+		let subpass = vk::SubpassDescription {
+			p_depth_stencil_attachment: &depth_attachment_ref,
+			pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+			..Default::default()
+		};
+		// Wait for the previous frames to finish rendering and sampling the atlas, and make the new tiles visible to
+		// the main pass and the volumetric fog
+		let dependencies = [
+			vk::SubpassDependency {
+				src_subpass: vk::SUBPASS_EXTERNAL,
+				dst_subpass: 0,
+				src_stage_mask: vk::PipelineStageFlags::LATE_FRAGMENT_TESTS |
+					vk::PipelineStageFlags::FRAGMENT_SHADER |
+					vk::PipelineStageFlags::COMPUTE_SHADER,
+				dst_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+					vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+				src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				dst_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+					vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				dependency_flags: vk::DependencyFlags::empty(),
+			},
+			vk::SubpassDependency {
+				src_subpass: 0,
+				dst_subpass: vk::SUBPASS_EXTERNAL,
+				src_stage_mask: vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+				dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+				src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				dst_access_mask: vk::AccessFlags::SHADER_READ,
+				dependency_flags: vk::DependencyFlags::empty(),
+			},
+		];
+		let renderpass_create_info = vk::RenderPassCreateInfo {
+			s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
+			attachment_count: renderpass_attachments.len() as u32,
+			p_attachments: renderpass_attachments.as_ptr(),
+			subpass_count: 1,
+			p_subpasses: &subpass,
+			dependency_count: dependencies.len() as u32,
+			p_dependencies: dependencies.as_ptr(),
+			..Default::default()
+		};
+		let renderpass;
+		unsafe {
+			renderpass = rs.device.create_render_pass(&renderpass_create_info, None).unwrap();
+		}
+		rs.register_pass(PASS_NAME, &renderpass_attachments);
+
+		renderpass
+	}
+
+	/// Creates the pipelines for meshes with full and packed vertices, reading only the positions.
+	fn create_pipelines(rs: &RenderState, renderpass: vk::RenderPass) -> (vk::PipelineLayout, Vec<vk::Pipeline>)
+	{
+		// The model-view-projection and model-light matrices
+		let matrices_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::VERTEX,
+			size: 2 * size_of::<Matrix4<f32>>() as u32,
+			offset: 0,
+		};
+		let layout_create_info = vk::PipelineLayoutCreateInfo {
+			s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &matrices_push_constant,
+			..Default::default()
+		};
+		let pipeline_layout;
+		unsafe {
+			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
+		}
+
+		let vertex_shader_module = rs.load_shader("shaders/shadow_vert.spv", &[]);
+		let fragment_shader_module = rs.load_shader("shaders/shadow_frag.spv", &[]);
+
+		let shader_entry_name = CString::new("main").unwrap();
+		let shader_stage_create_infos = [
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: vertex_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::VERTEX,
+				..Default::default()
+			},
+			vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: fragment_shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::FRAGMENT,
+				..Default::default()
+			},
+		];
+
+		// Pipelines for packed vertices the device can't read are never bound
+		let vertex_formats = [VertexFormat::Full, rs.get_packed_vertex_format()];
+		let vertex_input_binding_descriptions: Vec<vk::VertexInputBindingDescription> = vertex_formats
+			.iter()
+			.map(|format| vk::VertexInputBindingDescription {
+				binding: 0,
+				stride: format.get_stride(),
+				input_rate: vk::VertexInputRate::VERTEX,
+			})
+			.collect();
+		// Only the position is read
+		let vertex_input_attribute_descriptions: Vec<vk::VertexInputAttributeDescription> =
+			vertex_formats.iter().map(|format| format.get_attribute_descriptions()[0]).collect();
+		let vertex_input_state_infos: Vec<vk::PipelineVertexInputStateCreateInfo> = vertex_input_binding_descriptions
+			.iter()
+			.zip(vertex_input_attribute_descriptions.iter())
+			.map(|(binding, attribute)| vk::PipelineVertexInputStateCreateInfo {
+				s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
+				vertex_attribute_description_count: 1,
+				p_vertex_attribute_descriptions: attribute,
+				vertex_binding_description_count: 1,
+				p_vertex_binding_descriptions: binding,
+				..Default::default()
+			})
+			.collect();
+		let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_INPUT_ASSEMBLY_STATE_CREATE_INFO,
+			topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+			..Default::default()
+		};
+		// Viewport and scissor are dynamic, as each face has its own area of the atlas
+		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_VIEWPORT_STATE_CREATE_INFO,
+			scissor_count: 1,
+			viewport_count: 1,
+			..Default::default()
+		};
+		// Not all meshes are closed, so both sides cast shadows
+		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
+			cull_mode: vk::CullModeFlags::NONE,
+			front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+			line_width: 1.0,
+			polygon_mode: vk::PolygonMode::FILL,
+			..Default::default()
+		};
+		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
+			rasterization_samples: vk::SampleCountFlags::TYPE_1,
+			..Default::default()
+		};
+		let noop_stencil_state = vk::StencilOpState {
+			fail_op: vk::StencilOp::KEEP,
+			pass_op: vk::StencilOp::KEEP,
+			depth_fail_op: vk::StencilOp::KEEP,
+			compare_op: vk::CompareOp::ALWAYS,
+			..Default::default()
+		};
+		let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DEPTH_STENCIL_STATE_CREATE_INFO,
+			depth_test_enable: 1,
+			depth_write_enable: 1,
+			depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+			front: noop_stencil_state,
+			back: noop_stencil_state,
+			max_depth_bounds: 1.0,
+			min_depth_bounds: 0.0,
+			..Default::default()
+		};
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
+			..Default::default()
+		};
+		let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
+			s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
+			dynamic_state_count: dynamic_state.len() as u32,
+			p_dynamic_states: dynamic_state.as_ptr(),
+			..Default::default()
+		};
+		let pipeline_infos: Vec<vk::GraphicsPipelineCreateInfo> = vertex_input_state_infos
+			.iter()
+			.map(|vertex_input_state_info| vk::GraphicsPipelineCreateInfo {
+				s_type: vk::StructureType::GRAPHICS_PIPELINE_CREATE_INFO,
+				stage_count: shader_stage_create_infos.len() as u32,
+				p_stages: shader_stage_create_infos.as_ptr(),
+				p_vertex_input_state: vertex_input_state_info,
+				p_input_assembly_state: &vertex_input_assembly_state_info,
+				p_viewport_state: &viewport_state_info,
+				p_rasterization_state: &rasterization_info,
+				p_multisample_state: &multisample_state_info,
+				p_depth_stencil_state: &depth_state_info,
+				p_color_blend_state: &color_blend_state,
+				p_dynamic_state: &dynamic_state_info,
+				layout: pipeline_layout,
+				render_pass: renderpass,
+				..Default::default()
+			})
+			.collect();
+		let graphics_pipelines;
+		unsafe {
+			graphics_pipelines = rs
+				.device
+				.create_graphics_pipelines(rs.pipeline_cache, &pipeline_infos, None)
+				.expect("Unable to create shadow pipeline");
+
+			rs.device.destroy_shader_module(fragment_shader_module, None);
+			rs.device.destroy_shader_module(vertex_shader_module, None);
+		}
+		track_create(ObjectKind::Pipeline, graphics_pipelines[0], "shadows");
+		track_create(ObjectKind::Pipeline, graphics_pipelines[1], "shadows packed");
+
+		(pipeline_layout, graphics_pipelines)
+	}
+
+	pub fn new(rs: &RenderState) -> ShadowPass
+	{
+		let renderpass = ShadowPass::create_renderpass(rs);
+		let (pipeline_layout, pipelines) = ShadowPass::create_pipelines(rs, renderpass);
+
+		let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
+			p_next: ptr::null(),
+			command_buffer_count: 1,
+			command_pool: rs.commandpool,
+			level: vk::CommandBufferLevel::PRIMARY,
+		};
+		let commandbuffers;
+		unsafe {
+			commandbuffers = rs.device.allocate_command_buffers(&command_buffer_allocate_info).unwrap();
+		}
+
+		// Sampled before anything is rendered to it, and tiles are only cleared when rendered
+		let extent = vk::Extent2D {
+			width: SHADOW_ATLAS_SIZE,
+			height: SHADOW_ATLAS_SIZE,
+		};
+		let atlas = rs.create_texture(
+			vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			},
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			SHADOW_FORMAT,
+			vk::ImageAspectFlags::DEPTH,
+			vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			None,
+		);
+		rs.register_image(atlas.image, "shadow atlas", extent, SHADOW_FORMAT);
+		let framebuffer_create_info = vk::FramebufferCreateInfo {
+			s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+			render_pass: renderpass,
+			attachment_count: 1,
+			p_attachments: &atlas.view,
+			width: extent.width,
+			height: extent.height,
+			layers: 1,
+			..Default::default()
+		};
+		let framebuffer;
+		unsafe {
+			framebuffer = rs.device.create_framebuffer(&framebuffer_create_info, None).unwrap();
+		}
+
+		ShadowPass {
+			renderpass: renderpass,
+			pipeline_layout: pipeline_layout,
+			pipelines: [pipelines[0], pipelines[1]],
+			bound_vertex_format: Cell::new(None),
+			commandbuffer: commandbuffers[0],
+			atlas: atlas,
+			framebuffer: framebuffer,
+			allocations: Vec::new(),
+			frame: 0,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Returns how important the shadows of a light are, from 0 to 1: the part of the screen height its sphere covers,
+	/// seen from the eye with the given vertical field of view. Falls off with distance, and is 1 inside the sphere.
+	fn get_importance(light: &PointLight, eye: Point3<f32>, fov: Rad<f32>) -> f32
+	{
+		let distance = light.position.distance(eye);
+		if distance <= light.radius
+		{
+			return 1.0;
+		}
+		let angular_radius = (light.radius / distance).asin();
+		return (angular_radius / (0.5 * fov.0)).min(1.0);
+	}
+
+	/// Returns the face resolution a light of the given importance asks for, a power of two. Lights keep their current
+	/// resolution until their importance changes enough, so it does not flip back and forth.
+	fn get_resolution(light: &PointLight, importance: f32, current: Option<u32>) -> u32
+	{
+		// A tile is three faces wide
+		let largest = floor_power_of_two(light.shadow_resolution.min(SHADOW_ATLAS_SIZE / 3)).max(MIN_SHADOW_RESOLUTION);
+		let wanted = light.shadow_resolution as f32 * importance;
+		if let Some(current) = current
+		{
+			if current <= largest && wanted >= RESOLUTION_HYSTERESIS * current as f32 && wanted < 2.0 * current as f32
+			{
+				return current;
+			}
+		}
+		return floor_power_of_two(wanted.max(1.0) as u32).max(MIN_SHADOW_RESOLUTION).min(largest);
+	}
+
+	/// Places tiles of the given face resolutions, as (light, resolution), in rows across the atlas, largest first.
+	/// Returns None if they do not all fit.
+	fn pack(resolutions: &[(usize, u32)]) -> Option<Vec<(usize, ShadowTile)>>
+	{
+		let mut sorted = resolutions.to_vec();
+		sorted.sort_by_key(|&(_, resolution)| std::cmp::Reverse(resolution));
+		let mut tiles = Vec::with_capacity(sorted.len());
+		let (mut x, mut y, mut row_height) = (0, 0, 0);
+		for (light, resolution) in sorted
+		{
+			if x + 3 * resolution > SHADOW_ATLAS_SIZE
+			{
+				x = 0;
+				y += row_height;
+				row_height = 0;
+			}
+			if y + 2 * resolution > SHADOW_ATLAS_SIZE
+			{
+				return None;
+			}
+			tiles.push((
+				light,
+				ShadowTile {
+					x: x,
+					y: y,
+					resolution: resolution,
+				},
+			));
+			x += 3 * resolution;
+			row_height = row_height.max(2 * resolution);
+		}
+		return Some(tiles);
+	}
+
+	/// Shares the atlas between the lights with shadows, giving each a tile and an update interval from its
+	/// importance. When the tiles do not fit, the least important lights get smaller ones first, and lose their
+	/// shadows if they do not fit at the smallest resolution. A light keeps its rendered shadows while its tile stays.
+	fn budget(&mut self, lights: &[PointLight], eye: Point3<f32>, fov: Rad<f32>)
+	{
+		// Only these light the scene
+		let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+		let importances: Vec<f32> = lights.iter().map(|light| ShadowPass::get_importance(light, eye, fov)).collect();
+		let mut order: Vec<usize> = (0..lights.len()).filter(|&idx| lights[idx].shadow_resolution > 0).collect();
+		order.sort_by(|&a, &b| importances[b].partial_cmp(&importances[a]).unwrap_or(Ordering::Equal));
+
+		// Most important first
+		let mut resolutions: Vec<(usize, u32)> = order
+			.iter()
+			.map(|&idx| {
+				let current = match self.allocations.get(idx)
+				{
+					Some(Some(allocation)) => Some(allocation.tile.resolution),
+					_ => None,
+				};
+				(idx, ShadowPass::get_resolution(&lights[idx], importances[idx], current))
+			})
+			.collect();
+		let tiles = loop
+		{
+			if let Some(tiles) = ShadowPass::pack(&resolutions)
+			{
+				break tiles;
+			}
+			match resolutions.iter_mut().rev().find(|(_, resolution)| *resolution > MIN_SHADOW_RESOLUTION)
+			{
+				Some((_, resolution)) => *resolution /= 2,
+				None =>
+				{
+					resolutions.pop();
+				}
+			}
+		};
+
+		let mut allocations: Vec<Option<ShadowAllocation>> = lights.iter().map(|_| None).collect();
+		for (idx, tile) in tiles
+		{
+			let interval = 1 + ((MAX_UPDATE_INTERVAL - 1) as f32 * (1.0 - importances[idx])).round() as u64;
+			// Tiles that moved or changed size are rendered again
+			let rendered_frame = match self.allocations.get(idx)
+			{
+				Some(Some(allocation)) if allocation.tile == tile => allocation.rendered_frame,
+				_ => None,
+			};
+			allocations[idx] = Some(ShadowAllocation {
+				tile: tile,
+				interval: interval,
+				rendered_frame: rendered_frame,
+			});
+		}
+		self.allocations = allocations;
+	}
+
+	/// Returns the lights as seen by the shaders. Lights only get their shadow tile once it has been rendered.
+	pub fn get_light_uniforms(&self, lights: &[PointLight]) -> Vec<LightUniforms>
+	{
+		return lights
+			.iter()
+			.enumerate()
+			.map(|(idx, light)| {
+				let tile = match self.allocations.get(idx)
+				{
+					Some(Some(allocation)) if allocation.rendered_frame.is_some() => Some(allocation.tile),
+					_ => None,
+				};
+				LightUniforms::new(light.position, light.radius, light.color, tile)
+			})
+			.collect();
+	}
+
+	/// Returns the view and sampler of the shadow atlas, see MainPass::set_shadow_atlas().
+	pub fn get_shadow_atlas(&self) -> (vk::ImageView, vk::Sampler)
+	{
+		return (self.atlas.view, self.atlas.sampler);
+	}
+
+	/// Binds the pipeline for meshes with the given vertex format, unless it is already bound.
+	pub fn bind_mesh_pipeline(&self, cmd_buf: vk::CommandBuffer, vertex_format: VertexFormat)
+	{
+		if self.bound_vertex_format.get() == Some(vertex_format)
+		{
+			return;
+		}
+		unsafe {
+			self.device.cmd_bind_pipeline(
+				cmd_buf,
+				vk::PipelineBindPoint::GRAPHICS,
+				self.pipelines[vertex_format as usize],
+			);
+		}
+		self.bound_vertex_format.set(Some(vertex_format));
+	}
+
+	/// Shares the shadow atlas between the lights as seen from the camera, with the given view matrix and vertical
+	/// field of view, then renders the tiles of at most max_updates lights. Lights without a rendered tile go first,
+	/// then the ones most overdue for an update.
+	///
+	/// The shadow casters are drawn by draw_casters, given the view projection matrix of a cube face and the world to
+	/// light space matrix, see Drawable::draw_shadow().
+	pub fn render<F>(
+		&mut self, rs: &RenderState, lights: &[PointLight], view_matrix: &Matrix4<f32>, fov: Rad<f32>,
+		max_updates: usize, mut draw_casters: F,
+	) where
+		F: FnMut(&ShadowPass, vk::CommandBuffer, &Matrix4<f32>, &Matrix4<f32>),
+	{
+		self.frame += 1;
+		let eye = match view_matrix.invert()
+		{
+			Some(camera_matrix) => Point3::from_vec(camera_matrix.w.truncate()),
+			None => Point3::origin(),
+		};
+		self.budget(lights, eye, fov);
+
+		// Budget the updates, by how many intervals they are overdue
+		let frame = self.frame;
+		let overdue = |allocation: &ShadowAllocation| match allocation.rendered_frame
+		{
+			Some(rendered_frame) => (frame - rendered_frame) as f32 / allocation.interval as f32,
+			None => f32::MAX,
+		};
+		let mut updates: Vec<usize> = (0..self.allocations.len())
+			.filter(|&idx| match &self.allocations[idx]
+			{
+				Some(allocation) => overdue(allocation) >= 1.0,
+				None => false,
+			})
+			.collect();
+		updates.sort_by(|&a, &b| {
+			let overdue_a = overdue(self.allocations[a].as_ref().unwrap());
+			let overdue_b = overdue(self.allocations[b].as_ref().unwrap());
+			overdue_b.partial_cmp(&overdue_a).unwrap_or(Ordering::Equal)
+		});
+		updates.truncate(max_updates);
+		if updates.is_empty()
+		{
+			return;
+		}
+
+		let cmd_buf = self.commandbuffer;
+		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+			flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+		}
+		rs.trace_pass(cmd_buf, PASS_NAME, &[], &[self.atlas.image]);
+		self.bound_vertex_format.set(None);
+
+		// Only the updated tiles are rendered, the rest of the atlas is kept
+		let tiles: Vec<ShadowTile> = updates.iter().map(|&idx| self.allocations[idx].as_ref().unwrap().tile).collect();
+		let rects: Vec<vk::Rect2D> = tiles.iter().map(ShadowTile::get_rect).collect();
+		let min_x = rects.iter().map(|rect| rect.offset.x).min().unwrap();
+		let min_y = rects.iter().map(|rect| rect.offset.y).min().unwrap();
+		let max_x = rects.iter().map(|rect| rect.offset.x + rect.extent.width as i32).max().unwrap();
+		let max_y = rects.iter().map(|rect| rect.offset.y + rect.extent.height as i32).max().unwrap();
+		let render_pass_begin_info = vk::RenderPassBeginInfo {
+			s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
+			p_next: ptr::null(),
+			render_pass: self.renderpass,
+			framebuffer: self.framebuffer,
+			render_area: vk::Rect2D {
+				offset: vk::Offset2D {
+					x: min_x,
+					y: min_y,
+				},
+				extent: vk::Extent2D {
+					width: (max_x - min_x) as u32,
+					height: (max_y - min_y) as u32,
+				},
+			},
+			clear_value_count: 0,
+			p_clear_values: ptr::null(),
+		};
+		let clear_attachment = vk::ClearAttachment {
+			aspect_mask: vk::ImageAspectFlags::DEPTH,
+			color_attachment: 0,
+			clear_value: vk::ClearValue {
+				depth_stencil: vk::ClearDepthStencilValue {
+					depth: 1.0,
+					stencil: 0,
+				},
+			},
+		};
+		unsafe {
+			rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::INLINE);
+		}
+		for (&idx, (tile, &rect)) in updates.iter().zip(tiles.iter().zip(rects.iter()))
+		{
+			let light = &lights[idx];
+			let (view_matrices, projection_matrix) = get_cube_face_matrices(light.position, SHADOW_NEAR, light.radius);
+			let light_matrix =
+				Matrix4::from_scale(1.0 / light.radius) * Matrix4::from_translation(-light.position.to_vec());
+			let clear_rect = vk::ClearRect {
+				rect: rect,
+				base_array_layer: 0,
+				layer_count: 1,
+			};
+			unsafe {
+				rs.device.cmd_clear_attachments(cmd_buf, &[clear_attachment], &[clear_rect]);
+			}
+			for (face, view_matrix) in view_matrices.iter().enumerate()
+			{
+				let face_rect = tile.get_face_rect(face as u32);
+				let viewport = vk::Viewport {
+					x: face_rect.offset.x as f32,
+					y: face_rect.offset.y as f32,
+					width: tile.resolution as f32,
+					height: tile.resolution as f32,
+					min_depth: 0.0,
+					max_depth: 1.0,
+				};
+				unsafe {
+					rs.device.cmd_set_viewport(cmd_buf, 0, &[viewport]);
+					rs.device.cmd_set_scissor(cmd_buf, 0, &[face_rect]);
+				}
+				draw_casters(self, cmd_buf, &(projection_matrix * view_matrix), &light_matrix);
+			}
+		}
+
+		unsafe {
+			rs.device.cmd_end_render_pass(cmd_buf);
+		}
+		rs.end_pass(cmd_buf);
+		unsafe {
+			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+		}
+		let submit_info = vk::SubmitInfo {
+			s_type: vk::StructureType::SUBMIT_INFO,
+			command_buffer_count: 1,
+			p_command_buffers: &cmd_buf,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.queue_submit(rs.graphics_queue, &[submit_info], vk::Fence::null()).expect("queue submit failed.");
+		}
+
+		for &idx in updates.iter()
+		{
+			self.allocations[idx].as_mut().unwrap().rendered_frame = Some(self.frame);
+		}
+	}
+}
+
+impl Drop for ShadowPass
+{
+	fn drop(&mut self)
+	{
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+
+		for &pipeline in self.pipelines.iter()
+		{
+			track_destroy(ObjectKind::Pipeline, pipeline);
+		}
+		unsafe {
+			// Always wait for device idle
+			self.device.device_wait_idle().unwrap();
+
+			// The atlas is released when dropped
+			self.device.destroy_framebuffer(self.framebuffer, None);
+			for &pipeline in self.pipelines.iter()
+			{
+				self.device.destroy_pipeline(pipeline, None);
+			}
+			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+			self.device.destroy_render_pass(self.renderpass, None);
+		}
+	}
+}
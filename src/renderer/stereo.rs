@@ -0,0 +1,129 @@
+use crate::core::Console;
+use cgmath::{Matrix4, Vector3, Vector4};
+
+/// How the scene is rendered for the two eyes.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StereoMode
+{
+	/// A single view from the camera.
+	Off,
+	/// The left eye in the left half of the render image, and the right eye in the right half.
+	SideBySide,
+	/// One eye on the whole render image, alternating each frame.
+	FrameSequential,
+}
+
+impl StereoMode
+{
+	pub fn from_name(name: &str) -> Option<StereoMode>
+	{
+		match name
+		{
+			"off" => Some(StereoMode::Off),
+			"sbs" => Some(StereoMode::SideBySide),
+			"sequential" => Some(StereoMode::FrameSequential),
+			_ => None,
+		}
+	}
+}
+
+/// A view to render this frame, see Stereo::get_eyes().
+pub struct EyeView
+{
+	pub view_matrix: Matrix4<f32>,
+	pub projection_matrix: Matrix4<f32>,
+	/// Horizontal slice of the render image covered by the view, as the start and width in fractions of its width.
+	pub region: (f32, f32),
+}
+
+/// Experimental stereo rendering, as groundwork for VR. Each eye is offset from the camera by half the IPD, with an
+/// off-axis projection so that both views line up at the convergence distance.
+pub struct Stereo
+{
+	mode: StereoMode,
+	// Eye rendered in the current frame, in frame sequential mode
+	right_eye: bool,
+}
+
+impl Stereo
+{
+	pub fn new(console: &mut Console) -> Stereo
+	{
+		console.register_float("stereo_ipd", 0.064, "Distance between the eyes in stereo rendering, in meters");
+		console.register_float(
+			"stereo_convergence",
+			10.0,
+			"Distance at which the eyes' views line up in stereo rendering, in meters, 0 for parallel views",
+		);
+
+		Stereo {
+			mode: StereoMode::Off,
+			right_eye: false,
+		}
+	}
+
+	pub fn set_mode(&mut self, mode: StereoMode)
+	{
+		self.mode = mode;
+	}
+
+	pub fn get_mode(&self) -> StereoMode
+	{
+		return self.mode;
+	}
+
+	/// Returns the views to render this frame, given the camera's view matrix, the aspect ratio of the render image,
+	/// and a function returning the projection matrix for an aspect ratio. Side by side, each eye gets half the aspect
+	/// ratio.
+	pub fn get_eyes<F>(
+		&mut self, console: &Console, view_matrix: Matrix4<f32>, aspect_ratio: f32, projection: F,
+	) -> Vec<EyeView>
+	where
+		F: Fn(f32) -> Matrix4<f32>,
+	{
+		let half_ipd = 0.5 * console.get_float("stereo_ipd").max(0.0);
+		let convergence = console.get_float("stereo_convergence").max(0.0);
+		let eye = |offset: f32, aspect_scale: f32, region: (f32, f32)| {
+			let projection_matrix = projection(aspect_ratio * aspect_scale);
+			// Shifts the image sideways in clip space, so points at the convergence distance end up where they are
+			// for the camera
+			let shift = if convergence > 0.0
+			{
+				offset * projection_matrix.x.x / convergence
+			}
+			else
+			{
+				0.0
+			};
+			let mut off_axis = Matrix4::from_scale(1.0);
+			off_axis.w = Vector4::new(shift, 0.0, 0.0, 1.0);
+			EyeView {
+				view_matrix: Matrix4::from_translation(Vector3::new(-offset, 0.0, 0.0)) * view_matrix,
+				projection_matrix: off_axis * projection_matrix,
+				region: region,
+			}
+		};
+
+		match self.mode
+		{
+			StereoMode::Off => return vec![eye(0.0, 1.0, (0.0, 1.0))],
+			StereoMode::SideBySide =>
+			{
+				return vec![eye(-half_ipd, 0.5, (0.0, 0.5)), eye(half_ipd, 0.5, (0.5, 0.5))];
+			}
+			StereoMode::FrameSequential =>
+			{
+				self.right_eye = !self.right_eye;
+				let offset = if self.right_eye
+				{
+					half_ipd
+				}
+				else
+				{
+					-half_ipd
+				};
+				return vec![eye(offset, 1.0, (0.0, 1.0))];
+			}
+		}
+	}
+}
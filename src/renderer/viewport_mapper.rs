@@ -0,0 +1,122 @@
+use ash::vk;
+
+/// Displays at this DPI get a UI scale of 1.
+const REFERENCE_DPI: f32 = 96.0;
+
+/// Returns the UI scale suited to the DPI of the given display, in steps of a quarter. Displays at or below the
+/// reference DPI, and displays whose DPI is unknown, get a scale of 1.
+pub fn get_display_scale(video_subsystem: &sdl2::VideoSubsystem, display_index: i32) -> f32
+{
+	match video_subsystem.display_dpi(display_index)
+	{
+		Ok((diagonal_dpi, _, _)) => return ((diagonal_dpi / REFERENCE_DPI * 4.0).round() / 4.0).max(1.0),
+		Err(e) =>
+		{
+			warning!("Could not get the DPI of display {}: {}", display_index, e);
+			return 1.0;
+		}
+	}
+}
+
+/// Converts positions between the coordinate systems of the main window.
+///
+/// - Window coordinates are what SDL reports for the mouse, which can differ from the surface size on high DPI
+///   displays.
+/// - UI coordinates are pixels of the presented surface divided by the UI scale, used by the overlay.
+/// - Render coordinates are pixels of the render target, which is scaled to fit the surface while keeping its aspect
+///   ratio, leaving black bars on the sides that do not fit.
+pub struct ViewportMapper
+{
+	// Surface pixels per window unit
+	surface_scale: (f32, f32),
+	// Surface pixels per UI unit
+	ui_scale: f32,
+	ui_size: (f32, f32),
+	render_size: (f32, f32),
+	// Where the render target ends up on the surface
+	image_area: vk::Rect2D,
+}
+
+impl ViewportMapper
+{
+	pub fn new(
+		window_size: (u32, u32), surface_extent: vk::Extent2D, render_extent: vk::Extent2D, ui_scale: f32,
+	) -> ViewportMapper
+	{
+		let surface_size = (surface_extent.width as f32, surface_extent.height as f32);
+		let render_size = (render_extent.width.max(1) as f32, render_extent.height.max(1) as f32);
+		let scale = (surface_size.0 / render_size.0).min(surface_size.1 / render_size.1);
+		let image_size = ((render_size.0 * scale).round() as u32, (render_size.1 * scale).round() as u32);
+		let image_area = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: (surface_extent.width.saturating_sub(image_size.0) / 2) as i32,
+				y: (surface_extent.height.saturating_sub(image_size.1) / 2) as i32,
+			},
+			extent: vk::Extent2D {
+				width: image_size.0.max(1),
+				height: image_size.1.max(1),
+			},
+		};
+
+		ViewportMapper {
+			surface_scale: (surface_size.0 / window_size.0.max(1) as f32, surface_size.1 / window_size.1.max(1) as f32),
+			ui_scale: ui_scale,
+			ui_size: (surface_size.0 / ui_scale, surface_size.1 / ui_scale),
+			render_size: render_size,
+			image_area: image_area,
+		}
+	}
+
+	/// Returns the area of the surface the render target is presented in.
+	pub fn get_image_area(&self) -> vk::Rect2D
+	{
+		return self.image_area;
+	}
+
+	/// Returns the size of the whole surface in UI units.
+	pub fn get_ui_size(&self) -> (f32, f32)
+	{
+		return self.ui_size;
+	}
+
+	/// Returns the center of the area the render target is presented in, in UI coordinates.
+	pub fn get_image_center_ui(&self) -> (f32, f32)
+	{
+		let area = self.image_area;
+		return (
+			(area.offset.x as f32 + 0.5 * area.extent.width as f32) / self.ui_scale,
+			(area.offset.y as f32 + 0.5 * area.extent.height as f32) / self.ui_scale,
+		);
+	}
+
+	fn window_to_surface(&self, position: (i32, i32)) -> (f32, f32)
+	{
+		return (position.0 as f32 * self.surface_scale.0, position.1 as f32 * self.surface_scale.1);
+	}
+
+	pub fn window_to_ui(&self, position: (i32, i32)) -> (f32, f32)
+	{
+		let (x, y) = self.window_to_surface(position);
+		return (x / self.ui_scale, y / self.ui_scale);
+	}
+
+	/// Returns None for positions on the black bars around the render target.
+	pub fn window_to_render(&self, position: (i32, i32)) -> Option<(f32, f32)>
+	{
+		let (x, y) = self.window_to_surface(position);
+		let u = (x - self.image_area.offset.x as f32) / self.image_area.extent.width as f32;
+		let v = (y - self.image_area.offset.y as f32) / self.image_area.extent.height as f32;
+		if u < 0.0 || u >= 1.0 || v < 0.0 || v >= 1.0
+		{
+			return None;
+		}
+		return Some((u * self.render_size.0, v * self.render_size.1));
+	}
+
+	/// Returns the position in Vulkan normalized device coordinates, with y pointing down, for picking.
+	pub fn window_to_ndc(&self, position: (i32, i32)) -> Option<(f32, f32)>
+	{
+		let (x, y) = self.window_to_render(position)?;
+		return Some((2.0 * x / self.render_size.0 - 1.0, 2.0 * y / self.render_size.1 - 1.0));
+	}
+}
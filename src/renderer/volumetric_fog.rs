@@ -0,0 +1,521 @@
+use crate::core::Console;
+use crate::renderer::mainpass::MAX_LIGHTS;
+use crate::renderer::{track_create, track_destroy, BufferHandle, LightUniforms, ObjectKind, RenderState, Texture};
+use ash::util::Align;
+use ash::version::DeviceV1_0;
+use ash::vk;
+use ash::Device;
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Vector3};
+use std::cell::Cell;
+use std::ffi::CString;
+use std::mem::{align_of, size_of};
+use std::rc::Rc;
+use std::{ptr, slice};
+
+// Must match the constants in volumetric_fog.comp
+const GROUP_SIZE: u32 = 8;
+const FROXEL_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// Width, height and slices of the froxel grid at each fog_quality
+const QUALITY_GRIDS: [(u32, u32, u32); 3] = [(64, 36, 32), (128, 72, 64), (192, 108, 96)];
+// The sun lights the fog fully once this much higher than the horizon, as the sine of its elevation over it
+const SUN_FADE_SLOPE: f32 = 5.0;
+
+/// Must match the FogBlock uniforms in volumetric_fog.comp (std140).
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FogUniforms
+{
+	inverse_view_projection: Matrix4<f32>,
+	// Distance to the far edge of the volume in w
+	camera_position: [f32; 4],
+	camera_front: [f32; 4],
+	// Brightness of the sun in w
+	sun_direction: [f32; 4],
+	density: f32,
+	height_falloff: f32,
+	anisotropy: f32,
+	light_count: u32,
+	lights: [LightUniforms; MAX_LIGHTS],
+}
+
+/// Parameters of a volumetric_fog.comp dispatch. Must match the FogParamsBlock push constants.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FogParams
+{
+	stage: u32,
+}
+
+/// Volumetric fog lit by the sun and the point lights, rendered into a froxel grid by a compute pass and composited
+/// when presenting.
+///
+/// The froxels are cells of the view frustum, sliced exponentially with depth. The light scattered in each froxel is
+/// injected first, using the shadow atlas of the point lights, then integrated front to back, so that each froxel holds
+/// the light scattered towards the eye and the transmittance up to it.
+pub struct VolumetricFog
+{
+	descriptor_pool: vk::DescriptorPool,
+	descriptor_set_layout: vk::DescriptorSetLayout,
+	descriptor_set: vk::DescriptorSet,
+	pipeline_layout: vk::PipelineLayout,
+	pipeline: vk::Pipeline,
+	commandbuffer: vk::CommandBuffer,
+	uniforms: BufferHandle,
+	// None while the fog is disabled
+	froxels: Option<Texture>,
+	quality: usize,
+	// Far edge of the volume, 0 while the fog is disabled
+	distance: f32,
+	// Bound until a shadow atlas is given
+	empty_shadow_atlas: Texture,
+	shadow_atlas_view: Cell<vk::ImageView>,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device>,
+}
+
+impl VolumetricFog
+{
+	pub fn new(rs: &RenderState, console: &mut Console) -> VolumetricFog
+	{
+		console.register_bool("fog_volumetric", false, "Volumetric fog lit by the sun and the point lights");
+		console.register_float("fog_quality", 1.0, "Resolution of the volumetric fog, 0 low, 1 medium and 2 high");
+		console.register_float("fog_density", 0.02, "Extinction of the volumetric fog at the ground, per meter");
+		console.register_float("fog_height_falloff", 0.15, "How quickly the volumetric fog thins out with height");
+		console.register_float(
+			"fog_anisotropy",
+			0.6,
+			"Forward scattering of the volumetric fog [-1, 1], higher gives brighter halos around lights",
+		);
+		console.register_float("fog_distance", 120.0, "Distance the volumetric fog reaches from the camera, in meters");
+
+		let descriptor_sizes = [
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::STORAGE_IMAGE,
+				descriptor_count: 1,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::UNIFORM_BUFFER,
+				descriptor_count: 1,
+			},
+			vk::DescriptorPoolSize {
+				ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+				descriptor_count: 1,
+			},
+		];
+		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+			pool_size_count: descriptor_sizes.len() as u32,
+			p_pool_sizes: descriptor_sizes.as_ptr(),
+			max_sets: 1,
+			..Default::default()
+		};
+		// Froxels, uniforms and shadow atlas
+		let descriptor_types = [
+			vk::DescriptorType::STORAGE_IMAGE,
+			vk::DescriptorType::UNIFORM_BUFFER,
+			vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+		];
+		let dsl_bindings: Vec<vk::DescriptorSetLayoutBinding> = descriptor_types
+			.iter()
+			.enumerate()
+			.map(|(binding, &descriptor_type)| vk::DescriptorSetLayoutBinding {
+				binding: binding as u32,
+				descriptor_type: descriptor_type,
+				descriptor_count: 1,
+				stage_flags: vk::ShaderStageFlags::COMPUTE,
+				p_immutable_samplers: ptr::null(),
+			})
+			.collect();
+		let dsl_info = vk::DescriptorSetLayoutCreateInfo {
+			s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+			binding_count: dsl_bindings.len() as u32,
+			p_bindings: dsl_bindings.as_ptr(),
+			..Default::default()
+		};
+		let params_push_constant = vk::PushConstantRange {
+			stage_flags: vk::ShaderStageFlags::COMPUTE,
+			size: size_of::<FogParams>() as u32,
+			offset: 0,
+		};
+
+		let descriptor_pool;
+		let descriptor_set_layout;
+		let descriptor_set;
+		let pipeline_layout;
+		unsafe {
+			descriptor_pool = rs.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
+			descriptor_set_layout = rs.device.create_descriptor_set_layout(&dsl_info, None).unwrap();
+			let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+				s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+				descriptor_pool: descriptor_pool,
+				descriptor_set_count: 1,
+				p_set_layouts: &descriptor_set_layout,
+				..Default::default()
+			};
+			descriptor_set = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap()[0];
+			let layout_create_info = vk::PipelineLayoutCreateInfo {
+				s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+				set_layout_count: 1,
+				p_set_layouts: &descriptor_set_layout,
+				push_constant_range_count: 1,
+				p_push_constant_ranges: &params_push_constant,
+				..Default::default()
+			};
+			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
+		}
+
+		let shader_module =
+			rs.load_shader("shaders/volumetric_fog_comp.spv", &[("MAX_LIGHTS", format!("{}u", MAX_LIGHTS))]);
+		let shader_entry_name = CString::new("main").unwrap();
+		let pipeline_info = vk::ComputePipelineCreateInfo {
+			s_type: vk::StructureType::COMPUTE_PIPELINE_CREATE_INFO,
+			stage: vk::PipelineShaderStageCreateInfo {
+				s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+				module: shader_module,
+				p_name: shader_entry_name.as_ptr(),
+				stage: vk::ShaderStageFlags::COMPUTE,
+				..Default::default()
+			},
+			layout: pipeline_layout,
+			..Default::default()
+		};
+		let pipeline;
+		unsafe {
+			pipeline = rs
+				.device
+				.create_compute_pipelines(rs.pipeline_cache, &[pipeline_info], None)
+				.expect("Unable to create volumetric fog pipeline")[0];
+			rs.device.destroy_shader_module(shader_module, None);
+		}
+		track_create(ObjectKind::Pipeline, pipeline, "volumetric fog");
+
+		let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
+			command_buffer_count: 1,
+			command_pool: rs.commandpool,
+			level: vk::CommandBufferLevel::PRIMARY,
+			..Default::default()
+		};
+		let commandbuffer;
+		unsafe {
+			commandbuffer = rs.device.allocate_command_buffers(&command_buffer_allocate_info).unwrap()[0];
+		}
+
+		let uniforms = rs.create_buffer(
+			vk::BufferUsageFlags::UNIFORM_BUFFER,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			size_of::<FogUniforms>() as u64,
+		);
+		let uniforms_descriptor = vk::DescriptorBufferInfo {
+			buffer: uniforms.get(),
+			offset: 0,
+			range: size_of::<FogUniforms>() as u64,
+		};
+		let write_desc_set = vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: descriptor_set,
+			dst_binding: 1,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+			p_buffer_info: &uniforms_descriptor,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.update_descriptor_sets(&[write_desc_set], &[]);
+		}
+		let empty_shadow_atlas = rs.create_texture(
+			vk::Extent3D {
+				width: 1,
+				height: 1,
+				depth: 1,
+			},
+			vk::ImageType::TYPE_2D,
+			vk::ImageViewType::TYPE_2D,
+			vk::Format::D32_SFLOAT,
+			vk::ImageAspectFlags::DEPTH,
+			vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			vk::PipelineStageFlags::COMPUTE_SHADER,
+			None,
+		);
+
+		let fog = VolumetricFog {
+			descriptor_pool: descriptor_pool,
+			descriptor_set_layout: descriptor_set_layout,
+			descriptor_set: descriptor_set,
+			pipeline_layout: pipeline_layout,
+			pipeline: pipeline,
+			commandbuffer: commandbuffer,
+			uniforms: uniforms,
+			froxels: None,
+			quality: 0,
+			distance: 0.0,
+			empty_shadow_atlas: empty_shadow_atlas,
+			shadow_atlas_view: Cell::new(vk::ImageView::null()),
+			device: Rc::clone(&rs.device),
+		};
+		fog.set_shadow_atlas(rs, None);
+		return fog;
+	}
+
+	/// Returns the froxels to composite over the image, if the fog is enabled.
+	pub fn get_output(&self) -> Option<&Texture>
+	{
+		return self.froxels.as_ref();
+	}
+
+	/// Returns the distance to the far edge of the fog volume, 0 while the fog is disabled.
+	pub fn get_distance(&self) -> f32
+	{
+		return self.distance;
+	}
+
+	/// Binds the shadow atlas of the point lights, as a (view, sampler) pair, see MainPass::set_shadow_atlas().
+	pub fn set_shadow_atlas(&self, rs: &RenderState, shadow_atlas: Option<(vk::ImageView, vk::Sampler)>)
+	{
+		let (view, sampler) = shadow_atlas.unwrap_or((self.empty_shadow_atlas.view, self.empty_shadow_atlas.sampler));
+		// The descriptor is only rewritten when the atlas was created or replaced
+		if self.shadow_atlas_view.get() == view
+		{
+			return;
+		}
+		self.shadow_atlas_view.set(view);
+
+		let image_descriptor = vk::DescriptorImageInfo {
+			image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			image_view: view,
+			sampler: sampler,
+		};
+		let write_desc_set = vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: self.descriptor_set,
+			dst_binding: 2,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			p_image_info: &image_descriptor,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.update_descriptor_sets(&[write_desc_set], &[]);
+		}
+	}
+
+	/// Creates the froxel grid for the quality, replacing the current one.
+	fn create_froxels(&mut self, rs: &RenderState, quality: usize)
+	{
+		let (width, height, depth) = QUALITY_GRIDS[quality];
+		let froxels = rs.create_texture(
+			vk::Extent3D {
+				width: width,
+				height: height,
+				depth: depth,
+			},
+			vk::ImageType::TYPE_3D,
+			vk::ImageViewType::TYPE_3D,
+			FROXEL_FORMAT,
+			vk::ImageAspectFlags::COLOR,
+			vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			None,
+		);
+		let extent = vk::Extent2D {
+			width: width,
+			height: height * depth,
+		};
+		rs.register_image(froxels.image, "froxels", extent, FROXEL_FORMAT);
+		let image_descriptor = vk::DescriptorImageInfo {
+			image_layout: vk::ImageLayout::GENERAL,
+			image_view: froxels.view,
+			sampler: vk::Sampler::null(),
+		};
+		let write_desc_set = vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+			dst_set: self.descriptor_set,
+			dst_binding: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+			p_image_info: &image_descriptor,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.update_descriptor_sets(&[write_desc_set], &[]);
+		}
+		self.froxels = Some(froxels);
+		self.quality = quality;
+	}
+
+	/// Renders the fog seen with the given matrices, lit by the sun in the given direction and by the lights. Call
+	/// after the shadow pass, with the shadow atlas bound by set_shadow_atlas().
+	pub fn render(
+		&mut self, rs: &RenderState, console: &Console, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>,
+		sun_direction: Vector3<f32>, lights: &[LightUniforms],
+	)
+	{
+		if !console.get_bool("fog_volumetric")
+		{
+			self.froxels = None;
+			self.distance = 0.0;
+			return;
+		}
+		let quality = (console.get_float("fog_quality").max(0.0) as usize).min(QUALITY_GRIDS.len() - 1);
+		if self.froxels.is_none() || quality != self.quality
+		{
+			self.create_froxels(rs, quality);
+		}
+		self.distance = console.get_float("fog_distance").max(1.0);
+
+		let inverse_view = view_matrix.invert().unwrap_or_else(Matrix4::identity);
+		let camera_position = inverse_view.w.truncate();
+		// The camera looks down -z
+		let camera_front = -inverse_view.z.truncate().normalize();
+		// Fading in as the sun rises over the horizon
+		let sun_brightness = (SUN_FADE_SLOPE * sun_direction.y).max(0.0).min(1.0);
+		let light_count = lights.len().min(MAX_LIGHTS);
+		let mut light_uniforms = [LightUniforms::default(); MAX_LIGHTS];
+		light_uniforms[..light_count].copy_from_slice(&lights[..light_count]);
+		let uniforms = FogUniforms {
+			inverse_view_projection: (projection_matrix * view_matrix).invert().unwrap_or_else(Matrix4::identity),
+			camera_position: camera_position.extend(self.distance).into(),
+			camera_front: camera_front.extend(0.0).into(),
+			sun_direction: sun_direction.extend(sun_brightness).into(),
+			density: console.get_float("fog_density").max(0.0),
+			height_falloff: console.get_float("fog_height_falloff").max(0.0),
+			anisotropy: console.get_float("fog_anisotropy").max(-0.95).min(0.95),
+			light_count: light_count as u32,
+			lights: light_uniforms,
+		};
+		let uniforms_size = size_of::<FogUniforms>() as u64;
+		unsafe {
+			let mem_ptr = self.uniforms.get_mapped();
+			let mut mem_align = Align::new(mem_ptr, align_of::<FogUniforms>() as u64, uniforms_size);
+			mem_align.copy_from_slice(&[uniforms]);
+		}
+
+		let cmd_buf = self.commandbuffer;
+		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+			s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+			flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+		}
+		let (width, height, depth) = QUALITY_GRIDS[self.quality];
+		let froxels = self.froxels.as_mut().unwrap();
+		rs.trace_pass(cmd_buf, "volumetric fog", &[], &[froxels.image]);
+		rs.transition_texture(
+			froxels,
+			vk::AccessFlags::SHADER_WRITE,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::COMPUTE_SHADER,
+			Some(cmd_buf),
+		);
+
+		// Between the shadow atlas being rendered and sampled, and the froxels being injected and integrated
+		let memory_barrier = |src_access: vk::AccessFlags, src_stage: vk::PipelineStageFlags| unsafe {
+			let barrier = vk::MemoryBarrier {
+				s_type: vk::StructureType::MEMORY_BARRIER,
+				src_access_mask: src_access,
+				dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+				..Default::default()
+			};
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				src_stage,
+				vk::PipelineStageFlags::COMPUTE_SHADER,
+				vk::DependencyFlags::empty(),
+				&[barrier],
+				&[],
+				&[],
+			);
+		};
+		unsafe {
+			memory_barrier(
+				vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+			);
+			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+			rs.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::COMPUTE,
+				self.pipeline_layout,
+				0,
+				&[self.descriptor_set],
+				&[],
+			);
+			// Injection covers every froxel, integration every column
+			for &(stage, slices) in [(0, depth), (1, 1)].iter()
+			{
+				if stage == 1
+				{
+					memory_barrier(vk::AccessFlags::SHADER_WRITE, vk::PipelineStageFlags::COMPUTE_SHADER);
+				}
+				let params = FogParams {
+					stage: stage,
+				};
+				let params_bytes =
+					slice::from_raw_parts(&params as *const FogParams as *const u8, size_of::<FogParams>());
+				rs.device.cmd_push_constants(
+					cmd_buf,
+					self.pipeline_layout,
+					vk::ShaderStageFlags::COMPUTE,
+					0,
+					params_bytes,
+				);
+				rs.device.cmd_dispatch(
+					cmd_buf,
+					(width + GROUP_SIZE - 1) / GROUP_SIZE,
+					(height + GROUP_SIZE - 1) / GROUP_SIZE,
+					slices,
+				);
+			}
+		}
+
+		// Sampled when presenting
+		rs.transition_texture(
+			froxels,
+			vk::AccessFlags::SHADER_READ,
+			vk::ImageLayout::GENERAL,
+			vk::PipelineStageFlags::FRAGMENT_SHADER,
+			Some(cmd_buf),
+		);
+		rs.end_pass(cmd_buf);
+		unsafe {
+			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+		}
+		let submit_info = vk::SubmitInfo {
+			s_type: vk::StructureType::SUBMIT_INFO,
+			command_buffer_count: 1,
+			p_command_buffers: &cmd_buf,
+			..Default::default()
+		};
+		unsafe {
+			rs.device.queue_submit(rs.graphics_queue, &[submit_info], vk::Fence::null()).expect("queue submit failed.");
+		}
+	}
+}
+
+impl Drop for VolumetricFog
+{
+	fn drop(&mut self)
+	{
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+
+		track_destroy(ObjectKind::Pipeline, self.pipeline);
+		unsafe {
+			// The command buffer may still be executing
+			self.device.device_wait_idle().unwrap();
+
+			self.device.destroy_pipeline(self.pipeline, None);
+			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+			self.device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+			self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+		}
+	}
+}
@@ -0,0 +1,413 @@
+//! Boot-time self-test, run with --selftest to validate a machine and driver setup.
+
+use crate::core::{sim_sin_cos, BoundingBox, Config, Drawable, Frustum, Material, Mesh, VertexFormat};
+use crate::game::{
+	Collider, CombineRule, ContactPhase, ContactWorld, ConvexHull, GameEvent, GameState, GameStateMachine,
+	PhysicsMaterial, TriangleMesh,
+};
+use crate::renderer::{
+	DirectionalLight, FrameUniforms, IrradianceGridUniforms, LightUniforms, MainPass, PointLight, RenderState,
+	ShadowPass,
+};
+use ash::version::DeviceV1_0;
+use cgmath::prelude::*;
+use cgmath::{Deg, Matrix4, Point3, Rad, Vector3};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::Instant;
+
+const MATERIALS_DIR: &str = "assets/materials";
+const MESHES_DIR: &str = "assets/original/meshes";
+const OFFSCREEN_FRAMES: usize = 3;
+const TEST_MATERIAL: PhysicsMaterial = PhysicsMaterial {
+	restitution: 0.5,
+	static_friction: 0.6,
+	dynamic_friction: 0.4,
+	friction_combine: CombineRule::Average,
+	restitution_combine: CombineRule::Average,
+};
+
+/// A mesh drawn by the offscreen frames.
+struct TestObject
+{
+	mesh: Rc<Mesh>,
+	material: Rc<Material>,
+	model_matrix: Matrix4<f32>,
+}
+
+impl Drawable for TestObject
+{
+	fn get_mesh(&self) -> &Mesh
+	{
+		return &self.mesh;
+	}
+	fn get_material(&self) -> &Material
+	{
+		return &self.material;
+	}
+}
+
+/// Lights the offscreen frames, with a shadow map.
+const TEST_LIGHT: PointLight = PointLight {
+	position: Point3 {
+		x: 0.0,
+		y: 5.0,
+		z: 0.0,
+	},
+	radius: 100.0,
+	color: [1.0, 1.0, 1.0],
+	shadow_resolution: 256,
+};
+
+/// Also lights the offscreen frames, without shadows.
+const TEST_DIRECTIONAL_LIGHT: DirectionalLight = DirectionalLight {
+	direction: Vector3 {
+		x: 0.0,
+		y: 1.0,
+		z: 0.0,
+	},
+	color: [0.3, 0.3, 0.3],
+};
+
+/// Returns the paths of the files in the directory with the given extension, sorted.
+fn list_files(dir: &str, extension: &str) -> Result<Vec<String>, String>
+{
+	let mut paths = Vec::new();
+	for entry in fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e))?
+	{
+		let path = entry.map_err(|e| e.to_string())?.path();
+		if path.extension().map_or(false, |ext| ext == extension)
+		{
+			paths.push(path.to_string_lossy().into_owned());
+		}
+	}
+	paths.sort();
+	return Ok(paths);
+}
+
+/// Results of the checks run so far.
+struct SelfTest
+{
+	// Name, duration in ms and details or the error of each check
+	results: Vec<(&'static str, f32, Result<String, String>)>,
+}
+
+impl SelfTest
+{
+	/// Runs a check, timing it. Panics are caught and reported as failures.
+	fn check<F: FnOnce() -> Result<String, String>>(&mut self, name: &'static str, check: F)
+	{
+		let start = Instant::now();
+		let result = match panic::catch_unwind(AssertUnwindSafe(check))
+		{
+			Ok(result) => result,
+			Err(_) => Err("panicked".to_string()),
+		};
+		self.results.push((name, start.elapsed().as_secs_f32() * 1_000.0, result));
+	}
+
+	fn passed(&self) -> bool
+	{
+		return self.results.iter().all(|(_, _, result)| result.is_ok());
+	}
+
+	fn report(&self) -> String
+	{
+		let mut report = String::from("Self-test:\n");
+		for (name, milliseconds, result) in &self.results
+		{
+			let (status, details) = match result
+			{
+				Ok(details) => ("ok", details),
+				Err(e) => ("FAILED", e),
+			};
+			report.push_str(&format!("  {:<12}{:<8}{:>10.1} ms  {}\n", name, status, milliseconds, details));
+		}
+		let passed = self.results.iter().filter(|(_, _, result)| result.is_ok()).count();
+		report.push_str(&format!(
+			"Self-test {} ({}/{} checks passed)\n",
+			if self.passed()
+			{
+				"passed"
+			}
+			else
+			{
+				"failed"
+			},
+			passed,
+			self.results.len()
+		));
+		return report;
+	}
+}
+
+/// Checks the simulation math against known results.
+fn check_math() -> Result<String, String>
+{
+	let mut max_error: f64 = 0.0;
+	for step in -1_000..=1_000
+	{
+		let angle = step as f32 * 0.01;
+		let (sin, cos) = sim_sin_cos(angle);
+		let (expected_sin, expected_cos) = (angle as f64).sin_cos();
+		max_error = max_error.max((sin as f64 - expected_sin).abs()).max((cos as f64 - expected_cos).abs());
+	}
+	if max_error > 1e-5
+	{
+		return Err(format!("sine and cosine off by {:e}", max_error));
+	}
+
+	let view = Matrix4::look_at_rh(Point3::new(3.0, 4.0, 5.0), Point3::origin(), Vector3::unit_y());
+	let view_projection = cgmath::perspective(Deg(70.0), 1.5, 1.0, 1_000.0) * view;
+	let inverse = view_projection.invert().ok_or("view projection matrix is not invertible")?;
+	let difference: [[f32; 4]; 4] = (inverse * view_projection - Matrix4::identity()).into();
+	let identity_error = difference.iter().flatten().fold(0.0f32, |max, value| max.max(value.abs()));
+	if identity_error > 1e-4
+	{
+		return Err(format!("matrix inverse off by {:e}", identity_error));
+	}
+
+	let bounds = BoundingBox::from_points(vec![Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)]).unwrap();
+	match bounds.intersect_ray(Point3::new(0.0, 0.0, -5.0), Vector3::unit_z())
+	{
+		Some(distance) if (distance - 4.0).abs() < 1e-5 => (),
+		hit => return Err(format!("ray hit the unit box at {:?}, expected 4", hit)),
+	}
+
+	// The camera looks at the origin, and away from the point behind it
+	let frustum = Frustum::from_matrix(&view_projection);
+	if !frustum.intersects_sphere(Point3::origin(), 1.0) || frustum.intersects_sphere(Point3::new(6.0, 8.0, 10.0), 1.0)
+	{
+		return Err("frustum culled the wrong sphere".to_string());
+	}
+	return Ok(format!("max sin/cos error {:.1e}", max_error));
+}
+
+/// Checks that contacts begin, persist and end, with impulses pushing the colliders apart.
+fn check_physics() -> Result<String, String>
+{
+	let collider = |id: u32, x: f32, velocity: f32| Collider {
+		id: id,
+		position: Point3::new(x, 0.0, 0.0),
+		velocity: Vector3::new(velocity, 0.0, 0.0),
+		radius: 1.0,
+		hull: None,
+		mass: 1.0,
+		material: TEST_MATERIAL,
+	};
+	let mut world = ContactWorld::new();
+	let mut phases = Vec::new();
+	for &(distance, speed) in &[(1.5, 2.0), (1.5, 0.0), (3.0, 0.0)]
+	{
+		world.step(&[collider(0, 0.0, speed), collider(1, distance, -speed)]);
+		let events = world.drain_events();
+		if events.len() != 1
+		{
+			return Err(format!("expected one contact event, got {}", events.len()));
+		}
+		if events[0].phase == ContactPhase::Begin && events[0].normal_impulse <= 0.0
+		{
+			return Err(format!("approaching colliders got an impulse of {} Ns", events[0].normal_impulse));
+		}
+		phases.push(events[0].phase);
+	}
+	if phases != [ContactPhase::Begin, ContactPhase::Persist, ContactPhase::End]
+	{
+		return Err("contact phases out of order".to_string());
+	}
+	return Ok("contacts begin, persist and end".to_string());
+}
+
+/// Checks that the game states follow their events, ignoring the ones that do not apply.
+fn check_game_flow() -> Result<String, String>
+{
+	let mut game_state = GameStateMachine::new(GameState::Menu);
+	let mut entered = Vec::new();
+	for &event in &[
+		GameEvent::Pause,
+		GameEvent::StartRace,
+		GameEvent::Loaded,
+		GameEvent::Pause,
+		GameEvent::RaceFinished,
+		GameEvent::Resume,
+		GameEvent::RaceFinished,
+		GameEvent::QuitToMenu,
+	]
+	{
+		game_state.send(event);
+		entered.extend(game_state.poll());
+	}
+	let expected = [
+		GameState::Loading,
+		GameState::Playing,
+		GameState::Paused,
+		GameState::Playing,
+		GameState::Results,
+		GameState::Menu,
+	];
+	if entered != expected || !game_state.is_race()
+	{
+		return Err(format!("entered {:?}, expected {:?}", entered, expected));
+	}
+	game_state.send(GameEvent::Quit);
+	game_state.poll();
+	if game_state.is_running()
+	{
+		return Err("quitting did not end the game".to_string());
+	}
+	return Ok(format!("{} transitions", entered.len()));
+}
+
+/// Checks the distances to a cooked hull and triangle mesh of a box.
+fn check_collision() -> Result<String, String>
+{
+	let mut corners = Vec::new();
+	for &x in &[-1.0, 1.0]
+	{
+		for &y in &[-1.0, 1.0]
+		{
+			for &z in &[-1.0, 1.0]
+			{
+				corners.push(Point3::new(x, y, z));
+			}
+		}
+	}
+	// A point inside, which must not end up on the hull
+	corners.push(Point3::origin());
+	let hull = ConvexHull::from_points(&corners).ok_or("no hull around the box corners")?;
+	let (distance, normal) = hull.get_distance(Point3::new(0.0, 0.0, 3.0));
+	if (distance - 2.0).abs() > 1e-5 || (normal - Vector3::unit_z()).magnitude() > 1e-5
+	{
+		return Err(format!("point 2 above the hull measured {} away along {:?}", distance, normal));
+	}
+
+	// The top of the box, as two triangles
+	let top = [corners[2], corners[3], corners[7], corners[6]];
+	let mesh = TriangleMesh::new(&[[top[0], top[1], top[2]], [top[0], top[2], top[3]]]);
+	match mesh.cast_sphere(Point3::new(0.5, 5.0, 0.5), -Vector3::unit_y(), 1.0)
+	{
+		Some(distance) if (distance - 3.0).abs() < 1e-5 => (),
+		hit => return Err(format!("sphere cast hit the box top at {:?}, expected 3", hit)),
+	}
+	return Ok("hull and triangle mesh distances match".to_string());
+}
+
+/// Creates the main pass, loads the assets and renders a few frames offscreen, then runs the CPU checks. Prints a
+/// report, and returns whether all checks passed.
+///
+/// The present pass has been created by the caller, so the swapchain and its pipelines are already validated.
+pub fn run(rs: &RenderState, cfg: &Config) -> bool
+{
+	let mut selftest = SelfTest {
+		results: Vec::new(),
+	};
+	let mut mainpass = None;
+	let mut shadowpass = None;
+	selftest.check("pipelines", || {
+		mainpass = Some(MainPass::init(rs, cfg, "selftest"));
+		shadowpass = Some(ShadowPass::new(rs));
+		return Ok("main and shadow passes created".to_string());
+	});
+
+	let mut objects = Vec::new();
+	if let Some(mp) = &mainpass
+	{
+		selftest.check("assets", || {
+			let mut materials = Vec::new();
+			for path in list_files(MATERIALS_DIR, "json")?
+			{
+				materials.push(Material::load(rs, mp, &path)?);
+			}
+			rs.finish_texture_uploads()?;
+			if materials.is_empty()
+			{
+				return Err(format!("no materials in {}", MATERIALS_DIR));
+			}
+			let mut meshes = vec![Mesh::new_cuboid(rs, 2.0, 2.0, 2.0)];
+			for path in list_files(MESHES_DIR, "obj")?
+			{
+				meshes.push(Mesh::load_obj(rs, &path, VertexFormat::Full)?);
+			}
+			let details = format!("{} materials, {} meshes", materials.len(), meshes.len());
+			for (idx, mesh) in meshes.into_iter().enumerate()
+			{
+				objects.push(TestObject {
+					mesh: mesh,
+					material: materials[idx % materials.len()].clone(),
+					model_matrix: Matrix4::from_translation(Vector3::new(4.0 * idx as f32, 0.0, -10.0)),
+				});
+			}
+			return Ok(details);
+		});
+	}
+
+	if let (Some(mp), Some(sp)) = (&mut mainpass, &mut shadowpass)
+	{
+		selftest.check("rendering", || {
+			let view_matrix = Matrix4::identity();
+			let fov = Deg(70.0);
+			let projection_matrix = cgmath::perspective(fov, 1.5, 1.0, 1_000.0);
+			for _ in 0..OFFSCREEN_FRAMES
+			{
+				sp.render(
+					rs,
+					&[TEST_LIGHT],
+					&view_matrix,
+					Rad::from(fov),
+					1,
+					|sp, cmd_buf, view_projection, light_matrix| {
+						for object in &objects
+						{
+							object.draw_shadow(
+								&rs.device,
+								cmd_buf,
+								sp,
+								&object.model_matrix,
+								view_projection,
+								light_matrix,
+							);
+						}
+					},
+				);
+				let mut light_uniforms = sp.get_light_uniforms(&[TEST_LIGHT]);
+				light_uniforms.push(LightUniforms::from_directional(&TEST_DIRECTIONAL_LIGHT));
+				mp.update_frame_uniforms(&FrameUniforms::new(
+					view_matrix,
+					0.0,
+					0.0,
+					&light_uniforms,
+					&[],
+					IrradianceGridUniforms::default(),
+				));
+				mp.set_shadow_atlas(rs, Some(sp.get_shadow_atlas()));
+				let cmd_buf = mp.begin_frame(rs);
+				if mp.has_depth_prepass()
+				{
+					for object in &objects
+					{
+						object.draw(&rs.device, cmd_buf, mp, &object.model_matrix, &view_matrix, &projection_matrix);
+					}
+					mp.finish_depth_prepass(cmd_buf);
+				}
+				for object in &objects
+				{
+					object.draw(&rs.device, cmd_buf, mp, &object.model_matrix, &view_matrix, &projection_matrix);
+				}
+				mp.end_frame(rs);
+				unsafe {
+					rs.device.device_wait_idle().map_err(|e| format!("device lost: {}", e))?;
+				}
+			}
+			return Ok(format!("{} shadowed frames of {} objects", OFFSCREEN_FRAMES, objects.len()));
+		});
+	}
+
+	selftest.check("math", check_math);
+	selftest.check("physics", check_physics);
+	selftest.check("collision", check_collision);
+	selftest.check("game flow", check_game_flow);
+
+	print!("{}", selftest.report());
+	return selftest.passed();
+}